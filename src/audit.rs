@@ -0,0 +1,309 @@
+//! The audit log of tool invocations
+//!
+//! Every tool the model runs appends one line of JSON to the audit log next
+//! to the config file: when it ran, its arguments, whether it succeeded and
+//! the start of its output. `aido audit show` prints the log back, so it is
+//! always possible to review what was actually executed. Secrets are masked
+//! before anything is written.
+
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::redact::Redactor;
+use crate::tools::ToolInput;
+
+/// Name of the audit log inside the config directory
+const AUDIT_FILE_NAME: &str = "audit.jsonl";
+
+/// Longest tool output kept in an entry, in characters
+const MAX_OUTPUT_CHARS: usize = 2000;
+
+/// How a tool invocation ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditStatus {
+    /// The tool ran and returned output
+    Ok,
+    /// The tool ran and failed
+    Error,
+    /// A tool hook, e.g. a declined confirmation, stopped the call
+    Denied,
+}
+
+impl AuditStatus {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Error => "error",
+            Self::Denied => "denied",
+        }
+    }
+}
+
+/// A single tool invocation recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch when the tool finished
+    pub timestamp: u64,
+    pub tool: String,
+    /// Arguments the tool received, after tool hooks rewrote them
+    pub arguments: Value,
+    pub status: AuditStatus,
+    /// Output of the tool, or its error, cut off after
+    /// [`MAX_OUTPUT_CHARS`] characters
+    pub output: String,
+    /// Whether `output` was cut off
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl AuditEntry {
+    /// Creates an entry for a tool invocation that just finished
+    pub fn new(
+        tool: impl Into<String>,
+        input: &ToolInput,
+        status: AuditStatus,
+        output: &str,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        let (output, truncated) =
+            match output.char_indices().nth(MAX_OUTPUT_CHARS) {
+                Some((end, _)) => (output[..end].to_owned(), true),
+                None => (output.to_owned(), false),
+            };
+
+        Self {
+            timestamp,
+            tool: tool.into(),
+            arguments: serde_json::to_value(input).unwrap_or_default(),
+            status,
+            output,
+            truncated,
+        }
+    }
+}
+
+/// Append-only log of every tool invocation
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    redactor: Redactor,
+}
+
+impl AuditLog {
+    /// Opens the audit log stored next to the given config file
+    pub fn for_config_file(config_file_path: &str) -> Self {
+        let path = Path::new(config_file_path)
+            .parent()
+            .expect("Config file path should have a parent directory")
+            .join(AUDIT_FILE_NAME);
+
+        Self::new(path)
+    }
+
+    /// Opens the audit log at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), redactor: Redactor::new() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Masks secrets with `redactor` instead of only the built-in token
+    /// formats
+    #[must_use]
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Appends an entry to the log, with its secrets masked
+    pub fn record(&self, entry: &AuditEntry) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut entry = entry.clone();
+        self.redact(&mut entry);
+
+        let mut file =
+            OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+
+        Ok(())
+    }
+
+    /// Reads every entry in the log, oldest first, skipping lines that
+    /// cannot be parsed
+    ///
+    /// Secrets are masked again on the way out, for entries written before
+    /// a pattern matching them was configured.
+    pub fn entries(&self) -> std::io::Result<Vec<AuditEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let file = std::fs::File::open(&self.path)?;
+        let mut entries = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            if let Ok(mut entry) = serde_json::from_str(&line?) {
+                self.redact(&mut entry);
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn redact(&self, entry: &mut AuditEntry) {
+        self.redactor.redact_json(&mut entry.arguments);
+        entry.output = self.redactor.redact(&entry.output).into_owned();
+    }
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time
+pub(crate) fn format_timestamp(timestamp: u64) -> String {
+    let days = timestamp / 86_400;
+    let seconds = timestamp % 86_400;
+
+    // Converts days since the epoch to a civil date, from
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+        - day_of_era / 146_096)
+        / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Prints the last `limit` entries of the log, oldest first
+pub fn print_entries(log: &AuditLog, limit: usize) -> std::io::Result<()> {
+    let entries = log.entries()?;
+
+    if entries.is_empty() {
+        println!("No tool calls recorded yet.");
+        return Ok(());
+    }
+
+    for entry in &entries[entries.len().saturating_sub(limit)..] {
+        println!(
+            "{} UTC  {}  {}",
+            format_timestamp(entry.timestamp),
+            entry.tool,
+            entry.status.label()
+        );
+        println!("  arguments: {}", entry.arguments);
+        for line in entry.output.lines() {
+            println!("  | {line}");
+        }
+        if entry.truncated {
+            println!("  | [output truncated]");
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_truncates_output() {
+        let input = ToolInput::from([("args".to_owned(), "-al".into())]);
+        let long_output = "x".repeat(MAX_OUTPUT_CHARS + 10);
+
+        let entry =
+            AuditEntry::new("ls", &input, AuditStatus::Ok, &long_output);
+
+        assert_eq!(entry.output.len(), MAX_OUTPUT_CHARS);
+        assert!(entry.truncated);
+        assert_eq!(entry.arguments, serde_json::json!({ "args": "-al" }));
+
+        let entry = AuditEntry::new("ls", &input, AuditStatus::Ok, "a\nb");
+        assert_eq!(entry.output, "a\nb");
+        assert!(!entry.truncated);
+    }
+
+    #[test]
+    fn test_log_round_trip() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-audit-test-{}", std::process::id()));
+        let log = AuditLog::new(dir.join(AUDIT_FILE_NAME));
+        assert!(log.entries().unwrap().is_empty());
+
+        let entries = [
+            AuditEntry::new("ls", &ToolInput::new(), AuditStatus::Ok, "out"),
+            AuditEntry::new(
+                "search",
+                &ToolInput::new(),
+                AuditStatus::Denied,
+                "Error: denied",
+            ),
+        ];
+        for entry in &entries {
+            log.record(entry).unwrap();
+        }
+
+        assert_eq!(log.entries().unwrap(), entries);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_masks_secrets() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-audit-redact-test-{}", std::process::id()));
+        let log = AuditLog::new(dir.join(AUDIT_FILE_NAME))
+            .with_redactor(Redactor::new().with_secret("hunter2"));
+        let input =
+            ToolInput::from([("args".to_owned(), "-p hunter2".into())]);
+
+        log.record(&AuditEntry::new(
+            "ls",
+            &input,
+            AuditStatus::Ok,
+            "password: hunter2",
+        ))
+        .unwrap();
+
+        let written = std::fs::read_to_string(&log.path).unwrap();
+        assert!(!written.contains("hunter2"));
+        let entry = &log.entries().unwrap()[0];
+        assert_eq!(entry.arguments["args"], "-p [REDACTED]");
+        assert_eq!(entry.output, "password: [REDACTED]");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_827_696), "2000-02-29 12:34:56");
+        assert_eq!(format_timestamp(1_767_225_599), "2025-12-31 23:59:59");
+    }
+}
@@ -0,0 +1,302 @@
+//! Running several recipes over a list of inputs
+//!
+//! `aido batch --recipe 'lint-*' --each files.txt` runs every recipe
+//! matching the glob once per line of `files.txt`, passing the line as the
+//! user message, and prints a single report once all runs are done. A run
+//! that fails is recorded in the report rather than stopping the batch, and
+//! the command exits with an error if any did, so batches can serve as
+//! composite checks in CI.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::error::AidoResult;
+use crate::llm::Usage;
+use crate::recipe::RecipeStore;
+use crate::run::{self, RunOptions, RunOutcome};
+use crate::tools::Tool;
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("{failed} of {total} batch runs failed")]
+    Failed { failed: usize, total: usize },
+
+    #[error("No input items in {path}")]
+    NoItems { path: String },
+}
+
+/// The result of running one recipe on one input item
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchRun {
+    pub item: String,
+    pub recipe: String,
+    /// The answer, unless the run failed
+    pub text: Option<String>,
+    /// Why the run failed, if it did
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub usage: Usage,
+    pub cost: Option<f64>,
+}
+
+/// Every run of a batch, in the order they were made
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    pub runs: Vec<BatchRun>,
+    /// Whether the user stopped the batch with Ctrl-C
+    pub cancelled: bool,
+}
+
+impl BatchReport {
+    /// Number of runs that failed
+    pub fn failed(&self) -> usize {
+        self.runs.iter().filter(|run| run.error.is_some()).count()
+    }
+
+    /// Token usage summed across every run
+    pub fn usage(&self) -> Usage {
+        let mut usage = Usage::default();
+        for run in &self.runs {
+            usage += &run.usage;
+        }
+        usage
+    }
+
+    /// Estimated cost of the batch, if any run has a known cost
+    pub fn cost(&self) -> Option<f64> {
+        self.runs.iter().fold(None, |cost, run| run::add_costs(cost, run.cost))
+    }
+
+    /// Fails with [`BatchError::Failed`] if any run failed
+    pub fn check(&self) -> Result<(), BatchError> {
+        match self.failed() {
+            0 => Ok(()),
+            failed => {
+                Err(BatchError::Failed { failed, total: self.runs.len() })
+            }
+        }
+    }
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut item = None;
+
+        for run in &self.runs {
+            if item != Some(&run.item) {
+                writeln!(f, "## {}\n", run.item)?;
+                item = Some(&run.item);
+            }
+
+            writeln!(f, "### {}\n", run.recipe)?;
+            match (&run.text, &run.error) {
+                (_, Some(error)) => writeln!(f, "Error: {error}\n")?,
+                (Some(text), None) => writeln!(f, "{}\n", text.trim_end())?,
+                (None, None) => {}
+            }
+        }
+
+        write!(
+            f,
+            "{} runs, {} failed{}. Usage: {}",
+            self.runs.len(),
+            self.failed(),
+            if self.cancelled { ", cancelled" } else { "" },
+            self.usage()
+        )?;
+        if let Some(cost) = self.cost() {
+            write!(f, " (est. ${cost:.6})")?;
+        }
+        writeln!(f)
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    runs: &'a [BatchRun],
+    failed: usize,
+    cancelled: bool,
+    total_tokens: u32,
+    cost: Option<f64>,
+}
+
+/// Writes the report as a single line of JSON
+pub fn write_json(
+    mut writer: impl Write,
+    report: &BatchReport,
+) -> io::Result<()> {
+    let document = JsonReport {
+        runs: &report.runs,
+        failed: report.failed(),
+        cancelled: report.cancelled,
+        total_tokens: report.usage().total_tokens(),
+        cost: report.cost(),
+    };
+
+    serde_json::to_writer(&mut writer, &document)?;
+    writeln!(writer)
+}
+
+/// Reads the input items of a batch, one per non-empty line, from the
+/// file at `path` or from standard input if it is `-`
+pub fn read_items(path: &Path) -> AidoResult<Vec<String>> {
+    let content = if path == Path::new("-") {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        std::fs::read_to_string(path)?
+    };
+
+    let items = parse_items(&content);
+    if items.is_empty() {
+        return Err(
+            BatchError::NoItems { path: path.display().to_string() }.into()
+        );
+    }
+
+    Ok(items)
+}
+
+fn parse_items(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Runs each of `recipe_names` on each of `items`, one after another
+///
+/// `on_run` is called with the name and outcome of each successful run as
+/// it finishes. Nothing is streamed while the batch is in progress; a
+/// progress line per run goes to stderr instead.
+pub async fn run_batch(
+    config: &Config,
+    recipes: &RecipeStore,
+    recipe_names: &[String],
+    items: &[String],
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+    mut on_run: impl FnMut(&str, &RunOutcome),
+) -> BatchReport {
+    let mut quiet = options.clone();
+    quiet.copy_result = false;
+    quiet.output_file = None;
+    // A dry run's whole point is the request it prints
+    if !options.dry_run {
+        quiet.callbacks.on_text = Some(Arc::new(|_: &str| {}));
+    }
+
+    let total = items.len() * recipe_names.len();
+    let mut report = BatchReport::default();
+
+    'items: for item in items {
+        for recipe_name in recipe_names {
+            eprintln!(
+                "[{}/{total}] {recipe_name}: {item}",
+                report.runs.len() + 1
+            );
+
+            let result = Box::pin(run::run_recipe(
+                config.clone(),
+                recipes,
+                recipe_name,
+                Some(item.clone()),
+                tools,
+                &quiet,
+            ))
+            .await;
+
+            let mut run = BatchRun {
+                item: item.clone(),
+                recipe: recipe_name.clone(),
+                text: None,
+                error: None,
+                usage: Usage::default(),
+                cost: None,
+            };
+            match result {
+                Ok(outcome) => {
+                    on_run(recipe_name, &outcome);
+                    run.text = Some(outcome.text);
+                    run.usage = outcome.usage;
+                    run.cost = outcome.cost;
+                    report.cancelled = outcome.cancelled;
+                }
+                Err(e) => run.error = Some(e.to_string()),
+            }
+            report.runs.push(run);
+
+            if report.cancelled {
+                break 'items;
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch_run(item: &str, recipe: &str, error: Option<&str>) -> BatchRun {
+        BatchRun {
+            item: item.to_owned(),
+            recipe: recipe.to_owned(),
+            text: error.is_none().then(|| "Looks good.".to_owned()),
+            error: error.map(str::to_owned),
+            usage: Usage::new(10, 5, 15),
+            cost: Some(0.5),
+        }
+    }
+
+    #[test]
+    fn test_parse_items() {
+        assert_eq!(
+            parse_items("src/main.rs\n\n  src/lib.rs  \n"),
+            ["src/main.rs", "src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let report = BatchReport {
+            runs: vec![
+                batch_run("a.rs", "lint-naming", None),
+                batch_run("a.rs", "lint-style", Some("timed out")),
+                batch_run("b.rs", "lint-naming", None),
+            ],
+            cancelled: false,
+        };
+
+        assert_eq!(report.failed(), 1);
+        assert_eq!(report.usage().total_tokens(), 45);
+        assert!(matches!(
+            report.check(),
+            Err(BatchError::Failed { failed: 1, total: 3 })
+        ));
+
+        let text = report.to_string();
+        assert!(text.starts_with(
+            "## a.rs\n\n### lint-naming\n\nLooks good.\n\n### lint-style\n\n\
+             Error: timed out\n\n## b.rs\n\n"
+        ));
+        assert!(text.contains("3 runs, 1 failed. Usage: "));
+
+        let mut buffer = Vec::new();
+        write_json(&mut buffer, &report).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(json["failed"], 1);
+        assert_eq!(json["runs"][1]["error"], "timed out");
+        assert_eq!(json["total_tokens"], 45);
+    }
+}
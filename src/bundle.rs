@@ -0,0 +1,576 @@
+//! Recording runs to replay them offline
+//!
+//! `--record bundle.json` saves what it takes to reproduce a run: the
+//! config, the tools offered to the model, and for every request to the
+//! model the messages sent, the chunks of the reply as the provider sent
+//! them and the reply made of them. Secrets are masked throughout, as in
+//! exported sessions, so a bundle can be attached to a bug report.
+//!
+//! `aido replay bundle.json` runs the recorded conversation through the
+//! agent loop again without touching the network or the machine: the
+//! model's replies come from the bundle, in order, and so do the outputs
+//! of the tools it calls.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::error::AidoResult;
+use crate::llm::{LlmRequest, LlmResponse, Message, ToolCall, Usage};
+use crate::middleware::{Middleware, MiddlewareResult};
+use crate::redact::{REDACTED, Redactor};
+use crate::tools::{
+    Arg, ArgType, Tool, ToolContext, ToolDefinition, ToolInput,
+};
+
+/// Version of the bundle format, raised when it changes incompatibly
+pub const VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error(
+        "The bundle is version {found}; this aido reads version {VERSION}"
+    )]
+    UnsupportedVersion { found: u32 },
+
+    #[error("The bundle has no requests to the model to replay")]
+    Empty,
+
+    #[error(
+        "The replay asked the model more often than the recording, which \
+         has {recorded} replies"
+    )]
+    OutOfReplies { recorded: usize },
+
+    #[error("The recording has no more output of tool `{name}`")]
+    OutOfToolOutput { name: String },
+
+    #[error("Invalid bundle: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Everything recorded of a run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    /// Version of aido that recorded the run
+    pub aido_version: String,
+    /// The config the run was made with
+    pub config: Value,
+    /// The tools offered to the model
+    pub tools: Vec<ToolSchema>,
+    /// Every request made to the model, in order
+    pub exchanges: Vec<Exchange>,
+}
+
+/// A tool as the model was shown it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolSchema {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: Value,
+}
+
+/// One request to the model and its reply
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    /// The conversation sent
+    pub messages: Vec<Message>,
+    /// The reply as the provider sent it; empty for replies served from
+    /// the response cache
+    #[serde(default)]
+    pub chunks: Vec<Value>,
+    pub reply: Reply,
+}
+
+/// The reply the agent loop acted on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reply {
+    pub text: String,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default)]
+    pub reasoning: String,
+    #[serde(default)]
+    pub usage: Usage,
+}
+
+impl From<&LlmResponse> for Reply {
+    fn from(response: &LlmResponse) -> Self {
+        Self {
+            text: response.text().to_owned(),
+            tool_calls: response.tool_calls().to_vec(),
+            reasoning: response.reasoning().to_owned(),
+            usage: response.usage().clone(),
+        }
+    }
+}
+
+impl From<&Reply> for LlmResponse {
+    fn from(reply: &Reply) -> Self {
+        Self::new(&reply.text, reply.usage.clone(), reply.tool_calls.clone())
+            .with_reasoning(&reply.reasoning)
+    }
+}
+
+impl Bundle {
+    /// Reads the bundle at `path`
+    pub fn load(path: &Path) -> Result<Self, BundleError> {
+        let value =
+            serde_json::from_str::<Value>(&std::fs::read_to_string(path)?)?;
+        let found = value
+            .get("version")
+            .and_then(Value::as_u64)
+            .and_then(|version| u32::try_from(version).ok())
+            .unwrap_or_default();
+        if found != VERSION {
+            return Err(BundleError::UnsupportedVersion { found });
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// The recorded config, without the settings that would make a replay
+    /// reach out to the network or format tool output a second time
+    pub fn config(&self) -> Result<Config, BundleError> {
+        let mut config =
+            serde_json::from_value::<Config>(self.config.clone())?;
+        config.verify = None;
+        config.context_limit = None;
+        config.otel = None;
+        config.fallback_models = Vec::new();
+        config.tools.format.clear();
+        Ok(config)
+    }
+
+    /// The conversation the run started with
+    pub fn messages(&self) -> Result<Vec<Message>, BundleError> {
+        self.exchanges
+            .first()
+            .map(|exchange| exchange.messages.clone())
+            .ok_or(BundleError::Empty)
+    }
+
+    /// The answer the run ended with, if it ended with one
+    pub fn answer(&self) -> Option<&str> {
+        self.exchanges
+            .last()
+            .map(|exchange| &exchange.reply)
+            .filter(|reply| reply.tool_calls.is_empty())
+            .map(|reply| reply.text.as_str())
+    }
+
+    /// A middleware answering every request with the next recorded reply
+    pub fn replies(&self) -> Replay {
+        Replay {
+            replies: Mutex::new(
+                self.exchanges.iter().map(|e| (&e.reply).into()).collect(),
+            ),
+            recorded: self.exchanges.len(),
+        }
+    }
+
+    /// Stand-ins for the recorded tools that return, call after call, the
+    /// outputs recorded for them
+    pub fn tools(&self) -> Vec<Box<dyn Tool>> {
+        let outputs = self
+            .exchanges
+            .iter()
+            .flat_map(|exchange| &exchange.messages)
+            .filter_map(|message| match message {
+                Message::Tool { content, id } => Some((id.as_str(), content)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut calls = Vec::new();
+        for exchange in &self.exchanges {
+            let made = exchange
+                .messages
+                .iter()
+                .filter_map(|message| match message {
+                    Message::Assistant(_, calls) => calls.as_ref(),
+                    _ => None,
+                })
+                .flatten();
+            for call in made {
+                if !calls.iter().any(|c: &&ToolCall| c.id() == call.id()) {
+                    calls.push(call);
+                }
+            }
+        }
+
+        self.tools
+            .iter()
+            .map(|schema| {
+                let outputs = calls
+                    .iter()
+                    .filter(|call| call.name() == schema.name)
+                    .filter_map(|call| outputs.get(call.id()))
+                    .map(|output| (*output).clone())
+                    .collect();
+                Box::new(RecordedTool {
+                    definition: schema.definition(),
+                    outputs: Mutex::new(outputs),
+                }) as Box<dyn Tool>
+            })
+            .collect()
+    }
+}
+
+impl ToolSchema {
+    fn of(definition: &ToolDefinition) -> Self {
+        Self {
+            name: definition.name().to_owned(),
+            description: definition.description().to_owned(),
+            parameters: definition.json_value(),
+        }
+    }
+
+    /// The definition the schema was made from, as far as it tells
+    fn definition(&self) -> ToolDefinition {
+        let required = self.parameters["required"]
+            .as_array()
+            .map_or_else(Vec::new, |names| {
+                names.iter().filter_map(Value::as_str).collect()
+            });
+        let args = self.parameters["properties"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(name, property)| {
+                let mut arg = Arg::new(name)
+                    .description(
+                        property["description"].as_str().unwrap_or_default(),
+                    )
+                    .kind(
+                        serde_json::from_value::<ArgType>(
+                            property["type"].clone(),
+                        )
+                        .unwrap_or_default(),
+                    );
+                if let Some(values) = property["enum"].as_array() {
+                    arg =
+                        arg.with_enum(values.iter().filter_map(Value::as_str));
+                }
+                if required.contains(&name.as_str()) {
+                    arg = arg.required();
+                }
+                arg
+            })
+            .collect();
+
+        ToolDefinition::new(self.name.clone(), self.description.clone(), args)
+    }
+}
+
+/// Collects what a run sends to and gets from the model
+///
+/// Clones share what they record, so the recorder in the options of every
+/// run of a command adds to the same recording.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder {
+    recording: Arc<Mutex<Recording>>,
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    tools: Vec<ToolSchema>,
+    exchanges: Vec<Exchange>,
+    /// Chunks of the reply being received
+    chunks: Vec<Value>,
+}
+
+impl Recorder {
+    /// Records a chunk of the reply being received
+    pub fn chunk(&self, chunk: &Value) {
+        let mut recording = self.lock();
+        recording.chunks.push(chunk.clone());
+    }
+
+    /// Records that `request` was answered with `response`, along with the
+    /// chunks recorded since the last exchange
+    pub fn exchange(&self, request: &LlmRequest, response: &LlmResponse) {
+        let mut recording = self.lock();
+        for definition in request.tools() {
+            if !recording.tools.iter().any(|t| t.name == definition.name()) {
+                recording.tools.push(ToolSchema::of(definition));
+            }
+        }
+        let chunks = std::mem::take(&mut recording.chunks);
+        recording.exchanges.push(Exchange {
+            messages: request.messages().to_vec(),
+            chunks,
+            reply: response.into(),
+        });
+    }
+
+    /// Whether no request was recorded
+    pub fn is_empty(&self) -> bool {
+        self.lock().exchanges.is_empty()
+    }
+
+    /// The bundle of everything recorded, made with `config`
+    pub fn bundle(&self, config: &Config) -> Result<Bundle, BundleError> {
+        let recording = self.lock();
+        Ok(Bundle {
+            version: VERSION,
+            aido_version: env!("CARGO_PKG_VERSION").to_owned(),
+            config: serde_json::to_value(config)?,
+            tools: recording.tools.clone(),
+            exchanges: recording.exchanges.clone(),
+        })
+    }
+
+    /// Writes the bundle of everything recorded to `path`, with what
+    /// `redactor` masks masked, and the values of all configured headers
+    pub fn save(
+        &self,
+        path: &Path,
+        config: &Config,
+        redactor: &Redactor,
+    ) -> Result<(), BundleError> {
+        let mut bundle = serde_json::to_value(self.bundle(config)?)?;
+        let config = &mut bundle["config"];
+        mask_values(&mut config["headers"]);
+        mask_values(&mut config["otel"]["headers"]);
+        if let Some(profiles) = config["profiles"].as_object_mut() {
+            for profile in profiles.values_mut() {
+                mask_values(&mut profile["headers"]);
+                if profile["api_key"].is_string() {
+                    profile["api_key"] = Value::from(REDACTED);
+                }
+            }
+        }
+        redactor.redact_json(&mut bundle);
+        let json = serde_json::to_string_pretty(&bundle)?;
+        std::fs::write(path, format!("{json}\n"))?;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Recording> {
+        // A panic while recording leaves nothing half-written worth losing
+        // the rest of the recording over
+        self.recording
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Masks every value of `map`, headers being likely to carry credentials
+/// that no pattern recognizes
+fn mask_values(map: &mut Value) {
+    if let Some(map) = map.as_object_mut() {
+        for value in map.values_mut() {
+            *value = Value::from(REDACTED);
+        }
+    }
+}
+
+/// Answers requests with recorded replies instead of the model
+#[derive(Debug)]
+pub struct Replay {
+    replies: Mutex<VecDeque<LlmResponse>>,
+    recorded: usize,
+}
+
+impl Middleware for Replay {
+    fn before_request(
+        &self,
+        _request: &mut LlmRequest,
+    ) -> MiddlewareResult<Option<LlmResponse>> {
+        let reply = self
+            .replies
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .pop_front()
+            .ok_or(BundleError::OutOfReplies { recorded: self.recorded })?;
+        Ok(Some(reply))
+    }
+
+    fn rewrites_text(&self) -> bool {
+        false
+    }
+}
+
+/// A tool returning recorded output instead of running
+struct RecordedTool {
+    definition: ToolDefinition,
+    outputs: Mutex<VecDeque<String>>,
+}
+
+#[async_trait]
+impl Tool for RecordedTool {
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+
+    async fn execute(
+        &self,
+        _input: ToolInput,
+        _context: &ToolContext,
+    ) -> AidoResult<String> {
+        let output = self
+            .outputs
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .pop_front();
+        Ok(output.ok_or_else(|| BundleError::OutOfToolOutput {
+            name: self.definition.name().to_owned(),
+        })?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::Search;
+
+    fn recorded_run() -> Recorder {
+        let recorder = Recorder::default();
+        let search = Search::new();
+        let tools = vec![search.definition().clone()];
+        let call = ToolCall::new("call_1", "search", r#"{"pattern":"fn"}"#);
+
+        let mut messages = vec![
+            Message::System("Be brief.".to_owned()),
+            Message::User("Where is main?".to_owned()),
+        ];
+        recorder.chunk(&serde_json::json!({ "choices": [] }));
+        recorder.exchange(
+            &LlmRequest::new(messages.clone(), tools.clone()),
+            &LlmResponse::new("", Usage::new(5, 2, 7), vec![call.clone()]),
+        );
+
+        messages.push(Message::Assistant(String::new(), Some(vec![call])));
+        messages.push(Message::Tool {
+            content: "src/main.rs:1: fn main()".to_owned(),
+            id: "call_1".to_owned(),
+        });
+        recorder.exchange(
+            &LlmRequest::new(messages, tools),
+            &LlmResponse::new("In src/main.rs.", Usage::default(), Vec::new()),
+        );
+        recorder
+    }
+
+    #[test]
+    fn test_record() {
+        let bundle = recorded_run().bundle(&Config::default()).unwrap();
+
+        assert_eq!(bundle.version, VERSION);
+        assert_eq!(bundle.tools.len(), 1);
+        assert_eq!(bundle.tools[0].name, "search");
+        assert_eq!(bundle.exchanges.len(), 2);
+        assert_eq!(bundle.exchanges[0].chunks.len(), 1);
+        assert!(bundle.exchanges[1].chunks.is_empty());
+        assert_eq!(bundle.exchanges[0].reply.usage.total_tokens(), 7);
+        assert_eq!(bundle.messages().unwrap().len(), 2);
+        assert_eq!(bundle.answer(), Some("In src/main.rs."));
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let bundle = recorded_run().bundle(&Config::default()).unwrap();
+        let json = serde_json::to_string(&bundle).unwrap();
+        let bundle = serde_json::from_str::<Bundle>(&json).unwrap();
+
+        let replies = bundle.replies();
+        let mut request = LlmRequest::new(Vec::new(), Vec::new());
+        let first = replies.before_request(&mut request).unwrap().unwrap();
+        assert_eq!(first.tool_calls()[0].name(), "search");
+        replies.before_request(&mut request).unwrap();
+        assert!(replies.before_request(&mut request).is_err());
+
+        let tools = bundle.tools();
+        let definition = tools[0].definition();
+        assert_eq!(definition.name(), "search");
+        assert_eq!(
+            definition.args().len(),
+            Search::new().definition().args().len()
+        );
+        assert_eq!(
+            tools[0]
+                .execute(ToolInput::new(), &ToolContext::current().unwrap())
+                .await
+                .unwrap(),
+            "src/main.rs:1: fn main()"
+        );
+        assert!(
+            tools[0]
+                .execute(ToolInput::new(), &ToolContext::current().unwrap())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_save_masks_headers() {
+        let path = std::env::temp_dir()
+            .join(format!("aido-bundle-headers-{}.json", std::process::id()));
+        let header = |name: &str, value: &str| {
+            std::collections::BTreeMap::from([(
+                name.to_owned(),
+                value.to_owned(),
+            )])
+        };
+        let config = Config {
+            headers: header("x-team", "plain-looking-credential"),
+            otel: Some(crate::otel::OtelConfig {
+                endpoint: "http://localhost:4318".to_owned(),
+                headers: header("x-honeycomb-team", "another-credential"),
+            }),
+            profiles: [(
+                "work".to_owned(),
+                crate::config::Profile {
+                    api_key: Some("profile-key".to_owned()),
+                    headers: header("x-gateway", "gateway-credential"),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Config::default()
+        };
+
+        recorded_run().save(&path, &config, &Redactor::new()).unwrap();
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        for secret in [
+            "plain-looking-credential",
+            "another-credential",
+            "profile-key",
+            "gateway-credential",
+        ] {
+            assert!(!saved.contains(secret), "{secret} was saved");
+        }
+        let bundle = Bundle::load(&path).unwrap();
+        assert_eq!(bundle.config["headers"]["x-team"], REDACTED);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_checks_version() {
+        let path = std::env::temp_dir()
+            .join(format!("aido-bundle-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"version": 99}"#).unwrap();
+
+        assert!(matches!(
+            Bundle::load(&path),
+            Err(BundleError::UnsupportedVersion { found: 99 })
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+//! Caching model replies on disk
+//!
+//! Iterating on a script tends to send the same request over and over.
+//! Once `cache_ttl_secs` is set, the [`ResponseCache`] stores replies under
+//! a hash of the endpoint and the request that produced them, model,
+//! messages, tools and sampling settings included, so that an identical
+//! request is answered from disk at once and without spending tokens.
+//!
+//! Only deterministic requests, made with a temperature of 0, are cached,
+//! and only final answers: a reply calling tools would have them run again
+//! without the model being asked. The stored request is redacted before
+//! it is written, and replies holding a secret aren't cached at all, since
+//! a redacted answer would read differently from the one given. Replies
+//! expire after `cache_ttl_secs`; `--no-cache` bypasses the cache and
+//! `aido cache clear` empties it.
+
+use std::borrow::Cow;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::llm::{LlmResponse, ToolCall, Usage};
+use crate::redact::Redactor;
+
+/// Name of the cache directory inside the config directory
+const CACHE_DIR_NAME: &str = "cache";
+
+/// How long replies are kept unless `cache_ttl_secs` says otherwise
+pub const DEFAULT_TTL: Duration = Duration::from_hours(24);
+
+/// What a reply is stored under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    /// SHA-256 of the endpoint and the request
+    digest: String,
+    /// The request, kept in the entry to show what it answers
+    request: Value,
+}
+
+impl Key {
+    /// The key of `request` sent to `endpoint`, or `None` when the request
+    /// isn't deterministic and its reply shouldn't be reused
+    pub fn new(endpoint: &str, request: &Value) -> Option<Self> {
+        let deterministic = request
+            .get("temperature")
+            .and_then(Value::as_f64)
+            .is_some_and(|temperature| temperature.abs() < f64::EPSILON);
+        if !deterministic {
+            return None;
+        }
+
+        let keyed = json!({ "endpoint": endpoint, "request": request });
+        let digest = Sha256::digest(keyed.to_string().as_bytes());
+
+        Some(Self { digest: format!("{digest:x}"), request: request.clone() })
+    }
+}
+
+/// A cached reply, as stored on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// Seconds since the Unix epoch when the reply was stored
+    created: u64,
+    /// The digest of the key, checked on reading
+    digest: String,
+    /// The request the reply answers, redacted
+    request: Value,
+    /// The reply, never holding a secret
+    text: String,
+    tool_calls: Vec<ToolCall>,
+    #[serde(default)]
+    reasoning: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Replies to earlier requests, one file per request
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+    /// Masks secrets in what is written
+    redactor: Redactor,
+}
+
+impl ResponseCache {
+    /// Opens the cache stored next to the given config file
+    pub fn for_config_file(config_file_path: &str) -> Self {
+        let dir = Path::new(config_file_path)
+            .parent()
+            .expect("Config file path should have a parent directory")
+            .join(CACHE_DIR_NAME);
+
+        Self::new(dir)
+    }
+
+    /// Opens the cache in `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into(), ttl: DEFAULT_TTL, redactor: Redactor::new() }
+    }
+
+    /// Masks the secrets `redactor` knows in every stored entry
+    #[must_use]
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Ignores replies stored longer than `ttl` ago
+    #[must_use]
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The directory the replies are stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &Key) -> PathBuf {
+        self.dir.join(format!("{}.json", key.digest))
+    }
+
+    /// The stored reply under `key`, unless there is none or it expired
+    ///
+    /// Cached replies report no usage, since no tokens were spent on them.
+    pub fn get(&self, key: &Key) -> Option<LlmResponse> {
+        let content = std::fs::read_to_string(self.path(key)).ok()?;
+        let entry = serde_json::from_str::<Entry>(&content).ok()?;
+
+        let age = now().saturating_sub(entry.created);
+        (entry.digest == key.digest && age < self.ttl.as_secs()).then(|| {
+            LlmResponse::new(entry.text, Usage::default(), entry.tool_calls)
+                .with_reasoning(entry.reasoning)
+        })
+    }
+
+    /// Stores `response` as the reply under `key`, unless it calls tools or
+    /// holds a secret
+    pub fn put(&self, key: &Key, response: &LlmResponse) -> io::Result<()> {
+        let redacts =
+            |text| matches!(self.redactor.redact(text), Cow::Owned(_));
+        if !response.tool_calls().is_empty()
+            || redacts(response.text())
+            || redacts(response.reasoning())
+        {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir)?;
+
+        let mut request = key.request.clone();
+        self.redactor.redact_json(&mut request);
+        let entry = Entry {
+            created: now(),
+            digest: key.digest.clone(),
+            request,
+            text: response.text().to_owned(),
+            tool_calls: Vec::new(),
+            reasoning: response.reasoning().to_owned(),
+        };
+
+        // Written aside first so that readers never see half an entry
+        let path = self.path(key);
+        let partial =
+            path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&partial, serde_json::to_vec(&entry)?)?;
+        std::fs::rename(partial, path)
+    }
+
+    /// Removes every stored reply, returning how many there were
+    pub fn clear(&self) -> io::Result<usize> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                std::fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(test: &str) -> ResponseCache {
+        ResponseCache::new(
+            std::env::temp_dir()
+                .join(format!("aido-{test}-test-{}", std::process::id())),
+        )
+    }
+
+    fn key(request: &Value) -> Key {
+        Key::new("https://api.example.com/v1/chat/completions", request)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cache = cache("cache");
+        let request =
+            json!({ "model": "m", "temperature": 0, "messages": [1] });
+        let other = json!({ "model": "m", "temperature": 0, "messages": [2] });
+        assert!(cache.get(&key(&request)).is_none());
+
+        let response = LlmResponse::new("hi", Usage::new(10, 2, 12), vec![]);
+        cache.put(&key(&request), &response).unwrap();
+
+        let cached = cache.get(&key(&request)).unwrap();
+        assert_eq!(cached.text(), "hi");
+        assert_eq!(cached.usage().total_tokens(), 0);
+        assert!(cache.get(&key(&other)).is_none());
+
+        assert_eq!(cache.clear().unwrap(), 1);
+        assert!(cache.get(&key(&request)).is_none());
+        assert_eq!(cache.clear().unwrap(), 0);
+
+        std::fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_only_deterministic_answers_are_cached() {
+        let cache = cache("cache-deterministic");
+        let endpoint = "https://api.example.com/v1/chat/completions";
+
+        assert!(Key::new(endpoint, &json!({ "temperature": 0.7 })).is_none());
+        assert!(Key::new(endpoint, &json!({ "model": "m" })).is_none());
+
+        // The same request to another endpoint is another request
+        let request = json!({ "model": "m", "temperature": 0.0 });
+        let elsewhere = Key::new("http://localhost:8080", &request).unwrap();
+        assert_ne!(key(&request), elsewhere);
+
+        let calling = LlmResponse::new(
+            "",
+            Usage::default(),
+            vec![ToolCall::new("call_1", "ls", "{}")],
+        );
+        cache.put(&key(&request), &calling).unwrap();
+        assert!(cache.get(&key(&request)).is_none());
+        assert_eq!(cache.clear().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_entries_are_redacted() {
+        let cache = cache("cache-redacted")
+            .with_redactor(Redactor::new().with_secret("hunter2"));
+        let request = json!({
+            "temperature": 0,
+            "messages": [{ "role": "user", "content": "log in as hunter2" }],
+        });
+
+        // A redacted answer would read differently, so it isn't kept
+        cache
+            .put(
+                &key(&request),
+                &LlmResponse::new("hunter2", Usage::default(), vec![]),
+            )
+            .unwrap();
+        assert!(cache.get(&key(&request)).is_none());
+
+        cache
+            .put(
+                &key(&request),
+                &LlmResponse::new("logged in", Usage::default(), vec![]),
+            )
+            .unwrap();
+
+        let stored =
+            std::fs::read_to_string(cache.path(&key(&request))).unwrap();
+        assert!(!stored.contains("hunter2"));
+        assert_eq!(cache.get(&key(&request)).unwrap().text(), "logged in");
+
+        std::fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_expired_replies_are_ignored() {
+        let cache = cache("cache-ttl").with_ttl(Duration::ZERO);
+        let request = json!({ "model": "m", "temperature": 0 });
+
+        cache.put(&key(&request), &LlmResponse::default()).unwrap();
+
+        assert!(cache.get(&key(&request)).is_none());
+
+        std::fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+//! Cancelling runs that are in flight
+//!
+//! A run given a [`CancelToken`] through
+//! [`RunOptions::cancel`](crate::run::RunOptions::cancel) stops when the
+//! token is cancelled, as the command line does on Ctrl-C: the request
+//! to the model is aborted, a running tool is dropped along with
+//! any process it started, and the run returns what it had so far, marked
+//! as cancelled. Applications running several runs at once, such as an
+//! editor integration with a stop button, keep their tokens in a
+//! [`RunRegistry`] to cancel them by id, as [`crate::serve`] does for the
+//! `cancel` requests of `aido serve`.
+
+use std::collections::HashMap;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use thiserror::Error;
+use tokio::sync::Notify;
+
+#[derive(Error, Debug)]
+pub enum CancelError {
+    #[error("A run with id '{id}' is already in progress")]
+    DuplicateId { id: String },
+}
+
+#[derive(Debug, Default)]
+struct Signal {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Tells a run to stop; clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    signal: Arc<Signal>,
+}
+
+impl CancelToken {
+    /// Creates a token that isn't cancelled yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the runs using this token; they stop at the next chance
+    pub fn cancel(&self) {
+        self.signal.cancelled.store(true, Ordering::SeqCst);
+        self.signal.notify.notify_waiters();
+    }
+
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        self.signal.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the token is cancelled
+    pub async fn cancelled(&self) {
+        let mut notified = pin!(self.signal.notify.notified());
+        // Registered before checking, so a cancel in between isn't missed
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+/// The runs in flight, by id
+#[derive(Debug, Clone, Default)]
+pub struct RunRegistry {
+    runs: Arc<Mutex<HashMap<String, CancelToken>>>,
+}
+
+impl RunRegistry {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, CancelToken>> {
+        self.runs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Registers a run under `id` until the returned handle is dropped
+    pub fn start(
+        &self,
+        id: impl Into<String>,
+    ) -> Result<RunHandle, CancelError> {
+        let id = id.into();
+        let token = CancelToken::new();
+
+        let mut runs = self.lock();
+        if runs.contains_key(&id) {
+            return Err(CancelError::DuplicateId { id });
+        }
+        runs.insert(id.clone(), token.clone());
+        drop(runs);
+
+        Ok(RunHandle { registry: self.clone(), id, token })
+    }
+
+    /// Cancels the run registered under `id`, returning whether there was
+    /// one
+    pub fn cancel(&self, id: &str) -> bool {
+        self.lock().get(id).map(CancelToken::cancel).is_some()
+    }
+
+    /// The ids of the runs in flight, sorted
+    #[must_use]
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids = self.lock().keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+}
+
+/// A run registered in a [`RunRegistry`], removed from it when dropped
+#[derive(Debug)]
+pub struct RunHandle {
+    registry: RunRegistry,
+    id: String,
+    token: CancelToken,
+}
+
+impl RunHandle {
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The token to give the run, cancelled by [`RunRegistry::cancel`]
+    #[must_use]
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+impl Drop for RunHandle {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_cancels_by_id() {
+        let registry = RunRegistry::default();
+        let first = registry.start("first").unwrap();
+        let second = registry.start("second").unwrap();
+        assert!(matches!(
+            registry.start("first"),
+            Err(CancelError::DuplicateId { .. })
+        ));
+        assert_eq!(registry.ids(), ["first", "second"]);
+
+        assert!(registry.cancel("first"));
+        assert!(first.token().is_cancelled());
+        assert!(!second.token().is_cancelled());
+
+        drop(first);
+        assert!(!registry.cancel("first"));
+        assert_eq!(registry.ids(), ["second"]);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wakes_waiters() {
+        let token = CancelToken::new();
+        let waiter = {
+            let token = token.clone();
+            tokio::spawn(async move { token.cancelled().await })
+        };
+        tokio::task::yield_now().await;
+
+        token.cancel();
+        waiter.await.unwrap();
+
+        // Already cancelled tokens don't wait at all
+        token.cancelled().await;
+    }
+}
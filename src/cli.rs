@@ -1,6 +1,20 @@
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+
+use aido::{
+    config::RequestParams,
+    error::{AidoError, AidoResult},
+    output::{OutputFile, OutputFormat},
+    session::{DEFAULT_KEEP_RECENT, export::ExportFormat},
+    shell::env::{DEFAULT_RECIPE, ShellKind},
+};
 use clap::{Parser, Subcommand};
 
+/// Heading of the options setting the parameters of requests in `--help`
+const REQUEST_HEADING: &str = "Request parameters";
+
 #[derive(Parser)]
+#[allow(clippy::struct_excessive_bools)]
 #[command(name = "aido")]
 #[command(version = "1.0.0")]
 #[command(about = "A sample AI assistant application")]
@@ -10,22 +24,169 @@ pub struct Args {
     #[arg(short, long, global = true)]
     verbose: bool,
 
-    /// Print token usage after responding
+    /// Print token usage and latency after responding
     #[arg(short, long, global = true)]
     usage: bool,
 
     #[arg(short, long, global = true)]
     config_file: Option<String>,
 
+    /// Use the connection settings of a `[profiles.NAME]` config section,
+    /// instead of the one named by `AIDO_PROFILE`
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Maximum number of tool-calling round trips before giving up
+    #[arg(long, global = true)]
+    max_iterations: Option<usize>,
+
+    /// Sampling temperature, over the config's and the recipe's
+    #[arg(long, global = true, help_heading = REQUEST_HEADING)]
+    temperature: Option<f32>,
+
+    /// Sample only from the tokens in this top probability mass
+    #[arg(long, global = true, help_heading = REQUEST_HEADING)]
+    top_p: Option<f32>,
+
+    /// Maximum number of tokens generated per response
+    #[arg(long, global = true, help_heading = REQUEST_HEADING)]
+    max_tokens: Option<u32>,
+
+    /// Penalty, from -2 to 2, on tokens by how often they already appear
+    #[arg(
+        long,
+        global = true,
+        allow_negative_numbers = true,
+        help_heading = REQUEST_HEADING
+    )]
+    frequency_penalty: Option<f32>,
+
+    /// Penalty, from -2 to 2, on tokens that already appear at all
+    #[arg(
+        long,
+        global = true,
+        allow_negative_numbers = true,
+        help_heading = REQUEST_HEADING
+    )]
+    presence_penalty: Option<f32>,
+
+    /// Stop generating at this sequence; may be repeated up to 4 times
+    #[arg(
+        long,
+        global = true,
+        value_name = "SEQUENCE",
+        help_heading = REQUEST_HEADING
+    )]
+    stop: Vec<String>,
+
+    /// Seed, for providers that sample the same answer to the same request
+    #[arg(
+        long,
+        global = true,
+        allow_negative_numbers = true,
+        help_heading = REQUEST_HEADING
+    )]
+    seed: Option<i64>,
+
+    /// Request each reply whole instead of streaming it, for servers that
+    /// don't support streaming
+    #[arg(long, global = true, help_heading = REQUEST_HEADING)]
+    no_stream: bool,
+
+    /// Run tools in a temporary git worktree and show the resulting diff
+    #[arg(long, global = true)]
+    isolated: bool,
+
+    /// Print a timestamped outline of the run's steps when it finishes
+    #[arg(long, global = true)]
+    trace: bool,
+
+    /// Copy the answer, or its first code block, to the clipboard
+    #[arg(long, global = true)]
+    copy: bool,
+
+    /// Print the request that would be sent, as JSON, without calling the
+    /// API
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Always call the model, instead of answering requests made before
+    /// from the response cache. The cache is off unless `cache_ttl_secs`
+    /// is set in the config, and only requests made with a temperature of
+    /// 0 are cached
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Print the reasoning of reasoning models to stderr as it streams in
+    #[arg(long, global = true)]
+    show_reasoning: bool,
+
+    /// Stream the reply to stderr, when it is a terminal, and print only
+    /// the final answer to stdout; the default when stdout isn't a
+    /// terminal
+    #[arg(long, global = true)]
+    quiet_stream: bool,
+
+    /// Make runs as reproducible as possible, for CI: temperature 0, a
+    /// fixed seed, no fallback models and no response cache. Fails when
+    /// the provider doesn't report which backend answered
+    #[arg(long, global = true)]
+    deterministic: bool,
+
+    /// Fail on recipe headers that aren't valid YAML or have unknown keys,
+    /// instead of warning about them
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Leave the project's .aido.md and AGENTS.md out of the system prompt
+    #[arg(long, global = true)]
+    no_project_context: bool,
+
+    /// How to present the result of a run
+    #[arg(long, value_enum, global = true, default_value_t)]
+    output: OutputFormat,
+
+    /// Also write the final answer to this file
+    #[arg(long, global = true, value_name = "FILE")]
+    output_file: Option<PathBuf>,
+
+    /// Write everything printed during the run to the output file, tool
+    /// call notes and earlier replies included, not only the final answer
+    #[arg(long, global = true, requires = "output_file")]
+    tee: bool,
+
+    /// Record every request to the model and its reply, with the config
+    /// and tools, to this file for `aido replay`
+    #[arg(long, global = true, value_name = "FILE")]
+    record: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 
-    #[arg(short, long)]
+    /// Message to send, or '-' to read it from standard input until EOF;
+    /// with `run` and `tokens`, the recipe's user message, and with
+    /// `embed`, the text to embed
+    #[arg(short, long, global = true, value_name = "MESSAGE")]
     input: Option<String>,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
+    /// Write a config file for a local server that is running, Ollama or
+    /// LM Studio, or else for `OpenAI`
+    Init {
+        /// Use the OpenAI-compatible API at this URL instead
+        #[arg(long, value_name = "URL")]
+        api_url: Option<String>,
+
+        /// Model to use, instead of the first the local server serves
+        #[arg(long)]
+        model: Option<String>,
+
+        /// Replace the config file if there is one
+        #[arg(long)]
+        force: bool,
+    },
     /// Configuration-related commands
     Config {
         #[command(subcommand)]
@@ -36,14 +197,229 @@ pub enum Commands {
         #[command(subcommand)]
         command: RecipeCommands,
     },
+    /// Usage and cost tracking commands
+    Usage {
+        #[command(subcommand)]
+        command: UsageCommands,
+    },
+    /// Named pipelines of recipe steps
+    Workflow {
+        #[command(subcommand)]
+        command: WorkflowCommands,
+    },
+    /// Stored conversation commands
+    Session {
+        #[command(subcommand)]
+        command: SessionCommands,
+    },
+    /// Review the tools the assistant has run
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommands,
+    },
+    /// Response cache commands
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// List the prompts given with `--input` and the recipes run, with
+    /// the numbers `rerun` takes
+    History {
+        /// Number of prompts to show
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Run a prompt from the history again with the current settings
+    #[command(alias = "!!")]
+    Rerun {
+        /// Number of the prompt in `aido history`; defaults to the last
+        number: Option<usize>,
+    },
+    /// Run a conversation recorded with `--record` again, answering from
+    /// the recording instead of the model and tools
+    Replay {
+        /// The file written by `--record`
+        bundle: PathBuf,
+    },
+    /// Show where aido keeps its config, recipes and other files
+    Paths {
+        /// Copy recipes from directories earlier versions used into the
+        /// recipes directory
+        #[arg(long)]
+        migrate: bool,
+    },
+    /// Print the JSON Schema of what `--output json` prints for a run
+    Schema,
+    /// List the models the configured endpoint serves, marking the one in
+    /// use
+    Models,
+    /// Count the tokens a run of a recipe would send, without sending
+    /// anything
+    Tokens {
+        /// Name of the recipe
+        recipe: String,
+
+        /// The user message to count along with the recipe
+        #[arg(conflicts_with = "input")]
+        user_message: Option<String>,
+
+        /// Set a recipe template variable; may be repeated
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+    /// Run the last message again with the current recipe and model
+    /// settings, and show how the answer changed word by word
+    DiffLast {
+        /// Set a recipe template variable; may be repeated
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+    /// Ask a question about files in the current directory
+    Ask {
+        /// Glob selecting files to include, e.g. 'src/**/*.rs'; may be
+        /// repeated or comma-separated. When omitted, the files most
+        /// relevant to the question are picked automatically
+        #[arg(long, value_delimiter = ',')]
+        files: Vec<String>,
+
+        /// Maximum estimated tokens of file content to include; defaults
+        /// to `context_budget` from the config
+        #[arg(long)]
+        budget: Option<usize>,
+
+        /// The question to ask
+        question: String,
+    },
+    /// Send a prompt to several models at once and show their answers
+    /// side by side, with the latency, usage and cost of each
+    Compare {
+        /// Models to ask, comma-separated; `model@profile` asks through
+        /// the endpoint of a profile, and the name of a profile asks its
+        /// model
+        #[arg(long, value_delimiter = ',', required = true)]
+        models: Vec<String>,
+
+        /// The prompt to send
+        prompt: String,
+    },
+    /// Print the embeddings of texts as JSON, one vector per text
+    Embed {
+        /// Files whose contents to embed, or '-' for standard input; with
+        /// neither files nor `--input`, standard input is embedded
+        files: Vec<PathBuf>,
+
+        /// Embedding model; defaults to `embedding_model` from the config
+        #[arg(long)]
+        model: Option<String>,
+
+        /// Number of dimensions to shorten the vectors to, for models
+        /// that can
+        #[arg(long)]
+        dimensions: Option<u32>,
+    },
+    /// Embed the text files under a directory into the local index, which
+    /// runs search with the `retrieve` tool
+    Index {
+        /// Directory of notes or docs to index; indexing it again only
+        /// embeds the files that changed
+        dir: PathBuf,
+
+        /// Embedding model; defaults to `embedding_model` from the config
+        #[arg(long)]
+        model: Option<String>,
+    },
+    /// Print shell functions binding Ctrl-X Ctrl-A to replacing the
+    /// command line with the command a recipe suggests for it; add
+    /// `eval "$(aido shellenv --shell bash)"` to ~/.bashrc
+    Shellenv {
+        /// Shell to write the functions for
+        #[arg(long, value_enum)]
+        shell: ShellKind,
+
+        /// Recipe the command line is sent to
+        #[arg(long, default_value = DEFAULT_RECIPE)]
+        recipe: String,
+    },
+    /// Answer JSON-RPC requests to ask questions, run recipes and cancel
+    /// them, one per line on standard input, for editor integrations
+    Serve,
     /// Run a recipe
     Run {
         /// Name of the recipe to run
         recipe: String,
 
         /// An optional user message to include, if required by the recipe
+        #[arg(conflicts_with = "input")]
         user_message: Option<String>,
+
+        /// Run another recipe on the answer; may be repeated
+        #[arg(long, value_name = "RECIPE")]
+        then: Vec<String>,
+
+        /// Set a recipe template variable; may be repeated
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+
+        /// Run the command suggested in the answer after confirming it
+        #[arg(long)]
+        exec: bool,
+
+        /// Keep the conversation going after the answer, reading follow-up
+        /// messages until Ctrl-D
+        #[arg(long, conflicts_with_all = ["then", "exec"])]
+        interactive: bool,
+    },
+    /// Write a commit message for the staged changes
+    Commit {
+        /// Commit with the message after confirming it
+        #[arg(long)]
+        apply: bool,
     },
+    /// Run recipes on every line of a file and report on all the runs
+    Batch {
+        /// Recipe to run, or a glob like 'lint-*' selecting several; may be
+        /// repeated
+        #[arg(long = "recipe", value_name = "GLOB", required = true)]
+        recipes: Vec<String>,
+
+        /// File with one input item per line, or '-' for standard input
+        #[arg(long, value_name = "FILE")]
+        each: PathBuf,
+
+        /// Set a recipe template variable; may be repeated
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+}
+
+impl Commands {
+    /// Whether the command uses the settings in the config file, rather
+    /// than only where it is
+    pub const fn needs_config(&self) -> bool {
+        !matches!(
+            self,
+            Self::Init { .. }
+                | Self::Paths { .. }
+                | Self::Schema
+                | Self::Shellenv { .. }
+                | Self::History { .. }
+                | Self::Replay { .. }
+                | Self::Config { command: ConfigCommands::ShowPath }
+                | Self::Recipe {
+                    command: RecipeCommands::List
+                        | RecipeCommands::Show { .. }
+                        | RecipeCommands::ShowDir
+                }
+                | Self::Workflow { command: WorkflowCommands::List }
+                | Self::Session {
+                    command: SessionCommands::List
+                        | SessionCommands::Show { .. }
+                        | SessionCommands::Export { .. }
+                }
+                | Self::Audit { .. }
+                | Self::Usage { .. }
+        )
+    }
 }
 
 #[derive(Subcommand)]
@@ -71,6 +447,173 @@ pub enum RecipeCommands {
 
     /// Create a new recipe
     Create { name: String },
+
+    /// Check recipes for malformed headers, unknown header keys, tools
+    /// that aren't available and undeclared template variables
+    Validate {
+        /// Name of the recipe to check
+        #[arg(required_unless_present = "all")]
+        name: Option<String>,
+
+        /// Check every recipe
+        #[arg(long, conflicts_with = "name")]
+        all: bool,
+    },
+
+    /// Check a recipe and write it, with its metadata, to a signed
+    /// `.aidorecipe` file for sharing
+    Package {
+        /// Name of the recipe to package
+        name: String,
+
+        /// Author of the recipe; defaults to the git `user.name`
+        #[arg(long)]
+        author: Option<String>,
+
+        /// License of the recipe, e.g. MIT
+        #[arg(long)]
+        license: String,
+
+        /// Version of the recipe, e.g. 1.0.0
+        #[arg(long)]
+        version: String,
+
+        /// Oldest aido version able to run the recipe; defaults to this
+        /// one
+        #[arg(long, value_name = "VERSION")]
+        min_aido_version: Option<String>,
+
+        /// Where to write the package; defaults to `<name>.aidorecipe`
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /// Install a recipe or package from an http(s) URL, or every recipe
+    /// in a git repository
+    Install {
+        /// URL of a `.recipe` or `.aidorecipe` file, or of a git
+        /// repository, such as `git@host:team/recipes.git`
+        source: String,
+
+        /// Replace recipes of the same name installed from elsewhere or
+        /// edited since
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Fetch installed recipes again from where they were installed from
+    Update {
+        /// Names of the recipes to update; all installed ones by default
+        names: Vec<String>,
+
+        /// Replace recipes edited since they were installed, or whose
+        /// package is signed with another key now
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Trust the hooks of an installed recipe to run, as they are now
+    Trust {
+        /// Name of the installed recipe
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum WorkflowCommands {
+    /// List the workflows
+    List,
+
+    /// Run the steps of a workflow in order
+    Run {
+        /// Name of the workflow to run
+        name: String,
+
+        /// Input for the first step, and `{{ input }}` for every step
+        #[arg(conflicts_with = "input")]
+        user_message: Option<String>,
+
+        /// Set a template variable; may be repeated
+        #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+        vars: Vec<(String, String)>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SessionCommands {
+    /// List stored sessions
+    List,
+
+    /// Print what a session was run with, followed by its transcript
+    Show {
+        /// Id of the session to show
+        id: String,
+    },
+
+    /// Write a session, with its tool calls and their output, as a
+    /// Markdown transcript or as JSON, with secrets masked
+    Export {
+        /// Id of the session to export
+        id: String,
+
+        /// Format to export to
+        #[arg(long, value_enum, default_value_t)]
+        format: ExportFormat,
+
+        /// File to write to instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /// Ask for the last answer of a session again, saving the new answer
+    /// as a branch of it; `--temperature` and the other request options
+    /// apply
+    Retry {
+        /// Id of the session to retry
+        id: String,
+
+        /// Instruction to add to the last message, such as "Be brief."
+        #[arg(long)]
+        instruction: Option<String>,
+    },
+
+    /// Replace the older turns of a session with a summary, archiving the
+    /// full original
+    Compact {
+        /// Id of the session to compact
+        id: String,
+
+        /// Number of most recent messages to keep verbatim
+        #[arg(long, default_value_t = DEFAULT_KEEP_RECENT)]
+        keep: usize,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommands {
+    /// Show the most recent tool invocations, oldest first
+    Show {
+        /// Number of invocations to show
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommands {
+    /// Remove every cached response
+    Clear,
+}
+
+#[derive(Subcommand)]
+pub enum UsageCommands {
+    /// Show cumulative token usage and estimated cost per model
+    Report {
+        /// Break down the last weeks per model and recipe, with cost
+        /// trends and average run time
+        #[arg(long)]
+        weekly: bool,
+    },
 }
 
 impl Args {
@@ -90,7 +633,184 @@ impl Args {
         self.input.as_deref()
     }
 
+    /// Reads the message from standard input when `--input -` asks for
+    /// it, and hands it to `run` and `tokens` as the recipe's user message;
+    /// one-off chats and `embed` find it in [`Args::input`]
+    pub fn read_input(&mut self) -> AidoResult<()> {
+        if self.input.as_deref() == Some("-") {
+            self.input = Some(read_stdin_message()?);
+        }
+
+        match (&mut self.command, self.input.take()) {
+            (_, None) => {}
+            (None | Some(Commands::Embed { .. }), input) => self.input = input,
+            (
+                Some(
+                    Commands::Run { user_message, .. }
+                    | Commands::Tokens { user_message, .. }
+                    | Commands::Workflow {
+                        command: WorkflowCommands::Run { user_message, .. },
+                    },
+                ),
+                input,
+            ) => *user_message = input,
+            (Some(_), Some(_)) => {
+                return Err(AidoError::Usage(
+                    "--input only applies to one-off chats, `aido run`, \
+                     `aido tokens`, `aido embed` and `aido workflow run`"
+                        .to_owned(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn usage(&self) -> bool {
         self.usage
     }
+
+    pub fn max_iterations(&self) -> Option<usize> {
+        self.max_iterations
+    }
+
+    /// The request parameters given on the command line
+    pub fn request_params(&self) -> RequestParams {
+        RequestParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            stop: (!self.stop.is_empty()).then(|| self.stop.clone()),
+            seed: self.seed,
+            stream: self.no_stream.then_some(false),
+        }
+    }
+
+    pub fn isolated(&self) -> bool {
+        self.isolated
+    }
+
+    pub fn trace(&self) -> bool {
+        self.trace
+    }
+
+    pub fn copy(&self) -> bool {
+        self.copy
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn no_cache(&self) -> bool {
+        self.no_cache
+    }
+
+    /// The file to write the answer or transcript to, if any
+    pub fn output_file(&self) -> Option<OutputFile> {
+        self.output_file.as_ref().map(|path| {
+            if self.tee {
+                OutputFile::transcript(path)
+            } else {
+                OutputFile::answer(path)
+            }
+        })
+    }
+
+    pub fn record(&self) -> Option<&Path> {
+        self.record.as_deref()
+    }
+
+    pub fn deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn no_project_context(&self) -> bool {
+        self.no_project_context
+    }
+
+    pub fn show_reasoning(&self) -> bool {
+        self.show_reasoning
+    }
+
+    pub fn quiet_stream(&self) -> bool {
+        self.quiet_stream
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn output(&self) -> OutputFormat {
+        self.output
+    }
+}
+
+/// Reads a message from standard input until EOF, telling the user how to
+/// end it when it is typed at a terminal
+fn read_stdin_message() -> AidoResult<String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        eprintln!("Type the message, then press Ctrl-D on an empty line:");
+    }
+
+    let mut message = String::new();
+    stdin.lock().read_to_string(&mut message)?;
+    let message = message.trim_end();
+    if message.trim().is_empty() {
+        return Err("No message was read from standard input".into());
+    }
+
+    Ok(message.to_owned())
+}
+
+/// Parses a `NAME=VALUE` pair given to `--var`
+fn parse_var(arg: &str) -> Result<(String, String), String> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{arg}'"))?;
+
+    Ok((name.trim().to_owned(), value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use aido::shell::env;
+
+    use super::*;
+
+    #[test]
+    fn test_shellenv_invocation_parses() {
+        let mut command_line = vec!["aido".to_owned()];
+        command_line.extend(env::invocation("do"));
+        command_line.push("--list files".to_owned());
+        let args = Args::try_parse_from(command_line).unwrap();
+
+        assert_eq!(args.output, OutputFormat::Command);
+        assert!(matches!(
+            args.command(),
+            Some(Commands::Run { recipe, user_message: Some(message), .. })
+                if recipe == "do" && message == "--list files"
+        ));
+    }
+
+    #[test]
+    fn test_input_is_left_for_embed() {
+        let mut args =
+            Args::try_parse_from(["aido", "-i", "some text", "embed"])
+                .unwrap();
+        args.read_input().unwrap();
+        assert_eq!(args.input(), Some("some text"));
+
+        let mut args =
+            Args::try_parse_from(["aido", "-i", "some text", "models"])
+                .unwrap();
+        assert!(args.read_input().is_err());
+    }
 }
@@ -0,0 +1,82 @@
+//! Putting the answer of a run on the system clipboard
+//!
+//! Rather than linking a clipboard library, the platform's own command line
+//! tool is used: `pbcopy` on macOS, `clip` on Windows, and `wl-copy`,
+//! `xclip` or `xsel` on Linux, whichever is installed.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use thiserror::Error;
+
+use crate::markdown;
+
+/// Clipboard programs to try, in order, with their arguments
+const PROGRAMS: &[(&str, &[&str])] = &[
+    ("pbcopy", &[]),
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+    ("clip", &[]),
+];
+
+#[derive(Error, Debug)]
+pub enum ClipboardError {
+    #[error(
+        "No clipboard program found (install one of pbcopy, wl-copy, xclip \
+         or xsel)"
+    )]
+    Unavailable,
+
+    #[error("'{program}' failed to copy to the clipboard")]
+    Failed { program: &'static str },
+}
+
+/// Copies `text` to the clipboard using the first program that is installed
+pub fn copy(text: &str) -> Result<(), ClipboardError> {
+    for (program, args) in PROGRAMS {
+        let Ok(mut child) = Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            continue;
+        };
+
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+
+        return match child.wait() {
+            Ok(status) if status.success() && written => Ok(()),
+            _ => Err(ClipboardError::Failed { program }),
+        };
+    }
+
+    Err(ClipboardError::Unavailable)
+}
+
+/// The part of an answer worth copying: its first code block when it has
+/// one, since that is usually the command or snippet asked for, and the
+/// whole answer otherwise
+pub fn selection(answer: &str) -> &str {
+    markdown::code_blocks(answer)
+        .first()
+        .copied()
+        .unwrap_or_else(|| answer.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selection() {
+        assert_eq!(selection("```\ngit status\n```"), "git status");
+        assert_eq!(selection("  git status\n"), "git status");
+        assert_eq!(selection("```\nls\n```\n```\npwd\n```"), "ls");
+    }
+}
@@ -0,0 +1,177 @@
+//! Writing commit messages for staged changes
+//!
+//! `aido commit` hands the staged diff to the `commit` recipe and prints
+//! the message it comes up with. With `--apply`, the message is shown once
+//! more and committed with `git commit -m` after the user confirms it. A
+//! `commit` recipe in the recipes directory takes the place of the bundled
+//! one, for projects with conventions of their own.
+
+use std::io;
+use std::process::{Command, ExitStatus};
+
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::confirm;
+use crate::context;
+use crate::error::AidoResult;
+use crate::markdown;
+use crate::recipe::{Recipe, RecipeError, RecipeStore};
+use crate::run::{self, RunOptions, RunOutcome};
+use crate::tools::Tool;
+
+/// Name of the recipe used to write commit messages
+pub const RECIPE_NAME: &str = "commit";
+
+/// The recipe used when the recipes directory has no `commit` recipe
+const BUNDLED_RECIPE: &str = include_str!("../sample-recipes/commit.recipe");
+
+#[derive(Error, Debug)]
+pub enum CommitError {
+    #[error("Nothing is staged for commit; stage changes with `git add`")]
+    NothingStaged,
+
+    #[error("git {command} failed: {stderr}")]
+    Git { command: String, stderr: String },
+
+    #[error("Could not run git: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("The answer does not contain a commit message")]
+    EmptyMessage,
+}
+
+/// The changes staged for commit in the current repository, as a diff
+pub fn staged_diff() -> Result<String, CommitError> {
+    let output = Command::new("git")
+        .args([
+            "--no-pager",
+            "diff",
+            "--cached",
+            "--no-color",
+            "--no-ext-diff",
+        ])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(CommitError::Git {
+            command: "diff".to_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+
+    let diff = String::from_utf8_lossy(&output.stdout).into_owned();
+    if diff.trim().is_empty() {
+        return Err(CommitError::NothingStaged);
+    }
+
+    Ok(diff)
+}
+
+/// The `commit` recipe from `store`, or the bundled one if there is none
+pub fn recipe(store: &RecipeStore) -> Result<Recipe, RecipeError> {
+    match store.get(RECIPE_NAME) {
+        Err(RecipeError::NotFound { .. }) => Recipe::parse(BUNDLED_RECIPE),
+        result => result,
+    }
+}
+
+/// Runs the `commit` recipe on the staged changes
+///
+/// Diffs longer than the configured attachment limit keep their start and
+/// end.
+pub async fn suggest_message(
+    config: &Config,
+    store: &RecipeStore,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+) -> AidoResult<RunOutcome> {
+    let diff = staged_diff()?;
+    let limit = config
+        .attachment_token_limit
+        .unwrap_or(context::DEFAULT_ATTACHMENT_TOKEN_LIMIT);
+    let diff = context::truncate_middle(&diff, limit).into_owned();
+
+    let recipe = recipe(store)?;
+
+    Box::pin(run::run_loaded_recipe(
+        config.clone(),
+        &recipe,
+        RECIPE_NAME,
+        Some(diff),
+        tools,
+        options,
+    ))
+    .await
+}
+
+/// The commit message in `answer`: the code block if the model put the
+/// message in one anyway, or else the whole answer
+pub fn message_from_answer(answer: &str) -> Result<&str, CommitError> {
+    let answer = answer.trim();
+    let message = match markdown::code_blocks(answer).as_slice() {
+        [block] if answer.starts_with("```") => block.trim(),
+        _ => answer,
+    };
+
+    if message.is_empty() {
+        return Err(CommitError::EmptyMessage);
+    }
+
+    Ok(message)
+}
+
+/// Shows `message` and commits the staged changes with it if the user
+/// agrees, returning the exit status of `git commit`, or `None` when the
+/// user declined
+pub fn confirm_and_commit(
+    message: &str,
+) -> Result<Option<ExitStatus>, CommitError> {
+    eprintln!("\n    {}\n", message.replace('\n', "\n    "));
+
+    if !confirm::ask_on_terminal("Commit with this message?")? {
+        return Ok(None);
+    }
+
+    Ok(Some(Command::new("git").args(["commit", "-m", message]).status()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled_recipe_parses() {
+        let store = RecipeStore::new(
+            std::env::temp_dir()
+                .join(format!("aido-commit-test-{}", std::process::id())),
+        );
+
+        let recipe = recipe(&store).unwrap();
+
+        assert_eq!(recipe.header().name(), RECIPE_NAME);
+        assert!(recipe.body().contains("commit message"));
+    }
+
+    #[test]
+    fn test_message_from_answer() {
+        assert_eq!(
+            message_from_answer("Fix typo in README\n").unwrap(),
+            "Fix typo in README"
+        );
+        assert_eq!(
+            message_from_answer("```\nAdd retries\n\nBody text.\n```")
+                .unwrap(),
+            "Add retries\n\nBody text."
+        );
+        // A message that merely mentions code keeps its backticks
+        assert_eq!(
+            message_from_answer("Rename `run`\n\n```\nrun()\n```").unwrap(),
+            "Rename `run`\n\n```\nrun()\n```"
+        );
+        assert!(matches!(
+            message_from_answer("  \n"),
+            Err(CommitError::EmptyMessage)
+        ));
+    }
+}
@@ -0,0 +1,388 @@
+//! Sending the same prompt to several models at once
+//!
+//! `aido compare "prompt" --models gpt-4o-mini,llama3.2:3b@local` sends
+//! one request to each model concurrently and prints their answers side by
+//! side, with how long each took, its usage and its estimated cost. A
+//! model is asked through the configured endpoint, unless it names a
+//! profile after an `@`, or is the name of a profile on its own, in which
+//! case it is asked through the endpoint of that profile.
+//!
+//! Requests are made without tools, and a model that fails shows its error
+//! in place of an answer without failing the others.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::config::{Config, ConfigError};
+use crate::llm::{LlmClient, LlmRequest, Message, Usage};
+use crate::run::{self, RunOptions};
+use crate::usage::estimate_cost;
+
+/// Narrowest a column may be before answers are printed one after another
+/// instead of side by side
+const MIN_COLUMN_WIDTH: usize = 28;
+
+/// Width of the output when the terminal doesn't say
+const DEFAULT_WIDTH: usize = 120;
+
+/// Space between columns
+const GUTTER: &str = " │ ";
+
+#[derive(Error, Debug)]
+pub enum CompareError {
+    #[error("Compare needs at least two models; pass them with --models")]
+    TooFewModels,
+
+    #[error("{failed} of {total} models failed to answer")]
+    Failed { failed: usize, total: usize },
+}
+
+/// A model to ask, with the config to ask it with
+#[derive(Debug, Clone)]
+pub struct Contender {
+    /// The model as given on the command line
+    pub label: String,
+    config: Config,
+}
+
+impl Contender {
+    /// The contender given as `spec`: a model, `model@profile`, or the
+    /// name of a profile
+    pub fn parse(config: &Config, spec: &str) -> Result<Self, ConfigError> {
+        let (model, profile) = match spec.rsplit_once('@') {
+            Some((model, profile)) => (Some(model), Some(profile)),
+            None if config.profiles.contains_key(spec) => (None, Some(spec)),
+            None => (Some(spec), None),
+        };
+
+        let mut contender = match profile {
+            Some(profile) => config.with_profile(profile)?,
+            None => config.clone(),
+        };
+        if let Some(model) = model.filter(|model| !model.is_empty()) {
+            model.clone_into(&mut contender.model_name);
+        }
+
+        Ok(Self { label: spec.to_owned(), config: contender })
+    }
+
+    /// The model the contender asks
+    pub fn model(&self) -> &str {
+        &self.config.model_name
+    }
+}
+
+/// The answer of one model
+#[derive(Debug, Clone, Serialize)]
+pub struct Answer {
+    /// The model as given on the command line
+    pub label: String,
+    pub model: String,
+    /// The answer, unless the request failed
+    pub text: Option<String>,
+    /// Why the request failed, if it did
+    pub error: Option<String>,
+    /// Time from sending the request to receiving the whole answer
+    #[serde(rename = "latency_ms", serialize_with = "as_millis")]
+    pub latency: Duration,
+    #[serde(skip)]
+    pub usage: Usage,
+    pub total_tokens: u32,
+    /// Estimated cost, if the model has a configured price
+    pub cost: Option<f64>,
+}
+
+impl Answer {
+    /// The model as given, followed by the model it named when that
+    /// isn't the same, such as for a profile
+    fn heading(&self) -> String {
+        if self.label == self.model {
+            self.label.clone()
+        } else {
+            format!("{} ({})", self.label, self.model)
+        }
+    }
+
+    /// What the answer shows below its text: latency, usage and cost
+    fn summary(&self) -> String {
+        let mut summary = format!("{:.2}s", self.latency.as_secs_f64());
+        if self.error.is_none() {
+            let _ = write!(summary, ", {} tokens", self.usage.total_tokens());
+        }
+        if let Some(cost) = self.cost {
+            let _ = write!(summary, ", est. ${cost:.6}");
+        }
+        summary
+    }
+
+    fn body(&self) -> String {
+        match (&self.text, &self.error) {
+            (_, Some(error)) => format!("Error: {error}"),
+            (Some(text), None) => text.trim().to_owned(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+fn as_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(duration.as_millis())
+}
+
+/// The contenders given as `specs`, at least two of them
+pub fn contenders(
+    config: &Config,
+    specs: &[String],
+) -> Result<Vec<Contender>, crate::error::AidoError> {
+    if specs.len() < 2 {
+        return Err(CompareError::TooFewModels.into());
+    }
+
+    Ok(specs
+        .iter()
+        .map(|spec| Contender::parse(config, spec))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Sends `messages` to every contender at once, returning their answers in
+/// the order of `contenders`
+pub async fn compare(
+    contenders: &[Contender],
+    messages: Vec<Message>,
+    options: &RunOptions,
+) -> Vec<Answer> {
+    join_all(contenders.iter().map(|contender| {
+        let mut messages = messages.clone();
+        async move {
+            let config = run::run_config(&contender.config, options);
+            run::frame_messages(&config, &mut messages, options);
+            let request = LlmRequest::new(messages, Vec::new());
+
+            let llm = LlmClient::from_config(&config);
+
+            let started = Instant::now();
+            let result = llm.get_chat_completion(&request).await;
+            let latency = started.elapsed();
+
+            let usage =
+                result.as_ref().map(|r| r.usage().clone()).unwrap_or_default();
+            Answer {
+                label: contender.label.clone(),
+                model: config.model_name.clone(),
+                cost: result
+                    .is_ok()
+                    .then(|| {
+                        estimate_cost(
+                            &config.prices,
+                            &config.model_name,
+                            &usage,
+                        )
+                    })
+                    .flatten(),
+                text: result.as_ref().ok().map(|r| r.text().to_owned()),
+                error: result.err().map(|e| e.to_string()),
+                latency,
+                total_tokens: usage.total_tokens(),
+                usage,
+            }
+        }
+    }))
+    .await
+}
+
+/// Fails with [`CompareError::Failed`] if any model failed to answer
+pub fn check(answers: &[Answer]) -> Result<(), CompareError> {
+    match answers.iter().filter(|answer| answer.error.is_some()).count() {
+        0 => Ok(()),
+        failed => Err(CompareError::Failed { failed, total: answers.len() }),
+    }
+}
+
+/// Writes the answers side by side in columns fitting in `width`, or one
+/// after another when they don't fit
+pub fn write_answers(
+    mut writer: impl Write,
+    answers: &[Answer],
+    width: usize,
+) -> io::Result<()> {
+    let gutters = GUTTER.chars().count() * answers.len().saturating_sub(1);
+    let column = width.saturating_sub(gutters) / answers.len().max(1);
+
+    if column < MIN_COLUMN_WIDTH {
+        for answer in answers {
+            writeln!(writer, "## {}\n", answer.heading())?;
+            writeln!(writer, "{}\n", answer.body())?;
+            writeln!(writer, "{}\n", answer.summary())?;
+        }
+        return Ok(());
+    }
+
+    let cells = |part: &dyn Fn(&Answer) -> String| -> Vec<Vec<String>> {
+        answers.iter().map(|answer| wrap(&part(answer), column)).collect()
+    };
+    write_row(&mut writer, &cells(&Answer::heading), column)?;
+    write_row(&mut writer, &cells(&|_| "─".repeat(column)), column)?;
+    write_row(&mut writer, &cells(&Answer::body), column)?;
+    write_row(&mut writer, &cells(&|_| "─".repeat(column)), column)?;
+    write_row(&mut writer, &cells(&Answer::summary), column)
+}
+
+/// Writes the lines of `cells` next to each other, each padded to `column`
+fn write_row(
+    writer: &mut impl Write,
+    cells: &[Vec<String>],
+    column: usize,
+) -> io::Result<()> {
+    let height = cells.iter().map(Vec::len).max().unwrap_or(0);
+
+    for row in 0..height {
+        let line = cells
+            .iter()
+            .map(|lines| {
+                let text = lines.get(row).map_or("", String::as_str);
+                let padding = column.saturating_sub(text.chars().count());
+                format!("{text}{}", " ".repeat(padding))
+            })
+            .collect::<Vec<_>>()
+            .join(GUTTER);
+        writeln!(writer, "{}", line.trim_end())?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    answers: &'a [Answer],
+}
+
+/// Writes the answers as a single line of JSON
+pub fn write_json(
+    mut writer: impl Write,
+    answers: &[Answer],
+) -> io::Result<()> {
+    serde_json::to_writer(&mut writer, &JsonReport { answers })?;
+    writeln!(writer)
+}
+
+/// `text` broken into lines of at most `width` characters, between words
+/// where possible
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_inclusive(' ') {
+            if line.chars().count() + word.trim_end().chars().count() > width
+                && !line.is_empty()
+            {
+                lines.push(line.trim_end().to_owned());
+                line.clear();
+            }
+            line.push_str(word);
+
+            while line.chars().count() > width {
+                let split = line
+                    .char_indices()
+                    .nth(width)
+                    .map_or(line.len(), |(index, _)| index);
+                lines.push(line[..split].to_owned());
+                line = line[split..].to_owned();
+            }
+        }
+        lines.push(line.trim_end().to_owned());
+    }
+
+    lines
+}
+
+/// Width of the terminal, from `$COLUMNS`, or a default
+pub fn terminal_width() -> usize {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 0)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Profile;
+
+    fn answer(label: &str, text: &str) -> Answer {
+        Answer {
+            label: label.to_owned(),
+            model: label.to_owned(),
+            text: Some(text.to_owned()),
+            error: None,
+            latency: Duration::from_millis(1500),
+            usage: Usage::new(10, 5, 15),
+            total_tokens: 15,
+            cost: None,
+        }
+    }
+
+    #[test]
+    fn test_contender_parse() {
+        let mut config = Config {
+            model_name: "gpt-4o-mini".to_owned(),
+            api_url: "https://api.openai.com/v1".to_owned(),
+            ..Config::default()
+        };
+        config.profiles.insert(
+            "local".to_owned(),
+            Profile {
+                api_url: Some("http://localhost:11434/v1".to_owned()),
+                model_name: Some("llama3.2:3b".to_owned()),
+                ..Profile::default()
+            },
+        );
+
+        let hosted = Contender::parse(&config, "gpt-4o").unwrap();
+        assert_eq!(hosted.model(), "gpt-4o");
+        assert_eq!(hosted.config.api_url, "https://api.openai.com/v1");
+
+        let local = Contender::parse(&config, "local").unwrap();
+        assert_eq!(local.model(), "llama3.2:3b");
+        assert_eq!(local.config.api_url, "http://localhost:11434/v1");
+
+        let other = Contender::parse(&config, "qwen3:8b@local").unwrap();
+        assert_eq!(other.model(), "qwen3:8b");
+        assert_eq!(other.config.api_url, "http://localhost:11434/v1");
+
+        assert!(Contender::parse(&config, "x@remote").is_err());
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("one two three", 8), ["one two", "three"]);
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("a\n\nb", 4), ["a", "", "b"]);
+    }
+
+    #[test]
+    fn test_write_answers() {
+        let answers = [answer("a", "yes"), answer("b", "no, not really")];
+
+        let mut side_by_side = Vec::new();
+        write_answers(&mut side_by_side, &answers, 80).unwrap();
+        let side_by_side = String::from_utf8(side_by_side).unwrap();
+        let lines = side_by_side.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("a ") && lines[0].ends_with("│ b"));
+        assert!(lines[2].starts_with("yes") && lines[2].contains("no, not"));
+        assert!(lines[4].contains("1.50s, 15 tokens"));
+
+        let mut stacked = Vec::new();
+        write_answers(&mut stacked, &answers, 40).unwrap();
+        let stacked = String::from_utf8(stacked).unwrap();
+        assert!(stacked.starts_with("## a\n\nyes\n\n1.50s, 15 tokens\n"));
+    }
+}
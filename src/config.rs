@@ -1,31 +1,820 @@
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use log::{info, warn};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+use crate::{
+    error::AidoResult,
+    llm::{AzureSettings, Provider},
+    otel::OtelConfig,
+    tools::{ExecBackend, ToolsConfig},
+    usage::ModelPrice,
+    verify::VerifyConfig,
+};
+
+/// Service name API keys are stored under in the OS keychain
+pub const KEYRING_SERVICE: &str = "aido";
+
+/// Environment variable naming the profile to use when `--profile` isn't
+/// given
+pub const PROFILE_ENV_VAR: &str = "AIDO_PROFILE";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error(
+        "No API key: ${name} is not set and no other source of the key is \
+         configured"
+    )]
+    MissingEnvVar { name: String },
+
+    #[error(
+        "Could not read the API key '{entry}' from the keychain: {reason}"
+    )]
+    Keyring { entry: String, reason: String },
+
+    #[error(
+        "api_key_keyring is set, but aido was built without the keyring \
+         feature"
+    )]
+    KeyringUnsupported,
+
+    #[error("No profile named '{name}'; the config defines {available:?}")]
+    UnknownProfile { name: String, available: Vec<String> },
+
+    #[error("Invalid [tools] config: {reason}")]
+    InvalidTools { reason: String },
+
+    #[error("read_timeout_secs must be at least 1 second")]
+    ZeroReadTimeout,
+
+    #[error(
+        "No config file at {}; run `aido init` to create one",
+        path.display()
+    )]
+    NotConfigured { path: PathBuf },
+
+    #[error(
+        "{} already exists; pass --force to replace it",
+        path.display()
+    )]
+    AlreadyExists { path: PathBuf },
+
+    #[error("Could not load the config file: {0}")]
+    File(#[from] confy::ConfyError),
+}
+
+/// A named set of connection settings, selected with `--profile` or
+/// `AIDO_PROFILE`, e.g. `[profiles.local]` for a local server
+///
+/// Settings left out keep their top-level value, except that setting any
+/// of the three ways of giving the API key replaces all of them. `headers`
+/// and `request_metadata` are merged into the top-level ones.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub api_url: Option<String>,
+    #[serde(default)]
+    pub model_name: Option<String>,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    #[serde(default)]
+    pub provider: Option<Provider>,
+    #[serde(default)]
+    pub stream: Option<bool>,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    #[serde(default)]
+    pub api_key_keyring: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub request_metadata: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    /// The API key itself; prefer `api_key_env` or `api_key_keyring` to
+    /// keep it out of this file
+    #[serde(default)]
     pub api_key: String,
+    /// Environment variable holding the API key, such as `OPENAI_API_KEY`
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+    /// Name of the OS keychain entry holding the API key, stored under
+    /// the service `aido`
+    #[serde(default)]
+    pub api_key_keyring: Option<String>,
     pub api_url: String,
     pub model_name: String,
-    pub timeout: u64,
+    /// Deprecated and ignored, since requests never read it; see
+    /// `read_timeout_secs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Which kind of API `api_url` points at
+    #[serde(default)]
+    pub provider: Provider,
+    /// Deployment settings used when `provider = "azure"`
+    #[serde(default)]
+    pub azure: AzureSettings,
+    /// File of scripted replies served when `provider = "mock"`
+    #[serde(default)]
+    pub fixture: Option<PathBuf>,
+    /// Whether replies are streamed as they are generated; `false` requests
+    /// each reply whole, for servers without SSE or `stream_options`.
+    /// Defaults to true
+    #[serde(default)]
+    pub stream: Option<bool>,
+    /// Sampling temperature used unless a recipe overrides it
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Seed sent with every request, which providers that support it use
+    /// to sample the same answer to the same request
+    #[serde(default)]
+    pub seed: Option<i64>,
+    /// Maximum number of tokens generated per response
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// Nucleus sampling: only tokens within this top probability mass are
+    /// considered
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Penalty, from -2 to 2, on tokens by how often they already appear
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    /// Penalty, from -2 to 2, on tokens that already appear at all
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// Sequences, up to 4, at which the model stops generating
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Maximum number of tool-calling round trips before a run is aborted
+    #[serde(default)]
+    pub max_tool_iterations: Option<usize>,
+    /// Estimated cost in dollars at which a run is stopped instead of
+    /// asking the model again; needs the model's price under `[prices]`
+    #[serde(default)]
+    pub max_cost_per_run: Option<f64>,
+    /// Tokens at which a run is stopped instead of asking the model again
+    #[serde(default)]
+    pub max_tokens_per_run: Option<u32>,
+    /// Where tools that spawn subprocesses are executed
+    #[serde(default)]
+    pub exec: ExecBackend,
+    /// Built-in tools to disable and shell-command tools to add
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    /// Estimated tokens of project files `aido ask` may include as context
+    #[serde(default)]
+    pub context_budget: Option<usize>,
+    /// Estimated tokens of piped input a recipe keeps; longer input keeps
+    /// its start and end
+    #[serde(default)]
+    pub attachment_token_limit: Option<usize>,
+    /// Largest tool output passed on to the model, in bytes; longer output
+    /// is cut off with a note saying so
+    #[serde(default)]
+    pub max_tool_output_bytes: Option<usize>,
+    /// Seconds the API may send nothing, before a reply starts or between
+    /// its streamed chunks, before the request fails; at least 1, and
+    /// defaults to 300
+    #[serde(default)]
+    pub read_timeout_secs: Option<u64>,
+    /// How long replies to deterministic requests stay in the response
+    /// cache, in seconds; the cache is off when unset or 0
+    #[serde(default)]
+    pub cache_ttl_secs: Option<u64>,
+    /// Estimated tokens the conversation may grow to during a run before
+    /// its oldest turns are summarized; unlimited when unset
+    #[serde(default)]
+    pub context_limit: Option<usize>,
+    /// Context windows in tokens of models, by name, for models aido
+    /// doesn't know or servers that take less than the model could
+    #[serde(default)]
+    pub context_windows: BTreeMap<String, usize>,
+    /// Instructions placed before the system prompt of every run, such as
+    /// "I use the fish shell"; recipes can opt out with `prelude: false`
+    #[serde(default)]
+    pub system_prompt_prelude: Option<String>,
+    /// Whether the `.aido.md` and `AGENTS.md` files of the project are
+    /// added to the system prompt of recipe runs. Defaults to true
+    #[serde(default)]
+    pub project_context: Option<bool>,
+    /// Language answers must be written in, as an ISO 639-1 code or
+    /// English name; answers in another language are re-asked once
+    #[serde(default)]
+    pub output_language: Option<String>,
+    /// Have a judge score each answer, escalating to `fallback_models`
+    /// when the score is too low
+    #[serde(default)]
+    pub verify: Option<VerifyConfig>,
+    /// Stronger models to re-run on, in order, when an answer fails
+    /// verification
+    #[serde(default)]
+    pub fallback_models: Vec<String>,
+    /// Model `aido embed` uses, such as `nomic-embed-text` on Ollama;
+    /// defaults to `text-embedding-3-small`
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    /// Regular expressions matching secrets to mask in logs, `config show`
+    /// and the audit log, on top of the API key and well-known token
+    /// formats
+    #[serde(default)]
+    pub redact_patterns: Vec<String>,
+    /// Regular expressions matching lead-in and outro lines to strip from
+    /// answers with `--output bare`, on top of the built-in ones
+    #[serde(default)]
+    pub preamble_patterns: Vec<String>,
+    /// Extra HTTP headers sent with every request, e.g. the tags a gateway
+    /// like `LiteLLM` uses to attribute usage to a team
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Sent as the `metadata` field of every request, for gateways that
+    /// attribute usage from the request body instead of headers
+    #[serde(default)]
+    pub request_metadata: BTreeMap<String, serde_json::Value>,
+    /// Per-model prices used to estimate the cost of a run
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPrice>,
+    /// Fail on recipe headers that aren't valid YAML or have unknown keys,
+    /// instead of warning about them, like `--strict`
+    #[serde(default)]
+    pub strict: bool,
+    /// OpenTelemetry collector the trace of every run is exported to;
+    /// needs aido built with the `otel` feature
+    #[serde(default)]
+    pub otel: Option<OtelConfig>,
+    /// Named alternatives to the connection settings above
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Name of the profile applied when loading, if any
+    #[serde(skip)]
+    pub active_profile: Option<String>,
+}
+
+impl Config {
+    /// Overrides the connection settings with those of the named profile
+    pub fn apply_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        let profile = self.profiles.get(name).cloned().ok_or_else(|| {
+            ConfigError::UnknownProfile {
+                name: name.to_owned(),
+                available: self.profiles.keys().cloned().collect(),
+            }
+        })?;
+
+        if let Some(api_url) = profile.api_url {
+            self.api_url = api_url;
+        }
+        if let Some(model_name) = profile.model_name {
+            self.model_name = model_name;
+        }
+        if let Some(embedding_model) = profile.embedding_model {
+            self.embedding_model = Some(embedding_model);
+        }
+        if let Some(provider) = profile.provider {
+            self.provider = provider;
+        }
+        if let Some(stream) = profile.stream {
+            self.stream = Some(stream);
+        }
+        if profile.api_key.is_some()
+            || profile.api_key_env.is_some()
+            || profile.api_key_keyring.is_some()
+        {
+            self.api_key = profile.api_key.unwrap_or_default();
+            self.api_key_env = profile.api_key_env;
+            self.api_key_keyring = profile.api_key_keyring;
+        }
+        self.headers.extend(profile.headers);
+        self.request_metadata.extend(profile.request_metadata);
+        self.active_profile = Some(name.to_owned());
+
+        Ok(())
+    }
+
+    /// A copy of this config switched to the profile named `name`, with
+    /// the API key of the profile filled in
+    pub fn with_profile(&self, name: &str) -> Result<Self, ConfigError> {
+        let mut config = self.clone();
+        config.apply_profile(name)?;
+        resolve_api_key(&mut config)?;
+
+        Ok(config)
+    }
+}
+
+/// Parameters of the requests sent to the model, as recipe headers and the
+/// command line set them over those of the config
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RequestParams {
+    /// Sampling temperature
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Nucleus sampling probability mass
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// Maximum number of tokens to generate per response
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// Penalty on tokens by how often they already appear
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    /// Penalty on tokens that already appear
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// Sequences at which the model stops generating
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    /// Seed for providers that sample reproducibly
+    #[serde(default)]
+    pub seed: Option<i64>,
+    /// Whether the reply is streamed
+    #[serde(default)]
+    pub stream: Option<bool>,
+}
+
+impl RequestParams {
+    /// Overrides the settings of `config` with those that are set
+    pub fn apply_to(&self, config: &mut Config) {
+        config.temperature = self.temperature.or(config.temperature);
+        config.top_p = self.top_p.or(config.top_p);
+        config.max_tokens = self.max_tokens.or(config.max_tokens);
+        config.frequency_penalty =
+            self.frequency_penalty.or(config.frequency_penalty);
+        config.presence_penalty =
+            self.presence_penalty.or(config.presence_penalty);
+        if let Some(stop) = &self.stop {
+            config.stop.clone_from(stop);
+        }
+        config.seed = self.seed.or(config.seed);
+        config.stream = self.stream.or(config.stream);
+    }
+
+    /// Whether no parameter is set
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
-pub fn get_configuration_file_path()
--> Result<String, Box<dyn std::error::Error>> {
+pub fn get_configuration_file_path() -> AidoResult<String> {
     let path = confy::get_configuration_file_path("aido", None)?;
     Ok(path.to_string_lossy().to_string())
 }
 
-pub fn retrieve() -> Result<Config, Box<dyn std::error::Error>> {
-    let cfg: Config = confy::load("aido", None)?;
+pub fn retrieve() -> AidoResult<Config> {
+    let mut cfg: Config = confy::load("aido", None)?;
+    finish_loading(&mut cfg, None)?;
 
     Ok(cfg)
 }
 
-pub fn retrieve_from_path(
+/// Loads the config file at `path`, with the profile named by
+/// `AIDO_PROFILE` applied if it is set
+pub fn retrieve_from_path(path: impl AsRef<Path>) -> AidoResult<Config> {
+    retrieve_profile_from_path(path, None)
+}
+
+/// Loads the config file at `path` with the named profile applied, or the
+/// one named by `AIDO_PROFILE` when `profile` is `None`
+pub fn retrieve_profile_from_path(
     path: impl AsRef<Path>,
-) -> Result<Config, Box<dyn std::error::Error>> {
-    let cfg: Config = confy::load_path(path)?;
+    profile: Option<&str>,
+) -> AidoResult<Config> {
+    let mut cfg: Config = confy::load_path(path)?;
+    finish_loading(&mut cfg, profile)?;
 
     Ok(cfg)
 }
+
+/// Checks the tools config, applies the selected profile, checks the
+/// timeouts and fills in the API key
+fn finish_loading(
+    config: &mut Config,
+    profile: Option<&str>,
+) -> Result<(), ConfigError> {
+    config
+        .tools
+        .check()
+        .map_err(|reason| ConfigError::InvalidTools { reason })?;
+
+    let from_env =
+        std::env::var(PROFILE_ENV_VAR).ok().filter(|name| !name.is_empty());
+    if let Some(name) = profile.or(from_env.as_deref()) {
+        config.apply_profile(name)?;
+    }
+    if config.read_timeout_secs == Some(0) {
+        return Err(ConfigError::ZeroReadTimeout);
+    }
+
+    resolve_api_key(config)
+}
+
+/// Fills in `api_key` from the first source that has it: the environment
+/// variable named by `api_key_env`, then the keychain entry named by
+/// `api_key_keyring`, then the key written in the file
+fn resolve_api_key(config: &mut Config) -> Result<(), ConfigError> {
+    if let Some(name) = &config.api_key_env {
+        match std::env::var(name) {
+            Ok(key) if !key.is_empty() => {
+                config.api_key = key;
+                return Ok(());
+            }
+            _ if config.api_key_keyring.is_none()
+                && config.api_key.is_empty() =>
+            {
+                return Err(ConfigError::MissingEnvVar { name: name.clone() });
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(entry) = &config.api_key_keyring {
+        config.api_key = read_keyring(entry)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "keyring")]
+fn read_keyring(entry: &str) -> Result<String, ConfigError> {
+    keyring::Entry::new(KEYRING_SERVICE, entry)
+        .and_then(|e| e.get_password())
+        .map_err(|e| ConfigError::Keyring {
+            entry: entry.to_owned(),
+            reason: e.to_string(),
+        })
+}
+
+#[cfg(not(feature = "keyring"))]
+fn read_keyring(_entry: &str) -> Result<String, ConfigError> {
+    Err(ConfigError::KeyringUnsupported)
+}
+
+/// Settings changed by reloading the config file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigChanges {
+    /// Each setting that was applied, as `name: old -> new`
+    pub applied: Vec<String>,
+    /// Settings that changed but only take effect after a restart, such
+    /// as the API endpoint and key
+    pub needs_restart: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    pub fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.needs_restart.is_empty()
+    }
+}
+
+/// Settings that only take effect after a restart, since clients and tools
+/// are built with them once; every other setting is applied as it changes
+const RESTART_SETTINGS: &[&str] = &[
+    "api_key",
+    "api_key_env",
+    "api_key_keyring",
+    "api_url",
+    "provider",
+    "azure",
+    "exec",
+    "tools",
+    "cache_ttl_secs",
+    "headers",
+    "redact_patterns",
+];
+
+/// Notices when the config file of a long-lived process is modified
+///
+/// The directory of the file is watched rather than the file itself, since
+/// editors commonly save by replacing the file.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    changed: Arc<AtomicBool>,
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    /// Watches the config file at `path` for changes from now on
+    pub fn new(path: impl Into<PathBuf>) -> notify::Result<Self> {
+        let path = path.into();
+        let changed = Arc::new(AtomicBool::new(false));
+
+        let file_name = path.file_name().map(ToOwned::to_owned);
+        let flag = Arc::clone(&changed);
+        let mut watcher = notify::recommended_watcher(
+            move |event: notify::Result<Event>| {
+                if let Ok(event) = event
+                    && !event.kind.is_access()
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == file_name.as_deref())
+                {
+                    flag.store(true, Ordering::SeqCst);
+                }
+            },
+        )?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self { path, changed, _watcher: watcher })
+    }
+
+    /// Reloads the config file if it was modified since the last check
+    pub fn poll(&self) -> AidoResult<Option<Config>> {
+        if !self.changed.swap(false, Ordering::SeqCst) {
+            return Ok(None);
+        }
+
+        retrieve_from_path(&self.path).map(Some)
+    }
+
+    /// Applies to `current` the settings that are safe to change at
+    /// runtime, if the config file was modified since the last check,
+    /// keeping the profile `current` was loaded with; each change is
+    /// logged
+    pub fn reload_into(
+        &self,
+        current: &mut Config,
+    ) -> AidoResult<ConfigChanges> {
+        if !self.changed.swap(false, Ordering::SeqCst) {
+            return Ok(ConfigChanges::default());
+        }
+
+        let new = retrieve_profile_from_path(
+            &self.path,
+            current.active_profile.as_deref(),
+        )?;
+        let changes = apply_safe_changes(current, &new)?;
+        for change in &changes.applied {
+            info!("Config reloaded: {change}");
+        }
+        for name in &changes.needs_restart {
+            warn!("Config reloaded: {name} changed, which needs a restart");
+        }
+
+        Ok(changes)
+    }
+}
+
+/// Copies into `current` the settings of `new` that can change while a
+/// process is running
+///
+/// That is every setting but those in `RESTART_SETTINGS`, such as the
+/// connection settings, which are reported instead, since clients already
+/// built with them would silently keep the old ones.
+pub fn apply_safe_changes(
+    current: &mut Config,
+    new: &Config,
+) -> serde_json::Result<ConfigChanges> {
+    let mut changes = ConfigChanges::default();
+    let mut settings = settings(current)?;
+
+    for (name, value) in self::settings(new)? {
+        let old = settings.get(&name).unwrap_or(&Value::Null);
+        if *old == value {
+            continue;
+        }
+        if let Some(name) = RESTART_SETTINGS.iter().find(|s| **s == name) {
+            changes.needs_restart.push(name);
+            continue;
+        }
+
+        // Tables are left out, since they may hold credentials
+        changes.applied.push(match value {
+            Value::Object(_) => format!("{name} changed"),
+            _ => format!("{name}: {old} -> {value}"),
+        });
+        settings.insert(name, value);
+    }
+
+    let active_profile = current.active_profile.take();
+    *current = serde_json::from_value(Value::Object(settings))?;
+    current.active_profile = active_profile;
+
+    Ok(changes)
+}
+
+/// Every setting of `config` by name
+fn settings(config: &Config) -> serde_json::Result<Map<String, Value>> {
+    // Through text, so that `f32` settings read as written rather than
+    // widened to `f64`
+    serde_json::from_str(&serde_json::to_string(config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES_TOML: &str = r#"
+api_key = "openai-key"
+api_url = "https://api.openai.com/v1"
+model_name = "gpt-4.1"
+timeout = 30
+
+[headers]
+x-litellm-tags = "team:search"
+
+[profiles.local]
+api_url = "http://localhost:8080/v1"
+model_name = "qwen3"
+api_key = "local-key"
+
+[profiles.local.headers]
+x-litellm-tags = "team:search,project:aido"
+x-team = "search"
+
+[profiles.local.request_metadata]
+project = "aido"
+
+[profiles.work]
+api_key_env = "AIDO_TEST_WORK_API_KEY"
+"#;
+
+    /// Writes `PROFILES_TOML` to a file of its own and returns its path
+    fn write_profiles(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-{test}-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("aido.toml");
+        std::fs::write(&path, PROFILES_TOML).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let path = write_profiles("profile");
+
+        let config = retrieve_profile_from_path(&path, Some("local")).unwrap();
+        assert_eq!(config.api_url, "http://localhost:8080/v1");
+        assert_eq!(config.model_name, "qwen3");
+        assert_eq!(config.api_key, "local-key");
+        assert_eq!(config.timeout, Some(30));
+        assert_eq!(
+            config.headers["x-litellm-tags"],
+            "team:search,project:aido"
+        );
+        assert_eq!(config.headers.len(), 2);
+        assert_eq!(config.request_metadata["project"], "aido");
+
+        let mut config: Config = confy::load_path(&path).unwrap();
+        config.apply_profile("work").unwrap();
+        assert_eq!(config.model_name, "gpt-4.1");
+        assert_eq!(config.api_key, "");
+        assert_eq!(
+            config.api_key_env.as_deref(),
+            Some("AIDO_TEST_WORK_API_KEY")
+        );
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        let path = write_profiles("unknown-profile");
+
+        let error =
+            retrieve_profile_from_path(&path, Some("home")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No profile named 'home'; the config defines [\"local\", \"work\"]"
+        );
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_zero_read_timeout_is_rejected() {
+        let mut config =
+            Config { read_timeout_secs: Some(0), ..Config::default() };
+
+        let error = finish_loading(&mut config, None).unwrap_err();
+        assert!(matches!(error, ConfigError::ZeroReadTimeout));
+
+        config.read_timeout_secs = Some(1);
+        finish_loading(&mut config, None).unwrap();
+    }
+
+    #[test]
+    fn test_apply_safe_changes() {
+        let mut current = Config {
+            api_url: "http://one".to_owned(),
+            model_name: "small".to_owned(),
+            ..Config::default()
+        };
+        let new = Config {
+            api_url: "http://two".to_owned(),
+            model_name: "large".to_owned(),
+            temperature: Some(0.2),
+            ..Config::default()
+        };
+
+        let changes = apply_safe_changes(&mut current, &new).unwrap();
+
+        assert_eq!(
+            changes.applied,
+            ["model_name: \"small\" -> \"large\"", "temperature: null -> 0.2"]
+        );
+        assert_eq!(changes.needs_restart, ["api_url"]);
+        assert_eq!(current.model_name, "large");
+        assert_eq!(current.temperature, Some(0.2));
+        assert_eq!(current.api_url, "http://one");
+
+        let same = current.clone();
+        assert!(apply_safe_changes(&mut current, &same).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_watcher_reloads_modified_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-config-watch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("aido.toml");
+        let write = |model: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "api_url = \"http://localhost\"\nmodel_name = \
+                     \"{model}\"\ntimeout = 10\n"
+                ),
+            )
+            .unwrap();
+        };
+
+        write("small");
+        let watcher = ConfigWatcher::new(&path).unwrap();
+        std::fs::write(dir.join("other.toml"), "").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(watcher.poll().unwrap().is_none());
+
+        write("large");
+        let config = (0..50)
+            .find_map(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                watcher.poll().unwrap()
+            })
+            .unwrap();
+        assert_eq!(config.model_name, "large");
+        assert!(watcher.poll().unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_api_key_from_env() {
+        // Each test uses its own variable, since tests run in parallel
+        let name = "AIDO_TEST_API_KEY_FROM_ENV";
+        // SAFETY: no other test touches this variable
+        unsafe { std::env::set_var(name, "from-env") };
+        let mut config = Config {
+            api_key: "from-file".to_owned(),
+            api_key_env: Some(name.to_owned()),
+            ..Config::default()
+        };
+
+        resolve_api_key(&mut config).unwrap();
+
+        assert_eq!(config.api_key, "from-env");
+    }
+
+    #[test]
+    fn test_api_key_env_unset() {
+        let name = "AIDO_TEST_API_KEY_UNSET";
+        let mut config = Config {
+            api_key: "from-file".to_owned(),
+            api_key_env: Some(name.to_owned()),
+            ..Config::default()
+        };
+
+        // The key in the file is the fallback
+        resolve_api_key(&mut config).unwrap();
+        assert_eq!(config.api_key, "from-file");
+
+        config.api_key.clear();
+        assert!(matches!(
+            resolve_api_key(&mut config),
+            Err(ConfigError::MissingEnvVar { .. })
+        ));
+    }
+
+    #[test]
+    fn test_api_key_from_file() {
+        let mut config =
+            Config { api_key: "from-file".to_owned(), ..Config::default() };
+
+        resolve_api_key(&mut config).unwrap();
+
+        assert_eq!(config.api_key, "from-file");
+    }
+}
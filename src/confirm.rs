@@ -0,0 +1,165 @@
+//! Asking the user before tools run
+//!
+//! A recipe can declare which tool calls need the user's approval with
+//! `confirm: always`, `confirm: never` (the default) or a list of
+//! capabilities such as `confirm: [write, exec]`. The [`Confirm`] tool hook
+//! enforces that choice on top of any other hooks registered for the run.
+//! Tools that show what they are about to do and ask themselves, such as
+//! `apply_patch`, are left to it.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::middleware::{MiddlewareResult, ToolDecision, ToolHook};
+use crate::tools::{Capability, Tool, ToolInput};
+
+/// Blanket confirmation settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfirmMode {
+    Always,
+    Never,
+}
+
+/// Which tool calls need the user's approval
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ConfirmPolicy {
+    Mode(ConfirmMode),
+    /// Confirm tools with any of these capabilities
+    Capabilities(Vec<Capability>),
+}
+
+impl Default for ConfirmPolicy {
+    fn default() -> Self {
+        Self::Mode(ConfirmMode::Never)
+    }
+}
+
+impl ConfirmPolicy {
+    /// Whether calls to a tool with `capability` must be confirmed
+    #[must_use]
+    pub fn requires(&self, capability: Capability) -> bool {
+        match self {
+            Self::Mode(mode) => *mode == ConfirmMode::Always,
+            Self::Capabilities(capabilities) => {
+                capabilities.contains(&capability)
+            }
+        }
+    }
+
+    /// Whether no tool call ever needs confirming
+    #[must_use]
+    pub fn is_never(&self) -> bool {
+        match self {
+            Self::Mode(mode) => *mode == ConfirmMode::Never,
+            Self::Capabilities(capabilities) => capabilities.is_empty(),
+        }
+    }
+}
+
+type Prompt = Box<dyn Fn(&str) -> io::Result<bool> + Send + Sync>;
+
+/// Tool hook that asks the user before running tools the policy covers
+pub struct Confirm {
+    policy: ConfirmPolicy,
+    prompt: Prompt,
+}
+
+impl Confirm {
+    /// Asks on the terminal, even when standard input is piped
+    pub fn new(policy: ConfirmPolicy) -> Self {
+        Self::with_prompt(policy, ask_on_terminal)
+    }
+
+    /// Asks with `prompt`, which receives the question and returns whether
+    /// the user agreed
+    pub fn with_prompt(
+        policy: ConfirmPolicy,
+        prompt: impl Fn(&str) -> io::Result<bool> + Send + Sync + 'static,
+    ) -> Self {
+        Self { policy, prompt: Box::new(prompt) }
+    }
+}
+
+impl ToolHook for Confirm {
+    fn before_tool(
+        &self,
+        tool: &dyn Tool,
+        input: &mut ToolInput,
+    ) -> MiddlewareResult<ToolDecision> {
+        if !self.policy.requires(tool.capability()) || tool.confirms_itself() {
+            return Ok(ToolDecision::Allow);
+        }
+
+        let question = format!(
+            "Allow tool '{}' to run with {}?",
+            tool.definition().name(),
+            serde_json::to_string(input)?
+        );
+
+        if (self.prompt)(&question)? {
+            Ok(ToolDecision::Allow)
+        } else {
+            Ok(ToolDecision::Deny("the user declined to run it".to_string()))
+        }
+    }
+}
+
+/// Asks a yes/no question on the controlling terminal, defaulting to no
+pub(crate) fn ask_on_terminal(question: &str) -> io::Result<bool> {
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    if io::stdin().is_terminal() {
+        io::stdin().read_line(&mut answer)?;
+    } else {
+        BufReader::new(File::open("/dev/tty")?).read_line(&mut answer)?;
+    }
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::Search;
+
+    #[test]
+    fn test_policy_from_yaml() {
+        let always: ConfirmPolicy = serde_yaml::from_str("always").unwrap();
+        let listed: ConfirmPolicy =
+            serde_yaml::from_str("[write, exec]").unwrap();
+
+        assert!(always.requires(Capability::Read));
+        assert!(!listed.requires(Capability::Read));
+        assert!(listed.requires(Capability::Exec));
+        assert!(ConfirmPolicy::default().is_never());
+        assert!(!listed.is_never());
+    }
+
+    #[test]
+    fn test_confirm_asks_only_when_required() {
+        let declined = Confirm::with_prompt(
+            ConfirmPolicy::Mode(ConfirmMode::Always),
+            |_| Ok(false),
+        );
+        let unasked = Confirm::with_prompt(
+            ConfirmPolicy::Capabilities(vec![Capability::Exec]),
+            |_| panic!("should not ask"),
+        );
+        let mut input = ToolInput::new();
+
+        assert!(matches!(
+            declined.before_tool(&Search::new(), &mut input).unwrap(),
+            ToolDecision::Deny(_)
+        ));
+        assert_eq!(
+            unasked.before_tool(&Search::new(), &mut input).unwrap(),
+            ToolDecision::Allow
+        );
+    }
+}
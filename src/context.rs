@@ -0,0 +1,512 @@
+//! Packing workspace files into the context of a question
+//!
+//! `aido ask --files` expands the given globs under the current directory,
+//! ranks the matching files by how relevant their
+//! path looks to the question and how recently they were modified, and
+//! includes as many of them as fit in a token budget.
+//!
+//! Without `--files`, every file in the project is a candidate and the
+//! ranking also looks at file contents, keeping only files that mention
+//! the question's keywords.
+//!
+//! Text too long for the space left is cut in the middle by
+//! [`truncate_middle`], keeping its start and end with a note of how much
+//! was left out.
+//!
+//! Files excluded by .gitignore are never candidates, and neither are those
+//! excluded by an `.aidoignore` file, which takes the same syntax and is
+//! meant for what shouldn't reach a provider but is still tracked: secrets,
+//! vendored dependencies, huge generated files. A glob given to `--files`
+//! doesn't bring them back.
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use ignore::WalkBuilder;
+use ignore::overrides::OverrideBuilder;
+use thiserror::Error;
+
+/// Name of the files listing, like .gitignore does, paths never read into
+/// context
+pub const IGNORE_FILE_NAME: &str = ".aidoignore";
+
+/// Token budget for included files when none is given
+pub const DEFAULT_TOKEN_BUDGET: usize = 32_000;
+
+/// Most estimated tokens of piped input kept when no limit is configured
+pub const DEFAULT_ATTACHMENT_TOKEN_LIMIT: usize = 16_000;
+
+/// Fewest tokens worth including of a file cut down to fit the budget;
+/// files that would get less are left out instead
+const MIN_TRUNCATED_TOKENS: usize = 500;
+
+/// Tokens set aside for the note replacing the middle of truncated text
+const ELISION_NOTE_TOKENS: usize = 30;
+
+/// Shortest word in a question considered when matching file paths
+const MIN_KEYWORD_LENGTH: usize = 3;
+
+/// Words too common in questions to say anything about relevance
+const STOPWORDS: &[&str] = &[
+    "and", "are", "can", "does", "for", "from", "how", "the", "this", "that",
+    "what", "when", "where", "which", "who", "why", "with", "you", "your",
+];
+
+/// How much a keyword in a file's path counts compared to one occurrence
+/// in its content
+const PATH_MATCH_WEIGHT: usize = 5;
+
+/// Occurrences of a single keyword counted per file, so one word repeated
+/// all over a file doesn't drown out the others
+const MAX_COUNTED_OCCURRENCES: usize = 5;
+
+/// Files larger than this are never read when ranking by content
+const MAX_RANKED_FILE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ContextError {
+    #[error("Invalid file pattern: {0}")]
+    Pattern(#[from] ignore::Error),
+
+    #[error("No files match {patterns:?}")]
+    NoMatches { patterns: Vec<String> },
+}
+
+/// A workspace file selected as context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextFile {
+    /// Path relative to the workspace root
+    pub path: PathBuf,
+    pub content: String,
+}
+
+/// Files selected for a question, and those left out to stay in budget
+#[derive(Debug, Clone, Default)]
+pub struct PackedContext {
+    pub files: Vec<ContextFile>,
+    pub omitted: Vec<PathBuf>,
+}
+
+/// Roughly estimates the number of tokens in `text`
+///
+/// Uses the common rule of thumb of four characters per token, which is
+/// close enough for budgeting without shipping a tokenizer.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Cuts `text` down to about `max_tokens` tokens by keeping its start and
+/// end and replacing the middle with a note saying how many lines, out of
+/// how many, were left out
+///
+/// Cuts are moved to line boundaries when the kept parts have any, so
+/// lines are only split when a single line is too long to keep.
+pub fn truncate_middle(text: &str, max_tokens: usize) -> Cow<'_, str> {
+    if estimate_tokens(text) <= max_tokens {
+        return Cow::Borrowed(text);
+    }
+
+    // Four characters per token, like `estimate_tokens`
+    let kept_chars = max_tokens.saturating_sub(ELISION_NOTE_TOKENS) * 4;
+    let head_chars = kept_chars / 2;
+    let tail_chars = kept_chars - head_chars;
+    let total_chars = text.chars().count();
+
+    let char_offset =
+        |n: usize| text.char_indices().nth(n).map_or(text.len(), |(i, _)| i);
+    let head_end = char_offset(head_chars);
+    let head_end = text[..head_end].rfind('\n').map_or(head_end, |i| i + 1);
+    let tail_start = char_offset(total_chars - tail_chars).max(head_end);
+    let tail_start = if text[..tail_start].ends_with('\n') {
+        tail_start
+    } else {
+        text[tail_start..]
+            .find('\n')
+            .map_or(tail_start, |i| tail_start + i + 1)
+    };
+
+    let omitted = &text[head_end..tail_start];
+    let note = format!(
+        "[... {} of {} lines, about {} tokens, left out ...]",
+        omitted.lines().count(),
+        text.lines().count(),
+        estimate_tokens(omitted)
+    );
+
+    let head = &text[..head_end];
+    let separator =
+        if head.is_empty() || head.ends_with('\n') { "" } else { "\n" };
+    Cow::Owned(format!("{head}{separator}{note}\n{}", &text[tail_start..]))
+}
+
+/// A walk over `root` skipping what .gitignore and .aidoignore files
+/// exclude
+pub fn walker(root: &Path) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(root);
+    builder.add_custom_ignore_filename(IGNORE_FILE_NAME);
+    builder
+}
+
+/// Lists the files under `root` matching any of `patterns`, skipping files
+/// excluded by .gitignore or .aidoignore
+pub fn expand_globs(
+    root: &Path,
+    patterns: &[String],
+) -> Result<Vec<PathBuf>, ContextError> {
+    let mut overrides = OverrideBuilder::new(root);
+    for pattern in patterns {
+        overrides.add(pattern)?;
+    }
+
+    let overrides = overrides.build()?;
+
+    // The globs are matched after the walk rather than given to it as
+    // overrides, which would take precedence over the ignore files
+    let files = walker(root)
+        .build()
+        .flatten()
+        .filter(|entry| entry.file_type().is_some_and(|ft| ft.is_file()))
+        .filter(|entry| {
+            overrides.is_empty()
+                || overrides.matched(entry.path(), false).is_whitelist()
+        })
+        .map(|entry| {
+            entry
+                .path()
+                .strip_prefix(root)
+                .map_or_else(|_| entry.path().to_path_buf(), Path::to_path_buf)
+        })
+        .collect::<Vec<_>>();
+
+    if files.is_empty() {
+        return Err(ContextError::NoMatches { patterns: patterns.to_vec() });
+    }
+
+    Ok(files)
+}
+
+/// Scores how well a path matches the words of a question
+fn path_score(path: &Path, keywords: &[String]) -> usize {
+    let path = path.to_string_lossy().to_lowercase();
+    keywords.iter().filter(|k| path.contains(k.as_str())).count()
+}
+
+fn keywords(question: &str) -> Vec<String> {
+    question
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| word.len() >= MIN_KEYWORD_LENGTH)
+        .map(str::to_lowercase)
+        .filter(|word| !STOPWORDS.contains(&word.as_str()))
+        .collect()
+}
+
+/// Scores how often the words of a question appear in a file's content
+fn content_score(content: &str, keywords: &[String]) -> usize {
+    let content = content.to_lowercase();
+    keywords
+        .iter()
+        .map(|k| {
+            content.matches(k.as_str()).count().min(MAX_COUNTED_OCCURRENCES)
+        })
+        .sum()
+}
+
+/// Sorts scored files by descending score, most recently modified first
+/// among equal scores
+fn sort_by_score(root: &Path, scored: Vec<(usize, PathBuf)>) -> Vec<PathBuf> {
+    let mut scored = scored
+        .into_iter()
+        .map(|(score, path)| {
+            let modified = std::fs::metadata(root.join(&path))
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (score, modified, path)
+        })
+        .collect::<Vec<_>>();
+
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)));
+
+    scored.into_iter().map(|(_, _, path)| path).collect()
+}
+
+/// Orders `files` so the most relevant come first: those whose path
+/// mentions words from the question, then the most recently modified
+pub fn rank_files(
+    root: &Path,
+    files: Vec<PathBuf>,
+    question: &str,
+) -> Vec<PathBuf> {
+    let keywords = keywords(question);
+
+    let scored = files
+        .into_iter()
+        .map(|path| (path_score(&path, &keywords), path))
+        .collect();
+
+    sort_by_score(root, scored)
+}
+
+/// Picks the files relevant to a question by matching its keywords against
+/// each file's path and content, most relevant first. Files that mention
+/// none of the keywords are dropped.
+pub fn rank_by_relevance(
+    root: &Path,
+    files: Vec<PathBuf>,
+    question: &str,
+) -> Vec<PathBuf> {
+    let keywords = keywords(question);
+
+    let scored = files
+        .into_iter()
+        .filter_map(|path| {
+            let full_path = root.join(&path);
+            let size = std::fs::metadata(&full_path).ok()?.len();
+            if size > MAX_RANKED_FILE_SIZE {
+                return None;
+            }
+
+            let content = std::fs::read_to_string(full_path).ok()?;
+            let score = path_score(&path, &keywords) * PATH_MATCH_WEIGHT
+                + content_score(&content, &keywords);
+
+            (score > 0).then_some((score, path))
+        })
+        .collect();
+
+    sort_by_score(root, scored)
+}
+
+/// Reads files in order, keeping each one that still fits in `budget`
+/// tokens. Files that can't be read as text are skipped.
+///
+/// A file that doesn't fit is cut down to the space left, unless too
+/// little is left for that to be useful.
+pub fn pack(
+    root: &Path,
+    ranked: Vec<PathBuf>,
+    budget: usize,
+) -> PackedContext {
+    let mut packed = PackedContext::default();
+    let mut remaining = budget;
+
+    for path in ranked {
+        let Ok(content) = std::fs::read_to_string(root.join(&path)) else {
+            continue;
+        };
+
+        let tokens = estimate_tokens(&content);
+        if tokens > remaining && remaining < MIN_TRUNCATED_TOKENS {
+            packed.omitted.push(path);
+            continue;
+        }
+
+        let content = truncate_middle(&content, remaining).into_owned();
+        remaining = remaining.saturating_sub(estimate_tokens(&content));
+        packed.files.push(ContextFile { path, content });
+    }
+
+    packed
+}
+
+/// Builds the user message asking `question` about the packed files
+pub fn build_prompt(context: &PackedContext, question: &str) -> String {
+    let mut prompt = String::from(
+        "Answer the question at the end using the following files from my \
+         project.\n\n",
+    );
+
+    for file in &context.files {
+        let _ = write!(
+            prompt,
+            "--- {} ---\n{}\n\n",
+            file.path.display(),
+            file.content.trim_end()
+        );
+    }
+
+    if !context.omitted.is_empty() {
+        let omitted = context
+            .omitted
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let _ = write!(
+            prompt,
+            "(These files also matched but were left out for length: \
+             {omitted})\n\n"
+        );
+    }
+
+    let _ = write!(prompt, "Question: {question}");
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crate_root() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+    }
+
+    #[test]
+    fn test_expand_globs() {
+        let files =
+            expand_globs(&crate_root(), &["src/**/*.rs".to_string()]).unwrap();
+
+        assert!(files.contains(&PathBuf::from("src/context.rs")));
+        assert!(files.contains(&PathBuf::from("src/tools/ls.rs")));
+        assert!(!files.contains(&PathBuf::from("Cargo.toml")));
+    }
+
+    #[test]
+    fn test_expand_globs_skips_aidoignored_files() {
+        let root = std::env::temp_dir()
+            .join(format!("aido-aidoignore-test-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("secrets")).unwrap();
+        std::fs::write(root.join(IGNORE_FILE_NAME), "secrets/\n*.pem\n")
+            .unwrap();
+        std::fs::write(root.join("main.rs"), "").unwrap();
+        std::fs::write(root.join("cert.pem"), "").unwrap();
+        std::fs::write(root.join("secrets/token.txt"), "").unwrap();
+
+        let files = expand_globs(&root, &[]).unwrap();
+        assert_eq!(files, [PathBuf::from("main.rs")]);
+
+        // Naming them explicitly doesn't bring them back
+        for pattern in ["secrets/*", "*.pem"] {
+            let result = expand_globs(&root, &[pattern.to_string()]);
+            assert!(matches!(result, Err(ContextError::NoMatches { .. })));
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_expand_globs_no_matches() {
+        let result =
+            expand_globs(&crate_root(), &["**/*.does-not-exist".to_string()]);
+
+        assert!(matches!(result, Err(ContextError::NoMatches { .. })));
+    }
+
+    #[test]
+    fn test_rank_files_prefers_path_matches() {
+        let files = vec![
+            PathBuf::from("src/main.rs"),
+            PathBuf::from("src/recipe.rs"),
+            PathBuf::from("src/tools/ls.rs"),
+        ];
+
+        let ranked =
+            rank_files(&crate_root(), files, "How are recipe files parsed?");
+
+        assert_eq!(ranked[0], PathBuf::from("src/recipe.rs"));
+    }
+
+    #[test]
+    fn test_keywords_skip_stopwords() {
+        assert_eq!(
+            keywords("How does the LoopDetector work?"),
+            ["loopdetector", "work"]
+        );
+    }
+
+    #[test]
+    fn test_content_score_caps_repeats() {
+        let keywords = vec!["alpha".to_string(), "beta".to_string()];
+
+        assert_eq!(content_score("alpha ".repeat(20).as_str(), &keywords), 5);
+        assert_eq!(content_score("Alpha and beta", &keywords), 2);
+        assert_eq!(content_score("gamma", &keywords), 0);
+    }
+
+    #[test]
+    fn test_rank_by_relevance() {
+        let files = expand_globs(&crate_root(), &[]).unwrap();
+
+        let ranked = rank_by_relevance(
+            &crate_root(),
+            files,
+            "Where is LoopDetector defined?",
+        );
+
+        assert_eq!(ranked[0], PathBuf::from("src/run.rs"));
+        assert!(!ranked.contains(&PathBuf::from("Cargo.toml")));
+    }
+
+    #[test]
+    fn test_pack_respects_budget() {
+        let ranked =
+            vec![PathBuf::from("src/context.rs"), PathBuf::from("Cargo.toml")];
+
+        let packed = pack(&crate_root(), ranked, 1_000);
+
+        // The first file is cut down to the budget, leaving no room for
+        // the second
+        assert_eq!(packed.files.len(), 1);
+        assert_eq!(packed.files[0].path, PathBuf::from("src/context.rs"));
+        assert!(estimate_tokens(&packed.files[0].content) <= 1_000);
+        assert!(packed.files[0].content.starts_with("//! Packing"));
+        assert!(packed.files[0].content.contains("lines, about"));
+        assert_eq!(packed.omitted, [PathBuf::from("Cargo.toml")]);
+    }
+
+    #[test]
+    fn test_truncate_middle() {
+        let text = (1..=1000).fold(String::new(), |mut text, i| {
+            let _ = writeln!(text, "line {i:04}");
+            text
+        });
+
+        assert_eq!(truncate_middle(&text, 10_000), text);
+
+        let truncated = truncate_middle(&text, 300);
+        assert!(estimate_tokens(&truncated) <= 300);
+        assert!(truncated.starts_with("line 0001\nline 0002\n"));
+        assert!(truncated.ends_with("line 1000\n"));
+        assert!(truncated.contains(
+            "line 0054\n[... 892 of 1000 lines, about 2230 tokens, left \
+             out ...]\nline 0947\n"
+        ));
+    }
+
+    #[test]
+    fn test_truncate_middle_single_line() {
+        let text = "x".repeat(10_000);
+
+        let truncated = truncate_middle(&text, 100);
+
+        assert!(estimate_tokens(&truncated) <= 100);
+        assert!(truncated.starts_with("xxxx"));
+        assert!(truncated.contains("\n[... 1 of 1 lines, about"));
+        assert!(truncated.ends_with("xxxx"));
+    }
+
+    #[test]
+    fn test_build_prompt() {
+        let context = PackedContext {
+            files: vec![ContextFile {
+                path: PathBuf::from("a.rs"),
+                content: "fn a() {}\n".to_string(),
+            }],
+            omitted: vec![PathBuf::from("b.rs")],
+        };
+
+        let prompt = build_prompt(&context, "What does a do?");
+
+        assert!(prompt.contains("--- a.rs ---\nfn a() {}\n\n"));
+        assert!(prompt.contains("left out for length: b.rs"));
+        assert!(prompt.ends_with("Question: What does a do?"));
+    }
+}
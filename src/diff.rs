@@ -0,0 +1,61 @@
+//! Word-level differences between two answers
+//!
+//! `aido diff-last` shows how an answer changed between runs the way
+//! `git diff --word-diff` does: words that are gone between `[-` and `-]`,
+//! words that are new between `{+` and `+}`, or in red and green when the
+//! output is a terminal.
+
+use similar::{ChangeTag, TextDiff};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// `new` with the words that changed since `old` marked, in color if
+/// `color` is set
+pub fn word_diff(old: &str, new: &str, color: bool) -> String {
+    let diff = TextDiff::from_words(old, new);
+
+    // Neighbouring changes of the same kind are marked together
+    let mut runs: Vec<(ChangeTag, String)> = Vec::new();
+    for change in diff.iter_all_changes() {
+        match runs.last_mut() {
+            Some((tag, text)) if *tag == change.tag() => {
+                text.push_str(change.value());
+            }
+            _ => runs.push((change.tag(), change.value().to_owned())),
+        }
+    }
+
+    runs.iter()
+        .map(|(tag, text)| match (tag, color) {
+            (ChangeTag::Equal, _) => text.clone(),
+            (ChangeTag::Delete, false) => format!("[-{text}-]"),
+            (ChangeTag::Insert, false) => format!("{{+{text}+}}"),
+            (ChangeTag::Delete, true) => format!("{RED}{text}{RESET}"),
+            (ChangeTag::Insert, true) => format!("{GREEN}{text}{RESET}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_diff() {
+        assert_eq!(
+            word_diff(
+                "Run tar -xzf a.tgz to extract it",
+                "Run tar -xf a.tgz to extract it quickly",
+                false
+            ),
+            "Run tar [--xzf-]{+-xf+} a.tgz to extract it{+ quickly+}"
+        );
+        assert_eq!(word_diff("same", "same", false), "same");
+        assert_eq!(
+            word_diff("old", "new", true),
+            "\x1b[31mold\x1b[0m\x1b[32mnew\x1b[0m"
+        );
+    }
+}
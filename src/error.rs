@@ -0,0 +1,184 @@
+//! The error type of aido
+//!
+//! Runs, the config, recipes and tools fail with an [`AidoError`], which
+//! wraps the error of the module that failed. Its message is meant for the
+//! user and fits on one line, and [`AidoError::exit_code`] tells scripts
+//! what kind of failure ended the command:
+//!
+//! | Code | Failure                                               |
+//! |------|-------------------------------------------------------|
+//! | 1    | anything not listed below                             |
+//! | 2    | the command line is invalid                           |
+//! | 3    | the config is missing, invalid or incomplete          |
+//! | 4    | a recipe or workflow is missing, invalid or can't run |
+//! | 5    | the provider's API failed or answered unexpectedly    |
+//! | 6    | the run stopped without an acceptable answer          |
+//! | 7    | another run of the recipe holds its lock              |
+
+use std::io;
+
+use thiserror::Error;
+
+use crate::batch::BatchError;
+use crate::bundle::BundleError;
+use crate::cancel::CancelError;
+use crate::clipboard::ClipboardError;
+use crate::commit::CommitError;
+use crate::compare::CompareError;
+use crate::config::ConfigError;
+use crate::context::ContextError;
+use crate::isolation::IsolationError;
+use crate::llm::LlmError;
+use crate::lock::LockError;
+use crate::preamble::PreambleError;
+use crate::recipe::RecipeError;
+use crate::recipe::install::InstallError;
+use crate::recipe::package::PackageError;
+use crate::redact::RedactError;
+use crate::retrieval::RetrievalError;
+use crate::run::RunError;
+use crate::session::SessionError;
+use crate::workflow::WorkflowError;
+
+pub type AidoResult<T> = Result<T, AidoError>;
+
+#[derive(Error, Debug)]
+pub enum AidoError {
+    #[error("{0}")]
+    Usage(String),
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Recipe(#[from] RecipeError),
+
+    #[error(transparent)]
+    Package(#[from] PackageError),
+
+    #[error(transparent)]
+    Install(#[from] InstallError),
+
+    #[error(transparent)]
+    Llm(#[from] LlmError),
+
+    #[error(transparent)]
+    Run(#[from] RunError),
+
+    #[error(transparent)]
+    Lock(#[from] LockError),
+
+    #[error(transparent)]
+    Session(#[from] SessionError),
+
+    #[error(transparent)]
+    Batch(#[from] BatchError),
+
+    #[error(transparent)]
+    Workflow(#[from] WorkflowError),
+
+    #[error(transparent)]
+    Bundle(#[from] BundleError),
+
+    #[error(transparent)]
+    Commit(#[from] CommitError),
+
+    #[error(transparent)]
+    Compare(#[from] CompareError),
+
+    #[error(transparent)]
+    Context(#[from] ContextError),
+
+    #[error(transparent)]
+    Isolation(#[from] IsolationError),
+
+    #[error(transparent)]
+    Preamble(#[from] PreambleError),
+
+    #[error(transparent)]
+    Redact(#[from] RedactError),
+
+    #[error(transparent)]
+    Retrieval(#[from] RetrievalError),
+
+    #[error(transparent)]
+    Clipboard(#[from] ClipboardError),
+
+    #[error(transparent)]
+    Cancel(#[from] CancelError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A failure described only by its message
+    #[error("{0}")]
+    Message(String),
+
+    /// An error from code outside aido, such as a middleware
+    #[error("{0}")]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl AidoError {
+    /// The status the command exits with after this error
+    pub const fn exit_code(&self) -> u8 {
+        match self {
+            Self::Usage(_) | Self::Compare(CompareError::TooFewModels) => 2,
+            Self::Config(_) => 3,
+            Self::Recipe(_)
+            | Self::Package(_)
+            | Self::Install(_)
+            | Self::Workflow(_) => 4,
+            Self::Llm(_)
+            | Self::Compare(CompareError::Failed { .. })
+            | Self::Retrieval(RetrievalError::Llm(_)) => 5,
+            Self::Run(_) => 6,
+            Self::Lock(LockError::Busy { .. }) => 7,
+            _ => 1,
+        }
+    }
+}
+
+impl From<confy::ConfyError> for AidoError {
+    fn from(error: confy::ConfyError) -> Self {
+        Self::Config(error.into())
+    }
+}
+
+impl From<String> for AidoError {
+    fn from(message: String) -> Self {
+        Self::Message(message)
+    }
+}
+
+impl From<&str> for AidoError {
+    fn from(message: &str) -> Self {
+        Self::Message(message.to_owned())
+    }
+}
+
+impl From<Box<dyn std::error::Error + Send + Sync>> for AidoError {
+    fn from(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        Self::Other(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let not_found = RecipeError::NotFound { name: "x".to_owned() };
+        assert_eq!(AidoError::from(not_found).exit_code(), 4);
+        let limit = RunError::IterationLimit { limit: 3 };
+        assert_eq!(AidoError::from(limit).exit_code(), 6);
+        let unknown = LlmError::MissingData("choices".to_owned());
+        assert_eq!(AidoError::from(unknown).exit_code(), 5);
+        assert_eq!(AidoError::from("no luck").exit_code(), 1);
+        assert_eq!(AidoError::from("no luck").to_string(), "no luck");
+    }
+}
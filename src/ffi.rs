@@ -0,0 +1,202 @@
+//! C-compatible bindings, enabled with the `ffi` feature
+//!
+//! Build the shared library with `just build-ffi` and load
+//! `target/release/libaido.so` from any language with a C FFI. Python
+//! callers are better served by the extension module of the `python`
+//! feature.
+//!
+//! Every function takes UTF-8, NUL-terminated strings and returns a newly
+//! allocated JSON string that must be released with [`aido_string_free`].
+//! Runs and questions return the same document as `--output json`; failures
+//! return `{"error": "..."}`. Nothing is printed to the terminal, and tool
+//! calls that a recipe wants confirmed are declined.
+
+use std::ffi::{CStr, CString, c_char};
+use std::ptr;
+
+use serde_json::json;
+
+use crate::output;
+use crate::recipe::RecipeStore;
+use crate::run::RunOutcome;
+use crate::runner::Runner;
+
+type FfiResult = Result<String, Box<dyn std::error::Error>>;
+
+/// Reads an optional string argument
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a valid NUL-terminated string.
+unsafe fn read_str<'a>(ptr: *const c_char) -> Result<Option<&'a str>, String> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+
+    // SAFETY: non-null and NUL-terminated as promised by the caller
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map(Some)
+        .map_err(|e| format!("Argument is not valid UTF-8: {e}"))
+}
+
+/// Reads a string argument that must be present
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a valid NUL-terminated string.
+unsafe fn require_str<'a>(
+    ptr: *const c_char,
+    name: &str,
+) -> Result<&'a str, String> {
+    // SAFETY: forwarded from the caller
+    unsafe { read_str(ptr) }?.ok_or_else(|| format!("Missing {name}"))
+}
+
+fn runner(config_path: &str) -> Result<Runner, Box<dyn std::error::Error>> {
+    Ok(Runner::from_config_file(config_path)?
+        .on_text(|_| {})
+        .on_confirm(|_| false))
+}
+
+/// Blocks on `future` with a runtime local to this call
+fn block_on<F: Future<Output = FfiResult>>(future: F) -> FfiResult {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(future)
+}
+
+fn outcome_json(outcome: &RunOutcome) -> FfiResult {
+    let mut buffer = Vec::new();
+    output::write_json(&mut buffer, outcome)?;
+
+    Ok(String::from_utf8(buffer)?.trim_end().to_owned())
+}
+
+/// Hands a result to the caller as an owned C string
+fn into_c_string(result: FfiResult) -> *mut c_char {
+    let json = result
+        .unwrap_or_else(|e| json!({ "error": e.to_string() }).to_string());
+
+    // JSON never contains raw NUL bytes, since they are escaped
+    CString::new(json).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+/// Asks a question without a recipe
+///
+/// # Safety
+///
+/// Both arguments must be null or valid NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn aido_ask(
+    config_path: *const c_char,
+    question: *const c_char,
+) -> *mut c_char {
+    into_c_string((|| {
+        // SAFETY: forwarded from the caller
+        let config_path = unsafe { require_str(config_path, "config path") }?;
+        let question = unsafe { require_str(question, "question") }?;
+
+        let runner = runner(config_path)?;
+        block_on(async { outcome_json(&runner.ask(question).await?) })
+    })())
+}
+
+/// Runs a recipe, with an optional user message
+///
+/// # Safety
+///
+/// Every argument must be null or a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn aido_run(
+    config_path: *const c_char,
+    recipe: *const c_char,
+    user_message: *const c_char,
+) -> *mut c_char {
+    into_c_string((|| {
+        // SAFETY: forwarded from the caller
+        let config_path = unsafe { require_str(config_path, "config path") }?;
+        let recipe = unsafe { require_str(recipe, "recipe") }?;
+        let user_message = unsafe { read_str(user_message) }?;
+
+        let runner = runner(config_path)?;
+        block_on(async {
+            let outcome = runner
+                .run_recipe(recipe, user_message.map(str::to_owned))
+                .await?;
+            outcome_json(&outcome)
+        })
+    })())
+}
+
+/// Lists the available recipes as `[{"name": ..., "display_name": ...}]`
+///
+/// # Safety
+///
+/// `config_path` must be null or a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn aido_list_recipes(
+    config_path: *const c_char,
+) -> *mut c_char {
+    into_c_string((|| {
+        // SAFETY: forwarded from the caller
+        let config_path = unsafe { require_str(config_path, "config path") }?;
+
+        let recipes = RecipeStore::for_config_file(config_path)
+            .list()?
+            .into_iter()
+            .map(|r| json!({ "name": r.name, "display_name": r.display_name }))
+            .collect::<Vec<_>>();
+
+        Ok(serde_json::to_string(&recipes)?)
+    })())
+}
+
+/// Releases a string returned by any other `aido_` function
+///
+/// # Safety
+///
+/// `s` must be null or a pointer returned by this library that hasn't been
+/// freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn aido_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        // SAFETY: allocated by `CString::into_raw` in `into_c_string`
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(s: *mut c_char) -> serde_json::Value {
+        // SAFETY: returned by the function under test
+        let json = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_owned();
+        unsafe { aido_string_free(s) };
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_errors_are_returned_as_json() {
+        let result = take(unsafe { aido_ask(ptr::null(), ptr::null()) });
+
+        assert_eq!(result["error"], "Missing config path");
+    }
+
+    #[test]
+    fn test_list_recipes() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-ffi-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("recipes")).unwrap();
+        std::fs::write(dir.join("recipes/do.recipe"), "Body.").unwrap();
+        let config_path =
+            CString::new(dir.join("config.toml").to_str().unwrap()).unwrap();
+
+        let result = take(unsafe { aido_list_recipes(config_path.as_ptr()) });
+
+        assert_eq!(result[0]["name"], "do");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
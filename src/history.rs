@@ -0,0 +1,253 @@
+//! The history of the prompts typed on the command line
+//!
+//! Every message given with `--input` and every recipe run with `aido run`
+//! appends one line of JSON to the history file next to the config file.
+//! `aido history` lists the prompts, numbered like shell history, and
+//! `aido rerun N`, or `aido '!!'` for the last one, runs one of them again
+//! with the current settings.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the history file inside the config directory
+const HISTORY_FILE_NAME: &str = "history.jsonl";
+
+/// Longest part of a message `aido history` shows, in characters
+const MAX_SHOWN_CHARS: usize = 60;
+
+/// A prompt as it was given on the command line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch when the prompt was given
+    pub timestamp: u64,
+    /// Recipe that was run, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipe: Option<String>,
+    /// The user message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Recipes chained after the recipe with `--then`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub then: Vec<String>,
+    /// Values given for the recipe's template variables with `--var`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+}
+
+impl HistoryEntry {
+    /// Creates an entry for a prompt given just now
+    pub fn new(recipe: Option<String>, message: Option<String>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Self {
+            timestamp,
+            recipe,
+            message,
+            then: Vec::new(),
+            vars: BTreeMap::new(),
+        }
+    }
+
+    /// The same prompt, given again just now
+    #[must_use]
+    pub fn given_again(&self) -> Self {
+        Self { timestamp: Self::new(None, None).timestamp, ..self.clone() }
+    }
+
+    #[must_use]
+    pub fn with_then(mut self, then: &[String]) -> Self {
+        self.then = then.to_vec();
+        self
+    }
+
+    #[must_use]
+    pub fn with_vars(
+        mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        self.vars = vars.into_iter().collect();
+        self
+    }
+}
+
+impl fmt::Display for HistoryEntry {
+    /// Shows the entry on one line, as `recipe: message`, shortening long
+    /// messages
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(recipe) = &self.recipe {
+            write!(f, "{recipe}")?;
+            for next in &self.then {
+                write!(f, " --then {next}")?;
+            }
+            for (name, value) in &self.vars {
+                write!(f, " --var {name}={value}")?;
+            }
+            if self.message.is_some() {
+                write!(f, ": ")?;
+            }
+        }
+
+        let Some(message) = &self.message else {
+            return Ok(());
+        };
+        let message = message.replace(['\n', '\r'], " ");
+        match message.char_indices().nth(MAX_SHOWN_CHARS) {
+            Some((end, _)) => write!(f, "{}…", &message[..end]),
+            None => write!(f, "{message}"),
+        }
+    }
+}
+
+/// Append-only log of the prompts typed on the command line
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    /// Opens the history stored next to the given config file
+    pub fn for_config_file(config_file_path: &str) -> Self {
+        let path = Path::new(config_file_path)
+            .parent()
+            .expect("Config file path should have a parent directory")
+            .join(HISTORY_FILE_NAME);
+
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends an entry to the history
+    pub fn record(&self, entry: &HistoryEntry) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file =
+            OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+
+        Ok(())
+    }
+
+    /// Reads every entry in the history, oldest first, skipping lines that
+    /// cannot be parsed
+    pub fn entries(&self) -> std::io::Result<Vec<HistoryEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let file = std::fs::File::open(&self.path)?;
+        let mut entries = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            if let Ok(entry) = serde_json::from_str(&line?) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// The entry numbered `number` by `aido history`, counting from 1, or
+    /// the last entry without a number
+    pub fn get(
+        &self,
+        number: Option<usize>,
+    ) -> std::io::Result<Option<HistoryEntry>> {
+        let mut entries = self.entries()?;
+
+        Ok(match number {
+            None => entries.pop(),
+            Some(0) => None,
+            Some(number) if number <= entries.len() => {
+                Some(entries.swap_remove(number - 1))
+            }
+            Some(_) => None,
+        })
+    }
+}
+
+/// Prints the last `limit` entries of the history, oldest first, with the
+/// numbers `aido rerun` takes
+pub fn print_entries(history: &History, limit: usize) -> std::io::Result<()> {
+    let entries = history.entries()?;
+
+    if entries.is_empty() {
+        println!("No prompts recorded yet.");
+        return Ok(());
+    }
+
+    let start = entries.len().saturating_sub(limit);
+    for (index, entry) in entries.iter().enumerate().skip(start) {
+        println!(
+            "{:>5}  {} UTC  {entry}",
+            index + 1,
+            crate::audit::format_timestamp(entry.timestamp)
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_get() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-history-test-{}", std::process::id()));
+        let history =
+            History::for_config_file(dir.join("aido.toml").to_str().unwrap());
+
+        assert_eq!(history.get(None).unwrap(), None);
+
+        let first = HistoryEntry::new(None, Some("untar a.tgz".to_owned()));
+        let second = HistoryEntry::new(Some("commit".to_owned()), None)
+            .with_then(&["review".to_owned()])
+            .with_vars([("style".to_owned(), "terse".to_owned())]);
+        history.record(&first).unwrap();
+        history.record(&second).unwrap();
+
+        assert_eq!(
+            history.entries().unwrap(),
+            [first.clone(), second.clone()]
+        );
+        assert_eq!(history.get(None).unwrap(), Some(second));
+        assert_eq!(history.get(Some(1)).unwrap(), Some(first));
+        assert_eq!(history.get(Some(0)).unwrap(), None);
+        assert_eq!(history.get(Some(3)).unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_display() {
+        let entry = HistoryEntry::new(
+            Some("review".to_owned()),
+            Some("check\nthe retries".to_owned()),
+        )
+        .with_vars([("branch".to_owned(), "main".to_owned())]);
+        assert_eq!(
+            entry.to_string(),
+            "review --var branch=main: check the retries"
+        );
+
+        let long = HistoryEntry::new(None, Some("a".repeat(100)));
+        assert_eq!(
+            long.to_string(),
+            format!("{}…", "a".repeat(MAX_SHOWN_CHARS))
+        );
+    }
+}
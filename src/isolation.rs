@@ -0,0 +1,286 @@
+//! Isolated execution of agent runs
+//!
+//! When a run is isolated, tools execute inside a temporary git worktree of
+//! the current repository rather than the user's working tree. Once the run
+//! finishes, the changes made inside the worktree are collected as a patch
+//! that the user can review and apply themselves.
+//!
+//! The worktree starts from the files as the user has them: uncommitted
+//! changes and untracked files are carried over, and committed in the
+//! worktree so that the patch only holds what the run changed.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use thiserror::Error;
+
+/// Errors that can occur while setting up or tearing down an isolated run
+#[derive(Error, Debug)]
+pub enum IsolationError {
+    #[error("--isolated requires running inside a git repository")]
+    NotARepository,
+
+    #[error("git {command} failed: {stderr}")]
+    Git { command: String, stderr: String },
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// A temporary, detached git worktree checked out at `HEAD`, with the
+/// uncommitted changes of the repository on top
+///
+/// The worktree is removed again when this value is dropped.
+#[derive(Debug)]
+pub struct Worktree {
+    /// Root of the repository the worktree was created from
+    repo_root: PathBuf,
+    /// Location of the temporary worktree
+    path: PathBuf,
+    /// Directory the process was in, relative to the repository root
+    relative_dir: PathBuf,
+}
+
+impl Worktree {
+    /// Creates a worktree for the repository containing the current directory
+    pub fn create() -> Result<Self, IsolationError> {
+        Self::for_dir(&std::env::current_dir()?)
+    }
+
+    /// Creates a worktree for the repository containing `cwd`
+    pub fn for_dir(cwd: &Path) -> Result<Self, IsolationError> {
+        let repo_root =
+            repo_root(cwd).ok_or(IsolationError::NotARepository)?;
+
+        let relative_dir = cwd
+            .strip_prefix(&repo_root)
+            .map_or_else(|_| PathBuf::new(), Path::to_path_buf);
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let path = std::env::temp_dir()
+            .join(format!("aido-worktree-{}-{nanos}", std::process::id()));
+
+        git(
+            &repo_root,
+            &["worktree", "add", "--detach", &path.to_string_lossy(), "HEAD"],
+        )?;
+
+        info!("Created isolated worktree at {}", path.display());
+
+        // Removed again on drop if carrying the changes over fails
+        let worktree = Self { repo_root, path, relative_dir };
+        worktree.carry_over_changes()?;
+
+        Ok(worktree)
+    }
+
+    /// Copies the uncommitted changes and untracked files of the repository
+    /// into the worktree and commits them there, so that they are part of
+    /// `HEAD` for [`Worktree::diff`]
+    fn carry_over_changes(&self) -> Result<(), IsolationError> {
+        let changes = git(
+            &self.repo_root,
+            &[
+                "diff",
+                "--binary",
+                "--no-ext-diff",
+                "--no-textconv",
+                "--src-prefix=a/",
+                "--dst-prefix=b/",
+                "HEAD",
+            ],
+        )?;
+        let untracked = git(
+            &self.repo_root,
+            &["ls-files", "--others", "--exclude-standard", "-z"],
+        )?;
+        if changes.is_empty() && untracked.is_empty() {
+            return Ok(());
+        }
+
+        if !changes.is_empty() {
+            let patch_path = self.path.with_extension("base.patch");
+            std::fs::write(&patch_path, &changes)?;
+            let applied = git(
+                &self.path,
+                &["apply", "--binary", &patch_path.to_string_lossy()],
+            );
+            std::fs::remove_file(&patch_path)?;
+            applied?;
+        }
+        // Nested repositories are listed as directories, and left out
+        let untracked = untracked
+            .split('\0')
+            .filter(|file| !file.is_empty() && !file.ends_with('/'));
+        for file in untracked {
+            let to = self.path.join(file);
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(self.repo_root.join(file), to)?;
+        }
+
+        git(&self.path, &["add", "--all"])?;
+        git(
+            &self.path,
+            &[
+                "-c",
+                "user.name=aido",
+                "-c",
+                "user.email=aido@localhost",
+                "-c",
+                "commit.gpgsign=false",
+                "-c",
+                "core.hooksPath=/dev/null",
+                "commit",
+                "--quiet",
+                "--no-verify",
+                "--message",
+                "Uncommitted changes",
+            ],
+        )?;
+        info!("Carried the uncommitted changes over to the isolated worktree");
+
+        Ok(())
+    }
+
+    /// The directory inside the worktree corresponding to the directory the
+    /// user invoked aido from
+    #[must_use]
+    pub fn working_dir(&self) -> PathBuf {
+        self.path.join(&self.relative_dir)
+    }
+
+    /// Collects every change made inside the worktree, including new files,
+    /// as a patch relative to the files the run started from
+    pub fn diff(&self) -> Result<String, IsolationError> {
+        git(&self.path, &["add", "--all"])?;
+        git(&self.path, &["diff", "--cached", "--binary", "HEAD"])
+    }
+
+    /// Writes the changes made inside the worktree to `out`, where the
+    /// answer goes, and saves them to a patch file the user can apply with
+    /// `git apply`
+    pub fn present_diff(
+        &self,
+        out: &mut dyn Write,
+    ) -> Result<(), IsolationError> {
+        let diff = self.diff()?;
+
+        if diff.trim().is_empty() {
+            eprintln!("No changes were made in the isolated worktree.");
+            return Ok(());
+        }
+
+        let patch_path = self.path.with_extension("patch");
+        std::fs::write(&patch_path, &diff)?;
+
+        writeln!(out, "{diff}")?;
+        out.flush()?;
+        eprintln!(
+            "Changes saved to {}; apply them with:\n  git -C {} apply {}",
+            patch_path.display(),
+            self.repo_root.display(),
+            patch_path.display()
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let path = self.path.to_string_lossy();
+        if let Err(e) =
+            git(&self.repo_root, &["worktree", "remove", "--force", &path])
+        {
+            warn!("Failed to remove isolated worktree {path}: {e}");
+        }
+    }
+}
+
+/// Root of the git repository containing `dir`, if there is one
+pub fn repo_root(dir: &Path) -> Option<PathBuf> {
+    git(dir, &["rev-parse", "--show-toplevel"])
+        .ok()
+        .map(|root| PathBuf::from(root.trim()))
+}
+
+/// Runs git with the given arguments in `dir`, returning its stdout
+fn git(dir: &Path, args: &[&str]) -> Result<String, IsolationError> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+
+    if !output.status.success() {
+        // The subcommand, after any `-c name=value` settings
+        let settings = args
+            .chunks(2)
+            .take_while(|pair| pair.first() == Some(&"-c"))
+            .count();
+        return Err(IsolationError::Git {
+            command: args
+                .get(settings * 2)
+                .copied()
+                .unwrap_or_default()
+                .to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uncommitted_changes_are_carried_over() {
+        let repo = std::env::temp_dir()
+            .join(format!("aido-isolation-test-{}", std::process::id()));
+        std::fs::create_dir_all(repo.join("src")).unwrap();
+        std::fs::write(repo.join("src/lib.rs"), "committed\n").unwrap();
+        git(&repo, &["init", "--quiet"]).unwrap();
+        git(&repo, &["add", "--all"]).unwrap();
+        git(
+            &repo,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@localhost",
+                "commit",
+                "--quiet",
+                "--message",
+                "Initial",
+            ],
+        )
+        .unwrap();
+        std::fs::write(repo.join("src/lib.rs"), "edited\n").unwrap();
+        std::fs::write(repo.join("src/new.rs"), "untracked\n").unwrap();
+
+        let worktree = Worktree::for_dir(&repo.join("src")).unwrap();
+        let dir = worktree.working_dir();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("lib.rs")).unwrap(),
+            "edited\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("new.rs")).unwrap(),
+            "untracked\n"
+        );
+        assert!(worktree.diff().unwrap().is_empty());
+
+        std::fs::write(dir.join("lib.rs"), "changed by the run\n").unwrap();
+        let diff = worktree.diff().unwrap();
+        assert!(diff.contains("-edited\n+changed by the run"));
+        assert!(!diff.contains("new.rs"));
+
+        drop(worktree);
+        std::fs::remove_dir_all(repo).unwrap();
+    }
+}
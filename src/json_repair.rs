@@ -0,0 +1,260 @@
+//! Lenient parsing of model-produced JSON
+//!
+//! Models occasionally emit tool arguments that are almost, but not quite,
+//! valid JSON: trailing commas, raw newlines inside strings, single-quoted
+//! strings, unquoted keys, comments, or a code fence around the document.
+//! The repair pass here rewrites those json5-style constructs into strict
+//! JSON so they can be handed to `serde_json`.
+//!
+//! A document cut off mid-stream, such as arguments that ran into
+//! `max_tokens`, is not completed: closing it would run a tool with a
+//! truncated path or file body as if it were whole. It fails with
+//! [`ParseError::Truncated`] instead, so the model can be told.
+
+use std::fmt::Write;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("the JSON ends inside {inside}, as if it was cut off")]
+    Truncated { inside: &'static str },
+
+    #[error(transparent)]
+    Invalid(#[from] serde_json::Error),
+}
+
+/// Parses `input` as JSON, attempting a repair pass if strict parsing fails
+///
+/// When the repaired document still does not parse, the error from the
+/// original, strict attempt is returned since it points at the real problem.
+pub fn parse_lenient<T: DeserializeOwned>(
+    input: &str,
+) -> Result<T, ParseError> {
+    serde_json::from_str(input).or_else(|original_error| {
+        serde_json::from_str(&repair(input)?)
+            .map_err(|_| ParseError::Invalid(original_error))
+    })
+}
+
+/// Rewrites almost-JSON into strict JSON
+///
+/// Handles trailing commas, unescaped control characters in strings,
+/// single-quoted strings, unquoted object keys, `//` and `/* */` comments,
+/// and a code fence around the document. Fails if the document ends inside
+/// a string, array or object.
+pub fn repair(input: &str) -> Result<String, ParseError> {
+    let input = strip_fence(input);
+    let mut out = String::with_capacity(input.len());
+    let mut open_brackets = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                if !repair_string(c, &mut chars, &mut out) {
+                    return Err(ParseError::Truncated { inside: "a string" });
+                }
+            }
+            '{' | '[' => {
+                open_brackets.push(if c == '{' { '}' } else { ']' });
+                out.push(c);
+            }
+            '}' | ']' => {
+                remove_trailing_comma(&mut out);
+                open_brackets.pop();
+                out.push(c);
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = '\0';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            c if is_identifier_start(c)
+                && open_brackets.last() == Some(&'}')
+                && matches!(
+                    out.trim_end().chars().last(),
+                    Some('{' | ',')
+                ) =>
+            {
+                out.push('"');
+                out.push(c);
+                while let Some(c) = chars.next_if(|&c| is_identifier_char(c)) {
+                    out.push(c);
+                }
+                out.push('"');
+            }
+            c => out.push(c),
+        }
+    }
+
+    match open_brackets.last() {
+        Some('}') => Err(ParseError::Truncated { inside: "an object" }),
+        Some(_) => Err(ParseError::Truncated { inside: "an array" }),
+        None => Ok(out),
+    }
+}
+
+/// `input` without the Markdown code fence around it, if there is one
+fn strip_fence(input: &str) -> &str {
+    let trimmed = input.trim();
+    let Some(fenced) = trimmed.strip_prefix("```") else {
+        return input;
+    };
+    // The info string, such as `json`, runs to the end of the first line
+    let Some((_, body)) = fenced.split_once('\n') else {
+        return input;
+    };
+
+    body.trim_end().strip_suffix("```").unwrap_or(input)
+}
+
+/// Copies a string literal opened by `quote` into `out` as a double-quoted
+/// JSON string, escaping anything strict JSON doesn't allow raw; returns
+/// whether the literal was closed
+fn repair_string(
+    quote: char,
+    chars: &mut Peekable<Chars<'_>>,
+    out: &mut String,
+) -> bool {
+    out.push('"');
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                // `\'` is not a valid JSON escape
+                Some('\'') => out.push('\''),
+                Some(escaped) => {
+                    out.push('\\');
+                    out.push(escaped);
+                }
+                None => {}
+            },
+            c if c == quote => {
+                out.push('"');
+                return true;
+            }
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+
+    false
+}
+
+fn remove_trailing_comma(out: &mut String) {
+    let trimmed_len = out.trim_end().len();
+    if out[..trimmed_len].ends_with(',') {
+        out.truncate(trimmed_len - 1);
+    }
+}
+
+const fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '$'
+}
+
+const fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '$'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn parse(input: &str) -> Value {
+        parse_lenient(input).unwrap()
+    }
+
+    #[test]
+    fn test_valid_json_is_untouched() {
+        let input = r#"{"args": "-al", "n": [1, 2]}"#;
+
+        assert_eq!(repair(input).unwrap(), input);
+        assert_eq!(parse(input), json!({"args": "-al", "n": [1, 2]}));
+    }
+
+    #[test]
+    fn test_trailing_commas() {
+        assert_eq!(
+            parse(r#"{"a": [1, 2, ], "b": true, }"#),
+            json!({"a": [1, 2], "b": true})
+        );
+    }
+
+    #[test]
+    fn test_raw_control_characters_in_strings() {
+        assert_eq!(
+            parse("{\"text\": \"line one\nline two\ttabbed\"}"),
+            json!({"text": "line one\nline two\ttabbed"})
+        );
+    }
+
+    #[test]
+    fn test_single_quotes_and_unquoted_keys() {
+        assert_eq!(
+            parse(r#"{pattern: 'say "hi"', path: 'it\'s', flag: false}"#),
+            json!({"pattern": "say \"hi\"", "path": "it's", "flag": false})
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            parse("{\n  // the flags\n  \"args\": \"-l\" /* long */\n}"),
+            json!({"args": "-l"})
+        );
+    }
+
+    #[test]
+    fn test_code_fence() {
+        assert_eq!(
+            parse("```json\n{\"args\": \"-l\",}\n```"),
+            json!({"args": "-l"})
+        );
+        assert_eq!(parse("```\n[1, 2]\n```\n"), json!([1, 2]));
+    }
+
+    #[test]
+    fn test_truncated_document_is_an_error() {
+        for (input, inside) in [
+            (r#"{"path": "src/ma"#, "a string"),
+            (r#"{"args": ["a", "b"]"#, "an object"),
+            (r#"{"args": ["a", "b", "#, "an array"),
+            ("{'content': 'fn main() {", "a string"),
+        ] {
+            let error = parse_lenient::<Value>(input).unwrap_err();
+            assert!(
+                matches!(error, ParseError::Truncated { inside: i } if i == inside),
+                "{input}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_unrepairable_returns_original_error() {
+        let result: Result<Value, _> = parse_lenient(r#"{"a" 1}"#);
+
+        let Err(ParseError::Invalid(error)) = result else {
+            panic!("expected a JSON error, got {result:?}");
+        };
+        assert_eq!(error.column(), 6);
+    }
+}
@@ -0,0 +1,266 @@
+//! Guessing whether an answer is written in the language asked for
+//!
+//! When `output_language` is set, answers in another language are sent back
+//! to the model once with a request to answer again. Detection is a cheap
+//! heuristic: the writing system of the answer's letters tells most
+//! languages apart, and for languages written in the Latin alphabet the
+//! most common words are compared against English. Answers too short to
+//! judge always pass.
+
+use crate::markdown;
+
+/// Fewest letters an answer needs before its language is judged
+const MIN_LETTERS: usize = 20;
+
+/// Fraction of letters that must belong to the expected writing system
+const MIN_SCRIPT_SHARE: f64 = 0.5;
+
+/// Writing systems told apart by their Unicode ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Thai,
+    Hangul,
+    Kana,
+    Han,
+}
+
+impl Script {
+    fn of(c: char) -> Option<Self> {
+        let script = match c {
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Self::Latin,
+            '\u{0370}'..='\u{03FF}' => Self::Greek,
+            '\u{0400}'..='\u{04FF}' => Self::Cyrillic,
+            '\u{0590}'..='\u{05FF}' => Self::Hebrew,
+            '\u{0600}'..='\u{06FF}' => Self::Arabic,
+            '\u{0900}'..='\u{097F}' => Self::Devanagari,
+            '\u{0E00}'..='\u{0E7F}' => Self::Thai,
+            '\u{AC00}'..='\u{D7AF}' | '\u{1100}'..='\u{11FF}' => Self::Hangul,
+            '\u{3040}'..='\u{30FF}' => Self::Kana,
+            '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' => Self::Han,
+            _ => return None,
+        };
+        Some(script)
+    }
+}
+
+/// A language with the scripts it is written in and, for Latin-script
+/// languages, some of its most common words
+struct Language {
+    names: &'static [&'static str],
+    scripts: &'static [Script],
+    common_words: &'static [&'static str],
+}
+
+const ENGLISH_WORDS: &[&str] = &[
+    "the", "and", "is", "are", "to", "of", "in", "that", "it", "you", "for",
+    "with", "this", "be", "on", "not",
+];
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        names: &["en", "english"],
+        scripts: &[Script::Latin],
+        common_words: ENGLISH_WORDS,
+    },
+    Language {
+        names: &["de", "german", "deutsch"],
+        scripts: &[Script::Latin],
+        common_words: &[
+            "der", "die", "das", "und", "ist", "nicht", "ein", "eine", "zu",
+            "mit", "sie", "ich", "du", "auf", "für", "es",
+        ],
+    },
+    Language {
+        names: &["es", "spanish", "español"],
+        scripts: &[Script::Latin],
+        common_words: &[
+            "el", "la", "los", "las", "de", "que", "y", "es", "en", "un",
+            "una", "para", "con", "no", "por", "se",
+        ],
+    },
+    Language {
+        names: &["fr", "french", "français"],
+        scripts: &[Script::Latin],
+        common_words: &[
+            "le", "la", "les", "et", "est", "de", "des", "un", "une", "que",
+            "pour", "dans", "pas", "vous", "avec", "ce",
+        ],
+    },
+    Language {
+        names: &["it", "italian", "italiano"],
+        scripts: &[Script::Latin],
+        common_words: &[
+            "il", "la", "di", "che", "e", "è", "un", "una", "per", "non",
+            "con", "sono", "gli", "le", "del", "questo",
+        ],
+    },
+    Language {
+        names: &["pt", "portuguese", "português"],
+        scripts: &[Script::Latin],
+        common_words: &[
+            "o", "a", "os", "as", "de", "que", "e", "é", "um", "uma", "para",
+            "com", "não", "em", "do", "da",
+        ],
+    },
+    Language {
+        names: &["nl", "dutch", "nederlands"],
+        scripts: &[Script::Latin],
+        common_words: &[
+            "de", "het", "een", "en", "is", "van", "dat", "niet", "te", "met",
+            "je", "op", "voor", "zijn", "ik", "die",
+        ],
+    },
+    Language {
+        names: &["ru", "russian", "uk", "ukrainian", "bg", "bulgarian"],
+        scripts: &[Script::Cyrillic],
+        common_words: &[],
+    },
+    Language {
+        names: &["el", "greek"],
+        scripts: &[Script::Greek],
+        common_words: &[],
+    },
+    Language {
+        names: &["ar", "arabic", "fa", "persian"],
+        scripts: &[Script::Arabic],
+        common_words: &[],
+    },
+    Language {
+        names: &["he", "hebrew"],
+        scripts: &[Script::Hebrew],
+        common_words: &[],
+    },
+    Language {
+        names: &["hi", "hindi"],
+        scripts: &[Script::Devanagari],
+        common_words: &[],
+    },
+    Language {
+        names: &["th", "thai"],
+        scripts: &[Script::Thai],
+        common_words: &[],
+    },
+    Language {
+        names: &["ko", "korean"],
+        scripts: &[Script::Hangul],
+        common_words: &[],
+    },
+    Language {
+        names: &["ja", "japanese"],
+        scripts: &[Script::Kana, Script::Han],
+        common_words: &[],
+    },
+    Language {
+        names: &["zh", "chinese"],
+        scripts: &[Script::Han],
+        common_words: &[],
+    },
+];
+
+fn find_language(name: &str) -> Option<&'static Language> {
+    let name = name.trim().to_lowercase();
+    // Accept regional variants like "pt-BR"
+    let base = name.split(['-', '_']).next().unwrap_or_default();
+
+    LANGUAGES.iter().find(|l| l.names.contains(&base))
+}
+
+/// Counts how many words of `text` are among `words`
+fn count_common(text: &str, words: &[&str]) -> usize {
+    text.split(|c: char| !c.is_alphabetic())
+        .filter(|word| words.contains(&word.to_lowercase().as_str()))
+        .count()
+}
+
+/// Guesses whether `text` is written in `language`, given as an ISO 639-1
+/// code or English name
+///
+/// Returns `true` whenever it can't tell: for unknown languages, short
+/// texts, and texts that are mostly code.
+pub fn is_written_in(text: &str, language: &str) -> bool {
+    let Some(expected) = find_language(language) else {
+        return true;
+    };
+
+    // Code blocks are mostly English keywords whatever the prose is in
+    let mut prose = text.to_owned();
+    for block in markdown::code_blocks(text) {
+        prose = prose.replace(block, "");
+    }
+
+    let scripts = prose.chars().filter_map(Script::of).collect::<Vec<_>>();
+    if scripts.len() < MIN_LETTERS {
+        return true;
+    }
+
+    let in_script =
+        scripts.iter().filter(|s| expected.scripts.contains(s)).count();
+    #[allow(clippy::cast_precision_loss)] // Letter counts are small
+    if (in_script as f64) / (scripts.len() as f64) < MIN_SCRIPT_SHARE {
+        return false;
+    }
+
+    if expected.common_words.is_empty()
+        || expected.common_words == ENGLISH_WORDS
+    {
+        return true;
+    }
+
+    count_common(&prose, ENGLISH_WORDS)
+        <= count_common(&prose, expected.common_words)
+}
+
+/// The message asking the model to answer again in `language`
+pub fn corrective_instruction(language: &str) -> String {
+    format!(
+        "Your previous answer was not written in {language}. Answer again, \
+         entirely in {language}."
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latin_languages() {
+        let german = "Die Datei wurde gelöscht und ist nicht mehr auf der \
+                      Festplatte.";
+        let english = "The file was deleted and is not on the disk anymore.";
+
+        assert!(is_written_in(german, "de"));
+        assert!(is_written_in(german, "German"));
+        assert!(!is_written_in(english, "de"));
+        assert!(is_written_in(english, "en"));
+    }
+
+    #[test]
+    fn test_other_scripts() {
+        let russian = "Файл был удалён и больше не находится на диске.";
+        let japanese = "ファイルは削除され、もうディスクにはありません。";
+
+        assert!(is_written_in(russian, "ru"));
+        assert!(!is_written_in(russian, "es"));
+        assert!(is_written_in(japanese, "ja"));
+        assert!(!is_written_in(japanese, "ko"));
+        assert!(!is_written_in("The file was deleted from the disk.", "ru"));
+    }
+
+    #[test]
+    fn test_unsure_answers_pass() {
+        assert!(is_written_in("ok", "de"));
+        assert!(is_written_in("Anything at all goes here.", "klingon"));
+        // Only the prose outside code blocks counts
+        assert!(is_written_in(
+            "Führe das aus:\n```\nfind . -name '*.tar.gz' -exec tar -xzf \
+             {} + && echo done with all of the archives\n```",
+            "pt-BR"
+        ));
+    }
+}
@@ -0,0 +1,56 @@
+//! aido: do things with AI in your terminal
+//!
+//! The `aido` binary is a thin command line front end over this library.
+//! Embedders can drive the same agent loop through [`runner::Runner`], or
+//! [`run::run`] for full control, and extend it by registering
+//! [`middleware::Middleware`] on [`run::RunOptions`].
+
+pub mod audit;
+pub mod batch;
+pub mod bundle;
+pub mod cache;
+pub mod cancel;
+pub mod clipboard;
+pub mod commit;
+pub mod compare;
+pub mod config;
+pub mod confirm;
+pub mod context;
+pub mod diff;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod history;
+pub mod isolation;
+pub mod json_repair;
+pub mod language;
+pub mod limits;
+pub mod llm;
+pub mod lock;
+pub mod markdown;
+pub mod middleware;
+pub mod notices;
+pub mod otel;
+pub mod output;
+pub mod paths;
+pub mod preamble;
+pub mod project;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod recipe;
+pub mod redact;
+pub mod retrieval;
+pub mod run;
+pub mod runner;
+pub mod schema;
+pub mod serve;
+pub mod session;
+pub mod setup;
+pub mod shell;
+pub mod status;
+pub mod tokens;
+pub mod tools;
+pub mod trace;
+pub mod usage;
+pub mod verify;
+pub mod workflow;
@@ -0,0 +1,179 @@
+//! How many tokens models take, and requests that are too large for them
+//!
+//! Providers reject a request that doesn't fit the model's context window
+//! with a 400 error that rarely says which part of it is too large. Before
+//! each request, runs count its tokens and fail right away when it doesn't
+//! fit, saying what takes up the space and what can be done about it.
+//!
+//! The context windows of well-known models are built in; `context_windows`
+//! in the config adds others, or lowers them for servers that are set up to
+//! take less:
+//!
+//! ```toml
+//! [context_windows]
+//! "llama3.2:3b" = 8192
+//! ```
+//!
+//! Requests to models of unknown context windows are sent as they are.
+
+use crate::config::Config;
+use crate::tokens::TokenCount;
+
+/// Context windows in tokens of the models whose names start with each
+/// prefix; the longest matching prefix applies
+const KNOWN_CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-5", 400_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1-mini", 128_000),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+    ("claude", 200_000),
+    ("gemini-1.5-pro", 2_097_152),
+    ("gemini-1.5-flash", 1_048_576),
+    ("gemini-2", 1_048_576),
+];
+
+/// The context window of the model named `model`, from the config or the
+/// built-in list, if known
+///
+/// Built-in windows are looked up ignoring case and any `provider/` prefix.
+pub fn context_window(config: &Config, model: &str) -> Option<usize> {
+    if let Some(&window) = config.context_windows.get(model) {
+        return Some(window);
+    }
+
+    let name = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+    KNOWN_CONTEXT_WINDOWS
+        .iter()
+        .filter(|(prefix, _)| name.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|&(_, window)| window)
+}
+
+/// A request that doesn't fit the context window of its model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Oversize {
+    pub window: usize,
+    /// Tokens kept free for the answer, from `max_tokens`
+    pub reserved: usize,
+    pub tokens: TokenCount,
+}
+
+impl Oversize {
+    /// The request of `tokens` if it doesn't fit in `window` along with the
+    /// `reserved` tokens of the answer
+    pub fn check(
+        tokens: TokenCount,
+        window: usize,
+        reserved: usize,
+    ) -> Option<Self> {
+        (tokens.total() + reserved > window).then_some(Self {
+            window,
+            reserved,
+            tokens,
+        })
+    }
+
+    /// What each part of the request takes up, largest first
+    pub fn breakdown(&self) -> String {
+        let mut parts = self.parts();
+        parts.sort_by_key(|&(_, tokens)| std::cmp::Reverse(tokens));
+
+        parts
+            .iter()
+            .filter(|&&(_, tokens)| tokens > 0)
+            .map(|(part, tokens)| format!("{part} {tokens}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// How to make the request fit, starting with its largest part
+    pub fn advice(&self) -> String {
+        let largest = self
+            .parts()
+            .into_iter()
+            .max_by_key(|&(_, tokens)| tokens)
+            .map_or("", |(part, _)| part);
+
+        let advice = match largest {
+            "system prompt" => {
+                "shorten the recipe, or its examples, or the config's \
+                 `system_prompt_prelude`"
+            }
+            "earlier messages" => {
+                "compact the session with `aido session compact`, or set \
+                 `context_limit` in the config to have older turns \
+                 summarized"
+            }
+            "message" => {
+                "send less input; piped input to recipes is cut down to \
+                 `attachment_token_limit` tokens, and `aido ask` includes \
+                 files up to `context_budget`"
+            }
+            _ => "disable the tools you don't need under `[tools]`",
+        };
+        let reserved =
+            if self.reserved > 0 { ", lower `max_tokens`" } else { "" };
+
+        format!(
+            "To make it fit, {advice}{reserved}, or use a model with a \
+             larger context window"
+        )
+    }
+
+    fn parts(&self) -> [(&'static str, usize); 4] {
+        [
+            ("system prompt", self.tokens.system),
+            ("earlier messages", self.tokens.examples),
+            ("message", self.tokens.message),
+            ("tools", self.tokens.tools),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_window() {
+        let mut config = Config::default();
+        config.context_windows.insert("llama3.2:3b".to_owned(), 8192);
+
+        assert_eq!(context_window(&config, "gpt-4o-mini"), Some(128_000));
+        assert_eq!(context_window(&config, "gpt-4-0613"), Some(8_192));
+        assert_eq!(context_window(&config, "openai/GPT-4.1"), Some(1_047_576));
+        assert_eq!(context_window(&config, "llama3.2:3b"), Some(8192));
+        assert_eq!(context_window(&config, "llama3.2:1b"), None);
+    }
+
+    #[test]
+    fn test_oversize() {
+        let tokens = TokenCount {
+            tokenizer: "o200k_base",
+            system: 100,
+            examples: 7100,
+            message: 50,
+            tools: 0,
+            overhead: 10,
+        };
+
+        assert!(Oversize::check(tokens.clone(), 8192, 0).is_none());
+        let oversize = Oversize::check(tokens, 8192, 1024).unwrap();
+
+        assert_eq!(
+            oversize.breakdown(),
+            "earlier messages 7100, system prompt 100, message 50"
+        );
+        assert!(oversize.advice().contains("aido session compact"));
+        assert!(oversize.advice().contains("lower `max_tokens`"));
+    }
+}
@@ -1,6 +1,13 @@
+pub mod embeddings;
+mod mock;
+mod provider;
+
+pub use embeddings::Embeddings;
+pub use provider::{AzureSettings, Provider, ProviderConfig};
+
 use async_openai::{
-    Client,
-    config::OpenAIConfig,
+    config::Config as ApiConfig,
+    error::{ApiError as ApiErrorBody, OpenAIError},
     types::{
         ChatChoiceStream, ChatCompletionMessageToolCall,
         ChatCompletionMessageToolCallChunk,
@@ -12,18 +19,47 @@ use async_openai::{
         ChatCompletionRequestToolMessageContent,
         ChatCompletionRequestUserMessageArgs,
         ChatCompletionRequestUserMessageContent, ChatCompletionStreamOptions,
-        ChatCompletionTool, ChatCompletionToolType,
-        CreateChatCompletionRequestArgs, FunctionCall, FunctionCallStream,
-        FunctionObjectArgs,
+        ChatCompletionStreamResponseDelta, ChatCompletionTool,
+        ChatCompletionToolType, CompletionUsage, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse, FunctionCall, FunctionCallStream,
+        FunctionObjectArgs, ResponseFormat as ApiResponseFormat,
+        ResponseFormatJsonSchema, Stop,
     },
 };
-use futures_util::StreamExt;
-use log::{debug, error, trace};
+use eventsource_stream::Eventsource;
+use futures_util::{Stream, StreamExt};
+use log::{debug, error, trace, warn};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, btree_map::Entry};
 use std::fmt;
-use tokio::runtime::Runtime;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
+use crate::cache::{Key, ResponseCache};
+use crate::config::Config;
+use crate::notices::{self, Notice};
 use crate::tools::ToolDefinition;
 
+/// Progress of a streamed reply, reported as it arrives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEvent<'a> {
+    /// The first chunk of the reply arrived, which may carry no text yet
+    Started,
+    /// More text of the reply
+    Text(&'a str),
+    /// More of the reasoning a reasoning model does before replying
+    Reasoning(&'a str),
+    /// The model started writing a call to the named tool
+    ToolCall(&'a str),
+    /// The provider warned about the model, e.g. that it is deprecated
+    Notice(&'a Notice),
+    /// A chunk of the reply as the provider sent it, or the whole reply
+    /// when it isn't streamed
+    Chunk(&'a serde_json::Value),
+}
+
 /// Errors that can occur during LLM operations
 #[derive(Debug)]
 pub enum LlmError {
@@ -35,6 +71,8 @@ pub enum LlmError {
     InvalidResponse(String),
     /// Missing required data in response
     MissingData(String),
+    /// The provider can't promise to answer the same request the same way
+    NotReproducible(String),
 }
 
 impl fmt::Display for LlmError {
@@ -48,6 +86,9 @@ impl fmt::Display for LlmError {
             Self::MissingData(msg) => {
                 write!(f, "Missing required data: {msg}")
             }
+            Self::NotReproducible(msg) => {
+                write!(f, "Answers are not reproducible: {msg}")
+            }
         }
     }
 }
@@ -57,7 +98,9 @@ impl std::error::Error for LlmError {
         match self {
             Self::ApiError(e) => Some(e),
             Self::SerializationError(e) => Some(e),
-            Self::InvalidResponse(_) | Self::MissingData(_) => None,
+            Self::InvalidResponse(_)
+            | Self::MissingData(_)
+            | Self::NotReproducible(_) => None,
         }
     }
 }
@@ -78,9 +121,54 @@ type LlmResult<T> = Result<T, LlmError>;
 
 /// Client for interacting with Large Language Models via OpenAI-compatible APIs
 pub struct LlmClient {
-    client: Client<OpenAIConfig>,
+    http: reqwest::Client,
+    provider: ProviderConfig,
     model_name: String,
     temperature: f32,
+    max_tokens: Option<u32>,
+    top_p: Option<f32>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    stop: Vec<String>,
+    seed: Option<i64>,
+    /// Whether replies without a system fingerprint are an error
+    require_fingerprint: bool,
+    /// Sent as the `metadata` field of every request, if set
+    metadata: Option<serde_json::Value>,
+    /// Where replies are looked up before and stored after a request
+    cache: Option<ResponseCache>,
+    /// Whether replies are streamed, or requested whole from servers that
+    /// can't stream
+    stream: bool,
+    /// The fixture file replies are scripted in, for the mock provider
+    mock: Option<PathBuf>,
+}
+
+/// The form the model is asked to reply in, instead of free text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseFormat {
+    /// Any JSON object
+    JsonObject,
+    /// JSON matching `schema`, sent to the API under `name`
+    JsonSchema { name: String, schema: serde_json::Value },
+}
+
+impl From<ResponseFormat> for ApiResponseFormat {
+    fn from(format: ResponseFormat) -> Self {
+        match format {
+            ResponseFormat::JsonObject => Self::JsonObject,
+            ResponseFormat::JsonSchema { name, schema } => Self::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    description: None,
+                    name,
+                    schema: Some(schema),
+                    // Strict mode rejects schemas that leave any property
+                    // optional; answers are checked after the fact instead
+                    strict: None,
+                },
+            },
+        }
+    }
 }
 
 /// Request configuration for LLM chat completion
@@ -88,12 +176,25 @@ pub struct LlmClient {
 pub struct LlmRequest {
     messages: Vec<Message>,
     tools: Vec<ToolDefinition>,
+    response_format: Option<ResponseFormat>,
 }
 
 impl LlmRequest {
     /// Creates a new LLM request with the specified messages and tools
     pub fn new(messages: Vec<Message>, tools: Vec<ToolDefinition>) -> Self {
-        Self { messages, tools }
+        Self { messages, tools, response_format: None }
+    }
+
+    /// Asks for the reply in the given format
+    #[must_use]
+    pub fn with_response_format(mut self, format: ResponseFormat) -> Self {
+        self.response_format = Some(format);
+        self
+    }
+
+    /// Returns the format the reply is asked for in, if any
+    pub fn response_format(&self) -> Option<&ResponseFormat> {
+        self.response_format.as_ref()
     }
 
     /// Returns the messages in this request
@@ -101,6 +202,11 @@ impl LlmRequest {
         &self.messages
     }
 
+    /// Returns the messages in this request for modification
+    pub fn messages_mut(&mut self) -> &mut Vec<Message> {
+        &mut self.messages
+    }
+
     /// Returns the tools available for this request
     pub fn tools(&self) -> &[ToolDefinition] {
         &self.tools
@@ -161,7 +267,8 @@ impl From<Message> for ChatCompletionRequestMessage {
 }
 
 /// A message in a conversation with an LLM
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Message {
     /// A message from the user
     User(String),
@@ -179,28 +286,82 @@ pub struct LlmResponse {
     text: String,
     usage: Usage,
     tool_calls: Vec<ToolCall>,
+    reasoning: String,
+    timing: Option<Timing>,
 }
 
 impl LlmResponse {
+    /// Creates a response, e.g. one served without calling the model
+    pub fn new(
+        text: impl Into<String>,
+        usage: Usage,
+        tool_calls: Vec<ToolCall>,
+    ) -> Self {
+        Self {
+            text: text.into(),
+            usage,
+            tool_calls,
+            reasoning: String::new(),
+            timing: None,
+        }
+    }
+
+    /// Sets the reasoning that led to the response
+    #[must_use]
+    pub fn with_reasoning(mut self, reasoning: impl Into<String>) -> Self {
+        self.reasoning = reasoning.into();
+        self
+    }
+
+    /// Sets how long the response took to arrive
+    #[must_use]
+    pub fn with_timing(mut self, timing: Timing) -> Self {
+        self.timing = Some(timing);
+        self
+    }
+
     /// Returns the text content of the response
     pub fn text(&self) -> &str {
         &self.text
     }
 
+    /// Returns the text content of the response for modification
+    pub fn text_mut(&mut self) -> &mut String {
+        &mut self.text
+    }
+
+    /// Returns the reasoning that led to the response, empty for models
+    /// that don't report any
+    pub fn reasoning(&self) -> &str {
+        &self.reasoning
+    }
+
     /// Returns the usage statistics for this response
     pub fn usage(&self) -> &Usage {
         &self.usage
     }
 
+    /// Returns how long the response took to arrive, or `None` when it
+    /// didn't come from the model, e.g. when it was cached
+    pub fn timing(&self) -> Option<&Timing> {
+        self.timing.as_ref()
+    }
+
     /// Returns the tool calls made in this response
     pub fn tool_calls(&self) -> &[ToolCall] {
         &self.tool_calls
     }
+
+    /// Returns the tool calls made in this response for modification
+    pub fn tool_calls_mut(&mut self) -> &mut Vec<ToolCall> {
+        &mut self.tool_calls
+    }
 }
 
 /// Converts a stream chunk into an LLM response
 fn create_response_from_stream(
     stream: &ChatChoiceStream,
+    reasoning: &str,
     usage: Usage,
 ) -> LlmResponse {
     let mut text = String::new();
@@ -222,11 +383,52 @@ fn create_response_from_stream(
         }
     }
 
-    LlmResponse { text, usage, tool_calls }
+    LlmResponse {
+        text,
+        usage,
+        tool_calls,
+        reasoning: reasoning.to_owned(),
+        timing: None,
+    }
+}
+
+/// Converts the primary choice of a reply that wasn't streamed into an LLM
+/// response
+fn create_response_from_completion(
+    reply: &CreateChatCompletionResponse,
+    reasoning: &str,
+) -> LlmResult<LlmResponse> {
+    let choice = reply
+        .choices
+        .iter()
+        .find(|choice| choice.index == PRIMARY_CHOICE)
+        .ok_or_else(|| {
+            LlmError::MissingData("The reply has no first choice".to_string())
+        })?;
+
+    let tool_calls = choice
+        .message
+        .tool_calls
+        .iter()
+        .flatten()
+        .map(|call| ToolCall {
+            id: call.id.clone(),
+            name: call.function.name.clone(),
+            arguments: call.function.arguments.clone(),
+        })
+        .collect();
+
+    Ok(LlmResponse {
+        text: choice.message.content.clone().unwrap_or_default(),
+        usage: reply.usage.clone().map(Usage::from).unwrap_or_default(),
+        tool_calls,
+        reasoning: reasoning.to_owned(),
+        timing: None,
+    })
 }
 
 /// Represents a tool call made by the LLM
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ToolCall {
     id: String,
     name: String,
@@ -234,6 +436,15 @@ pub struct ToolCall {
 }
 
 impl ToolCall {
+    /// Creates a new tool call with the given id, tool name and arguments
+    pub fn new(
+        id: impl Into<String>,
+        name: impl Into<String>,
+        arguments: impl Into<String>,
+    ) -> Self {
+        Self { id: id.into(), name: name.into(), arguments: arguments.into() }
+    }
+
     /// Returns the name of the tool that was called
     pub fn name(&self) -> &str {
         &self.name
@@ -250,6 +461,22 @@ impl ToolCall {
     }
 }
 
+impl From<CompletionUsage> for Usage {
+    fn from(usage: CompletionUsage) -> Self {
+        Self::new(
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens,
+        )
+        .with_reasoning_tokens(
+            usage
+                .completion_tokens_details
+                .and_then(|d| d.reasoning_tokens)
+                .unwrap_or_default(),
+        )
+    }
+}
+
 impl From<ToolCall> for ChatCompletionMessageToolCall {
     fn from(tool_call: ToolCall) -> Self {
         Self {
@@ -264,12 +491,15 @@ impl From<ToolCall> for ChatCompletionMessageToolCall {
 }
 
 /// Token usage statistics for an LLM request
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[allow(clippy::struct_field_names)] // API response structure requires these exact names
 pub struct Usage {
     prompt_tokens: u32,
     completion_tokens: u32,
     total_tokens: u32,
+    /// Part of the completion tokens spent on reasoning
+    #[serde(default)]
+    reasoning_tokens: u32,
 }
 
 impl Usage {
@@ -279,7 +509,19 @@ impl Usage {
         completion_tokens: u32,
         total_tokens: u32,
     ) -> Self {
-        Self { prompt_tokens, completion_tokens, total_tokens }
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+            reasoning_tokens: 0,
+        }
+    }
+
+    /// Sets how many of the completion tokens were spent on reasoning
+    #[must_use]
+    pub fn with_reasoning_tokens(mut self, reasoning_tokens: u32) -> Self {
+        self.reasoning_tokens = reasoning_tokens;
+        self
     }
 
     /// Returns the number of tokens used in the prompt
@@ -296,17 +538,72 @@ impl Usage {
     pub fn total_tokens(&self) -> u32 {
         self.total_tokens
     }
+
+    /// Returns the number of completion tokens spent on reasoning
+    pub fn reasoning_tokens(&self) -> u32 {
+        self.reasoning_tokens
+    }
 }
 
-/// Global tokio runtime for handling async operations in sync contexts
-/// Uses a single-threaded runtime to minimize overhead
-static TOKIO_RUNTIME: std::sync::LazyLock<Runtime> =
-    std::sync::LazyLock::new(|| {
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .expect("Failed to create tokio runtime")
-    });
+impl std::ops::AddAssign<&Self> for Usage {
+    fn add_assign(&mut self, other: &Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} prompt + {} completion",
+            self.prompt_tokens, self.completion_tokens
+        )?;
+        if self.reasoning_tokens > 0 {
+            write!(f, " ({} reasoning)", self.reasoning_tokens)?;
+        }
+        write!(f, " = {} tokens", self.total_tokens)
+    }
+}
+
+/// How long a reply took to arrive, measured from sending the request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// Until the first token of the reply arrived; unknown for replies
+    /// that weren't streamed
+    pub first_token: Option<Duration>,
+    /// Until the whole reply had arrived
+    pub total: Duration,
+}
+
+impl Timing {
+    /// How many of `completion_tokens` were generated per second, counting
+    /// from the first token when that is known
+    pub fn tokens_per_second(&self, completion_tokens: u32) -> Option<f64> {
+        let generating =
+            self.total.saturating_sub(self.first_token.unwrap_or_default());
+        (completion_tokens > 0 && !generating.is_zero())
+            .then(|| f64::from(completion_tokens) / generating.as_secs_f64())
+    }
+}
+
+/// How long connecting to the API may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the API may go quiet, while a reply streams or before it
+/// starts, unless `read_timeout_secs` says otherwise
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_mins(5);
+
+/// An HTTP client giving up on connections that can't be made or stall
+fn http_client(read_timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(read_timeout)
+        .build()
+        .expect("HTTP client settings should be valid")
+}
 
 impl LlmClient {
     /// Creates a new LLM client with the specified configuration
@@ -315,16 +612,78 @@ impl LlmClient {
         api_key: impl Into<String>,
         base_uri: impl Into<String>,
     ) -> Self {
-        let config =
-            OpenAIConfig::new().with_api_key(api_key).with_api_base(base_uri);
+        let config = async_openai::config::OpenAIConfig::new()
+            .with_api_key(api_key)
+            .with_api_base(base_uri);
 
-        let client = Client::with_config(config);
+        Self::with_provider(model_name, ProviderConfig::openai(config))
+    }
+
+    /// Creates a client for the provider, model and sampling settings in
+    /// the user's config
+    pub fn from_config(config: &Config) -> Self {
+        let provider = ProviderConfig::from_config(config);
+        let mut llm = Self::with_provider(&config.model_name, provider);
+        if let Some(temperature) = config.temperature {
+            llm = llm.with_temperature(temperature);
+        }
+        if let Some(max_tokens) = config.max_tokens {
+            llm = llm.with_max_tokens(max_tokens);
+        }
+        if let Some(top_p) = config.top_p {
+            llm = llm.with_top_p(top_p);
+        }
+        if let Some(penalty) = config.frequency_penalty {
+            llm = llm.with_frequency_penalty(penalty);
+        }
+        if let Some(penalty) = config.presence_penalty {
+            llm = llm.with_presence_penalty(penalty);
+        }
+        if !config.stop.is_empty() {
+            llm = llm.with_stop(config.stop.clone());
+        }
+        if let Some(seed) = config.seed {
+            llm = llm.with_seed(seed);
+        }
+        if !config.request_metadata.is_empty() {
+            llm =
+                llm.with_metadata(serde_json::json!(config.request_metadata));
+        }
+        if let Some(stream) = config.stream {
+            llm = llm.with_streaming(stream);
+        }
+        if let Some(secs) = config.read_timeout_secs {
+            llm = llm.with_read_timeout(Duration::from_secs(secs));
+        }
+        if config.provider == Provider::Mock {
+            llm.mock = Some(config.fixture.clone().unwrap_or_default());
+        }
+        llm
+    }
+
+    /// Creates a new LLM client talking to the given provider
+    pub fn with_provider(
+        model_name: impl Into<String>,
+        provider: ProviderConfig,
+    ) -> Self {
         let model_name = model_name.into();
 
         Self {
-            client,
+            http: http_client(DEFAULT_READ_TIMEOUT),
+            provider,
             model_name,
             temperature: 0.7, // Default temperature
+            max_tokens: None,
+            top_p: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            stop: Vec::new(),
+            seed: None,
+            require_fingerprint: false,
+            metadata: None,
+            cache: None,
+            stream: true,
+            mock: None,
         }
     }
 
@@ -334,12 +693,88 @@ impl LlmClient {
         self
     }
 
-    /// Creates a streaming chat completion request
-    pub fn get_chat_completion_streaming(
+    /// Sets the maximum number of tokens the model may generate per response
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Samples only from the tokens making up the top `top_p` of the
+    /// probability mass
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    /// Penalizes tokens by how often they already appear in the answer
+    pub fn with_frequency_penalty(mut self, penalty: f32) -> Self {
+        self.frequency_penalty = Some(penalty);
+        self
+    }
+
+    /// Penalizes tokens that already appear in the answer
+    pub fn with_presence_penalty(mut self, penalty: f32) -> Self {
+        self.presence_penalty = Some(penalty);
+        self
+    }
+
+    /// Stops generating at any of the sequences in `stop`
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Sends `seed` with every request, so that providers supporting it
+    /// sample the same answer to the same request
+    pub fn with_seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Fails requests whose reply carries no system fingerprint, without
+    /// which the provider promises nothing about reproducing it
+    #[must_use]
+    pub fn require_fingerprint(mut self) -> Self {
+        self.require_fingerprint = true;
+        self
+    }
+
+    /// Sends `metadata` as the `metadata` field of every request, which
+    /// gateways like `LiteLLM` use to attribute usage
+    pub fn with_metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Answers requests made before from `cache`, and stores the replies
+    /// to new ones in it
+    #[must_use]
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Streams replies if `stream`, or else requests each reply whole,
+    /// for servers that support neither SSE nor `stream_options`
+    #[must_use]
+    pub fn with_streaming(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    /// Gives up on a reply once the API sends nothing for `timeout`,
+    /// between chunks of a streamed reply or before it starts
+    #[must_use]
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.http = http_client(timeout);
+        self
+    }
+
+    /// Builds the body of the API request for `request`
+    fn build_request(
         &self,
         request: &LlmRequest,
-        mut on_chunk: impl FnMut(&str),
-    ) -> LlmResult<LlmResponse> {
+    ) -> LlmResult<CreateChatCompletionRequest> {
         let tools = request
             .tools
             .iter()
@@ -353,96 +788,554 @@ impl LlmClient {
             .map(std::convert::Into::into)
             .collect::<Vec<ChatCompletionRequestMessage>>();
 
-        let request = CreateChatCompletionRequestArgs::default()
+        let mut request_args = CreateChatCompletionRequestArgs::default();
+        request_args
             .model(&self.model_name)
             .temperature(self.temperature)
             .tools(tools)
-            .stream(true)
-            .stream_options(ChatCompletionStreamOptions {
-                include_usage: true,
-            })
-            .messages(messages)
-            .build()?;
+            .messages(messages);
+
+        if self.stream {
+            request_args.stream(true).stream_options(
+                ChatCompletionStreamOptions { include_usage: true },
+            );
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            request_args.max_completion_tokens(max_tokens);
+        }
+        if let Some(top_p) = self.top_p {
+            request_args.top_p(top_p);
+        }
+        if let Some(penalty) = self.frequency_penalty {
+            request_args.frequency_penalty(penalty);
+        }
+        if let Some(penalty) = self.presence_penalty {
+            request_args.presence_penalty(penalty);
+        }
+        if !self.stop.is_empty() {
+            request_args.stop(Stop::StringArray(self.stop.clone()));
+        }
+        if let Some(seed) = self.seed {
+            request_args.seed(seed);
+        }
+        if let Some(metadata) = &self.metadata {
+            request_args.metadata(metadata.clone());
+        }
+        if let Some(format) = &request.response_format {
+            request_args.response_format(format.clone());
+        }
+
+        Ok(request_args.build()?)
+    }
+
+    /// The JSON body that would be sent to the API for `request`, without
+    /// sending it
+    pub fn request_body(
+        &self,
+        request: &LlmRequest,
+    ) -> LlmResult<serde_json::Value> {
+        Ok(serde_json::to_value(self.build_request(request)?)?)
+    }
+
+    /// Creates a streaming chat completion request, passing each piece of
+    /// text to `on_chunk` as it arrives
+    pub async fn get_chat_completion_streaming(
+        &self,
+        request: &LlmRequest,
+        mut on_chunk: impl FnMut(&str),
+    ) -> LlmResult<LlmResponse> {
+        self.stream_chat_completion(request, |event| {
+            if let StreamEvent::Text(text) = event {
+                on_chunk(text);
+            }
+        })
+        .await
+    }
+
+    /// Creates a chat completion request, reporting the reply's progress
+    /// to `on_event` as it arrives, or all at once when it isn't streamed
+    pub async fn stream_chat_completion(
+        &self,
+        request: &LlmRequest,
+        mut on_event: impl FnMut(StreamEvent<'_>),
+    ) -> LlmResult<LlmResponse> {
+        if let Some(fixture) = &self.mock {
+            let reply =
+                mock::reply(fixture, &self.model_name, request.messages())?;
+            let (response, _) =
+                self.read_whole(reply, Instant::now(), &mut on_event)?;
+            return Ok(response);
+        }
+
+        let request = self.build_request(request)?;
+
+        let cache_key = match &self.cache {
+            Some(_) => Key::new(
+                &self.provider.url("/chat/completions"),
+                &serde_json::to_value(&request)?,
+            ),
+            None => None,
+        };
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key)
+            && let Some(response) = cache.get(key)
+        {
+            debug!("Answering from the response cache");
+            report_whole(&response, &mut on_event);
+            return Ok(response);
+        }
 
         if log::log_enabled!(log::Level::Debug) {
             let json = serde_json::to_string(&request)?;
             debug!("{json}");
         }
 
+        let (response, fingerprint) = if self.stream {
+            Box::pin(self.receive_streamed(&request, &mut on_event)).await?
+        } else {
+            self.receive_whole(&request, &mut on_event).await?
+        };
+
+        if self.require_fingerprint && fingerprint.is_none() {
+            return Err(LlmError::NotReproducible(format!(
+                "the reply from {} has no system fingerprint, so the \
+                 provider doesn't say which backend produced it",
+                self.model_name
+            )));
+        }
+
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key)
+            && let Err(e) = cache.put(key, &response)
+        {
+            warn!("Failed to cache the reply: {e}");
+        }
+
+        Ok(response)
+    }
+
+    /// Sends `request` and reads the reply as it streams in, returning it
+    /// with its system fingerprint
+    async fn receive_streamed(
+        &self,
+        request: &CreateChatCompletionRequest,
+        on_event: &mut impl FnMut(StreamEvent<'_>),
+    ) -> LlmResult<(LlmResponse, Option<String>)> {
         let mut usage = Usage::default();
-        let mut aggregated_response: Option<ChatChoiceStream> = None;
+        let mut choices = ChoiceAggregator::default();
 
-        TOKIO_RUNTIME.block_on(async {
-            let mut stream = self
-                .client
-                .chat()
-                .create_stream(request)
-                .await
-                .map_err(LlmError::from)?;
-
-            while let Some(event) = stream.next().await {
-                match event {
-                    Ok(chunk) => {
-                        trace!("Received chunk: {chunk:?}");
-
-                        let choice =
-                            chunk.choices.first().ok_or_else(|| {
-                                LlmError::InvalidResponse(
-                                    "No choices in response".to_string(),
-                                )
-                            })?;
-
-                        if let Some(existing_response) =
-                            &mut aggregated_response
-                        {
-                            merge_stream_chunks(existing_response, choice);
-                        } else {
-                            aggregated_response = Some(choice.clone());
-                        }
+        let sent = Instant::now();
+        let mut first_token = None;
+        let (headers, stream) = self.send_streaming(request).await?;
+        for notice in notices::from_headers(&self.model_name, &headers) {
+            on_event(StreamEvent::Notice(&notice));
+        }
+
+        let mut stream = std::pin::pin!(stream);
+        let mut started = false;
+        let mut fingerprint = None;
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok(chunk) => {
+                    trace!("Received chunk: {chunk}");
+                    on_event(StreamEvent::Chunk(&chunk));
+                    for notice in notices::from_chunk(&self.model_name, &chunk)
+                    {
+                        on_event(StreamEvent::Notice(&notice));
+                    }
+                    let reasoning = reasoning_deltas(&chunk);
+                    let chunk = serde_json::from_value::<
+                        CreateChatCompletionStreamResponse,
+                    >(chunk)?;
+                    if !started {
+                        started = true;
+                        on_event(StreamEvent::Started);
+                    }
+                    if first_token.is_none() && has_tokens(&chunk, &reasoning)
+                    {
+                        first_token = Some(sent.elapsed());
+                    }
 
-                        if let Some(content) = &choice.delta.content {
-                            on_chunk(content);
+                    // Keep-alive and usage-only chunks carry no choices
+                    for (index, text) in &reasoning {
+                        choices.merge_reasoning(*index, text);
+
+                        if ChoiceAggregator::is_primary(*index) {
+                            on_event(StreamEvent::Reasoning(text));
                         }
+                    }
+                    for choice in &chunk.choices {
+                        choices.merge(choice);
 
-                        debug!(
-                            "{}",
-                            serde_json::to_string(&aggregated_response)
-                                .unwrap_or_default()
-                        );
-
-                        if let Some(u) = chunk.usage {
-                            usage = Usage::new(
-                                u.prompt_tokens,
-                                u.completion_tokens,
-                                u.total_tokens,
-                            );
+                        if ChoiceAggregator::is_primary(choice.index) {
+                            report_delta(&choice.delta, on_event);
                         }
                     }
-                    Err(e) => {
-                        error!("Error in stream: {e}");
-                        return Err(LlmError::from(e));
+
+                    debug!(
+                        "{}",
+                        serde_json::to_string(&choices.primary())
+                            .unwrap_or_default()
+                    );
+
+                    if chunk.system_fingerprint.is_some() {
+                        fingerprint = chunk.system_fingerprint;
+                    }
+                    if let Some(u) = chunk.usage {
+                        usage = u.into();
                     }
                 }
+                Err(e) => {
+                    error!("Error in stream: {e}");
+                    return Err(LlmError::from(e));
+                }
             }
-            Ok(())
-        })?;
+        }
 
-        Ok(create_response_from_stream(
-            &aggregated_response.ok_or_else(|| {
+        let response = create_response_from_stream(
+            choices.primary().ok_or_else(|| {
                 LlmError::MissingData(
                     "No response received from stream".to_string(),
                 )
             })?,
+            choices.primary_reasoning(),
             usage,
-        ))
+        )
+        .with_timing(Timing { first_token, total: sent.elapsed() });
+
+        Ok((response, fingerprint))
+    }
+
+    /// Sends `request` without streaming and reads the whole reply,
+    /// returning it with its system fingerprint
+    async fn receive_whole(
+        &self,
+        request: &CreateChatCompletionRequest,
+        on_event: &mut impl FnMut(StreamEvent<'_>),
+    ) -> LlmResult<(LlmResponse, Option<String>)> {
+        let sent = Instant::now();
+        let reply = self
+            .http
+            .post(self.provider.url("/chat/completions"))
+            .headers(self.provider.headers())
+            .query(&self.provider.query())
+            .json(request)
+            .send()
+            .await
+            .map_err(OpenAIError::from)?;
+
+        let status = reply.status();
+        let headers = reply.headers().clone();
+        let body = reply.text().await.map_err(OpenAIError::from)?;
+        if !status.is_success() {
+            return Err(api_error(status, &body).into());
+        }
+        for notice in notices::from_headers(&self.model_name, &headers) {
+            on_event(StreamEvent::Notice(&notice));
+        }
+
+        trace!("Received reply: {body}");
+        self.read_whole(serde_json::from_str(&body)?, sent, on_event)
+    }
+
+    /// Reads `reply`, a whole reply to a request sent at `sent`, returning
+    /// it with its system fingerprint
+    fn read_whole(
+        &self,
+        reply: serde_json::Value,
+        sent: Instant,
+        on_event: &mut impl FnMut(StreamEvent<'_>),
+    ) -> LlmResult<(LlmResponse, Option<String>)> {
+        on_event(StreamEvent::Chunk(&reply));
+        for notice in notices::from_chunk(&self.model_name, &reply) {
+            on_event(StreamEvent::Notice(&notice));
+        }
+        let reasoning = message_reasoning(&reply);
+        let reply =
+            serde_json::from_value::<CreateChatCompletionResponse>(reply)?;
+
+        let response = create_response_from_completion(&reply, &reasoning)?
+            .with_timing(Timing { first_token: None, total: sent.elapsed() });
+        report_whole(&response, on_event);
+
+        Ok((response, reply.system_fingerprint))
     }
 
-    /// Creates a non-streaming chat completion request
-    pub fn get_chat_completion(
+    /// Sends `request`, returning the headers of the reply and the chunks
+    /// streamed in its body
+    ///
+    /// The request is made here rather than through `async_openai`, which
+    /// hides the headers, where providers put deprecation and rate limit
+    /// warnings. Chunks are read as plain JSON, since the typed chunks drop
+    /// the reasoning.
+    async fn send_streaming(
+        &self,
+        request: &CreateChatCompletionRequest,
+    ) -> LlmResult<(
+        HeaderMap,
+        impl Stream<Item = Result<serde_json::Value, OpenAIError>> + use<>,
+    )> {
+        let response = self
+            .http
+            .post(self.provider.url("/chat/completions"))
+            .headers(self.provider.headers())
+            .query(&self.provider.query())
+            .json(request)
+            .send()
+            .await
+            .map_err(OpenAIError::from)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.map_err(OpenAIError::from)?;
+            return Err(api_error(status, &body).into());
+        }
+
+        let headers = response.headers().clone();
+        let chunks = response
+            .bytes_stream()
+            .eventsource()
+            .take_while(|event| {
+                let done = matches!(event, Ok(e) if e.data == "[DONE]");
+                std::future::ready(!done)
+            })
+            .map(|event| {
+                let event = event
+                    .map_err(|e| OpenAIError::StreamError(e.to_string()))?;
+                serde_json::from_str(&event.data)
+                    .map_err(OpenAIError::JSONDeserialize)
+            });
+
+        Ok((headers, chunks))
+    }
+
+    /// The names of the models the endpoint serves, from its `/models`
+    /// API, sorted
+    pub async fn list_models(&self) -> LlmResult<Vec<String>> {
+        if self.mock.is_some() {
+            return Ok(vec![self.model_name.clone()]);
+        }
+
+        let response = self
+            .http
+            .get(self.provider.url("/models"))
+            .headers(self.provider.headers())
+            .query(&self.provider.query())
+            .send()
+            .await
+            .map_err(OpenAIError::from)?;
+
+        let status = response.status();
+        let body = response.text().await.map_err(OpenAIError::from)?;
+        if !status.is_success() {
+            return Err(api_error(status, &body).into());
+        }
+
+        model_names(&serde_json::from_str(&body)?)
+    }
+
+    /// Creates a chat completion request, without following the reply's
+    /// progress
+    pub async fn get_chat_completion(
         &self,
         request: &LlmRequest,
     ) -> LlmResult<LlmResponse> {
-        self.get_chat_completion_streaming(request, |_| {})
+        self.get_chat_completion_streaming(request, |_| {}).await
+    }
+}
+
+/// The model names in a `/models` response, which lists them as
+/// `{"data": [{"id": ...}]}`
+fn model_names(response: &serde_json::Value) -> LlmResult<Vec<String>> {
+    let models = response
+        .get("data")
+        .and_then(serde_json::Value::as_array)
+        .ok_or_else(|| {
+            LlmError::InvalidResponse(
+                "the model list has no `data` array".to_string(),
+            )
+        })?;
+
+    let mut names = models
+        .iter()
+        .filter_map(|model| model.get("id")?.as_str())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+
+    Ok(names)
+}
+
+/// The error described by the body of a failed request, which providers
+/// send as `{"error": {"message": ...}}`
+fn api_error(status: reqwest::StatusCode, body: &str) -> OpenAIError {
+    #[derive(Deserialize)]
+    struct ErrorResponse {
+        error: ApiErrorBody,
+    }
+
+    let error = serde_json::from_str::<ErrorResponse>(body).map_or_else(
+        |_| ApiErrorBody {
+            message: if body.trim().is_empty() {
+                status.to_string()
+            } else {
+                format!("{status}: {}", body.trim())
+            },
+            r#type: None,
+            param: None,
+            code: None,
+        },
+        |response| response.error,
+    );
+
+    OpenAIError::ApiError(error)
+}
+
+/// Reports the text and newly named tool calls in a streamed delta
+fn report_delta(
+    delta: &ChatCompletionStreamResponseDelta,
+    on_event: &mut impl FnMut(StreamEvent<'_>),
+) {
+    if let Some(content) = &delta.content {
+        on_event(StreamEvent::Text(content));
+    }
+
+    // A call's name arrives once, in the first delta of that call
+    let names = delta
+        .tool_calls
+        .iter()
+        .flatten()
+        .filter_map(|call| call.function.as_ref()?.name.as_deref());
+    for name in names.filter(|name| !name.is_empty()) {
+        on_event(StreamEvent::ToolCall(name));
+    }
+}
+
+/// Whether a streamed chunk carries any of the reply, as opposed to just
+/// the role, usage or a keep-alive
+fn has_tokens(
+    chunk: &CreateChatCompletionStreamResponse,
+    reasoning: &[(u32, String)],
+) -> bool {
+    reasoning.iter().any(|(_, text)| !text.is_empty())
+        || chunk.choices.iter().any(|choice| {
+            choice.delta.content.as_deref().is_some_and(|t| !t.is_empty())
+                || choice.delta.tool_calls.is_some()
+        })
+}
+
+/// The reasoning text in a streamed chunk, by choice index
+///
+/// Providers put it in a `reasoning_content` (`DeepSeek`, vLLM) or
+/// `reasoning` (`OpenRouter`, Ollama) field of the delta, neither of which
+/// is part of the standard chunk.
+fn reasoning_deltas(chunk: &serde_json::Value) -> Vec<(u32, String)> {
+    let choices = chunk.get("choices").and_then(serde_json::Value::as_array);
+
+    choices
+        .into_iter()
+        .flatten()
+        .filter_map(|choice| {
+            let index = choice.get("index")?.as_u64()?;
+            let delta = choice.get("delta")?;
+            let text = ["reasoning_content", "reasoning"]
+                .iter()
+                .find_map(|field| delta.get(field)?.as_str())
+                .filter(|text| !text.is_empty())?;
+
+            Some((u32::try_from(index).ok()?, text.to_owned()))
+        })
+        .collect()
+}
+
+/// The reasoning in the message of the primary choice of a reply that
+/// wasn't streamed, in the same fields as [`reasoning_deltas`] reads
+fn message_reasoning(reply: &serde_json::Value) -> String {
+    let choices = reply.get("choices").and_then(serde_json::Value::as_array);
+
+    choices
+        .into_iter()
+        .flatten()
+        .find(|choice| {
+            choice.get("index").and_then(serde_json::Value::as_u64)
+                == Some(u64::from(PRIMARY_CHOICE))
+        })
+        .and_then(|choice| {
+            let message = choice.get("message")?;
+            ["reasoning_content", "reasoning"]
+                .iter()
+                .find_map(|field| message.get(field)?.as_str())
+        })
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Reports a reply that arrived whole, from the cache or a request that
+/// isn't streamed, as though it had just been streamed
+fn report_whole(
+    response: &LlmResponse,
+    on_event: &mut impl FnMut(StreamEvent<'_>),
+) {
+    on_event(StreamEvent::Started);
+    if !response.reasoning().is_empty() {
+        on_event(StreamEvent::Reasoning(response.reasoning()));
+    }
+    if !response.text().is_empty() {
+        on_event(StreamEvent::Text(response.text()));
+    }
+    for call in response.tool_calls() {
+        on_event(StreamEvent::ToolCall(call.name()));
+    }
+}
+
+/// The index of the choice that becomes the response
+///
+/// Requests ask for a single choice, so a provider sending others anyway
+/// never changes which one is shown, whatever order they arrive in.
+const PRIMARY_CHOICE: u32 = 0;
+
+/// Accumulates streamed choices, keyed by their index
+///
+/// Only the [`PRIMARY_CHOICE`] becomes the response, but every index is
+/// aggregated separately so that deltas of different choices never get
+/// mixed together.
+#[derive(Debug, Default)]
+struct ChoiceAggregator {
+    choices: BTreeMap<u32, ChatChoiceStream>,
+    /// Reasoning of each choice, which the standard types have no room for
+    reasoning: BTreeMap<u32, String>,
+}
+
+impl ChoiceAggregator {
+    /// Merges a streamed choice into the aggregate for its index
+    fn merge(&mut self, choice: &ChatChoiceStream) {
+        match self.choices.entry(choice.index) {
+            Entry::Occupied(mut existing) => {
+                merge_stream_chunks(existing.get_mut(), choice);
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(choice.clone());
+            }
+        }
+    }
+
+    /// Appends reasoning text to the aggregate for its index
+    fn merge_reasoning(&mut self, index: u32, text: &str) {
+        self.reasoning.entry(index).or_default().push_str(text);
+    }
+
+    /// Whether the choice with the given index is the primary choice
+    const fn is_primary(index: u32) -> bool {
+        index == PRIMARY_CHOICE
+    }
+
+    /// The aggregated primary choice, if it has been received
+    fn primary(&self) -> Option<&ChatChoiceStream> {
+        self.choices.get(&PRIMARY_CHOICE)
+    }
+
+    /// The reasoning of the primary choice, if any
+    fn primary_reasoning(&self) -> &str {
+        self.reasoning.get(&PRIMARY_CHOICE).map_or("", String::as_str)
     }
 }
 
@@ -565,6 +1458,7 @@ mod tests {
         ChatCompletionStreamResponseDelta, FinishReason, FunctionCallStream,
     };
     use std::error::Error;
+    use tokio::io::AsyncWriteExt;
 
     #[test]
     fn test_llm_error_display() {
@@ -595,6 +1489,13 @@ mod tests {
             missing_data.to_string(),
             "Missing required data: missing field"
         );
+
+        let not_reproducible =
+            LlmError::NotReproducible("no fingerprint".to_string());
+        assert_eq!(
+            not_reproducible.to_string(),
+            "Answers are not reproducible: no fingerprint"
+        );
     }
 
     #[test]
@@ -645,6 +1546,47 @@ mod tests {
         assert_eq!(usage.total_tokens(), 150);
     }
 
+    #[test]
+    fn test_usage_add_assign_and_display() {
+        let mut usage = Usage::new(100, 50, 150);
+        usage += &Usage::new(10, 5, 15);
+
+        assert_eq!(usage.prompt_tokens(), 110);
+        assert_eq!(usage.completion_tokens(), 55);
+        assert_eq!(usage.total_tokens(), 165);
+        assert_eq!(
+            usage.to_string(),
+            "110 prompt + 55 completion = 165 tokens"
+        );
+    }
+
+    #[test]
+    fn test_usage_reasoning_tokens() {
+        let mut usage = Usage::new(100, 50, 150).with_reasoning_tokens(30);
+        usage += &Usage::new(10, 5, 15).with_reasoning_tokens(2);
+
+        assert_eq!(usage.reasoning_tokens(), 32);
+        assert_eq!(
+            usage.to_string(),
+            "110 prompt + 55 completion (32 reasoning) = 165 tokens"
+        );
+    }
+
+    #[test]
+    fn test_timing_tokens_per_second() {
+        let streamed = Timing {
+            first_token: Some(Duration::from_millis(500)),
+            total: Duration::from_millis(2500),
+        };
+        assert_eq!(streamed.tokens_per_second(40), Some(20.0));
+        assert_eq!(streamed.tokens_per_second(0), None);
+
+        let whole =
+            Timing { first_token: None, total: Duration::from_secs(4) };
+        assert_eq!(whole.tokens_per_second(40), Some(10.0));
+        assert_eq!(Timing::default().tokens_per_second(40), None);
+    }
+
     #[test]
     fn test_usage_default() {
         let usage = Usage::default();
@@ -707,7 +1649,9 @@ mod tests {
                     ChatCompletionRequestUserMessageContent::Text(text) => {
                         assert_eq!(text, "Hello, world!");
                     }
-                    _ => panic!("Expected text content"),
+                    ChatCompletionRequestUserMessageContent::Array(_) => {
+                        panic!("Expected text content")
+                    }
                 }
             }
             _ => panic!("Expected user message"),
@@ -775,7 +1719,9 @@ mod tests {
                     ChatCompletionRequestSystemMessageContent::Text(text) => {
                         assert_eq!(text, "You are a helpful assistant");
                     }
-                    _ => panic!("Expected text content"),
+                    ChatCompletionRequestSystemMessageContent::Array(_) => {
+                        panic!("Expected text content")
+                    }
                 }
             }
             _ => panic!("Expected system message"),
@@ -796,7 +1742,9 @@ mod tests {
                     ChatCompletionRequestToolMessageContent::Text(text) => {
                         assert_eq!(text, "Tool result");
                     }
-                    _ => panic!("Expected text content"),
+                    ChatCompletionRequestToolMessageContent::Array(_) => {
+                        panic!("Expected text content")
+                    }
                 }
                 assert_eq!(tool_msg.tool_call_id, "call_123");
             }
@@ -829,6 +1777,8 @@ mod tests {
             text: "Hello, world!".to_string(),
             usage,
             tool_calls,
+            reasoning: String::new(),
+            timing: None,
         };
 
         assert_eq!(response.text(), "Hello, world!");
@@ -853,7 +1803,40 @@ mod tests {
             "https://api.openai.com/v1",
         );
         assert_eq!(client.model_name, "gpt-4");
-        assert_eq!(client.temperature, 0.7);
+        assert!((client.temperature - 0.7).abs() < f32::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_stream_times_out() {
+        // A server that starts a reply and never sends a chunk
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      content-type: text/event-stream\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            std::future::pending::<()>().await;
+        });
+        let client = LlmClient::new("gpt-4", "key", url)
+            .with_read_timeout(Duration::from_millis(100));
+        let request =
+            LlmRequest::new(vec![Message::User("Hi".to_string())], vec![]);
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            client.get_chat_completion_streaming(&request, |_| {}),
+        )
+        .await
+        .expect("the read timeout should end the request");
+
+        assert!(result.is_err());
+        server.abort();
     }
 
     #[test]
@@ -865,7 +1848,128 @@ mod tests {
         )
         .with_temperature(0.3);
 
-        assert_eq!(client.temperature, 0.3);
+        assert!((client.temperature - 0.3).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_llm_client_with_max_tokens() {
+        let client = LlmClient::new(
+            "gpt-4",
+            "test-api-key",
+            "https://api.openai.com/v1",
+        );
+        assert_eq!(client.max_tokens, None);
+
+        let client = client.with_max_tokens(256);
+        assert_eq!(client.max_tokens, Some(256));
+    }
+
+    #[test]
+    fn test_model_names() {
+        let response = serde_json::json!({
+            "object": "list",
+            "data": [
+                { "id": "qwen3:8b", "object": "model" },
+                { "id": "llama3.2", "object": "model" },
+                { "object": "model" },
+            ],
+        });
+
+        assert_eq!(model_names(&response).unwrap(), ["llama3.2", "qwen3:8b"]);
+        assert!(matches!(
+            model_names(&serde_json::json!({ "models": [] })),
+            Err(LlmError::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_request_body_metadata() {
+        let client = LlmClient::new(
+            "gpt-4",
+            "test-api-key",
+            "https://api.openai.com/v1",
+        );
+        let request =
+            LlmRequest::new(vec![Message::User("Hi".to_string())], vec![]);
+
+        let body = client.request_body(&request).unwrap();
+        assert!(body.get("metadata").is_none());
+
+        let client =
+            client.with_metadata(serde_json::json!({ "team": "search" }));
+        let body = client.request_body(&request).unwrap();
+        assert_eq!(body["metadata"], serde_json::json!({ "team": "search" }));
+    }
+
+    #[test]
+    fn test_request_body_streaming() {
+        let request =
+            LlmRequest::new(vec![Message::User("Hi".to_string())], vec![]);
+        let config = Config { stream: Some(false), ..Config::default() };
+
+        let body = LlmClient::new("gpt-4", "key", "http://localhost/v1")
+            .request_body(&request)
+            .unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+
+        let body =
+            LlmClient::from_config(&config).request_body(&request).unwrap();
+        assert!(body.get("stream").is_none());
+        assert!(body.get("stream_options").is_none());
+    }
+
+    #[test]
+    fn test_request_body_sampling_params() {
+        let config = Config {
+            model_name: "gpt-4o".to_owned(),
+            top_p: Some(0.5),
+            frequency_penalty: Some(-1.0),
+            presence_penalty: Some(0.25),
+            stop: vec!["\n\n".to_owned()],
+            seed: Some(7),
+            ..Config::default()
+        };
+        let request =
+            LlmRequest::new(vec![Message::User("Hi".to_string())], vec![]);
+
+        let body = LlmClient::from_config(&config).request_body(&request);
+        let body = body.unwrap();
+
+        assert_eq!(body["top_p"], 0.5);
+        assert_eq!(body["frequency_penalty"], -1.0);
+        assert_eq!(body["presence_penalty"], 0.25);
+        assert_eq!(body["stop"], serde_json::json!(["\n\n"]));
+        assert_eq!(body["seed"], 7);
+
+        let body = LlmClient::new("gpt-4o", "key", "http://localhost")
+            .request_body(&request)
+            .unwrap();
+        assert!(body.get("top_p").is_none());
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn test_request_body_response_format() {
+        let llm = LlmClient::new("gpt-4o", "key", "http://localhost");
+        let schema = serde_json::json!({ "type": "object" });
+        let request =
+            LlmRequest::new(vec![Message::User("Hi".to_string())], vec![])
+                .with_response_format(ResponseFormat::JsonSchema {
+                    name: "answer".to_string(),
+                    schema: schema.clone(),
+                });
+
+        let body = llm.request_body(&request).unwrap();
+
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(body["response_format"]["json_schema"]["name"], "answer");
+        assert_eq!(body["response_format"]["json_schema"]["schema"], schema);
+
+        let body = llm
+            .request_body(&LlmRequest::new(Vec::new(), Vec::new()))
+            .unwrap();
+        assert!(body.get("response_format").is_none());
     }
 
     #[test]
@@ -948,6 +2052,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_merge_stream_content_with_new_content() {
         let mut target = ChatChoiceStream {
             index: 0,
@@ -981,6 +2086,32 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
+    fn test_report_delta() {
+        let delta = ChatCompletionStreamResponseDelta {
+            content: Some("Let me look.".to_string()),
+            tool_calls: Some(vec![ChatCompletionMessageToolCallChunk {
+                index: 0,
+                id: Some("call_1".to_string()),
+                r#type: Some(ChatCompletionToolType::Function),
+                function: Some(FunctionCallStream {
+                    name: Some("ls".to_string()),
+                    arguments: None,
+                }),
+            }]),
+            role: None,
+            function_call: None,
+            refusal: None,
+        };
+        let mut events = Vec::new();
+
+        report_delta(&delta, &mut |event| events.push(format!("{event:?}")));
+
+        assert_eq!(events, ["Text(\"Let me look.\")", "ToolCall(\"ls\")"]);
+    }
+
+    #[test]
+    #[allow(deprecated)]
     fn test_merge_stream_content_with_existing_content() {
         let mut target = ChatChoiceStream {
             index: 0,
@@ -1014,6 +2145,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_merge_stream_chunks_with_finish_reason() {
         let mut target = ChatChoiceStream {
             index: 0,
@@ -1057,7 +2189,7 @@ mod tests {
         );
 
         let usage = Usage::new(100, 50, 150);
-        let response = create_response_from_stream(&stream, usage);
+        let response = create_response_from_stream(&stream, "", usage);
 
         assert_eq!(response.text(), "Hello, world!");
         assert_eq!(response.usage().total_tokens(), 150);
@@ -1084,7 +2216,7 @@ mod tests {
         );
 
         let usage = Usage::new(100, 50, 150);
-        let response = create_response_from_stream(&stream, usage);
+        let response = create_response_from_stream(&stream, "", usage);
 
         assert_eq!(response.text(), "");
         assert_eq!(response.tool_calls().len(), 1);
@@ -1249,6 +2381,179 @@ mod tests {
         assert_eq!(tool_calls[2].id, Some("call_456".to_string()));
     }
 
+    #[test]
+    fn test_choice_aggregator_separates_indices() {
+        let mut aggregator = ChoiceAggregator::default();
+
+        // Another choice arriving first doesn't take the place of the first
+        aggregator.merge(&create_test_chat_choice_stream(
+            1,
+            Some("Bonjour".to_string()),
+            None,
+            None,
+        ));
+        assert!(aggregator.primary().is_none());
+        aggregator.merge(&create_test_chat_choice_stream(
+            0,
+            Some("Hello".to_string()),
+            None,
+            None,
+        ));
+        aggregator.merge(&create_test_chat_choice_stream(
+            0,
+            Some(" world".to_string()),
+            None,
+            Some(FinishReason::Stop),
+        ));
+
+        assert!(ChoiceAggregator::is_primary(0));
+        assert!(!ChoiceAggregator::is_primary(1));
+
+        let primary = aggregator.primary().unwrap();
+        assert_eq!(primary.delta.content, Some("Hello world".to_string()));
+        assert_eq!(primary.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(
+            aggregator.choices[&1].delta.content,
+            Some("Bonjour".to_string())
+        );
+    }
+
+    #[test]
+    fn test_reasoning_deltas() {
+        let chunk = serde_json::json!({
+            "choices": [
+                { "index": 0, "delta": { "reasoning_content": "Hmm, " } },
+                { "index": 1, "delta": { "reasoning": "Well" } },
+                { "index": 2, "delta": { "content": "Hi" } },
+                { "index": 3, "delta": { "reasoning_content": "" } },
+            ]
+        });
+
+        assert_eq!(
+            reasoning_deltas(&chunk),
+            [(0, "Hmm, ".to_string()), (1, "Well".to_string())]
+        );
+        assert!(reasoning_deltas(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_has_tokens() {
+        let chunk = |delta: serde_json::Value| {
+            serde_json::from_value::<CreateChatCompletionStreamResponse>(
+                serde_json::json!({
+                    "id": "1",
+                    "object": "chat.completion.chunk",
+                    "created": 0,
+                    "model": "gpt-4",
+                    "choices": [{ "index": 0, "delta": delta }],
+                }),
+            )
+            .unwrap()
+        };
+
+        let role = chunk(serde_json::json!({ "role": "assistant" }));
+        assert!(!has_tokens(&role, &[]));
+        assert!(!has_tokens(
+            &chunk(serde_json::json!({ "content": "" })),
+            &[]
+        ));
+        assert!(has_tokens(&role, &[(0, "Hmm".to_string())]));
+        assert!(has_tokens(
+            &chunk(serde_json::json!({ "content": "Hi" })),
+            &[]
+        ));
+    }
+
+    #[test]
+    fn test_create_response_from_completion() {
+        let reply = serde_json::json!({
+            "id": "1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4",
+            "choices": [
+                {
+                    "index": 1,
+                    "message": { "role": "assistant", "content": "No" },
+                    "finish_reason": "stop"
+                },
+                {
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "reasoning_content": "The user wants a file",
+                        "tool_calls": [{
+                            "id": "call_1",
+                            "type": "function",
+                            "function": {
+                                "name": "ls",
+                                "arguments": "{\"args\":\"-a\"}"
+                            }
+                        }]
+                    },
+                    "finish_reason": "tool_calls"
+                }
+            ],
+            "usage": {
+                "prompt_tokens": 10,
+                "completion_tokens": 5,
+                "total_tokens": 15
+            }
+        });
+
+        let reasoning = message_reasoning(&reply);
+        let reply =
+            serde_json::from_value::<CreateChatCompletionResponse>(reply)
+                .unwrap();
+        let response =
+            create_response_from_completion(&reply, &reasoning).unwrap();
+
+        assert_eq!(response.text(), "");
+        assert_eq!(response.reasoning(), "The user wants a file");
+        assert_eq!(
+            response.tool_calls(),
+            [ToolCall::new("call_1", "ls", "{\"args\":\"-a\"}")]
+        );
+        assert_eq!(response.usage().total_tokens(), 15);
+    }
+
+    #[test]
+    fn test_choice_aggregator_reasoning() {
+        let mut aggregator = ChoiceAggregator::default();
+
+        // Reasoning arrives before any content of the choice
+        aggregator.merge_reasoning(0, "Let me ");
+        assert!(ChoiceAggregator::is_primary(0));
+        aggregator.merge_reasoning(0, "think.");
+        aggregator.merge_reasoning(1, "Other");
+        aggregator.merge(&create_test_chat_choice_stream(
+            0,
+            Some("Answer".to_string()),
+            None,
+            Some(FinishReason::Stop),
+        ));
+
+        assert!(!ChoiceAggregator::is_primary(1));
+        assert_eq!(aggregator.primary_reasoning(), "Let me think.");
+
+        let response = create_response_from_stream(
+            aggregator.primary().unwrap(),
+            aggregator.primary_reasoning(),
+            Usage::default(),
+        );
+        assert_eq!(response.text(), "Answer");
+        assert_eq!(response.reasoning(), "Let me think.");
+    }
+
+    #[test]
+    fn test_choice_aggregator_empty() {
+        let aggregator = ChoiceAggregator::default();
+
+        assert!(aggregator.primary().is_none());
+        assert_eq!(aggregator.primary_reasoning(), "");
+    }
+
     // Helper function to create a test ChatChoiceStream
     #[allow(deprecated)]
     fn create_test_chat_choice_stream(
@@ -0,0 +1,188 @@
+//! Embeddings from the OpenAI-compatible `/embeddings` API
+//!
+//! An embedding is a vector of numbers standing for the meaning of a text,
+//! so that texts of similar meaning have vectors close to each other. The
+//! endpoint and key are those of the chat completions; the model is the
+//! config's `embedding_model`, since chat models don't embed.
+
+use async_openai::config::Config as ApiConfig;
+use async_openai::error::OpenAIError;
+use async_openai::types::{CreateEmbeddingRequest, EmbeddingInput};
+use serde::{Deserialize, Serialize};
+
+use super::{LlmClient, LlmError, LlmResult, Usage, api_error};
+
+/// The embedding model used when the config doesn't name one
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// The embeddings of a list of texts
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    /// The model that made the embeddings, as the endpoint names it
+    pub model: String,
+    /// One vector per text, in the order the texts were given
+    pub vectors: Vec<Vec<f32>>,
+    pub usage: Usage,
+}
+
+impl Embeddings {
+    /// The embeddings as one line of JSON, each vector along with the name
+    /// of the input it is of, from `inputs`
+    pub fn to_json(&self, inputs: &[String]) -> serde_json::Result<String> {
+        serde_json::to_string(&EmbeddingReport {
+            model: &self.model,
+            data: inputs
+                .iter()
+                .zip(&self.vectors)
+                .map(|(input, embedding)| EmbeddedText { input, embedding })
+                .collect(),
+            usage: EmbeddingUsage {
+                prompt_tokens: self.usage.prompt_tokens(),
+                total_tokens: self.usage.total_tokens(),
+            },
+        })
+    }
+}
+
+/// Embeddings as `aido embed` prints them
+#[derive(Serialize)]
+struct EmbeddingReport<'a> {
+    model: &'a str,
+    data: Vec<EmbeddedText<'a>>,
+    usage: EmbeddingUsage,
+}
+
+#[derive(Serialize)]
+struct EmbeddedText<'a> {
+    input: &'a str,
+    embedding: &'a [f32],
+}
+
+/// An `/embeddings` response, leaving out what some local servers don't
+/// send
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    #[serde(default)]
+    model: String,
+    data: Vec<EmbeddingData>,
+    #[serde(default)]
+    usage: Option<EmbeddingUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EmbeddingUsage {
+    prompt_tokens: u32,
+    total_tokens: u32,
+}
+
+impl LlmClient {
+    /// The embeddings of `texts` by `model`, in `dimensions` dimensions if
+    /// given and the model can shorten its vectors
+    pub async fn embed(
+        &self,
+        model: &str,
+        texts: Vec<String>,
+        dimensions: Option<u32>,
+    ) -> LlmResult<Embeddings> {
+        if self.mock.is_some() {
+            return Err(LlmError::MissingData(
+                "The mock provider scripts no embeddings".to_owned(),
+            ));
+        }
+
+        let request = CreateEmbeddingRequest {
+            model: model.to_owned(),
+            input: EmbeddingInput::StringArray(texts),
+            dimensions,
+            ..CreateEmbeddingRequest::default()
+        };
+
+        let response = self
+            .http
+            .post(self.provider.url("/embeddings"))
+            .headers(self.provider.headers())
+            .query(&self.provider.query())
+            .json(&request)
+            .send()
+            .await
+            .map_err(OpenAIError::from)?;
+
+        let status = response.status();
+        let body = response.text().await.map_err(OpenAIError::from)?;
+        if !status.is_success() {
+            return Err(api_error(status, &body).into());
+        }
+
+        let response = serde_json::from_str::<EmbeddingResponse>(&body)?;
+        Ok(embeddings(response, model))
+    }
+}
+
+/// The embeddings in `response` to a request for `model`, in the order of
+/// the texts
+fn embeddings(mut response: EmbeddingResponse, model: &str) -> Embeddings {
+    response.data.sort_by_key(|data| data.index);
+
+    let usage = response.usage.map_or_else(Usage::default, |usage| {
+        Usage::new(usage.prompt_tokens, 0, usage.total_tokens)
+    });
+    let model = if response.model.is_empty() {
+        model.to_owned()
+    } else {
+        response.model
+    };
+
+    Embeddings {
+        model,
+        vectors: response.data.into_iter().map(|d| d.embedding).collect(),
+        usage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embeddings_from_response() {
+        let response = serde_json::from_str::<EmbeddingResponse>(
+            r#"{
+                "object": "list",
+                "model": "text-embedding-3-small",
+                "data": [
+                    {"object": "embedding", "index": 1, "embedding": [0.5]},
+                    {"object": "embedding", "index": 0, "embedding": [0.25]}
+                ],
+                "usage": {"prompt_tokens": 4, "total_tokens": 4}
+            }"#,
+        )
+        .unwrap();
+
+        let hosted = embeddings(response, "requested");
+        assert_eq!(hosted.model, "text-embedding-3-small");
+        assert_eq!(hosted.vectors, [[0.25], [0.5]]);
+        assert_eq!(hosted.usage.total_tokens(), 4);
+
+        // As some local servers answer
+        let bare = serde_json::from_str::<EmbeddingResponse>(
+            r#"{"data": [{"embedding": [1.0, 0.0]}]}"#,
+        )
+        .unwrap();
+        let local = embeddings(bare, "nomic-embed-text");
+        assert_eq!(local.model, "nomic-embed-text");
+        assert_eq!(local.usage.total_tokens(), 0);
+
+        let json =
+            hosted.to_json(&["a.txt".to_owned(), "b.txt".to_owned()]).unwrap();
+        assert!(json.starts_with(
+            r#"{"model":"text-embedding-3-small","data":[{"input":"a.txt","embedding":[0.25]}"#
+        ));
+    }
+}
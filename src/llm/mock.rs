@@ -0,0 +1,271 @@
+//! Replies scripted in a fixture file, for `provider = "mock"`
+//!
+//! With the mock provider, `fixture` is the path of a YAML (or JSON) file
+//! of replies, served without any network, to try recipes offline or to
+//! test them end to end:
+//!
+//! ```yaml
+//! replies:
+//!   - tool_calls:
+//!       - name: search
+//!         arguments: { pattern: "cargo install" }
+//!   - text: The README explains how to install aido.
+//!   - when: weather
+//!     text: It's sunny.
+//! ```
+//!
+//! Each user message starts a turn, in which the model is asked again
+//! after every round of tool calls. The first reply of the fixture answers
+//! the first request of a turn, the second reply the request after the
+//! first tool calls, and so on. A reply with `when` is only served in
+//! turns whose user message contains it, and takes precedence over the
+//! replies without. Usage left out is estimated from the text.
+
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{LlmError, LlmResult, Message};
+use crate::context::estimate_tokens;
+
+/// A fixture file
+#[derive(Debug, Deserialize)]
+struct Fixture {
+    replies: Vec<Reply>,
+}
+
+/// A scripted reply
+#[derive(Debug, Deserialize)]
+struct Reply {
+    /// Text the user message of the turn must contain
+    #[serde(default)]
+    when: Option<String>,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    reasoning: String,
+    #[serde(default)]
+    tool_calls: Vec<ScriptedCall>,
+    #[serde(default)]
+    usage: Option<ScriptedUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScriptedCall {
+    name: String,
+    /// The arguments, as a map or as a JSON string
+    #[serde(default)]
+    arguments: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScriptedUsage {
+    #[serde(default)]
+    prompt_tokens: u32,
+    #[serde(default)]
+    completion_tokens: u32,
+}
+
+/// The reply the fixture at `path` scripts for a request of `messages`,
+/// as the API would send it whole
+pub fn reply(
+    path: &Path,
+    model_name: &str,
+    messages: &[Message],
+) -> LlmResult<serde_json::Value> {
+    if path.as_os_str().is_empty() {
+        return Err(LlmError::MissingData(
+            "The mock provider needs a `fixture` file of replies".to_owned(),
+        ));
+    }
+    let fixture = std::fs::read_to_string(path).map_err(|e| {
+        LlmError::MissingData(format!(
+            "Could not read the mock fixture {}: {e}",
+            path.display()
+        ))
+    })?;
+    let fixture: Fixture = serde_yaml::from_str(&fixture).map_err(|e| {
+        LlmError::InvalidResponse(format!(
+            "The mock fixture {} is invalid: {e}",
+            path.display()
+        ))
+    })?;
+
+    let (user_message, step) = turn(messages);
+    let scripted = fixture
+        .replies
+        .iter()
+        .filter(|reply| {
+            reply.when.as_ref().is_some_and(|when| user_message.contains(when))
+        })
+        .collect::<Vec<_>>();
+    let replies = if scripted.is_empty() {
+        fixture.replies.iter().filter(|reply| reply.when.is_none()).collect()
+    } else {
+        scripted
+    };
+    let reply = replies.get(step).ok_or_else(|| {
+        LlmError::MissingData(format!(
+            "The mock fixture {} scripts {} replies for this turn, and the \
+             model was asked for reply {}",
+            path.display(),
+            replies.len(),
+            step + 1
+        ))
+    })?;
+
+    Ok(completion(reply, model_name, messages, step))
+}
+
+/// The user message of the current turn, and how many replies were given
+/// in the turn since
+fn turn(messages: &[Message]) -> (&str, usize) {
+    let mut step = 0;
+    for message in messages.iter().rev() {
+        match message {
+            Message::User(text) => return (text, step),
+            Message::Assistant(..) => step += 1,
+            Message::System(_) | Message::Tool { .. } => {}
+        }
+    }
+    ("", step)
+}
+
+/// `reply` as a chat completion
+fn completion(
+    reply: &Reply,
+    model_name: &str,
+    messages: &[Message],
+    step: usize,
+) -> serde_json::Value {
+    let tool_calls = reply
+        .tool_calls
+        .iter()
+        .enumerate()
+        .map(|(i, call)| {
+            let arguments = match &call.arguments {
+                Some(serde_json::Value::String(arguments)) => {
+                    arguments.clone()
+                }
+                Some(arguments) => arguments.to_string(),
+                None => "{}".to_owned(),
+            };
+            json!({
+                "id": format!("call_{step}_{i}"),
+                "type": "function",
+                "function": { "name": call.name, "arguments": arguments },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let (prompt_tokens, completion_tokens) = reply.usage.as_ref().map_or_else(
+        || {
+            let prompt = serde_json::to_string(messages).unwrap_or_default();
+            let completion = format!("{}{tool_calls:?}", reply.text);
+            (estimate(&prompt), estimate(&completion))
+        },
+        |usage| (usage.prompt_tokens, usage.completion_tokens),
+    );
+
+    let mut message = json!({ "role": "assistant", "content": reply.text });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = json!(tool_calls);
+    }
+    if !reply.reasoning.is_empty() {
+        message["reasoning_content"] = json!(reply.reasoning);
+    }
+    json!({
+        "id": "mock",
+        "object": "chat.completion",
+        "created": 0,
+        "model": model_name,
+        "system_fingerprint": "mock",
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason":
+                if tool_calls.is_empty() { "stop" } else { "tool_calls" },
+        }],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        },
+    })
+}
+
+fn estimate(text: &str) -> u32 {
+    u32::try_from(estimate_tokens(text)).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ToolCall;
+
+    const FIXTURE: &str = "replies:
+  - tool_calls:
+      - name: search
+        arguments: { query: rust }
+  - text: Found it.
+    usage: { prompt_tokens: 10, completion_tokens: 2 }
+  - when: weather
+    text: It's sunny.
+";
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("aido-mock-{name}-{}.yaml", std::process::id()));
+        std::fs::write(&path, FIXTURE).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_reply_follows_the_turn() {
+        let path = fixture("turn");
+        let mut messages = vec![
+            Message::System("Be brief.".to_owned()),
+            Message::User("find rust".to_owned()),
+        ];
+
+        let first = reply(&path, "m", &messages).unwrap();
+        let call = &first["choices"][0]["message"]["tool_calls"][0];
+        assert_eq!(call["function"]["name"], "search");
+        assert_eq!(call["function"]["arguments"], r#"{"query":"rust"}"#);
+        assert_eq!(first["choices"][0]["finish_reason"], "tool_calls");
+
+        messages.push(Message::Assistant(
+            String::new(),
+            Some(vec![ToolCall::new("call_0_0", "search", "{}")]),
+        ));
+        messages.push(Message::Tool {
+            content: "a result".to_owned(),
+            id: "call_0_0".to_owned(),
+        });
+        let second = reply(&path, "m", &messages).unwrap();
+        assert_eq!(second["choices"][0]["message"]["content"], "Found it.");
+        assert_eq!(second["usage"]["total_tokens"], 12);
+
+        messages.push(Message::Assistant("Found it.".to_owned(), None));
+        assert!(reply(&path, "m", &messages).is_err());
+
+        // A new turn starts over
+        messages.push(Message::User("and now?".to_owned()));
+        assert!(reply(&path, "m", &messages).is_ok());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reply_when() {
+        let path = fixture("when");
+        let messages = vec![Message::User("what's the weather?".to_owned())];
+
+        let reply = reply(&path, "m", &messages).unwrap();
+        assert_eq!(reply["choices"][0]["message"]["content"], "It's sunny.");
+        assert!(reply["usage"]["prompt_tokens"].as_u64().unwrap() > 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
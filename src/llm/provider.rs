@@ -0,0 +1,262 @@
+//! API providers the LLM client can talk to
+//!
+//! Plain OpenAI-compatible endpoints authenticate with a bearer token and
+//! address models by name. Azure instead routes requests to a named
+//! deployment, requires an `api-version` query parameter, and authenticates
+//! with an `api-key` header. Either way, the `headers` from the config are
+//! sent along too, e.g. for a gateway to attribute usage.
+
+use async_openai::config::{AzureConfig, Config as ApiConfig, OpenAIConfig};
+use log::warn;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Azure API version used when the config doesn't specify one
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
+
+/// The flavor of API that `api_url` points at
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// The official API or any OpenAI-compatible server
+    #[default]
+    OpenAi,
+    /// Microsoft Azure's hosted models
+    Azure,
+    /// Replies scripted in the file `fixture` names, served
+    /// without any network, for tests and offline demos
+    Mock,
+}
+
+/// Settings only used when the provider is [`Provider::Azure`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AzureSettings {
+    /// Name of the deployment to send requests to; defaults to the model
+    /// name
+    #[serde(default)]
+    pub deployment: Option<String>,
+    /// Value of the `api-version` query parameter
+    #[serde(default = "default_api_version")]
+    pub api_version: String,
+}
+
+impl Default for AzureSettings {
+    fn default() -> Self {
+        Self { deployment: None, api_version: default_api_version() }
+    }
+}
+
+fn default_api_version() -> String {
+    DEFAULT_AZURE_API_VERSION.to_string()
+}
+
+/// Client configuration for whichever provider is in use
+#[derive(Debug, Clone)]
+pub struct ProviderConfig {
+    api: Api,
+    /// Sent with every request on top of the provider's own headers
+    extra_headers: HeaderMap,
+}
+
+#[derive(Debug, Clone)]
+enum Api {
+    OpenAi(OpenAIConfig),
+    Azure(AzureConfig),
+}
+
+impl ProviderConfig {
+    /// Client configuration for an OpenAI-compatible endpoint
+    pub fn openai(config: OpenAIConfig) -> Self {
+        Self { api: Api::OpenAi(config), extra_headers: HeaderMap::new() }
+    }
+
+    /// Builds the client configuration described by the user's config
+    ///
+    /// Headers that aren't valid HTTP are left out with a warning.
+    pub fn from_config(config: &Config) -> Self {
+        let api = match config.provider {
+            // The mock provider never connects
+            Provider::OpenAi | Provider::Mock => Api::OpenAi(
+                OpenAIConfig::new()
+                    .with_api_key(&config.api_key)
+                    .with_api_base(&config.api_url),
+            ),
+            Provider::Azure => {
+                let deployment = config
+                    .azure
+                    .deployment
+                    .as_deref()
+                    .unwrap_or(&config.model_name);
+
+                Api::Azure(
+                    AzureConfig::new()
+                        .with_api_key(&config.api_key)
+                        .with_api_base(config.api_url.trim_end_matches('/'))
+                        .with_deployment_id(deployment)
+                        .with_api_version(&config.azure.api_version),
+                )
+            }
+        };
+
+        let mut extra_headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            match (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    extra_headers.insert(name, value);
+                }
+                _ => warn!("Ignoring invalid header '{name}' in the config"),
+            }
+        }
+
+        Self { api, extra_headers }
+    }
+}
+
+impl ApiConfig for ProviderConfig {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = match &self.api {
+            Api::OpenAi(config) => config.headers(),
+            Api::Azure(config) => config.headers(),
+        };
+        headers.extend(self.extra_headers.clone());
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        match &self.api {
+            Api::OpenAi(config) => config.url(path),
+            Api::Azure(config) => config.url(path),
+        }
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        match &self.api {
+            Api::OpenAi(config) => config.query(),
+            Api::Azure(config) => config.query(),
+        }
+    }
+
+    fn api_base(&self) -> &str {
+        match &self.api {
+            Api::OpenAi(config) => config.api_base(),
+            Api::Azure(config) => config.api_base(),
+        }
+    }
+
+    fn api_key(&self) -> &SecretString {
+        match &self.api {
+            Api::OpenAi(config) => config.api_key(),
+            Api::Azure(config) => config.api_key(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn azure_config(deployment: Option<&str>) -> Config {
+        Config {
+            api_key: "secret".to_string(),
+            api_url: "https://example.openai.azure.com/".to_string(),
+            model_name: "gpt-4o".to_string(),
+            provider: Provider::Azure,
+            azure: AzureSettings {
+                deployment: deployment.map(str::to_string),
+                ..AzureSettings::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_openai_provider() {
+        let config = Config {
+            api_key: "secret".to_string(),
+            api_url: "https://api.openai.com/v1".to_string(),
+            ..Config::default()
+        };
+
+        let provider = ProviderConfig::from_config(&config);
+
+        assert_eq!(
+            provider.url("/chat/completions"),
+            "https://api.openai.com/v1/chat/completions"
+        );
+        assert!(provider.query().is_empty());
+        assert!(provider.headers().contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_azure_provider_uses_deployment_url() {
+        let provider =
+            ProviderConfig::from_config(&azure_config(Some("my-deployment")));
+
+        assert_eq!(
+            provider.url("/chat/completions"),
+            "https://example.openai.azure.com/openai/deployments/\
+             my-deployment/chat/completions"
+        );
+        assert_eq!(
+            provider.query(),
+            [("api-version", DEFAULT_AZURE_API_VERSION)]
+        );
+        assert_eq!(provider.headers()["api-key"], "secret");
+        assert!(!provider.headers().contains_key("authorization"));
+    }
+
+    #[test]
+    fn test_azure_deployment_defaults_to_model_name() {
+        let provider = ProviderConfig::from_config(&azure_config(None));
+
+        assert!(provider.url("").ends_with("/openai/deployments/gpt-4o"));
+    }
+
+    #[test]
+    fn test_extra_headers() {
+        let config = Config {
+            api_key: "secret".to_string(),
+            headers: [
+                ("x-litellm-tags", "team:search,project:aido"),
+                ("bad header", "x"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            ..Config::default()
+        };
+
+        let headers = ProviderConfig::from_config(&config).headers();
+
+        assert_eq!(headers["x-litellm-tags"], "team:search,project:aido");
+        assert!(headers.contains_key("authorization"));
+        assert!(!headers.contains_key("bad header"));
+    }
+
+    #[test]
+    fn test_provider_deserialization() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "api_key": "key",
+                "api_url": "https://example.openai.azure.com",
+                "model_name": "gpt-4o",
+                "timeout": 30,
+                "provider": "azure",
+                "azure": { "deployment": "prod" }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.provider, Provider::Azure);
+        assert_eq!(config.azure.deployment.as_deref(), Some("prod"));
+        assert_eq!(config.azure.api_version, DEFAULT_AZURE_API_VERSION);
+    }
+}
@@ -0,0 +1,275 @@
+//! Keeping simultaneous runs of a recipe apart
+//!
+//! A recipe that edits files can ask for a lock with `lock: recipe` or
+//! `lock: project` in its header. The run then holds a `flock` on lock
+//! files for as long as it lasts, so a second invocation in the same
+//! project fails right away instead of interleaving its changes with the
+//! first one's. A `lock: project` run holds the project's lock file
+//! exclusively, and a `lock: recipe` run holds its recipe's exclusively
+//! and shares the project's with runs of other recipes, so that either
+//! keeps project-wide runs out. The locks are released when the run ends,
+//! even if the process is killed.
+
+use std::fs::{File, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use directories::BaseDirs;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::isolation;
+
+/// Which runs a recipe's lock keeps out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockScope {
+    /// Other runs of the same recipe in the same project
+    Recipe,
+    /// Runs of any locking recipe in the same project
+    Project,
+}
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error(
+        "another aido run is in progress in {project}{}; wait for it to \
+         finish or remove {path} if it is stuck",
+        holder.map(|pid| format!(" (pid {pid})")).unwrap_or_default()
+    )]
+    Busy { project: String, path: String, holder: Option<u32> },
+
+    #[error("Failed to lock {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// How a lock file is held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Along with other shared holders, keeping out exclusive ones
+    Shared,
+    /// Keeping out every other holder
+    Exclusive,
+}
+
+/// The locks of a run, held until this value is dropped
+#[derive(Debug)]
+pub struct RunLock {
+    // Closing the files releases the locks
+    _files: Vec<File>,
+}
+
+impl RunLock {
+    /// Locks `recipe` in the project containing the current directory: its
+    /// git repository, or the directory itself outside of one
+    pub fn for_recipe(
+        scope: LockScope,
+        recipe: &str,
+    ) -> Result<Self, LockError> {
+        let cwd = std::env::current_dir().map_err(|source| LockError::Io {
+            path: ".".to_owned(),
+            source,
+        })?;
+        let project = isolation::repo_root(&cwd).unwrap_or(cwd);
+
+        Self::take(&lock_dir()?, &project, scope, recipe)
+    }
+
+    /// Takes the locks of `recipe` in `project`, kept in `dir`, without
+    /// waiting for them
+    fn take(
+        dir: &Path,
+        project: &Path,
+        scope: LockScope,
+        recipe: &str,
+    ) -> Result<Self, LockError> {
+        let files = lock_files(dir, project, scope, recipe)
+            .iter()
+            .map(|(path, access)| acquire(path, project, *access))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { _files: files })
+    }
+}
+
+/// Takes the lock on the file at `path` without waiting for it
+fn acquire(
+    path: &Path,
+    project: &Path,
+    access: Access,
+) -> Result<File, LockError> {
+    let io_error =
+        |source| LockError::Io { path: path.display().to_string(), source };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(io_error)?;
+    }
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(io_error)?;
+
+    let locked = match access {
+        Access::Shared => file.try_lock_shared(),
+        Access::Exclusive => file.try_lock(),
+    };
+    match locked {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            let holder = file
+                .read_to_string(&mut holder)
+                .ok()
+                .and_then(|_| holder.trim().parse().ok());
+
+            return Err(LockError::Busy {
+                project: project.display().to_string(),
+                path: path.display().to_string(),
+                holder,
+            });
+        }
+        Err(TryLockError::Error(e)) => return Err(io_error(e)),
+    }
+
+    // Record who holds the lock, for the message others get; a shared
+    // lock has no single holder, but clears one left by a killed run
+    file.set_len(0)
+        .and_then(|()| file.rewind())
+        .and_then(|()| match access {
+            Access::Shared => Ok(()),
+            Access::Exclusive => write!(file, "{}", std::process::id()),
+        })
+        .map_err(io_error)?;
+
+    Ok(file)
+}
+
+/// Where lock files are kept: in the user's runtime directory, or their
+/// cache directory on systems without one, so that they never show up in
+/// the project itself and other users can't get in their way
+fn lock_dir() -> Result<PathBuf, LockError> {
+    let dirs = BaseDirs::new().ok_or_else(|| LockError::Io {
+        path: "the lock directory".to_owned(),
+        source: io::Error::new(
+            io::ErrorKind::NotFound,
+            "there is no home directory",
+        ),
+    })?;
+    let base = dirs.runtime_dir().unwrap_or_else(|| dirs.cache_dir());
+
+    Ok(base.join("aido").join("locks"))
+}
+
+/// The lock files in `dir` a run of `recipe` in `project` takes, and how
+///
+/// Files are keyed by a digest of the project's path, which stays the same
+/// across builds of aido.
+fn lock_files(
+    dir: &Path,
+    project: &Path,
+    scope: LockScope,
+    recipe: &str,
+) -> Vec<(PathBuf, Access)> {
+    let digest = format!(
+        "{:x}",
+        Sha256::digest(project.as_os_str().as_encoded_bytes())
+    );
+    let key = &digest[..16];
+    let project_file = dir.join(format!("{key}.lock"));
+
+    match scope {
+        LockScope::Project => vec![(project_file, Access::Exclusive)],
+        LockScope::Recipe => vec![
+            (project_file, Access::Shared),
+            (dir.join(format!("{key}-{recipe}.lock")), Access::Exclusive),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_lock_is_refused() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-lock-test-{}", std::process::id()));
+        let project = dir.join("project");
+
+        let lock =
+            RunLock::take(&dir, &project, LockScope::Recipe, "edit").unwrap();
+        let error = RunLock::take(&dir, &project, LockScope::Recipe, "edit")
+            .unwrap_err();
+
+        assert!(matches!(
+            error,
+            LockError::Busy { holder: Some(pid), .. }
+                if pid == std::process::id()
+        ));
+        assert!(
+            error.to_string().starts_with("another aido run is in progress")
+        );
+
+        drop(lock);
+        RunLock::take(&dir, &project, LockScope::Recipe, "edit").unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_project_lock_keeps_out_recipe_locks() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-lock-scope-test-{}", std::process::id()));
+        let project = dir.join("project");
+        let take =
+            |scope, recipe| RunLock::take(&dir, &project, scope, recipe);
+
+        // Runs of different recipes only keep out project-wide runs
+        let edit = take(LockScope::Recipe, "edit").unwrap();
+        let fix = take(LockScope::Recipe, "fix").unwrap();
+        assert!(take(LockScope::Project, "release").is_err());
+
+        drop((edit, fix));
+        let release = take(LockScope::Project, "release").unwrap();
+        assert!(take(LockScope::Recipe, "edit").is_err());
+        assert!(take(LockScope::Project, "other").is_err());
+
+        drop(release);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_files() {
+        let dir = Path::new("/locks");
+        let project = Path::new("/work/project");
+        let files = |project, scope, recipe| {
+            lock_files(dir, Path::new(project), scope, recipe)
+        };
+
+        assert_ne!(
+            files("/work/project", LockScope::Recipe, "edit"),
+            files("/work/project", LockScope::Recipe, "fix")
+        );
+        assert_ne!(
+            files("/work/project", LockScope::Recipe, "edit"),
+            files("/work/other", LockScope::Recipe, "edit")
+        );
+        assert_eq!(
+            files("/work/project", LockScope::Project, "edit"),
+            files("/work/project", LockScope::Project, "fix")
+        );
+        // The same in every build
+        assert_eq!(
+            lock_files(dir, project, LockScope::Project, "edit"),
+            [(dir.join("65d80d2c48b3d23b.lock"), Access::Exclusive)]
+        );
+    }
+}
@@ -1,23 +1,67 @@
+use std::collections::HashMap;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 use std::vec;
 
-use crate::{
-    cli::{Args, Commands, ConfigCommands, RecipeCommands},
-    llm::Message,
-    tools::Tool,
+use crate::cli::{
+    Args, AuditCommands, CacheCommands, Commands, ConfigCommands,
+    RecipeCommands, SessionCommands, UsageCommands, WorkflowCommands,
+};
+use aido::{
+    audit::{self, AuditLog},
+    batch,
+    bundle::{Bundle, Recorder},
+    cache::ResponseCache,
+    cancel::CancelToken,
+    commit, compare, config, context, diff,
+    error::{AidoError, AidoResult},
+    history::{self, History, HistoryEntry},
+    limits,
+    llm::{LlmClient, Message},
+    notices::NoticeLog,
+    output::{self, OutputFormat},
+    paths,
+    recipe::{
+        self, Recipe, RecipeError, RecipeStore, install,
+        package::{self, PackageMetadata, RecipePackage},
+        validate,
+    },
+    redact::{self, Redactor},
+    retrieval::{self, Index},
+    run,
+    runner::Runner,
+    session::{
+        self, RunContext, Session, SessionError, SessionStore,
+        export::ExportFormat,
+    },
+    setup, shell,
+    tokens::TokenCount,
+    tools::{Tool, ToolRegistry},
+    usage::{self, Ledger, LedgerEntry},
+    workflow::{self, WorkflowStore},
 };
 use clap::Parser;
-use log::info;
+use log::{info, warn};
 
 mod cli;
-mod config;
-mod llm;
-mod recipe;
-mod run;
-mod tools;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-    let args = Args::parse();
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    match Box::pin(try_main()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+async fn try_main() -> AidoResult<()> {
+    let mut args = Args::parse();
+    args.read_input()?;
 
     let config_file_path = if let Some(config_file) = args.config_file() {
         config_file.to_string()
@@ -25,71 +69,46 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         config::get_configuration_file_path()?
     };
 
-    let tools: Vec<Box<dyn Tool>> = vec![Box::new(tools::Ls::new())];
+    let config = load_config(&args, &config_file_path).await?;
+    let redactor = Redactor::for_config(&config)?;
+    redact::init_logging(redactor.clone());
+    let audit_log = AuditLog::for_config_file(&config_file_path)
+        .with_redactor(redactor.clone());
 
-    let config = config::retrieve_from_path(&config_file_path)?;
+    let tools = ToolRegistry::from_config(&config)
+        .with_index(&config, &Index::path_for_config_file(&config_file_path))
+        .into_tools();
+    let run_options =
+        run_options(&args, &config, &config_file_path, &audit_log, &redactor);
+    // The server's runs are cancelled by its requests, and Ctrl-C stops it
+    if !matches!(args.command(), Some(Commands::Serve)) {
+        cancel_on_ctrl_c(&run_options.cancel);
+    }
+    // Runs only append to the transcript, so it starts out empty
+    if let Some(file) = &run_options.output_file
+        && !run_options.dry_run
+    {
+        file.truncate()?;
+    }
 
-    if let Some(command) = args.command() {
-        match command {
-            Commands::Config { command } => match command {
-                ConfigCommands::Show => {
-                    println!("{config:?}");
-                    return Ok(());
-                }
-                ConfigCommands::ShowPath => {
-                    println!("{config_file_path}");
-                    return Ok(());
-                }
-                ConfigCommands::Edit => {
-                    println!("...editing config...");
-                    return Ok(());
-                }
-                ConfigCommands::Validate => {
-                    println!("...validating config...");
-                    return Ok(());
-                }
-            },
-            Commands::Recipe { command } => {
-                match command {
-                    RecipeCommands::List => {
-                        recipe::list(&config_file_path)?;
-                    }
-                    RecipeCommands::Show { name } => {
-                        println!("...showing recipe: {name}...");
-                        let recipe_dir =
-                            recipe::get_recipes_dir(&config_file_path);
-                        let recipe = recipe::get_content(&recipe_dir, name)?;
-
-                        println!("{recipe}");
-                    }
-                    RecipeCommands::Create { name } => {
-                        println!("...creating recipe: {name}...");
-                    }
-                    RecipeCommands::ShowDir => {
-                        // recipe dir is in the parent dir of the config file
-                        let recipe_dir =
-                            recipe::get_recipes_dir(&config_file_path);
-                        let recipe_dir = recipe_dir.to_string_lossy();
-
-                        println!("{recipe_dir}");
-                    }
-                }
-                return Ok(());
-            }
-            Commands::Run { recipe, user_message } => {
-                let recipes_dir = recipe::get_recipes_dir(&config_file_path);
-                run::run_recipe(
-                    config,
-                    &recipes_dir,
-                    recipe,
-                    user_message.to_owned(),
-                    &tools,
-                    args.usage(),
-                )?;
+    if let Some(entry) = given_prompt(&args) {
+        record_prompt(&config_file_path, &entry, &run_options);
+    }
 
-                return Ok(());
-            }
-        }
+    if let Some(command) = args.command() {
+        let result = handle_command(
+            command,
+            args.input(),
+            &config,
+            &config_file_path,
+            &redactor,
+            &tools,
+            &run_options,
+        )
+        .await;
+        save_recording(args.record(), &config, &redactor, &run_options)?;
+        return result
+            .map_err(|e| point_to_legacy_recipe(e, &config_file_path));
     }
 
     info!("Configuration loaded: {config:?}");
@@ -98,10 +117,1623 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     if let Some(input) = args.input() {
         info!("Input: {:?}", args.input());
         let messages = vec![Message::User(input.to_string())];
-        run::run(config, messages, &tools, args.usage())?;
+        let result = run_messages(
+            &config,
+            &config_file_path,
+            messages,
+            &tools,
+            &run_options,
+        )
+        .await;
+        save_recording(args.record(), &config, &redactor, &run_options)?;
+        result?;
     } else {
         info!("No input file provided; all done.");
     }
 
     Ok(())
 }
+
+/// Cancels the runs using `cancel` when the user presses Ctrl-C, which
+/// makes them stop and keep what they had so far; pressing it again quits
+/// at once, for when nothing is running that watches the token
+fn cancel_on_ctrl_c(cancel: &CancelToken) {
+    let cancel = cancel.clone();
+    tokio::spawn(async move {
+        // If the signal handler can't be installed, Ctrl-C quits as usual
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if cancel.is_cancelled() {
+                std::process::exit(130);
+            }
+            cancel.cancel();
+        }
+    });
+}
+
+/// Writes what was recorded for `--record` to `path`, even when the run
+/// failed, since that's when a recording helps most
+fn save_recording(
+    path: Option<&Path>,
+    config: &config::Config,
+    redactor: &Redactor,
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    let (Some(path), Some(recorder)) = (path, &options.recorder) else {
+        return Ok(());
+    };
+    if options.dry_run {
+        return Ok(());
+    }
+
+    recorder.save(path, config, redactor)?;
+    eprintln!("Recorded the run to {}", path.display());
+    Ok(())
+}
+
+/// Runs the conversation recorded in the bundle at `path` again, answering
+/// from the recording instead of the model and tools
+async fn replay(path: &Path, run_options: &run::RunOptions) -> AidoResult<()> {
+    let bundle = Bundle::load(path)?;
+    let config = bundle.config()?;
+    let options = run::RunOptions {
+        cache: None,
+        audit: None,
+        reviewer: None,
+        ..run_options.clone()
+    }
+    .with_middleware(bundle.replies());
+
+    let outcome =
+        run::run(&config, bundle.messages()?, &bundle.tools(), &options)
+            .await?;
+    if !outcome.cancelled
+        && bundle.answer().is_some_and(|answer| answer != outcome.text)
+    {
+        eprintln!("Note: the replay ended with another answer than the run");
+    }
+
+    print_outcome(&outcome, &options)
+}
+
+/// Loads the config file, offering to set aido up first when there is
+/// none at the default location
+///
+/// Commands that don't use the settings run without one, with the
+/// defaults.
+async fn load_config(
+    args: &Args,
+    config_file_path: &str,
+) -> AidoResult<config::Config> {
+    let path = Path::new(config_file_path);
+    // `aido init` writes the config file, which may be missing or broken
+    if matches!(args.command(), Some(Commands::Init { .. })) {
+        return Ok(config::Config::default());
+    }
+    if args.config_file().is_none() && !path.exists() {
+        if args.command().is_some_and(|command| !command.needs_config()) {
+            return Ok(config::Config::default());
+        }
+        setup::first_run(path).await?;
+    }
+
+    let config = config::retrieve_profile_from_path(path, args.profile())?;
+    if config.timeout.is_some() {
+        eprintln!(
+            "Warning: `timeout` in {config_file_path} is deprecated and \
+             ignored; remove it, and set `read_timeout_secs` instead to \
+             change how long the API may go quiet"
+        );
+    }
+
+    Ok(config)
+}
+
+/// Writes a config file for the server at `api_url` and `model`, or those
+/// found running locally
+async fn init(
+    config_file_path: &str,
+    api_url: Option<&str>,
+    model: Option<&str>,
+    force: bool,
+) -> AidoResult<()> {
+    let config = setup::initial_config(api_url, model).await;
+    setup::write_config(Path::new(config_file_path), &config, force)?;
+    println!(
+        "Wrote {config_file_path}, using {} at {}",
+        config.model_name, config.api_url
+    );
+
+    Ok(())
+}
+
+/// Turns a recipe that isn't found into one that needs migrating when a
+/// directory earlier versions used has it
+fn point_to_legacy_recipe(
+    error: AidoError,
+    config_file_path: &str,
+) -> AidoError {
+    if let AidoError::Recipe(RecipeError::NotFound { name }) = &error
+        && let Some(dir) = paths::find_legacy_recipe(
+            &recipe::get_recipes_dir(config_file_path),
+            name,
+        )
+    {
+        return RecipeError::NotMigrated { name: name.clone(), dir }.into();
+    }
+
+    error
+}
+
+/// Runs the subcommand given on the command line
+#[expect(clippy::too_many_lines, reason = "one arm for each subcommand")]
+async fn handle_command(
+    command: &Commands,
+    input: Option<&str>,
+    config: &config::Config,
+    config_file_path: &str,
+    redactor: &Redactor,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    match command {
+        Commands::Init { api_url, model, force } => {
+            init(
+                config_file_path,
+                api_url.as_deref(),
+                model.as_deref(),
+                *force,
+            )
+            .await
+        }
+        Commands::Config { command } => {
+            handle_config_command(command, config, config_file_path, redactor);
+            Ok(())
+        }
+        Commands::Recipe { command } => {
+            handle_recipe_command(
+                command,
+                config_file_path,
+                tools,
+                run_options,
+            )
+            .await
+        }
+        Commands::Workflow { command } => {
+            run_workflow(command, config, config_file_path, tools, run_options)
+                .await
+        }
+        Commands::Session { command } => {
+            handle_session_command(
+                command,
+                config,
+                config_file_path,
+                tools,
+                run_options,
+            )
+            .await
+        }
+        Commands::Audit { command } => {
+            let audit_log = AuditLog::for_config_file(config_file_path)
+                .with_redactor(redactor.clone());
+            handle_audit_command(command, &audit_log)
+        }
+        Commands::Cache { command } => {
+            handle_cache_command(command, config_file_path)
+        }
+        Commands::Usage { command } => {
+            handle_usage_command(command, config_file_path)
+        }
+        Commands::Paths { migrate } => {
+            show_paths(config_file_path, *migrate, run_options)
+        }
+        Commands::Schema => print_schema(),
+        Commands::Serve => serve(config, config_file_path, run_options).await,
+        Commands::Shellenv { shell, recipe } => {
+            print!("{}", shell::env::script(*shell, &program(), recipe));
+            Ok(())
+        }
+        Commands::Models => list_models(config, run_options).await,
+        Commands::DiffLast { .. } => {
+            Box::pin(diff_last(config, config_file_path, tools, run_options))
+                .await
+        }
+        Commands::Tokens { recipe, user_message, .. } => count_tokens(
+            config,
+            config_file_path,
+            recipe,
+            user_message.to_owned(),
+            tools,
+            run_options,
+        ),
+        Commands::Ask { files, budget, question } => {
+            let budget = budget.or(config.context_budget);
+            let messages = ask_messages(files, budget, question)?;
+
+            run_messages(
+                config,
+                config_file_path,
+                messages,
+                tools,
+                run_options,
+            )
+            .await
+        }
+        Commands::Compare { models, prompt } => {
+            compare_models(config, models, prompt, run_options).await
+        }
+        Commands::Embed { files, model, dimensions } => {
+            let model = retrieval::embedding_model(config, model.as_deref());
+            embed(config, files, model, *dimensions, input, run_options).await
+        }
+        Commands::Index { dir, model } => {
+            let model = retrieval::embedding_model(config, model.as_deref());
+            index_dir(config, config_file_path, dir, model, run_options).await
+        }
+        Commands::History { limit } => show_history(config_file_path, *limit),
+        Commands::Replay { bundle } => replay(bundle, run_options).await,
+        Commands::Rerun { number } => {
+            rerun(config, config_file_path, *number, tools, run_options).await
+        }
+        Commands::Run { recipe, user_message, interactive: true, .. } => {
+            run_interactive(
+                config,
+                config_file_path,
+                recipe,
+                user_message.to_owned(),
+                tools,
+                run_options,
+            )
+            .await
+        }
+        Commands::Run { recipe, user_message, then, exec, .. } => {
+            let recipes = [std::slice::from_ref(recipe), then].concat();
+
+            let outcome = run_recipes(
+                config,
+                config_file_path,
+                &recipes,
+                user_message.to_owned(),
+                tools,
+                run_options,
+            )
+            .await?;
+
+            exec_if_asked(*exec, &outcome, run_options)
+        }
+        Commands::Commit { apply } => {
+            commit_staged(config, config_file_path, *apply, tools, run_options)
+                .await
+        }
+        Commands::Batch { recipes, each, .. } => {
+            run_batch(
+                config,
+                config_file_path,
+                recipes,
+                each,
+                tools,
+                run_options,
+            )
+            .await
+        }
+    }
+}
+
+/// The options for runs started from the command line
+fn run_options(
+    args: &Args,
+    config: &config::Config,
+    config_file_path: &str,
+    audit_log: &AuditLog,
+    redactor: &Redactor,
+) -> run::RunOptions {
+    let cache = config
+        .cache_ttl_secs
+        .filter(|&secs| secs > 0 && !args.no_cache())
+        .map(|secs| {
+            ResponseCache::for_config_file(config_file_path)
+                .with_ttl(Duration::from_secs(secs))
+                .with_redactor(redactor.clone())
+        });
+
+    let options = run::RunOptions {
+        isolated: args.isolated(),
+        trace: args.trace(),
+        output: args.output(),
+        copy_result: args.copy(),
+        output_file: args.output_file(),
+        check_command: matches!(
+            args.command(),
+            Some(Commands::Run { exec: true, .. })
+        ),
+        dry_run: args.dry_run(),
+        show_reasoning: args.show_reasoning(),
+        deterministic: args.deterministic(),
+        strict: args.strict(),
+        skip_project_context: args.no_project_context(),
+        params: args.request_params(),
+        // Partial output would be mangled for whatever reads stdout
+        quiet_stream: args.quiet_stream() || !io::stdout().is_terminal(),
+        audit: Some(audit_log.clone()),
+        cache,
+        notices: Some(NoticeLog::for_config_file(config_file_path)),
+        vars: match args.command() {
+            Some(
+                Commands::Run { vars, .. }
+                | Commands::Tokens { vars, .. }
+                | Commands::DiffLast { vars }
+                | Commands::Batch { vars, .. }
+                | Commands::Workflow {
+                    command: WorkflowCommands::Run { vars, .. },
+                },
+            ) => vars.iter().cloned().collect(),
+            _ => HashMap::new(),
+        },
+        // Log lines on stderr would be mangled by the spinner
+        status_line: io::stderr().is_terminal()
+            && !log::log_enabled!(log::Level::Info),
+        show_tool_output: io::stderr().is_terminal(),
+        ..run::RunOptions::new(config, args.usage(), args.max_iterations())
+    };
+    if args.record().is_some() {
+        options.with_recorder(Recorder::default())
+    } else {
+        options
+    }
+}
+
+/// Runs a conversation that isn't based on a recipe, then records and
+/// prints the outcome
+async fn run_messages(
+    config: &config::Config,
+    config_file_path: &str,
+    messages: Vec<Message>,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let outcome = run::run(config, messages, tools, run_options).await?;
+
+    record_run(config, config_file_path, &outcome, None, run_options);
+    print_outcome(&outcome, run_options)?;
+
+    Ok(())
+}
+
+/// Sends `prompt` to each of `models` at once and prints their answers
+async fn compare_models(
+    config: &config::Config,
+    models: &[String],
+    prompt: &str,
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let contenders = compare::contenders(config, models)?;
+    let messages = vec![Message::User(prompt.to_owned())];
+
+    if run_options.dry_run {
+        for contender in &contenders {
+            eprintln!("Would ask {}", contender.model());
+        }
+        return Ok(());
+    }
+
+    let answers = compare::compare(&contenders, messages, run_options).await;
+    if run_options.output == OutputFormat::Json {
+        compare::write_json(io::stdout(), &answers)?;
+    } else {
+        let width = compare::terminal_width();
+        compare::write_answers(io::stdout(), &answers, width)?;
+    }
+
+    Ok(compare::check(&answers)?)
+}
+
+/// Prints the embeddings by `model` of the texts `aido embed` was given:
+/// `text`, from `--input`, and the contents of `files`
+async fn embed(
+    config: &config::Config,
+    files: &[PathBuf],
+    model: &str,
+    dimensions: Option<u32>,
+    text: Option<&str>,
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let mut inputs = Vec::new();
+    let mut texts = Vec::new();
+    if let Some(text) = text {
+        inputs.push("--input".to_owned());
+        texts.push(text.to_owned());
+    }
+    for file in files {
+        let text = if file == Path::new("-") {
+            io::read_to_string(io::stdin())?
+        } else {
+            std::fs::read_to_string(file)?
+        };
+        inputs.push(file.display().to_string());
+        texts.push(text);
+    }
+    if texts.is_empty() {
+        inputs.push("-".to_owned());
+        texts.push(io::read_to_string(io::stdin())?);
+    }
+
+    if run_options.dry_run {
+        eprintln!("Would embed {} texts with {model}", texts.len());
+        return Ok(());
+    }
+
+    let embeddings =
+        LlmClient::from_config(config).embed(model, texts, dimensions).await?;
+    println!("{}", embeddings.to_json(&inputs)?);
+
+    Ok(())
+}
+
+/// Brings the files under `dir` in the index up to date
+async fn index_dir(
+    config: &config::Config,
+    config_file_path: &str,
+    dir: &Path,
+    model: &str,
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let mut index =
+        Index::load(Index::path_for_config_file(config_file_path))?;
+    if run_options.dry_run {
+        eprintln!("Would index {} with {model}", dir.display());
+        return Ok(());
+    }
+
+    let llm = LlmClient::from_config(config);
+    let report = index.update(&llm, model, dir).await?;
+    index.save()?;
+
+    if run_options.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        println!(
+            "Embedded {} chunks of {} files; {} unchanged, {} removed. The \
+             index at {} has {} chunks.",
+            report.chunks,
+            report.indexed,
+            report.unchanged,
+            report.removed,
+            index.path().display(),
+            index.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// How the shell scripts `aido shellenv` prints run aido: by the path of
+/// this executable, so that they work when it isn't on the `PATH`
+fn program() -> String {
+    std::env::current_exe()
+        .map_or_else(|_| "aido".to_owned(), |exe| exe.display().to_string())
+}
+
+/// Builds the conversation for `aido ask`: the question along with the
+/// project files most relevant to it that fit in `budget` tokens, or the
+/// default budget
+fn ask_messages(
+    files: &[String],
+    budget: Option<usize>,
+    question: &str,
+) -> AidoResult<Vec<Message>> {
+    let budget = budget.unwrap_or(context::DEFAULT_TOKEN_BUDGET);
+    let root = std::env::current_dir()?;
+    let ranked = if files.is_empty() {
+        let candidates = context::expand_globs(&root, &[])?;
+        context::rank_by_relevance(&root, candidates, question)
+    } else {
+        let matches = context::expand_globs(&root, files)?;
+        context::rank_files(&root, matches, question)
+    };
+
+    let packed = context::pack(&root, ranked, budget);
+    info!(
+        "Including {} files, omitting {}",
+        packed.files.len(),
+        packed.omitted.len()
+    );
+
+    Ok(vec![Message::User(context::build_prompt(&packed, question))])
+}
+
+/// Appends the usage of a finished run to the ledger and stores its
+/// conversation as a session. Failing to record either is not worth
+/// failing the run over, so errors are only logged. Dry runs never ran, so
+/// they aren't recorded.
+fn record_run(
+    config: &config::Config,
+    config_file_path: &str,
+    outcome: &run::RunOutcome,
+    recipe: Option<&str>,
+    options: &run::RunOptions,
+) {
+    if options.dry_run {
+        return;
+    }
+
+    record_usage(config_file_path, outcome, recipe);
+
+    let mut context = RunContext::current(&outcome.model)
+        .with_profile(config.active_profile.as_deref());
+    if let Some(name) = recipe {
+        let file =
+            RecipeStore::for_config_file(config_file_path).content(name).ok();
+        context = context.with_recipe(name, file.as_deref());
+    }
+
+    let session = Session::new(&outcome.model, outcome.messages.clone())
+        .with_context(context);
+    save_session(config_file_path, &session);
+}
+
+/// Adds the usage of a finished run to the ledger
+fn record_usage(
+    config_file_path: &str,
+    outcome: &run::RunOutcome,
+    recipe: Option<&str>,
+) {
+    let entry = LedgerEntry::new(
+        &outcome.model,
+        recipe.map(str::to_owned),
+        &outcome.usage,
+        outcome.cost,
+    )
+    .with_duration(outcome.trace.duration());
+
+    if let Err(e) = Ledger::for_config_file(config_file_path).record(&entry) {
+        warn!("Failed to record usage: {e}");
+    }
+}
+
+fn save_session(config_file_path: &str, session: &Session) -> bool {
+    match SessionStore::for_config_file(config_file_path).save(session) {
+        Ok(()) => {
+            info!("Saved session {}", session.id);
+            true
+        }
+        Err(e) => {
+            warn!("Failed to save session: {e}");
+            false
+        }
+    }
+}
+
+/// Answers the last message of the session `id` again, saving the
+/// conversation with the new answer as a branch of the session
+async fn retry_session(
+    config: &config::Config,
+    config_file_path: &str,
+    id: &str,
+    instruction: Option<&str>,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let parent = SessionStore::for_config_file(config_file_path).load(id)?;
+    let messages = parent.retry_messages(instruction)?;
+    let config =
+        config::Config { model_name: parent.model.clone(), ..config.clone() };
+
+    let outcome = run::run(&config, messages, tools, run_options).await?;
+    print_outcome(&outcome, run_options)?;
+    if run_options.dry_run {
+        return Ok(());
+    }
+
+    let recipe = parent.context.as_ref().and_then(|c| c.recipe.as_deref());
+    record_usage(config_file_path, &outcome, recipe);
+
+    let mut context = RunContext::current(&outcome.model)
+        .with_profile(config.active_profile.as_deref());
+    if let Some(parent_context) = &parent.context {
+        context.recipe.clone_from(&parent_context.recipe);
+        context.recipe_hash.clone_from(&parent_context.recipe_hash);
+    }
+    let branch = Session::new(&outcome.model, outcome.messages)
+        .with_context(context)
+        .with_parent(&parent.id);
+    if save_session(config_file_path, &branch) {
+        eprintln!(
+            "Saved the new answer as session {}, a branch of {id}",
+            branch.id
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs a recipe, followed by any recipes chained after it with `--then`
+async fn run_recipes(
+    config: &config::Config,
+    config_file_path: &str,
+    recipes: &[String],
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<run::RunOutcome> {
+    let outcome = run::run_chain(
+        config,
+        &RecipeStore::for_config_file(config_file_path),
+        recipes,
+        user_message,
+        tools,
+        run_options,
+        |recipe, outcome| {
+            record_run(
+                config,
+                config_file_path,
+                outcome,
+                Some(recipe),
+                run_options,
+            );
+        },
+    )
+    .await?;
+
+    if recipes.len() > 1
+        && !run_options.dry_run
+        && run_options.print_usage
+        && run_options.output.streams()
+    {
+        if run_options.quiet_stream {
+            eprintln!("Chain total: {}", outcome.usage);
+        } else {
+            println!("Chain total: {}", outcome.usage);
+        }
+    }
+    print_outcome(&outcome, run_options)?;
+
+    Ok(outcome)
+}
+
+/// Answers the JSON-RPC requests on stdin until it ends
+async fn serve(
+    config: &config::Config,
+    config_file_path: &str,
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let tools = ToolRegistry::from_config(config)
+        .with_index(config, &Index::path_for_config_file(config_file_path))
+        .into_tools();
+    // Stdout carries the responses, and stdin the requests rather than
+    // answers to confirmations
+    let options = run::RunOptions {
+        status_line: false,
+        show_tool_output: false,
+        ..run_options.clone()
+    };
+    let runner = Runner::new(
+        config.clone(),
+        RecipeStore::for_config_file(config_file_path),
+    )
+    .with_tools(tools)
+    .with_options(options)
+    .watching_config_file(config_file_path)
+    .on_text(|_| {})
+    .on_confirm(|_| false);
+
+    let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+    Ok(aido::serve::serve(&runner, stdin, tokio::io::stdout()).await?)
+}
+
+/// Runs a recipe, then answers the follow-up messages typed by the user in
+/// the same conversation until they press Ctrl-D
+async fn run_interactive(
+    config: &config::Config,
+    config_file_path: &str,
+    recipe: &str,
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let config_watcher = config::ConfigWatcher::new(config_file_path)
+        .inspect_err(|e| {
+            warn!("Not watching the config file {config_file_path}: {e}");
+        })
+        .ok()
+        .map(Arc::new);
+    let options = run::RunOptions { config_watcher, ..run_options.clone() };
+    let outcome = run::run_recipe_conversation(
+        config.clone(),
+        &RecipeStore::for_config_file(config_file_path),
+        recipe,
+        user_message,
+        tools,
+        &options,
+        |outcome| {
+            print_outcome(outcome, run_options)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            read_follow_up()
+        },
+    )
+    .await?;
+
+    record_run(config, config_file_path, &outcome, Some(recipe), run_options);
+    if outcome.cancelled {
+        print_outcome(&outcome, run_options)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the user's next message from stdin, skipping blank lines;
+/// `None` once the input ends
+fn read_follow_up() -> io::Result<Option<String>> {
+    loop {
+        eprint!("> ");
+        io::stderr().flush()?;
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            eprintln!();
+            return Ok(None);
+        }
+
+        let line = line.trim();
+        if !line.is_empty() {
+            return Ok(Some(line.to_owned()));
+        }
+    }
+}
+
+/// Runs the recipes matching `patterns` on every item listed in `each` and
+/// prints the report, failing if any run failed
+async fn run_batch(
+    config: &config::Config,
+    config_file_path: &str,
+    patterns: &[String],
+    each: &Path,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let store = RecipeStore::for_config_file(config_file_path);
+    let recipes = store.matching(patterns)?;
+    let items = batch::read_items(each)?;
+
+    let report = batch::run_batch(
+        config,
+        &store,
+        &recipes,
+        &items,
+        tools,
+        run_options,
+        |recipe, outcome| {
+            record_run(
+                config,
+                config_file_path,
+                outcome,
+                Some(recipe),
+                run_options,
+            );
+        },
+    )
+    .await;
+
+    match run_options.output {
+        _ if run_options.dry_run => {}
+        OutputFormat::Json => batch::write_json(io::stdout(), &report)?,
+        OutputFormat::Text | OutputFormat::Bare | OutputFormat::Command => {
+            print!("{report}");
+        }
+    }
+
+    Ok(report.check()?)
+}
+
+/// Writes a commit message for the staged changes and, with `apply`,
+/// commits with it once the user confirms, exiting with git's status if
+/// the commit fails
+async fn commit_staged(
+    config: &config::Config,
+    config_file_path: &str,
+    apply: bool,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let store = RecipeStore::for_config_file(config_file_path);
+    let outcome =
+        commit::suggest_message(config, &store, tools, run_options).await?;
+
+    record_run(
+        config,
+        config_file_path,
+        &outcome,
+        Some(commit::RECIPE_NAME),
+        run_options,
+    );
+    print_outcome(&outcome, run_options)?;
+
+    if !apply || outcome.cancelled || run_options.dry_run {
+        return Ok(());
+    }
+
+    let message = commit::message_from_answer(&outcome.text)?;
+    match commit::confirm_and_commit(message)? {
+        None => eprintln!("Not committing."),
+        Some(status) if !status.success() => {
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Some(_) => {}
+    }
+
+    Ok(())
+}
+
+/// Runs the command suggested by a run started with `--exec`, unless it
+/// was cancelled or only a dry run
+fn exec_if_asked(
+    exec: bool,
+    outcome: &run::RunOutcome,
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    if !exec || outcome.cancelled || options.dry_run {
+        return Ok(());
+    }
+
+    exec_suggested(&outcome.text)
+}
+
+/// Runs the command suggested in `answer` once the user confirms it,
+/// exiting with the command's status if it fails
+fn exec_suggested(answer: &str) -> AidoResult<()> {
+    let command = shell::extract_command(answer)
+        .ok_or("Could not find a command in the answer")?;
+    if !shell::check_and_report(command) {
+        return Err("Not running the command, which failed its checks".into());
+    }
+
+    match shell::confirm_and_run(command)? {
+        None => eprintln!("Not running the command."),
+        Some(status) if !status.success() => {
+            eprintln!("Command failed ({status})");
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Some(_) => {}
+    }
+
+    Ok(())
+}
+
+/// Prints the models the endpoint serves, one per line, with the configured
+/// one marked
+async fn list_models(
+    config: &config::Config,
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    let models = LlmClient::from_config(config).list_models().await?;
+
+    if options.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&models)?);
+        return Ok(());
+    }
+
+    let bold = io::stdout().is_terminal();
+    for model in &models {
+        if *model == config.model_name && bold {
+            println!("* \x1b[1m{model}\x1b[0m");
+        } else if *model == config.model_name {
+            println!("* {model}");
+        } else {
+            println!("  {model}");
+        }
+    }
+
+    if !models.contains(&config.model_name) {
+        eprintln!(
+            "The configured model '{}' is not one of the {} models the \
+             endpoint serves",
+            config.model_name,
+            models.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs the last message of the most recent session again, with its recipe
+/// if it had one, and prints the new answer marked with what changed
+async fn diff_last(
+    config: &config::Config,
+    config_file_path: &str,
+    tools: &[Box<dyn Tool>],
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    let Some(session) =
+        SessionStore::for_config_file(config_file_path).list()?.pop()
+    else {
+        return Err("There is no earlier run to compare with".into());
+    };
+    let Some((message, previous)) = session.last_exchange() else {
+        return Err(format!(
+            "Session {} has no answer to compare with",
+            session.id
+        )
+        .into());
+    };
+    let recipe = session.context.as_ref().and_then(|c| c.recipe.as_deref());
+
+    let mut quiet = options.clone();
+    quiet.copy_result = false;
+    quiet.output_file = None;
+    // The cache would answer with the very reply being compared with
+    quiet.cache = None;
+    // A dry run's whole point is the request it prints
+    if !options.dry_run {
+        quiet.callbacks.on_text = Some(Arc::new(|_: &str| {}));
+    }
+
+    let outcome = if let Some(name) = recipe {
+        run::run_recipe(
+            config.clone(),
+            &RecipeStore::for_config_file(config_file_path),
+            name,
+            Some(message.to_owned()),
+            tools,
+            &quiet,
+        )
+        .await?
+    } else {
+        let messages = vec![Message::User(message.to_owned())];
+        run::run(config, messages, tools, &quiet).await?
+    };
+    record_run(config, config_file_path, &outcome, recipe, options);
+    if outcome.cancelled {
+        eprintln!("Cancelled. Usage so far: {}", outcome.usage);
+    }
+    if options.dry_run || outcome.cancelled {
+        return Ok(());
+    }
+
+    if session.model != outcome.model {
+        eprintln!("Model: {} -> {}", session.model, outcome.model);
+    }
+    if previous == outcome.text {
+        eprintln!("The answer is the same as in session {}", session.id);
+    }
+    let color = io::stdout().is_terminal();
+    println!("{}", diff::word_diff(previous, &outcome.text, color));
+
+    Ok(())
+}
+
+/// Prints how many tokens a run of `recipe_name` with `user_message` would
+/// send, part by part
+fn count_tokens(
+    config: &config::Config,
+    config_file_path: &str,
+    recipe_name: &str,
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    let recipe =
+        RecipeStore::for_config_file(config_file_path).get(recipe_name)?;
+    let (config, messages, definitions) = run::recipe_request(
+        config.clone(),
+        &recipe,
+        recipe_name,
+        user_message,
+        tools,
+        options,
+    )?;
+    let tokens =
+        TokenCount::of_request(&config.model_name, &messages, &definitions);
+
+    if options.output == OutputFormat::Json {
+        let mut json = serde_json::to_value(&tokens)?;
+        json["total"] = tokens.total().into();
+        println!("{json}");
+    } else {
+        println!("{tokens}");
+    }
+
+    if let Some(limit) = config.context_limit
+        && tokens.total() > limit
+    {
+        eprintln!(
+            "The request is over the context limit of {limit} tokens by {}",
+            tokens.total() - limit
+        );
+    }
+    if let Some(window) = limits::context_window(&config, &config.model_name)
+        && tokens.total() > window
+    {
+        eprintln!(
+            "The request is over the context window of {} by {} tokens",
+            config.model_name,
+            tokens.total() - window
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints the result of a finished run in formats that aren't streamed
+fn print_outcome(
+    outcome: &run::RunOutcome,
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    if outcome.cancelled {
+        eprintln!("Cancelled. Usage so far: {}", outcome.usage);
+    }
+
+    let mut stdout = io::stdout().lock();
+    let printed = match options.output {
+        // A cancelled answer is cut off, and may not be safe to pipe on
+        OutputFormat::Text if options.quiet_stream && !outcome.cancelled => {
+            writeln!(stdout, "{}", outcome.text)
+        }
+        OutputFormat::Text => Ok(()),
+        OutputFormat::Json => output::write_json(stdout, outcome),
+        // The text of a dry run is the request, not an answer to search
+        OutputFormat::Bare | OutputFormat::Command if options.dry_run => {
+            writeln!(stdout, "{}", outcome.text)
+        }
+        OutputFormat::Bare => writeln!(stdout, "{}", outcome.text),
+        OutputFormat::Command if outcome.cancelled => {
+            return Err(
+                "The answer was cut off before it suggested a command".into(),
+            );
+        }
+        OutputFormat::Command => {
+            let command = shell::extract_command(&outcome.text)
+                .ok_or("Could not find a command in the answer")?;
+            writeln!(stdout, "{command}")
+        }
+    };
+
+    match printed {
+        // Whatever reads the answer stopped early, as `| head` does
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        printed => Ok(printed?),
+    }
+}
+
+/// The prompt given on the command line, for the history: the message of
+/// a one-off chat, or the recipe `aido run` runs
+fn given_prompt(args: &Args) -> Option<HistoryEntry> {
+    match args.command() {
+        None => args
+            .input()
+            .map(|input| HistoryEntry::new(None, Some(input.to_owned()))),
+        Some(Commands::Run { recipe, user_message, then, vars, .. }) => Some(
+            HistoryEntry::new(Some(recipe.clone()), user_message.clone())
+                .with_then(then)
+                .with_vars(vars.iter().cloned()),
+        ),
+        Some(_) => None,
+    }
+}
+
+fn show_history(config_file_path: &str, limit: usize) -> AidoResult<()> {
+    let history = History::for_config_file(config_file_path);
+    Ok(history::print_entries(&history, limit)?)
+}
+
+/// Adds a prompt given on the command line to the history
+///
+/// Like failing to record a run, failing to record the prompt is only
+/// logged. Dry runs aren't recorded.
+fn record_prompt(
+    config_file_path: &str,
+    entry: &HistoryEntry,
+    options: &run::RunOptions,
+) {
+    if options.dry_run {
+        return;
+    }
+
+    if let Err(e) = History::for_config_file(config_file_path).record(entry) {
+        warn!("Failed to record the prompt in the history: {e}");
+    }
+}
+
+/// Runs the prompt numbered `number` in the history again, or the last one
+/// without a number
+async fn rerun(
+    config: &config::Config,
+    config_file_path: &str,
+    number: Option<usize>,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let history = History::for_config_file(config_file_path);
+    let Some(entry) = history.get(number)? else {
+        return Err(AidoError::Usage(number.map_or_else(
+            || "No prompts recorded yet".to_owned(),
+            |number| {
+                format!(
+                    "No prompt number {number} in the history; see `aido \
+                     history`"
+                )
+            },
+        )));
+    };
+
+    eprintln!("{entry}");
+    record_prompt(config_file_path, &entry.given_again(), run_options);
+
+    let Some(recipe) = entry.recipe else {
+        let message = entry.message.unwrap_or_default();
+        return run_messages(
+            config,
+            config_file_path,
+            vec![Message::User(message)],
+            tools,
+            run_options,
+        )
+        .await;
+    };
+
+    let recipes =
+        std::iter::once(recipe).chain(entry.then).collect::<Vec<_>>();
+    let options = run::RunOptions {
+        vars: entry.vars.into_iter().collect(),
+        ..run_options.clone()
+    };
+    run_recipes(
+        config,
+        config_file_path,
+        &recipes,
+        entry.message,
+        tools,
+        &options,
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn print_schema() -> AidoResult<()> {
+    let schema = serde_json::to_string_pretty(&output::json_schema())?;
+    println!("{schema}");
+    Ok(())
+}
+
+fn show_paths(
+    config_file_path: &str,
+    migrate: bool,
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    let all = paths::all(config_file_path)?;
+    let recipes_dir = recipe::get_recipes_dir(config_file_path);
+    let legacy_dirs = paths::legacy_recipe_dirs(&recipes_dir);
+
+    if migrate {
+        for dir in &legacy_dirs {
+            let migration = paths::migrate_recipes(dir, &recipes_dir)?;
+            for (from, to) in &migration.copied {
+                println!("Copied {} to {}", from.display(), to.display());
+            }
+            for from in &migration.skipped {
+                println!(
+                    "Skipped {}: the recipes directory has one of that name",
+                    from.display()
+                );
+            }
+        }
+        if legacy_dirs.is_empty() {
+            println!("No recipes to migrate");
+        }
+        return Ok(());
+    }
+
+    if options.output == OutputFormat::Json {
+        let mut json = all
+            .iter()
+            .map(|(name, path)| {
+                ((*name).to_owned(), path.display().to_string().into())
+            })
+            .collect::<serde_json::Map<_, _>>();
+        json.insert(
+            "legacy_recipes".to_owned(),
+            legacy_dirs.iter().map(|d| d.display().to_string()).collect(),
+        );
+        println!("{}", serde_json::Value::Object(json));
+        return Ok(());
+    }
+
+    for (name, path) in &all {
+        println!("{name:<12} {}", path.display());
+    }
+    if !legacy_dirs.is_empty() {
+        eprintln!();
+        for dir in &legacy_dirs {
+            eprintln!("Recipes in {} aren't read anymore", dir.display());
+        }
+        eprintln!("Run `aido paths --migrate` to copy them over");
+    }
+
+    Ok(())
+}
+
+fn handle_config_command(
+    command: &ConfigCommands,
+    config: &config::Config,
+    config_file_path: &str,
+    redactor: &Redactor,
+) {
+    match command {
+        ConfigCommands::Show => {
+            println!("{}", redactor.redact(&format!("{config:?}")));
+        }
+        ConfigCommands::ShowPath => {
+            println!("{config_file_path}");
+        }
+        ConfigCommands::Edit => {
+            println!("...editing config...");
+        }
+        ConfigCommands::Validate => {
+            println!("...validating config...");
+        }
+    }
+}
+
+async fn handle_recipe_command(
+    command: &RecipeCommands,
+    config_file_path: &str,
+    tools: &[Box<dyn Tool>],
+    options: &run::RunOptions,
+) -> AidoResult<()> {
+    let store = RecipeStore::for_config_file(config_file_path);
+
+    match command {
+        RecipeCommands::List => {
+            for recipe in store.list()? {
+                println!("{:<20} {}", recipe.name, recipe.display_name);
+            }
+        }
+        RecipeCommands::Show { name } => {
+            println!("{}", store.content(name)?);
+        }
+        RecipeCommands::Create { name } => {
+            println!("...creating recipe: {name}...");
+        }
+        RecipeCommands::Validate { name, .. } => {
+            let names = match name {
+                Some(name) => vec![name.clone()],
+                None => store.list()?.into_iter().map(|r| r.name).collect(),
+            };
+            validate_recipes(&store, &names, tools, options.output)?;
+        }
+        RecipeCommands::ShowDir => {
+            // recipe dir is in the parent dir of the config file
+            println!("{}", store.dir().display());
+        }
+        RecipeCommands::Package {
+            name,
+            author,
+            license,
+            version,
+            min_aido_version,
+            out,
+        } => {
+            let content = store.content(name)?;
+            let recipe = Recipe::parse(&content)?;
+            let Some(author) = author.clone().or_else(git_user_name) else {
+                return Err("No author given; pass --author".into());
+            };
+
+            let mut metadata =
+                PackageMetadata::new(name, author, license, version, &recipe);
+            if let Some(min_aido_version) = min_aido_version {
+                metadata = metadata.with_min_aido_version(min_aido_version);
+            }
+            let key = package::load_or_create_key(
+                &package::key_path_for_config_file(config_file_path),
+            )?;
+            let package = RecipePackage::create(metadata, &content, &key)?;
+
+            let path = out.clone().unwrap_or_else(|| {
+                format!("{name}.{}", package::PACKAGE_EXTENSION).into()
+            });
+            std::fs::write(&path, package.to_json()?)?;
+            println!("{}", path.display());
+            eprintln!("Signed with key {}", package.public_key());
+        }
+        RecipeCommands::Install { source, force } => {
+            let outcomes = install::install(&store, source, *force).await?;
+            print_install_outcomes(&outcomes, options.output);
+        }
+        RecipeCommands::Update { names, force } => {
+            let outcomes = install::update(&store, names, *force).await?;
+            if outcomes.is_empty() {
+                eprintln!(
+                    "No recipes were installed with `aido recipe install`"
+                );
+            }
+            print_install_outcomes(&outcomes, options.output);
+        }
+        RecipeCommands::Trust { name } => {
+            install::trust(&store, name)?;
+            eprintln!("The hooks of '{name}' will run without asking");
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints what installing or updating did with each recipe
+fn print_install_outcomes(
+    outcomes: &[(String, install::Outcome)],
+    output: OutputFormat,
+) {
+    for (name, outcome) in outcomes {
+        if output == OutputFormat::Json {
+            let json = serde_json::json!({
+                "recipe": name,
+                "outcome": outcome,
+            });
+            println!("{json}");
+        } else {
+            println!("{name}: {outcome}");
+        }
+    }
+}
+
+/// Checks the recipes named `names`, printing their problems, and fails
+/// when any has some
+fn validate_recipes(
+    store: &RecipeStore,
+    names: &[String],
+    tools: &[Box<dyn Tool>],
+    output: OutputFormat,
+) -> AidoResult<()> {
+    let tool_names =
+        tools.iter().map(|t| t.definition().name()).collect::<Vec<_>>();
+    let mut failed = 0;
+
+    for name in names {
+        let problems = validate::validate(&store.content(name)?, &tool_names);
+        if !problems.is_empty() {
+            failed += 1;
+        }
+
+        if output == OutputFormat::Json {
+            let json = serde_json::json!({
+                "recipe": name,
+                "valid": problems.is_empty(),
+                "problems": problems,
+            });
+            println!("{json}");
+        } else if problems.is_empty() {
+            println!("{name}: ok");
+        } else {
+            println!("{name}:");
+            for problem in &problems {
+                println!("  {problem}");
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(RecipeError::ValidationFailed { count: failed }.into());
+    }
+
+    Ok(())
+}
+
+/// The `user.name` from the git config, if set
+fn git_user_name() -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(["config", "user.name"])
+        .output()
+        .ok()?;
+    let name = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+    (output.status.success() && !name.is_empty()).then_some(name)
+}
+
+fn handle_usage_command(
+    command: &UsageCommands,
+    config_file_path: &str,
+) -> AidoResult<()> {
+    match command {
+        UsageCommands::Report { weekly } => {
+            let ledger = Ledger::for_config_file(config_file_path);
+            if *weekly {
+                usage::print_weekly_report(&ledger)?;
+            } else {
+                usage::print_report(&ledger)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_cache_command(
+    command: &CacheCommands,
+    config_file_path: &str,
+) -> AidoResult<()> {
+    match command {
+        CacheCommands::Clear => {
+            let cache = ResponseCache::for_config_file(config_file_path);
+            let removed = cache.clear()?;
+            println!("Removed {removed} cached responses");
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_audit_command(
+    command: &AuditCommands,
+    log: &AuditLog,
+) -> AidoResult<()> {
+    match command {
+        AuditCommands::Show { limit } => audit::print_entries(log, *limit)?,
+    }
+
+    Ok(())
+}
+
+/// Runs the subcommand of `aido workflow`
+async fn run_workflow(
+    command: &WorkflowCommands,
+    config: &config::Config,
+    config_file_path: &str,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let store = WorkflowStore::for_config_file(config_file_path)?;
+
+    match command {
+        WorkflowCommands::List => {
+            for workflow in store.list()? {
+                println!(
+                    "{:<20} {}",
+                    workflow.name,
+                    workflow.description.unwrap_or_default()
+                );
+            }
+        }
+        WorkflowCommands::Run { name, user_message, .. } => {
+            let workflow = store.get(name)?;
+            let outcome = Box::pin(workflow::run_workflow(
+                config,
+                &RecipeStore::for_config_file(config_file_path),
+                &workflow,
+                user_message.clone(),
+                tools,
+                run_options,
+                |recipe, outcome| {
+                    record_run(
+                        config,
+                        config_file_path,
+                        outcome,
+                        Some(recipe),
+                        run_options,
+                    );
+                },
+            ))
+            .await?;
+
+            if !run_options.dry_run
+                && run_options.print_usage
+                && run_options.output.streams()
+            {
+                if run_options.quiet_stream {
+                    eprintln!("Workflow total: {}", outcome.usage);
+                } else {
+                    println!("Workflow total: {}", outcome.usage);
+                }
+            }
+            print_outcome(&outcome, run_options)?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_session_command(
+    command: &SessionCommands,
+    config: &config::Config,
+    config_file_path: &str,
+    tools: &[Box<dyn Tool>],
+    run_options: &run::RunOptions,
+) -> AidoResult<()> {
+    let store = SessionStore::for_config_file(config_file_path);
+
+    match command {
+        SessionCommands::List => {
+            for session in store.list()? {
+                println!(
+                    "{:<24} {:>4} messages  {}",
+                    session.id,
+                    session.messages.len(),
+                    session.title()
+                );
+            }
+        }
+        SessionCommands::Show { id } => {
+            let session = store.load(id)?;
+            if let Some(context) = &session.context {
+                println!("{context}\n");
+            }
+            if let Some(parent) = &session.parent {
+                println!("Branched from session {parent}\n");
+            }
+            println!("{}", session::transcript(&session.messages));
+        }
+        SessionCommands::Export { id, format, out } => {
+            let session = session::export::redact(
+                &store.load(id)?,
+                &Redactor::for_config(config)?,
+            );
+            let exported = match format {
+                ExportFormat::Md => session::export::to_markdown(&session),
+                ExportFormat::Json => {
+                    format!("{}\n", session::export::to_json(&session)?)
+                }
+            };
+
+            match out {
+                Some(path) => std::fs::write(path, exported.as_bytes())?,
+                None => print!("{exported}"),
+            }
+        }
+        SessionCommands::Retry { id, instruction } => {
+            retry_session(
+                config,
+                config_file_path,
+                id,
+                instruction.as_deref(),
+                tools,
+                run_options,
+            )
+            .await?;
+        }
+        SessionCommands::Compact { id, keep } => {
+            let original = store.load(id)?;
+            if run_options.dry_run {
+                let request =
+                    session::summary_request(&original.messages, *keep)
+                        .ok_or_else(|| SessionError::NothingToCompact {
+                            id: id.clone(),
+                        })?;
+                let body =
+                    LlmClient::from_config(config).request_body(&request)?;
+                println!("{}", serde_json::to_string_pretty(&body)?);
+                return Ok(());
+            }
+
+            let mut session = original.clone();
+            let Some(usage) =
+                run::compact_session(config, &mut session, *keep, run_options)
+                    .await?
+            else {
+                eprintln!("Cancelled; session {id} is unchanged");
+                return Ok(());
+            };
+            let archived = store.archive(&original)?;
+            store.update(&session)?;
+
+            let cost = usage::estimate_cost(
+                &config.prices,
+                &config.model_name,
+                &usage,
+            );
+            let entry =
+                LedgerEntry::new(&config.model_name, None, &usage, cost);
+            if let Err(e) =
+                Ledger::for_config_file(config_file_path).record(&entry)
+            {
+                warn!("Failed to record usage: {e}");
+            }
+
+            println!(
+                "Compacted session {id} from {} to {} messages; \
+                 the original is archived at {}",
+                original.messages.len(),
+                session.messages.len(),
+                archived.display()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+//! Picking apart the Markdown that models answer in
+
+/// Returns the contents of every complete fenced code block in `text`, in
+/// order, without their fences and language tags
+pub fn code_blocks(text: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut rest = text;
+
+    while let Some((_, after_fence)) = rest.split_once("```")
+        // Skip the language tag on the opening fence
+        && let Some((_, block)) = after_fence.split_once('\n')
+        && let Some((code, after_block)) = block.split_once("```")
+    {
+        blocks.push(code.trim_end_matches('\n'));
+        rest = after_block;
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_blocks() {
+        let answer = "Run this:\n\n```sh\nls -al\n```\n\nthen\n```\npwd\n```";
+
+        assert_eq!(code_blocks(answer), ["ls -al", "pwd"]);
+        assert!(code_blocks("no code here").is_empty());
+        assert!(code_blocks("```unterminated\nls").is_empty());
+    }
+}
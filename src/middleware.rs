@@ -0,0 +1,391 @@
+//! Extension points around the LLM and tool calls made by the agent loop
+//!
+//! A [`Middleware`] sees each request before it is sent and each response
+//! before the loop acts on it; a [`ToolHook`] sees each tool invocation
+//! before and after it runs. Both are registered on
+//! [`RunOptions`](crate::run::RunOptions) and run in registration order
+//! before a call and in reverse order after it, so the first one registered
+//! wraps all of the others.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::llm::{LlmRequest, LlmResponse};
+use crate::tools::{Tool, ToolInput};
+
+pub type MiddlewareResult<T> =
+    Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Hooks that can inspect and modify the conversation around an LLM call
+///
+/// Both hooks default to doing nothing, so implementors only override what
+/// they need. Returning an error from either hook aborts the run.
+pub trait Middleware: Send + Sync {
+    /// Called before each LLM call with the request about to be sent
+    ///
+    /// Changes to the request only affect this call, not the conversation
+    /// history the loop keeps. Returning a response skips the LLM call
+    /// entirely and uses that response instead, e.g. to serve from a cache.
+    fn before_request(
+        &self,
+        request: &mut LlmRequest,
+    ) -> MiddlewareResult<Option<LlmResponse>> {
+        let _ = request;
+        Ok(None)
+    }
+
+    /// Called with each response before the loop acts on it
+    fn after_response(
+        &self,
+        request: &LlmRequest,
+        response: &mut LlmResponse,
+    ) -> MiddlewareResult<()> {
+        let _ = (request, response);
+        Ok(())
+    }
+
+    /// Whether [`Middleware::after_response`] may change the text of a
+    /// response, in which case replies are printed once it has, instead of
+    /// streamed as they come
+    ///
+    /// Middleware that only looks at responses returns `false` to keep
+    /// replies streaming.
+    fn rewrites_text(&self) -> bool {
+        true
+    }
+}
+
+/// Whether a tool call may go ahead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolDecision {
+    /// Run the tool
+    Allow,
+    /// Skip the tool and tell the model why
+    Deny(String),
+}
+
+/// Hooks around every tool invocation, e.g. for a custom policy engine
+///
+/// Both hooks default to doing nothing. Returning an error from either hook
+/// aborts the run, while [`ToolDecision::Deny`] only skips the one call.
+pub trait ToolHook: Send + Sync {
+    /// Called before a tool runs with the arguments it is about to receive
+    fn before_tool(
+        &self,
+        tool: &dyn Tool,
+        input: &mut ToolInput,
+    ) -> MiddlewareResult<ToolDecision> {
+        let _ = (tool, input);
+        Ok(ToolDecision::Allow)
+    }
+
+    /// Called with the tool's output before it is sent back to the model
+    fn after_tool(
+        &self,
+        tool: &dyn Tool,
+        input: &ToolInput,
+        output: &mut String,
+    ) -> MiddlewareResult<()> {
+        let _ = (tool, input, output);
+        Ok(())
+    }
+}
+
+/// An ordered list of registered middleware and tool hooks
+#[derive(Clone, Default)]
+pub struct MiddlewareStack {
+    middleware: Vec<Arc<dyn Middleware>>,
+    tool_hooks: Vec<Arc<dyn ToolHook>>,
+}
+
+impl MiddlewareStack {
+    /// Adds a middleware to the end of the stack
+    pub fn push(&mut self, middleware: impl Middleware + 'static) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    /// Adds a tool hook to the end of the stack
+    pub fn push_tool_hook(&mut self, hook: impl ToolHook + 'static) {
+        self.tool_hooks.push(Arc::new(hook));
+    }
+
+    pub fn len(&self) -> usize {
+        self.middleware.len() + self.tool_hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middleware.is_empty() && self.tool_hooks.is_empty()
+    }
+
+    /// Runs every `before_request` hook in order, stopping at the first
+    /// middleware that supplies a response
+    pub fn before_request(
+        &self,
+        request: &mut LlmRequest,
+    ) -> MiddlewareResult<Option<LlmResponse>> {
+        for middleware in &self.middleware {
+            if let Some(response) = middleware.before_request(request)? {
+                return Ok(Some(response));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Whether any middleware may change the text of responses, see
+    /// [`Middleware::rewrites_text`]
+    pub fn rewrites_text(&self) -> bool {
+        self.middleware.iter().any(|middleware| middleware.rewrites_text())
+    }
+
+    /// Runs every `after_response` hook in reverse order
+    pub fn after_response(
+        &self,
+        request: &LlmRequest,
+        response: &mut LlmResponse,
+    ) -> MiddlewareResult<()> {
+        for middleware in self.middleware.iter().rev() {
+            middleware.after_response(request, response)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs every `before_tool` hook in order, stopping at the first hook
+    /// that denies the call
+    pub fn before_tool(
+        &self,
+        tool: &dyn Tool,
+        input: &mut ToolInput,
+    ) -> MiddlewareResult<ToolDecision> {
+        for hook in &self.tool_hooks {
+            let decision = hook.before_tool(tool, input)?;
+            if decision != ToolDecision::Allow {
+                return Ok(decision);
+            }
+        }
+
+        Ok(ToolDecision::Allow)
+    }
+
+    /// Runs every `after_tool` hook in reverse order
+    pub fn after_tool(
+        &self,
+        tool: &dyn Tool,
+        input: &ToolInput,
+        output: &mut String,
+    ) -> MiddlewareResult<()> {
+        for hook in self.tool_hooks.iter().rev() {
+            hook.after_tool(tool, input, output)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MiddlewareStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MiddlewareStack")
+            .field("middleware", &self.middleware.len())
+            .field("tool_hooks", &self.tool_hooks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::error::AidoResult;
+    use crate::llm::{Message, Usage};
+    use crate::tools::{Capability, Search, ToolContext, ToolDefinition};
+
+    /// Records the order hooks are called in
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn before_request(
+            &self,
+            _request: &mut LlmRequest,
+        ) -> MiddlewareResult<Option<LlmResponse>> {
+            self.calls.lock().unwrap().push(format!("before {}", self.name));
+            Ok(None)
+        }
+
+        fn after_response(
+            &self,
+            _request: &LlmRequest,
+            _response: &mut LlmResponse,
+        ) -> MiddlewareResult<()> {
+            self.calls.lock().unwrap().push(format!("after {}", self.name));
+            Ok(())
+        }
+
+        fn rewrites_text(&self) -> bool {
+            false
+        }
+    }
+
+    struct Redact;
+
+    impl Middleware for Redact {
+        fn before_request(
+            &self,
+            request: &mut LlmRequest,
+        ) -> MiddlewareResult<Option<LlmResponse>> {
+            for message in request.messages_mut() {
+                if let Message::User(content) = message {
+                    *content = content.replace("hunter2", "[redacted]");
+                }
+            }
+            Ok(None)
+        }
+
+        fn after_response(
+            &self,
+            _request: &LlmRequest,
+            response: &mut LlmResponse,
+        ) -> MiddlewareResult<()> {
+            *response.text_mut() = response.text().to_uppercase();
+            Ok(())
+        }
+    }
+
+    struct Cache;
+
+    impl Middleware for Cache {
+        fn before_request(
+            &self,
+            _request: &mut LlmRequest,
+        ) -> MiddlewareResult<Option<LlmResponse>> {
+            Ok(Some(LlmResponse::new("cached", Usage::default(), vec![])))
+        }
+    }
+
+    /// Denies every tool that can do more than read
+    struct ReadOnlyPolicy;
+
+    impl ToolHook for ReadOnlyPolicy {
+        fn before_tool(
+            &self,
+            tool: &dyn Tool,
+            _input: &mut ToolInput,
+        ) -> MiddlewareResult<ToolDecision> {
+            if tool.capability() > Capability::Read {
+                return Ok(ToolDecision::Deny("read-only session".into()));
+            }
+            Ok(ToolDecision::Allow)
+        }
+
+        fn after_tool(
+            &self,
+            _tool: &dyn Tool,
+            _input: &ToolInput,
+            output: &mut String,
+        ) -> MiddlewareResult<()> {
+            output.push_str("\n[checked]");
+            Ok(())
+        }
+    }
+
+    struct Writer;
+
+    #[async_trait::async_trait]
+    impl Tool for Writer {
+        fn definition(&self) -> &ToolDefinition {
+            unimplemented!()
+        }
+
+        fn capability(&self) -> Capability {
+            Capability::Write
+        }
+
+        async fn execute(
+            &self,
+            _input: ToolInput,
+            _context: &ToolContext,
+        ) -> AidoResult<String> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_tool_hooks_apply_policy() {
+        let mut stack = MiddlewareStack::default();
+        stack.push_tool_hook(ReadOnlyPolicy);
+        let mut input = ToolInput::new();
+
+        assert_eq!(
+            stack.before_tool(&Search::new(), &mut input).unwrap(),
+            ToolDecision::Allow
+        );
+        assert_eq!(
+            stack.before_tool(&Writer, &mut input).unwrap(),
+            ToolDecision::Deny("read-only session".to_string())
+        );
+
+        let mut output = "found".to_string();
+        stack.after_tool(&Search::new(), &input, &mut output).unwrap();
+        assert_eq!(output, "found\n[checked]");
+    }
+
+    #[test]
+    fn test_hooks_run_in_onion_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut stack = MiddlewareStack::default();
+        stack.push(Recorder { name: "a", calls: Arc::clone(&calls) });
+        stack.push(Recorder { name: "b", calls: Arc::clone(&calls) });
+
+        let mut request = LlmRequest::default();
+        assert!(stack.before_request(&mut request).unwrap().is_none());
+        stack.after_response(&request, &mut LlmResponse::default()).unwrap();
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["before a", "before b", "after b", "after a"]
+        );
+        // Only looking at replies, so they can stream
+        assert!(!stack.rewrites_text());
+    }
+
+    #[test]
+    fn test_middleware_can_modify_request_and_response() {
+        let mut stack = MiddlewareStack::default();
+        stack.push(Redact);
+
+        let mut request = LlmRequest::new(
+            vec![Message::User("my password is hunter2".to_string())],
+            vec![],
+        );
+        let mut response =
+            LlmResponse::new("done", Usage::default(), Vec::new());
+
+        stack.before_request(&mut request).unwrap();
+        stack.after_response(&request, &mut response).unwrap();
+
+        assert!(matches!(
+            &request.messages()[0],
+            Message::User(content) if content == "my password is [redacted]"
+        ));
+        assert_eq!(response.text(), "DONE");
+        assert!(stack.rewrites_text());
+    }
+
+    #[test]
+    fn test_middleware_can_short_circuit() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut stack = MiddlewareStack::default();
+        stack.push(Cache);
+        stack.push(Recorder { name: "a", calls: Arc::clone(&calls) });
+
+        let response =
+            stack.before_request(&mut LlmRequest::default()).unwrap();
+
+        assert_eq!(response.unwrap().text(), "cached");
+        assert!(calls.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+//! Warnings providers send along with replies
+//!
+//! Providers announce that a model is going away through the `Deprecation`
+//! and `Sunset` headers, or a `warning` field in the reply, and say how
+//! much of the rate limit is left through the `x-ratelimit-*` headers.
+//! Nobody reads those in a script that runs every hour, so they are turned
+//! into [`Notice`]s and printed to stderr. The [`NoticeLog`] remembers when
+//! each kind of notice was last shown for each model, so that automation
+//! owners get told once a day rather than on every run.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use reqwest::header::HeaderMap;
+use serde_json::Value;
+
+/// Name of the file, next to the config file, recording when notices were
+/// last shown
+const NOTICE_LOG_FILE_NAME: &str = "notices.json";
+
+/// How long a notice stays quiet after it was shown
+pub const NOTICE_INTERVAL: Duration = Duration::from_hours(24);
+
+/// Share of the rate limit below which what is left gets reported, in
+/// percent
+const RATE_LIMIT_WARNING_PERCENT: u64 = 10;
+
+/// What a notice is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NoticeKind {
+    /// The model is deprecated or about to be retired
+    Deprecation,
+    /// Little of the rate limit is left
+    RateLimit,
+    /// Any other warning from the provider
+    Warning,
+}
+
+impl NoticeKind {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Deprecation => "deprecation",
+            Self::RateLimit => "rate-limit",
+            Self::Warning => "warning",
+        }
+    }
+}
+
+/// A warning about a model, from the provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notice {
+    model: String,
+    kind: NoticeKind,
+    message: String,
+}
+
+impl Notice {
+    pub fn new(
+        model: impl Into<String>,
+        kind: NoticeKind,
+        message: impl Into<String>,
+    ) -> Self {
+        Self { model: model.into(), kind, message: message.into() }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub const fn kind(&self) -> NoticeKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Identifies the notices that are shown at most once per interval
+    fn key(&self) -> String {
+        format!("{}|{}", self.model, self.kind.as_str())
+    }
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Notice for model {}: {}", self.model, self.message)
+    }
+}
+
+/// The notices in the headers of a reply from `model`
+pub fn from_headers(model: &str, headers: &HeaderMap) -> Vec<Notice> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+
+    let mut notices = Vec::new();
+
+    match (header("deprecation"), header("sunset")) {
+        (None, None) => {}
+        (_, Some(sunset)) => notices.push(Notice::new(
+            model,
+            NoticeKind::Deprecation,
+            format!("the model is deprecated and will be retired on {sunset}"),
+        )),
+        (Some(_), None) => notices.push(Notice::new(
+            model,
+            NoticeKind::Deprecation,
+            "the model is deprecated",
+        )),
+    }
+
+    // Each value looks like `299 - "Deprecated model" "<date>"`
+    for value in headers.get_all("warning") {
+        if let Ok(value) = value.to_str()
+            && let Some(text) = value.split('"').nth(1)
+        {
+            notices.push(warning(model, text));
+        }
+    }
+
+    let mut low = Vec::new();
+    for resource in ["requests", "tokens"] {
+        let number = |name: String| header(&name)?.parse::<u64>().ok();
+        if let (Some(limit), Some(remaining)) = (
+            number(format!("x-ratelimit-limit-{resource}")),
+            number(format!("x-ratelimit-remaining-{resource}")),
+        ) && limit > 0
+            && remaining * 100 < limit * RATE_LIMIT_WARNING_PERCENT
+        {
+            let reset = header(&format!("x-ratelimit-reset-{resource}"))
+                .map(|reset| format!(", resetting in {reset}"))
+                .unwrap_or_default();
+            low.push(format!("{remaining} of {limit} {resource} left{reset}"));
+        }
+    }
+    if !low.is_empty() {
+        notices.push(Notice::new(
+            model,
+            NoticeKind::RateLimit,
+            format!("the rate limit is nearly used up: {}", low.join("; ")),
+        ));
+    }
+
+    notices
+}
+
+/// The notices in the `warning` or `warnings` field of a streamed chunk
+/// from `model`
+pub fn from_chunk(model: &str, chunk: &Value) -> Vec<Notice> {
+    let single = chunk.get("warning").into_iter();
+    let many = chunk.get("warnings").and_then(Value::as_array);
+
+    single
+        .chain(many.into_iter().flatten())
+        .filter_map(|warning| match warning {
+            Value::String(text) => Some(text.as_str()),
+            warning => warning.get("message")?.as_str(),
+        })
+        .filter(|text| !text.trim().is_empty())
+        .map(|text| warning(model, text.trim()))
+        .collect()
+}
+
+/// A warning with `text`, which is about deprecation if it says so
+fn warning(model: &str, text: &str) -> Notice {
+    let kind = if text.to_lowercase().contains("deprecat") {
+        NoticeKind::Deprecation
+    } else {
+        NoticeKind::Warning
+    };
+
+    Notice::new(model, kind, text)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// When each notice was last shown, kept in a JSON file
+#[derive(Debug, Clone)]
+pub struct NoticeLog {
+    path: PathBuf,
+}
+
+impl NoticeLog {
+    /// Opens the log stored next to the given config file
+    pub fn for_config_file(config_file_path: &str) -> Self {
+        let path = Path::new(config_file_path)
+            .parent()
+            .expect("Config file path should have a parent directory")
+            .join(NOTICE_LOG_FILE_NAME);
+
+        Self::new(path)
+    }
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Seconds since the Unix epoch when each notice was last shown, by
+    /// key
+    fn read(&self) -> BTreeMap<String, u64> {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether `notice` wasn't shown in the last [`NOTICE_INTERVAL`] before
+    /// `now`, in which case it is recorded as shown at `now`
+    pub fn take_due(&self, notice: &Notice, now: u64) -> io::Result<bool> {
+        let mut shown = self.read();
+        let key = notice.key();
+        if shown.get(&key).is_some_and(|&last| {
+            now.saturating_sub(last) < NOTICE_INTERVAL.as_secs()
+        }) {
+            return Ok(false);
+        }
+
+        shown.insert(key, now);
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&shown)?)?;
+
+        Ok(true)
+    }
+
+    /// Prints `notice` to stderr, unless it was shown in the last
+    /// [`NOTICE_INTERVAL`]
+    pub fn show(&self, notice: &Notice) {
+        info!("{notice}");
+
+        match self.take_due(notice, now()) {
+            Ok(true) => eprintln!("{notice}"),
+            Ok(false) => {}
+            Err(e) => {
+                warn!("Failed to update {}: {e}", self.path.display());
+                eprintln!("{notice}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "sunset",
+            HeaderValue::from_static("Wed, 11 Nov 2026 23:59:59 GMT"),
+        );
+        headers.insert("deprecation", HeaderValue::from_static("@1780000000"));
+        headers.insert("x-ratelimit-limit-requests", 1000.into());
+        headers.insert("x-ratelimit-remaining-requests", 20.into());
+        headers.insert(
+            "x-ratelimit-reset-requests",
+            HeaderValue::from_static("6m0s"),
+        );
+        headers.insert("x-ratelimit-limit-tokens", 1000.into());
+        headers.insert("x-ratelimit-remaining-tokens", 500.into());
+
+        assert_eq!(
+            from_headers("gpt-old", &headers),
+            [
+                Notice::new(
+                    "gpt-old",
+                    NoticeKind::Deprecation,
+                    "the model is deprecated and will be retired on \
+                     Wed, 11 Nov 2026 23:59:59 GMT"
+                ),
+                Notice::new(
+                    "gpt-old",
+                    NoticeKind::RateLimit,
+                    "the rate limit is nearly used up: 20 of 1000 requests \
+                     left, resetting in 6m0s"
+                ),
+            ]
+        );
+        assert!(from_headers("gpt-old", &HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_from_warning_header_and_chunk() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "warning",
+            HeaderValue::from_static(r#"299 - "Deprecated model""#),
+        );
+        assert_eq!(
+            from_headers("m", &headers),
+            [Notice::new("m", NoticeKind::Deprecation, "Deprecated model")]
+        );
+
+        let chunk = json!({
+            "choices": [],
+            "warning": "Slow down",
+            "warnings": [{ "message": "This model is deprecated" }, ""],
+        });
+        assert_eq!(
+            from_chunk("m", &chunk),
+            [
+                Notice::new("m", NoticeKind::Warning, "Slow down"),
+                Notice::new(
+                    "m",
+                    NoticeKind::Deprecation,
+                    "This model is deprecated"
+                ),
+            ]
+        );
+        assert!(from_chunk("m", &json!({ "choices": [] })).is_empty());
+    }
+
+    #[test]
+    fn test_notices_are_shown_once_per_interval() {
+        let log =
+            NoticeLog::new(std::env::temp_dir().join(format!(
+                "aido-notices-test-{}.json",
+                std::process::id()
+            )));
+        let deprecated = Notice::new("m", NoticeKind::Deprecation, "old");
+        let limited = Notice::new("m", NoticeKind::RateLimit, "slow");
+        let day = NOTICE_INTERVAL.as_secs();
+
+        assert!(log.take_due(&deprecated, 1000).unwrap());
+        assert!(!log.take_due(&deprecated, 1000 + day - 1).unwrap());
+        assert!(log.take_due(&limited, 1000 + day - 1).unwrap());
+        assert!(log.take_due(&deprecated, 1000 + day).unwrap());
+        assert!(
+            log.take_due(&Notice::new("n", NoticeKind::Deprecation, "old"), 0)
+                .unwrap()
+        );
+
+        std::fs::remove_file(log.path()).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! Export of run traces to an OpenTelemetry collector
+//!
+//! When aido runs inside larger automation its runs can show up in the
+//! tracing stack already watching that automation. With the `otel` feature
+//! and an `[otel]` table in the config, the [`Trace`] of every run is sent
+//! once the run is over to an OTLP/HTTP collector as JSON: a span for the
+//! run, with a child span for each model reply, carrying the model and the
+//! tokens used, and each tool call. Tool arguments are left out, as they
+//! may hold anything the model read.
+//!
+//! When the `TRACEPARENT` environment variable holds a W3C trace context,
+//! as set by tools like `otel-cli`, the run joins that trace as a child of
+//! the span it names.
+//!
+//! Exporting never fails a run; a collector that can't be reached is only
+//! warned about.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::trace::Trace;
+
+/// The `[otel]` table of the config
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OtelConfig {
+    /// Base URL of the collector's OTLP/HTTP receiver, such as
+    /// `http://localhost:4318`; spans are posted to `/v1/traces` under it
+    pub endpoint: String,
+    /// Extra HTTP headers sent with every export, e.g. for authentication
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Sends the spans of a finished run, which answered with `model` and
+/// failed with `error` if it did, to the collector
+#[cfg(feature = "otel")]
+pub async fn export(
+    config: &OtelConfig,
+    trace: &Trace,
+    model: &str,
+    error: Option<&str>,
+) {
+    let parent = std::env::var("TRACEPARENT")
+        .ok()
+        .and_then(|value| spans::parse_traceparent(&value));
+    let spans = spans::spans(trace, model, error, parent.as_ref());
+
+    if let Err(e) = spans::send(config, spans).await {
+        log::warn!("Could not export the trace to {}: {e}", config.endpoint);
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+#[expect(clippy::unused_async, reason = "matches the otel feature's export")]
+pub async fn export(
+    _config: &OtelConfig,
+    _trace: &Trace,
+    _model: &str,
+    _error: Option<&str>,
+) {
+    log::warn!(
+        "[otel] is configured, but aido was built without the otel feature"
+    );
+}
+
+#[cfg(feature = "otel")]
+mod spans {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use opentelemetry::InstrumentationScope;
+    use opentelemetry::trace::{
+        Event, SpanContext, SpanId, SpanKind, Status, TraceContextExt,
+        TraceFlags, TraceState,
+    };
+    use opentelemetry::{KeyValue, propagation::TextMapPropagator};
+    use opentelemetry_otlp::{Protocol, WithExportConfig, WithHttpConfig};
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::{
+        IdGenerator, RandomIdGenerator, SpanData, SpanEvents, SpanExporter,
+        SpanLinks,
+    };
+
+    use super::OtelConfig;
+    use crate::trace::{Trace, TraceEventKind};
+
+    /// How long the collector gets to accept the spans
+    const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Path of the OTLP/HTTP receiver for traces
+    const TRACES_PATH: &str = "/v1/traces";
+
+    /// Reads a W3C `traceparent` value, `00-<trace id>-<span id>-<flags>`,
+    /// into the span a run's span is a child of
+    pub fn parse_traceparent(value: &str) -> Option<SpanContext> {
+        let carrier = HashMap::from([(
+            "traceparent".to_owned(),
+            value.trim().to_owned(),
+        )]);
+        let context = TraceContextPropagator::new().extract(&carrier);
+        let parent = context.span().span_context().clone();
+        parent.is_valid().then_some(parent)
+    }
+
+    /// The spans of `trace`: one for the run, under `parent` if there is
+    /// one, with a child for each model reply and tool call
+    pub fn spans(
+        trace: &Trace,
+        model: &str,
+        error: Option<&str>,
+        parent: Option<&SpanContext>,
+    ) -> Vec<SpanData> {
+        let ids = RandomIdGenerator::default();
+        let trace_id =
+            parent.map_or_else(|| ids.new_trace_id(), SpanContext::trace_id);
+        let context = |span_id| {
+            SpanContext::new(
+                trace_id,
+                span_id,
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            )
+        };
+
+        let start = trace.started_at();
+        let run_id = ids.new_span_id();
+        let mut run = span(
+            context(run_id),
+            parent.map_or(SpanId::INVALID, SpanContext::span_id),
+            "aido run".into(),
+            SpanKind::Internal,
+            (start, start + trace.elapsed()),
+            vec![KeyValue::new("gen_ai.request.model", model.to_owned())],
+        );
+        run.parent_span_is_remote = parent.is_some();
+        if let Some(error) = error {
+            run.status = Status::error(error.to_owned());
+        }
+
+        let mut spans = Vec::new();
+        for event in trace.events() {
+            let started = start + event.started_at;
+            let ended = started + event.duration;
+            let (name, kind, attributes, failed) = match &event.kind {
+                TraceEventKind::ModelReply {
+                    model,
+                    prompt_tokens,
+                    completion_tokens,
+                    ..
+                } => (
+                    format!("chat {model}"),
+                    SpanKind::Client,
+                    vec![
+                        KeyValue::new("gen_ai.operation.name", "chat"),
+                        KeyValue::new("gen_ai.request.model", model.clone()),
+                        KeyValue::new(
+                            "gen_ai.usage.input_tokens",
+                            i64::from(*prompt_tokens),
+                        ),
+                        KeyValue::new(
+                            "gen_ai.usage.output_tokens",
+                            i64::from(*completion_tokens),
+                        ),
+                    ],
+                    false,
+                ),
+                TraceEventKind::ToolCall { name, failed, .. } => (
+                    format!("execute_tool {name}"),
+                    SpanKind::Internal,
+                    vec![
+                        KeyValue::new("gen_ai.operation.name", "execute_tool"),
+                        KeyValue::new("gen_ai.tool.name", name.clone()),
+                    ],
+                    *failed,
+                ),
+                TraceEventKind::ExamplesPruned { .. } => {
+                    run.events.events.push(Event::new(
+                        event.kind.to_string(),
+                        started,
+                        Vec::new(),
+                        0,
+                    ));
+                    continue;
+                }
+            };
+
+            let mut span = span(
+                context(ids.new_span_id()),
+                run_id,
+                name.into(),
+                kind,
+                (started, ended),
+                attributes,
+            );
+            if failed {
+                span.status = Status::error("");
+            }
+            spans.push(span);
+        }
+        spans.insert(0, run);
+        spans
+    }
+
+    fn span(
+        context: SpanContext,
+        parent_span_id: SpanId,
+        name: Cow<'static, str>,
+        span_kind: SpanKind,
+        (start_time, end_time): (std::time::SystemTime, std::time::SystemTime),
+        attributes: Vec<KeyValue>,
+    ) -> SpanData {
+        SpanData {
+            span_context: context,
+            parent_span_id,
+            parent_span_is_remote: false,
+            span_kind,
+            name,
+            start_time,
+            end_time,
+            attributes,
+            dropped_attributes_count: 0,
+            events: SpanEvents::default(),
+            links: SpanLinks::default(),
+            status: Status::Unset,
+            instrumentation_scope: InstrumentationScope::builder("aido")
+                .build(),
+        }
+    }
+
+    /// Posts `spans` to the collector as OTLP/HTTP JSON
+    pub async fn send(
+        config: &OtelConfig,
+        spans: Vec<SpanData>,
+    ) -> Result<(), String> {
+        let endpoint = config.endpoint.trim_end_matches('/');
+        let url = if endpoint.ends_with(TRACES_PATH) {
+            endpoint.to_owned()
+        } else {
+            format!("{endpoint}{TRACES_PATH}")
+        };
+
+        let mut exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(url)
+            .with_timeout(EXPORT_TIMEOUT)
+            .with_headers(config.headers.clone().into_iter().collect())
+            .build()
+            .map_err(|e| e.to_string())?;
+        exporter.set_resource(
+            &Resource::builder_empty()
+                .with_service_name("aido")
+                .with_attributes([KeyValue::new(
+                    "service.version",
+                    env!("CARGO_PKG_VERSION"),
+                )])
+                .build(),
+        );
+        exporter.export(spans).await.map_err(|e| e.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::time::Instant;
+
+        use super::*;
+
+        #[test]
+        fn test_parse_traceparent() {
+            let parent = parse_traceparent(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .unwrap();
+            assert_eq!(
+                parent.trace_id().to_string(),
+                "0af7651916cd43dd8448eb211c80319c"
+            );
+            assert_eq!(parent.span_id().to_string(), "b7ad6b7169203331");
+            for invalid in [
+                "",
+                "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+                "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+                "00-0af7651916cd43dd8448eb211c80319c-zzad6b7169203331-01",
+            ] {
+                assert_eq!(parse_traceparent(invalid), None, "{invalid}");
+            }
+        }
+
+        #[test]
+        fn test_spans() {
+            let mut trace = Trace::start();
+            trace.record(
+                Instant::now(),
+                TraceEventKind::ModelReply {
+                    model: "gpt-test".to_owned(),
+                    prompt_tokens: 120,
+                    completion_tokens: 30,
+                    tool_calls: 1,
+                },
+            );
+            trace.record(
+                Instant::now(),
+                TraceEventKind::ToolCall {
+                    name: "ls".to_owned(),
+                    arguments: "{\"path\":\"secret\"}".to_owned(),
+                    failed: true,
+                },
+            );
+            trace.record(
+                Instant::now(),
+                TraceEventKind::ExamplesPruned { dropped: 1, total: 3 },
+            );
+            let parent = parse_traceparent(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .unwrap();
+
+            let spans = spans(&trace, "gpt-test", Some("boom"), Some(&parent));
+            assert_eq!(spans.len(), 3);
+
+            let run = &spans[0];
+            assert_eq!(run.name, "aido run");
+            assert_eq!(run.span_context.trace_id(), parent.trace_id());
+            assert_eq!(run.parent_span_id, parent.span_id());
+            assert!(run.parent_span_is_remote);
+            assert_eq!(run.status, Status::error("boom"));
+            assert_eq!(
+                run.events[0].name,
+                "left out 1 of 3 recipe examples to fit the context limit"
+            );
+
+            let chat = &spans[1];
+            assert_eq!(chat.name, "chat gpt-test");
+            assert_eq!(chat.span_kind, SpanKind::Client);
+            assert_eq!(chat.parent_span_id, run.span_context.span_id());
+            assert_eq!(
+                chat.attributes[2],
+                KeyValue::new("gen_ai.usage.input_tokens", 120)
+            );
+            assert_eq!(
+                chat.attributes[3],
+                KeyValue::new("gen_ai.usage.output_tokens", 30)
+            );
+
+            let tool = &spans[2];
+            assert_eq!(tool.name, "execute_tool ls");
+            assert!(matches!(tool.status, Status::Error { .. }));
+            assert!(!format!("{tool:?}").contains("secret"));
+        }
+
+        #[test]
+        fn test_spans_without_parent() {
+            let spans = spans(&Trace::start(), "m", None, None);
+            let run = &spans[0];
+            assert_eq!(run.parent_span_id, SpanId::INVALID);
+            assert!(!run.parent_span_is_remote);
+            assert_eq!(run.status, Status::Unset);
+        }
+    }
+}
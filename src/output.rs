@@ -0,0 +1,417 @@
+//! Formatting of the result of a run for consumption by other programs
+//!
+//! In the default text format the model's reply is streamed to the terminal
+//! as it arrives. In the JSON format nothing is printed while the run is in
+//! progress; once it finishes a single JSON document describing the outcome
+//! is written instead, so aido can be used in scripts and pipelines. The
+//! bare format prints just the answer, stripped of any chatter around it,
+//! and the command format just the shell command it suggests, for shell
+//! key bindings to put on the command line.
+//!
+//! Whatever the format, an [`OutputFile`] can keep the answer, or the whole
+//! transcript, on disk as well.
+//!
+//! The JSON document is described by the JSON Schema [`json_schema`]
+//! returns, which `aido schema` prints. Its `schema_version` is
+//! [`SCHEMA_VERSION`], which changes whenever a field is added, renamed,
+//! removed or changes type, so scripts can check they know the document
+//! they read.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::llm::Usage;
+use crate::run::RunOutcome;
+use crate::trace::{TraceEvent, TraceEventKind};
+
+/// How the result of a run is presented
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Stream the reply to the terminal as it is generated
+    #[default]
+    Text,
+    /// Print one JSON document once the run has finished
+    Json,
+    /// Print only the answer, without the model's introduction and closing
+    /// remarks, once the run has finished
+    Bare,
+    /// Print only the shell command the answer suggests, as `--exec` would
+    /// run it, failing when there is none
+    Command,
+}
+
+impl OutputFormat {
+    /// Whether the reply should be printed while it is being generated
+    pub fn streams(self) -> bool {
+        self == Self::Text
+    }
+}
+
+/// A file the result of runs is written to, on top of stdout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputFile {
+    path: PathBuf,
+    /// Whether everything streamed during runs is written, rather than only
+    /// the final answer
+    transcript: bool,
+}
+
+impl OutputFile {
+    /// Writes the final answer of each run to `path`, replacing the answer
+    /// of the run before
+    pub fn answer(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), transcript: false }
+    }
+
+    /// Appends everything streamed during runs to `path`, tool call notes
+    /// and replies before the final one included
+    pub fn transcript(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), transcript: true }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub const fn is_transcript(&self) -> bool {
+        self.transcript
+    }
+
+    /// Creates the file, or empties it if it exists
+    pub fn truncate(&self) -> io::Result<()> {
+        File::create(&self.path).map(drop)
+    }
+
+    /// Opens the file for appending to the transcript
+    pub fn append(&self) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(&self.path)
+    }
+
+    /// Replaces the content of the file with `answer`
+    pub fn write_answer(&self, answer: &str) -> io::Result<()> {
+        std::fs::write(&self.path, format!("{answer}\n"))
+    }
+}
+
+/// Version of the JSON document describing a finished run
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The JSON document describing a finished run
+#[derive(Debug, Serialize)]
+struct JsonOutcome<'a> {
+    schema_version: u32,
+    text: &'a str,
+    model: &'a str,
+    usage: JsonUsage,
+    cost: Option<f64>,
+    cancelled: bool,
+    trace: Vec<JsonTraceEvent<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+#[allow(clippy::struct_field_names)] // Matches the names used by the API
+struct JsonUsage {
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    total_tokens: u32,
+    reasoning_tokens: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum JsonTraceEventKind<'a> {
+    ModelReply { completion_tokens: u32, tool_calls: usize },
+    ToolCall { name: &'a str, arguments: &'a str, failed: bool },
+    ExamplesPruned { dropped: usize, total: usize },
+}
+
+#[derive(Debug, Serialize)]
+struct JsonTraceEvent<'a> {
+    started_at_ms: u64,
+    duration_ms: u64,
+    #[serde(flatten)]
+    kind: JsonTraceEventKind<'a>,
+}
+
+impl From<&Usage> for JsonUsage {
+    fn from(usage: &Usage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens(),
+            completion_tokens: usage.completion_tokens(),
+            total_tokens: usage.total_tokens(),
+            reasoning_tokens: usage.reasoning_tokens(),
+        }
+    }
+}
+
+impl<'a> From<&'a TraceEvent> for JsonTraceEvent<'a> {
+    fn from(event: &'a TraceEvent) -> Self {
+        let kind = match &event.kind {
+            TraceEventKind::ModelReply {
+                completion_tokens,
+                tool_calls,
+                ..
+            } => JsonTraceEventKind::ModelReply {
+                completion_tokens: *completion_tokens,
+                tool_calls: *tool_calls,
+            },
+            TraceEventKind::ToolCall { name, arguments, failed } => {
+                JsonTraceEventKind::ToolCall {
+                    name,
+                    arguments,
+                    failed: *failed,
+                }
+            }
+            TraceEventKind::ExamplesPruned { dropped, total } => {
+                JsonTraceEventKind::ExamplesPruned {
+                    dropped: *dropped,
+                    total: *total,
+                }
+            }
+        };
+
+        Self {
+            started_at_ms: millis(event.started_at),
+            duration_ms: millis(event.duration),
+            kind,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Writes the outcome of a run as a single line of JSON
+pub fn write_json(
+    mut writer: impl Write,
+    outcome: &RunOutcome,
+) -> std::io::Result<()> {
+    serde_json::to_writer(&mut writer, &document(outcome))?;
+    writeln!(writer)
+}
+
+/// The document [`write_json`] writes, as a value
+pub fn to_json(outcome: &RunOutcome) -> serde_json::Result<Value> {
+    serde_json::to_value(document(outcome))
+}
+
+fn document(outcome: &RunOutcome) -> JsonOutcome<'_> {
+    JsonOutcome {
+        schema_version: SCHEMA_VERSION,
+        text: &outcome.text,
+        model: &outcome.model,
+        usage: JsonUsage::from(&outcome.usage),
+        cost: outcome.cost,
+        cancelled: outcome.cancelled,
+        trace: outcome.trace.events().iter().map(Into::into).collect(),
+    }
+}
+
+/// The JSON Schema of the document [`write_json`] writes
+pub fn json_schema() -> Value {
+    let count = json!({ "type": "integer", "minimum": 0 });
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "aido run",
+        "description": "The outcome of a run, as printed with `--output json`",
+        "type": "object",
+        "properties": {
+            "schema_version": {
+                "description": "Version of this schema",
+                "const": SCHEMA_VERSION,
+            },
+            "text": {
+                "description": "The final answer of the model",
+                "type": "string",
+            },
+            "model": {
+                "description": "The model that gave the answer",
+                "type": "string",
+            },
+            "usage": {
+                "description": "Tokens used by every request of the run",
+                "type": "object",
+                "properties": {
+                    "prompt_tokens": count,
+                    "completion_tokens": count,
+                    "total_tokens": count,
+                    "reasoning_tokens": count,
+                },
+                "required": [
+                    "prompt_tokens",
+                    "completion_tokens",
+                    "total_tokens",
+                    "reasoning_tokens",
+                ],
+                "additionalProperties": false,
+            },
+            "cost": {
+                "description": "Estimated cost in dollars, or null when the \
+                                model has no price configured",
+                "type": ["number", "null"],
+            },
+            "cancelled": {
+                "description": "Whether the run was stopped with Ctrl-C, \
+                                leaving `text` incomplete",
+                "type": "boolean",
+            },
+            "trace": {
+                "description": "Every model reply and tool call of the run, \
+                                in order",
+                "type": "array",
+                "items": { "oneOf": [
+                    trace_event_schema("model_reply", &json!({
+                        "completion_tokens": count,
+                        "tool_calls": count,
+                    })),
+                    trace_event_schema("tool_call", &json!({
+                        "name": { "type": "string" },
+                        "arguments": {
+                            "description": "The arguments as the model \
+                                            sent them, as a JSON string",
+                            "type": "string",
+                        },
+                        "failed": { "type": "boolean" },
+                    })),
+                    trace_event_schema("examples_pruned", &json!({
+                        "dropped": count,
+                        "total": count,
+                    })),
+                ] },
+            },
+        },
+        "required": [
+            "schema_version",
+            "text",
+            "model",
+            "usage",
+            "cost",
+            "cancelled",
+            "trace",
+        ],
+        "additionalProperties": false,
+    })
+}
+
+/// The schema of trace events of `kind`, with the fields `properties`
+/// along with the timing every event has
+fn trace_event_schema(kind: &str, properties: &Value) -> Value {
+    let count = json!({ "type": "integer", "minimum": 0 });
+    let mut all = json!({
+        "kind": { "const": kind },
+        "started_at_ms": count,
+        "duration_ms": count,
+    });
+    let mut required = vec!["kind", "started_at_ms", "duration_ms"];
+
+    if let (Some(all), Some(properties)) =
+        (all.as_object_mut(), properties.as_object())
+    {
+        for (name, schema) in properties {
+            all.insert(name.clone(), schema.clone());
+            required.push(name);
+        }
+    }
+
+    json!({
+        "type": "object",
+        "properties": all,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::trace::Trace;
+
+    #[test]
+    fn test_write_json() {
+        let mut trace = Trace::start();
+        trace.record(
+            Instant::now(),
+            TraceEventKind::ModelReply {
+                model: "gpt-4o".to_string(),
+                prompt_tokens: 20,
+                completion_tokens: 5,
+                tool_calls: 1,
+            },
+        );
+        trace.record(
+            Instant::now(),
+            TraceEventKind::ToolCall {
+                name: "ls".to_string(),
+                arguments: r#"{"args": "-al"}"#.to_string(),
+                failed: false,
+            },
+        );
+        trace.record(
+            Instant::now(),
+            TraceEventKind::ExamplesPruned { dropped: 1, total: 3 },
+        );
+        let outcome = RunOutcome {
+            text: "done".to_string(),
+            model: "gpt-4o".to_string(),
+            usage: Usage::new(10, 5, 15),
+            cost: Some(0.25),
+            trace,
+            ..RunOutcome::default()
+        };
+
+        let mut buffer = Vec::new();
+        write_json(&mut buffer, &outcome).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+
+        assert_eq!(json["text"], "done");
+        assert_eq!(json["model"], "gpt-4o");
+        assert_eq!(json["usage"]["total_tokens"], 15);
+        assert_eq!(json["cost"], 0.25);
+        assert_eq!(json["cancelled"], false);
+        assert_eq!(json["trace"][0]["kind"], "model_reply");
+        assert_eq!(json["trace"][1]["kind"], "tool_call");
+        assert_eq!(json["trace"][1]["name"], "ls");
+        assert!(json["trace"][1]["duration_ms"].is_u64());
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(
+            crate::schema::violations(&json_schema(), &json),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_output_file() {
+        let path = std::env::temp_dir()
+            .join(format!("aido-output-test-{}.txt", std::process::id()));
+
+        let answer = OutputFile::answer(&path);
+        answer.write_answer("first").unwrap();
+        answer.write_answer("second").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+
+        let transcript = OutputFile::transcript(&path);
+        transcript.truncate().unwrap();
+        transcript.append().unwrap().write_all(b"one ").unwrap();
+        transcript.append().unwrap().write_all(b"two").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one two");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_output_format_streams() {
+        assert!(OutputFormat::Text.streams());
+        assert!(!OutputFormat::Json.streams());
+        assert!(!OutputFormat::Bare.streams());
+    }
+}
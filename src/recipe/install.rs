@@ -0,0 +1,793 @@
+//! Installing recipes shared by others
+//!
+//! `aido recipe install` fetches recipes into the recipes directory from a
+//! URL or a git repository:
+//!
+//! - a git URL, such as `git@host:team/recipes.git`, `ssh://...`, one
+//!   ending in `.git` or one prefixed with `git+`, is cloned and every
+//!   `.recipe` and `.aidorecipe` file in it is installed
+//! - any other http(s) URL is downloaded as a single file
+//!
+//! Packages, the `.aidorecipe` files written by `aido recipe package`, are
+//! installed under the name in their metadata once their signature and
+//! the aido version they need are checked; recipe files are installed
+//! under their file name. Either way the recipe has to parse strictly.
+//!
+//! Where each recipe came from is recorded in `installed.json` in the
+//! recipes directory, so `aido recipe update` can fetch it again. Updates
+//! leave recipes that were edited since they were installed alone, as
+//! well as packages that are now signed with another key, unless forced.
+//!
+//! The hooks of an installed recipe are shell commands someone else wrote,
+//! so they only run once the user has trusted them, when asked before the
+//! first run or with `aido recipe trust`. The trust is recorded next to
+//! the provenance, and lapses when the hooks change.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::package::{PACKAGE_EXTENSION, PackageError, RecipePackage};
+use super::{Recipe, RecipeStore};
+
+/// Name of the file recording where installed recipes came from, inside
+/// the recipes directory
+const INSTALLED_FILE_NAME: &str = "installed.json";
+
+/// Extension of recipe files
+const RECIPE_EXTENSION: &str = "recipe";
+
+/// How long a download may take
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum InstallError {
+    #[error("'{location}' is neither an http(s) URL nor a git repository URL")]
+    UnsupportedSource { location: String },
+
+    #[error("Could not download {url}: {reason}")]
+    Download { url: String, reason: String },
+
+    #[error("git {command} failed: {stderr}")]
+    Git { command: String, stderr: String },
+
+    #[error("{location} has no recipes")]
+    NoRecipes { location: String },
+
+    #[error("'{name}' is not a valid recipe name")]
+    InvalidName { name: String },
+
+    #[error("Recipe '{name}' from {location} is invalid: {reason}")]
+    InvalidRecipe { name: String, location: String, reason: String },
+
+    #[error(
+        "Recipe '{name}' already exists and wasn't installed from \
+         {location}; pass --force to replace it"
+    )]
+    Exists { name: String, location: String },
+
+    #[error("Recipe '{name}' wasn't installed with `aido recipe install`")]
+    NotInstalled { name: String },
+
+    #[error("Recipe '{name}' has no hooks to trust")]
+    NoHooks { name: String },
+
+    #[error(transparent)]
+    Package(#[from] PackageError),
+
+    #[error("Invalid {INSTALLED_FILE_NAME}: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Where recipes are fetched from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A git repository, by its URL as `git clone` takes it
+    Git(String),
+    /// A single file at an http(s) URL
+    Url(String),
+}
+
+impl Source {
+    /// The source at `location`, which is a git repository if it looks
+    /// like one and a file to download otherwise
+    pub fn parse(location: &str) -> Result<Self, InstallError> {
+        if let Some(url) = location.strip_prefix("git+") {
+            return Ok(Self::Git(url.to_owned()));
+        }
+        if ["git@", "git://", "ssh://"]
+            .iter()
+            .any(|prefix| location.starts_with(prefix))
+            || Path::new(location.trim_end_matches('/'))
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("git"))
+        {
+            return Ok(Self::Git(location.to_owned()));
+        }
+        if location.starts_with("https://") || location.starts_with("http://")
+        {
+            return Ok(Self::Url(location.to_owned()));
+        }
+
+        Err(InstallError::UnsupportedSource { location: location.to_owned() })
+    }
+}
+
+/// Where an installed recipe came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The URL given to `aido recipe install`
+    pub source: String,
+    /// Path of the recipe file inside the repository, for git sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Commit the recipe was installed from, for git sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Version of the package the recipe was installed from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Key the package the recipe was installed from was signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// SHA-256 of the recipe file as installed, in hex
+    pub sha256: String,
+    /// Seconds since the Unix epoch when the recipe was installed
+    pub installed_at: u64,
+    /// Digest of the hooks the user trusted to run, see [`hooks_digest`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trusted_hooks: Option<String>,
+}
+
+/// A recipe fetched from a source, not installed yet
+#[derive(Debug, Clone)]
+pub struct Fetched {
+    pub name: String,
+    /// The recipe file
+    pub content: String,
+    path: Option<String>,
+    revision: Option<String>,
+    version: Option<String>,
+    public_key: Option<String>,
+}
+
+impl Fetched {
+    /// The recipe in the file named `file_name`, checking packages and
+    /// that the recipe parses
+    pub fn from_file(
+        file_name: &str,
+        content: &str,
+        location: &str,
+    ) -> Result<Self, InstallError> {
+        let invalid =
+            |name: &str, reason: String| InstallError::InvalidRecipe {
+                name: name.to_owned(),
+                location: location.to_owned(),
+                reason,
+            };
+
+        let is_package = Path::new(file_name)
+            .extension()
+            .is_some_and(|extension| extension == PACKAGE_EXTENSION);
+
+        let fetched = if is_package {
+            let package = RecipePackage::parse(content)?;
+            package.check_compatible()?;
+
+            Self {
+                name: package.metadata().name.clone(),
+                content: package.recipe().to_owned(),
+                path: None,
+                revision: None,
+                version: Some(package.metadata().version.clone()),
+                public_key: Some(package.public_key().to_owned()),
+            }
+        } else {
+            let name = file_name
+                .strip_suffix(&format!(".{RECIPE_EXTENSION}"))
+                .unwrap_or(file_name);
+            Recipe::parse_strict(content)
+                .map_err(|e| invalid(name, e.to_string()))?;
+
+            Self {
+                name: name.to_owned(),
+                content: content.to_owned(),
+                path: None,
+                revision: None,
+                version: None,
+                public_key: None,
+            }
+        };
+
+        if !is_valid_name(&fetched.name) {
+            return Err(InstallError::InvalidName { name: fetched.name });
+        }
+
+        Ok(fetched)
+    }
+}
+
+/// What installing or updating did with a recipe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Installed,
+    Updated,
+    UpToDate,
+    /// Left alone since it was edited after it was installed
+    ChangedLocally,
+    /// Left alone since its package is signed with another key now
+    KeyChanged,
+    /// Left alone since its source doesn't have it anymore
+    Gone,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Installed => "installed",
+            Self::Updated => "updated",
+            Self::UpToDate => "up to date",
+            Self::ChangedLocally => {
+                "changed since it was installed, left as is; --force \
+                 replaces it"
+            }
+            Self::KeyChanged => {
+                "signed with another key now, left as is; --force replaces \
+                 it"
+            }
+            Self::Gone => "no longer at its source, left as is",
+        })
+    }
+}
+
+/// The record of where the installed recipes came from
+#[derive(Debug, Clone)]
+pub struct Installed {
+    path: PathBuf,
+    recipes: BTreeMap<String, Provenance>,
+}
+
+impl Installed {
+    /// Reads the record of the recipes installed into `store`
+    pub fn load(store: &RecipeStore) -> Result<Self, InstallError> {
+        let path = store.dir().join(INSTALLED_FILE_NAME);
+        let recipes = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { path, recipes })
+    }
+
+    pub fn save(&self) -> Result<(), InstallError> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(
+            &self.path,
+            serde_json::to_string_pretty(&self.recipes)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Where the recipe named `name` came from, if it was installed
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Provenance> {
+        self.recipes.get(name)
+    }
+
+    /// Writes `fetched` from `location` to `store`, unless it would
+    /// replace something the user may want to keep and `force` isn't set
+    ///
+    /// Callers check first that a recipe of the same name from elsewhere
+    /// isn't there, see [`Self::check_conflicts`].
+    pub fn apply(
+        &mut self,
+        store: &RecipeStore,
+        fetched: Fetched,
+        location: &str,
+        force: bool,
+    ) -> Result<Outcome, InstallError> {
+        let file = recipe_file(store, &fetched.name);
+        let current = match std::fs::read_to_string(&file) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let outcome = match (&current, self.get(&fetched.name)) {
+            (None, _) => Outcome::Installed,
+            (Some(current), _) if *current == fetched.content => {
+                Outcome::UpToDate
+            }
+            (Some(current), Some(provenance)) if !force => {
+                if sha256(current) != provenance.sha256 {
+                    return Ok(Outcome::ChangedLocally);
+                }
+                if provenance.public_key.is_some()
+                    && provenance.public_key != fetched.public_key
+                {
+                    return Ok(Outcome::KeyChanged);
+                }
+                Outcome::Updated
+            }
+            (Some(_), _) => Outcome::Updated,
+        };
+
+        if outcome != Outcome::UpToDate {
+            std::fs::create_dir_all(store.dir())?;
+            std::fs::write(&file, &fetched.content)?;
+        }
+        // Kept, since it only matches as long as the hooks stay the same
+        let trusted_hooks = self
+            .get(&fetched.name)
+            .and_then(|provenance| provenance.trusted_hooks.clone());
+        self.recipes.insert(
+            fetched.name,
+            Provenance {
+                source: location.to_owned(),
+                path: fetched.path,
+                revision: fetched.revision,
+                version: fetched.version,
+                public_key: fetched.public_key,
+                sha256: sha256(&fetched.content),
+                installed_at: now(),
+                trusted_hooks,
+            },
+        );
+
+        Ok(outcome)
+    }
+
+    /// Whether the hooks of the recipe named `name`, with the digest
+    /// `digest`, need the user's trust to run: they do if the recipe was
+    /// installed and the user hasn't trusted these hooks yet
+    #[must_use]
+    pub fn needs_trust(&self, name: &str, digest: &str) -> bool {
+        self.get(name).is_some_and(|provenance| {
+            provenance.trusted_hooks.as_deref() != Some(digest)
+        })
+    }
+
+    /// Records that the user trusts the hooks with the digest `digest` of
+    /// the installed recipe `name`
+    pub fn trust(
+        &mut self,
+        name: &str,
+        digest: &str,
+    ) -> Result<(), InstallError> {
+        let provenance = self.recipes.get_mut(name).ok_or_else(|| {
+            InstallError::NotInstalled { name: name.to_owned() }
+        })?;
+        provenance.trusted_hooks = Some(digest.to_owned());
+
+        Ok(())
+    }
+
+    /// Fails if one of `fetched` would replace a recipe that wasn't
+    /// installed from `location`, unless `force` is set
+    pub fn check_conflicts(
+        &self,
+        store: &RecipeStore,
+        fetched: &[Fetched],
+        location: &str,
+        force: bool,
+    ) -> Result<(), InstallError> {
+        if force {
+            return Ok(());
+        }
+
+        for recipe in fetched {
+            let from_location = self
+                .get(&recipe.name)
+                .is_some_and(|provenance| provenance.source == location);
+            if !from_location && recipe_file(store, &recipe.name).exists() {
+                return Err(InstallError::Exists {
+                    name: recipe.name.clone(),
+                    location: location.to_owned(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Installs the recipes at `location` into `store`
+pub async fn install(
+    store: &RecipeStore,
+    location: &str,
+    force: bool,
+) -> Result<Vec<(String, Outcome)>, InstallError> {
+    let fetched = fetch(&Source::parse(location)?, location).await?;
+    if fetched.is_empty() {
+        return Err(InstallError::NoRecipes { location: location.to_owned() });
+    }
+
+    let mut installed = Installed::load(store)?;
+    installed.check_conflicts(store, &fetched, location, force)?;
+
+    let mut outcomes = Vec::new();
+    for recipe in fetched {
+        let name = recipe.name.clone();
+        outcomes
+            .push((name, installed.apply(store, recipe, location, force)?));
+    }
+    installed.save()?;
+
+    Ok(outcomes)
+}
+
+/// Fetches the recipes named `names`, or all installed ones, from where
+/// they were installed from again, and updates them
+pub async fn update(
+    store: &RecipeStore,
+    names: &[String],
+    force: bool,
+) -> Result<Vec<(String, Outcome)>, InstallError> {
+    let mut installed = Installed::load(store)?;
+
+    let mut by_source = BTreeMap::<String, Vec<String>>::new();
+    let names = if names.is_empty() {
+        installed.recipes.keys().cloned().collect()
+    } else {
+        names.to_vec()
+    };
+    for name in names {
+        let Some(provenance) = installed.get(&name) else {
+            return Err(InstallError::NotInstalled { name });
+        };
+        by_source.entry(provenance.source.clone()).or_default().push(name);
+    }
+
+    let mut outcomes = Vec::new();
+    for (location, names) in by_source {
+        let mut fetched = fetch(&Source::parse(&location)?, &location).await?;
+
+        for name in names {
+            let outcome = match fetched.iter().position(|f| f.name == name) {
+                Some(index) => installed.apply(
+                    store,
+                    fetched.swap_remove(index),
+                    &location,
+                    force,
+                )?,
+                None => Outcome::Gone,
+            };
+            outcomes.push((name, outcome));
+        }
+    }
+    installed.save()?;
+
+    Ok(outcomes)
+}
+
+/// Trusts the hooks of the installed recipe `name` in `store` as they are
+/// now, for `aido recipe trust`
+pub fn trust(store: &RecipeStore, name: &str) -> Result<(), InstallError> {
+    let mut installed = Installed::load(store)?;
+    if installed.get(name).is_none() {
+        return Err(InstallError::NotInstalled { name: name.to_owned() });
+    }
+
+    let invalid = |reason: String| InstallError::InvalidRecipe {
+        name: name.to_owned(),
+        location: store.dir().display().to_string(),
+        reason,
+    };
+    let content = store.content(name).map_err(|e| invalid(e.to_string()))?;
+    let recipe =
+        Recipe::parse(&content).map_err(|e| invalid(e.to_string()))?;
+    let digest = hooks_digest(&recipe)
+        .ok_or_else(|| InstallError::NoHooks { name: name.to_owned() })?;
+
+    installed.trust(name, &digest)?;
+    installed.save()
+}
+
+/// A digest of the hooks of `recipe`, if it has any, which changes with
+/// them
+#[must_use]
+pub fn hooks_digest(recipe: &Recipe) -> Option<String> {
+    let header = recipe.header();
+    if header.pre_hook().is_none() && header.post_hook().is_none() {
+        return None;
+    }
+
+    Some(sha256(&format!(
+        "{}\0{}",
+        header.pre_hook().unwrap_or_default(),
+        header.post_hook().unwrap_or_default()
+    )))
+}
+
+/// The recipes at `source`, given as `location`
+pub async fn fetch(
+    source: &Source,
+    location: &str,
+) -> Result<Vec<Fetched>, InstallError> {
+    match source {
+        Source::Url(url) => {
+            let content = download(url).await?;
+            let file_name = url_file_name(url).ok_or_else(|| {
+                InstallError::InvalidName { name: url.clone() }
+            })?;
+
+            Ok(vec![Fetched::from_file(&file_name, &content, location)?])
+        }
+        Source::Git(url) => {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.subsec_nanos());
+            let dir = std::env::temp_dir()
+                .join(format!("aido-install-{}-{nanos}", std::process::id()));
+
+            let fetched = clone_and_collect(url, &dir, location);
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir)?;
+            }
+
+            fetched
+        }
+    }
+}
+
+async fn download(url: &str) -> Result<String, InstallError> {
+    let failed = |e: reqwest::Error| InstallError::Download {
+        url: url.to_owned(),
+        reason: e.to_string(),
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(failed)?;
+
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(failed)?
+        .text()
+        .await
+        .map_err(failed)
+}
+
+/// Clones the repository at `url` into `dir` and reads the recipes in it
+fn clone_and_collect(
+    url: &str,
+    dir: &Path,
+    location: &str,
+) -> Result<Vec<Fetched>, InstallError> {
+    let dir_arg = dir.to_string_lossy();
+    git(&["clone", "--quiet", "--depth", "1", url, &dir_arg])?;
+    let revision = git(&["-C", &dir_arg, "rev-parse", "HEAD"])?;
+
+    let mut files = Vec::new();
+    collect_recipe_files(dir, &mut files)?;
+    files.sort();
+
+    files
+        .iter()
+        .map(|file| {
+            let file_name =
+                file.file_name().map(|n| n.to_string_lossy().into_owned());
+            let content = std::fs::read_to_string(file)?;
+            let mut fetched = Fetched::from_file(
+                &file_name.unwrap_or_default(),
+                &content,
+                location,
+            )?;
+            fetched.path = file
+                .strip_prefix(dir)
+                .ok()
+                .map(|path| path.to_string_lossy().into_owned());
+            fetched.revision = Some(revision.clone());
+
+            Ok(fetched)
+        })
+        .collect()
+}
+
+/// Adds the recipe and package files under `dir` to `files`, skipping
+/// hidden directories such as `.git`
+fn collect_recipe_files(
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                collect_recipe_files(&path, files)?;
+            }
+        } else if file_type.is_file()
+            && path.extension().is_some_and(|extension| {
+                extension == RECIPE_EXTENSION || extension == PACKAGE_EXTENSION
+            })
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs git with `args`, returning what it printed
+fn git(args: &[&str]) -> Result<String, InstallError> {
+    let output = Command::new("git").args(args).output().map_err(|e| {
+        InstallError::Git {
+            command: args[0].to_owned(),
+            stderr: e.to_string(),
+        }
+    })?;
+
+    if !output.status.success() {
+        return Err(InstallError::Git {
+            command: args[0].to_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// The last segment of the path of `url`, if it has one
+fn url_file_name(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let name = url.path_segments()?.rev().find(|s| !s.is_empty())?;
+
+    Some(name.to_owned())
+}
+
+/// Whether `name` can be the name of a recipe file in the recipes
+/// directory
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn recipe_file(store: &RecipeStore, name: &str) -> PathBuf {
+    store.dir().join(format!("{name}.{RECIPE_EXTENSION}"))
+}
+
+fn sha256(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECIPE: &str = "---\nname: Review\n---\nReview this.";
+
+    #[test]
+    fn test_source_parse() {
+        for git in [
+            "git@github.com:team/recipes.git",
+            "ssh://git@host/team/recipes",
+            "https://github.com/team/recipes.git",
+        ] {
+            assert_eq!(Source::parse(git).unwrap(), Source::Git(git.into()));
+        }
+        assert_eq!(
+            Source::parse("git+https://host/team/recipes").unwrap(),
+            Source::Git("https://host/team/recipes".into())
+        );
+        assert_eq!(
+            Source::parse("https://host/review.recipe").unwrap(),
+            Source::Url("https://host/review.recipe".into())
+        );
+        assert!(Source::parse("review.recipe").is_err());
+
+        assert_eq!(
+            url_file_name("https://host/r/review.recipe?raw=1").as_deref(),
+            Some("review.recipe")
+        );
+    }
+
+    #[test]
+    fn test_from_file() {
+        let fetched =
+            Fetched::from_file("review.recipe", RECIPE, "https://host")
+                .unwrap();
+        assert_eq!(fetched.name, "review");
+
+        assert!(matches!(
+            Fetched::from_file(
+                "bad.recipe",
+                "---\ntemperature: hot\n---\nHi",
+                "x"
+            ),
+            Err(InstallError::InvalidRecipe { .. })
+        ));
+        assert!(matches!(
+            Fetched::from_file("..recipe", RECIPE, "x"),
+            Err(InstallError::InvalidName { .. })
+        ));
+    }
+
+    #[test]
+    fn test_apply_and_conflicts() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-install-test-{}", std::process::id()));
+        let store = RecipeStore::new(dir.join("recipes"));
+        let source = "https://host/review.recipe";
+        let fetch = |content: &str| {
+            Fetched::from_file("review.recipe", content, source).unwrap()
+        };
+
+        let mut installed = Installed::load(&store).unwrap();
+        let outcome =
+            installed.apply(&store, fetch(RECIPE), source, false).unwrap();
+        assert_eq!(outcome, Outcome::Installed);
+        installed.save().unwrap();
+
+        let installed = Installed::load(&store).unwrap();
+        assert_eq!(installed.get("review").unwrap().source, source);
+        assert!(
+            installed
+                .check_conflicts(&store, &[fetch(RECIPE)], source, false)
+                .is_ok()
+        );
+        assert!(matches!(
+            installed.check_conflicts(
+                &store,
+                &[fetch(RECIPE)],
+                "https://elsewhere/review.recipe",
+                false
+            ),
+            Err(InstallError::Exists { .. })
+        ));
+
+        let newer = format!("{RECIPE}\nBe brief.");
+        let mut installed = installed;
+        assert_eq!(
+            installed.apply(&store, fetch(RECIPE), source, false).unwrap(),
+            Outcome::UpToDate
+        );
+        assert_eq!(
+            installed.apply(&store, fetch(&newer), source, false).unwrap(),
+            Outcome::Updated
+        );
+
+        std::fs::write(recipe_file(&store, "review"), "Edited").unwrap();
+        assert_eq!(
+            installed.apply(&store, fetch(RECIPE), source, false).unwrap(),
+            Outcome::ChangedLocally
+        );
+        assert_eq!(store.content("review").unwrap(), "Edited");
+        assert_eq!(
+            installed.apply(&store, fetch(RECIPE), source, true).unwrap(),
+            Outcome::Updated
+        );
+        assert_eq!(store.content("review").unwrap(), RECIPE);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,451 @@
+//! Packaging recipes for sharing
+//!
+//! `aido recipe package` checks a recipe and writes it to a single
+//! `.aidorecipe` file, together with metadata saying who wrote it, under
+//! which license, which version it is, which tools it expects and the
+//! oldest aido able to run it. The package is signed with the author's
+//! ed25519 key, created next to the config file on first use, so that
+//! whoever installs it can tell that it comes from the key they trust and
+//! wasn't changed on the way:
+//!
+//! ```text
+//! {
+//!   "format": 1,
+//!   "metadata": { "name": "review", "author": "Ada", ... },
+//!   "recipe": "---\nname: review\n...",
+//!   "public_key": "<base64>",
+//!   "signature": "<base64>"
+//! }
+//! ```
+//!
+//! The signature covers the format, the metadata and the recipe, as their
+//! compact JSON.
+
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{Recipe, RecipeError};
+
+/// Extension of recipe package files
+pub const PACKAGE_EXTENSION: &str = "aidorecipe";
+
+/// Name of the file holding the signing key, inside the config directory
+const KEY_FILE_NAME: &str = "recipe-signing.key";
+
+/// Version of the package layout written by this aido
+const FORMAT: u32 = 1;
+
+/// Matches versions such as `1.2.3` or `1.2.3-beta.1`
+static VERSION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\d+)\.(\d+)\.(\d+)([-+][0-9A-Za-z.+-]+)?$").unwrap()
+});
+
+#[derive(Error, Debug)]
+pub enum PackageError {
+    #[error(transparent)]
+    Recipe(#[from] RecipeError),
+
+    #[error("Invalid package metadata: {message}")]
+    InvalidMetadata { message: String },
+
+    #[error("Not a recipe package: {0}")]
+    Malformed(#[from] serde_json::Error),
+
+    #[error(
+        "Package format {format} is not supported; this aido reads format \
+         {FORMAT}"
+    )]
+    UnsupportedFormat { format: u32 },
+
+    #[error("The package signature does not match its content")]
+    BadSignature,
+
+    #[error("The recipe needs aido {required} or later; this is {current}")]
+    TooOld { required: String, current: String },
+
+    #[error("Invalid signing key in {}: {reason}", path.display())]
+    InvalidKey { path: PathBuf, reason: String },
+
+    #[error("Could not generate a signing key: {0}")]
+    Random(String),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// What a package says about the recipe in it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageMetadata {
+    /// Name the recipe is installed under
+    pub name: String,
+    pub author: String,
+    /// License of the recipe, preferably as an SPDX identifier
+    pub license: String,
+    /// Version of the recipe, such as `1.2.0`
+    pub version: String,
+    /// Tools the recipe lets the model use
+    #[serde(default)]
+    pub required_tools: Vec<String>,
+    /// Oldest aido version able to run the recipe
+    pub min_aido_version: String,
+}
+
+impl PackageMetadata {
+    /// Metadata for the recipe named `name`, needing this version of aido
+    /// and the tools the recipe allows
+    pub fn new(
+        name: impl Into<String>,
+        author: impl Into<String>,
+        license: impl Into<String>,
+        version: impl Into<String>,
+        recipe: &Recipe,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            author: author.into(),
+            license: license.into(),
+            version: version.into(),
+            required_tools: recipe.header().allowed_tools().to_vec(),
+            min_aido_version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+
+    /// Needs at least `version` of aido instead of this one
+    #[must_use]
+    pub fn with_min_aido_version(
+        mut self,
+        version: impl Into<String>,
+    ) -> Self {
+        self.min_aido_version = version.into();
+        self
+    }
+
+    fn check(&self) -> Result<(), PackageError> {
+        let invalid =
+            |message: String| PackageError::InvalidMetadata { message };
+
+        for (field, value) in [
+            ("name", &self.name),
+            ("author", &self.author),
+            ("license", &self.license),
+        ] {
+            if value.trim().is_empty() {
+                return Err(invalid(format!("the {field} is empty")));
+            }
+        }
+        for (field, value) in [
+            ("version", &self.version),
+            ("min_aido_version", &self.min_aido_version),
+        ] {
+            if !VERSION_REGEX.is_match(value) {
+                return Err(invalid(format!(
+                    "the {field} '{value}' is not of the form 1.2.3"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of a package covered by its signature
+#[derive(Serialize)]
+struct Signed<'a> {
+    format: u32,
+    metadata: &'a PackageMetadata,
+    recipe: &'a str,
+}
+
+/// A signed recipe with its metadata, as stored in a `.aidorecipe` file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipePackage {
+    format: u32,
+    metadata: PackageMetadata,
+    /// The recipe file, exactly as written
+    recipe: String,
+    /// The key of the signer, in base64
+    public_key: String,
+    /// Signature of the format, metadata and recipe, in base64
+    signature: String,
+}
+
+impl RecipePackage {
+    /// Checks the recipe and its metadata and signs them with `key`
+    pub fn create(
+        metadata: PackageMetadata,
+        recipe: &str,
+        key: &SigningKey,
+    ) -> Result<Self, PackageError> {
+        metadata.check()?;
+        Recipe::parse_strict(recipe)?;
+
+        let signature = key.sign(&signed_bytes(FORMAT, &metadata, recipe)?);
+
+        Ok(Self {
+            format: FORMAT,
+            metadata,
+            recipe: recipe.to_owned(),
+            public_key: BASE64.encode(key.verifying_key().as_bytes()),
+            signature: BASE64.encode(signature.to_bytes()),
+        })
+    }
+
+    /// Reads a package, checking that it is intact and that its recipe
+    /// parses
+    ///
+    /// This only shows that whoever holds [`public_key`](Self::public_key)
+    /// signed the package; whether that key is to be trusted is up to the
+    /// caller.
+    pub fn parse(content: &str) -> Result<Self, PackageError> {
+        let package = serde_json::from_str::<Self>(content)?;
+        if package.format != FORMAT {
+            return Err(PackageError::UnsupportedFormat {
+                format: package.format,
+            });
+        }
+
+        package.verify()?;
+        package.metadata.check()?;
+        Recipe::parse_strict(&package.recipe)?;
+
+        Ok(package)
+    }
+
+    fn verify(&self) -> Result<(), PackageError> {
+        let decode = |text: &str| BASE64.decode(text).ok();
+
+        let key = decode(&self.public_key)
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or(PackageError::BadSignature)?;
+        let signature = decode(&self.signature)
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(PackageError::BadSignature)?;
+
+        let signed = signed_bytes(self.format, &self.metadata, &self.recipe)?;
+        key.verify_strict(&signed, &signature)
+            .map_err(|_| PackageError::BadSignature)
+    }
+
+    /// Checks that this aido is recent enough to run the recipe
+    pub fn check_compatible(&self) -> Result<(), PackageError> {
+        let current = env!("CARGO_PKG_VERSION");
+        if version_core(current)
+            < version_core(&self.metadata.min_aido_version)
+        {
+            return Err(PackageError::TooOld {
+                required: self.metadata.min_aido_version.clone(),
+                current: current.to_owned(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The package as written to a `.aidorecipe` file
+    pub fn to_json(&self) -> Result<String, PackageError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    #[must_use]
+    pub fn metadata(&self) -> &PackageMetadata {
+        &self.metadata
+    }
+
+    /// The content of the recipe file
+    #[must_use]
+    pub fn recipe(&self) -> &str {
+        &self.recipe
+    }
+
+    /// The key the package was signed with, in base64
+    #[must_use]
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+}
+
+fn signed_bytes(
+    format: u32,
+    metadata: &PackageMetadata,
+    recipe: &str,
+) -> Result<Vec<u8>, PackageError> {
+    Ok(serde_json::to_vec(&Signed { format, metadata, recipe })?)
+}
+
+/// The major, minor and patch numbers of `version`
+fn version_core(version: &str) -> (u64, u64, u64) {
+    let number = |captures: &regex::Captures<'_>, index: usize| {
+        captures[index].parse().unwrap_or(u64::MAX)
+    };
+
+    VERSION_REGEX.captures(version).map_or((0, 0, 0), |captures| {
+        (number(&captures, 1), number(&captures, 2), number(&captures, 3))
+    })
+}
+
+/// Path of the signing key kept next to the given config file
+pub fn key_path_for_config_file(config_file_path: &str) -> PathBuf {
+    Path::new(config_file_path)
+        .parent()
+        .expect("Config file path should have a parent directory")
+        .join(KEY_FILE_NAME)
+}
+
+/// Loads the signing key stored at `path`, creating one if there is none
+pub fn load_or_create_key(path: &Path) -> Result<SigningKey, PackageError> {
+    let invalid = |reason: &str| PackageError::InvalidKey {
+        path: path.to_owned(),
+        reason: reason.to_owned(),
+    };
+
+    match std::fs::read_to_string(path) {
+        Ok(content) => {
+            let seed = BASE64
+                .decode(content.trim())
+                .map_err(|_| invalid("not base64"))?;
+            let seed = <[u8; 32]>::try_from(seed)
+                .map_err(|_| invalid("not 32 bytes long"))?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut seed = [0; 32];
+            getrandom::fill(&mut seed)
+                .map_err(|e| PackageError::Random(e.to_string()))?;
+            write_private(path, &BASE64.encode(seed))?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `content` to a new file at `path` only the user can read
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    writeln!(options.open(path)?, "{content}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECIPE: &str =
+        "---\nname: review\nallowed_tools: [git_diff]\n---\nReview this.";
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn metadata() -> PackageMetadata {
+        PackageMetadata::new(
+            "review",
+            "Ada",
+            "MIT",
+            "1.2.0",
+            &Recipe::parse(RECIPE).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_package_round_trip() {
+        let package =
+            RecipePackage::create(metadata(), RECIPE, &key()).unwrap();
+
+        let parsed =
+            RecipePackage::parse(&package.to_json().unwrap()).unwrap();
+
+        assert_eq!(parsed.recipe(), RECIPE);
+        assert_eq!(parsed.metadata().required_tools, ["git_diff"]);
+        assert_eq!(
+            parsed.public_key(),
+            BASE64.encode(key().verifying_key().as_bytes())
+        );
+        parsed.check_compatible().unwrap();
+    }
+
+    #[test]
+    fn test_tampered_package_is_rejected() {
+        let package =
+            RecipePackage::create(metadata(), RECIPE, &key()).unwrap();
+        let json = package.to_json().unwrap();
+
+        let tampered = json.replace("Review this.", "Delete everything.");
+        assert!(matches!(
+            RecipePackage::parse(&tampered),
+            Err(PackageError::BadSignature)
+        ));
+
+        let relicensed = json.replace("\"MIT\"", "\"GPL-3.0\"");
+        assert!(matches!(
+            RecipePackage::parse(&relicensed),
+            Err(PackageError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn test_create_checks_recipe_and_metadata() {
+        assert!(matches!(
+            RecipePackage::create(metadata(), "---\nname: [\n---\n", &key()),
+            Err(PackageError::Recipe(_))
+        ));
+
+        let mut bad_version = metadata();
+        bad_version.version = "latest".to_owned();
+        assert!(matches!(
+            RecipePackage::create(bad_version, RECIPE, &key()),
+            Err(PackageError::InvalidMetadata { .. })
+        ));
+
+        let mut no_license = metadata();
+        no_license.license = String::new();
+        assert!(RecipePackage::create(no_license, RECIPE, &key()).is_err());
+    }
+
+    #[test]
+    fn test_newer_aido_required() {
+        let metadata = metadata().with_min_aido_version("999.0.0");
+        let package = RecipePackage::create(metadata, RECIPE, &key()).unwrap();
+
+        assert!(matches!(
+            package.check_compatible(),
+            Err(PackageError::TooOld { .. })
+        ));
+        assert!(version_core("1.10.0") > version_core("1.9.3-beta"));
+    }
+
+    #[test]
+    fn test_key_is_created_once() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-package-test-{}", std::process::id()));
+        let path = dir.join(KEY_FILE_NAME);
+
+        let created = load_or_create_key(&path).unwrap();
+        let loaded = load_or_create_key(&path).unwrap();
+        assert_eq!(created.to_bytes(), loaded.to_bytes());
+
+        std::fs::write(&path, "short").unwrap();
+        assert!(matches!(
+            load_or_create_key(&path),
+            Err(PackageError::InvalidKey { .. })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+//! Rendering recipe bodies as templates
+//!
+//! A recipe body is a [minijinja] template, so besides substituting
+//! variables with `{{ name }}` it can use conditions, loops and filters:
+//!
+//! ```text
+//! Review the changes on {{ branch | default("main") }}.
+//! {% if strict %}
+//! Point out every style issue as well.
+//! {% endif %}
+//! ```
+//!
+//! Along with the recipe's variables, templates see `env`, a few
+//! environment variables that can't hold secrets (`{{ env.USER }}`, see
+//! [`ENV_NAMES`]), `os`, the operating system, and `cwd`, the current
+//! directory. Int and bool variables are numbers and booleans, so
+//! `{% if strict %}` is false for `--var strict=false`.
+//!
+//! A `{{ name }}` placeholder without a value stays in the prompt, as
+//! `{{name}}`, as it did before bodies were templates. Any other use of a
+//! variable without a value, such as `{{ name | upper }}`, is an error
+//! rather than empty text, except in conditions and with `default`.
+//!
+//! Text that only looks like template syntax, such as `${#array}` in a
+//! shell snippet, goes in a `{% raw %}` block. A body written before bodies
+//! were templates that isn't a valid template still has its placeholders
+//! filled in as before, with a warning, and `aido recipe validate` points
+//! it out.
+//!
+//! [minijinja]: https://docs.rs/minijinja
+
+use std::collections::{BTreeMap, HashSet};
+
+use log::warn;
+use minijinja::{Environment, UndefinedBehavior, Value};
+
+use super::{RecipeError, vars};
+
+/// Names templates see besides the recipe's variables
+pub const BUILTIN_NAMES: [&str; 3] = ["env", "os", "cwd"];
+
+/// The environment variables templates see in `env`; the others may hold
+/// credentials, and are passed to a recipe with `--var` instead
+pub const ENV_NAMES: &[&str] = &[
+    "CI", "EDITOR", "HOME", "LANG", "LOGNAME", "PWD", "SHELL", "TERM", "TZ",
+    "USER", "USERNAME", "VISUAL",
+];
+
+/// Renders `body` with the variables in `values`
+pub fn render(
+    body: &str,
+    mut values: BTreeMap<String, Value>,
+) -> Result<String, RecipeError> {
+    let env = environment();
+    let template = match env.template_from_named_str("recipe body", body) {
+        Ok(template) => template,
+        Err(e) => {
+            warn!(
+                "The recipe body isn't a valid template ({e}), so only its \
+                 {{{{ name }}}} placeholders are filled in; put text that \
+                 looks like template syntax in a {{% raw %}} block"
+            );
+            return Ok(vars::substitute(body, &values));
+        }
+    };
+
+    for name in unset_placeholders(body, &template.undeclared_variables(false))
+    {
+        values
+            .entry(name.to_owned())
+            .or_insert_with(|| Value::from(format!("{{{{{name}}}}}")));
+    }
+    // Variables of the recipe win over the built-in names
+    values.entry("env".to_owned()).or_insert_with(|| {
+        Value::from(
+            std::env::vars()
+                .filter(|(name, _)| ENV_NAMES.contains(&name.as_str()))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    });
+    values
+        .entry("os".to_owned())
+        .or_insert_with(|| Value::from(std::env::consts::OS));
+    values.entry("cwd".to_owned()).or_insert_with(|| {
+        Value::from(
+            std::env::current_dir()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or_default(),
+        )
+    });
+
+    Ok(template.render(values)?)
+}
+
+/// The variables `body` substitutes with a plain `{{ name }}` placeholder
+/// that aren't set by the template itself, such as loop variables, or
+/// provided to every template
+pub fn placeholders(body: &str) -> Result<Vec<&str>, RecipeError> {
+    let env = environment();
+    let template = env.template_from_named_str("recipe body", body)?;
+    Ok(unset_placeholders(body, &template.undeclared_variables(false)))
+}
+
+/// The plain placeholders in `body` whose names are in `undeclared`
+fn unset_placeholders<'a>(
+    body: &'a str,
+    undeclared: &HashSet<String>,
+) -> Vec<&'a str> {
+    let mut seen = HashSet::new();
+    vars::placeholders(body)
+        .filter(|name| undeclared.contains(*name))
+        .filter(|name| !BUILTIN_NAMES.contains(name))
+        .filter(|name| seen.insert(*name))
+        .collect()
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_keep_trailing_newline(true);
+    // A line holding just a `{% ... %}` tag leaves no blank line behind
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, Value)]) -> BTreeMap<String, Value> {
+        pairs
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_render_logic() {
+        let body = "Review.\n{% if strict %}\nBe strict.\n{% endif %}\n\
+                    {% for f in files %}- {{ f | upper }}\n{% endfor %}";
+        let files = Value::from(vec!["a.rs", "b.rs"]);
+
+        assert_eq!(
+            render(
+                body,
+                values(&[
+                    ("strict", Value::from(true)),
+                    ("files", files.clone())
+                ])
+            )
+            .unwrap(),
+            "Review.\nBe strict.\n- A.RS\n- B.RS\n"
+        );
+        assert_eq!(
+            render(
+                body,
+                values(&[("strict", Value::from(false)), ("files", files)])
+            )
+            .unwrap(),
+            "Review.\n- A.RS\n- B.RS\n"
+        );
+    }
+
+    #[test]
+    fn test_render_keeps_unset_placeholders() {
+        assert_eq!(
+            render("{{ topic }} {% if extra %}x{% endif %}", BTreeMap::new())
+                .unwrap(),
+            "{{topic}} "
+        );
+    }
+
+    #[test]
+    fn test_render_builtins() {
+        assert_eq!(
+            render("{{ os }}", BTreeMap::new()).unwrap(),
+            std::env::consts::OS
+        );
+        assert_eq!(
+            render("{% if env.PATH %}leaked{% endif %}", BTreeMap::new())
+                .unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_render_fails_on_other_unset_uses() {
+        let error =
+            render("{{ topic | upper }}", BTreeMap::new()).unwrap_err();
+        assert!(matches!(error, RecipeError::InvalidTemplate(_)));
+        assert_eq!(
+            render("{{ topic | default('rust') }}", BTreeMap::new()).unwrap(),
+            "rust"
+        );
+    }
+
+    #[test]
+    fn test_invalid_template() {
+        // Bodies from before templates keep working
+        assert_eq!(
+            render(
+                "echo ${#items} {{ topic }} {{ other }}",
+                values(&[("topic", Value::from("rust"))])
+            )
+            .unwrap(),
+            "echo ${#items} rust {{ other }}"
+        );
+        assert_eq!(
+            render("{% raw %}echo ${#items}{% endraw %}", BTreeMap::new())
+                .unwrap(),
+            "echo ${#items}"
+        );
+    }
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(
+            placeholders(
+                "{{ a }} {{ os }} {% for b in c %}{{ b }}{% endfor %} {{ a }}"
+            )
+            .unwrap(),
+            ["a"]
+        );
+        assert!(placeholders("{% if %}").is_err());
+    }
+}
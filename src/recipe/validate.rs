@@ -0,0 +1,302 @@
+//! Checking recipes for mistakes that running them would hide
+//!
+//! Running a recipe is forgiving: a header that isn't valid YAML or has
+//! unknown keys is only warned about, unless runs are strict, and
+//! placeholders without a value are left in the prompt as they are.
+//! `aido recipe validate` reports each of these, along with tools in
+//! `allowed_tools` that aren't available and bodies that aren't valid
+//! templates.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{HEADER_REGEX, Header, template};
+use crate::tools::format;
+
+/// What is wrong with a recipe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The recipe is empty, or its header is malformed or has a setting
+    /// of the wrong type
+    InvalidHeader,
+    /// The header has a key aido doesn't know
+    UnknownKey,
+    /// `allowed_tools` names a tool that isn't available
+    UnknownTool,
+    /// The body refers to a variable the header doesn't declare
+    UnresolvedVariable,
+    /// The body isn't a valid template
+    InvalidTemplate,
+}
+
+/// A mistake found in a recipe
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(kind: ProblemKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into() }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The problems of the recipe `content`, given the names of the tools that
+/// are available
+pub fn validate(content: &str, tools: &[&str]) -> Vec<Problem> {
+    if content.trim().is_empty() {
+        return vec![Problem::new(
+            ProblemKind::InvalidHeader,
+            "the recipe is empty",
+        )];
+    }
+
+    let Some(captures) = HEADER_REGEX.captures(content) else {
+        if content.starts_with("---") {
+            return vec![Problem::new(
+                ProblemKind::InvalidHeader,
+                "the header has no closing `---` line",
+            )];
+        }
+        return unresolved_variables(content, &Header::default());
+    };
+    let body = &captures[4];
+
+    let mut problems = Vec::new();
+    let header = match parse_header(&captures[2], &mut problems) {
+        Some(header) => header,
+        None if problems.is_empty() => Header::default(),
+        None => return problems,
+    };
+
+    problems.extend(
+        header
+            .allowed_tools()
+            .iter()
+            .filter(|tool| !tools.contains(&tool.as_str()))
+            .map(|tool| {
+                Problem::new(
+                    ProblemKind::UnknownTool,
+                    format!(
+                        "allowed_tools names `{tool}`, which isn't a tool"
+                    ),
+                )
+            }),
+    );
+    problems.extend(header.tool_format().iter().filter_map(
+        |(tool, template)| {
+            format::check(template).err().map(|e| {
+                Problem::new(
+                    ProblemKind::InvalidHeader,
+                    format!("tool_format of `{tool}`: {e}"),
+                )
+            })
+        },
+    ));
+    problems.extend(unresolved_variables(body, &header));
+
+    problems
+}
+
+/// The problems of the header of the recipe `content`: YAML errors,
+/// unknown keys and settings that are invalid
+pub fn header_problems(content: &str) -> Vec<Problem> {
+    validate(content, &[])
+        .into_iter()
+        .filter(|problem| {
+            matches!(
+                problem.kind,
+                ProblemKind::InvalidHeader | ProblemKind::UnknownKey
+            )
+        })
+        .collect()
+}
+
+/// Parses the header `yaml`, adding its problems to `problems`
+///
+/// Returns `None` for an empty header, and for one too broken to check
+/// further.
+fn parse_header(yaml: &str, problems: &mut Vec<Problem>) -> Option<Header> {
+    if yaml.trim().is_empty() {
+        return None;
+    }
+
+    let value = match serde_yaml::from_str::<serde_yaml::Value>(yaml) {
+        Ok(value) => value,
+        Err(e) => {
+            problems.push(Problem::new(
+                ProblemKind::InvalidHeader,
+                format!("the header isn't valid YAML: {e}"),
+            ));
+            return None;
+        }
+    };
+    let Some(mapping) = value.as_mapping() else {
+        problems.push(Problem::new(
+            ProblemKind::InvalidHeader,
+            "the header isn't a list of `key: value` settings",
+        ));
+        return None;
+    };
+
+    let known = known_keys();
+    for key in mapping.keys() {
+        let key = key.as_str().map_or_else(
+            || serde_yaml::to_string(key).unwrap_or_default(),
+            str::to_owned,
+        );
+        if !known.contains(&key) {
+            problems.push(Problem::new(
+                ProblemKind::UnknownKey,
+                format!("unknown header key `{}`", key.trim()),
+            ));
+        }
+    }
+
+    match serde_yaml::from_value::<Header>(value) {
+        Ok(header) => Some(header),
+        Err(e) => {
+            problems.push(Problem::new(
+                ProblemKind::InvalidHeader,
+                format!("invalid header setting: {e}"),
+            ));
+            None
+        }
+    }
+}
+
+/// The keys a header may have, which are those of a serialized header
+fn known_keys() -> BTreeSet<String> {
+    serde_yaml::to_value(Header::default())
+        .ok()
+        .and_then(|value| value.as_mapping().cloned())
+        .into_iter()
+        .flat_map(serde_yaml::Mapping::into_keys)
+        .filter_map(|key| key.as_str().map(str::to_owned))
+        .collect()
+}
+
+/// A problem for each variable `body` refers to that `header` doesn't
+/// declare, which is left in the prompt as is unless given with `--var`
+///
+/// A body that isn't a valid template is a problem of its own.
+fn unresolved_variables(body: &str, header: &Header) -> Vec<Problem> {
+    let placeholders = match template::placeholders(body) {
+        Ok(placeholders) => placeholders,
+        Err(e) => {
+            return vec![Problem::new(
+                ProblemKind::InvalidTemplate,
+                e.to_string(),
+            )];
+        }
+    };
+
+    placeholders
+        .into_iter()
+        .filter(|name| !header.variables().contains_key(*name))
+        .map(|name| {
+            Problem::new(
+                ProblemKind::UnresolvedVariable,
+                format!(
+                    "`{{{{{name}}}}}` isn't declared under `variables:`, so \
+                     it stays in the prompt unless given with --var"
+                ),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(content: &str) -> Vec<ProblemKind> {
+        validate(content, &["ls", "git_diff"])
+            .into_iter()
+            .map(|problem| problem.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_recipes() {
+        assert!(kinds("Just a prompt").is_empty());
+        assert!(
+            kinds(
+                "---\nname: Review\nallowed_tools: [git_diff]\nvariables:\n  \
+                 branch: { default: main }\n---\nReview {{ branch }}."
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        assert_eq!(kinds(""), [ProblemKind::InvalidHeader]);
+        assert_eq!(
+            kinds("---\nname: [x\n---\nBody"),
+            [ProblemKind::InvalidHeader]
+        );
+        assert_eq!(kinds("---\nname: x\nBody"), [ProblemKind::InvalidHeader]);
+        assert_eq!(
+            kinds("---\ntemperature: hot\n---\nBody"),
+            [ProblemKind::InvalidHeader]
+        );
+        assert_eq!(
+            kinds("---\n- a list\n---\nBody"),
+            [ProblemKind::InvalidHeader]
+        );
+        assert_eq!(
+            kinds("---\ntool_format: { ls: '{{status}}' }\n---\nBody"),
+            [ProblemKind::InvalidHeader]
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys_tools_and_variables() {
+        let problems = validate(
+            "---\nmodle: gpt-4o\nallowed_tools: [ls, cat]\n---\n\
+             Use {{style}} for {{topic}}, {{style}}.",
+            &["ls"],
+        );
+
+        assert_eq!(
+            problems.iter().map(|p| p.kind).collect::<Vec<_>>(),
+            [
+                ProblemKind::UnknownKey,
+                ProblemKind::UnknownTool,
+                ProblemKind::UnresolvedVariable,
+                ProblemKind::UnresolvedVariable,
+            ]
+        );
+        assert_eq!(problems[0].message, "unknown header key `modle`");
+        assert!(problems[1].message.contains("`cat`"));
+        assert!(problems[2].message.starts_with("`{{style}}`"));
+        assert!(problems[3].message.starts_with("`{{topic}}`"));
+    }
+
+    #[test]
+    fn test_templates() {
+        // Loop variables and the built-in names need no declaring
+        assert!(
+            kinds(
+                "{% for file in files %}{{ file }}{% endfor %} on {{ os }}, \
+                 {{ env.USER }}"
+            )
+            .is_empty()
+        );
+        assert_eq!(
+            kinds("---\nname: x\n---\necho ${#items[@]}"),
+            [ProblemKind::InvalidTemplate]
+        );
+    }
+}
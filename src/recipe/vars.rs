@@ -0,0 +1,318 @@
+//! Typed template variables declared in a recipe header
+//!
+//! A recipe body may refer to variables as `{{name}}`. Variables declared
+//! under `variables:` in the header have a type and optional validation
+//! rules; values given with `--var name=value` are checked and normalized
+//! against them before they are substituted:
+//!
+//! ```text
+//! ---
+//! variables:
+//!   branch: { type: string, pattern: "^[a-z0-9/_-]+$", default: main }
+//!   count: { type: int, min: 1, max: 20 }
+//!   style: { type: enum, values: [terse, detailed] }
+//!   notes: { type: path }
+//! ---
+//! ```
+//!
+//! Int and bool variables reach the [template](super::template) as numbers
+//! and booleans. Undeclared variables are substituted as plain strings, and
+//! placeholders without a value are left as they are.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::LazyLock;
+
+use minijinja::Value;
+use regex::Regex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::{RecipeError, template};
+
+/// Matches `{{name}}` placeholders, allowing spaces inside the braces;
+/// recipes, tool output formats and custom tool commands all use them
+pub static PLACEHOLDER_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap()
+});
+
+/// The kind of value a variable holds
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum VarKind {
+    #[default]
+    String,
+    Int,
+    Bool,
+    /// One of a fixed list of `values`
+    Enum,
+    /// A path that must exist
+    Path,
+}
+
+/// A variable declared in a recipe header
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Variable {
+    #[serde(default, rename = "type")]
+    kind: VarKind,
+    /// Shown to the user when the variable is missing
+    #[serde(default)]
+    description: Option<String>,
+    /// Value used when none is given on the command line
+    #[serde(default, deserialize_with = "deserialize_scalar")]
+    default: Option<String>,
+    /// Regex string values must match
+    #[serde(default)]
+    pattern: Option<String>,
+    /// Smallest allowed int value
+    #[serde(default)]
+    min: Option<i64>,
+    /// Largest allowed int value
+    #[serde(default)]
+    max: Option<i64>,
+    /// Allowed enum values
+    #[serde(default)]
+    values: Vec<String>,
+}
+
+impl Variable {
+    #[must_use]
+    pub fn kind(&self) -> VarKind {
+        self.kind
+    }
+
+    #[must_use]
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    #[must_use]
+    pub fn default_value(&self) -> Option<&str> {
+        self.default.as_deref()
+    }
+
+    /// The template value of `value`, which [`coerce`](Self::coerce)
+    /// accepted
+    fn template_value(&self, value: String) -> Value {
+        match self.kind {
+            VarKind::Int => value
+                .parse::<i64>()
+                .map_or_else(|_| Value::from(value.clone()), Value::from),
+            VarKind::Bool => Value::from(value == "true"),
+            _ => Value::from(value),
+        }
+    }
+
+    /// Checks `value` against the variable's type and rules, returning it
+    /// in normalized form, or the reason it was rejected
+    pub fn coerce(&self, value: &str) -> Result<String, String> {
+        match self.kind {
+            VarKind::String => {
+                if let Some(pattern) = &self.pattern {
+                    let regex = Regex::new(pattern)
+                        .map_err(|e| format!("invalid pattern: {e}"))?;
+                    if !regex.is_match(value) {
+                        return Err(format!(
+                            "'{value}' does not match /{pattern}/"
+                        ));
+                    }
+                }
+                Ok(value.to_owned())
+            }
+            VarKind::Int => {
+                let number = value
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| format!("'{value}' is not an integer"))?;
+                if let Some(min) = self.min
+                    && number < min
+                {
+                    return Err(format!("{number} is less than {min}"));
+                }
+                if let Some(max) = self.max
+                    && number > max
+                {
+                    return Err(format!("{number} is greater than {max}"));
+                }
+                Ok(number.to_string())
+            }
+            VarKind::Bool => match value.trim().to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok("true".to_owned()),
+                "false" | "no" | "off" | "0" => Ok("false".to_owned()),
+                _ => Err(format!("'{value}' is not true or false")),
+            },
+            VarKind::Enum => {
+                if self.values.iter().any(|v| v == value) {
+                    Ok(value.to_owned())
+                } else {
+                    Err(format!(
+                        "'{value}' is not one of {}",
+                        self.values.join(", ")
+                    ))
+                }
+            }
+            VarKind::Path => {
+                if Path::new(value).exists() {
+                    Ok(value.to_owned())
+                } else {
+                    Err(format!("'{value}' does not exist"))
+                }
+            }
+        }
+    }
+}
+
+/// Accepts any YAML scalar as a string, so `default: 3` works for an int
+fn deserialize_scalar<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    use serde_yaml::Value;
+
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(Value::Bool(b)) => Ok(Some(b.to_string())),
+        Some(Value::Number(n)) => Ok(Some(n.to_string())),
+        Some(other) => Err(D::Error::custom(format!(
+            "expected a single value as default, found {other:?}"
+        ))),
+    }
+}
+
+/// The names of the variables `body` refers to, in order of appearance
+pub(super) fn placeholders(body: &str) -> impl Iterator<Item = &str> {
+    PLACEHOLDER_REGEX
+        .captures_iter(body)
+        .filter_map(|captures| captures.get(1))
+        .map(|name| name.as_str())
+}
+
+/// Fills in the `{{name}}` placeholders of `body` that have a value in
+/// `values`, leaving the rest of it as it is
+pub(super) fn substitute(
+    body: &str,
+    values: &BTreeMap<String, Value>,
+) -> String {
+    PLACEHOLDER_REGEX
+        .replace_all(body, |captures: &regex::Captures<'_>| {
+            values
+                .get(&captures[1])
+                .map_or_else(|| captures[0].to_owned(), ToString::to_string)
+        })
+        .into_owned()
+}
+
+/// Renders the template `body` with the variables
+///
+/// Every declared variable must have a valid value, either from `values`
+/// or its default.
+pub fn render(
+    body: &str,
+    declared: &BTreeMap<String, Variable>,
+    values: &HashMap<String, String>,
+) -> Result<String, RecipeError> {
+    let mut resolved = values
+        .iter()
+        .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+        .collect::<BTreeMap<_, _>>();
+
+    for (name, variable) in declared {
+        let value = values
+            .get(name)
+            .map(String::as_str)
+            .or_else(|| variable.default_value())
+            .ok_or_else(|| RecipeError::MissingVariable {
+                name: name.clone(),
+                description: variable.description().map(str::to_owned),
+            })?;
+
+        let value = variable.coerce(value).map_err(|reason| {
+            RecipeError::InvalidVariable { name: name.clone(), reason }
+        })?;
+        resolved.insert(name.clone(), variable.template_value(value));
+    }
+
+    template::render(body, resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(yaml: &str) -> Variable {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| ((*k).to_owned(), (*v).to_owned())).collect()
+    }
+
+    #[test]
+    fn test_coerce_int() {
+        let count = variable("{ type: int, min: 1, max: 20 }");
+
+        assert_eq!(count.coerce(" 07 "), Ok("7".to_owned()));
+        assert!(count.coerce("0").unwrap_err().contains("less than 1"));
+        assert!(count.coerce("21").unwrap_err().contains("greater than"));
+        assert!(count.coerce("many").unwrap_err().contains("not an integer"));
+    }
+
+    #[test]
+    fn test_coerce_bool_and_enum() {
+        let flag = variable("{ type: bool }");
+        let style = variable("{ type: enum, values: [terse, detailed] }");
+
+        assert_eq!(flag.coerce("Yes"), Ok("true".to_owned()));
+        assert_eq!(flag.coerce("0"), Ok("false".to_owned()));
+        assert!(flag.coerce("maybe").is_err());
+        assert_eq!(style.coerce("terse"), Ok("terse".to_owned()));
+        assert!(style.coerce("loud").unwrap_err().contains("terse, detailed"));
+    }
+
+    #[test]
+    fn test_coerce_string_pattern_and_path() {
+        let branch = variable(r#"{ pattern: "^[a-z]+$" }"#);
+        let file = variable("{ type: path }");
+
+        assert_eq!(branch.kind(), VarKind::String);
+        assert!(branch.coerce("main").is_ok());
+        assert!(branch.coerce("Main; rm -rf").is_err());
+        assert!(file.coerce(env!("CARGO_MANIFEST_DIR")).is_ok());
+        assert!(file.coerce("/no/such/aido/path").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let declared = BTreeMap::from([
+            ("count".to_owned(), variable("{ type: int, default: 3 }")),
+            ("style".to_owned(), variable("{ type: enum, values: [a, b] }")),
+        ]);
+        let body = "Give {{ count }} {{style}} ideas about {{topic}}. {{x}}";
+
+        let rendered = render(
+            body,
+            &declared,
+            &values(&[("style", "b"), ("topic", "rust")]),
+        )
+        .unwrap();
+
+        assert_eq!(rendered, "Give 3 b ideas about rust. {{x}}");
+    }
+
+    #[test]
+    fn test_render_rejects_missing_and_invalid_values() {
+        let declared =
+            BTreeMap::from([("count".to_owned(), variable("{ type: int }"))]);
+
+        assert!(matches!(
+            render("{{count}}", &declared, &HashMap::new()),
+            Err(RecipeError::MissingVariable { .. })
+        ));
+        assert!(matches!(
+            render("{{count}}", &declared, &values(&[("count", "x")])),
+            Err(RecipeError::InvalidVariable { .. })
+        ));
+    }
+}
@@ -0,0 +1,583 @@
+//! Finding the passages of local notes and docs relevant to a question
+//!
+//! `aido index ~/notes` splits the text files under a directory into
+//! chunks of a few paragraphs, embeds each with the config's
+//! `embedding_model` and keeps the vectors in `index.json` next to the
+//! config file. Indexing the directory again only embeds the files that
+//! changed, and drops those that are gone.
+//!
+//! Once there is an index and `enabled` under `[tools]` names it, runs
+//! are offered the `retrieve` tool, which embeds a query and returns the
+//! chunks whose vectors are closest to it. The index is read on the first
+//! search of a run. Every chunk is compared with the query, which is quick
+//! enough for the tens of thousands of chunks a notes directory makes.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+
+use crate::config::Config;
+use crate::context;
+use crate::error::AidoResult;
+use crate::llm::embeddings::DEFAULT_EMBEDDING_MODEL;
+use crate::llm::{LlmClient, LlmError};
+use crate::tools::{
+    Arg, ArgType, Tool, ToolContext, ToolDefinition, ToolDefinitionBuilder,
+    ToolInput,
+};
+
+/// Name of the index file inside the config directory
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// Name of the tool runs search the index with
+pub const TOOL_NAME: &str = "retrieve";
+
+/// Longest chunk, in bytes; paragraphs are kept together up to this size
+const MAX_CHUNK_LEN: usize = 1500;
+
+/// Files larger than this are left out of the index
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Number of chunks embedded per request
+const EMBED_BATCH_SIZE: usize = 64;
+
+/// Number of chunks `retrieve` returns when the model doesn't ask for a
+/// number, and the most it returns
+const DEFAULT_TOP_K: usize = 5;
+const MAX_TOP_K: usize = 20;
+
+#[derive(Error, Debug)]
+pub enum RetrievalError {
+    #[error("There is no index yet; `aido index <dir>` makes one")]
+    NoIndex,
+
+    #[error("Cannot index {path}: {source}")]
+    Unreadable { path: PathBuf, source: io::Error },
+
+    #[error(
+        "The embedding endpoint returned {returned} vectors for {expected} \
+         texts"
+    )]
+    MissingVectors { expected: usize, returned: usize },
+
+    #[error(transparent)]
+    Llm(#[from] LlmError),
+
+    #[error("Invalid index: {0}; `aido index` rebuilds it")]
+    Json(#[from] serde_json::Error),
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// A piece of an indexed file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    /// First line of the chunk in its file, counting from 1
+    pub start_line: usize,
+    /// Last line of the chunk in its file
+    pub end_line: usize,
+    pub text: String,
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+impl Chunk {
+    fn new(line: usize, text: &str) -> Self {
+        Self {
+            start_line: line,
+            end_line: line,
+            text: text.to_owned(),
+            embedding: Vec::new(),
+        }
+    }
+
+    /// Appends `other`, separated by `separator`, if the result isn't
+    /// longer than a chunk may be
+    fn absorb(&mut self, other: &Self, separator: &str) -> bool {
+        if self.text.len() + separator.len() + other.text.len() > MAX_CHUNK_LEN
+        {
+            return false;
+        }
+
+        self.text.push_str(separator);
+        self.text.push_str(&other.text);
+        self.end_line = other.end_line;
+        true
+    }
+}
+
+/// An indexed file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexedFile {
+    /// Hash of the content the chunks were made from
+    pub sha256: String,
+    pub chunks: Vec<Chunk>,
+}
+
+/// What indexing a directory did
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IndexReport {
+    /// Files embedded, because they were new or had changed
+    pub indexed: usize,
+    /// Chunks embedded
+    pub chunks: usize,
+    /// Files already indexed as they are
+    pub unchanged: usize,
+    /// Files dropped from the index because they are gone
+    pub removed: usize,
+}
+
+/// A chunk found by a search, with how close it is to the query, from -1
+/// to 1
+#[derive(Debug, Clone, Copy)]
+pub struct Match<'a> {
+    pub path: &'a Path,
+    pub chunk: &'a Chunk,
+    pub score: f32,
+}
+
+/// The embedded chunks of the indexed files
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    #[serde(skip)]
+    path: PathBuf,
+    /// The model the chunks were embedded with; vectors of other models
+    /// can't be compared with them
+    pub model: String,
+    /// The indexed files, by absolute path
+    pub files: BTreeMap<PathBuf, IndexedFile>,
+}
+
+impl Index {
+    /// Where the index kept next to the given config file is
+    pub fn path_for_config_file(config_file_path: &str) -> PathBuf {
+        Path::new(config_file_path)
+            .parent()
+            .expect("Config file path should have a parent directory")
+            .join(INDEX_FILE_NAME)
+    }
+
+    /// Reads the index at `path`, which is empty if there is no file yet
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, RetrievalError> {
+        let path = path.into();
+        let index = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { path, ..index })
+    }
+
+    pub fn save(&self) -> Result<(), RetrievalError> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string(self)?)?;
+
+        Ok(())
+    }
+
+    /// Where the index is kept
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of chunks in the index
+    pub fn len(&self) -> usize {
+        self.files.values().map(|file| file.chunks.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Brings the files under `dir` in the index up to date, embedding
+    /// their chunks with `model`
+    ///
+    /// The chunks of other directories are kept, unless they were
+    /// embedded with another model, in which case they are dropped.
+    pub async fn update(
+        &mut self,
+        llm: &LlmClient,
+        model: &str,
+        dir: &Path,
+    ) -> Result<IndexReport, RetrievalError> {
+        let dir = dir.canonicalize().map_err(|source| {
+            RetrievalError::Unreadable { path: dir.to_owned(), source }
+        })?;
+        if self.model != model {
+            self.files.clear();
+            model.clone_into(&mut self.model);
+        }
+
+        let mut report = IndexReport::default();
+        let mut seen = Vec::new();
+        let mut pending = Vec::new();
+        for (path, content) in text_files(&dir) {
+            let sha256 = format!("{:x}", Sha256::digest(&content));
+            seen.push(path.clone());
+            if self.files.get(&path).is_some_and(|f| f.sha256 == sha256) {
+                report.unchanged += 1;
+                continue;
+            }
+
+            let chunks = chunk(&content);
+            report.indexed += 1;
+            report.chunks += chunks.len();
+            pending.push((path, IndexedFile { sha256, chunks }));
+        }
+
+        let texts = pending
+            .iter()
+            .flat_map(|(_, file)| &file.chunks)
+            .map(|chunk| chunk.text.clone())
+            .collect::<Vec<_>>();
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBED_BATCH_SIZE) {
+            vectors.extend(embed(llm, model, batch.to_vec()).await?);
+        }
+
+        let mut vectors = vectors.into_iter();
+        for (path, mut file) in pending {
+            for chunk in &mut file.chunks {
+                chunk.embedding = vectors.next().unwrap_or_default();
+            }
+            self.files.insert(path, file);
+        }
+
+        let before = self.files.len();
+        self.files
+            .retain(|path, _| !path.starts_with(&dir) || seen.contains(path));
+        report.removed = before - self.files.len();
+
+        Ok(report)
+    }
+
+    /// The `k` chunks closest to `query`, closest first
+    pub async fn search(
+        &self,
+        llm: &LlmClient,
+        query: &str,
+        k: usize,
+    ) -> Result<Vec<Match<'_>>, RetrievalError> {
+        let query = embed(llm, &self.model, vec![query.to_owned()]).await?;
+
+        Ok(self.nearest(&query[0], k))
+    }
+
+    /// The `k` chunks whose vectors are closest to `vector`, closest first
+    pub fn nearest(&self, vector: &[f32], k: usize) -> Vec<Match<'_>> {
+        let mut matches = self
+            .files
+            .iter()
+            .flat_map(|(path, file)| {
+                file.chunks.iter().map(move |chunk| Match {
+                    path,
+                    chunk,
+                    score: cosine_similarity(vector, &chunk.embedding),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+        matches.truncate(k);
+        matches
+    }
+}
+
+/// The embeddings of `texts`, failing unless there is one for each text
+async fn embed(
+    llm: &LlmClient,
+    model: &str,
+    texts: Vec<String>,
+) -> Result<Vec<Vec<f32>>, RetrievalError> {
+    let expected = texts.len();
+    let vectors = llm.embed(model, texts, None).await?.vectors;
+    if vectors.len() != expected {
+        return Err(RetrievalError::MissingVectors {
+            expected,
+            returned: vectors.len(),
+        });
+    }
+
+    Ok(vectors)
+}
+
+/// The text files under `dir` that aren't ignored or too large, with their
+/// contents
+fn text_files(dir: &Path) -> Vec<(PathBuf, String)> {
+    context::walker(dir)
+        .build()
+        .flatten()
+        .filter(|entry| entry.file_type().is_some_and(|ft| ft.is_file()))
+        .filter(|entry| {
+            entry.metadata().is_ok_and(|m| m.len() <= MAX_FILE_SIZE)
+        })
+        .filter_map(|entry| {
+            // Files that aren't UTF-8 are taken to be binary
+            let content = std::fs::read_to_string(entry.path()).ok()?;
+            (!content.trim().is_empty()).then(|| (entry.into_path(), content))
+        })
+        .collect()
+}
+
+/// `text` split into chunks of whole paragraphs where they fit, and of
+/// whole lines where they don't
+fn chunk(text: &str) -> Vec<Chunk> {
+    let mut paragraphs: Vec<Chunk> = Vec::new();
+    let mut in_paragraph = false;
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            in_paragraph = false;
+            continue;
+        }
+
+        for piece in pieces(line) {
+            let piece = Chunk::new(index + 1, piece);
+            let absorbed = in_paragraph
+                && paragraphs
+                    .last_mut()
+                    .is_some_and(|last| last.absorb(&piece, "\n"));
+            if !absorbed {
+                paragraphs.push(piece);
+            }
+            in_paragraph = true;
+        }
+    }
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for paragraph in paragraphs {
+        if !chunks
+            .last_mut()
+            .is_some_and(|last| last.absorb(&paragraph, "\n\n"))
+        {
+            chunks.push(paragraph);
+        }
+    }
+
+    chunks
+}
+
+/// `line` cut into pieces no longer than a chunk may be
+fn pieces(line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = line;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut end = rest.len().min(MAX_CHUNK_LEN);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        rest = tail;
+        Some(piece)
+    })
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+/// The tool runs search the index with
+pub struct Retrieve {
+    definition: ToolDefinition,
+    config: Config,
+    index: PathBuf,
+    /// The index, read on the first search
+    loaded: OnceCell<Index>,
+}
+
+impl Retrieve {
+    /// The tool searching the index at `index` through the endpoint of
+    /// `config`
+    pub fn new(config: &Config, index: impl Into<PathBuf>) -> Self {
+        let definition = ToolDefinitionBuilder::new(TOOL_NAME)
+            .description(
+                "Search the user's indexed notes and documents for the \
+                 passages most relevant to a query. Results are formatted \
+                 as path:lines followed by the passage",
+            )
+            .arg(
+                Arg::new("query")
+                    .description(
+                        "What to look for, such as the user's question or \
+                         the topic it is about",
+                    )
+                    .kind(ArgType::String)
+                    .required(),
+            )
+            .arg(
+                Arg::new("k")
+                    .description(format!(
+                        "Number of passages to return, {DEFAULT_TOP_K} if \
+                         not given and at most {MAX_TOP_K}"
+                    ))
+                    .kind(ArgType::Integer),
+            )
+            .build();
+
+        Self {
+            definition,
+            config: config.clone(),
+            index: index.into(),
+            loaded: OnceCell::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for Retrieve {
+    async fn execute(
+        &self,
+        input: ToolInput,
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        let query = input
+            .get("query")
+            .and_then(Value::as_str)
+            .ok_or("Missing required argument: query")?;
+        let k = input
+            .get("k")
+            .and_then(Value::as_u64)
+            .and_then(|k| usize::try_from(k).ok())
+            .unwrap_or(DEFAULT_TOP_K)
+            .clamp(1, MAX_TOP_K);
+
+        let index = self
+            .loaded
+            .get_or_try_init(|| async { Index::load(&self.index) })
+            .await?;
+        if index.is_empty() {
+            return Err(RetrievalError::NoIndex.into());
+        }
+
+        let llm = LlmClient::from_config(&self.config);
+        let matches = index.search(&llm, query, k).await?;
+
+        Ok(format_matches(&matches, &context.working_dir))
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+/// The matches as `retrieve` returns them, with paths relative to `cwd`
+/// when they are inside it
+fn format_matches(matches: &[Match<'_>], cwd: &Path) -> String {
+    let mut output = String::new();
+
+    for found in matches {
+        let path = found.path.strip_prefix(cwd).unwrap_or(found.path);
+        let _ = writeln!(
+            output,
+            "{}:{}-{} (score {:.2})\n{}\n",
+            path.display(),
+            found.chunk.start_line,
+            found.chunk.end_line,
+            found.score,
+            found.chunk.text
+        );
+    }
+
+    if output.is_empty() {
+        return "No indexed passages match".to_owned();
+    }
+    output.truncate(output.trim_end().len());
+    output
+}
+
+/// The model `aido index` embeds with: `model` if given, otherwise the
+/// config's
+pub fn embedding_model<'a>(
+    config: &'a Config,
+    model: Option<&'a str>,
+) -> &'a str {
+    model
+        .or(config.embedding_model.as_deref())
+        .unwrap_or(DEFAULT_EMBEDDING_MODEL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk() {
+        let text = "# Notes\n\nFirst paragraph,\nstill first.\n\n\nSecond.\n";
+        let chunks = chunk(text);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].text,
+            "# Notes\n\nFirst paragraph,\nstill first.\n\nSecond."
+        );
+        assert_eq!((chunks[0].start_line, chunks[0].end_line), (1, 7));
+
+        let paragraph = "word ".repeat(100);
+        let long = [paragraph.as_str(); 4].join("\n\n");
+        let chunks = chunk(&long);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|c| c.text.len() <= MAX_CHUNK_LEN));
+        assert_eq!((chunks[1].start_line, chunks[1].end_line), (5, 7));
+
+        let line = "é".repeat(MAX_CHUNK_LEN);
+        let chunks = chunk(&line);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|c| c.text.len() <= MAX_CHUNK_LEN));
+        assert_eq!(chunks[0].text.clone() + &chunks[1].text, line);
+    }
+
+    #[test]
+    fn test_nearest() {
+        let file = |chunks: &[(&str, [f32; 2])]| IndexedFile {
+            sha256: String::new(),
+            chunks: chunks
+                .iter()
+                .map(|(text, embedding)| Chunk {
+                    embedding: embedding.to_vec(),
+                    ..Chunk::new(1, text)
+                })
+                .collect(),
+        };
+        let mut index = Index::default();
+        index
+            .files
+            .insert("/notes/a.md".into(), file(&[("cats", [1.0, 0.0])]));
+        index.files.insert(
+            "/notes/b.md".into(),
+            file(&[("dogs", [0.0, 1.0]), ("pets", [0.7, 0.7])]),
+        );
+
+        let matches = index.nearest(&[1.0, 0.2], 2);
+        let texts = matches.iter().map(|m| &m.chunk.text).collect::<Vec<_>>();
+        assert_eq!(texts, ["cats", "pets"]);
+        assert!(matches[0].score > 0.95);
+
+        let output = format_matches(&matches, Path::new("/notes"));
+        assert!(output.starts_with("a.md:1-1 (score 0.98)\ncats\n\nb.md"));
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!(
+            (cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6
+        );
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]).abs() < 1e-6);
+    }
+}
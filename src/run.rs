@@ -1,125 +1,3611 @@
-use std::{io::Write, path::Path, vec};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::Instant,
+    vec,
+};
 
-use log::info;
+use log::{info, warn};
+use thiserror::Error;
 
 use crate::{
-    config::Config,
-    llm::{self, LlmRequest, Message},
-    tools::Tool,
+    audit::{AuditEntry, AuditLog, AuditStatus},
+    bundle::Recorder,
+    cache::ResponseCache,
+    cancel::CancelToken,
+    clipboard,
+    config::{Config, ConfigWatcher, RequestParams},
+    confirm::{self, Confirm},
+    context::{self, estimate_tokens},
+    error::AidoResult,
+    isolation::Worktree,
+    json_repair, language,
+    limits::{self, Oversize},
+    llm::{
+        self, LlmClient, LlmRequest, LlmResponse, Message, ResponseFormat,
+        StreamEvent, ToolCall, Usage,
+    },
+    lock::RunLock,
+    middleware::{Middleware, MiddlewareStack, ToolDecision, ToolHook},
+    notices::NoticeLog,
+    otel,
+    output::{OutputFile, OutputFormat},
+    preamble::{self, Stripper},
+    project,
+    recipe::{
+        Example, Header, Recipe, RecipeError, RecipeStore, examples, hooks,
+    },
+    schema, session, shell,
+    status::{self, StatusLine},
+    tokens::TokenCount,
+    tools::{
+        Tool, ToolContext, ToolDefinition, ToolInput, format, process, sandbox,
+    },
+    trace::{Trace, TraceEventKind},
+    usage, verify,
 };
-use std::io::{self};
+use std::io::{self, IsTerminal, Read};
+
+/// Number of tool-calling round trips allowed when neither the config nor
+/// the command line specifies a limit
+pub const DEFAULT_MAX_TOOL_ITERATIONS: usize = 25;
+
+/// Largest tool output, in bytes, passed on to the model when the config
+/// doesn't set a limit
+pub const DEFAULT_MAX_TOOL_OUTPUT_BYTES: usize = 32 * 1024;
+
+/// Seed of deterministic runs whose config doesn't set one
+pub const DETERMINISTIC_SEED: i64 = 42;
+
+/// Number of identical consecutive tool calls after which the model is
+/// considered to be stuck in a loop
+const MAX_REPEATED_TOOL_CALLS: usize = 3;
+
+/// Estimated tokens each message costs on top of its content, for the
+/// role and framing the API adds
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// Errors that terminate the agent loop
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error(
+        "Stopped after {limit} tool iterations without a final answer \
+         (raise the limit with --max-iterations or `max_tool_iterations` \
+         in the config)"
+    )]
+    IterationLimit { limit: usize },
+
+    #[error(
+        "Stopped because the model called tool '{name}' with identical \
+         arguments {count} times in a row: {arguments}"
+    )]
+    RepeatedToolCall { name: String, arguments: String, count: usize },
+
+    #[error(
+        "Stopped before asking the model again: the run has used {spent}, \
+         its budget is {budget} (raise `{setting}` in the config)"
+    )]
+    BudgetExceeded { spent: String, budget: String, setting: &'static str },
+
+    #[error("The answer does not match the recipe's schema: {problems}")]
+    SchemaMismatch { problems: String },
+
+    #[error(
+        "The request takes up about {tokens} tokens, more than the context \
+         window of {model} holds ({window}{}): {breakdown}. {advice}",
+        if *reserved > 0 {
+            format!(", with {reserved} kept free for the answer")
+        } else {
+            String::new()
+        }
+    )]
+    RequestTooLarge {
+        model: String,
+        tokens: usize,
+        window: usize,
+        reserved: usize,
+        breakdown: String,
+        advice: String,
+    },
+}
+
+/// Options controlling the behavior of a single run
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct RunOptions {
+    /// Print token usage after each response
+    pub print_usage: bool,
+    /// Maximum number of tool-calling round trips; falls back to
+    /// [`DEFAULT_MAX_TOOL_ITERATIONS`] when unset
+    pub max_tool_iterations: Option<usize>,
+    /// Execute tools inside a temporary git worktree and present the
+    /// resulting diff instead of touching the working tree
+    pub isolated: bool,
+    /// The worktree an isolated run executes tools in, when the caller
+    /// created it and presents its diff; otherwise the run creates one of
+    /// its own
+    pub worktree: Option<Arc<Worktree>>,
+    /// Print an outline of every step of the run once it is over
+    pub trace: bool,
+    /// What was done to the request before the run, recorded first in its
+    /// trace and noted on stderr by dry runs
+    pub preparation: Vec<TraceEventKind>,
+    /// How the result of the run is presented
+    pub output: OutputFormat,
+    /// Hooks run around every LLM and tool call
+    pub middleware: MiddlewareStack,
+    /// Values for the template variables of a recipe, from `--var`
+    pub vars: HashMap<String, String>,
+    /// Put the answer, or its first code block, on the clipboard once the
+    /// run finishes
+    pub copy_result: bool,
+    /// Also write the answer, or everything streamed, to this file
+    pub output_file: Option<OutputFile>,
+    /// Check what is copied as a shell command first, and leave it off the
+    /// clipboard if it is broken or destructive
+    pub check_command: bool,
+    /// Functions taking over the run's interaction with the terminal
+    pub callbacks: Callbacks,
+    /// Where every tool invocation is recorded, if anywhere
+    pub audit: Option<AuditLog>,
+    /// Where replies to identical requests are looked up, if anywhere
+    pub cache: Option<ResponseCache>,
+    /// Records when warnings from the provider were last printed, so each
+    /// is printed at most once a day; without it they are only logged
+    pub notices: Option<NoticeLog>,
+    /// Stops the run once cancelled, as the command line does on Ctrl-C
+    pub cancel: CancelToken,
+    /// Print the first request as JSON instead of sending it
+    pub dry_run: bool,
+    /// Show a spinner and what the run is waiting for on stderr while no
+    /// output is being printed
+    pub status_line: bool,
+    /// Echo what the programs behind tools print to stderr, dimmed, as it
+    /// comes
+    pub show_tool_output: bool,
+    /// Print the reasoning of reasoning models to stderr, dimmed, as it
+    /// streams in
+    pub show_reasoning: bool,
+    /// JSON schema the answer must match; answers that don't are sent
+    /// back once with what is wrong with them
+    pub response_schema: Option<serde_json::Value>,
+    /// Sample at temperature 0 with a fixed seed, without fallback models
+    /// or the response cache, and fail when the provider can't say which
+    /// backend answered
+    pub deterministic: bool,
+    /// Stream the reply to stderr, or nowhere when stderr isn't a
+    /// terminal, leaving stdout to the final answer, which the caller
+    /// prints
+    pub quiet_stream: bool,
+    /// Fail on recipe headers with problems instead of warning about them,
+    /// as the config's `strict` also does
+    pub strict: bool,
+    /// Leave the project's `.aido.md` and `AGENTS.md` out of recipe runs,
+    /// as the config's `project_context = false` also does
+    pub skip_project_context: bool,
+    /// Names of the only tools the model is offered, when set, as a
+    /// workflow step's `tools` limits them
+    pub allowed_tools: Option<Vec<String>>,
+    /// The recipe reviewing answers until it accepts one, as a recipe's
+    /// `verify` names it
+    pub reviewer: Option<Arc<Reviewer>>,
+    /// Where every request to the model, its reply and the chunks it came
+    /// in are recorded, for `--record`; set with
+    /// [`RunOptions::with_recorder`]
+    pub recorder: Option<Recorder>,
+    /// Request parameters given on the command line, over those of the
+    /// config and recipe
+    pub params: RequestParams,
+    /// What the run this one is part of has spent, counted against the
+    /// budget; [`run`] starts counting when unset
+    pub spending: Option<Spending>,
+    /// Notices edits to the config file, which conversations apply between
+    /// answers
+    pub config_watcher: Option<Arc<ConfigWatcher>>,
+}
+
+/// Tokens and cost spent by a run, counting every request it makes:
+/// fallback models, the judge, reviews and summaries of earlier turns
+#[derive(Debug, Clone, Default)]
+pub struct Spending(Arc<Mutex<Spent>>);
 
-pub fn run(
+#[derive(Debug, Default)]
+struct Spent {
+    tokens: u32,
+    /// Cost of the requests to models with a price
+    cost: f64,
+}
+
+impl Spending {
+    /// Counts `usage` of a request to `model`, priced as `config` says
+    fn add(&self, config: &Config, model: &str, usage: &Usage) {
+        let cost = usage::estimate_cost(&config.prices, model, usage);
+        let mut spent =
+            self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        spent.tokens = spent.tokens.saturating_add(usage.total_tokens());
+        spent.cost += cost.unwrap_or(0.0);
+    }
+
+    /// Fails once the token or cost budget `config` gives a run is used up
+    fn check(&self, config: &Config) -> Result<(), RunError> {
+        let (tokens, cost) = {
+            let spent = self
+                .0
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            (spent.tokens, spent.cost)
+        };
+        if let Some(budget) = config.max_tokens_per_run
+            && tokens >= budget
+        {
+            return Err(RunError::BudgetExceeded {
+                spent: format!("{tokens} tokens"),
+                budget: format!("{budget} tokens"),
+                setting: "max_tokens_per_run",
+            });
+        }
+        if let Some(budget) = config.max_cost_per_run
+            && cost >= budget
+        {
+            return Err(RunError::BudgetExceeded {
+                spent: format!("${cost:.4}"),
+                budget: format!("${budget:.4}"),
+                setting: "max_cost_per_run",
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A recipe that reviews answers, which are revised for its critique
+#[derive(Debug, Clone)]
+pub struct Reviewer {
+    name: String,
+    recipe: Recipe,
+    /// The config and options the reviewing recipe is run with, rather
+    /// than those the reviewed recipe changed
     config: Config,
+    options: RunOptions,
+    max_revisions: usize,
+}
+
+impl Reviewer {
+    /// The reviewer `recipe` names with `verify`, from `recipes`, run with
+    /// `config` and `options`
+    pub fn for_recipe(
+        recipe: &Recipe,
+        recipes: &RecipeStore,
+        config: &Config,
+        options: &RunOptions,
+    ) -> Result<Option<Self>, RecipeError> {
+        let Some(name) = recipe.header().verify() else {
+            return Ok(None);
+        };
+
+        let mut quiet = options.clone();
+        quiet.callbacks.on_text = Some(Arc::new(|_: &str| {}));
+        quiet.output_file = None;
+        quiet.copy_result = false;
+        quiet.check_command = false;
+        quiet.response_schema = None;
+        quiet.trace = false;
+        quiet.preparation = Vec::new();
+        quiet.reviewer = None;
+        // A replay has no reviewer, so its requests are left out
+        quiet.recorder = None;
+
+        Ok(Some(Self {
+            name: name.to_owned(),
+            recipe: recipes.get(name)?,
+            config: config.clone(),
+            options: quiet,
+            max_revisions: recipe.header().max_revisions(),
+        }))
+    }
+}
+
+impl Reviewer {
+    /// Has the reviewing recipe review the answer of `outcome`, shown the
+    /// whole conversation the answer replies to
+    ///
+    /// The reviewer is offered no tools, and neither runs its hooks nor
+    /// takes its lock: it only judges what it is shown. It spends from the
+    /// budget of the run in `options`.
+    async fn review(
+        &self,
+        outcome: &RunOutcome,
+        options: &RunOptions,
+    ) -> AidoResult<RunOutcome> {
+        let conversation = outcome
+            .messages
+            .split_last()
+            .map_or(&[][..], |(_answer, conversation)| conversation);
+        let request = verify::review_request(
+            &session::transcript(conversation),
+            &outcome.text,
+        );
+
+        let options = RunOptions {
+            spending: options.spending.clone(),
+            ..self.options.clone()
+        };
+        let mut prepared = prepare_request(
+            self.config.clone(),
+            &self.recipe,
+            &self.name,
+            Some(request),
+            &options,
+        )?;
+        let messages = std::mem::take(&mut prepared.messages);
+        Box::pin(run(&prepared.config, messages, &[], &prepared.options)).await
+    }
+}
+
+/// Receives text as it is generated
+pub type TextCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Answers a yes/no question
+pub type ConfirmCallback = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Functions an embedding application supplies to take part in a run
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    /// Receives the text of the run as it is generated, instead of stdout
+    pub on_text: Option<TextCallback>,
+    /// Answers the questions asked before tool calls that need
+    /// confirmation, instead of the terminal
+    pub on_confirm: Option<ConfirmCallback>,
+}
+
+impl fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("on_text", &self.on_text.is_some())
+            .field("on_confirm", &self.on_confirm.is_some())
+            .finish()
+    }
+}
+
+/// Forwards everything written to it to an `on_text` callback
+struct CallbackWriter(TextCallback);
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.0)(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RunOutcome {
+    /// Marks the run as cancelled, keeping `partial` as its answer
+    fn cancel(&mut self, partial: String) {
+        self.text = partial;
+        self.cancelled = true;
+    }
+}
+
+impl RunOptions {
+    /// Builds run options from the config, letting the command line override
+    pub fn new(
+        config: &Config,
+        print_usage: bool,
+        max_iterations: Option<usize>,
+    ) -> Self {
+        Self {
+            print_usage,
+            max_tool_iterations: max_iterations.or(config.max_tool_iterations),
+            ..Self::default()
+        }
+    }
+
+    /// Registers a middleware to run around every LLM call
+    pub fn with_middleware(
+        mut self,
+        middleware: impl Middleware + 'static,
+    ) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Records every request to the model, its reply and the chunks it
+    /// came in to `recorder`
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Registers a hook to run around every tool call
+    pub fn with_tool_hook(mut self, hook: impl ToolHook + 'static) -> Self {
+        self.middleware.push_tool_hook(hook);
+        self
+    }
+
+    /// What the run has spent so far
+    fn spending(&self) -> Spending {
+        self.spending.clone().unwrap_or_default()
+    }
+
+    fn tool_iteration_limit(&self) -> usize {
+        self.max_tool_iterations.unwrap_or(DEFAULT_MAX_TOOL_ITERATIONS)
+    }
+
+    /// What tools are called in: the worktree of an isolated run, or the
+    /// directory aido runs in, asking with `on_confirm` or on the terminal
+    pub fn tool_context(&self) -> io::Result<ToolContext> {
+        let context = self
+            .worktree
+            .as_ref()
+            .map_or_else(ToolContext::current, |worktree| {
+                Ok(ToolContext::new(worktree.working_dir()))
+            })?;
+        let ask = self.callbacks.on_confirm.clone().unwrap_or_else(|| {
+            Arc::new(|question| {
+                confirm::ask_on_terminal(question).unwrap_or_else(|e| {
+                    warn!("Could not ask on the terminal, so no: {e}");
+                    false
+                })
+            })
+        });
+
+        Ok(context.with_ask(ask))
+    }
+
+    fn allows_tool(&self, name: &str) -> bool {
+        self.allowed_tools
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|tool| tool == name))
+    }
+
+    /// Definitions of the tools in `tools` the model is offered
+    fn tool_definitions(
+        &self,
+        tools: &[Box<dyn Tool>],
+    ) -> Vec<ToolDefinition> {
+        tools
+            .iter()
+            .map(|t| t.definition())
+            .filter(|definition| self.allows_tool(definition.name()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The result of a completed run
+#[derive(Debug, Clone, Default)]
+pub struct RunOutcome {
+    /// The final answer produced by the model
+    pub text: String,
+    /// The model that produced the answer
+    pub model: String,
+    /// Token usage summed across every request made during the run
+    pub usage: Usage,
+    /// Estimated cost of the run, if the model has a configured price
+    pub cost: Option<f64>,
+    /// Every model reply and tool call made during the run
+    pub trace: Trace,
+    /// The whole conversation, ending with the final answer
+    pub messages: Vec<Message>,
+    /// Whether the user stopped the run with Ctrl-C, in which case `text`
+    /// holds whatever part of the answer had arrived
+    pub cancelled: bool,
+}
+
+/// Detects a model repeatedly issuing the exact same tool call
+#[derive(Debug, Default)]
+struct LoopDetector {
+    last_call: Option<(String, String)>,
+    repeats: usize,
+}
+
+impl LoopDetector {
+    /// Records a tool call, failing once the same call has been seen
+    /// [`MAX_REPEATED_TOOL_CALLS`] times in a row
+    fn record(&mut self, call: &ToolCall) -> Result<(), RunError> {
+        let signature = (call.name().to_owned(), call.arguments().to_owned());
+
+        if self.last_call.as_ref() == Some(&signature) {
+            self.repeats += 1;
+        } else {
+            self.last_call = Some(signature);
+            self.repeats = 1;
+        }
+
+        if self.repeats >= MAX_REPEATED_TOOL_CALLS {
+            return Err(RunError::RepeatedToolCall {
+                name: call.name().to_owned(),
+                arguments: call.arguments().to_owned(),
+                count: self.repeats,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn run(
+    config: &Config,
     mut messages: Vec<Message>,
     tools: &[Box<dyn Tool>],
-    print_usage: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let llm =
-        llm::LlmClient::new(config.model_name, config.api_key, config.api_url);
+    options: &RunOptions,
+) -> AidoResult<RunOutcome> {
+    let mut trace = Trace::start();
+    for event in &options.preparation {
+        trace.record(Instant::now(), event.clone());
+    }
+    let config = &run_config(config, options);
+    // Recipes run on behalf of this one, like the reviewer, spend from the
+    // same budget
+    let options =
+        &RunOptions { spending: Some(options.spending()), ..options.clone() };
+    frame_messages(config, &mut messages, options);
 
-    let tool_definitions =
-        tools.iter().map(|t| t.definition().clone()).collect::<Vec<_>>();
+    let stripper = if options.output == OutputFormat::Bare {
+        Some(Stripper::new(&config.preamble_patterns)?)
+    } else {
+        None
+    };
 
-    let mut out = io::BufWriter::new(io::stdout().lock());
-    loop {
-        let response = llm.get_chat_completion_streaming(
-            &LlmRequest::new(messages.clone(), tool_definitions.clone()),
-            |chunk| {
-                write!(out, "{chunk}").unwrap();
-                out.flush().unwrap();
-            },
-        )?;
+    if options.dry_run {
+        for event in &options.preparation {
+            eprintln!("Note: {event}");
+        }
+        return print_request(config, messages, tools, options);
+    }
+
+    let result = if options.isolated && options.worktree.is_none() {
+        let worktree = Arc::new(Worktree::create()?);
+        let options = &RunOptions {
+            worktree: Some(Arc::clone(&worktree)),
+            ..options.clone()
+        };
+        let result = Box::pin(run_reviewed(
+            config, messages, tools, options, &mut trace,
+        ))
+        .await;
+        worktree.present_diff(&mut text_writer(options)?)?;
+        result
+    } else {
+        Box::pin(run_reviewed(config, messages, tools, options, &mut trace))
+            .await
+    };
+
+    // Printed even when the run failed, since that's when it helps most
+    if options.trace {
+        eprint!("{trace}");
+    }
+
+    if let Some(otel) = &config.otel {
+        let (model, error) = match &result {
+            Ok(outcome) => (outcome.model.as_str(), None),
+            Err(e) => (config.model_name.as_str(), Some(e.to_string())),
+        };
+        otel::export(otel, &trace, model, error.as_deref()).await;
+    }
+
+    let result = result.map(|mut outcome| {
+        if let Some(stripper) = &stripper {
+            outcome.text = stripper.strip(&outcome.text).to_owned();
+        }
+        outcome
+    });
+
+    if let Some(file) = &options.output_file
+        && !file.is_transcript()
+        && let Ok(outcome) = &result
+    {
+        file.write_answer(&outcome.text)?;
+    }
+
+    if options.copy_result
+        && let Ok(outcome) = &result
+        && !outcome.cancelled
+    {
+        let selection = clipboard::selection(&outcome.text);
+        if options.check_command && !shell::check_and_report(selection) {
+            eprintln!("Not copying the command.");
+        } else {
+            match clipboard::copy(selection) {
+                Ok(()) => eprintln!("Copied to the clipboard."),
+                Err(e) => warn!("{e}"),
+            }
+        }
+    }
+
+    result.map(|outcome| RunOutcome { trace, ..outcome })
+}
+
+/// Runs the agent loop, and then has the reviewer review the answer and
+/// the answer revised for its critique until it is accepted, or has been
+/// revised as many times as the reviewer allows
+///
+/// While reviewing, nothing is streamed: only the last answer is printed.
+/// Its usage and cost cover every answer and review.
+async fn run_reviewed(
+    config: &Config,
+    mut messages: Vec<Message>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+    trace: &mut Trace,
+) -> AidoResult<RunOutcome> {
+    let Some(reviewer) = &options.reviewer else {
+        return Box::pin(run_checked(config, messages, tools, options, trace))
+            .await;
+    };
+
+    let mut quiet = options.clone();
+    quiet.callbacks.on_text = Some(Arc::new(|_: &str| {}));
+    quiet.output_file = None;
+
+    let (mut usage, mut cost) = (Usage::default(), None);
+    let mut revisions = 0;
+    let answer = loop {
+        let outcome =
+            Box::pin(run_checked(config, messages, tools, &quiet, trace))
+                .await?;
+        usage += &outcome.usage;
+        cost = add_costs(cost, outcome.cost);
+        if outcome.cancelled {
+            break outcome;
+        }
+
+        let review = reviewer.review(&outcome, options).await?;
+        usage += &review.usage;
+        cost = add_costs(cost, review.cost);
+
+        let critique = match verify::parse_review(&review.text) {
+            verify::Review::Accepted => {
+                info!("{} accepted the answer", reviewer.name);
+                break outcome;
+            }
+            verify::Review::Rejected(critique) => critique,
+        };
+        if revisions == reviewer.max_revisions {
+            warn!(
+                "Out of revisions; {} still found problems: {critique}",
+                reviewer.name
+            );
+            break outcome;
+        }
+        revisions += 1;
+        info!("Revising the answer for {}: {critique}", reviewer.name);
+
+        messages = outcome.messages;
+        messages.push(Message::User(verify::revision_request(&critique)));
+    };
+
+    let mut out = text_writer(options)?;
+    writeln!(out, "{}", answer.text)?;
+    out.flush()?;
+
+    Ok(RunOutcome { usage, cost, ..answer })
+}
+
+/// Runs the agent loop and, when `verify` is configured, has a judge score
+/// the answer, asking each of the fallback models in turn for the answer
+/// until one passes
+///
+/// A fallback model is asked for the last answer only, in the conversation
+/// the first model held, so the tools that were called aren't called again.
+/// While verifying, nothing is streamed: only the best answer is printed,
+/// once it is picked. Its usage and cost cover every attempt and judgement.
+async fn run_checked(
+    config: &Config,
+    messages: Vec<Message>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+    trace: &mut Trace,
+) -> AidoResult<RunOutcome> {
+    let Some(verify) = &config.verify else {
+        return Box::pin(run_loop(config, messages, tools, options, trace))
+            .await;
+    };
+
+    let mut quiet = options.clone();
+    quiet.callbacks.on_text = Some(Arc::new(|_: &str| {}));
+    // Only the answer that is picked belongs in the transcript
+    quiet.output_file = None;
+
+    let judge_config = Config {
+        model_name: verify
+            .judge_model
+            .clone()
+            .unwrap_or_else(|| config.model_name.clone()),
+        ..config.clone()
+    };
+    let judge = LlmClient::from_config(&judge_config);
+
+    let models =
+        std::iter::once(&config.model_name).chain(&config.fallback_models);
+    let (mut usage, mut cost) = (Usage::default(), None);
+    let mut best: Option<(u8, RunOutcome)> = None;
+
+    // The conversation up to the answer, once the first model has held it
+    let mut asked: Option<Vec<Message>> = None;
+    for model in models {
+        let config = Config { model_name: model.clone(), ..config.clone() };
+        let conversation = asked.clone().unwrap_or_else(|| messages.clone());
+        let outcome =
+            Box::pin(run_loop(&config, conversation, tools, &quiet, trace))
+                .await?;
+        usage += &outcome.usage;
+        cost = add_costs(cost, outcome.cost);
+        if outcome.cancelled {
+            best = Some((0, outcome));
+            break;
+        }
+        if asked.is_none() {
+            asked = outcome
+                .messages
+                .split_last()
+                .map(|(_answer, conversation)| conversation.to_vec());
+        }
+
+        options.spending().check(&config)?;
+        let (verdict, judge_usage) =
+            verify::judge(&judge, &messages, &outcome.text).await?;
+        options.spending().add(
+            &config,
+            &judge_config.model_name,
+            &judge_usage,
+        );
+        usage += &judge_usage;
+        cost = add_costs(
+            cost,
+            usage::estimate_cost(
+                &config.prices,
+                &judge_config.model_name,
+                &judge_usage,
+            ),
+        );
+
+        // An answer the judge couldn't score is taken as it is
+        let Some(verdict) = verdict else {
+            warn!("The judge's reply had no score; accepting the answer");
+            best = Some((u8::MAX, outcome));
+            break;
+        };
+        if best.as_ref().is_none_or(|(score, _)| verdict.score > *score) {
+            best = Some((verdict.score, outcome));
+        }
+        if verdict.score >= verify.threshold {
+            info!("{model} scored {}/10: {}", verdict.score, verdict.reason);
+            break;
+        }
+        warn!(
+            "The answer from {model} scored {}/10: {}",
+            verdict.score, verdict.reason
+        );
+    }
+
+    let (_, best) = best.expect("There is always at least one model");
+    let mut out = text_writer(options)?;
+    writeln!(out, "{}", best.text)?;
+    out.flush()?;
+
+    Ok(RunOutcome { usage, cost, ..best })
+}
+
+async fn run_loop(
+    config: &Config,
+    mut messages: Vec<Message>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+    trace: &mut Trace,
+) -> AidoResult<RunOutcome> {
+    let mut outcome = RunOutcome {
+        model: config.model_name.clone(),
+        ..RunOutcome::default()
+    };
 
-        writeln!(out)?;
-        out.flush()?;
+    let llm = llm_client(config, options);
 
-        if print_usage {
-            writeln!(out, "{:?}", response.usage())?;
+    let tool_definitions = options.tool_definitions(tools);
+
+    let iteration_limit = options.tool_iteration_limit();
+    let mut iterations = 0;
+    let mut loop_detector = LoopDetector::default();
+    let mut reasked = Reasked::default();
+
+    warn_unpriced_budget(config);
+    let spending = options.spending();
+    let mut out = text_writer(options)?;
+    let status = StatusLine::new(options.status_line);
+    'turns: loop {
+        spending.check(config)?;
+        if let Some(limit) = config.context_limit {
+            let usage =
+                fit_context(&llm, &mut messages, limit, &status).await?;
+            spending.add(config, &config.model_name, &usage);
+            outcome.usage += &usage;
         }
 
-        out.flush()?;
+        check_request_size(config, &messages, &tool_definitions)?;
+
+        let started = Instant::now();
+        let mut request =
+            new_request(messages.clone(), tool_definitions.clone(), options);
+        let held = reasked.may_reask(config, options);
+        let reply =
+            get_reply(&llm, &mut request, options, held, &mut out, &status);
+        let response = match reply.await? {
+            Reply::Complete(response) => response,
+            Reply::Cancelled(partial) => {
+                messages.push(Message::Assistant(partial.clone(), None));
+                outcome.cancel(partial);
+                break;
+            }
+        };
+
+        outcome.usage += response.usage();
+        spending.add(config, &config.model_name, response.usage());
+        trace.record(started, reply_event(&config.model_name, &response));
+
+        if options.print_usage {
+            write_usage(&mut out, config, &response)?;
+        }
 
         if response.tool_calls().is_empty() {
+            messages
+                .push(Message::Assistant(response.text().to_owned(), None));
+
+            if let Some(correction) = answer_correction(
+                config,
+                options,
+                response.text(),
+                &mut reasked,
+            )? {
+                messages.push(Message::User(correction));
+                continue;
+            }
+
+            if held {
+                writeln!(out, "{}", response.text())?;
+            }
+            response.text().clone_into(&mut outcome.text);
             break;
         }
 
-        // Add the response message to the messages:
-        {
-            let assistant_message = Message::Assistant(
-                response.text().to_owned(),
-                match response.tool_calls() {
-                    &[] => None,
-                    tool_calls => Some(tool_calls.to_vec()),
-                },
+        if iterations >= iteration_limit {
+            return Err(
+                RunError::IterationLimit { limit: iteration_limit }.into()
             );
-            messages.push(assistant_message);
         }
+        iterations += 1;
+
+        messages.push(Message::Assistant(
+            response.text().to_owned(),
+            Some(response.tool_calls().to_vec()),
+        ));
 
         info!("{:?}", response.tool_calls());
 
-        // Invoke the tool:
-        let tool_message = {
-            let first_tool = response.tool_calls().first().unwrap();
-            let matching_tool = tools
-                .iter()
-                .find(|t| t.definition().name() == first_tool.name())
-                .ok_or_else(|| {
-                    format!("Tool {} not found", first_tool.name())
-                })?;
+        // Every call is answered, one after the other, since APIs reject
+        // a conversation with a call left unanswered
+        let calls = response.tool_calls();
+        for (index, call) in calls.iter().enumerate() {
+            loop_detector.record(call)?;
 
-            let tool_output =
-                invoke_tool(matching_tool.as_ref(), first_tool.arguments())?;
+            let called = until_cancelled(
+                call_tool(tools, call, config, options, &status, trace),
+                &options.cancel,
+            )
+            .await;
 
-            Message::Tool {
-                content: tool_output,
-                id: first_tool.id().to_owned(),
-            }
-        };
+            let Some(tool_message) = called else {
+                for call in &calls[index..] {
+                    messages.push(Message::Tool {
+                        content: "Error: cancelled by the user".to_string(),
+                        id: call.id().to_owned(),
+                    });
+                }
+                outcome.cancel(String::new());
+                break 'turns;
+            };
+            messages.push(tool_message?);
+        }
+    }
 
-        // add a tool message
-        messages.push(tool_message);
+    outcome.cost =
+        usage::estimate_cost(&config.prices, &outcome.model, &outcome.usage);
+    outcome.messages = messages;
+
+    Ok(outcome)
+}
+
+/// The trace event of `response`, a reply from `model`
+fn reply_event(model: &str, response: &LlmResponse) -> TraceEventKind {
+    TraceEventKind::ModelReply {
+        model: model.to_owned(),
+        prompt_tokens: response.usage().prompt_tokens(),
+        completion_tokens: response.usage().completion_tokens(),
+        tool_calls: response.tool_calls().len(),
     }
+}
 
-    Ok(())
+/// Warns that the cost budget of a run can't be enforced if the model has
+/// no price
+fn warn_unpriced_budget(config: &Config) {
+    if config.max_cost_per_run.is_some()
+        && !config.prices.contains_key(&config.model_name)
+    {
+        warn!(
+            "max_cost_per_run can't be enforced: {} has no price under \
+             [prices]",
+            config.model_name
+        );
+    }
 }
 
-pub fn run_recipe(
-    config: Config,
-    recipes_dir: &Path,
-    recipe_name: &str,
-    user_message: Option<String>,
+/// Roughly estimates the tokens a message takes up in a request
+fn message_tokens(message: &Message) -> usize {
+    let content_tokens = match message {
+        Message::User(content)
+        | Message::System(content)
+        | Message::Tool { content, .. } => estimate_tokens(content),
+        Message::Assistant(content, tool_calls) => {
+            estimate_tokens(content)
+                + tool_calls
+                    .iter()
+                    .flatten()
+                    .map(|call| {
+                        estimate_tokens(call.name())
+                            + estimate_tokens(call.arguments())
+                    })
+                    .sum::<usize>()
+        }
+    };
+
+    content_tokens + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// Counts how many of the most recent messages fit in `budget` tokens
+fn recent_within(messages: &[Message], budget: usize) -> usize {
+    let mut remaining = budget;
+
+    messages
+        .iter()
+        .rev()
+        .take_while(|message| {
+            let tokens = message_tokens(message);
+            let fits = tokens <= remaining;
+            remaining = remaining.saturating_sub(tokens);
+            fits
+        })
+        .count()
+}
+
+/// The client a run talks to the model through
+fn llm_client(config: &Config, options: &RunOptions) -> LlmClient {
+    let llm = LlmClient::from_config(config);
+    if options.deterministic {
+        return llm.require_fingerprint();
+    }
+    match &options.cache {
+        Some(cache) => llm.with_cache(cache.clone()),
+        None => llm,
+    }
+}
+
+/// `config` with the request parameters of `options` applied, and the
+/// settings of deterministic runs if they are
+pub(crate) fn run_config<'a>(
+    config: &'a Config,
+    options: &RunOptions,
+) -> Cow<'a, Config> {
+    let mut config = Cow::Borrowed(config);
+    if !options.params.is_empty() {
+        options.params.apply_to(config.to_mut());
+    }
+    if options.deterministic {
+        config = Cow::Owned(deterministic_config(&config));
+    }
+
+    config
+}
+
+/// `config` with the settings of a deterministic run: greedy sampling,
+/// a fixed seed, and only the configured model
+fn deterministic_config(config: &Config) -> Config {
+    Config {
+        temperature: Some(0.0),
+        seed: Some(config.seed.unwrap_or(DETERMINISTIC_SEED)),
+        fallback_models: Vec::new(),
+        ..config.clone()
+    }
+}
+
+/// Fails when the request of `messages` and `tools` doesn't fit the
+/// context window of the model, if known, rather than having the provider
+/// reject it
+fn check_request_size(
+    config: &Config,
+    messages: &[Message],
+    tools: &[ToolDefinition],
+) -> Result<(), RunError> {
+    let Some(window) = limits::context_window(config, &config.model_name)
+    else {
+        return Ok(());
+    };
+    let tokens = TokenCount::of_request(&config.model_name, messages, tools);
+    let reserved = config.max_tokens.map_or(0, |max| max as usize);
+
+    match Oversize::check(tokens, window, reserved) {
+        Some(oversize) => Err(RunError::RequestTooLarge {
+            model: config.model_name.clone(),
+            tokens: oversize.tokens.total(),
+            window,
+            reserved,
+            breakdown: oversize.breakdown(),
+            advice: oversize.advice(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Replaces the older turns of `session` with a summary, keeping the
+/// `keep_recent` most recent messages, as a run with `options` would
+///
+/// The summary counts against the run's budget. Cancelling the run leaves
+/// `session` as it was and returns `None`.
+pub async fn compact_session(
+    config: &Config,
+    session: &mut session::Session,
+    keep_recent: usize,
+    options: &RunOptions,
+) -> AidoResult<Option<Usage>> {
+    let spending = options.spending();
+    spending.check(config)?;
+    let llm = llm_client(config, options);
+
+    let Some(usage) = until_cancelled(
+        session::compact(session, &llm, keep_recent),
+        &options.cancel,
+    )
+    .await
+    else {
+        return Ok(None);
+    };
+    let usage = usage?;
+    spending.add(config, &config.model_name, &usage);
+
+    Ok(Some(usage))
+}
+
+/// Keeps the conversation under `limit` estimated tokens by summarizing its
+/// oldest turns, returning the usage of the summary request
+///
+/// The most recent messages filling up to half of the limit are kept as-is
+/// so the model doesn't lose track of what it was just doing.
+async fn fit_context(
+    llm: &LlmClient,
+    messages: &mut Vec<Message>,
+    limit: usize,
+    status: &StatusLine,
+) -> AidoResult<Usage> {
+    let tokens = messages.iter().map(message_tokens).sum::<usize>();
+    if tokens <= limit {
+        return Ok(Usage::default());
+    }
+
+    let keep_recent = recent_within(messages, limit / 2);
+    let before = messages.len();
+    status.set("summarizing earlier turns");
+    let summarized =
+        session::summarize_old_turns(messages, llm, keep_recent).await;
+    status.clear();
+    let Some(usage) = summarized? else {
+        warn!(
+            "Conversation is ~{tokens} tokens, over the context limit of \
+             {limit}, but has no older turns to summarize"
+        );
+        return Ok(Usage::default());
+    };
+
+    info!(
+        "Summarized {} older messages to stay within the context limit of \
+         {limit} tokens",
+        before - messages.len() + 1
+    );
+
+    Ok(usage)
+}
+
+/// Prints the request that would start the run, for `--dry-run`
+fn print_request(
+    config: &Config,
+    messages: Vec<Message>,
     tools: &[Box<dyn Tool>],
-    print_usage: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let recipe = crate::recipe::get(recipes_dir, recipe_name)?;
+    options: &RunOptions,
+) -> AidoResult<RunOutcome> {
+    let tool_definitions = options.tool_definitions(tools);
+    let request = new_request(messages.clone(), tool_definitions, options);
+    let body = llm::LlmClient::from_config(config).request_body(&request)?;
 
-    info!("Running recipe: {}", recipe.header().name());
+    // The request is the answer, so structured output carries it as the
+    // text instead of it being printed alongside
+    let json = serde_json::to_string_pretty(&body)?;
+    let mut out = text_writer(options)?;
+    writeln!(out, "{json}")?;
+    out.flush()?;
+
+    Ok(RunOutcome {
+        model: config.model_name.clone(),
+        text: json,
+        messages,
+        ..RunOutcome::default()
+    })
+}
 
-    let messages = {
-        let mut messages = vec![Message::System(recipe.body().to_owned())];
+/// Where the streamed answer goes
+fn text_writer(options: &RunOptions) -> io::Result<Box<dyn Write + Send>> {
+    // Structured output and quietly streamed answers are written once the
+    // run is over, so nothing goes to stdout along the way
+    let writer: Box<dyn Write + Send> =
+        match (&options.callbacks.on_text, options.output.streams()) {
+            (Some(on_text), _) => {
+                Box::new(CallbackWriter(Arc::clone(on_text)))
+            }
+            (None, true) if options.quiet_stream => {
+                if io::stderr().is_terminal() {
+                    Box::new(io::stderr())
+                } else {
+                    Box::new(io::sink())
+                }
+            }
+            (None, true) => Box::new(io::BufWriter::new(io::stdout())),
+            (None, false) => Box::new(io::sink()),
+        };
 
-        if let Some(user_msg) = user_message {
-            messages.push(Message::User(user_msg));
+    match &options.output_file {
+        Some(file) if file.is_transcript() => {
+            Ok(Box::new(Tee(writer, io::BufWriter::new(file.append()?))))
         }
+        _ => Ok(writer),
+    }
+}
 
-        messages
-    };
+/// Writes everything to both of its writers, e.g. the terminal and a
+/// transcript file
+struct Tee<A, B>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
 
-    run(config, messages, tools, print_usage)
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
 }
 
-fn invoke_tool(
-    tool: &dyn Tool,
-    args: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    info!("Invoking tool: {}", tool.definition().name());
+/// A request for the next reply, in the format the options ask for
+fn new_request(
+    messages: Vec<Message>,
+    tools: Vec<ToolDefinition>,
+    options: &RunOptions,
+) -> LlmRequest {
+    let request = LlmRequest::new(messages, tools);
+    match &options.response_schema {
+        Some(schema) => {
+            request.with_response_format(ResponseFormat::JsonSchema {
+                name: schema::SCHEMA_NAME.to_owned(),
+                schema: schema.clone(),
+            })
+        }
+        None => request,
+    }
+}
 
-    let args_parsed = serde_json::from_str(args)?;
+/// Which kinds of correction were sent already; answers in the wrong
+/// language or shape are only sent back once
+#[derive(Debug, Default)]
+struct Reasked {
+    language: bool,
+    schema: bool,
+}
 
-    let output = tool.execute(args_parsed);
+impl Reasked {
+    /// Whether the next answer may still be sent back, in which case it
+    /// isn't printed before it is checked
+    fn may_reask(&self, config: &Config, options: &RunOptions) -> bool {
+        (config.output_language.is_some() && !self.language)
+            || options.response_schema.is_some()
+    }
+}
 
-    info!("Tool output: {output:?}");
+/// The message sending the final answer back to the model, if it has to
+/// be, failing when it still doesn't match the schema after a correction
+fn answer_correction(
+    config: &Config,
+    options: &RunOptions,
+    answer: &str,
+    reasked: &mut Reasked,
+) -> Result<Option<String>, RunError> {
+    if !reasked.language
+        && let Some(correction) = language_correction(config, answer)
+    {
+        reasked.language = true;
+        return Ok(Some(correction));
+    }
 
-    output
+    if let Some(schema) = &options.response_schema
+        && let Err(problems) = schema::check_answer(schema, answer)
+    {
+        if reasked.schema {
+            return Err(RunError::SchemaMismatch { problems });
+        }
+        warn!("The answer doesn't match the schema; asking again");
+        reasked.schema = true;
+        return Ok(Some(schema_correction(&problems)));
+    }
+
+    Ok(None)
+}
+
+/// The message asking for the answer again when it doesn't match the
+/// schema, saying why
+fn schema_correction(problems: &str) -> String {
+    format!(
+        "Your answer does not match the required JSON schema: {problems}. \
+         Reply again with only the corrected JSON."
+    )
+}
+
+/// The message asking for the answer again when `output_language` is set
+/// and the answer seems to be written in another language
+fn language_correction(config: &Config, answer: &str) -> Option<String> {
+    let language = config.output_language.as_deref()?;
+    if language::is_written_in(answer, language) {
+        return None;
+    }
+
+    warn!("The answer doesn't look like {language}; asking again");
+    Some(language::corrective_instruction(language))
+}
+
+/// Prints the token usage of one reply, with its cost when it is known
+fn write_usage(
+    out: &mut impl Write,
+    config: &Config,
+    reply: &LlmResponse,
+) -> io::Result<()> {
+    let reply_usage = reply.usage();
+    write!(out, "{reply_usage}")?;
+    if let Some(cost) =
+        usage::estimate_cost(&config.prices, &config.model_name, reply_usage)
+    {
+        write!(out, " (est. ${cost:.6})")?;
+    }
+    if let Some(timing) = reply.timing() {
+        write!(out, " in {:.2}s", timing.total.as_secs_f64())?;
+        if let Some(first_token) = timing.first_token {
+            write!(out, ", first token {:.2}s", first_token.as_secs_f64())?;
+        }
+        if let Some(rate) =
+            timing.tokens_per_second(reply_usage.completion_tokens())
+        {
+            write!(out, ", {rate:.1} tokens/s")?;
+        }
+    }
+    writeln!(out)?;
+    out.flush()
+}
+
+/// Prints part of a model's reasoning to stderr, dimmed on terminals
+fn write_reasoning(chunk: &str) {
+    let mut stderr = io::stderr().lock();
+    if stderr.is_terminal() {
+        write!(stderr, "\x1b[2m{chunk}\x1b[0m").ok();
+    } else {
+        write!(stderr, "{chunk}").ok();
+    }
+    stderr.flush().ok();
+}
+
+/// How a request to the model ended
+enum Reply {
+    Complete(LlmResponse),
+    /// Interrupted with Ctrl-C after streaming the given part of the reply
+    Cancelled(String),
+}
+
+/// Gets the model's reply to `request`, unless a middleware supplies one
+/// first, and has the middleware look at it
+///
+/// The reply is streamed to `out` as it comes, unless a middleware may
+/// rewrite its text, in which case it is written once the middleware has.
+/// An answer `held` back, as it may be sent back to the model, isn't
+/// written at all.
+async fn get_reply(
+    llm: &LlmClient,
+    request: &mut LlmRequest,
+    options: &RunOptions,
+    held: bool,
+    out: &mut (dyn Write + Send),
+    status: &StatusLine,
+) -> AidoResult<Reply> {
+    let buffered = held || options.middleware.rewrites_text();
+    let short_circuit = options.middleware.before_request(request)?;
+    let streamed = short_circuit.is_none() && !buffered;
+    let mut notices = Vec::new();
+    let reply = if let Some(response) = short_circuit {
+        Reply::Complete(response)
+    } else {
+        let mut partial = String::new();
+        let mut reasoning = false;
+        // Reported once the stream ends, which a callback can't cut short
+        let mut write_error = None;
+        status.set("connecting");
+        let response = until_cancelled(
+            Box::pin(llm.stream_chat_completion(
+                request,
+                |event| match event {
+                    StreamEvent::Started => {
+                        status.set("waiting for first token");
+                    }
+                    // The status line would erase text already on the line
+                    StreamEvent::ToolCall(name) if partial.is_empty() => {
+                        status.set(format!("writing tool call: {name}"));
+                    }
+                    StreamEvent::ToolCall(_) => {}
+                    // Printed once the reply is done, not in the middle of it
+                    StreamEvent::Notice(notice) => {
+                        notices.push(notice.clone());
+                    }
+                    StreamEvent::Reasoning(chunk) => {
+                        if !reasoning {
+                            reasoning = true;
+                            status.set("thinking");
+                        }
+                        if options.show_reasoning {
+                            status.clear();
+                            write_reasoning(chunk);
+                        }
+                    }
+                    StreamEvent::Chunk(chunk) => {
+                        if let Some(recorder) = &options.recorder {
+                            recorder.chunk(chunk);
+                        }
+                    }
+                    StreamEvent::Text(chunk) => {
+                        if partial.is_empty() {
+                            status.clear();
+                            if reasoning && options.show_reasoning {
+                                eprintln!("\n");
+                            }
+                        }
+                        partial.push_str(chunk);
+                        if streamed && write_error.is_none() {
+                            write_error = write!(out, "{chunk}")
+                                .and_then(|()| out.flush())
+                                .err();
+                        }
+                    }
+                },
+            )),
+            &options.cancel,
+        )
+        .await;
+        status.clear();
+        if let Some(e) = write_error {
+            return Err(e.into());
+        }
+
+        match response {
+            Some(response) => Reply::Complete(response?),
+            None => Reply::Cancelled(partial),
+        }
+    };
+
+    let reply = match reply {
+        Reply::Complete(mut response) => {
+            options.middleware.after_response(request, &mut response)?;
+            if let Some(recorder) = &options.recorder {
+                recorder.exchange(request, &response);
+            }
+            // An answer that may be sent back is printed by the caller once
+            // it passes
+            if !held || !response.tool_calls().is_empty() {
+                if !streamed {
+                    write!(out, "{}", response.text())?;
+                }
+                writeln!(out)?;
+            }
+            Reply::Complete(response)
+        }
+        cancelled @ Reply::Cancelled(_) => {
+            writeln!(out)?;
+            cancelled
+        }
+    };
+    out.flush()?;
+
+    for notice in &notices {
+        match &options.notices {
+            Some(log) => log.show(notice),
+            None => info!("{notice}"),
+        }
+    }
+
+    Ok(reply)
+}
+
+/// Awaits `future` unless `cancel` is cancelled first, in which case the
+/// future is dropped, cancelling whatever request or tool it was running
+async fn until_cancelled<F: Future>(
+    future: F,
+    cancel: &CancelToken,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        () = cancel.cancelled() => None,
+    }
+}
+
+pub async fn run_recipe(
+    config: Config,
+    recipes: &RecipeStore,
+    recipe_name: &str,
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+) -> AidoResult<RunOutcome> {
+    let recipe = recipes.get(recipe_name)?;
+    let mut options = options.clone();
+    options.reviewer =
+        Reviewer::for_recipe(&recipe, recipes, &config, &options)?
+            .map(Arc::new);
+
+    run_loaded_recipe(
+        config,
+        &recipe,
+        recipe_name,
+        user_message,
+        tools,
+        &options,
+    )
+    .await
+}
+
+/// Runs `recipe`, which was already loaded, under the name `recipe_name`
+///
+/// This is [`run_recipe`] for recipes that don't come from a
+/// [`RecipeStore`], such as the bundled ones. Their answers are reviewed
+/// by the reviewer in `options`, if any, rather than by the recipe their
+/// `verify` names.
+pub async fn run_loaded_recipe(
+    config: Config,
+    recipe: &Recipe,
+    recipe_name: &str,
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+) -> AidoResult<RunOutcome> {
+    let mut prepared =
+        prepare_recipe(config, recipe, recipe_name, user_message, options)?;
+    prepared.start().await?;
+
+    let messages = std::mem::take(&mut prepared.messages);
+    let outcome =
+        run(&prepared.config, messages, tools, &prepared.options).await?;
+    prepared.finish(&outcome).await?;
+    Ok(outcome)
+}
+
+/// Runs a recipe, then keeps the conversation going for as long as
+/// `on_answer` returns another message from the user
+///
+/// Every follow-up is answered with the recipe's system prompt, settings
+/// and tools. `on_answer` is called with the outcome of each answer; the
+/// returned outcome is that of the last one, except that its usage and
+/// cost cover the whole conversation. A cancelled answer or a dry run ends
+/// the conversation. Edits to the config file that
+/// [`RunOptions::config_watcher`] notices apply from the next answer on.
+pub async fn run_recipe_conversation(
+    config: Config,
+    recipes: &RecipeStore,
+    recipe_name: &str,
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+    mut on_answer: impl FnMut(&RunOutcome) -> io::Result<Option<String>>,
+) -> AidoResult<RunOutcome> {
+    let recipe = recipes.get(recipe_name)?;
+    let reviewer = Reviewer::for_recipe(&recipe, recipes, &config, options)?;
+    // Reloaded into between turns, with the recipe applied again on top
+    let mut global_config = config.clone();
+    let mut prepared =
+        prepare_recipe(config, &recipe, recipe_name, user_message, options)?;
+    prepared.options.reviewer = reviewer.map(Arc::new);
+    prepared.start().await?;
+
+    let mut messages = std::mem::take(&mut prepared.messages);
+    let mut conversation = RunOutcome::default();
+    loop {
+        let outcome = Box::pin(run(
+            &prepared.config,
+            messages,
+            tools,
+            &prepared.options,
+        ))
+        .await?;
+        prepared.finish(&outcome).await?;
+
+        let mut usage = std::mem::take(&mut conversation.usage);
+        usage += &outcome.usage;
+        let cost = add_costs(conversation.cost, outcome.cost);
+        conversation = RunOutcome { usage, cost, ..outcome };
+
+        if conversation.cancelled || options.dry_run {
+            break;
+        }
+        let Some(message) = on_answer(&conversation)? else {
+            break;
+        };
+        if let Some(watcher) = &options.config_watcher {
+            match watcher.reload_into(&mut global_config) {
+                Ok(changes) if !changes.applied.is_empty() => {
+                    let mut config = global_config.clone();
+                    apply_recipe_overrides(&mut config, recipe.header())?;
+                    prepared.config = config;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Keeping the config, which failed to reload: {e}");
+                }
+            }
+        }
+
+        messages = conversation.messages.clone();
+        messages.push(Message::User(message));
+    }
+
+    Ok(conversation)
+}
+
+/// The conversation a run of `recipe` would start with, the config it
+/// would run with and the tools of `tools` it would offer, without locking
+/// or sending anything
+pub fn recipe_request(
+    config: Config,
+    recipe: &Recipe,
+    recipe_name: &str,
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+) -> AidoResult<(Config, Vec<Message>, Vec<ToolDefinition>)> {
+    let options = RunOptions { dry_run: true, ..options.clone() };
+    let prepared =
+        prepare_recipe(config, recipe, recipe_name, user_message, &options)?;
+
+    let mut messages = prepared.messages;
+    frame_messages(&prepared.config, &mut messages, &prepared.options);
+    let definitions = prepared.options.tool_definitions(tools);
+
+    Ok((prepared.config, messages, definitions))
+}
+
+/// What a run of a recipe starts from, once the recipe is applied
+struct PreparedRecipe {
+    config: Config,
+    messages: Vec<Message>,
+    options: RunOptions,
+    /// Command whose output is added to the system prompt before the run
+    pre_hook: Option<String>,
+    /// Command the answer is piped into once it is in
+    post_hook: Option<String>,
+    /// Held until the run is over
+    _lock: Option<RunLock>,
+}
+
+impl PreparedRecipe {
+    /// Runs the recipe's pre-hook, adding its output to the system prompt,
+    /// in the worktree the run is isolated in, which is created for the
+    /// hooks to share if they need it
+    async fn start(&mut self) -> AidoResult<()> {
+        if self.options.dry_run {
+            return Ok(());
+        }
+        if self.options.isolated
+            && self.options.worktree.is_none()
+            && (self.pre_hook.is_some() || self.post_hook.is_some())
+        {
+            self.options.worktree = Some(Arc::new(Worktree::create()?));
+        }
+
+        let Some(command) = &self.pre_hook else {
+            return Ok(());
+        };
+        let output = hooks::run_pre_hook(&self.hook_env()?, command).await?;
+        let limit = self
+            .config
+            .attachment_token_limit
+            .unwrap_or(context::DEFAULT_ATTACHMENT_TOKEN_LIMIT);
+        let output = context::truncate_middle(&output, limit);
+        if let Some(Message::System(system_prompt)) = self.messages.first_mut()
+        {
+            hooks::add_context(system_prompt, command, &output);
+        }
+
+        Ok(())
+    }
+
+    /// Pipes the answer into the recipe's post-hook, unless there is no
+    /// complete answer to give it, and presents the changes made in the
+    /// worktree the hooks shared with the run
+    async fn finish(&self, outcome: &RunOutcome) -> AidoResult<()> {
+        if let Some(command) = &self.post_hook
+            && !outcome.cancelled
+            && !self.options.dry_run
+        {
+            hooks::run_post_hook(&self.hook_env()?, command, &outcome.text)
+                .await?;
+        }
+        if let Some(worktree) = &self.options.worktree {
+            worktree.present_diff(&mut text_writer(&self.options)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Where the recipe's hooks run: where its tools do
+    fn hook_env(&self) -> io::Result<hooks::HookEnv<'_>> {
+        Ok(hooks::HookEnv {
+            exec: &self.config.exec,
+            working_dir: self.options.tool_context()?.working_dir,
+            audit: self.options.audit.as_ref(),
+        })
+    }
+}
+
+/// Takes the lock of `recipe` and approves its hooks, then applies it to
+/// the config and options and builds the conversation it starts
+fn prepare_recipe(
+    config: Config,
+    recipe: &Recipe,
+    recipe_name: &str,
+    user_message: Option<String>,
+    options: &RunOptions,
+) -> AidoResult<PreparedRecipe> {
+    // Dry runs change nothing
+    let lock = match recipe.header().lock() {
+        Some(scope) if !options.dry_run => {
+            Some(RunLock::for_recipe(scope, recipe_name)?)
+        }
+        _ => None,
+    };
+
+    match recipe.header().pre_hook() {
+        Some(command) if options.dry_run => {
+            eprintln!("Note: the pre-hook `{command}` isn't run in dry runs");
+        }
+        _ if !options.dry_run => {
+            hooks::approve(recipe, |question| {
+                options.callbacks.on_confirm.as_ref().map_or_else(
+                    || confirm::ask_on_terminal(question),
+                    |on_confirm| Ok(on_confirm(question)),
+                )
+            })?;
+        }
+        _ => {}
+    }
+
+    let prepared =
+        prepare_request(config, recipe, recipe_name, user_message, options)?;
+    Ok(PreparedRecipe { _lock: lock, ..prepared })
+}
+
+/// Applies `recipe` to `config` and `options`, and makes the conversation
+/// a run of it starts with, without taking its lock or approving its hooks
+fn prepare_request(
+    mut config: Config,
+    recipe: &Recipe,
+    recipe_name: &str,
+    user_message: Option<String>,
+    options: &RunOptions,
+) -> AidoResult<PreparedRecipe> {
+    info!("Running recipe: {}", recipe.header().name());
+
+    if options.strict || config.strict {
+        recipe.check_header(recipe_name)?;
+    } else {
+        for problem in recipe.header_problems() {
+            eprintln!("Warning: recipe '{recipe_name}': {problem}");
+        }
+    }
+    recipe.header().check_requirements()?;
+
+    apply_recipe_overrides(&mut config, recipe.header())?;
+
+    let attachment_limit = config
+        .attachment_token_limit
+        .unwrap_or(context::DEFAULT_ATTACHMENT_TOKEN_LIMIT);
+    let user_message = match user_message {
+        None if recipe.header().reads_stdin() => {
+            read_piped_stdin()?.map(|input| {
+                context::truncate_middle(&input, attachment_limit).into_owned()
+            })
+        }
+        user_message => user_message,
+    };
+
+    let mut system_prompt = recipe.render(&options.vars)?;
+    if !options.skip_project_context && config.project_context != Some(false) {
+        for file in project::find(&std::env::current_dir()?) {
+            let content =
+                context::truncate_middle(&file.content, attachment_limit);
+            project::add_to_prompt(&mut system_prompt, &file.path, &content);
+        }
+    }
+
+    let mut messages = vec![Message::System(system_prompt)];
+    if let Some(user_msg) = user_message {
+        messages.push(Message::User(user_msg));
+    }
+
+    // The recipe's confirmation requirements apply on top of whatever
+    // hooks the caller registered
+    let mut options = options.clone();
+    let examples = recipe.header().examples();
+    if let Some(pruned) =
+        insert_examples(examples, &mut messages, config.context_limit)
+    {
+        options.preparation.push(pruned);
+    }
+    options.copy_result |= recipe.header().copy_result();
+    options.check_command |= recipe.header().shell_command();
+    let allowed = recipe.header().allowed_tools();
+    if !allowed.is_empty() {
+        // A workflow step limited to some tools narrows the recipe's list
+        options.allowed_tools =
+            Some(options.allowed_tools.take().map_or_else(
+                || allowed.to_vec(),
+                |limited| {
+                    limited
+                        .into_iter()
+                        .filter(|tool| allowed.contains(tool))
+                        .collect()
+                },
+            ));
+    }
+    if let Some(schema) = recipe.header().schema() {
+        options.response_schema = Some(schema.clone());
+    }
+    let policy = recipe.header().confirm().clone();
+    if !policy.is_never() {
+        let confirm = match &options.callbacks.on_confirm {
+            Some(on_confirm) => {
+                let on_confirm = Arc::clone(on_confirm);
+                Confirm::with_prompt(policy, move |question| {
+                    Ok(on_confirm(question))
+                })
+            }
+            None => Confirm::new(policy),
+        };
+        options.middleware.push_tool_hook(confirm);
+    }
+
+    Ok(PreparedRecipe {
+        config,
+        messages,
+        options,
+        pre_hook: recipe.header().pre_hook().map(str::to_owned),
+        post_hook: recipe.header().post_hook().map(str::to_owned),
+        _lock: None,
+    })
+}
+
+/// Inserts the `examples` between the system message and the user's input,
+/// leaving out those that don't fit in `context_limit` next to the rest of
+/// `messages`, and returns the event recording how many were left out
+fn insert_examples(
+    examples: &[Example],
+    messages: &mut Vec<Message>,
+    context_limit: Option<usize>,
+) -> Option<TraceEventKind> {
+    let example_tokens = |example: &Example| {
+        example.messages().iter().map(message_tokens).sum::<usize>()
+    };
+    let used = messages.iter().map(message_tokens).sum::<usize>();
+    let budget =
+        context_limit.map_or(usize::MAX, |limit| limit.saturating_sub(used));
+    let kept = examples::fit(examples, budget, example_tokens);
+
+    messages.splice(1..1, kept.iter().flat_map(|example| example.messages()));
+
+    (kept.len() < examples.len()).then(|| TraceEventKind::ExamplesPruned {
+        dropped: examples.len() - kept.len(),
+        total: examples.len(),
+    })
+}
+
+/// Runs each recipe in turn, giving every recipe after the first the answer
+/// of the one before it as its user message
+///
+/// `on_step` is called with the name and outcome of each recipe as it
+/// finishes. The returned outcome is that of the last recipe, except that
+/// its usage and cost cover the whole chain. A cancelled recipe ends the
+/// chain early.
+pub async fn run_chain(
+    config: &Config,
+    recipes: &RecipeStore,
+    recipe_names: &[String],
+    user_message: Option<String>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+    mut on_step: impl FnMut(&str, &RunOutcome),
+) -> AidoResult<RunOutcome> {
+    let mut chained = RunOutcome::default();
+    let mut user_message = user_message;
+
+    for recipe_name in recipe_names {
+        let outcome = Box::pin(run_recipe(
+            config.clone(),
+            recipes,
+            recipe_name,
+            user_message.take(),
+            tools,
+            options,
+        ))
+        .await?;
+        on_step(recipe_name, &outcome);
+
+        let mut usage = std::mem::take(&mut chained.usage);
+        usage += &outcome.usage;
+        let cost = add_costs(chained.cost, outcome.cost);
+        chained = RunOutcome { usage, cost, ..outcome };
+
+        // Later recipes would need the answer a dry run never gets
+        if chained.cancelled || options.dry_run {
+            break;
+        }
+        user_message = Some(chained.text.clone());
+    }
+
+    Ok(chained)
+}
+
+/// Adds up costs, treating an unknown cost as zero unless both are unknown
+pub(crate) fn add_costs(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+    }
+}
+
+/// Reads all of standard input when it is piped in rather than typed at a
+/// terminal, returning `None` when there is nothing to read
+fn read_piped_stdin() -> io::Result<Option<String>> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
+
+    let mut input = String::new();
+    stdin.lock().read_to_string(&mut input)?;
+    let input = input.trim_end();
+
+    Ok((!input.is_empty()).then(|| input.to_owned()))
+}
+
+/// Applies the model settings declared in a recipe header on top of the
+/// global config, failing if its `sandbox_root` would widen the one tools
+/// are confined to
+fn apply_recipe_overrides(
+    config: &mut Config,
+    header: &Header,
+) -> Result<(), RecipeError> {
+    if let Some(model) = header.model() {
+        model.clone_into(&mut config.model_name);
+    }
+    header.params().apply_to(config);
+    if let Some(language) = header.output_language() {
+        config.output_language = Some(language.to_owned());
+    }
+    if !header.prelude() {
+        config.system_prompt_prelude = None;
+    }
+    for (tool, template) in header.tool_format() {
+        config.tools.format.insert(tool.clone(), template.clone());
+    }
+    if let Some(root) = header.sandbox_root() {
+        sandbox::check_narrower_root(config, &std::env::current_dir()?, root)
+            .map_err(|reason| RecipeError::InvalidSandboxRoot {
+                root: root.to_owned(),
+                reason,
+            })?;
+        config.tools.sandbox_root = Some(root.to_owned());
+    }
+
+    Ok(())
+}
+
+/// Adds what every run adds to the system prompt: the configured prelude
+/// and, for bare and command output, the instruction to answer without a
+/// preamble
+pub(crate) fn frame_messages(
+    config: &Config,
+    messages: &mut Vec<Message>,
+    options: &RunOptions,
+) {
+    prepend_prelude(config.system_prompt_prelude.as_deref(), messages);
+    if matches!(options.output, OutputFormat::Bare | OutputFormat::Command) {
+        append_instruction(preamble::INSTRUCTION, messages);
+    }
+}
+
+/// Puts the configured prelude in front of the system prompt, adding a
+/// system prompt if the conversation has none
+fn prepend_prelude(prelude: Option<&str>, messages: &mut Vec<Message>) {
+    let Some(prelude) = prelude.map(str::trim).filter(|p| !p.is_empty())
+    else {
+        return;
+    };
+
+    match messages.first_mut() {
+        // A resumed conversation may already start with it
+        Some(Message::System(content)) if content.starts_with(prelude) => {}
+        Some(Message::System(content)) => {
+            *content = format!("{prelude}\n\n{content}");
+        }
+        _ => messages.insert(0, Message::System(prelude.to_owned())),
+    }
+}
+
+/// Puts `instruction` at the end of the system prompt, adding a system
+/// prompt if the conversation has none
+fn append_instruction(instruction: &str, messages: &mut Vec<Message>) {
+    match messages.first_mut() {
+        // A resumed conversation may already end with it
+        Some(Message::System(content)) if content.ends_with(instruction) => {}
+        Some(Message::System(content)) => {
+            *content = format!("{content}\n\n{instruction}");
+        }
+        _ => messages.insert(0, Message::System(instruction.to_owned())),
+    }
+}
+
+/// Runs the tool requested by `call` and wraps its output in a tool message
+async fn call_tool(
+    tools: &[Box<dyn Tool>],
+    call: &ToolCall,
+    config: &Config,
+    options: &RunOptions,
+    status: &StatusLine,
+    trace: &mut Trace,
+) -> AidoResult<Message> {
+    let offered =
+        tools.iter().filter(|t| options.allows_tool(t.definition().name()));
+    // A made-up or disallowed tool is pointed out to the model, which can
+    // call one of the others instead
+    let Some(matching_tool) =
+        offered.clone().find(|t| t.definition().name() == call.name())
+    else {
+        let available =
+            offered.map(|t| t.definition().name()).collect::<Vec<_>>();
+        let message = format!(
+            "Error: there is no tool '{}'. The tools available are: {}",
+            call.name(),
+            available.join(", ")
+        );
+        warn!("{message}");
+        trace.record(
+            Instant::now(),
+            TraceEventKind::ToolCall {
+                name: call.name().to_owned(),
+                arguments: call.arguments().to_owned(),
+                failed: true,
+            },
+        );
+        return Ok(Message::Tool {
+            content: message,
+            id: call.id().to_owned(),
+        });
+    };
+
+    // Arguments that can't be parsed even after repair, or don't match the
+    // tool's parameters, and tools that fail are reported back to the
+    // model so it can retry, rather than ending the run
+    let started = Instant::now();
+    let tool_output =
+        match parse_tool_arguments(call, matching_tool.definition()) {
+            Ok(input) => {
+                invoke_tool_with_hooks(
+                    matching_tool.as_ref(),
+                    input,
+                    options,
+                    status,
+                    config,
+                )
+                .await
+            }
+            Err(message) => Ok(Err(message)),
+        };
+    trace.record(
+        started,
+        TraceEventKind::ToolCall {
+            name: call.name().to_owned(),
+            arguments: call.arguments().to_owned(),
+            failed: !matches!(tool_output, Ok(Ok(_))),
+        },
+    );
+
+    let content = tool_output?.unwrap_or_else(|message| {
+        warn!("{message}");
+        message
+    });
+    Ok(Message::Tool { content, id: call.id().to_owned() })
+}
+
+/// Parses the arguments of a tool call, repairing slightly malformed JSON,
+/// and checks them against the parameters in `definition`. On failure,
+/// returns an error message meant for the model.
+fn parse_tool_arguments(
+    call: &ToolCall,
+    definition: &ToolDefinition,
+) -> Result<ToolInput, String> {
+    let input: ToolInput = json_repair::parse_lenient(call.arguments())
+        .map_err(|e| match e {
+            json_repair::ParseError::Truncated { .. } => format!(
+                "Error: the arguments for tool '{}' are incomplete ({e}), \
+                 so the tool was not run. Retry the call with complete \
+                 arguments, making them shorter if they were cut off by \
+                 the length limit.",
+                call.name()
+            ),
+            json_repair::ParseError::Invalid(_) => format!(
+                "Error: the arguments for tool '{}' are not valid JSON \
+                 ({e}). Retry the call with a valid JSON object as \
+                 arguments.",
+                call.name()
+            ),
+        })?;
+
+    // Optional arguments given as null count as left out, as tools take
+    // them
+    let given: serde_json::Map<_, _> = input
+        .iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    let problems = schema::violations(&definition.json_value(), &given.into());
+    if !problems.is_empty() {
+        let problems = problems
+            .iter()
+            .map(|problem| format!("- {problem}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err(format!(
+            "Error: the arguments for tool '{}' don't match its \
+             parameters:\n{problems}\nRetry the call with arguments that \
+             do.",
+            call.name()
+        ));
+    }
+
+    Ok(input)
+}
+
+/// Runs a tool, giving the registered tool hooks a chance to rewrite its
+/// input, deny the call, or rewrite its output, and records the invocation
+/// in the audit log
+///
+/// A failure of the tool itself is the inner error, a message meant for
+/// the model; only failing hooks end the run.
+async fn invoke_tool_with_hooks(
+    tool: &dyn Tool,
+    mut input: ToolInput,
+    options: &RunOptions,
+    status: &StatusLine,
+    config: &Config,
+) -> AidoResult<Result<String, String>> {
+    let (middleware, audit) = (&options.middleware, options.audit.as_ref());
+    let context = options.tool_context()?;
+    let mut decision = middleware.before_tool(tool, &mut input)?;
+    // Checked after the hooks, which may have changed the arguments
+    if matches!(decision, ToolDecision::Allow)
+        && let Err(reason) = sandbox::check(
+            tool.definition(),
+            &input,
+            config,
+            &context.working_dir,
+        )
+    {
+        decision = ToolDecision::Deny(reason);
+    }
+    if let ToolDecision::Deny(reason) = decision {
+        info!("Tool {} denied: {reason}", tool.definition().name());
+        let message = format!(
+            "Error: the call to tool '{}' was denied: {reason}",
+            tool.definition().name()
+        );
+        record_audit(audit, tool, &input, AuditStatus::Denied, &message);
+        return Ok(Ok(message));
+    }
+
+    // Set only now, since hooks may ask the user for confirmation
+    status.set(format!(
+        "executing tool: {}",
+        status::describe_tool_call(tool.definition().name(), &input)
+    ));
+    let max_output_bytes =
+        config.max_tool_output_bytes.unwrap_or(DEFAULT_MAX_TOOL_OUTPUT_BYTES);
+    let echo = options.show_tool_output.then(|| {
+        process::Echo::new(status.printer(), tool.definition().name())
+    });
+    let output = process::echoing(
+        echo,
+        invoke_tool(tool, input.clone(), &context, max_output_bytes),
+    )
+    .await;
+    status.clear();
+    match &output {
+        Ok(output) => {
+            record_audit(audit, tool, &input, AuditStatus::Ok, output);
+        }
+        Err(e) => {
+            let error = e.to_string();
+            record_audit(audit, tool, &input, AuditStatus::Error, &error);
+        }
+    }
+
+    let name = tool.definition().name();
+    let mut output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            return Ok(Err(format!("Error: the tool '{name}' failed: {e}")));
+        }
+    };
+    middleware.after_tool(tool, &input, &mut output)?;
+
+    if let Some(template) = format::template_for(&config.tools.format, name) {
+        output = format::apply(template, name, &input, &output);
+    }
+
+    Ok(Ok(output))
+}
+
+/// Appends a tool invocation to the audit log, if there is one; failing to
+/// write it doesn't stop the run
+fn record_audit(
+    audit: Option<&AuditLog>,
+    tool: &dyn Tool,
+    input: &ToolInput,
+    status: AuditStatus,
+    output: &str,
+) {
+    let Some(audit) = audit else {
+        return;
+    };
+
+    let entry =
+        AuditEntry::new(tool.definition().name(), input, status, output);
+    if let Err(e) = audit.record(&entry) {
+        warn!("Failed to record the tool call in the audit log: {e}");
+    }
+}
+
+async fn invoke_tool(
+    tool: &dyn Tool,
+    input: ToolInput,
+    context: &ToolContext,
+    max_output_bytes: usize,
+) -> AidoResult<String> {
+    info!("Invoking tool: {}", tool.definition().name());
+
+    let output = tool
+        .execute(input, context)
+        .await
+        .map(|output| truncate_output(output, max_output_bytes));
+
+    info!("Tool output: {output:?}");
+
+    output
+}
+
+/// Cuts `output` down to its first `max_bytes` bytes, ending at a line
+/// break when there is one in the second half, and notes how much was
+/// left out
+fn truncate_output(mut output: String, max_bytes: usize) -> String {
+    if output.len() <= max_bytes {
+        return output;
+    }
+
+    let mut end = max_bytes;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    if let Some(line_end) = output[..end].rfind('\n')
+        && line_end >= end / 2
+    {
+        end = line_end + 1;
+    }
+
+    let omitted = output.len() - end;
+    output.truncate(end);
+    let separator =
+        if output.is_empty() || output.ends_with('\n') { "" } else { "\n" };
+
+    format!("{output}{separator}[output truncated, {omitted} bytes omitted]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{Arg, ArgType, ToolDefinitionBuilder};
+
+    fn tool_call(name: &str, arguments: &str) -> ToolCall {
+        ToolCall::new("call_1", name, arguments)
+    }
+
+    #[test]
+    fn test_loop_detector_allows_distinct_calls() {
+        let mut detector = LoopDetector::default();
+
+        for i in 0..10 {
+            let args = format!(r#"{{"args": "{i}"}}"#);
+            assert!(detector.record(&tool_call("ls", &args)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_loop_detector_stops_identical_calls() {
+        let mut detector = LoopDetector::default();
+        let call = tool_call("ls", r#"{"args": "-al"}"#);
+
+        assert!(detector.record(&call).is_ok());
+        assert!(detector.record(&call).is_ok());
+
+        let result = detector.record(&call);
+        assert!(matches!(
+            result,
+            Err(RunError::RepeatedToolCall { count: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_loop_detector_resets_on_different_call() {
+        let mut detector = LoopDetector::default();
+        let call = tool_call("ls", r#"{"args": "-al"}"#);
+        let other = tool_call("ls", r#"{"args": "-h"}"#);
+
+        assert!(detector.record(&call).is_ok());
+        assert!(detector.record(&call).is_ok());
+        assert!(detector.record(&other).is_ok());
+        assert!(detector.record(&call).is_ok());
+        assert!(detector.record(&call).is_ok());
+    }
+
+    #[test]
+    fn test_apply_recipe_overrides() {
+        let mut config = Config {
+            model_name: "global-model".to_string(),
+            temperature: Some(0.7),
+            ..Config::default()
+        };
+        config.tools.format.insert("ls".to_owned(), "{{output}}".to_owned());
+        config.tools.format.insert("*".to_owned(), "{{call}}".to_owned());
+        let header: Header = serde_yaml::from_str(
+            "model: recipe-model\nmax_tokens: 100\n\
+             tool_format: { ls: '$ {{call}}' }",
+        )
+        .unwrap();
+
+        apply_recipe_overrides(&mut config, &header).unwrap();
+
+        assert_eq!(config.model_name, "recipe-model");
+        assert_eq!(config.temperature, Some(0.7));
+        assert_eq!(config.max_tokens, Some(100));
+        assert_eq!(config.tools.format["ls"], "$ {{call}}");
+        assert_eq!(config.tools.format["*"], "{{call}}");
+    }
+
+    #[test]
+    fn test_recipe_sandbox_root_can_only_narrow() {
+        let header = |root: &str| -> Header {
+            serde_yaml::from_str(&format!("sandbox_root: {root}")).unwrap()
+        };
+
+        let mut config = Config::default();
+        apply_recipe_overrides(&mut config, &header("src")).unwrap();
+        assert_eq!(
+            config.tools.sandbox_root,
+            Some(std::path::PathBuf::from("src"))
+        );
+
+        for widening in ["/", "..", "src/../.."] {
+            let mut config = Config::default();
+            let error = apply_recipe_overrides(&mut config, &header(widening))
+                .unwrap_err();
+            assert!(
+                matches!(error, RecipeError::InvalidSandboxRoot { .. }),
+                "{widening}: {error}"
+            );
+            assert_eq!(config.tools.sandbox_root, None);
+        }
+
+        // Nor past a narrower root in the config
+        let mut config = Config::default();
+        config.tools.sandbox_root = Some(std::path::PathBuf::from("src"));
+        assert!(apply_recipe_overrides(&mut config, &header(".")).is_err());
+    }
+
+    #[test]
+    fn test_run_config() {
+        let config = Config {
+            temperature: Some(0.7),
+            top_p: Some(0.9),
+            ..Config::default()
+        };
+        let mut options = RunOptions::default();
+
+        assert!(matches!(run_config(&config, &options), Cow::Borrowed(_)));
+
+        options.params = RequestParams {
+            temperature: Some(0.1),
+            stop: Some(vec!["END".to_owned()]),
+            ..RequestParams::default()
+        };
+        let overridden = run_config(&config, &options);
+        assert_eq!(overridden.temperature, Some(0.1));
+        assert_eq!(overridden.top_p, Some(0.9));
+        assert_eq!(overridden.stop, ["END"]);
+
+        options.deterministic = true;
+        assert_eq!(run_config(&config, &options).temperature, Some(0.0));
+    }
+
+    #[test]
+    fn test_prepend_prelude() {
+        let mut messages = vec![Message::User("hi".to_string())];
+        prepend_prelude(Some("Be brief."), &mut messages);
+        assert_eq!(messages[0], Message::System("Be brief.".to_string()));
+
+        let mut messages = vec![Message::System("You are aido.".to_string())];
+        prepend_prelude(Some("Be brief."), &mut messages);
+        prepend_prelude(Some("Be brief."), &mut messages);
+        assert_eq!(
+            messages,
+            [Message::System("Be brief.\n\nYou are aido.".to_string())]
+        );
+
+        prepend_prelude(None, &mut messages);
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn test_append_instruction() {
+        let mut messages = vec![Message::System("You are aido.".to_string())];
+        append_instruction("No preamble.", &mut messages);
+        append_instruction("No preamble.", &mut messages);
+        assert_eq!(
+            messages,
+            [Message::System("You are aido.\n\nNo preamble.".to_string())]
+        );
+
+        let mut messages = vec![Message::User("hi".to_string())];
+        append_instruction("No preamble.", &mut messages);
+        assert_eq!(messages[0], Message::System("No preamble.".to_string()));
+    }
+
+    #[test]
+    fn test_truncate_output() {
+        assert_eq!(truncate_output("short".to_string(), 10), "short");
+
+        let output = "line one\nline two\nline three\n".to_string();
+        assert_eq!(
+            truncate_output(output, 20),
+            "line one\nline two\n[output truncated, 11 bytes omitted]"
+        );
+
+        // Never splits a character, even without a line break to cut at
+        let output = "ééééé".to_string();
+        assert_eq!(
+            truncate_output(output, 5),
+            "éé\n[output truncated, 6 bytes omitted]"
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_repairs_json() {
+        let call = tool_call("ls", "{'args': '-al',}");
+        let ls =
+            ToolDefinitionBuilder::new("ls").arg(Arg::new("args")).build();
+
+        let input = parse_tool_arguments(&call, &ls).unwrap();
+
+        assert_eq!(input["args"], "-al");
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_reports_error_to_model() {
+        let call = tool_call("ls", "not json at all");
+        let ls = ToolDefinitionBuilder::new("ls").build();
+
+        let message = parse_tool_arguments(&call, &ls).unwrap_err();
+
+        assert!(message.contains("arguments for tool 'ls' are not valid"));
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_refuses_truncated_json() {
+        let call = tool_call("ls", r#"{"path": "src/ma"#);
+        let ls =
+            ToolDefinitionBuilder::new("ls").arg(Arg::new("path")).build();
+
+        let message = parse_tool_arguments(&call, &ls).unwrap_err();
+
+        assert!(
+            message.contains("arguments for tool 'ls' are incomplete"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_parse_tool_arguments_checks_parameters() {
+        let definition = ToolDefinitionBuilder::new("ps")
+            .arg(Arg::new("name").required())
+            .arg(Arg::new("count").kind(ArgType::Integer))
+            .arg(Arg::new("sort").with_enum(["memory", "cpu"]))
+            .build();
+        let check = |arguments: &str| {
+            parse_tool_arguments(&tool_call("ps", arguments), &definition)
+        };
+
+        assert!(check(r#"{"name": "x", "count": 3, "sort": null}"#).is_ok());
+        assert_eq!(
+            check(r#"{"name": null, "count": "3", "sort": "disk"}"#)
+                .unwrap_err(),
+            "Error: the arguments for tool 'ps' don't match its parameters:\n\
+             - $: missing required property 'name'\n\
+             - $.count: expected integer, got string\n\
+             - $.sort: must be one of [\"memory\",\"cpu\"]\n\
+             Retry the call with arguments that do."
+        );
+    }
+
+    #[test]
+    fn test_run_options_cli_overrides_config() {
+        let config =
+            Config { max_tool_iterations: Some(5), ..Config::default() };
+
+        let options = RunOptions::new(&config, false, Some(2));
+        assert_eq!(options.tool_iteration_limit(), 2);
+
+        let options = RunOptions::new(&config, false, None);
+        assert_eq!(options.tool_iteration_limit(), 5);
+
+        let options = RunOptions::new(&Config::default(), false, None);
+        assert_eq!(
+            options.tool_iteration_limit(),
+            DEFAULT_MAX_TOOL_ITERATIONS
+        );
+    }
+
+    #[test]
+    fn test_message_tokens_counts_tool_calls() {
+        let plain = Message::Assistant("abcd".to_string(), None);
+        let with_call = Message::Assistant(
+            "abcd".to_string(),
+            Some(vec![tool_call("ls", r#"{"args": "-al"}"#)]),
+        );
+
+        assert_eq!(message_tokens(&plain), 1 + MESSAGE_OVERHEAD_TOKENS);
+        assert_eq!(message_tokens(&with_call), 6 + MESSAGE_OVERHEAD_TOKENS);
+    }
+
+    #[test]
+    fn test_recent_within() {
+        let messages = vec![
+            Message::User("a".repeat(400)),
+            Message::Assistant("b".repeat(40), None),
+            Message::User("c".repeat(40)),
+        ];
+
+        // The last two messages take 14 tokens each
+        assert_eq!(recent_within(&messages, 10), 0);
+        assert_eq!(recent_within(&messages, 28), 2);
+        assert_eq!(recent_within(&messages, 1_000), 3);
+    }
+
+    #[test]
+    fn test_add_costs() {
+        assert_eq!(add_costs(None, None), None);
+        assert_eq!(add_costs(Some(0.5), None), Some(0.5));
+        assert_eq!(add_costs(Some(0.5), Some(0.25)), Some(0.75));
+    }
+
+    #[test]
+    fn test_tee_writes_to_both() {
+        let mut tee = Tee(Vec::new(), Vec::new());
+        write!(tee, "hello {}", 42).unwrap();
+        tee.flush().unwrap();
+
+        assert_eq!(tee.0, b"hello 42");
+        assert_eq!(tee.1, b"hello 42");
+    }
+
+    #[tokio::test]
+    async fn test_until_cancelled_passes_through_output() {
+        let cancel = CancelToken::new();
+        assert_eq!(until_cancelled(async { 42 }, &cancel).await, Some(42));
+
+        cancel.cancel();
+        assert_eq!(
+            until_cancelled(std::future::pending::<()>(), &cancel).await,
+            None
+        );
+    }
+
+    #[test]
+    fn test_cancel_keeps_partial_answer() {
+        let mut outcome = RunOutcome::default();
+
+        outcome.cancel("The files are".to_string());
+
+        assert!(outcome.cancelled);
+        assert_eq!(outcome.text, "The files are");
+    }
+
+    #[test]
+    fn test_schema_mismatch_is_sent_back_once() {
+        let options = RunOptions {
+            response_schema: Some(serde_json::json!({
+                "type": "object",
+                "required": ["files"],
+            })),
+            ..RunOptions::default()
+        };
+        let mut reasked = Reasked::default();
+        let config = Config::default();
+
+        let correction =
+            answer_correction(&config, &options, "{}", &mut reasked).unwrap();
+        assert!(
+            correction
+                .unwrap()
+                .contains("$: missing required property 'files'")
+        );
+
+        assert!(matches!(
+            answer_correction(&config, &options, "{}", &mut reasked),
+            Err(RunError::SchemaMismatch { .. })
+        ));
+        assert!(
+            answer_correction(
+                &config,
+                &options,
+                r#"{"files": []}"#,
+                &mut reasked
+            )
+            .unwrap()
+            .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conversation_ends_after_dry_run() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-conversation-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("chat.recipe"), "Be helpful.").unwrap();
+        let options = RunOptions {
+            dry_run: true,
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let outcome = run_recipe_conversation(
+            Config::default(),
+            &RecipeStore::new(&dir),
+            "chat",
+            Some("hello".to_owned()),
+            &[],
+            &options,
+            |_| panic!("A dry run never gets an answer to follow up on"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(outcome.messages.len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_prints_request() {
+        let config = Config {
+            model_name: "test-model".to_owned(),
+            system_prompt_prelude: Some("Be brief.".to_owned()),
+            ..Config::default()
+        };
+        let printed = Arc::new(std::sync::Mutex::new(String::new()));
+        let sink = Arc::clone(&printed);
+        let options = RunOptions {
+            dry_run: true,
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(move |text| {
+                    sink.lock().unwrap().push_str(text);
+                })),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+        let tools: Vec<Box<dyn Tool>> =
+            vec![Box::new(crate::tools::Search::new())];
+
+        let messages = vec![Message::User("hello".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        let body: serde_json::Value =
+            serde_json::from_str(&printed.lock().unwrap()).unwrap();
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["messages"][0]["content"], "Be brief.");
+        assert_eq!(body["messages"][1]["content"], "hello");
+        assert_eq!(body["tools"][0]["function"]["name"], "search");
+        assert_eq!(outcome.messages.len(), 2);
+        assert_eq!(outcome.text, printed.lock().unwrap().trim_end());
+    }
+
+    #[test]
+    fn test_allowed_tools() {
+        let tools: Vec<Box<dyn Tool>> =
+            vec![Box::new(crate::tools::Search::new())];
+        let names = |allowed_tools: Option<Vec<String>>| {
+            let options =
+                RunOptions { allowed_tools, ..RunOptions::default() };
+            options
+                .tool_definitions(&tools)
+                .iter()
+                .map(|definition| definition.name().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(None), ["search"]);
+        assert_eq!(names(Some(vec!["search".to_owned()])), ["search"]);
+        assert!(names(Some(vec!["ls".to_owned()])).is_empty());
+        assert!(names(Some(Vec::new())).is_empty());
+    }
+
+    #[test]
+    fn test_insert_examples_drops_what_does_not_fit() {
+        let example = |user: &str, priority| Example {
+            user: user.to_owned(),
+            assistant: "x".repeat(40),
+            priority,
+        };
+        let examples =
+            [example("first", 0), example("second", 1), example("third", 0)];
+        let input = || {
+            vec![
+                Message::System("Answer with a command.".to_owned()),
+                Message::User("y".repeat(400)),
+            ]
+        };
+
+        let mut messages = input();
+        assert_eq!(insert_examples(&examples, &mut messages, None), None);
+        assert_eq!(messages.len(), 8);
+
+        let mut messages = input();
+        let limit = messages.iter().map(message_tokens).sum::<usize>() + 30;
+        assert_eq!(
+            insert_examples(&examples, &mut messages, Some(limit)),
+            Some(TraceEventKind::ExamplesPruned { dropped: 2, total: 3 })
+        );
+        assert_eq!(messages[1], Message::User("second".to_owned()));
+        assert_eq!(messages[3], Message::User("y".repeat(400)));
+        assert_eq!(messages.len(), 4);
+    }
+
+    struct Shout(ToolDefinition);
+
+    #[async_trait::async_trait]
+    impl Tool for Shout {
+        fn definition(&self) -> &ToolDefinition {
+            &self.0
+        }
+
+        async fn execute(
+            &self,
+            input: ToolInput,
+            _context: &ToolContext,
+        ) -> AidoResult<String> {
+            let text = input.get("text").and_then(|text| text.as_str());
+            Ok(text.unwrap_or_default().to_uppercase())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_against_mock_provider() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-mock-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: shout
+        arguments: { text: hello }
+  - text: Shouted.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let tools: Vec<Box<dyn Tool>> = vec![Box::new(Shout(
+            ToolDefinitionBuilder::new("shout")
+                .arg(Arg::new("text").kind(ArgType::String))
+                .build(),
+        ))];
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let messages = vec![Message::User("shout hello".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        assert_eq!(outcome.text, "Shouted.");
+        assert!(outcome.messages.contains(&Message::Tool {
+            content: "HELLO".to_owned(),
+            id: "call_0_0".to_owned(),
+        }));
+        assert!(outcome.usage.total_tokens() > 0);
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    /// Answers with the directory it is called in
+    struct Pwd(ToolDefinition);
+
+    #[async_trait::async_trait]
+    impl Tool for Pwd {
+        fn definition(&self) -> &ToolDefinition {
+            &self.0
+        }
+
+        async fn execute(
+            &self,
+            _input: ToolInput,
+            context: &ToolContext,
+        ) -> AidoResult<String> {
+            Ok(context.working_dir.display().to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_isolated_tools_work_in_the_worktree() {
+        let repo = std::env::temp_dir()
+            .join(format!("aido-run-isolated-{}", std::process::id()));
+        std::fs::create_dir_all(&repo).unwrap();
+        std::fs::write(repo.join("README.md"), "readme\n").unwrap();
+        for args in [
+            &["init", "--quiet"][..],
+            &["add", "--all"],
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@localhost",
+                "commit",
+                "--quiet",
+                "--message",
+                "Initial",
+            ],
+        ] {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&repo)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        let fixture = repo.join("replies.yaml");
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: pwd
+        arguments: {}
+  - text: Done.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let tools: Vec<Box<dyn Tool>> =
+            vec![Box::new(Pwd(ToolDefinitionBuilder::new("pwd").build()))];
+        let worktree = Arc::new(Worktree::for_dir(&repo).unwrap());
+        let options = RunOptions {
+            isolated: true,
+            worktree: Some(Arc::clone(&worktree)),
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+        let cwd = std::env::current_dir().unwrap();
+
+        let messages = vec![Message::User("where?".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        assert!(outcome.messages.contains(&Message::Tool {
+            content: worktree.working_dir().display().to_string(),
+            id: "call_0_0".to_owned(),
+        }));
+        assert_eq!(std::env::current_dir().unwrap(), cwd);
+
+        drop(worktree);
+        std::fs::remove_dir_all(repo).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failing_custom_tool_does_not_end_the_run() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-failing-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: check
+        arguments: {}
+  - text: It does not compile.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let check = crate::tools::CustomToolConfig {
+            description: "Checks the code".to_owned(),
+            command: "echo 'error: expected `;`' >&2; exit 101".to_owned(),
+            args: std::collections::BTreeMap::new(),
+            capability: crate::tools::Capability::Exec,
+        };
+        let tools: Vec<Box<dyn Tool>> =
+            vec![Box::new(crate::tools::CustomTool::new(
+                "check",
+                &check,
+                crate::tools::ExecBackend::Host,
+            ))];
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let messages = vec![Message::User("check it".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        assert_eq!(outcome.text, "It does not compile.");
+        let Some(Message::Tool { content, .. }) = outcome
+            .messages
+            .iter()
+            .find(|message| matches!(message, Message::Tool { .. }))
+        else {
+            panic!("no tool message in {:?}", outcome.messages);
+        };
+        assert!(content.contains("101"), "{content}");
+        assert!(content.contains("error: expected `;`"), "{content}");
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tool_errors_are_reported_to_the_model() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-tool-error-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: search
+        arguments: { pattern: \"foo(\" }
+  - text: The pattern was invalid.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let tools: Vec<Box<dyn Tool>> =
+            vec![Box::new(crate::tools::Search::new())];
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let messages = vec![Message::User("find foo(".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        assert_eq!(outcome.text, "The pattern was invalid.");
+        let Some(Message::Tool { content, .. }) = outcome
+            .messages
+            .iter()
+            .find(|message| matches!(message, Message::Tool { .. }))
+        else {
+            panic!("no tool message in {:?}", outcome.messages);
+        };
+        assert!(
+            content.starts_with(
+                "Error: the tool 'search' failed: Invalid search pattern"
+            ),
+            "{content}"
+        );
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_tool_arguments_are_reported_to_the_model() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-rejected-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: git_diff
+        arguments: { revision: \"--output=x\" }
+  - tool_calls:
+      - name: ls
+        arguments: { flags: \"--color\" }
+  - text: Both were refused.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let backend = crate::tools::ExecBackend::Host;
+        let tools: Vec<Box<dyn Tool>> = vec![
+            Box::new(crate::tools::GitDiff::new(backend.clone())),
+            Box::new(crate::tools::Ls::new(backend)),
+        ];
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let messages = vec![Message::User("look".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        assert_eq!(outcome.text, "Both were refused.");
+        let errors = outcome
+            .messages
+            .iter()
+            .filter_map(|message| match message {
+                Message::Tool { content, .. } => Some(content.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                "Error: the tool 'git_diff' failed: Invalid revision \
+                 '--output=x': must not start with '-'",
+                "Error: the tool 'ls' failed: `--color` isn't a cluster of \
+                 short options such as -alh",
+            ]
+        );
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    /// Runs `config` with `options`, asking "go", and returns the outcome
+    /// and everything printed along the way
+    async fn run_printing(
+        config: &Config,
+        options: RunOptions,
+    ) -> (AidoResult<RunOutcome>, String) {
+        let printed = Arc::new(std::sync::Mutex::new(String::new()));
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new({
+                    let printed = Arc::clone(&printed);
+                    move |text| printed.lock().unwrap().push_str(text)
+                })),
+                on_confirm: None,
+            },
+            ..options
+        };
+
+        let messages = vec![Message::User("go".to_owned())];
+        let outcome = run(config, messages, &[], &options).await;
+        let printed = printed.lock().unwrap().clone();
+        (outcome, printed)
+    }
+
+    #[tokio::test]
+    async fn test_answer_in_another_language_is_not_printed() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-language-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - text: Привет, это ответ на русском языке, и он довольно длинный.
+  - when: Answer again
+    text: Hello, this answer is written in English as it should be.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            output_language: Some("English".to_owned()),
+            ..Config::default()
+        };
+
+        let (outcome, printed) =
+            run_printing(&config, RunOptions::default()).await;
+
+        let answer =
+            "Hello, this answer is written in English as it should be.";
+        assert_eq!(outcome.unwrap().text, answer);
+        assert_eq!(printed, format!("{answer}\n"));
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_answer_not_matching_the_schema_is_not_printed() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-schema-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - text: Sure, here it is.
+  - when: Reply again
+    text: '{\"name\": \"aido\"}'
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let options = RunOptions {
+            response_schema: Some(serde_json::json!({
+                "type": "object",
+                "required": ["name"],
+            })),
+            ..RunOptions::default()
+        };
+
+        let (outcome, printed) = run_printing(&config, options).await;
+
+        assert_eq!(outcome.unwrap().text, r#"{"name": "aido"}"#);
+        assert_eq!(printed, "{\"name\": \"aido\"}\n");
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_is_recorded() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-record-{}.yaml", std::process::id()));
+        std::fs::write(&fixture, "replies:\n  - text: Recorded.\n").unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let recorder = Recorder::default();
+
+        let options = RunOptions::default().with_recorder(recorder.clone());
+        let (outcome, _) = run_printing(&config, options).await;
+        outcome.unwrap();
+
+        let bundle = recorder.bundle(&config).unwrap();
+        assert_eq!(bundle.exchanges.len(), 1);
+        assert_eq!(bundle.answer(), Some("Recorded."));
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    /// Rewrites the text of replies
+    struct Shouting;
+
+    impl Middleware for Shouting {
+        fn after_response(
+            &self,
+            _request: &LlmRequest,
+            response: &mut LlmResponse,
+        ) -> crate::middleware::MiddlewareResult<()> {
+            *response.text_mut() = response.text().to_uppercase();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_reply_is_printed() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-rewrite-{}.yaml", std::process::id()));
+        std::fs::write(&fixture, "replies:\n  - text: Quiet.\n").unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let printed = Arc::new(std::sync::Mutex::new(String::new()));
+        let mut options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new({
+                    let printed = Arc::clone(&printed);
+                    move |text| printed.lock().unwrap().push_str(text)
+                })),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+        options.middleware.push(Shouting);
+
+        let messages = vec![Message::User("hi".to_owned())];
+        let outcome = run(&config, messages, &[], &options).await.unwrap();
+
+        assert_eq!(outcome.text, "QUIET.");
+        assert_eq!(printed.lock().unwrap().trim(), "QUIET.");
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_at_budget() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-budget-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: shout
+    usage: { prompt_tokens: 1000, completion_tokens: 100 }
+  - text: Done.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            model_name: "m".to_owned(),
+            prices: [(
+                "m".to_owned(),
+                usage::ModelPrice { prompt: 1.0, completion: 1.0 },
+            )]
+            .into(),
+            ..Config::default()
+        };
+        let tools: Vec<Box<dyn Tool>> =
+            vec![Box::new(Shout(ToolDefinitionBuilder::new("shout").build()))];
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+        let run_with = |config: Config| {
+            let (tools, options) = (&tools, &options);
+            async move {
+                let messages = vec![Message::User("go".to_owned())];
+                run(&config, messages, tools, options).await
+            }
+        };
+
+        let error = run_with(Config {
+            max_tokens_per_run: Some(1000),
+            ..config.clone()
+        })
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("1100 tokens"), "{error}");
+
+        let error = run_with(Config {
+            max_cost_per_run: Some(0.001),
+            ..config.clone()
+        })
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("max_cost_per_run"), "{error}");
+
+        let outcome = run_with(Config {
+            max_cost_per_run: Some(0.01),
+            max_tokens_per_run: Some(10_000),
+            ..config
+        })
+        .await
+        .unwrap();
+        assert_eq!(outcome.text, "Done.");
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_budget_counts_the_judge() {
+        let fixture = std::env::temp_dir().join(format!(
+            "aido-run-budget-judge-{}.yaml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - text: An answer.
+    usage: { prompt_tokens: 10, completion_tokens: 10 }
+  - when: 'Final answer:'
+    text: '3 Not good.'
+    usage: { prompt_tokens: 1000, completion_tokens: 10 }
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            model_name: "m".to_owned(),
+            fallback_models: vec!["m2".to_owned()],
+            verify: Some(verify::VerifyConfig::default()),
+            max_tokens_per_run: Some(500),
+            ..Config::default()
+        };
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        // The answer alone is well within the budget, but not with the
+        // judge's verdict on it, so the fallback model isn't asked
+        let messages = vec![Message::User("go".to_owned())];
+        let error = run(&config, messages, &[], &options).await.unwrap_err();
+        assert!(error.to_string().contains("1030 tokens"), "{error}");
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    /// Counts its calls
+    struct Counted(ToolDefinition, Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl Tool for Counted {
+        fn definition(&self) -> &ToolDefinition {
+            &self.0
+        }
+
+        async fn execute(
+            &self,
+            _input: ToolInput,
+            _context: &ToolContext,
+        ) -> AidoResult<String> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok("done".to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_every_tool_call_is_answered() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-parallel-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: status
+        arguments: { target: a }
+      - name: status
+        arguments: { target: b }
+  - text: Both are fine.
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let tools: Vec<Box<dyn Tool>> = vec![Box::new(Counted(
+            ToolDefinitionBuilder::new("status")
+                .arg(Arg::new("target").kind(ArgType::String))
+                .build(),
+            Arc::clone(&calls),
+        ))];
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let messages = vec![Message::User("check".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        assert_eq!(outcome.text, "Both are fine.");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        let answered = outcome
+            .messages
+            .iter()
+            .filter_map(|message| match message {
+                Message::Tool { id, .. } => Some(id.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(answered, ["call_0_0", "call_0_1"]);
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fallback_answers_without_calling_tools_again() {
+        let fixture = std::env::temp_dir()
+            .join(format!("aido-run-fallback-{}.yaml", std::process::id()));
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: deploy
+  - text: Deployed.
+  - when: 'Final answer:'
+    text: '3 Too terse.'
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            model_name: "m".to_owned(),
+            fallback_models: vec!["m2".to_owned()],
+            verify: Some(verify::VerifyConfig::default()),
+            ..Config::default()
+        };
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let tools: Vec<Box<dyn Tool>> = vec![Box::new(Counted(
+            ToolDefinitionBuilder::new("deploy").build(),
+            Arc::clone(&calls),
+        ))];
+        let printed = Arc::new(std::sync::Mutex::new(String::new()));
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new({
+                    let printed = Arc::clone(&printed);
+                    move |text| printed.lock().unwrap().push_str(text)
+                })),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let messages = vec![Message::User("deploy it".to_owned())];
+        let outcome = run(&config, messages, &tools, &options).await.unwrap();
+
+        // Both models answered, and the tool only ran for the first
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(outcome.text, "Deployed.");
+        assert_eq!(*printed.lock().unwrap(), "Deployed.\n");
+
+        std::fs::remove_file(fixture).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recipe_allowed_tools() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-run-allowed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("status.recipe"),
+            "---\nallowed_tools: [status]\n---\nReport the status.",
+        )
+        .unwrap();
+        let fixture = dir.join("fixture.yaml");
+        std::fs::write(
+            &fixture,
+            "replies:\n  - tool_calls:\n      - name: deploy\n  \
+             - text: Not deployed.\n",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let tools: Vec<Box<dyn Tool>> = ["status", "deploy"]
+            .into_iter()
+            .map(|name| {
+                Box::new(Counted(
+                    ToolDefinitionBuilder::new(name).build(),
+                    Arc::clone(&calls),
+                )) as Box<dyn Tool>
+            })
+            .collect();
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let outcome = run_recipe(
+            config,
+            &RecipeStore::new(&dir),
+            "status",
+            Some("ship it".to_owned()),
+            &tools,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        // Only the recipe's tools are offered, or run when called
+        assert!(
+            outcome.messages.contains(&Message::Tool {
+                content:
+                    "Error: there is no tool 'deploy'. The tools available \
+                      are: status"
+                        .to_owned(),
+                id: "call_0_0".to_owned(),
+            })
+        );
+        assert_eq!(outcome.text, "Not deployed.");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        // `aido tokens` counts the same tools
+        let recipes = RecipeStore::new(&dir);
+        let (_, _, definitions) = recipe_request(
+            Config::default(),
+            &recipes.get("status").unwrap(),
+            "status",
+            None,
+            &tools,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            definitions.iter().map(ToolDefinition::name).collect::<Vec<_>>(),
+            ["status"]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_reviewer_sees_the_conversation_only() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-run-reviewer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("main.recipe"),
+            "---\nverify: critic\n---\nDeploy things.",
+        )
+        .unwrap();
+        let hooked = dir.join("hooked");
+        std::fs::write(
+            dir.join("critic.recipe"),
+            format!(
+                "---\npre_hook: touch {}\n---\nBe critical.",
+                hooked.display()
+            ),
+        )
+        .unwrap();
+        // The reviewer only accepts when it is shown the tool's result
+        let fixture = dir.join("fixture.yaml");
+        std::fs::write(
+            &fixture,
+            "replies:
+  - tool_calls:
+      - name: deploy
+  - text: Deployed.
+  - when: 'Tool result: done'
+    text: ACCEPT
+",
+        )
+        .unwrap();
+        let config = Config {
+            provider: llm::Provider::Mock,
+            fixture: Some(fixture.clone()),
+            ..Config::default()
+        };
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let tools: Vec<Box<dyn Tool>> = vec![Box::new(Counted(
+            ToolDefinitionBuilder::new("deploy").build(),
+            Arc::clone(&calls),
+        ))];
+        let options = RunOptions {
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(|_| {})),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let outcome = run_recipe(
+            config,
+            &RecipeStore::new(&dir),
+            "main",
+            Some("deploy it".to_owned()),
+            &tools,
+            &options,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(outcome.text, "Deployed.");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(!hooked.exists(), "the reviewer's hook ran");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_dry_run() {
+        let config = Config {
+            model_name: "test-model".to_owned(),
+            temperature: Some(0.9),
+            fallback_models: vec!["bigger-model".to_owned()],
+            ..Config::default()
+        };
+        assert!(deterministic_config(&config).fallback_models.is_empty());
+
+        let printed = Arc::new(std::sync::Mutex::new(String::new()));
+        let sink = Arc::clone(&printed);
+        let options = RunOptions {
+            dry_run: true,
+            deterministic: true,
+            callbacks: Callbacks {
+                on_text: Some(Arc::new(move |text| {
+                    sink.lock().unwrap().push_str(text);
+                })),
+                on_confirm: None,
+            },
+            ..RunOptions::default()
+        };
+
+        let messages = vec![Message::User("hello".to_owned())];
+        run(&config, messages, &[], &options).await.unwrap();
+
+        let body: serde_json::Value =
+            serde_json::from_str(&printed.lock().unwrap()).unwrap();
+        assert_eq!(body["temperature"], 0.0);
+        assert_eq!(body["seed"], DETERMINISTIC_SEED);
+    }
 }
@@ -0,0 +1,350 @@
+//! A programmatic surface mirroring the command line
+//!
+//! [`Runner`] bundles a configuration, the recipes directory and a set of
+//! tools, and runs questions or recipes the same way `aido` and
+//! `aido run` do. Output and confirmations go to the terminal unless the
+//! embedding application takes them over with [`Runner::on_text`] and
+//! [`Runner::on_confirm`]. Long-lived applications can pick up edits to
+//! the config file with [`Runner::reload_config`], even while runs are in
+//! flight, each run keeping the config it started with, and stop a
+//! run started with [`Runner::ask_with_id`] or [`Runner::run_recipe_with_id`]
+//! from elsewhere with [`Runner::cancel`].
+//!
+//! ```no_run
+//! # async fn example() -> aido::error::AidoResult<()> {
+//! let runner = aido::runner::Runner::from_config_file("aido.toml")?
+//!     .on_text(|text| print!("{text}"))
+//!     .on_confirm(|_question| false);
+//!
+//! for recipe in runner.recipes().list()? {
+//!     println!("{}", recipe.name);
+//! }
+//! let outcome = runner.run_recipe("do", Some("untar photos".into())).await?;
+//! println!("{}", outcome.usage);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::{Arc, PoisonError, RwLock};
+
+use log::warn;
+
+use crate::audit::AuditLog;
+use crate::cancel::RunRegistry;
+use crate::config::{self, Config, ConfigChanges, ConfigWatcher};
+use crate::error::AidoResult;
+use crate::llm::Message;
+use crate::recipe::RecipeStore;
+use crate::redact::Redactor;
+use crate::retrieval::Index;
+use crate::run::{self, RunOptions, RunOutcome};
+use crate::tools::{Tool, ToolRegistry};
+
+/// Runs questions and recipes with a configuration and set of tools
+pub struct Runner {
+    /// Replaced as a whole on reload, so that runs in flight keep theirs
+    config: RwLock<Arc<Config>>,
+    recipes: RecipeStore,
+    tools: Vec<Box<dyn Tool>>,
+    options: RunOptions,
+    watcher: Option<ConfigWatcher>,
+    runs: RunRegistry,
+}
+
+impl Runner {
+    /// Creates a runner using the built-in tools
+    pub fn new(config: Config, recipes: RecipeStore) -> Self {
+        let tools = ToolRegistry::from_config(&config).into_tools();
+        let options = RunOptions::new(&config, false, None);
+
+        Self {
+            config: RwLock::new(Arc::new(config)),
+            recipes,
+            tools,
+            options,
+            watcher: None,
+            runs: RunRegistry::default(),
+        }
+    }
+
+    /// Loads the config file at `path` and the recipes stored next to it,
+    /// recording tool calls in the audit log there too
+    pub fn from_config_file(path: &str) -> AidoResult<Self> {
+        let config = config::retrieve_from_path(path)?;
+        let tools = ToolRegistry::from_config(&config)
+            .with_index(&config, &Index::path_for_config_file(path))
+            .into_tools();
+        let redactor = Redactor::for_config(&config)?;
+        let mut runner = Self::new(config, RecipeStore::for_config_file(path))
+            .with_tools(tools)
+            .watching_config_file(path);
+        runner.options.audit =
+            Some(AuditLog::for_config_file(path).with_redactor(redactor));
+
+        Ok(runner)
+    }
+
+    /// Notices edits to the config file at `path` from now on, for
+    /// [`Runner::reload_config`] to apply; failing to watch it is logged
+    #[must_use]
+    pub fn watching_config_file(mut self, path: &str) -> Self {
+        self.watcher = ConfigWatcher::new(path)
+            .inspect_err(|e| warn!("Not watching the config file {path}: {e}"))
+            .ok();
+        self
+    }
+
+    /// Replaces the tools the model may call
+    #[must_use]
+    pub fn with_tools(mut self, tools: Vec<Box<dyn Tool>>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Replaces the options used for every run, keeping the callbacks
+    /// already registered unless `options` sets its own
+    #[must_use]
+    pub fn with_options(mut self, options: RunOptions) -> Self {
+        let callbacks = std::mem::take(&mut self.options.callbacks);
+        self.options = options;
+        self.options.callbacks.on_text =
+            self.options.callbacks.on_text.take().or(callbacks.on_text);
+        self.options.callbacks.on_confirm =
+            self.options.callbacks.on_confirm.take().or(callbacks.on_confirm);
+        self
+    }
+
+    /// Receives the text of each run as it is generated
+    #[must_use]
+    pub fn on_text(
+        mut self,
+        f: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Self {
+        self.options.callbacks.on_text = Some(Arc::new(f));
+        self
+    }
+
+    /// Decides on tool calls that need confirmation, given the question
+    /// that would be asked on the terminal
+    #[must_use]
+    pub fn on_confirm(
+        mut self,
+        f: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.options.callbacks.on_confirm = Some(Arc::new(f));
+        self
+    }
+
+    /// Applies the settings that are safe to change at runtime, such as
+    /// the model and temperature, if the config file was modified since it
+    /// was loaded; runs started from then on use them
+    ///
+    /// Only runners created with [`Runner::from_config_file`] or watching
+    /// their config file with [`Runner::watching_config_file`] know of
+    /// edits; others never change. Each change is logged.
+    pub fn reload_config(&self) -> AidoResult<ConfigChanges> {
+        let Some(watcher) = &self.watcher else {
+            return Ok(ConfigChanges::default());
+        };
+
+        let mut config = Config::clone(&self.config());
+        let changes = watcher.reload_into(&mut config)?;
+        if !changes.applied.is_empty() {
+            *self.config.write().unwrap_or_else(PoisonError::into_inner) =
+                Arc::new(config);
+        }
+
+        Ok(changes)
+    }
+
+    /// The config runs started now use
+    #[must_use]
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    #[must_use]
+    pub fn recipes(&self) -> &RecipeStore {
+        &self.recipes
+    }
+
+    #[must_use]
+    pub fn tools(&self) -> &[Box<dyn Tool>] {
+        &self.tools
+    }
+
+    /// The runs started with an id that are still in flight
+    #[must_use]
+    pub fn runs(&self) -> &RunRegistry {
+        &self.runs
+    }
+
+    /// Stops the run started with `id`, returning whether it was still in
+    /// flight; the run returns what it had so far, marked as cancelled
+    pub fn cancel(&self, id: &str) -> bool {
+        self.runs.cancel(id)
+    }
+
+    /// Asks a question without a recipe, like `aido "<question>"`
+    pub async fn ask(&self, question: &str) -> AidoResult<RunOutcome> {
+        self.ask_with_options(question, &self.options).await
+    }
+
+    /// Like [`Runner::ask`], but can be stopped with [`Runner::cancel`]
+    /// while it runs
+    pub async fn ask_with_id(
+        &self,
+        id: &str,
+        question: &str,
+    ) -> AidoResult<RunOutcome> {
+        let run = self.runs.start(id)?;
+        let options =
+            RunOptions { cancel: run.token().clone(), ..self.options.clone() };
+
+        self.ask_with_options(question, &options).await
+    }
+
+    async fn ask_with_options(
+        &self,
+        question: &str,
+        options: &RunOptions,
+    ) -> AidoResult<RunOutcome> {
+        let messages = vec![Message::User(question.to_owned())];
+
+        Box::pin(run::run(&self.config(), messages, &self.tools, options))
+            .await
+    }
+
+    /// Runs a recipe by name, like `aido run <recipe> [message]`
+    pub async fn run_recipe(
+        &self,
+        name: &str,
+        user_message: Option<String>,
+    ) -> AidoResult<RunOutcome> {
+        self.run_recipe_with_options(name, user_message, &self.options).await
+    }
+
+    /// Like [`Runner::run_recipe`], but can be stopped with
+    /// [`Runner::cancel`] while it runs
+    pub async fn run_recipe_with_id(
+        &self,
+        id: &str,
+        name: &str,
+        user_message: Option<String>,
+    ) -> AidoResult<RunOutcome> {
+        let run = self.runs.start(id)?;
+        let options =
+            RunOptions { cancel: run.token().clone(), ..self.options.clone() };
+
+        self.run_recipe_with_options(name, user_message, &options).await
+    }
+
+    async fn run_recipe_with_options(
+        &self,
+        name: &str,
+        user_message: Option<String>,
+        options: &RunOptions,
+    ) -> AidoResult<RunOutcome> {
+        Box::pin(run::run_recipe(
+            Config::clone(&self.config()),
+            &self.recipes,
+            name,
+            user_message,
+            &self.tools,
+            options,
+        ))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_options_keeps_callbacks() {
+        let runner = Runner::new(Config::default(), RecipeStore::new("."))
+            .on_confirm(|_| true)
+            .with_options(RunOptions { trace: true, ..RunOptions::default() })
+            .with_tools(Vec::new());
+
+        assert!(runner.options.trace);
+        assert!(runner.options.callbacks.on_confirm.is_some());
+        assert!(runner.options.callbacks.on_text.is_none());
+        assert!(runner.tools().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_runs_use_the_reloaded_config() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-runner-reload-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fixture = dir.join("replies.yaml");
+        std::fs::write(&fixture, "replies:\n  - text: Hi.\n").unwrap();
+        let path = dir.join("aido.toml");
+        let write = |model: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "provider = \"mock\"\napi_url = \"\"\nfixture = {:?}\n\
+                     timeout = 10\nmodel_name = \"{model}\"\n",
+                    fixture.display().to_string()
+                ),
+            )
+            .unwrap();
+        };
+
+        write("small");
+        let runner = Runner::from_config_file(&path.display().to_string())
+            .unwrap()
+            .on_text(|_| {})
+            .with_tools(Vec::new());
+        assert_eq!(runner.ask("hi").await.unwrap().model, "small");
+
+        write("large");
+        let changes = (0..50)
+            .map(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                runner.reload_config().unwrap()
+            })
+            .find(|changes| !changes.is_empty())
+            .unwrap();
+        assert_eq!(changes.applied, ["model_name: \"small\" -> \"large\""]);
+        assert_eq!(runner.ask("hi").await.unwrap().model, "large");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_by_id() {
+        // A server that accepts the request and never answers
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            api_url: format!("http://{}", listener.local_addr().unwrap()),
+            model_name: "m".to_owned(),
+            ..Config::default()
+        };
+        let server = tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let runner = Runner::new(config, RecipeStore::new("."))
+            .on_text(|_| {})
+            .with_tools(Vec::new());
+
+        let (outcome, cancelled) =
+            tokio::join!(runner.ask_with_id("a", "hi"), async {
+                while runner.runs().ids().is_empty() {
+                    tokio::task::yield_now().await;
+                }
+                runner.cancel("a")
+            });
+
+        assert!(cancelled);
+        assert!(outcome.unwrap().cancelled);
+        assert!(runner.runs().ids().is_empty());
+        assert!(!runner.cancel("a"));
+        server.abort();
+    }
+}
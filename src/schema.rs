@@ -0,0 +1,460 @@
+//! Checking answers against the JSON schema a recipe declares
+//!
+//! A recipe with a `schema` in its header asks the model for a JSON answer
+//! matching it, through the structured output of the API. Providers that
+//! ignore the request and models that stray anyway are caught here: the
+//! answer is parsed and checked against the schema, and the run asks the
+//! model once more, saying what was wrong, before giving up.
+//!
+//! The arguments of tool calls are checked the same way, against the
+//! parameters of the tool, and the model is told what to fix.
+//!
+//! The checks cover the keywords structured output schemas are made of:
+//! `type`, `enum`, `const`, `properties`, `required`,
+//! `additionalProperties`, `items`, `anyOf`, `oneOf`, `allOf`, `$ref` into
+//! the schema itself, `pattern`, and the length, size and range bounds.
+//! Other keywords are ignored.
+
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::json_repair;
+use crate::markdown;
+
+/// Name the schema is sent to the API under
+pub const SCHEMA_NAME: &str = "answer";
+
+/// Parses `answer` as JSON, taking it out of the code block the model may
+/// have put it in
+pub fn parse_answer(answer: &str) -> Result<Value, json_repair::ParseError> {
+    let answer = answer.trim();
+    let json = match markdown::code_blocks(answer).as_slice() {
+        [block] if answer.starts_with("```") => block,
+        _ => answer,
+    };
+
+    json_repair::parse_lenient(json)
+}
+
+/// Checks `answer` against `schema`, returning the parsed answer, or a
+/// description of what is wrong with it
+pub fn check_answer(schema: &Value, answer: &str) -> Result<Value, String> {
+    let value = parse_answer(answer)
+        .map_err(|e| format!("the answer is not valid JSON: {e}"))?;
+
+    let problems = violations(schema, &value);
+    if !problems.is_empty() {
+        return Err(problems.join("; "));
+    }
+
+    Ok(value)
+}
+
+/// Every way in which `value` doesn't match `schema`, each naming where in
+/// `value` the problem is
+pub fn violations(schema: &Value, value: &Value) -> Vec<String> {
+    let mut validator = Validator { root: schema, problems: Vec::new() };
+    validator.check(schema, value, "$");
+    validator.problems
+}
+
+struct Validator<'a> {
+    /// The whole schema, which `$ref`s point into
+    root: &'a Value,
+    problems: Vec<String>,
+}
+
+impl Validator<'_> {
+    fn fail(&mut self, path: &str, problem: impl std::fmt::Display) {
+        self.problems.push(format!("{path}: {problem}"));
+    }
+
+    /// Whether `value` matches `schema`, without reporting why not
+    fn matches(&self, schema: &Value, value: &Value) -> bool {
+        let mut validator =
+            Validator { root: self.root, problems: Vec::new() };
+        validator.check(schema, value, "$");
+        validator.problems.is_empty()
+    }
+
+    fn check(&mut self, schema: &Value, value: &Value, path: &str) {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(false) => {
+                self.fail(path, "is not allowed");
+                return;
+            }
+            _ => return,
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match reference.strip_prefix('#').map(|p| self.root.pointer(p)) {
+                Some(Some(target)) => self.check(target, value, path),
+                _ => {
+                    self.fail(
+                        path,
+                        format!("cannot resolve $ref {reference}"),
+                    );
+                }
+            }
+        }
+
+        if let Some(expected) = schema.get("type")
+            && !type_matches(expected, value)
+        {
+            let expected = type_names(expected);
+            self.fail(
+                path,
+                format!("expected {expected}, got {}", kind(value)),
+            );
+            return;
+        }
+
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array)
+            && !allowed.contains(value)
+        {
+            self.fail(
+                path,
+                format!("must be one of {}", Value::from(allowed.clone())),
+            );
+        }
+        if let Some(constant) = schema.get("const")
+            && constant != value
+        {
+            self.fail(path, format!("must be {constant}"));
+        }
+
+        self.check_combinators(schema, value, path);
+
+        match value {
+            Value::Object(object) => self.check_object(schema, object, path),
+            Value::Array(items) => self.check_array(schema, items, path),
+            Value::String(string) => check_string(self, schema, string, path),
+            Value::Number(_) => self.check_number(schema, value, path),
+            Value::Null | Value::Bool(_) => {}
+        }
+    }
+
+    fn check_combinators(
+        &mut self,
+        schema: &Map<String, Value>,
+        value: &Value,
+        path: &str,
+    ) {
+        for subschema in
+            schema.get("allOf").and_then(Value::as_array).into_iter().flatten()
+        {
+            self.check(subschema, value, path);
+        }
+
+        if let Some(options) = schema.get("anyOf").and_then(Value::as_array)
+            && !options.iter().any(|option| self.matches(option, value))
+        {
+            self.fail(path, "does not match any of the allowed schemas");
+        }
+
+        if let Some(options) = schema.get("oneOf").and_then(Value::as_array) {
+            let matching = options
+                .iter()
+                .filter(|option| self.matches(option, value))
+                .count();
+            if matching != 1 {
+                self.fail(
+                    path,
+                    format!("must match exactly one schema of oneOf, matches {matching}"),
+                );
+            }
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+    ) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+
+        for name in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(name) {
+                self.fail(path, format!("missing required property '{name}'"));
+            }
+        }
+
+        for (name, value) in object {
+            let path = format!("{path}.{name}");
+            match properties.and_then(|p| p.get(name)) {
+                Some(subschema) => self.check(subschema, value, &path),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        self.fail(&path, "is not an allowed property");
+                    }
+                    Some(subschema) => self.check(subschema, value, &path),
+                    None => {}
+                },
+            }
+        }
+    }
+
+    fn check_array(
+        &mut self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+    ) {
+        check_bounds(
+            self,
+            schema,
+            items.len(),
+            "minItems",
+            "maxItems",
+            "items",
+            path,
+        );
+
+        if let Some(subschema) = schema.get("items") {
+            for (index, item) in items.iter().enumerate() {
+                self.check(subschema, item, &format!("{path}[{index}]"));
+            }
+        }
+    }
+
+    fn check_number(
+        &mut self,
+        schema: &Map<String, Value>,
+        value: &Value,
+        path: &str,
+    ) {
+        let Some(number) = value.as_f64() else {
+            return;
+        };
+        let bound =
+            |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+
+        if let Some(minimum) = bound("minimum")
+            && number < minimum
+        {
+            self.fail(path, format!("must be at least {minimum}"));
+        }
+        if let Some(maximum) = bound("maximum")
+            && number > maximum
+        {
+            self.fail(path, format!("must be at most {maximum}"));
+        }
+        if let Some(minimum) = bound("exclusiveMinimum")
+            && number <= minimum
+        {
+            self.fail(path, format!("must be greater than {minimum}"));
+        }
+        if let Some(maximum) = bound("exclusiveMaximum")
+            && number >= maximum
+        {
+            self.fail(path, format!("must be less than {maximum}"));
+        }
+    }
+}
+
+fn check_string(
+    validator: &mut Validator<'_>,
+    schema: &Map<String, Value>,
+    string: &str,
+    path: &str,
+) {
+    let length = string.chars().count();
+    check_bounds(
+        validator,
+        schema,
+        length,
+        "minLength",
+        "maxLength",
+        "characters",
+        path,
+    );
+
+    // Patterns the regex crate can't compile are not held against the answer
+    if let Some(pattern) = schema.get("pattern").and_then(Value::as_str)
+        && let Ok(regex) = Regex::new(pattern)
+        && !regex.is_match(string)
+    {
+        validator.fail(path, format!("does not match the pattern {pattern}"));
+    }
+}
+
+/// Checks a length against the `min` and `max` keywords of `schema`
+fn check_bounds(
+    validator: &mut Validator<'_>,
+    schema: &Map<String, Value>,
+    length: usize,
+    min: &str,
+    max: &str,
+    unit: &str,
+    path: &str,
+) {
+    let bound = |keyword: &str| {
+        schema
+            .get(keyword)
+            .and_then(Value::as_u64)
+            .and_then(|n| usize::try_from(n).ok())
+    };
+
+    if let Some(min) = bound(min)
+        && length < min
+    {
+        validator.fail(path, format!("must have at least {min} {unit}"));
+    }
+    if let Some(max) = bound(max)
+        && length > max
+    {
+        validator.fail(path, format!("must have at most {max} {unit}"));
+    }
+}
+
+/// Whether `value` is of the type, or one of the types, in `expected`
+fn type_matches(expected: &Value, value: &Value) -> bool {
+    match expected {
+        Value::String(name) => is_type(name, value),
+        Value::Array(names) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .any(|name| is_type(name, value)),
+        _ => true,
+    }
+}
+
+fn is_type(name: &str, value: &Value) -> bool {
+    match name {
+        "integer" => {
+            value.is_i64()
+                || value.is_u64()
+                || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "number" => value.is_number(),
+        name => kind(value) == name,
+    }
+}
+
+/// The JSON Schema name of the type of `value`
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn type_names(expected: &Value) -> String {
+    match expected {
+        Value::Array(names) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" or "),
+        expected => expected.as_str().unwrap_or_default().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "age": { "type": "integer", "minimum": 0 },
+                "tags": { "type": "array", "items": { "$ref": "#/$defs/tag" } },
+                "role": { "enum": ["admin", "user"] },
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "$defs": { "tag": { "type": "string", "maxLength": 3 } },
+        })
+    }
+
+    #[test]
+    fn test_valid_answer() {
+        let value = json!({
+            "name": "Ada",
+            "age": 36,
+            "tags": ["abc"],
+            "role": "admin",
+        });
+
+        assert!(violations(&person_schema(), &value).is_empty());
+    }
+
+    #[test]
+    fn test_violations_name_their_location() {
+        let value = json!({
+            "name": "",
+            "age": -1.5,
+            "tags": ["abcd", 1],
+            "role": "guest",
+            "extra": true,
+        });
+
+        assert_eq!(
+            violations(&person_schema(), &value),
+            [
+                "$.age: expected integer, got number",
+                "$.extra: is not an allowed property",
+                "$.name: must have at least 1 characters",
+                "$.role: must be one of [\"admin\",\"user\"]",
+                "$.tags[0]: must have at most 3 characters",
+                "$.tags[1]: expected string, got number",
+            ]
+        );
+        assert_eq!(
+            violations(&person_schema(), &json!({ "name": "Ada" })),
+            ["$: missing required property 'age'"]
+        );
+    }
+
+    #[test]
+    fn test_combinators() {
+        let schema = json!({
+            "anyOf": [{ "type": "string" }, { "type": "null" }],
+        });
+        assert!(violations(&schema, &json!(null)).is_empty());
+        assert_eq!(
+            violations(&schema, &json!(1)),
+            ["$: does not match any of the allowed schemas"]
+        );
+
+        let schema = json!({
+            "oneOf": [{ "type": "integer" }, { "type": "number" }],
+        });
+        assert!(violations(&schema, &json!(1.5)).is_empty());
+        assert_eq!(violations(&schema, &json!(1)).len(), 1);
+    }
+
+    #[test]
+    fn test_check_answer() {
+        let schema = json!({ "type": "object", "required": ["ok"] });
+
+        assert_eq!(
+            check_answer(&schema, "```json\n{\"ok\": true}\n```").unwrap(),
+            json!({ "ok": true })
+        );
+        assert_eq!(
+            check_answer(&schema, "{}").unwrap_err(),
+            "$: missing required property 'ok'"
+        );
+        assert!(
+            check_answer(&schema, "Sure!")
+                .unwrap_err()
+                .starts_with("the answer is not valid JSON")
+        );
+    }
+}
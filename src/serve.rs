@@ -0,0 +1,319 @@
+//! A JSON-RPC server on stdin and stdout, for editor integrations
+//!
+//! `aido serve` reads JSON-RPC 2.0 requests, one per line, and writes one
+//! response per line as each is done. Questions and runs go on while
+//! further requests are read, so that a client can stop one with `cancel`,
+//! given the id of the request that started it:
+//!
+//! ```text
+//! → {"jsonrpc": "2.0", "id": 1, "method": "run",
+//!    "params": {"recipe": "do", "message": "untar photos.tar.gz"}}
+//! → {"jsonrpc": "2.0", "id": 2, "method": "cancel", "params": {"id": 1}}
+//! ← {"jsonrpc": "2.0", "id": 2, "result": true}
+//! ← {"jsonrpc": "2.0", "id": 1, "result": {"cancelled": true, ...}}
+//! ```
+//!
+//! The methods are `ask` (with a `question`), `run` (with a `recipe` and
+//! an optional `message`), `cancel` (with an `id`) and `list_recipes`.
+//! Questions and runs answer with the same document as `--output json`.
+//! Cancelling aborts the request to the model and kills the processes of
+//! a running tool. Tool calls that a recipe wants confirmed are declined,
+//! since there is no terminal to ask on. Edits to the config file apply to
+//! the questions and runs started after them.
+
+use std::io;
+use std::pin::Pin;
+
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use log::warn;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::AidoResult;
+use crate::output;
+use crate::run::RunOutcome;
+use crate::runner::Runner;
+
+/// The request isn't valid JSON
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The question or run failed
+const RUN_FAILED: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct AskParams {
+    question: String,
+}
+
+#[derive(Deserialize)]
+struct RunParams {
+    recipe: String,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CancelParams {
+    id: Value,
+}
+
+/// The response to a question or run, once it is over
+type Pending<'a> = Pin<Box<dyn Future<Output = Value> + Send + 'a>>;
+
+/// What a request leads to
+enum Handled<'a> {
+    /// A response to write right away
+    Done(Value),
+    /// A question or run to wait on
+    Started(Pending<'a>),
+}
+
+/// Answers the requests read from `input` on `output` until `input` ends
+/// and every question and run started is over
+pub async fn serve(
+    runner: &Runner,
+    input: impl AsyncBufRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+) -> io::Result<()> {
+    let mut lines = input.lines();
+    let mut pending = FuturesUnordered::new();
+    let mut reading = true;
+
+    while reading || !pending.is_empty() {
+        let response = tokio::select! {
+            // Polling new runs first registers their ids before a cancel
+            // of them is read
+            biased;
+            Some(response) = pending.next(), if !pending.is_empty() => {
+                response
+            }
+            line = lines.next_line(), if reading => match line? {
+                None => {
+                    reading = false;
+                    continue;
+                }
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => {
+                    if let Err(e) = runner.reload_config() {
+                        warn!("Keeping the config, which failed to reload: {e}");
+                    }
+                    match handle(runner, &line) {
+                        Handled::Done(response) => response,
+                        Handled::Started(response) => {
+                            pending.push(response);
+                            continue;
+                        }
+                    }
+                }
+            },
+        };
+
+        output.write_all(format!("{response}\n").as_bytes()).await?;
+        output.flush().await?;
+    }
+
+    Ok(())
+}
+
+fn handle<'a>(runner: &'a Runner, line: &str) -> Handled<'a> {
+    match serde_json::from_str::<Request>(line) {
+        Ok(request) => call(runner, request).unwrap_or_else(Handled::Done),
+        Err(e) => {
+            Handled::Done(error(&Value::Null, PARSE_ERROR, &e.to_string()))
+        }
+    }
+}
+
+/// Calls the method of `request`, failing with the error response to
+/// write if it can't be called
+fn call(runner: &Runner, request: Request) -> Result<Handled<'_>, Value> {
+    let Request { id, method, params } = request;
+    let run_id = run_id(&id);
+
+    match method.as_str() {
+        "ask" => {
+            let AskParams { question } = self::params(&id, params)?;
+            Ok(started(id, async move {
+                runner.ask_with_id(&run_id, &question).await
+            }))
+        }
+        "run" => {
+            let RunParams { recipe, message } = self::params(&id, params)?;
+            Ok(started(id, async move {
+                runner.run_recipe_with_id(&run_id, &recipe, message).await
+            }))
+        }
+        "cancel" => {
+            let CancelParams { id: run } = self::params(&id, params)?;
+            let cancelled = runner.cancel(&self::run_id(&run));
+            Ok(Handled::Done(result(&id, json!(cancelled))))
+        }
+        "list_recipes" => {
+            let recipes = runner
+                .recipes()
+                .list()
+                .map_err(|e| error(&id, RUN_FAILED, &e.to_string()))?
+                .into_iter()
+                .map(|r| {
+                    json!({ "name": r.name, "display_name": r.display_name })
+                })
+                .collect();
+            Ok(Handled::Done(result(&id, recipes)))
+        }
+        method => Err(error(
+            &id,
+            METHOD_NOT_FOUND,
+            &format!("Unknown method '{method}'"),
+        )),
+    }
+}
+
+/// Waits on a question or run, to answer the request `id` with its outcome
+fn started<'a>(
+    id: Value,
+    run: impl Future<Output = AidoResult<RunOutcome>> + Send + 'a,
+) -> Handled<'a> {
+    Handled::Started(Box::pin(async move { outcome(&id, run.await) }))
+}
+
+/// The id runs are registered under: the id of the request that started
+/// them
+fn run_id(id: &Value) -> String {
+    id.as_str().map_or_else(|| id.to_string(), ToOwned::to_owned)
+}
+
+fn params<T: DeserializeOwned>(id: &Value, params: Value) -> Result<T, Value> {
+    serde_json::from_value(params)
+        .map_err(|e| error(id, INVALID_PARAMS, &e.to_string()))
+}
+
+fn outcome(id: &Value, outcome: AidoResult<RunOutcome>) -> Value {
+    match outcome.map(|outcome| output::to_json(&outcome)) {
+        Ok(Ok(document)) => result(id, document),
+        Ok(Err(e)) => error(id, RUN_FAILED, &e.to_string()),
+        Err(e) => error(id, RUN_FAILED, &e.to_string()),
+    }
+}
+
+fn result(id: &Value, result: Value) -> Value {
+    let mut response = json!({ "jsonrpc": "2.0", "id": id });
+    response["result"] = result;
+    response
+}
+
+fn error(id: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn responses(output: &[u8]) -> Vec<Value> {
+        output
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_answered() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-serve-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("recipes")).unwrap();
+        std::fs::write(dir.join("recipes/do.recipe"), "Body.").unwrap();
+        let runner = Runner::new(
+            crate::config::Config::default(),
+            crate::recipe::RecipeStore::new(dir.join("recipes")),
+        );
+        let input = concat!(
+            "{\"jsonrpc\": \"2.0\", \"id\": 1, ",
+            "\"method\": \"list_recipes\"}\n",
+            "\n",
+            "not json\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": \"a\", \"method\": \"run\"}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"cancel\", ",
+            "\"params\": {\"id\": 9}}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 3, \"method\": \"stop\"}\n",
+        );
+        let mut output = Vec::new();
+
+        serve(&runner, input.as_bytes(), &mut output).await.unwrap();
+
+        let responses = responses(&output);
+        assert_eq!(responses[0]["result"][0]["name"], "do");
+        assert_eq!(responses[1]["error"]["code"], PARSE_ERROR);
+        assert_eq!(responses[2]["id"], "a");
+        assert_eq!(responses[2]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(responses[3]["result"], false);
+        assert_eq!(responses[4]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses.len(), 5);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_cancel_stops_a_run() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-serve-cancel-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fixture = dir.join("fixture.yaml");
+        std::fs::write(
+            &fixture,
+            "replies:\n  - tool_calls:\n      - name: wait\n  - text: Done.\n",
+        )
+        .unwrap();
+        let config_path = dir.join("aido.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "api_url = \"\"\nfixture = {fixture:?}\nmodel_name = \"m\"\ntimeout = 10\n\
+                 provider = \"mock\"\n\n[tools.custom.wait]\n\
+                 description = \"Waits\"\ncommand = \"sleep 30\"\n\
+                 capability = \"read\"\n"
+            ),
+        )
+        .unwrap();
+        let runner = Runner::from_config_file(config_path.to_str().unwrap())
+            .unwrap()
+            .on_text(|_| {});
+        let input = concat!(
+            "{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"ask\", ",
+            "\"params\": {\"question\": \"hi\"}}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"cancel\", ",
+            "\"params\": {\"id\": 1}}\n",
+        );
+        let mut output = Vec::new();
+
+        let started = std::time::Instant::now();
+        serve(&runner, input.as_bytes(), &mut output).await.unwrap();
+
+        let responses = responses(&output);
+        assert_eq!(responses[0]["id"], 2);
+        assert_eq!(responses[0]["result"], true);
+        assert_eq!(responses[1]["id"], 1);
+        assert_eq!(responses[1]["result"]["cancelled"], true);
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
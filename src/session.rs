@@ -0,0 +1,704 @@
+//! Stored conversations
+//!
+//! Every run is saved as a session: a JSON file in the `sessions` directory
+//! next to the config file holding the full message history. Sessions can
+//! be compacted, replacing their older turns with an LLM-written summary so
+//! they stay small enough to resume; the full original is archived first.
+//!
+//! Sessions also record the [`RunContext`] they were held in: the aido
+//! version, model, recipe and the hash of its file, config profile, git
+//! commit of the project and OS, so that a saved run can be reproduced and
+//! compared with later ones. `aido session show` prints it as the header
+//! of the transcript.
+//!
+//! `aido session retry` asks for the last answer of a session again,
+//! perhaps with another temperature or an instruction added to the last
+//! message. The new answer is saved as a branch: a new session naming the
+//! one it was made from as its parent, which is left as it was.
+
+pub mod export;
+
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::error::AidoResult;
+use crate::llm::{LlmClient, LlmRequest, Message, Usage};
+
+/// Name of the sessions directory inside the config directory
+const SESSIONS_DIR_NAME: &str = "sessions";
+
+/// Name of the directory inside the sessions directory holding originals
+/// of compacted sessions
+const ARCHIVE_DIR_NAME: &str = "archive";
+
+/// Number of the next session made by this process, telling apart the
+/// sessions it makes within the same second
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// Number of most recent messages left untouched by compaction
+pub const DEFAULT_KEEP_RECENT: usize = 4;
+
+/// Instructions given to the model when summarizing old turns
+const SUMMARY_PROMPT: &str = "Summarize the following conversation between \
+    a user and an AI assistant, including the results of any tools the \
+    assistant used. Keep every fact, decision, file name and open question \
+    needed to continue the conversation, and leave out everything else.";
+
+/// Prefix of the message that replaces compacted turns
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:";
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("Session '{id}' not found")]
+    NotFound { id: String },
+
+    #[error("Session '{id}' is too short to compact")]
+    NothingToCompact { id: String },
+
+    #[error("Session '{id}' has no message of the user to answer again")]
+    NothingToRetry { id: String },
+
+    #[error("Session '{id}' already exists")]
+    Exists { id: String },
+
+    #[error(
+        "Invalid session id '{id}': use only letters, digits, '-' and '_'"
+    )]
+    InvalidId { id: String },
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid session file: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// What a run was made with
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunContext {
+    pub aido_version: String,
+    pub model: String,
+    /// Name of the recipe the run followed, if any
+    pub recipe: Option<String>,
+    /// SHA-256 of the recipe file, telling edits of the recipe apart
+    pub recipe_hash: Option<String>,
+    /// Config profile the run used, if any
+    pub profile: Option<String>,
+    /// Commit checked out where the run was started, if that is in a git
+    /// repository
+    pub git_commit: Option<String>,
+    /// Operating system and architecture, e.g. `linux-x86_64`
+    pub os: String,
+}
+
+impl RunContext {
+    /// Describes a run with `model`, started in the current directory
+    pub fn current(model: impl Into<String>) -> Self {
+        Self {
+            aido_version: env!("CARGO_PKG_VERSION").to_owned(),
+            model: model.into(),
+            recipe: None,
+            recipe_hash: None,
+            profile: None,
+            git_commit: head_commit(),
+            os: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        }
+    }
+
+    /// Records the recipe the run followed and, if its file could be read,
+    /// the file's hash
+    #[must_use]
+    pub fn with_recipe(mut self, name: &str, content: Option<&str>) -> Self {
+        self.recipe = Some(name.to_owned());
+        self.recipe_hash =
+            content.map(|content| format!("{:x}", Sha256::digest(content)));
+        self
+    }
+
+    #[must_use]
+    pub fn with_profile(mut self, profile: Option<&str>) -> Self {
+        self.profile = profile.map(str::to_owned);
+        self
+    }
+}
+
+impl fmt::Display for RunContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "aido:    {}", self.aido_version)?;
+        writeln!(f, "model:   {}", self.model)?;
+        if let Some(recipe) = &self.recipe {
+            write!(f, "recipe:  {recipe}")?;
+            if let Some(hash) = &self.recipe_hash {
+                write!(f, " (sha256 {hash})")?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(profile) = &self.profile {
+            writeln!(f, "profile: {profile}")?;
+        }
+        if let Some(commit) = &self.git_commit {
+            writeln!(f, "commit:  {commit}")?;
+        }
+        write!(f, "os:      {}", self.os)
+    }
+}
+
+/// The commit checked out in the current directory, if it is in a git
+/// repository
+fn head_commit() -> Option<String> {
+    let output =
+        Command::new("git").args(["rev-parse", "HEAD"]).output().ok()?;
+    let commit = String::from_utf8(output.stdout).ok()?;
+
+    output.status.success().then(|| commit.trim().to_owned())
+}
+
+/// A stored conversation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// Seconds since the Unix epoch when the session was created
+    pub created: u64,
+    /// Model the conversation was held with
+    pub model: String,
+    /// What the run was made with; missing in sessions saved by older
+    /// versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<RunContext>,
+    /// Id of the session this one branched from, for a retried answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    pub messages: Vec<Message>,
+}
+
+impl Session {
+    /// Creates a session with a fresh, unique id
+    pub fn new(model: impl Into<String>, messages: Vec<Message>) -> Self {
+        let now =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            id: format!("{}-{}-{sequence}", now.as_secs(), std::process::id()),
+            created: now.as_secs(),
+            model: model.into(),
+            context: None,
+            parent: None,
+            messages,
+        }
+    }
+
+    /// Records what the run was made with
+    #[must_use]
+    pub fn with_context(mut self, context: RunContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Records that the session branched from the session `parent`
+    #[must_use]
+    pub fn with_parent(mut self, parent: impl Into<String>) -> Self {
+        self.parent = Some(parent.into());
+        self
+    }
+
+    /// The conversation up to the last message of the user, to ask for
+    /// the answer to it again, with `instruction` added to that message
+    pub fn retry_messages(
+        &self,
+        instruction: Option<&str>,
+    ) -> Result<Vec<Message>, SessionError> {
+        let asked = self
+            .messages
+            .iter()
+            .rposition(|m| matches!(m, Message::User(_)))
+            .ok_or_else(|| SessionError::NothingToRetry {
+                id: self.id.clone(),
+            })?;
+
+        let mut messages = self.messages[..=asked].to_vec();
+        if let (Some(Message::User(question)), Some(instruction)) =
+            (messages.last_mut(), instruction)
+        {
+            let _ = write!(question, "\n\n{}", instruction.trim());
+        }
+
+        Ok(messages)
+    }
+
+    /// The first thing the user said, used to recognize the session
+    pub fn title(&self) -> &str {
+        self.messages
+            .iter()
+            .find_map(|m| match m {
+                Message::User(content) => content.lines().next(),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// The last thing the user said and the final answer to it, if the
+    /// conversation got that far
+    pub fn last_exchange(&self) -> Option<(&str, &str)> {
+        let asked = self
+            .messages
+            .iter()
+            .rposition(|m| matches!(m, Message::User(_)))?;
+        let Message::User(question) = &self.messages[asked] else {
+            return None;
+        };
+        let answer =
+            self.messages[asked..].iter().rev().find_map(|m| match m {
+                Message::Assistant(content, _) if !content.is_empty() => {
+                    Some(content.as_str())
+                }
+                _ => None,
+            })?;
+
+        Some((question, answer))
+    }
+}
+
+/// Directory of stored sessions
+#[derive(Debug, Clone)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    /// Opens the session store next to the given config file
+    pub fn for_config_file(config_file_path: &str) -> Self {
+        let dir = Path::new(config_file_path)
+            .parent()
+            .expect("Config file path should have a parent directory")
+            .join(SESSIONS_DIR_NAME);
+
+        Self::new(dir)
+    }
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file of the session `id`, which must be a plain name so that
+    /// it can't point outside the store
+    fn path(&self, id: &str) -> Result<PathBuf, SessionError> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+        if !valid {
+            return Err(SessionError::InvalidId { id: id.to_owned() });
+        }
+
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    /// Writes a new session, failing if one with its id is already stored
+    pub fn save(&self, session: &Session) -> Result<(), SessionError> {
+        let path = self.path(&session.id)?;
+        std::fs::create_dir_all(&self.dir)?;
+        let json = serde_json::to_string_pretty(session)?;
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::AlreadyExists {
+                    SessionError::Exists { id: session.id.clone() }
+                } else {
+                    e.into()
+                }
+            })?;
+        std::io::Write::write_all(&mut file, json.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Replaces a stored session with a new version of it, such as a
+    /// compacted one
+    pub fn update(&self, session: &Session) -> Result<(), SessionError> {
+        let path = self.path(&session.id)?;
+        if !path.is_file() {
+            return Err(SessionError::NotFound { id: session.id.clone() });
+        }
+        std::fs::write(path, serde_json::to_string_pretty(session)?)?;
+
+        Ok(())
+    }
+
+    pub fn load(&self, id: &str) -> Result<Session, SessionError> {
+        let path = self.path(id)?;
+        if !path.is_file() {
+            return Err(SessionError::NotFound { id: id.to_owned() });
+        }
+
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Loads every stored session, oldest first, skipping unreadable files
+    pub fn list(&self) -> Result<Vec<Session>, SessionError> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut sessions = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Ok(content) = std::fs::read_to_string(&path)
+                && let Ok(session) = serde_json::from_str::<Session>(&content)
+            {
+                sessions.push(session);
+            }
+        }
+
+        sessions
+            .sort_by(|a, b| a.created.cmp(&b.created).then(a.id.cmp(&b.id)));
+
+        Ok(sessions)
+    }
+
+    /// Copies a session into the archive, returning where it was saved
+    pub fn archive(&self, session: &Session) -> Result<PathBuf, SessionError> {
+        self.path(&session.id)?;
+        let archive_dir = self.dir.join(ARCHIVE_DIR_NAME);
+        std::fs::create_dir_all(&archive_dir)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let path = archive_dir.join(format!("{}-{now}.json", session.id));
+        std::fs::write(&path, serde_json::to_string_pretty(session)?)?;
+
+        Ok(path)
+    }
+}
+
+/// Splits a conversation into its leading system messages, the older turns
+/// to summarize, and the `keep_recent` most recent messages to keep as-is
+///
+/// The split never separates a tool result from the assistant message that
+/// requested it. Returns `None` when there is nothing old enough to
+/// summarize.
+pub fn split_for_compaction(
+    messages: &[Message],
+    keep_recent: usize,
+) -> Option<(&[Message], &[Message], &[Message])> {
+    let system_len = messages
+        .iter()
+        .take_while(|m| matches!(m, Message::System(_)))
+        .count();
+
+    let mut split = messages.len().saturating_sub(keep_recent).max(system_len);
+    while split > system_len
+        && split < messages.len()
+        && matches!(messages[split], Message::Tool { .. })
+    {
+        split -= 1;
+    }
+
+    if split <= system_len {
+        return None;
+    }
+
+    Some((
+        &messages[..system_len],
+        &messages[system_len..split],
+        &messages[split..],
+    ))
+}
+
+/// Renders messages as a plain-text transcript
+pub fn transcript(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .map(|message| match message {
+            Message::User(content) => format!("User: {content}"),
+            Message::System(content) => format!("System: {content}"),
+            Message::Tool { content, .. } => format!("Tool result: {content}"),
+            Message::Assistant(content, tool_calls) => {
+                let mut text = format!("Assistant: {content}");
+                for call in tool_calls.iter().flatten() {
+                    let _ = write!(
+                        text,
+                        "\n[called {}({})]",
+                        call.name(),
+                        call.arguments()
+                    );
+                }
+                text
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Replaces the older turns of `session` with a summary written by `llm`,
+/// keeping the system prompt and the `keep_recent` most recent messages
+pub async fn compact(
+    session: &mut Session,
+    llm: &LlmClient,
+    keep_recent: usize,
+) -> AidoResult<Usage> {
+    summarize_old_turns(&mut session.messages, llm, keep_recent)
+        .await?
+        .ok_or_else(|| {
+            SessionError::NothingToCompact { id: session.id.clone() }.into()
+        })
+}
+
+/// The request asking for a summary of the turns of `messages` before the
+/// `keep_recent` most recent messages, if any are that old
+pub fn summary_request(
+    messages: &[Message],
+    keep_recent: usize,
+) -> Option<LlmRequest> {
+    let (_, old, _) = split_for_compaction(messages, keep_recent)?;
+
+    Some(LlmRequest::new(
+        vec![
+            Message::System(SUMMARY_PROMPT.to_string()),
+            Message::User(transcript(old)),
+        ],
+        Vec::new(),
+    ))
+}
+
+/// Replaces the older turns of a conversation with a summary written by
+/// `llm`, keeping the system prompt and the `keep_recent` most recent
+/// messages
+///
+/// Returns the usage of the summary request, or `None` when nothing was old
+/// enough to summarize.
+pub async fn summarize_old_turns(
+    messages: &mut Vec<Message>,
+    llm: &LlmClient,
+    keep_recent: usize,
+) -> AidoResult<Option<Usage>> {
+    let (Some((system, _, recent)), Some(request)) = (
+        split_for_compaction(messages, keep_recent),
+        summary_request(messages, keep_recent),
+    ) else {
+        return Ok(None);
+    };
+
+    let response = llm.get_chat_completion(&request).await?;
+
+    let mut summarized = system.to_vec();
+    summarized.push(Message::System(format!(
+        "{SUMMARY_PREFIX}\n{}",
+        response.text().trim()
+    )));
+    summarized.extend_from_slice(recent);
+    *messages = summarized;
+
+    Ok(Some(response.usage().clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::ToolCall;
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            Message::System("be helpful".to_string()),
+            Message::User("list files".to_string()),
+            Message::Assistant(
+                String::new(),
+                Some(vec![ToolCall::new("1", "ls", "{}")]),
+            ),
+            Message::Tool { content: "a.rs".to_string(), id: "1".to_string() },
+            Message::Assistant("There is a.rs".to_string(), None),
+            Message::User("thanks".to_string()),
+            Message::Assistant("You're welcome".to_string(), None),
+        ]
+    }
+
+    #[test]
+    fn test_split_for_compaction() {
+        let messages = conversation();
+
+        let (system, old, recent) =
+            split_for_compaction(&messages, 2).unwrap();
+
+        assert_eq!(system, &messages[..1]);
+        assert_eq!(old, &messages[1..5]);
+        assert_eq!(recent, &messages[5..]);
+    }
+
+    #[test]
+    fn test_split_keeps_tool_results_with_their_call() {
+        let messages = conversation();
+
+        // A split at index 3 would orphan the tool result
+        let (_, old, recent) = split_for_compaction(&messages, 4).unwrap();
+
+        assert_eq!(old, &messages[1..2]);
+        assert!(matches!(recent[0], Message::Assistant(_, Some(_))));
+    }
+
+    #[test]
+    fn test_split_nothing_to_compact() {
+        let messages = conversation();
+
+        assert!(split_for_compaction(&messages, 0).is_some());
+        assert!(split_for_compaction(&messages, 6).is_none());
+        assert!(split_for_compaction(&messages, 10).is_none());
+    }
+
+    #[test]
+    fn test_transcript() {
+        let text = transcript(&conversation()[1..4]);
+
+        assert_eq!(
+            text,
+            "User: list files\n\nAssistant: \n[called ls({})]\n\n\
+             Tool result: a.rs"
+        );
+    }
+
+    #[test]
+    fn test_run_context() {
+        let context = RunContext::current("gpt-4o")
+            .with_recipe("commit", Some("hello"))
+            .with_profile(Some("local"));
+
+        assert_eq!(context.aido_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            context.recipe_hash.as_deref(),
+            Some(
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e\
+                 1b161e5c1fa7425e73043362938b9824"
+            )
+        );
+
+        let header = context.to_string();
+        assert!(header.starts_with("aido:    "));
+        assert!(header.contains("\nmodel:   gpt-4o\n"));
+        assert!(header.contains("\nrecipe:  commit (sha256 2cf24dba"));
+        assert!(header.contains("\nprofile: local\n"));
+        assert!(header.ends_with(&format!("os:      {}", context.os)));
+
+        // Without a recipe or profile those lines are left out
+        let header = RunContext::current("m").to_string();
+        assert!(!header.contains("recipe:"));
+        assert!(!header.contains("profile:"));
+    }
+
+    #[test]
+    fn test_older_sessions_load_without_context() {
+        let json =
+            r#"{"id": "1", "created": 0, "model": "m", "messages": []}"#;
+
+        let session = serde_json::from_str::<Session>(json).unwrap();
+
+        assert_eq!(session.context, None);
+    }
+
+    #[test]
+    fn test_retry_messages() {
+        let session = Session::new("model", conversation());
+
+        let messages = session.retry_messages(None).unwrap();
+        assert_eq!(messages, conversation()[..6]);
+
+        let messages = session.retry_messages(Some("Be formal.")).unwrap();
+        assert_eq!(
+            messages.last(),
+            Some(&Message::User("thanks\n\nBe formal.".to_owned()))
+        );
+
+        let empty = Session::new("model", conversation()[..1].to_vec());
+        assert!(matches!(
+            empty.retry_messages(None),
+            Err(SessionError::NothingToRetry { .. })
+        ));
+    }
+
+    #[test]
+    fn test_session_title() {
+        let session = Session::new("model", conversation());
+
+        assert_eq!(session.title(), "list files");
+    }
+
+    #[test]
+    fn test_last_exchange() {
+        let mut session = Session::new("model", conversation());
+        assert_eq!(
+            session.last_exchange(),
+            Some(("thanks", "You're welcome"))
+        );
+
+        session.messages.truncate(4);
+        assert_eq!(session.last_exchange(), None);
+    }
+
+    #[test]
+    fn test_new_sessions_have_distinct_ids() {
+        let first = Session::new("model", Vec::new());
+        let second = Session::new("model", Vec::new());
+        assert_ne!(first.id, second.id);
+    }
+
+    #[test]
+    fn test_store_round_trip_and_archive() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-session-test-{}", std::process::id()));
+        let store = SessionStore::new(&dir);
+        let session = Session::new("model", conversation())
+            .with_context(RunContext::current("model"));
+
+        store.save(&session).unwrap();
+        assert_eq!(store.load(&session.id).unwrap(), session);
+        assert_eq!(store.list().unwrap(), std::slice::from_ref(&session));
+
+        let archived = store.archive(&session).unwrap();
+        assert!(archived.starts_with(dir.join(ARCHIVE_DIR_NAME)));
+        // Archived copies are not listed as sessions
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        assert!(matches!(
+            store.load("missing"),
+            Err(SessionError::NotFound { .. })
+        ));
+
+        // A stored session is only replaced on purpose
+        assert!(matches!(
+            store.save(&session),
+            Err(SessionError::Exists { .. })
+        ));
+        let mut compacted = session.clone();
+        compacted.messages.truncate(2);
+        store.update(&compacted).unwrap();
+        assert_eq!(store.load(&session.id).unwrap(), compacted);
+        assert!(matches!(
+            store.update(&Session::new("model", Vec::new())),
+            Err(SessionError::NotFound { .. })
+        ));
+
+        for id in ["../config", "a/b", "..", ""] {
+            assert!(matches!(
+                store.load(id),
+                Err(SessionError::InvalidId { .. })
+            ));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+//! Rendering sessions for sharing
+//!
+//! `aido session export` writes a stored session as a Markdown transcript
+//! to read, or as JSON for tools, each with the messages, the tool calls
+//! with their arguments and what the tools returned. Unlike the session
+//! file, both say which tool each result came from, and the JSON lists the
+//! messages with their roles the way chat APIs do:
+//!
+//! ```text
+//! {"id": "...", "model": "...", "messages": [
+//!   {"role": "user", "content": "..."},
+//!   {"role": "assistant", "content": "", "tool_calls": [
+//!     {"id": "c1", "name": "ls", "arguments": {"args": "src"}}]},
+//!   {"role": "tool", "tool_call_id": "c1", "name": "ls", "content": "..."}
+//! ]}
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::{RunContext, Session};
+use crate::llm::{Message, ToolCall};
+use crate::redact::Redactor;
+
+/// How a session is exported
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// A Markdown transcript
+    #[default]
+    #[value(alias = "markdown")]
+    Md,
+    /// One JSON document
+    Json,
+}
+
+/// A session as exported to JSON
+#[derive(Debug, Serialize)]
+pub struct ExportedSession<'a> {
+    pub id: &'a str,
+    /// Seconds since the Unix epoch when the session was created
+    pub created: u64,
+    pub model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<&'a RunContext>,
+    /// Id of the session this one branched from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<&'a str>,
+    pub messages: Vec<ExportedMessage<'a>>,
+}
+
+/// A message as exported to JSON
+#[derive(Debug, Serialize)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum ExportedMessage<'a> {
+    System {
+        content: &'a str,
+    },
+    User {
+        content: &'a str,
+    },
+    Assistant {
+        content: &'a str,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ExportedToolCall<'a>>,
+    },
+    Tool {
+        tool_call_id: &'a str,
+        /// The tool that was called, if the call is in the session
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<&'a str>,
+        content: &'a str,
+    },
+}
+
+/// A tool call as exported to JSON
+#[derive(Debug, Serialize)]
+pub struct ExportedToolCall<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    /// The arguments, parsed if they are JSON as they should be, and as
+    /// the model wrote them otherwise
+    pub arguments: Value,
+}
+
+impl<'a> ExportedSession<'a> {
+    pub fn new(session: &'a Session) -> Self {
+        let tool_names = tool_names(&session.messages);
+
+        let messages = session
+            .messages
+            .iter()
+            .map(|message| match message {
+                Message::System(content) => {
+                    ExportedMessage::System { content }
+                }
+                Message::User(content) => ExportedMessage::User { content },
+                Message::Assistant(content, tool_calls) => {
+                    ExportedMessage::Assistant {
+                        content,
+                        tool_calls: tool_calls
+                            .iter()
+                            .flatten()
+                            .map(|call| ExportedToolCall {
+                                id: call.id(),
+                                name: call.name(),
+                                arguments: parse_arguments(call.arguments()),
+                            })
+                            .collect(),
+                    }
+                }
+                Message::Tool { content, id } => ExportedMessage::Tool {
+                    tool_call_id: id,
+                    name: tool_names.get(id.as_str()).copied(),
+                    content,
+                },
+            })
+            .collect();
+
+        Self {
+            id: &session.id,
+            created: session.created,
+            model: &session.model,
+            context: session.context.as_ref(),
+            parent: session.parent.as_deref(),
+            messages,
+        }
+    }
+}
+
+/// The session as one JSON document
+pub fn to_json(session: &Session) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(&ExportedSession::new(session))
+}
+
+/// `session` with every secret `redactor` knows of masked in its messages
+pub fn redact(session: &Session, redactor: &Redactor) -> Session {
+    let redact = |text: &str| redactor.redact(text).into_owned();
+
+    let messages = session
+        .messages
+        .iter()
+        .map(|message| match message {
+            Message::System(content) => Message::System(redact(content)),
+            Message::User(content) => Message::User(redact(content)),
+            Message::Assistant(content, tool_calls) => Message::Assistant(
+                redact(content),
+                tool_calls.as_ref().map(|calls| {
+                    calls
+                        .iter()
+                        .map(|call| {
+                            ToolCall::new(
+                                call.id(),
+                                call.name(),
+                                redact(call.arguments()),
+                            )
+                        })
+                        .collect()
+                }),
+            ),
+            Message::Tool { content, id } => {
+                Message::Tool { content: redact(content), id: id.clone() }
+            }
+        })
+        .collect();
+
+    Session { messages, ..session.clone() }
+}
+
+/// The session as a Markdown transcript
+pub fn to_markdown(session: &Session) -> String {
+    let tool_names = tool_names(&session.messages);
+    let mut markdown = String::new();
+
+    let title = session.title();
+    let _ = writeln!(
+        markdown,
+        "# {}\n",
+        if title.is_empty() { "Session" } else { title }
+    );
+    let _ = writeln!(
+        markdown,
+        "Session `{}` with `{}`, started {} UTC\n",
+        session.id,
+        session.model,
+        crate::audit::format_timestamp(session.created)
+    );
+    if let Some(context) = &session.context {
+        let _ = writeln!(markdown, "{}\n", fenced(&context.to_string(), ""));
+    }
+
+    for message in &session.messages {
+        match message {
+            Message::System(content) => {
+                let _ =
+                    writeln!(markdown, "## System\n\n{}\n", content.trim());
+            }
+            Message::User(content) => {
+                let _ = writeln!(markdown, "## User\n\n{}\n", content.trim());
+            }
+            Message::Assistant(content, tool_calls) => {
+                markdown.push_str("## Assistant\n\n");
+                if !content.trim().is_empty() {
+                    let _ = writeln!(markdown, "{}\n", content.trim());
+                }
+                for call in tool_calls.iter().flatten() {
+                    let arguments = parse_arguments(call.arguments());
+                    let arguments = serde_json::to_string_pretty(&arguments)
+                        .unwrap_or_else(|_| call.arguments().to_owned());
+                    let _ = writeln!(
+                        markdown,
+                        "Called `{}`:\n\n{}\n",
+                        call.name(),
+                        fenced(&arguments, "json")
+                    );
+                }
+            }
+            Message::Tool { content, id } => {
+                let name = tool_names.get(id.as_str()).copied();
+                let _ = writeln!(
+                    markdown,
+                    "### Output of `{}`\n\n{}\n",
+                    name.unwrap_or(id),
+                    fenced(content.trim_end(), "")
+                );
+            }
+        }
+    }
+
+    markdown.truncate(markdown.trim_end().len());
+    markdown.push('\n');
+    markdown
+}
+
+/// The names of the tools called in `messages`, by the ids of the calls
+fn tool_names(messages: &[Message]) -> HashMap<&str, &str> {
+    messages
+        .iter()
+        .filter_map(|message| match message {
+            Message::Assistant(_, tool_calls) => tool_calls.as_ref(),
+            _ => None,
+        })
+        .flatten()
+        .map(|call| (call.id(), call.name()))
+        .collect()
+}
+
+fn parse_arguments(arguments: &str) -> Value {
+    serde_json::from_str(arguments)
+        .unwrap_or_else(|_| Value::String(arguments.to_owned()))
+}
+
+/// `text` in a fenced code block, with a fence longer than any run of
+/// backticks inside it
+fn fenced(text: &str, language: &str) -> String {
+    let longest_run =
+        text.split(|c| c != '`').map(str::len).max().unwrap_or_default();
+    let fence = "`".repeat(longest_run.max(2) + 1);
+
+    format!("{fence}{language}\n{text}\n{fence}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session::new(
+            "gpt-4o-mini",
+            vec![
+                Message::System("Be brief.".to_owned()),
+                Message::User("What is in src?".to_owned()),
+                Message::Assistant(
+                    String::new(),
+                    Some(vec![ToolCall::new("c1", "ls", r#"{"args":"src"}"#)]),
+                ),
+                Message::Tool {
+                    content: "main.rs\n```\n".to_owned(),
+                    id: "c1".to_owned(),
+                },
+                Message::Assistant("Just main.rs.".to_owned(), None),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_to_json() {
+        let json =
+            serde_json::from_str::<Value>(&to_json(&session()).unwrap())
+                .unwrap();
+
+        assert_eq!(json["model"], "gpt-4o-mini");
+        assert_eq!(json["messages"][1]["role"], "user");
+        assert_eq!(
+            json["messages"][2]["tool_calls"][0]["arguments"]["args"],
+            "src"
+        );
+        assert_eq!(json["messages"][3]["role"], "tool");
+        assert_eq!(json["messages"][3]["name"], "ls");
+        assert!(json["messages"][4].get("tool_calls").is_none());
+    }
+
+    #[test]
+    fn test_redact() {
+        let mut session = session();
+        session.messages.push(Message::User("key sk-secret".to_owned()));
+        let redactor = Redactor::new().with_secret("sk-secret");
+
+        let redacted = redact(&session, &redactor);
+        assert!(!to_markdown(&redacted).contains("sk-secret"));
+        assert_eq!(redacted.messages[..5], session.messages[..5]);
+    }
+
+    #[test]
+    fn test_to_markdown() {
+        let markdown = to_markdown(&session());
+
+        assert!(markdown.starts_with("# What is in src?\n"));
+        assert!(markdown.contains(
+            "## Assistant\n\nCalled `ls`:\n\n```json\n{\n  \"args\": \"src\"\n}\n```"
+        ));
+        assert!(
+            markdown
+                .contains("### Output of `ls`\n\n````\nmain.rs\n```\n````")
+        );
+        assert!(markdown.ends_with("## Assistant\n\nJust main.rs.\n"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Setting aido up on a machine without a config file
+//!
+//! Without a config file, aido would run with empty settings and fail at
+//! the first request with an error from the HTTP client. Instead, runs
+//! look for a local server first, Ollama or LM Studio on their default
+//! ports, and offer to write a config using it; otherwise they fail saying
+//! to run `aido init`, which writes one for a local server or `OpenAI`.
+
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{Config, ConfigError};
+use crate::confirm;
+use crate::llm::LlmClient;
+
+/// Local servers with an OpenAI-compatible API, by name, at their default
+/// addresses
+pub const LOCAL_ENDPOINTS: &[(&str, &str)] = &[
+    ("Ollama", "http://localhost:11434/v1"),
+    ("LM Studio", "http://localhost:1234/v1"),
+];
+
+/// How long a local server has to list its models before it is taken not
+/// to be running
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The API the config `aido init` writes points at when no local server is
+/// running
+const DEFAULT_API_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+const DEFAULT_API_KEY_ENV: &str = "OPENAI_API_KEY";
+
+/// A local server that answered, with the models it serves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalEndpoint {
+    pub name: &'static str,
+    pub api_url: &'static str,
+    pub models: Vec<String>,
+}
+
+impl LocalEndpoint {
+    /// A config using the server with `model`, or the first model it
+    /// serves, if it serves any
+    pub fn config(&self, model: Option<&str>) -> Option<Config> {
+        let model =
+            model.or_else(|| self.models.first().map(String::as_str))?;
+
+        Some(config_for(self.api_url, model))
+    }
+}
+
+/// The local servers that are running, in the order of
+/// [`LOCAL_ENDPOINTS`]
+pub async fn detect_local_endpoints() -> Vec<LocalEndpoint> {
+    let mut found = Vec::new();
+
+    for &(name, api_url) in LOCAL_ENDPOINTS {
+        let client = LlmClient::new("", "", api_url);
+        if let Ok(Ok(models)) =
+            tokio::time::timeout(PROBE_TIMEOUT, client.list_models()).await
+        {
+            found.push(LocalEndpoint { name, api_url, models });
+        }
+    }
+
+    found
+}
+
+/// A config for the API at `api_url` with `model`, and the defaults for
+/// everything else
+pub fn config_for(api_url: &str, model: &str) -> Config {
+    Config {
+        api_url: api_url.to_owned(),
+        model_name: model.to_owned(),
+        ..Config::default()
+    }
+}
+
+/// The config `aido init` writes: for the API at `api_url` if given,
+/// otherwise for the first local server running, otherwise for `OpenAI`
+/// with the key in `$OPENAI_API_KEY`
+pub async fn initial_config(
+    api_url: Option<&str>,
+    model: Option<&str>,
+) -> Config {
+    if let Some(api_url) = api_url {
+        return config_for(api_url, model.unwrap_or(DEFAULT_MODEL));
+    }
+
+    for endpoint in detect_local_endpoints().await {
+        if let Some(config) = endpoint.config(model) {
+            return config;
+        }
+    }
+
+    Config {
+        api_key_env: Some(DEFAULT_API_KEY_ENV.to_owned()),
+        ..config_for(DEFAULT_API_URL, model.unwrap_or(DEFAULT_MODEL))
+    }
+}
+
+/// Writes `config` to `path`, unless a file is there already and
+/// `overwrite` isn't set
+pub fn write_config(
+    path: &Path,
+    config: &Config,
+    overwrite: bool,
+) -> Result<(), ConfigError> {
+    if path.exists() && !overwrite {
+        return Err(ConfigError::AlreadyExists { path: path.to_owned() });
+    }
+
+    Ok(confy::store_path(path, config)?)
+}
+
+/// Called when there is no config file at `path`: offers to write one for
+/// a local server that is running, failing with what to do otherwise
+pub async fn first_run(path: &Path) -> Result<(), ConfigError> {
+    for endpoint in detect_local_endpoints().await {
+        let Some(config) = endpoint.config(None) else {
+            continue;
+        };
+        let question = format!(
+            "No config file yet. Use {} at {} with the model '{}'?",
+            endpoint.name, endpoint.api_url, config.model_name
+        );
+
+        if confirm::ask_on_terminal(&question).unwrap_or(false) {
+            write_config(path, &config, false)?;
+            eprintln!(
+                "Wrote {}; `aido config edit` changes it",
+                path.display()
+            );
+            return Ok(());
+        }
+    }
+
+    Err(ConfigError::NotConfigured { path: path.to_owned() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_endpoint_config() {
+        let endpoint = LocalEndpoint {
+            name: "Ollama",
+            api_url: "http://localhost:11434/v1",
+            models: vec!["llama3.2:3b".to_owned(), "qwen3:8b".to_owned()],
+        };
+
+        let config = endpoint.config(None).unwrap();
+        assert_eq!(config.api_url, "http://localhost:11434/v1");
+        assert_eq!(config.model_name, "llama3.2:3b");
+        assert_eq!(
+            endpoint.config(Some("qwen3:8b")).unwrap().model_name,
+            "qwen3:8b"
+        );
+
+        let empty = LocalEndpoint { models: Vec::new(), ..endpoint };
+        assert!(empty.config(None).is_none());
+    }
+
+    #[test]
+    fn test_write_config() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-setup-test-{}", std::process::id()));
+        let path = dir.join("aido.toml");
+        let config = config_for("http://localhost:1234/v1", "local-model");
+
+        write_config(&path, &config, false).unwrap();
+        assert!(matches!(
+            write_config(&path, &config, false),
+            Err(ConfigError::AlreadyExists { .. })
+        ));
+        write_config(&path, &config, true).unwrap();
+
+        let loaded = crate::config::retrieve_from_path(&path).unwrap();
+        assert_eq!(loaded.api_url, "http://localhost:1234/v1");
+        assert_eq!(loaded.model_name, "local-model");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
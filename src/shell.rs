@@ -0,0 +1,92 @@
+//! Running the shell command a model suggested
+//!
+//! `aido run <recipe> --exec` takes the command from the answer, shows it,
+//! and runs it in the user's shell once they confirm. Commands are
+//! [checked](check) first, and not run at all when they are broken or
+//! destructive.
+//!
+//! [`env`] writes the shell functions that bind a key to suggesting a
+//! command for the command line.
+
+pub mod check;
+pub mod env;
+
+use std::io;
+use std::process::{Command, ExitStatus};
+
+use crate::confirm;
+use crate::markdown;
+
+/// Finds the command suggested in an answer: the last code block, or the
+/// whole answer when it is a single line
+pub fn extract_command(answer: &str) -> Option<&str> {
+    if let Some(block) = markdown::code_blocks(answer).last() {
+        let block = block.trim();
+        return (!block.is_empty()).then_some(block);
+    }
+
+    let answer = answer.trim().trim_matches('`').trim();
+    (!answer.is_empty() && !answer.contains('\n')).then_some(answer)
+}
+
+/// Runs `command` in the user's shell, inheriting the terminal
+pub fn run_in_shell(command: &str) -> io::Result<ExitStatus> {
+    if cfg!(windows) {
+        return Command::new("cmd").args(["/C", command]).status();
+    }
+
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".into());
+    Command::new(shell).args(["-c", command]).status()
+}
+
+/// Prints what is wrong with `command` to stderr, returning whether it is
+/// fit to be copied or run
+pub fn check_and_report(command: &str) -> bool {
+    let problems = check::check(command);
+    for problem in &problems {
+        eprintln!("{problem}");
+    }
+
+    !problems.iter().any(check::Problem::is_blocking)
+}
+
+/// Shows `command` and runs it if the user agrees, returning its exit
+/// status, or `None` when the user declined
+pub fn confirm_and_run(command: &str) -> io::Result<Option<ExitStatus>> {
+    eprintln!("\n    {}\n", command.replace('\n', "\n    "));
+
+    if !confirm::ask_on_terminal("Run this command?")? {
+        return Ok(None);
+    }
+
+    run_in_shell(command).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_command() {
+        assert_eq!(
+            extract_command("tar -xzf photos.tar.gz\n"),
+            Some("tar -xzf photos.tar.gz")
+        );
+        assert_eq!(extract_command("`ls -al`"), Some("ls -al"));
+        assert_eq!(
+            extract_command(
+                "First:\n```\nls\n```\nThen:\n```sh\nrm a.txt\n```"
+            ),
+            Some("rm a.txt")
+        );
+        assert_eq!(extract_command("You could list\nthe files."), None);
+        assert_eq!(extract_command("  "), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_in_shell_reports_exit_status() {
+        assert!(run_in_shell("true").unwrap().success());
+        assert_eq!(run_in_shell("exit 3").unwrap().code(), Some(3));
+    }
+}
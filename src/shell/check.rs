@@ -0,0 +1,518 @@
+//! Checking a suggested shell command before it is copied or run
+//!
+//! Models get quoting wrong and, now and then, suggest something that
+//! wipes a disk. The command is cut into simple commands at the newlines
+//! and operators outside quotes, and each is split into words the way a
+//! POSIX shell would, which catches unbalanced quotes, then looked at for
+//! the classic ways of destroying a system. Command substitutions are
+//! flagged and checked as commands of their own. This is a safety net
+//! independent of the model, not a sandbox: anything it doesn't recognize
+//! passes.
+
+use std::fmt;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+/// Matches the `:(){ :|:& };:` fork bomb, whatever the function is called
+static FORK_BOMB_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\S+)\s*\(\)\s*\{\s*(\S+)\s*\|\s*(\S+)\s*&\s*\}\s*;\s*(\S+)")
+        .unwrap()
+});
+
+/// What ends one simple command and starts the next, longest first
+const SEPARATORS: &[&str] = &["||", "&&", "|&", "\n", ";", "|", "&", "(", ")"];
+
+/// Words that start a compound command, after which the program follows
+const RESERVED_WORDS: &[&str] =
+    &["{", "}", "!", "if", "then", "else", "elif", "while", "until", "do"];
+
+/// Paths whose recursive removal takes everything with it
+const CRITICAL_PATHS: &[&str] =
+    &["/", "/*", "~", "~/", "~/*", "$HOME", "$HOME/", "*", ".", "./*", ".."];
+
+/// Programs that, fed a script, run it
+const SHELLS: &[&str] = &["sh", "bash", "zsh", "dash", "ksh", "fish"];
+
+/// Programs that download things
+const DOWNLOADERS: &[&str] = &["curl", "wget"];
+
+/// How bad a problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Worth a second look, but the command may be what the user wants
+    Warning,
+    /// The command is broken or destructive and is not copied or run
+    Blocking,
+}
+
+/// Something wrong with a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Problem {
+    fn warning(message: impl Into<String>) -> Self {
+        Self { severity: Severity::Warning, message: message.into() }
+    }
+
+    fn blocking(message: impl Into<String>) -> Self {
+        Self { severity: Severity::Blocking, message: message.into() }
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.severity == Severity::Blocking
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "Warning: {}", self.message),
+            Severity::Blocking => write!(f, "Blocked: {}", self.message),
+        }
+    }
+}
+
+/// Everything wrong with `command`, most severe first
+pub fn check(command: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    if FORK_BOMB_REGEX.captures(command).is_some_and(|captures| {
+        (2..=4).all(|group| captures[group] == captures[1])
+    }) {
+        problems.push(Problem::blocking(
+            "the command is a fork bomb, which freezes the system",
+        ));
+    }
+
+    let scanned = scan(command);
+    if scanned.unclosed_substitution {
+        problems.push(Problem::blocking(
+            "the command has a command substitution that is never closed, \
+             so the shell would not run it as written",
+        ));
+    }
+    for substitution in &scanned.substitutions {
+        problems.push(Problem::warning(format!(
+            "the command runs `{}` through a command substitution and \
+             uses its output as part of the command",
+            substitution.trim()
+        )));
+        problems.extend(check(substitution));
+    }
+
+    let mut previous: Option<String> = None;
+    let mut piped = false;
+    for (text, separator) in scanned.commands {
+        let Some(words) = shlex::split(text) else {
+            problems.push(Problem::blocking(
+                "the command has unbalanced quotes or ends with a \
+                 backslash, so the shell would not run it as written",
+            ));
+            continue;
+        };
+        // Blank lines, and the line break after a `|`, join what is around
+        // them
+        if words.is_empty() {
+            continue;
+        }
+
+        let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+        problems.extend(check_simple_command(
+            &words,
+            piped.then_some(previous.as_deref()).flatten(),
+        ));
+
+        previous = words.first().map(|word| program_name(word).to_owned());
+        piped = matches!(separator, Some("|" | "|&"));
+    }
+
+    problems.sort_by_key(|problem| std::cmp::Reverse(problem.severity));
+    problems.dedup();
+    problems
+}
+
+/// A command cut into the parts the shell runs separately
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Scanned<'a> {
+    /// The simple commands, each with the separator that follows it
+    commands: Vec<(&'a str, Option<&'static str>)>,
+    /// What is inside each `$(...)` and pair of backticks
+    substitutions: Vec<&'a str>,
+    /// A `$(` or backtick is never closed
+    unclosed_substitution: bool,
+}
+
+/// Cuts `command` at the separators outside quotes, comments and command
+/// substitutions, collecting the substitutions along the way
+fn scan(command: &str) -> Scanned<'_> {
+    let bytes = command.as_bytes();
+    let mut scanned = Scanned::default();
+    let mut quote = None;
+    let mut start = 0;
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let rest = &command[index..];
+        match (quote, bytes[index]) {
+            (Some(b'\''), b'\'') | (Some(b'"'), b'"') => quote = None,
+            (Some(b'\''), _) => {}
+            // Whatever follows a backslash is taken as is
+            (_, b'\\') => index += 1,
+            (_, b'`') => {
+                let Some(length) = closing_backtick(&rest[1..]) else {
+                    scanned.unclosed_substitution = true;
+                    break;
+                };
+                scanned.substitutions.push(&rest[1..=length]);
+                index += length + 1;
+            }
+            (_, b'$') if rest.starts_with("$(") => {
+                let Some(length) = closing_paren(&rest[2..]) else {
+                    scanned.unclosed_substitution = true;
+                    break;
+                };
+                // `$((...))` is arithmetic, which runs nothing
+                if !rest.starts_with("$((") {
+                    scanned.substitutions.push(&rest[2..2 + length]);
+                }
+                index += length + 2;
+            }
+            (Some(_), _) => {}
+            (None, b'\'' | b'"') => quote = Some(bytes[index]),
+            (None, b'#')
+                if index == 0 || bytes[index - 1].is_ascii_whitespace() =>
+            {
+                index += rest.find('\n').unwrap_or(rest.len());
+                continue;
+            }
+            // `>&`, `&>` and `>|` redirect rather than separate
+            (None, b'&' | b'|')
+                if index > 0 && matches!(bytes[index - 1], b'>' | b'<')
+                    || rest.starts_with("&>") => {}
+            (None, _) => {
+                if let Some(separator) = SEPARATORS
+                    .iter()
+                    .find(|separator| rest.starts_with(**separator))
+                {
+                    scanned
+                        .commands
+                        .push((&command[start..index], Some(separator)));
+                    index += separator.len();
+                    start = index;
+                    continue;
+                }
+            }
+        }
+        index += 1;
+    }
+    scanned.commands.push((&command[start..], None));
+
+    scanned
+}
+
+/// The length of the text before the backtick closing a substitution
+fn closing_backtick(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 1,
+            b'`' => return Some(index),
+            _ => {}
+        }
+        index += 1;
+    }
+
+    None
+}
+
+/// The length of the text before the parenthesis closing a substitution,
+/// skipping nested parentheses and quoted text
+fn closing_paren(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0_usize;
+    let mut quote = None;
+    let mut index = 0;
+    while index < bytes.len() {
+        match (quote, bytes[index]) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(b'\''), _) => {}
+            (_, b'\\') => index += 1,
+            (None, c @ (b'\'' | b'"')) => quote = Some(c),
+            (None, b'(') => depth += 1,
+            (None, b')') if depth == 0 => return Some(index),
+            (None, b')') => depth -= 1,
+            _ => {}
+        }
+        index += 1;
+    }
+
+    None
+}
+
+/// The name of the program `word` runs, without its directory
+fn program_name(word: &str) -> &str {
+    word.rsplit('/').next().unwrap_or(word)
+}
+
+/// The problems with a single command, `piped_from` being the program
+/// whose output it reads, if any
+fn check_simple_command(
+    words: &[&str],
+    piped_from: Option<&str>,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    // Look past the programs that run the rest of the line
+    let mut words = words;
+    while let Some((&first, rest)) = words.split_first() {
+        match program_name(first) {
+            "sudo" | "doas" => {
+                problems.push(Problem::warning(format!(
+                    "the command runs as root through {first}"
+                )));
+            }
+            "env" | "nohup" | "nice" | "time" | "command" | "exec" => {}
+            word if RESERVED_WORDS.contains(&word) => {}
+            _ => break,
+        }
+        words = rest;
+        while let Some((&flag, rest)) = words.split_first()
+            && (flag.starts_with('-') || flag.contains('='))
+        {
+            words = rest;
+        }
+    }
+
+    let Some((&program, args)) = words.split_first() else {
+        return problems;
+    };
+    let flags = || args.iter().filter(|arg| arg.starts_with('-'));
+    let operands = || args.iter().filter(|arg| !arg.starts_with('-'));
+    let recursive = flags().any(|flag| {
+        *flag == "--recursive"
+            || (!flag.starts_with("--")
+                && (flag.contains('r') || flag.contains('R')))
+    });
+
+    match program_name(program) {
+        "rm" if recursive => {
+            if let Some(path) =
+                operands().find(|path| CRITICAL_PATHS.contains(path))
+            {
+                problems.push(Problem::blocking(format!(
+                    "`rm` would recursively delete everything in {path}"
+                )));
+            } else if let Some(path) =
+                operands().find(|path| path.contains('$'))
+            {
+                problems.push(Problem::warning(format!(
+                    "`rm` recursively deletes {path}, which depends on \
+                     a variable that may be empty"
+                )));
+            }
+            if flags().any(|flag| *flag == "--no-preserve-root") {
+                problems.push(Problem::blocking(
+                    "`rm --no-preserve-root` can delete the whole system",
+                ));
+            }
+        }
+        "chmod" | "chown" | "chgrp" if recursive => {
+            if let Some(path) = operands()
+                .find(|path| ["/", "/*", "~", "$HOME"].contains(path))
+            {
+                problems.push(Problem::blocking(format!(
+                    "`{program}` would recursively change everything in \
+                     {path}"
+                )));
+            }
+        }
+        name if name.starts_with("mkfs") => {
+            problems.push(Problem::blocking(format!(
+                "`{name}` formats a disk, erasing what is on it"
+            )));
+        }
+        "dd" => {
+            if let Some(target) =
+                operands().find_map(|arg| arg.strip_prefix("of=/dev/"))
+                && !is_harmless_device(target)
+            {
+                problems.push(Problem::blocking(format!(
+                    "`dd` would overwrite the device /dev/{target}"
+                )));
+            }
+        }
+        name if SHELLS.contains(&name)
+            && piped_from.is_some_and(|from| DOWNLOADERS.contains(&from)) =>
+        {
+            problems.push(Problem::warning(format!(
+                "the command runs a downloaded script with {name} without \
+                 showing it first"
+            )));
+        }
+        _ => {}
+    }
+
+    if let Some(device) = redirect_targets(words)
+        .find_map(|target| target.strip_prefix("/dev/"))
+        .filter(|device| !is_harmless_device(device))
+    {
+        problems.push(Problem::blocking(format!(
+            "the command would overwrite the device /dev/{device}"
+        )));
+    }
+
+    problems
+}
+
+/// Devices it is fine to write to
+fn is_harmless_device(device: &str) -> bool {
+    ["null", "zero", "stdout", "stderr", "tty"].contains(&device)
+        || device.starts_with("fd/")
+        || device.starts_with("pts/")
+}
+
+/// The files the output of a command is redirected to
+fn redirect_targets<'a>(
+    words: &'a [&'a str],
+) -> impl Iterator<Item = &'a str> + 'a {
+    words.iter().enumerate().filter_map(|(index, word)| {
+        let target = word.trim_start_matches(|c: char| c.is_ascii_digit());
+        let target = target
+            .strip_prefix(">>")
+            .or_else(|| target.strip_prefix('>'))?
+            .trim_start_matches(['|', '&']);
+        if target.is_empty() {
+            words.get(index + 1).copied()
+        } else {
+            Some(target)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(command: &str) -> Vec<String> {
+        check(command).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_ordinary_commands_pass() {
+        for command in [
+            "ls -al",
+            "rm -rf ./target",
+            "find . -name '*.tmp' -delete",
+            r#"git commit -m "it's done""#,
+            "cargo build 2>/dev/null && echo ok",
+            "dd if=/dev/zero of=disk.img bs=1M count=10",
+            "chmod -R u+w ./build",
+        ] {
+            assert_eq!(messages(command), Vec::<String>::new(), "{command}");
+        }
+    }
+
+    #[test]
+    fn test_unbalanced_quotes_block() {
+        assert_eq!(
+            messages("echo 'hello"),
+            ["Blocked: the command has unbalanced quotes or ends with a \
+              backslash, so the shell would not run it as written"]
+        );
+        assert!(check(r#"grep "foo file.txt"#)[0].is_blocking());
+    }
+
+    #[test]
+    fn test_destructive_commands_block() {
+        for command in [
+            "rm -rf /",
+            "sudo rm -fr / --no-preserve-root",
+            "cd /tmp; rm -r -f ~",
+            "rm --recursive --force *",
+            "mkfs.ext4 /dev/sda1",
+            "dd if=image.iso of=/dev/sdb bs=4M",
+            "cat image > /dev/nvme0n1",
+            "chmod -R 777 /",
+            ":(){ :|:& };:",
+        ] {
+            assert!(
+                check(command).first().is_some_and(Problem::is_blocking),
+                "{command}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_risky_commands_warn() {
+        assert_eq!(
+            messages("curl -fsSL https://example.com/install.sh | bash"),
+            ["Warning: the command runs a downloaded script with bash \
+              without showing it first"]
+        );
+        assert_eq!(
+            messages("sudo apt update"),
+            ["Warning: the command runs as root through sudo"]
+        );
+        assert_eq!(
+            messages(r#"rm -rf "$BUILD_DIR/""#),
+            ["Warning: `rm` recursively deletes $BUILD_DIR/, which depends \
+              on a variable that may be empty"]
+        );
+    }
+
+    #[test]
+    fn test_multi_line_commands_are_checked() {
+        assert!(check("cd /tmp\nrm -rf ~")[0].is_blocking());
+        assert!(check("echo cleaning up\n\n  rm -rf /\n")[0].is_blocking());
+        assert!(check("if true; then\n  rm -rf ~\nfi")[0].is_blocking());
+        assert!(check("(cd /tmp && rm -rf ~)")[0].is_blocking());
+        assert_eq!(
+            messages("curl -fsSL https://example.com/x.sh |\n  sh"),
+            ["Warning: the command runs a downloaded script with sh \
+              without showing it first"]
+        );
+        assert_eq!(
+            messages("# rm -rf / is what not to do; ls\nls -al"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_command_substitutions_are_flagged() {
+        assert_eq!(
+            messages("echo $(date)"),
+            ["Warning: the command runs `date` through a command \
+              substitution and uses its output as part of the command"]
+        );
+        assert!(check("echo \"`rm -rf /`\"")[0].is_blocking());
+        assert!(check("ls $(rm -rf ~; echo .)")[0].is_blocking());
+        assert!(check("echo $(ls")[0].is_blocking());
+        assert_eq!(
+            messages("echo '$(date)' $((1 + 2))"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let scanned =
+            scan("cd /tmp; ls -a | wc -l && echo \"a;b\" 2>&1\necho done");
+
+        assert_eq!(
+            scanned.commands,
+            [
+                ("cd /tmp", Some(";")),
+                (" ls -a ", Some("|")),
+                (" wc -l ", Some("&&")),
+                (" echo \"a;b\" 2>&1", Some("\n")),
+                ("echo done", None),
+            ]
+        );
+        assert!(scanned.substitutions.is_empty());
+    }
+}
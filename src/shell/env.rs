@@ -0,0 +1,161 @@
+//! Shell functions and key bindings for aido
+//!
+//! `eval "$(aido shellenv --shell bash)"` in `~/.bashrc`, or the same for
+//! zsh, or `aido shellenv --shell fish | source` in `config.fish`, binds
+//! Ctrl-X Ctrl-A to send the command line to a recipe, `do` unless
+//! `--recipe` names another, and replace it with the command the answer
+//! suggests, for the user to check and run. The scripts are generated
+//! here, from the arguments [`invocation`] gives, so they keep up with the
+//! command line of aido.
+
+use std::fmt::Write as _;
+
+use clap::ValueEnum;
+
+use crate::output::OutputFormat;
+
+/// Recipe the key binding sends the command line to by default
+pub const DEFAULT_RECIPE: &str = "do";
+
+/// Name of the shell function the key is bound to
+const FUNCTION_NAME: &str = "__aido_suggest";
+
+/// A shell `aido shellenv` writes a script for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ShellKind {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// The arguments the key binding runs aido with, before the command line
+pub fn invocation(recipe: &str) -> Vec<String> {
+    let output = OutputFormat::Command
+        .to_possible_value()
+        .expect("Output formats are all possible values");
+
+    vec![
+        "run".to_owned(),
+        recipe.to_owned(),
+        "--output".to_owned(),
+        output.get_name().to_owned(),
+        "--".to_owned(),
+    ]
+}
+
+/// The script setting up `shell` to run `program` on the command line with
+/// `recipe`
+pub fn script(shell: ShellKind, program: &str, recipe: &str) -> String {
+    let quote = match shell {
+        ShellKind::Bash | ShellKind::Zsh => posix_quote,
+        ShellKind::Fish => fish_quote,
+    };
+    let mut command = quote(program);
+    for arg in invocation(recipe) {
+        let _ = write!(command, " {}", quote(&arg));
+    }
+
+    match shell {
+        ShellKind::Bash => bash(&command),
+        ShellKind::Zsh => zsh(&command),
+        ShellKind::Fish => fish(&command),
+    }
+}
+
+fn bash(command: &str) -> String {
+    format!(
+        r#"# aido key binding for bash; Ctrl-X Ctrl-A replaces the command line
+# with the command aido suggests for it
+{FUNCTION_NAME}() {{
+    [[ -n $READLINE_LINE ]] || return
+    local suggestion
+    suggestion=$({command} "$READLINE_LINE") || return
+    READLINE_LINE=$suggestion
+    READLINE_POINT=${{#READLINE_LINE}}
+}}
+bind -x '"\C-x\C-a": {FUNCTION_NAME}'
+"#
+    )
+}
+
+fn zsh(command: &str) -> String {
+    format!(
+        r#"# aido key binding for zsh; Ctrl-X Ctrl-A replaces the command line
+# with the command aido suggests for it
+{FUNCTION_NAME}() {{
+    [[ -n $BUFFER ]] || return
+    local suggestion
+    zle -I
+    if suggestion=$({command} "$BUFFER"); then
+        BUFFER=$suggestion
+        CURSOR=${{#BUFFER}}
+    fi
+    zle reset-prompt
+}}
+zle -N {FUNCTION_NAME}
+bindkey '^X^A' {FUNCTION_NAME}
+"#
+    )
+}
+
+fn fish(command: &str) -> String {
+    format!(
+        r#"# aido key binding for fish; Ctrl-X Ctrl-A replaces the command line
+# with the command aido suggests for it
+function {FUNCTION_NAME}
+    set -l line (commandline)
+    test -n "$line"; or return
+    set -l suggestion ({command} "$line" | string collect)
+    and commandline --replace -- $suggestion
+    commandline --function repaint
+end
+bind \cx\ca {FUNCTION_NAME}
+"#
+    )
+}
+
+/// `arg` quoted for bash and zsh
+fn posix_quote(arg: &str) -> String {
+    shlex::try_quote(arg).map_or_else(
+        |_| format!("'{}'", arg.replace('\'', r"'\''")),
+        Into::into,
+    )
+}
+
+/// `arg` quoted for fish, whose single quotes only escape `\` and `'`
+fn fish_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
+    {
+        return arg.to_owned();
+    }
+
+    format!("'{}'", arg.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let bash = script(ShellKind::Bash, "/opt/my tools/aido", "do");
+        assert!(bash.contains(
+            "suggestion=$('/opt/my tools/aido' run do --output command -- \
+             \"$READLINE_LINE\")"
+        ));
+        assert!(bash.contains(r#"bind -x '"\C-x\C-a": __aido_suggest'"#));
+
+        let zsh = script(ShellKind::Zsh, "aido", "shell");
+        assert!(
+            zsh.contains("$(aido run shell --output command -- \"$BUFFER\")")
+        );
+        assert!(zsh.contains("bindkey '^X^A' __aido_suggest"));
+
+        let fish = script(ShellKind::Fish, "/opt/it's/aido", "do");
+        assert!(fish.contains(
+            r#"('/opt/it\'s/aido' run do --output command -- "$line" | string collect)"#
+        ));
+        assert!(fish.contains(r"bind \cx\ca __aido_suggest"));
+    }
+}
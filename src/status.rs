@@ -0,0 +1,245 @@
+//! A spinner and status line shown while nothing else is being printed
+//!
+//! Waiting for the first token or for a slow tool otherwise leaves the
+//! terminal silent, which looks like a hang. [`StatusLine`] draws a spinner
+//! with what is going on and for how long on stderr, from a thread of its
+//! own so that tools blocking the runtime don't freeze it, and erases it
+//! again before any real output is printed.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::tools::ToolInput;
+
+/// Frames of the spinner animation
+const FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Time between two frames; also how long a status stays invisible, so
+/// quick steps don't flicker
+const TICK: Duration = Duration::from_millis(100);
+
+/// Longest description of a tool call shown, in characters
+const MAX_DESCRIPTION_CHARS: usize = 60;
+
+/// Moves to the start of the line and erases it
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+#[derive(Default)]
+struct State {
+    /// What is being waited for and since when, if anything
+    status: Option<(String, Instant)>,
+    /// Whether the line currently shows a status
+    drawn: bool,
+    stopped: bool,
+}
+
+impl State {
+    fn erase(&mut self) {
+        if self.drawn {
+            eprint!("{CLEAR_LINE}");
+            io::stderr().flush().ok();
+            self.drawn = false;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A status line on stderr, removed again when dropped
+///
+/// A disabled status line does nothing, so callers don't need to check
+/// whether stderr is a terminal.
+pub struct StatusLine {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StatusLine {
+    /// Creates a status line, drawn only if `enabled`
+    pub fn new(enabled: bool) -> Self {
+        let shared = Arc::new(Shared::default());
+        let thread = enabled.then(|| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || animate(&shared))
+        });
+
+        Self { shared, thread }
+    }
+
+    /// Shows `message` with a spinner and the time since this call
+    pub fn set(&self, message: impl Into<String>) {
+        if self.thread.is_some() {
+            self.shared.lock().status = Some((message.into(), Instant::now()));
+        }
+    }
+
+    /// Hides the status line; once this returns, the line is erased
+    pub fn clear(&self) {
+        if self.thread.is_some() {
+            let mut state = self.shared.lock();
+            state.status = None;
+            state.erase();
+        }
+    }
+
+    /// A handle printing lines to stderr above the status line, for what
+    /// is printed while a status is shown
+    pub fn printer(&self) -> Printer {
+        Printer {
+            shared: self.thread.is_some().then(|| Arc::clone(&self.shared)),
+        }
+    }
+}
+
+/// Prints lines to stderr without mangling the [`StatusLine`] there
+#[derive(Clone)]
+pub struct Printer {
+    shared: Option<Arc<Shared>>,
+}
+
+impl Printer {
+    /// Prints `line` and a line break; a status line shown is erased first
+    /// and drawn again below on its next tick
+    #[allow(clippy::significant_drop_tightening)] // Held while printing
+    pub fn print_line(&self, line: &str) {
+        let mut state = self.shared.as_ref().map(|shared| shared.lock());
+        if let Some(state) = &mut state {
+            state.erase();
+        }
+        eprintln!("{line}");
+    }
+}
+
+impl Drop for StatusLine {
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.lock();
+            state.stopped = true;
+            state.erase();
+        }
+        self.shared.wake.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Redraws the status line every [`TICK`] until it is dropped
+#[allow(clippy::significant_drop_tightening)] // Released while waiting
+fn animate(shared: &Shared) {
+    let mut frame = 0;
+    let mut state = shared.lock();
+
+    while !state.stopped {
+        if let Some((message, since)) = &state.status {
+            let elapsed = since.elapsed();
+            if elapsed >= TICK {
+                eprint!("{CLEAR_LINE}{}", render(frame, message, elapsed));
+                io::stderr().flush().ok();
+                state.drawn = true;
+                frame += 1;
+            }
+        }
+
+        state = shared
+            .wake
+            .wait_timeout(state, TICK)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+    }
+}
+
+/// One frame of the status line
+fn render(frame: usize, message: &str, elapsed: Duration) -> String {
+    format!(
+        "{} {message} ({:.1}s)",
+        FRAMES[frame % FRAMES.len()],
+        elapsed.as_secs_f64()
+    )
+}
+
+/// Describes a tool call for the status line, e.g. `ls -alh`
+pub fn describe_tool_call(name: &str, input: &ToolInput) -> String {
+    let mut keys = input.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    let mut description = name.to_owned();
+    for key in keys {
+        match &input[key] {
+            serde_json::Value::String(value) if value.is_empty() => {}
+            serde_json::Value::String(value) => {
+                description.push(' ');
+                description.push_str(value);
+            }
+            serde_json::Value::Null => {}
+            value => {
+                description.push(' ');
+                description.push_str(&value.to_string());
+            }
+        }
+    }
+
+    // Keep the status on a single line
+    let description = description.replace(['\n', '\r'], " ");
+    match description.char_indices().nth(MAX_DESCRIPTION_CHARS) {
+        Some((end, _)) => format!("{}…", &description[..end]),
+        None => description,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_tool_call() {
+        let input = ToolInput::from([("args".to_owned(), "-alh".into())]);
+        assert_eq!(describe_tool_call("ls", &input), "ls -alh");
+
+        let input = ToolInput::from([
+            ("pattern".to_owned(), "fn main".into()),
+            ("max_results".to_owned(), 5.into()),
+        ]);
+        assert_eq!(describe_tool_call("search", &input), "search 5 fn main");
+
+        let input = ToolInput::from([(
+            "command".to_owned(),
+            format!("echo one\necho {}", "x".repeat(100)).into(),
+        )]);
+        let description = describe_tool_call("exec", &input);
+        assert!(description.starts_with("exec echo one echo xxx"));
+        assert_eq!(description.chars().count(), MAX_DESCRIPTION_CHARS + 1);
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(0, "waiting for the model", Duration::from_millis(1250)),
+            "⠋ waiting for the model (1.2s)"
+        );
+        assert!(
+            render(FRAMES.len() + 1, "x", Duration::ZERO).starts_with('⠙')
+        );
+    }
+
+    #[test]
+    fn test_disabled_status_line_is_inert() {
+        let status = StatusLine::new(false);
+        status.set("waiting");
+        assert!(status.shared.lock().status.is_none());
+        status.clear();
+    }
+}
@@ -0,0 +1,173 @@
+//! Counting the tokens of a request before it is sent
+//!
+//! Elsewhere tokens are estimated at four characters each, which is good
+//! enough to decide what fits but not to tell what a prompt costs.
+//! `aido tokens` counts them with a BPE tokenizer instead: the model's own
+//! when it is a GPT model whose name is recognized, and `o200k_base`, that
+//! of the recent GPT models, for any other. Other models tokenize
+//! differently, so for them the count is an estimate.
+
+use std::fmt;
+
+use serde::Serialize;
+use tiktoken_rs::CoreBPE;
+use tiktoken_rs::tokenizer::{Tokenizer, get_tokenizer};
+
+use crate::llm::Message;
+use crate::tools::ToolDefinition;
+
+/// Tokens every message takes up beyond its content, for its role and the
+/// delimiters around it
+const MESSAGE_OVERHEAD_TOKENS: usize = 3;
+
+/// Tokens that start the reply, once per request
+const REPLY_PRIMING_TOKENS: usize = 3;
+
+/// The tokens a request takes up, by part
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TokenCount {
+    /// Name of the tokenizer that counted them
+    pub tokenizer: &'static str,
+    /// The system prompt: the recipe, with the configured prelude
+    pub system: usize,
+    /// The example exchanges of the recipe
+    pub examples: usize,
+    /// The user's message
+    pub message: usize,
+    /// The definitions of the tools offered to the model
+    pub tools: usize,
+    /// What the chat format adds around the messages
+    pub overhead: usize,
+}
+
+impl TokenCount {
+    /// Counts the tokens of a request to `model` starting the conversation
+    /// `messages` and offering `tools`
+    ///
+    /// A user message after the last reply is taken to be the user's
+    /// message, and any exchange before it an example.
+    pub fn of_request(
+        model: &str,
+        messages: &[Message],
+        tools: &[ToolDefinition],
+    ) -> Self {
+        let (tokenizer, bpe) = tokenizer_for(model);
+        let count = |text: &str| bpe.encode_with_special_tokens(text).len();
+
+        let mut tokens = Self {
+            tokenizer: tokenizer_name(tokenizer),
+            overhead: REPLY_PRIMING_TOKENS
+                + messages.len() * MESSAGE_OVERHEAD_TOKENS,
+            ..Self::default()
+        };
+        for (index, message) in messages.iter().enumerate() {
+            match message {
+                Message::System(content) => tokens.system += count(content),
+                Message::User(content) if index == messages.len() - 1 => {
+                    tokens.message += count(content);
+                }
+                Message::User(content)
+                | Message::Assistant(content, _)
+                | Message::Tool { content, .. } => {
+                    tokens.examples += count(content);
+                }
+            }
+        }
+        tokens.tools = tools
+            .iter()
+            .map(|tool| count(&tool.json_value().to_string()))
+            .sum();
+
+        tokens
+    }
+
+    pub const fn total(&self) -> usize {
+        self.system + self.examples + self.message + self.tools + self.overhead
+    }
+}
+
+impl fmt::Display for TokenCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (part, tokens) in [
+            ("System prompt", self.system),
+            ("Examples", self.examples),
+            ("Message", self.message),
+            ("Tools", self.tools),
+            ("Chat format", self.overhead),
+        ] {
+            if tokens > 0 {
+                writeln!(f, "{part:<14} {tokens:>7}")?;
+            }
+        }
+        write!(f, "{:<14} {:>7} ({})", "Total", self.total(), self.tokenizer)
+    }
+}
+
+/// The tokenizer of the GPT model named `model`, ignoring any `provider/`
+/// prefix, or `o200k_base` when the name isn't recognized
+fn tokenizer_for(model: &str) -> (Tokenizer, &'static CoreBPE) {
+    let name = model.rsplit('/').next().unwrap_or(model);
+    let tokenizer = get_tokenizer(name).unwrap_or(Tokenizer::O200kBase);
+
+    let bpe = match tokenizer {
+        Tokenizer::O200kBase => tiktoken_rs::o200k_base_singleton(),
+        Tokenizer::Cl100kBase => tiktoken_rs::cl100k_base_singleton(),
+        Tokenizer::P50kBase => tiktoken_rs::p50k_base_singleton(),
+        Tokenizer::P50kEdit => tiktoken_rs::p50k_edit_singleton(),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => {
+            tiktoken_rs::r50k_base_singleton()
+        }
+    };
+
+    (tokenizer, bpe)
+}
+
+const fn tokenizer_name(tokenizer: Tokenizer) -> &'static str {
+    match tokenizer {
+        Tokenizer::O200kBase => "o200k_base",
+        Tokenizer::Cl100kBase => "cl100k_base",
+        Tokenizer::P50kBase => "p50k_base",
+        Tokenizer::P50kEdit => "p50k_edit",
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => "r50k_base",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::ToolDefinitionBuilder;
+
+    #[test]
+    fn test_tokenizer_for() {
+        assert_eq!(tokenizer_for("gpt-4o-mini").0, Tokenizer::O200kBase);
+        assert_eq!(tokenizer_for("gpt-4-0613").0, Tokenizer::Cl100kBase);
+        assert_eq!(tokenizer_for("openai/gpt-4").0, Tokenizer::Cl100kBase);
+        assert_eq!(tokenizer_for("llama3.2:3b").0, Tokenizer::O200kBase);
+    }
+
+    #[test]
+    fn test_of_request() {
+        let messages = [
+            Message::System("You are terse.".to_owned()),
+            Message::User("untar a.tgz".to_owned()),
+            Message::Assistant("tar -xzf a.tgz".to_owned(), None),
+            Message::User("hello world".to_owned()),
+        ];
+        let tools = [ToolDefinitionBuilder::new("ls")
+            .description("List files")
+            .build()];
+
+        let tokens = TokenCount::of_request("gpt-4o", &messages, &tools);
+
+        assert_eq!(tokens.tokenizer, "o200k_base");
+        assert_eq!(tokens.system, 4);
+        assert_eq!(tokens.message, 2);
+        assert!(tokens.examples > 0);
+        assert!(tokens.tools > 0);
+        assert_eq!(tokens.overhead, 15);
+        assert_eq!(
+            tokens.total(),
+            4 + tokens.examples + 2 + tokens.tools + 15
+        );
+    }
+}
@@ -1,23 +1,142 @@
+mod custom;
+pub mod exec;
+pub mod format;
+mod git;
 mod ls;
-
+mod patch;
+pub mod process;
+mod registry;
+pub mod sandbox;
+mod search;
+mod system;
+
+pub use custom::{CustomArg, CustomTool, CustomToolConfig};
+pub use exec::ExecBackend;
+pub use git::{GitDiff, GitLog, GitStatus};
 pub use ls::Ls;
+pub use patch::ApplyPatch;
+pub use registry::{ToolRegistry, ToolsConfig};
+pub use search::Search;
+pub use system::{Df, Ps, Uname};
 
 use core::fmt;
 use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use serde::Serialize;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value, json};
 
+use crate::config::Config;
+use crate::error::AidoResult;
+
 pub type ToolInput = HashMap<String, Value>;
 
-pub trait Tool {
+/// The tools aido ships with, set up according to `config`, whether or
+/// not the config disables them
+pub fn builtin(config: &Config) -> Vec<Box<dyn Tool>> {
+    vec![
+        Box::new(Ls::new(config.exec.clone())),
+        Box::new(Search::new()),
+        Box::new(GitStatus::new(config.exec.clone())),
+        Box::new(GitDiff::new(config.exec.clone())),
+        Box::new(GitLog::new(config.exec.clone())),
+        Box::new(ApplyPatch::new()),
+        Box::new(Ps::new()),
+        Box::new(Df::new()),
+        Box::new(Uname::new()),
+    ]
+}
+
+/// Names of the built-in tools runs are only offered when `enabled` under
+/// `[tools]` in the config names them
+pub const OPT_IN: &[&str] =
+    &["apply_patch", "ps", "df", "uname", crate::retrieval::TOOL_NAME];
+
+/// What a tool is able to do to the user's machine, from least to most
+/// dangerous
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    /// Only reads information
+    Read,
+    /// Modifies files
+    Write,
+    /// Executes arbitrary commands
+    Exec,
+}
+
+/// Asks the user a yes/no question, returning whether they agreed
+pub type Ask = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// What a tool call is made in
+#[derive(Clone)]
+pub struct ToolContext {
+    /// The directory the tool works in and resolves relative paths
+    /// against: the one aido runs in, or the worktree of an isolated run
+    pub working_dir: PathBuf,
+    /// Who tools that ask before changing anything ask; without anyone to
+    /// ask, the answer is no
+    pub ask: Option<Ask>,
+}
+
+impl ToolContext {
+    pub const fn new(working_dir: PathBuf) -> Self {
+        Self { working_dir, ask: None }
+    }
+
+    /// A context for calls made in the directory aido runs in
+    pub fn current() -> io::Result<Self> {
+        Ok(Self::new(std::env::current_dir()?))
+    }
+
+    /// Asks the user with `ask` for the tools that confirm themselves
+    #[must_use]
+    pub fn with_ask(mut self, ask: Ask) -> Self {
+        self.ask = Some(ask);
+        self
+    }
+
+    /// Asks the user `question`, taking no one to ask for a no
+    pub fn confirm(&self, question: &str) -> bool {
+        self.ask.as_ref().is_some_and(|ask| ask(question))
+    }
+}
+
+impl fmt::Debug for ToolContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ToolContext")
+            .field("working_dir", &self.working_dir)
+            .field("ask", &self.ask.is_some())
+            .finish()
+    }
+}
+
+#[async_trait]
+pub trait Tool: Send + Sync {
     fn definition(&self) -> &ToolDefinition;
 
+    /// The most dangerous thing this tool can do
+    fn capability(&self) -> Capability {
+        Capability::Read
+    }
+
+    /// Whether the tool asks the user itself before changing anything,
+    /// whatever the recipe's `confirm` says, so that it isn't asked twice
+    fn confirms_itself(&self) -> bool {
+        false
+    }
+
     /// Executes the tool with the given input and returns a result.
-    fn execute(
+    async fn execute(
         &self,
         input: ToolInput,
-    ) -> Result<String, Box<dyn std::error::Error>>;
+        context: &ToolContext,
+    ) -> AidoResult<String>;
 }
 
 impl fmt::Debug for dyn Tool {
@@ -29,8 +148,12 @@ impl fmt::Debug for dyn Tool {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum ArgType {
+    #[default]
     String,
     Number,
     Integer,
@@ -59,6 +182,12 @@ pub struct Arg {
     kind: ArgType,
     enum_vals: Option<Vec<String>>,
     required: bool,
+    /// Whether the argument is a file or directory, which the sandbox
+    /// keeps inside its root
+    path: bool,
+    /// Whether the argument is a unified diff, the files of which the
+    /// sandbox keeps inside its root
+    patch: bool,
 }
 
 impl Arg {
@@ -69,6 +198,8 @@ impl Arg {
             kind: ArgType::String,
             enum_vals: None,
             required: false,
+            path: false,
+            patch: false,
         }
     }
     pub fn description(mut self, text: impl Into<String>) -> Self {
@@ -91,6 +222,31 @@ impl Arg {
         self.required = true;
         self
     }
+    /// Marks the argument as a file or directory, which the sandbox keeps
+    /// inside its root
+    pub fn path(mut self) -> Self {
+        self.path = true;
+        self
+    }
+
+    /// Marks the argument as a unified diff, the files of which the
+    /// sandbox keeps inside its root
+    pub fn patch(mut self) -> Self {
+        self.patch = true;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub const fn is_path(&self) -> bool {
+        self.path
+    }
+
+    pub const fn is_patch(&self) -> bool {
+        self.patch
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,338 @@
+//! Shell-command tools defined in the config file
+//!
+//! Each entry under `[tools.custom]` becomes a tool the model can call like
+//! a built-in one. Its command is a template run with `sh -c` through the
+//! configured [`ExecBackend`], whose `{{name}}` placeholders stand for the
+//! arguments the model passes. The values are handed to the shell as
+//! positional parameters, which the placeholders expand to in double
+//! quotes, so each is a single word the shell never interprets; the
+//! placeholders themselves must not be quoted. Arguments the model leaves
+//! out are replaced by nothing.
+//!
+//! ```toml
+//! [tools.custom.cargo_check]
+//! description = "Check a package of the Rust workspace for errors"
+//! command = "cargo check --quiet --package {{package}}"
+//!
+//! [tools.custom.cargo_check.args.package]
+//! description = "Name of the package"
+//! required = true
+//! ```
+
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use regex::Captures;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::error::AidoResult;
+use crate::recipe::PLACEHOLDER_REGEX;
+use crate::tools::{
+    Arg, ArgType, Capability, ExecBackend, Tool, ToolContext, ToolDefinition,
+    ToolDefinitionBuilder, ToolInput, process,
+};
+
+/// A tool declared under `[tools.custom.<name>]`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CustomToolConfig {
+    /// Tells the model what the tool does and when to use it
+    pub description: String,
+    /// Command run with `sh -c`, with a `{{name}}` placeholder for each
+    /// argument
+    pub command: String,
+    /// Arguments the model may pass, by name
+    #[serde(default)]
+    pub args: BTreeMap<String, CustomArg>,
+    /// The most dangerous thing the command does, which recipes base their
+    /// confirmations on; commands are assumed to be able to do anything
+    #[serde(default = "default_capability")]
+    pub capability: Capability,
+}
+
+/// An argument of a custom tool
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CustomArg {
+    #[serde(default, rename = "type")]
+    pub kind: ArgType,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub required: bool,
+    /// The only values the model may pass
+    #[serde(default, rename = "enum")]
+    pub values: Vec<String>,
+    /// The argument is a file or directory, which must be inside the
+    /// sandbox root
+    #[serde(default)]
+    pub path: bool,
+}
+
+const fn default_capability() -> Capability {
+    Capability::Exec
+}
+
+impl CustomToolConfig {
+    /// Checks that the command only refers to declared arguments
+    pub fn check(&self) -> Result<(), String> {
+        if self.command.trim().is_empty() {
+            return Err("the command is empty".to_owned());
+        }
+
+        for captures in PLACEHOLDER_REGEX.captures_iter(&self.command) {
+            let name = &captures[1];
+            if !self.args.contains_key(name) {
+                return Err(format!(
+                    "the command uses {{{{{name}}}}}, which is not one of \
+                     its args"
+                ));
+            }
+
+            let placeholder = captures.get(0).unwrap();
+            if is_quoted(&self.command, placeholder.start()) {
+                return Err(format!(
+                    "the command quotes {{{{{name}}}}}, which is passed as a \
+                     single word already; leave out the quotes"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the text of `command` at `index` is inside single or double
+/// quotes
+fn is_quoted(command: &str, index: usize) -> bool {
+    let mut quote = None;
+    let mut escaped = false;
+    for c in command[..index].chars() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (None | Some('"'), '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            _ => {}
+        }
+    }
+
+    quote.is_some()
+}
+
+/// The command `template` with its placeholders replaced by references to
+/// positional parameters, and the values of those parameters from `input`
+fn render(template: &str, input: &ToolInput) -> (String, Vec<String>) {
+    let mut values = Vec::new();
+    let script = PLACEHOLDER_REGEX
+        .replace_all(template, |captures: &Captures<'_>| {
+            let value = match input.get(&captures[1]) {
+                None | Some(Value::Null) => return String::new(),
+                Some(Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+            };
+            values.push(value);
+            format!("\"${{{}}}\"", values.len())
+        })
+        .into_owned();
+
+    (script, values)
+}
+
+/// Runs a shell command declared in the config
+pub struct CustomTool {
+    definition: ToolDefinition,
+    command: String,
+    capability: Capability,
+    backend: ExecBackend,
+}
+
+impl CustomTool {
+    pub fn new(
+        name: &str,
+        config: &CustomToolConfig,
+        backend: ExecBackend,
+    ) -> Self {
+        let mut definition =
+            ToolDefinitionBuilder::new(name).description(&config.description);
+        for (arg_name, arg) in &config.args {
+            let mut definition_arg = Arg::new(arg_name)
+                .description(&arg.description)
+                .kind(arg.kind);
+            if !arg.values.is_empty() {
+                definition_arg = definition_arg.with_enum(&arg.values);
+            }
+            if arg.required {
+                definition_arg = definition_arg.required();
+            }
+            if arg.path {
+                definition_arg = definition_arg.path();
+            }
+            definition = definition.arg(definition_arg);
+        }
+
+        Self {
+            definition: definition.build(),
+            command: config.command.clone(),
+            capability: config.capability,
+            backend,
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for CustomTool {
+    async fn execute(
+        &self,
+        input: ToolInput,
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        let (script, values) = render(&self.command, &input);
+
+        let mut command = Command::from(self.backend.command(
+            "sh",
+            &context.working_dir,
+            self.capability,
+        ));
+        // The values follow `$0`, the name the script runs under
+        command
+            .args(["-c", &script, "sh"])
+            .args(&values)
+            // Stop the command if the run is cancelled while it is going
+            .kill_on_drop(true);
+
+        let output = process::output(&mut command).await?;
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+        // A failing command is an answer the model needs to see, such as
+        // the errors of a compiler, rather than a reason to end the run
+        if !output.status.success() {
+            return Ok(format!(
+                "Error: the command failed ({}):\n{text}",
+                output.status
+            ));
+        }
+        if text.trim().is_empty() {
+            return Ok("(no output)".to_string());
+        }
+
+        Ok(text)
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+
+    fn capability(&self) -> Capability {
+        self.capability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(command: &str, args: &[&str]) -> CustomToolConfig {
+        CustomToolConfig {
+            description: "test tool".to_owned(),
+            command: command.to_owned(),
+            args: args
+                .iter()
+                .map(|name| ((*name).to_owned(), CustomArg::default()))
+                .collect(),
+            capability: Capability::Exec,
+        }
+    }
+
+    #[test]
+    fn test_render_passes_arguments_as_parameters() {
+        let input = ToolInput::from([
+            ("path".to_owned(), Value::from("it's; rm -rf /")),
+            ("count".to_owned(), Value::from(3)),
+        ]);
+
+        let (script, values) =
+            render("wc -l {{ path }} {{count}} {{missing}}", &input);
+        assert_eq!(script, r#"wc -l "${1}" "${2}" "#);
+        assert_eq!(values, ["it's; rm -rf /", "3"]);
+    }
+
+    #[test]
+    fn test_check_rejects_undeclared_placeholders() {
+        assert!(config("echo {{name}}", &["name"]).check().is_ok());
+        assert_eq!(
+            config("echo {{name}}", &[]).check().unwrap_err(),
+            "the command uses {{name}}, which is not one of its args"
+        );
+        assert!(config("  ", &[]).check().is_err());
+    }
+
+    #[test]
+    fn test_check_rejects_quoted_placeholders() {
+        for command in
+            ["echo '{{x}}'", r#"echo "a {{x}}""#, r#"echo "\"" '{{x}}'"#]
+        {
+            let error = config(command, &["x"]).check().unwrap_err();
+            assert!(error.contains("quotes {{x}}"), "{command}: {error}");
+        }
+        assert!(config(r#"echo "a" {{x}} 'b'"#, &["x"]).check().is_ok());
+        assert!(config(r"echo \' {{x}}", &["x"]).check().is_ok());
+    }
+
+    #[test]
+    fn test_definition_lists_args() {
+        let mut config = config("echo {{name}}", &["name"]);
+        config.args.get_mut("name").unwrap().required = true;
+
+        let tool = CustomTool::new("greet", &config, ExecBackend::Host);
+
+        assert_eq!(tool.definition().name(), "greet");
+        assert_eq!(
+            tool.definition().json_value()["required"],
+            json!(["name"])
+        );
+        assert_eq!(tool.capability(), Capability::Exec);
+    }
+
+    #[tokio::test]
+    async fn test_execute_runs_command() {
+        let tool = CustomTool::new(
+            "greet",
+            &config("echo hello {{name}}", &["name"]),
+            ExecBackend::Host,
+        );
+
+        let input = ToolInput::from([("name".to_owned(), "a b".into())]);
+        assert_eq!(
+            tool.execute(input, &ToolContext::current().unwrap())
+                .await
+                .unwrap(),
+            "hello a b\n"
+        );
+
+        // Nothing in a value is run
+        let input =
+            ToolInput::from([("name".to_owned(), "$(echo x); `id`".into())]);
+        assert_eq!(
+            tool.execute(input, &ToolContext::current().unwrap())
+                .await
+                .unwrap(),
+            "hello $(echo x); `id`\n"
+        );
+
+        let failing = CustomTool::new(
+            "fail",
+            &config("echo oops; exit 3", &[]),
+            ExecBackend::Host,
+        );
+        let output = failing
+            .execute(ToolInput::new(), &ToolContext::current().unwrap())
+            .await
+            .unwrap();
+        assert!(output.starts_with("Error: the command failed"), "{output}");
+        assert!(output.contains("status: 3"), "{output}");
+        assert!(output.ends_with("oops\n"), "{output}");
+    }
+}
@@ -0,0 +1,356 @@
+//! Execution backends for tools that spawn subprocesses
+//!
+//! Tools never construct a [`Command`] for an external program directly;
+//! they ask the configured [`ExecBackend`] for one. The default backend runs
+//! programs on the host, the container backend runs them inside a
+//! disposable podman/docker container with the project mounted, and the
+//! bubblewrap backend confines them with `bwrap` on Linux.
+
+use std::path::Path;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tools::Capability;
+
+/// Directory the project is mounted at inside a container
+const CONTAINER_WORKDIR: &str = "/workspace";
+
+/// Directory the read-only source is mounted at when copying into a
+/// container
+const CONTAINER_SOURCE_DIR: &str = "/aido-source";
+
+/// Where tool subprocesses are executed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum ExecBackend {
+    /// Run programs directly on the host
+    #[default]
+    Host,
+    /// Run programs inside a disposable container
+    Container(ContainerConfig),
+    /// Run programs inside a bubblewrap sandbox (Linux only)
+    Bubblewrap(BubblewrapConfig),
+}
+
+/// Settings for the container execution backend
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerConfig {
+    /// Container runtime binary, e.g. `podman` or `docker`
+    #[serde(default = "default_runtime")]
+    pub runtime: String,
+    /// Image the tools are executed in
+    pub image: String,
+    /// How the project directory is made available to the container
+    #[serde(default)]
+    pub mount: MountMode,
+    /// Allow the container to access the network
+    #[serde(default)]
+    pub network: bool,
+}
+
+/// How the project directory is mounted into a container
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum MountMode {
+    /// Mount the project read-only
+    #[default]
+    ReadOnly,
+    /// Copy the project into the container so writes never reach the host
+    Copy,
+}
+
+/// Settings for the bubblewrap execution backend
+///
+/// The filesystem is always visible read-only. Only tools whose
+/// [`Capability`] is at least `writable_from` may write to the working
+/// directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BubblewrapConfig {
+    /// Path to the bubblewrap binary
+    #[serde(default = "default_bwrap")]
+    pub bwrap: String,
+    /// Allow sandboxed programs to access the network
+    #[serde(default)]
+    pub network: bool,
+    /// Lowest tool capability granted write access to the working directory
+    #[serde(default = "default_writable_from")]
+    pub writable_from: Capability,
+}
+
+fn default_bwrap() -> String {
+    "bwrap".to_string()
+}
+
+const fn default_writable_from() -> Capability {
+    Capability::Write
+}
+
+fn default_runtime() -> String {
+    "podman".to_string()
+}
+
+impl ExecBackend {
+    /// Builds a command that runs `program` in `working_dir` on behalf of a
+    /// tool with the given capability. Arguments added to the returned
+    /// command are passed through to `program`.
+    pub fn command(
+        &self,
+        program: &str,
+        working_dir: &Path,
+        capability: Capability,
+    ) -> Command {
+        match self {
+            Self::Host => {
+                let mut command = Command::new(program);
+                command.current_dir(working_dir);
+                command
+            }
+            Self::Container(container) => {
+                container.command(program, working_dir)
+            }
+            Self::Bubblewrap(bwrap) => {
+                bwrap.command(program, working_dir, capability)
+            }
+        }
+    }
+}
+
+impl BubblewrapConfig {
+    fn command(
+        &self,
+        program: &str,
+        working_dir: &Path,
+        capability: Capability,
+    ) -> Command {
+        let mut command = Command::new(&self.bwrap);
+        command
+            .args(["--ro-bind", "/", "/"])
+            .args(["--dev", "/dev"])
+            .args(["--proc", "/proc"])
+            .args(["--tmpfs", "/tmp"])
+            .args(["--unshare-all", "--die-with-parent"]);
+
+        if self.network {
+            command.arg("--share-net");
+        }
+
+        // Bound after /tmp so a working directory under /tmp stays visible
+        let bind = if capability >= self.writable_from {
+            "--bind"
+        } else {
+            "--ro-bind"
+        };
+        command
+            .arg(bind)
+            .arg(working_dir)
+            .arg(working_dir)
+            .arg("--chdir")
+            .arg(working_dir)
+            .arg(program);
+
+        command
+    }
+}
+
+impl ContainerConfig {
+    fn command(&self, program: &str, working_dir: &Path) -> Command {
+        let mut command = Command::new(&self.runtime);
+        command.args(["run", "--rm", "-i"]);
+
+        if !self.network {
+            command.arg("--network=none");
+        }
+
+        let host_dir = working_dir.to_string_lossy();
+
+        match self.mount {
+            MountMode::ReadOnly => {
+                command
+                    .arg("-v")
+                    .arg(format!("{host_dir}:{CONTAINER_WORKDIR}:ro"))
+                    .args(["-w", CONTAINER_WORKDIR, &self.image, program]);
+            }
+            MountMode::Copy => {
+                // Mount the source read-only and copy it into a scratch
+                // directory, then hand the remaining arguments to `program`.
+                command
+                    .arg("-v")
+                    .arg(format!("{host_dir}:{CONTAINER_SOURCE_DIR}:ro"))
+                    .args([&self.image, "sh", "-c"])
+                    .arg(format!(
+                        "mkdir -p {CONTAINER_WORKDIR} && \
+                         cp -a {CONTAINER_SOURCE_DIR}/. {CONTAINER_WORKDIR} && \
+                         cd {CONTAINER_WORKDIR} && exec \"$@\""
+                    ))
+                    .args(["sh", program]);
+            }
+        }
+
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_host_backend_runs_program_directly() {
+        let command = ExecBackend::Host.command(
+            "/bin/ls",
+            Path::new("/tmp"),
+            Capability::Read,
+        );
+
+        assert_eq!(command.get_program(), "/bin/ls");
+        assert_eq!(command.get_current_dir(), Some(Path::new("/tmp")));
+        assert!(args(&command).is_empty());
+    }
+
+    #[test]
+    fn test_container_backend_read_only_mount() {
+        let backend = ExecBackend::Container(ContainerConfig {
+            runtime: "docker".to_string(),
+            image: "alpine".to_string(),
+            mount: MountMode::ReadOnly,
+            network: false,
+        });
+
+        let mut command = backend.command(
+            "/bin/ls",
+            Path::new("/project"),
+            Capability::Read,
+        );
+        command.arg("-al");
+
+        assert_eq!(command.get_program(), "docker");
+        assert_eq!(
+            args(&command),
+            [
+                "run",
+                "--rm",
+                "-i",
+                "--network=none",
+                "-v",
+                "/project:/workspace:ro",
+                "-w",
+                "/workspace",
+                "alpine",
+                "/bin/ls",
+                "-al"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_container_backend_copy_mount() {
+        let backend = ExecBackend::Container(ContainerConfig {
+            runtime: "podman".to_string(),
+            image: "alpine".to_string(),
+            mount: MountMode::Copy,
+            network: true,
+        });
+
+        let command = backend.command(
+            "/bin/ls",
+            Path::new("/project"),
+            Capability::Read,
+        );
+        let args = args(&command);
+
+        assert!(!args.contains(&"--network=none".to_string()));
+        assert!(args.contains(&"/project:/aido-source:ro".to_string()));
+        assert_eq!(args.last().map(String::as_str), Some("/bin/ls"));
+    }
+
+    #[test]
+    fn test_bubblewrap_backend_read_only_for_read_tools() {
+        let backend = ExecBackend::Bubblewrap(BubblewrapConfig {
+            bwrap: "bwrap".to_string(),
+            network: false,
+            writable_from: Capability::Write,
+        });
+
+        let command = backend.command(
+            "/bin/ls",
+            Path::new("/project"),
+            Capability::Read,
+        );
+        let args = args(&command);
+
+        assert_eq!(command.get_program(), "bwrap");
+        assert!(args.contains(&"--unshare-all".to_string()));
+        assert!(!args.contains(&"--share-net".to_string()));
+        assert!(!args.contains(&"--bind".to_string()));
+        assert_eq!(
+            &args[args.len() - 6..],
+            [
+                "--ro-bind",
+                "/project",
+                "/project",
+                "--chdir",
+                "/project",
+                "/bin/ls"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bubblewrap_backend_writable_for_capable_tools() {
+        let backend = ExecBackend::Bubblewrap(BubblewrapConfig {
+            bwrap: "bwrap".to_string(),
+            network: true,
+            writable_from: Capability::Write,
+        });
+
+        let command = backend.command(
+            "/bin/sh",
+            Path::new("/project"),
+            Capability::Exec,
+        );
+        let args = args(&command);
+
+        assert!(args.contains(&"--share-net".to_string()));
+        assert!(args.contains(&"--bind".to_string()));
+    }
+
+    #[test]
+    fn test_exec_backend_deserialization() {
+        let backend: ExecBackend = serde_json::from_str(
+            r#"{"backend": "container", "image": "alpine"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            backend,
+            ExecBackend::Container(ContainerConfig {
+                runtime: "podman".to_string(),
+                image: "alpine".to_string(),
+                mount: MountMode::ReadOnly,
+                network: false,
+            })
+        );
+
+        let backend: ExecBackend =
+            serde_json::from_str(r#"{"backend": "host"}"#).unwrap();
+        assert_eq!(backend, ExecBackend::Host);
+
+        let backend: ExecBackend =
+            serde_json::from_str(r#"{"backend": "bubblewrap"}"#).unwrap();
+        assert_eq!(
+            backend,
+            ExecBackend::Bubblewrap(BubblewrapConfig {
+                bwrap: "bwrap".to_string(),
+                network: false,
+                writable_from: Capability::Write,
+            })
+        );
+    }
+}
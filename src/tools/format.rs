@@ -0,0 +1,118 @@
+//! Templates shaping tool output before the model sees it
+//!
+//! The output of a tool is sent to the model as it is, unless a template
+//! for the tool is set under `[tools.format]` in the config or under
+//! `tool_format:` in a recipe header, which takes precedence. A template
+//! for `*` applies to the tools without their own:
+//!
+//! ```toml
+//! [tools.format]
+//! git_diff = "```diff\n{{output}}\n```"
+//! "*" = "$ {{call}}\n{{output}}"
+//! ```
+//!
+//! Templates may refer to `{{tool}}`, the name of the tool, `{{call}}`, the
+//! tool with its arguments as the status line shows it, `{{arguments}}`,
+//! the arguments as JSON, and `{{output}}`.
+
+use std::collections::BTreeMap;
+
+use regex::Captures;
+
+use super::ToolInput;
+use crate::recipe::PLACEHOLDER_REGEX;
+
+/// The key of the template for tools without their own
+pub const ANY_TOOL: &str = "*";
+
+/// The placeholders templates may refer to
+const PLACEHOLDERS: &[&str] = &["tool", "call", "arguments", "output"];
+
+/// The template for the tool named `tool` among `templates`, if any
+pub fn template_for<'a>(
+    templates: &'a BTreeMap<String, String>,
+    tool: &str,
+) -> Option<&'a str> {
+    templates.get(tool).or_else(|| templates.get(ANY_TOOL)).map(String::as_str)
+}
+
+/// Checks that `template` only refers to known placeholders
+pub fn check(template: &str) -> Result<(), String> {
+    let Some(captures) = PLACEHOLDER_REGEX
+        .captures_iter(template)
+        .find(|captures| !PLACEHOLDERS.contains(&&captures[1]))
+    else {
+        return Ok(());
+    };
+
+    Err(format!(
+        "unknown placeholder '{}'; templates may use {}",
+        &captures[0],
+        PLACEHOLDERS
+            .iter()
+            .map(|name| format!("{{{{{name}}}}}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+/// `template` filled in with the call of `tool` with `input` that returned
+/// `output`
+///
+/// Placeholders in the values themselves, such as in the output, are left
+/// as they are.
+pub fn apply(
+    template: &str,
+    tool: &str,
+    input: &ToolInput,
+    output: &str,
+) -> String {
+    PLACEHOLDER_REGEX
+        .replace_all(template, |captures: &Captures<'_>| match &captures[1] {
+            "tool" => tool.to_owned(),
+            "call" => crate::status::describe_tool_call(tool, input),
+            "arguments" => serde_json::to_string(input).unwrap_or_default(),
+            "output" => output.trim_end().to_owned(),
+            _ => captures[0].to_owned(),
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let input =
+            ToolInput::from([("path".to_owned(), serde_json::json!("src"))]);
+
+        assert_eq!(
+            apply(
+                "$ {{ call }}\n```\n{{output}}\n```",
+                "ls",
+                &input,
+                "a.rs {{tool}}\n"
+            ),
+            "$ ls src\n```\na.rs {{tool}}\n```"
+        );
+        assert_eq!(
+            apply("{{tool}} {{arguments}} {{other}}", "ls", &input, ""),
+            r#"ls {"path":"src"} {{other}}"#
+        );
+    }
+
+    #[test]
+    fn test_template_for_and_check() {
+        let templates = BTreeMap::from([
+            ("ls".to_owned(), "ls: {{output}}".to_owned()),
+            (ANY_TOOL.to_owned(), "{{output}}".to_owned()),
+        ]);
+
+        assert_eq!(template_for(&templates, "ls"), Some("ls: {{output}}"));
+        assert_eq!(template_for(&templates, "search"), Some("{{output}}"));
+        assert_eq!(template_for(&BTreeMap::new(), "ls"), None);
+        assert!(check("{{call}}\n{{output}}").is_ok());
+        assert!(check("{{exit_code}}").unwrap_err().contains("{{exit_code}}"));
+    }
+}
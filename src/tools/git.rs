@@ -0,0 +1,376 @@
+//! Read-only git tools: `git_status`, `git_diff` and `git_log`
+//!
+//! Each tool runs a fixed git subcommand through the configured
+//! [`ExecBackend`]. The model only picks revisions and paths, which are
+//! checked before use: nothing it passes can start with `-` and be taken
+//! for an option, and paths always come after `--`. Pagers, external diff
+//! drivers and textconv filters are disabled so that the repository's own
+//! config can't run programs on the model's behalf.
+
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::process::Command;
+
+use crate::error::AidoResult;
+use crate::tools::{
+    Arg, ArgType, ExecBackend, Tool, ToolContext, ToolDefinition,
+    ToolDefinitionBuilder, ToolInput, process,
+};
+
+/// Commits listed by `git_log` when the model doesn't ask for a number
+const DEFAULT_LOG_COUNT: u64 = 10;
+
+/// Most commits `git_log` lists at once
+const MAX_LOG_COUNT: u64 = 100;
+
+/// Checks that `value`, given for `name`, can't be mistaken for an option
+/// or smuggle in anything but a single argument
+fn sanitized<'a>(name: &str, value: &'a str) -> Result<&'a str, String> {
+    if value.starts_with('-') {
+        return Err(format!(
+            "Invalid {name} '{value}': must not start with '-'"
+        ));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(format!(
+            "Invalid {name}: must not contain control characters"
+        ));
+    }
+
+    Ok(value)
+}
+
+/// Checks that `revision` only uses characters found in revision
+/// expressions such as `HEAD~3`, `main..feature` or `v1.0^{commit}`
+fn sanitized_revision(revision: &str) -> Result<&str, String> {
+    let revision = sanitized("revision", revision)?;
+    let allowed =
+        |c: char| c.is_ascii_alphanumeric() || "._/~^@{}-:".contains(c);
+    if !revision.chars().all(allowed) {
+        return Err(format!("Invalid revision '{revision}'"));
+    }
+
+    Ok(revision)
+}
+
+/// A string argument, if the model gave a non-empty one
+fn optional_str<'a>(input: &'a ToolInput, name: &str) -> Option<&'a str> {
+    input.get(name).and_then(Value::as_str).filter(|s| !s.is_empty())
+}
+
+/// Runs `git` with `args` in the working directory of `context`, returning
+/// its output
+async fn run_git(
+    backend: &ExecBackend,
+    context: &ToolContext,
+    args: &[&str],
+) -> AidoResult<String> {
+    let mut command = Command::from(backend.command(
+        "git",
+        &context.working_dir,
+        crate::tools::Capability::Read,
+    ));
+    command
+        .args(["--no-pager", "-c", "core.fsmonitor=false"])
+        .args(args)
+        .env("GIT_PAGER", "cat")
+        .env("GIT_EXTERNAL_DIFF", "")
+        // Stop git if the run is cancelled while it is still going
+        .kill_on_drop(true);
+
+    let output = process::output(&mut command).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.first().copied().unwrap_or_default(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if stdout.trim().is_empty() {
+        return Ok("(no output)".to_string());
+    }
+
+    Ok(stdout)
+}
+
+/// Shows the working tree status
+pub struct GitStatus {
+    definition: ToolDefinition,
+    backend: ExecBackend,
+}
+
+impl GitStatus {
+    pub fn new(backend: ExecBackend) -> Self {
+        let definition = ToolDefinitionBuilder::new("git_status")
+            .description(
+                "Show the current branch and which files are staged, \
+                 modified or untracked in the git repository",
+            )
+            .build();
+        Self { definition, backend }
+    }
+}
+
+#[async_trait]
+impl Tool for GitStatus {
+    async fn execute(
+        &self,
+        _input: ToolInput,
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        run_git(&self.backend, context, &["status", "--short", "--branch"])
+            .await
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+/// Shows changes in the working tree, the index or between revisions
+pub struct GitDiff {
+    definition: ToolDefinition,
+    backend: ExecBackend,
+}
+
+impl GitDiff {
+    pub fn new(backend: ExecBackend) -> Self {
+        let definition = ToolDefinitionBuilder::new("git_diff")
+            .description(
+                "Show a diff of the git repository: unstaged changes by \
+                 default, staged changes with staged=true, or the changes \
+                 since a revision",
+            )
+            .arg(
+                Arg::new("staged")
+                    .description("Show the changes staged for commit")
+                    .kind(ArgType::Boolean),
+            )
+            .arg(
+                Arg::new("revision")
+                    .description(
+                        "Compare against this revision, e.g. HEAD~1, or \
+                         between two, e.g. main..feature",
+                    )
+                    .kind(ArgType::String),
+            )
+            .arg(
+                Arg::new("path")
+                    .description("Only show changes to this file or directory")
+                    .kind(ArgType::String)
+                    .path(),
+            )
+            .build();
+        Self { definition, backend }
+    }
+}
+
+/// The arguments for `git diff` asked for by `input`
+fn diff_args(input: &ToolInput) -> Result<Vec<String>, String> {
+    let mut args = ["diff", "--no-color", "--no-ext-diff", "--no-textconv"]
+        .map(String::from)
+        .to_vec();
+
+    if input.get("staged").and_then(Value::as_bool).unwrap_or_default() {
+        args.push("--cached".to_string());
+    }
+    if let Some(revision) = optional_str(input, "revision") {
+        args.push(sanitized_revision(revision)?.to_string());
+    }
+    args.push("--".to_string());
+    if let Some(path) = optional_str(input, "path") {
+        args.push(sanitized("path", path)?.to_string());
+    }
+
+    Ok(args)
+}
+
+#[async_trait]
+impl Tool for GitDiff {
+    async fn execute(
+        &self,
+        input: ToolInput,
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        let args = diff_args(&input)?;
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+        run_git(&self.backend, context, &args).await
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+/// Lists recent commits
+pub struct GitLog {
+    definition: ToolDefinition,
+    backend: ExecBackend,
+}
+
+impl GitLog {
+    pub fn new(backend: ExecBackend) -> Self {
+        let definition = ToolDefinitionBuilder::new("git_log")
+            .description(
+                "List recent commits of the git repository, newest first, \
+                 as: hash date author subject",
+            )
+            .arg(
+                Arg::new("count")
+                    .description(format!(
+                        "Number of commits to list, at most {MAX_LOG_COUNT}. \
+                         Defaults to {DEFAULT_LOG_COUNT}"
+                    ))
+                    .kind(ArgType::Integer),
+            )
+            .arg(
+                Arg::new("revision")
+                    .description(
+                        "List the history of this revision or range instead \
+                         of HEAD, e.g. main..feature",
+                    )
+                    .kind(ArgType::String),
+            )
+            .arg(
+                Arg::new("path")
+                    .description(
+                        "Only list commits touching this file or directory",
+                    )
+                    .kind(ArgType::String)
+                    .path(),
+            )
+            .build();
+        Self { definition, backend }
+    }
+}
+
+/// The arguments for `git log` asked for by `input`
+fn log_args(input: &ToolInput) -> Result<Vec<String>, String> {
+    let count = input
+        .get("count")
+        .and_then(Value::as_u64)
+        .unwrap_or(DEFAULT_LOG_COUNT)
+        .clamp(1, MAX_LOG_COUNT);
+
+    let mut args = vec![
+        "log".to_string(),
+        "--no-color".to_string(),
+        format!("--max-count={count}"),
+        "--date=short".to_string(),
+        "--format=%h %ad %an %s".to_string(),
+    ];
+    if let Some(revision) = optional_str(input, "revision") {
+        args.push(sanitized_revision(revision)?.to_string());
+    }
+    args.push("--".to_string());
+    if let Some(path) = optional_str(input, "path") {
+        args.push(sanitized("path", path)?.to_string());
+    }
+
+    Ok(args)
+}
+
+#[async_trait]
+impl Tool for GitLog {
+    async fn execute(
+        &self,
+        input: ToolInput,
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        let args = log_args(&input)?;
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+        run_git(&self.backend, context, &args).await
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(pairs: &[(&str, Value)]) -> ToolInput {
+        pairs.iter().map(|(k, v)| ((*k).to_string(), v.clone())).collect()
+    }
+
+    #[test]
+    fn test_diff_args() {
+        let args = diff_args(&input(&[
+            ("staged", true.into()),
+            ("path", "src/main.rs".into()),
+        ]))
+        .unwrap();
+        assert_eq!(
+            args,
+            [
+                "diff",
+                "--no-color",
+                "--no-ext-diff",
+                "--no-textconv",
+                "--cached",
+                "--",
+                "src/main.rs"
+            ]
+        );
+
+        let args =
+            diff_args(&input(&[("revision", "main..HEAD~2".into())])).unwrap();
+        assert_eq!(args[4..], ["main..HEAD~2", "--"]);
+    }
+
+    #[test]
+    fn test_log_args() {
+        let args = log_args(&input(&[("count", 1000.into())])).unwrap();
+        assert_eq!(args[2], format!("--max-count={MAX_LOG_COUNT}"));
+        assert_eq!(args.last().unwrap(), "--");
+
+        let args = log_args(&ToolInput::new()).unwrap();
+        assert_eq!(args[2], format!("--max-count={DEFAULT_LOG_COUNT}"));
+    }
+
+    #[test]
+    fn test_rejects_option_injection() {
+        assert!(
+            diff_args(&input(&[("revision", "--output=x".into())])).is_err()
+        );
+        assert!(diff_args(&input(&[("path", "-p".into())])).is_err());
+        assert!(
+            log_args(&input(&[("revision", "HEAD; rm -rf /".into())]))
+                .is_err()
+        );
+        assert!(log_args(&input(&[("path", "a\nb".into())])).is_err());
+        // Paths may contain anything else, spaces included
+        assert!(
+            log_args(&input(&[("path", "docs/my notes.md".into())])).is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_git_log_runs() {
+        // The crate may be built outside of a git checkout
+        if crate::isolation::repo_root(std::path::Path::new(env!(
+            "CARGO_MANIFEST_DIR"
+        )))
+        .is_none()
+        {
+            return;
+        }
+
+        let output = GitLog::new(ExecBackend::Host)
+            .execute(
+                input(&[("count", 1.into())]),
+                &ToolContext::current().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(output.lines().count(), 1);
+    }
+}
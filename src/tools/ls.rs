@@ -1,48 +1,89 @@
+use std::sync::LazyLock;
+
+use async_trait::async_trait;
+use regex::Regex;
 use serde_json::Value;
+use tokio::process::Command;
 
+use crate::error::AidoResult;
 use crate::tools::{
-    Arg, ArgType, Tool, ToolDefinition, ToolDefinitionBuilder, ToolInput,
+    Arg, ArgType, ExecBackend, Tool, ToolContext, ToolDefinition,
+    ToolDefinitionBuilder, ToolInput, process,
 };
 
+/// Matches clusters of short options, such as `-alh`, which name no files
+static FLAGS_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^-[A-Za-z1]+$").unwrap());
+
 pub struct Ls {
     definition: ToolDefinition,
+    backend: ExecBackend,
 }
 
 impl Ls {
-    pub fn new() -> Self {
+    pub fn new(backend: ExecBackend) -> Self {
         let definition = ToolDefinitionBuilder::new("ls")
             .description("List directory contents")
             .arg(
-                Arg::new("args")
-                    .description("The input arguments for ls. Example: -alh")
+                Arg::new("flags")
+                    .description("Short options for ls. Example: -alh")
                     .kind(ArgType::String),
             )
+            .arg(
+                Arg::new("path")
+                    .description(
+                        "The directory or file to list; the current \
+                         directory by default",
+                    )
+                    .kind(ArgType::String)
+                    .path(),
+            )
             .build();
-        Self { definition }
+        Self { definition, backend }
     }
 }
 
+#[async_trait]
 impl Tool for Ls {
-    fn execute(
+    async fn execute(
         &self,
         input: ToolInput,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let maybe_input = input.get("args").and_then(Value::as_str);
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        let flags = input
+            .get("flags")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        if let Some(flag) = flags.iter().find(|f| !FLAGS_REGEX.is_match(f)) {
+            return Err(format!(
+                "`{flag}` isn't a cluster of short options such as -alh"
+            )
+            .into());
+        }
+        let path = input.get("path").and_then(Value::as_str);
 
-        let mut command = std::process::Command::new("/bin/ls");
+        let mut command = Command::from(self.backend.command(
+            "/bin/ls",
+            &context.working_dir,
+            self.capability(),
+        ));
 
-        if let Some(args) = maybe_input
-            && !args.is_empty()
+        // Stop ls if the run is cancelled while it is still going
+        command.kill_on_drop(true);
+
+        command.args(flags).arg("--");
+        if let Some(path) = path
+            && !path.is_empty()
         {
-            command.arg(args);
+            command.arg(path);
         }
 
-        // First, get the current working directory of this process:
-        command.current_dir(std::env::current_dir()?);
-
-        let output = command.output()?.stdout;
+        let output = process::output(&mut command).await?.stdout;
 
-        Ok(String::from_utf8(output)?)
+        Ok(String::from_utf8(output)
+            .map_err(|e| format!("ls printed invalid UTF-8: {e}"))?)
     }
 
     fn definition(&self) -> &ToolDefinition {
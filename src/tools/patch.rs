@@ -0,0 +1,937 @@
+//! Letting the model edit files with a unified diff
+//!
+//! `apply_patch` takes a diff in the format of `diff -u` and `git diff`,
+//! checks that every hunk matches the files in the current directory,
+//! shows the diff and asks the user whether to apply it, whatever the
+//! recipe's `confirm` says. Without anyone to ask, as in `aido serve` or
+//! an embedding application that declines, nothing is written. The tool
+//! is opt-in, offered only when `enabled` under `[tools]` names it.
+//!
+//! A patch applies in full or not at all: when a hunk doesn't match, no
+//! file is written, and the model is told which hunk to fix. The new
+//! contents are written to temporary files next to the ones they replace,
+//! which are only renamed into place once every one of them was written.
+//!
+//! Models count lines poorly, so the line numbers of hunk headers are only
+//! where the search for the hunk starts, and differences in trailing
+//! whitespace are ignored. Files are created from `/dev/null` and deleted
+//! to it, and a file whose old and new names differ is renamed, unless a
+//! file has the new name already. Patched files keep their line endings,
+//! and their final newline or lack of one unless the diff changes it.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal};
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use log::warn;
+use serde_json::Value;
+
+use crate::error::AidoResult;
+use crate::tools::{
+    Arg, ArgType, Capability, Tool, ToolContext, ToolDefinition,
+    ToolDefinitionBuilder, ToolInput,
+};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// A line of a hunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+/// A change to one place in a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Hunk {
+    /// Line the hunk starts at in the old file, counting from 1
+    old_start: usize,
+    lines: Vec<Line>,
+    /// Whether the hunk ends the old file, which has no final newline
+    old_no_newline: bool,
+    /// Whether the hunk ends the new file, which has no final newline
+    new_no_newline: bool,
+}
+
+impl Hunk {
+    /// The lines the hunk expects to find
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(text) | Line::Removed(text) => {
+                    Some(text.as_str())
+                }
+                Line::Added(_) => None,
+            })
+            .collect()
+    }
+
+    /// The lines the hunk leaves in their place
+    fn new_lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Context(text) | Line::Added(text) => Some(text.as_str()),
+            Line::Removed(_) => None,
+        })
+    }
+}
+
+/// The changes to one file; a file without an old path is created, and
+/// one without a new path deleted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct FilePatch {
+    old: Option<PathBuf>,
+    new: Option<PathBuf>,
+    hunks: Vec<Hunk>,
+}
+
+/// Parses a unified diff into the changes to each file it names
+///
+/// The line counts of a hunk header say which lines belong to the hunk, so
+/// a removed line starting with `-- ` isn't taken for the start of the
+/// next file. Hunk lines right after a hunk whose counts are used up still
+/// belong to it, since models miscount.
+fn parse(diff: &str) -> Result<Vec<FilePatch>, String> {
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut lines = diff.lines().peekable();
+    // Lines of the old and new file the current hunk has yet to cover
+    let (mut old_left, mut new_left) = (0, 0);
+
+    while let Some(line) = lines.next() {
+        let in_hunk = old_left > 0 || new_left > 0;
+        let next_is_new_name =
+            lines.peek().is_some_and(|next| next.starts_with("+++ "));
+
+        if let Some(old) = line.strip_prefix("--- ")
+            && next_is_new_name
+            && !in_hunk
+        {
+            let new = lines.next().unwrap_or_default();
+            patches.push(FilePatch {
+                old: file_name(old)?,
+                new: file_name(&new[4..])?,
+                hunks: Vec::new(),
+            });
+        } else if line.starts_with("@@") {
+            let patch = patches.last_mut().ok_or_else(|| {
+                "the diff has a hunk before the `---` and `+++` lines naming \
+                 its file"
+                    .to_owned()
+            })?;
+            let old_start;
+            (old_start, old_left, new_left) = hunk_header(line)?;
+            patch.hunks.push(Hunk { old_start, ..Hunk::default() });
+        } else if let Some(hunk) =
+            patches.last_mut().and_then(|patch| patch.hunks.last_mut())
+        {
+            let line = match line.chars().next() {
+                Some(' ') => Line::Context(line[1..].to_owned()),
+                Some('-') => Line::Removed(line[1..].to_owned()),
+                Some('+') => Line::Added(line[1..].to_owned()),
+                // Blank context lines often lose their space
+                None => Line::Context(String::new()),
+                // `\ No newline at end of file`, about the line before
+                Some('\\') => {
+                    match hunk.lines.last() {
+                        Some(Line::Removed(_)) => hunk.old_no_newline = true,
+                        Some(Line::Added(_)) => hunk.new_no_newline = true,
+                        Some(Line::Context(_)) => {
+                            hunk.old_no_newline = true;
+                            hunk.new_no_newline = true;
+                        }
+                        None => {}
+                    }
+                    continue;
+                }
+                // The `diff` and `index` lines between files
+                _ => continue,
+            };
+            if !matches!(line, Line::Added(_)) {
+                old_left = old_left.saturating_sub(1);
+            }
+            if !matches!(line, Line::Removed(_)) {
+                new_left = new_left.saturating_sub(1);
+            }
+            hunk.lines.push(line);
+        }
+    }
+
+    if patches.is_empty() {
+        return Err("the diff names no file; start each file's changes with \
+                    `--- a/path` and `+++ b/path` lines"
+            .to_owned());
+    }
+    Ok(patches)
+}
+
+/// The path in a `---` or `+++` line, without its `a/` or `b/` prefix and
+/// timestamp, or `None` for `/dev/null`, failing for paths leading out of
+/// the current directory
+fn file_name(text: &str) -> Result<Option<PathBuf>, String> {
+    let name = text.split('\t').next().unwrap_or_default().trim();
+    if name == "/dev/null" || name.is_empty() {
+        return Ok(None);
+    }
+
+    let name = name
+        .strip_prefix("a/")
+        .or_else(|| name.strip_prefix("b/"))
+        .unwrap_or(name);
+    let path = PathBuf::from(name);
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(format!(
+            "`{name}` isn't a path under the current directory; name files \
+             relative to it, without `..`"
+        ));
+    }
+
+    Ok(Some(path))
+}
+
+/// The old start line and the old and new line counts of the hunk header
+/// `@@ -12,5 +12,6 @@`, in which a count left out is 1
+fn hunk_header(header: &str) -> Result<(usize, usize, usize), String> {
+    let invalid = || format!("invalid hunk header `{header}`");
+    let range = |sign: char| {
+        let range = header
+            .split_whitespace()
+            .skip(1)
+            .find_map(|part| part.strip_prefix(sign))
+            .ok_or_else(invalid)?;
+        let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+        match (start.parse::<usize>(), count.parse::<usize>()) {
+            (Ok(start), Ok(count)) => Ok((start, count)),
+            _ => Err(invalid()),
+        }
+    };
+
+    let (old_start, old_count) = range('-')?;
+    let (_, new_count) = range('+')?;
+    Ok((old_start, old_count, new_count))
+}
+
+/// The files `diff` would change, for the sandbox to check, failing if it
+/// can't tell
+pub fn paths(diff: &str) -> Result<Vec<PathBuf>, String> {
+    Ok(parse(diff)?
+        .into_iter()
+        .flat_map(|patch| [patch.old, patch.new])
+        .flatten()
+        .collect())
+}
+
+/// `content` with `hunks` applied, or the number of the first hunk that
+/// doesn't match, counting from 1
+fn apply_hunks(content: &str, hunks: &[Hunk]) -> Result<String, usize> {
+    let line_end = if content.contains("\r\n") { "\r\n" } else { "\n" };
+    let lines = content.lines().collect::<Vec<_>>();
+    let mut patched = Vec::new();
+    let mut cursor = 0;
+
+    for (number, hunk) in hunks.iter().enumerate() {
+        let old = hunk.old_lines();
+        // A hunk without old lines, as `diff -U0` makes for insertions,
+        // goes after its start line rather than at it
+        let expected = if old.is_empty() {
+            hunk.old_start
+        } else {
+            hunk.old_start.saturating_sub(1)
+        }
+        .max(cursor);
+        let position =
+            find(&lines, &old, cursor, expected).ok_or(number + 1)?;
+
+        patched.extend_from_slice(&lines[cursor..position]);
+        patched.extend(hunk.new_lines());
+        cursor = position + old.len();
+    }
+    patched.extend_from_slice(&lines[cursor..]);
+
+    let final_newline = if hunks.iter().any(|hunk| hunk.new_no_newline) {
+        false
+    } else {
+        hunks.iter().any(|hunk| hunk.old_no_newline)
+            || content.is_empty()
+            || content.ends_with('\n')
+    };
+    let mut patched = patched.join(line_end);
+    if final_newline && !patched.is_empty() {
+        patched.push_str(line_end);
+    }
+    Ok(patched)
+}
+
+/// Where `needle` is in `lines`, at or after `from`, choosing the place
+/// closest to `expected`
+fn find(
+    lines: &[&str],
+    needle: &[&str],
+    from: usize,
+    expected: usize,
+) -> Option<usize> {
+    let last = lines.len().checked_sub(needle.len())?;
+    if from > last {
+        return None;
+    }
+    let matches = |at: usize| {
+        needle
+            .iter()
+            .zip(&lines[at..])
+            .all(|(want, have)| want.trim_end() == have.trim_end())
+    };
+
+    let expected = expected.clamp(from, last);
+    (0..=last - from).find_map(|distance| {
+        let after = expected + distance;
+        let before = expected.checked_sub(distance).filter(|&at| at >= from);
+        [before, Some(after)]
+            .into_iter()
+            .flatten()
+            .find(|&at| at <= last && matches(at))
+    })
+}
+
+/// The contents the files `patches` changes will have, by path relative
+/// to `dir`, or `None` for the files it deletes
+fn plan(
+    patches: &[FilePatch],
+    dir: &Path,
+) -> Result<BTreeMap<PathBuf, Option<String>>, String> {
+    let mut changes: BTreeMap<PathBuf, Option<String>> = BTreeMap::new();
+
+    for patch in patches {
+        let current =
+            match &patch.old {
+                None => String::new(),
+                Some(old) => match changes.get(old) {
+                    Some(Some(content)) => content.clone(),
+                    Some(None) => {
+                        return Err(format!("{} is deleted", old.display()));
+                    }
+                    None => std::fs::read_to_string(dir.join(old)).map_err(
+                        |e| format!("cannot read {}: {e}", old.display()),
+                    )?,
+                },
+            };
+        if let (None, Some(new)) = (&patch.old, &patch.new)
+            && dir.join(new).exists()
+        {
+            return Err(format!(
+                "{} already exists, but the diff creates it",
+                new.display()
+            ));
+        }
+
+        let name = patch.new.as_ref().or(patch.old.as_ref());
+        let name = name.map_or_else(String::new, |n| n.display().to_string());
+        let content = apply_hunks(&current, &patch.hunks).map_err(|hunk| {
+            format!(
+                "hunk {hunk} of {name} doesn't match the file; make its \
+                 context and removed lines the same as the file's"
+            )
+        })?;
+
+        match (&patch.old, &patch.new) {
+            (_, None) if !content.is_empty() => {
+                return Err(format!(
+                    "the diff deletes {name}, but doesn't remove all of it"
+                ));
+            }
+            (Some(old), new) if new.as_ref() != Some(old) => {
+                if let Some(new) = new
+                    && dir.join(new).exists()
+                    && !matches!(changes.get(new), Some(None))
+                {
+                    return Err(format!(
+                        "{} already exists, but the diff renames {} to it",
+                        new.display(),
+                        old.display()
+                    ));
+                }
+                changes.insert(old.clone(), None);
+            }
+            _ => {}
+        }
+        if let Some(new) = &patch.new {
+            changes.insert(new.clone(), Some(content));
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Writes the planned `changes` to the files under `dir`: all of them or,
+/// when one can't be written, none
+fn write(
+    changes: &BTreeMap<PathBuf, Option<String>>,
+    dir: &Path,
+) -> io::Result<()> {
+    let mut staged = Staged::default();
+    if let Err(e) = staged.stage(changes, dir) {
+        staged.roll_back();
+        return Err(e);
+    }
+
+    staged.commit()
+}
+
+/// Changes made next to the files of a patch, which are undone or put in
+/// place together
+#[derive(Debug, Default)]
+struct Staged {
+    /// Temporary files holding new contents, with the files they become
+    written: Vec<(PathBuf, PathBuf)>,
+    /// Files the patch deletes, moved aside, with where they were
+    deleted: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Staged {
+    /// Writes the new contents to temporary files and moves the deleted
+    /// files aside, stopping at the first that fails
+    fn stage(
+        &mut self,
+        changes: &BTreeMap<PathBuf, Option<String>>,
+        dir: &Path,
+    ) -> io::Result<()> {
+        for (path, content) in changes {
+            let path = dir.join(path);
+            if let Some(content) = content {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let temp = sibling(&path, "new");
+                self.written.push((temp.clone(), path.clone()));
+                std::fs::write(&temp, content)?;
+                // Patched files keep their permissions
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    std::fs::set_permissions(&temp, metadata.permissions())?;
+                }
+            } else {
+                let aside = sibling(&path, "deleted");
+                std::fs::rename(&path, &aside)?;
+                self.deleted.push((aside, path));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the temporary files and moves the deleted files back
+    fn roll_back(self) {
+        for (temp, _) in self.written {
+            // Writing it may have failed before the file was created
+            if let Err(e) = std::fs::remove_file(&temp)
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                warn!("Failed to remove {}: {e}", temp.display());
+            }
+        }
+        for (aside, path) in self.deleted {
+            if let Err(e) = std::fs::rename(&aside, &path) {
+                warn!(
+                    "Failed to restore {} from {}: {e}",
+                    path.display(),
+                    aside.display()
+                );
+            }
+        }
+    }
+
+    /// Renames the temporary files into place and removes the deleted
+    /// files, or undoes everything if a rename fails
+    fn commit(self) -> io::Result<()> {
+        // The files already in place, with where the ones they replaced are
+        let mut placed = Vec::new();
+        let failed =
+            self.written.iter().find_map(|(temp, path)| {
+                match replace(temp, path) {
+                    Ok(old) => {
+                        placed.push((path.clone(), old));
+                        None
+                    }
+                    Err(e) => Some(e),
+                }
+            });
+        if let Some(e) = failed {
+            for (path, old) in placed.into_iter().rev() {
+                put_back(&path, old.as_deref());
+            }
+            self.roll_back();
+            return Err(e);
+        }
+
+        for aside in placed.into_iter().filter_map(|(_, old)| old) {
+            if let Err(e) = std::fs::remove_file(&aside) {
+                warn!("Failed to remove {}: {e}", aside.display());
+            }
+        }
+        for (aside, _) in self.deleted {
+            if let Err(e) = std::fs::remove_file(&aside) {
+                warn!("Failed to remove {}: {e}", aside.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Renames `temp` to `path`, moving aside the file it replaces, if any,
+/// and returning where that went
+fn replace(temp: &Path, path: &Path) -> io::Result<Option<PathBuf>> {
+    let old = path.exists().then(|| sibling(path, "old"));
+    if let Some(old) = &old {
+        std::fs::rename(path, old)?;
+    }
+    if let Err(e) = std::fs::rename(temp, path) {
+        if let Some(old) = &old {
+            put_back(path, Some(old));
+        }
+        return Err(e);
+    }
+
+    Ok(old)
+}
+
+/// Undoes [`replace`]: `path` gets back the file moved aside to `old`, or
+/// is removed if it was new
+fn put_back(path: &Path, old: Option<&Path>) {
+    let undone = old.map_or_else(
+        || std::fs::remove_file(path),
+        |old| std::fs::rename(old, path),
+    );
+    if let Err(e) = undone {
+        warn!("Failed to restore {}: {e}", path.display());
+    }
+}
+
+/// A hidden file next to `path`, for what becomes of it while a patch is
+/// written
+fn sibling(path: &Path, purpose: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(
+        ".{name}.aido-{}.{purpose}",
+        std::process::id()
+    ))
+}
+
+/// What applying the patch did, for the model
+fn summary(patches: &[FilePatch]) -> String {
+    let mut summary = "Applied the patch:".to_owned();
+    for patch in patches {
+        let _ = match (&patch.old, &patch.new) {
+            (None, Some(new)) => {
+                write!(summary, " created {};", new.display())
+            }
+            (Some(old), None) => {
+                write!(summary, " deleted {};", old.display())
+            }
+            (Some(old), Some(new)) if old != new => write!(
+                summary,
+                " renamed {} to {};",
+                old.display(),
+                new.display()
+            ),
+            (_, Some(new)) => write!(summary, " modified {};", new.display()),
+            (None, None) => Ok(()),
+        };
+    }
+    summary.pop();
+    summary
+}
+
+/// `diff` with removed lines in red and added lines in green
+fn colored(diff: &str) -> String {
+    diff.lines()
+        .map(|line| {
+            let color = match line.chars().next() {
+                _ if line.starts_with("---") || line.starts_with("+++") => "",
+                Some('-') => RED,
+                Some('+') => GREEN,
+                Some('@') => CYAN,
+                _ => "",
+            };
+            if color.is_empty() {
+                line.to_owned()
+            } else {
+                format!("{color}{line}{RESET}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub struct ApplyPatch {
+    definition: ToolDefinition,
+}
+
+impl ApplyPatch {
+    pub fn new() -> Self {
+        let definition = ToolDefinitionBuilder::new("apply_patch")
+            .description(
+                "Change files under the current directory by applying a \
+                 unified diff, as `git diff` prints it. Each file's changes \
+                 start with `--- a/path` and `+++ b/path` lines, \
+                 `/dev/null` standing for the old name of a new file and \
+                 the new name of a deleted one. Hunks need 3 lines of \
+                 context, copied exactly from the file. Nothing is changed \
+                 if any hunk doesn't match",
+            )
+            .arg(
+                Arg::new("patch")
+                    .description("The unified diff to apply")
+                    .kind(ArgType::String)
+                    .required()
+                    .patch(),
+            )
+            .build();
+
+        Self { definition }
+    }
+}
+
+impl Default for ApplyPatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Tool for ApplyPatch {
+    async fn execute(
+        &self,
+        input: ToolInput,
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        let diff = input
+            .get("patch")
+            .and_then(Value::as_str)
+            .ok_or("Missing required argument: patch")?;
+        let dir = &context.working_dir;
+
+        // Told to the model, which can fix the patch and call again
+        let planned = parse(diff).and_then(|patches| {
+            plan(&patches, dir).map(|changes| (patches, changes))
+        });
+        let (patches, changes) = planned.map_err(|reason| {
+            format!("the patch was not applied: {reason}")
+        })?;
+
+        let shown = if io::stderr().is_terminal() {
+            colored(diff.trim_end())
+        } else {
+            diff.trim_end().to_owned()
+        };
+        if !context.confirm(&format!("\n{shown}\n\nApply this patch?")) {
+            return Err(
+                "the user declined the patch, so no file was changed".into()
+            );
+        }
+
+        write(&changes, dir)?;
+        Ok(summary(&patches))
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+
+    fn capability(&self) -> Capability {
+        Capability::Write
+    }
+
+    fn confirms_itself(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 3b18e51..a5c1966 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1,4 +1,4 @@
+ fn main() {
+-    println!(\"hello\");
++    println!(\"hello, world\");
+
+ }
+--- /dev/null
++++ b/NOTES.md
+@@ -0,0 +1 @@
++# Notes
+";
+
+    #[test]
+    fn test_parse() {
+        let patches = parse(DIFF).unwrap();
+
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].old, Some(PathBuf::from("src/lib.rs")));
+        assert_eq!(patches[0].hunks[0].old_start, 1);
+        assert_eq!(patches[0].hunks[0].lines.len(), 5);
+        assert_eq!(patches[1].old, None);
+        assert_eq!(
+            paths(DIFF).unwrap(),
+            ["src/lib.rs", "src/lib.rs", "NOTES.md"].map(PathBuf::from)
+        );
+        assert!(parse("just text").is_err());
+    }
+
+    #[test]
+    fn test_parse_counts_hunk_lines() {
+        // Lines that look like file headers, but the counts say aren't
+        let diff = "\
+--- a/notes.md
++++ b/notes.md
+@@ -1,2 +1,2 @@
+ # Notes
+--- old rule
++++ new rule
+--- a/other.md
++++ b/other.md
+@@ -1 +1 @@
+-a
++b
+";
+        let patches = parse(diff).unwrap();
+
+        assert_eq!(patches.len(), 2);
+        assert_eq!(
+            patches[0].hunks[0].lines,
+            [
+                Line::Context("# Notes".to_owned()),
+                Line::Removed("-- old rule".to_owned()),
+                Line::Added("++ new rule".to_owned()),
+            ]
+        );
+        assert_eq!(patches[1].new, Some(PathBuf::from("other.md")));
+    }
+
+    #[test]
+    fn test_parse_rejects_paths_out_of_the_directory() {
+        for name in ["b/-/../../etc/x", "../x", "/etc/passwd", "b/src/../../x"]
+        {
+            let diff =
+                format!("--- /dev/null\n+++ {name}\n@@ -0,0 +1 @@\n+x\n");
+            let error = parse(&diff).unwrap_err();
+            assert!(error.contains("isn't a path under"), "{name}: {error}");
+        }
+    }
+
+    #[test]
+    fn test_apply_hunks_keeps_line_endings() {
+        let hunk = Hunk {
+            old_start: 1,
+            lines: vec![
+                Line::Removed("a".to_owned()),
+                Line::Added("A".to_owned()),
+            ],
+            ..Hunk::default()
+        };
+
+        assert_eq!(
+            apply_hunks("a\r\nb\r\n", std::slice::from_ref(&hunk)).unwrap(),
+            "A\r\nb\r\n"
+        );
+        assert_eq!(
+            apply_hunks("a\nb", std::slice::from_ref(&hunk)).unwrap(),
+            "A\nb"
+        );
+
+        // `\ No newline at end of file` changes the final newline
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+A\n\\ No newline at end of file\n";
+        let patches = parse(diff).unwrap();
+        assert_eq!(apply_hunks("a\n", &patches[0].hunks).unwrap(), "A");
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+A\n";
+        let patches = parse(diff).unwrap();
+        assert_eq!(apply_hunks("a", &patches[0].hunks).unwrap(), "A\n");
+    }
+
+    #[test]
+    fn test_apply_hunks_finds_moved_lines() {
+        let hunk = |old_start| Hunk {
+            old_start,
+            lines: vec![
+                Line::Context("b".to_owned()),
+                Line::Removed("c".to_owned()),
+                Line::Added("C".to_owned()),
+            ],
+            ..Hunk::default()
+        };
+
+        // Line numbers off, and trailing whitespace in the file
+        let patched = apply_hunks("a\nb  \nc\nd\n", &[hunk(9)]).unwrap();
+        assert_eq!(patched, "a\nb\nC\nd\n");
+        assert_eq!(apply_hunks("a\nb\nx\n", &[hunk(1)]), Err(1));
+    }
+
+    #[test]
+    fn test_apply_hunks_without_context() {
+        // `diff -U0` inserting X after e, and replacing b
+        let diff = "--- a/f\n+++ b/f\n@@ -2 +2 @@\n-b\n+B\n\
+                    @@ -5,0 +6 @@\n+X\n";
+        let patches = parse(diff).unwrap();
+
+        assert_eq!(
+            apply_hunks("a\nb\nc\nd\ne\nf\n", &patches[0].hunks).unwrap(),
+            "a\nB\nc\nd\ne\nX\nf\n"
+        );
+        let diff = "--- a/f\n+++ b/f\n@@ -0,0 +1 @@\n+X\n";
+        let patches = parse(diff).unwrap();
+        assert_eq!(apply_hunks("a\n", &patches[0].hunks).unwrap(), "X\na\n");
+    }
+
+    #[test]
+    fn test_patch_applies_in_full_or_not_at_all() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-patch-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        let original = "fn main() {\n    println!(\"hello\");\n\n}\n";
+        std::fs::write(dir.join("src/lib.rs"), original).unwrap();
+
+        let stale = DIFF.replace(" fn main() {", " fn start() {");
+        let error = plan(&parse(&stale).unwrap(), &dir).unwrap_err();
+        assert!(error.starts_with("hunk 1 of src/lib.rs doesn't match"));
+
+        let patches = parse(DIFF).unwrap();
+        write(&plan(&patches, &dir).unwrap(), &dir).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/lib.rs")).unwrap(),
+            original.replace("hello", "hello, world")
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("NOTES.md")).unwrap(),
+            "# Notes\n"
+        );
+        assert_eq!(
+            summary(&patches),
+            "Applied the patch: modified src/lib.rs; created NOTES.md"
+        );
+
+        // Creating a file that is there already fails
+        assert!(plan(&patches[1..], &dir).unwrap_err().contains("exists"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename_onto_existing_file_fails() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-patch-rename-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("old.txt"), "a\n").unwrap();
+        std::fs::write(dir.join("new.txt"), "b\n").unwrap();
+
+        let rename = "--- a/old.txt\n+++ b/new.txt\n@@ -1 +1 @@\n-a\n+c\n";
+        let error = plan(&parse(rename).unwrap(), &dir).unwrap_err();
+        assert_eq!(
+            error,
+            "new.txt already exists, but the diff renames old.txt to it"
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write_changes_nothing() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-patch-write-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "a\n").unwrap();
+        std::fs::write(dir.join("0.txt"), "x\n").unwrap();
+        // A file where the new file's directory would have to be
+        std::fs::write(dir.join("b"), "").unwrap();
+
+        let changes = BTreeMap::from([
+            (PathBuf::from("a.txt"), Some("changed\n".to_owned())),
+            (PathBuf::from("b/c.txt"), Some("new\n".to_owned())),
+            (PathBuf::from("0.txt"), None),
+        ]);
+        assert!(write(&changes, &dir).is_err());
+
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["0.txt", "a.txt", "b"]);
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "a\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_rename_puts_back_the_files_renamed() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-patch-commit-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "a\n").unwrap();
+        std::fs::write(dir.join("a.new"), "changed\n").unwrap();
+        std::fs::write(dir.join("b.new"), "new\n").unwrap();
+
+        let staged = Staged {
+            written: vec![
+                (dir.join("a.new"), dir.join("a.txt")),
+                (dir.join("b.new"), dir.join("b.txt")),
+                (dir.join("missing.new"), dir.join("c.txt")),
+            ],
+            deleted: Vec::new(),
+        };
+        assert!(staged.commit().is_err());
+
+        let files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["a.txt"]);
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "a\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_patch_is_only_written_once_the_user_agrees() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-patch-confirm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "a\n").unwrap();
+        let input = ToolInput::from([(
+            "patch".to_owned(),
+            Value::from("--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+b\n"),
+        )]);
+        let asked = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let answering = |answer: bool| {
+            let asked = std::sync::Arc::clone(&asked);
+            ToolContext::new(dir.clone()).with_ask(std::sync::Arc::new(
+                move |question: &str| {
+                    asked.lock().unwrap().push(question.to_owned());
+                    answer
+                },
+            ))
+        };
+        let read = || std::fs::read_to_string(dir.join("a.txt")).unwrap();
+        let tool = ApplyPatch::new();
+
+        // Without anyone to ask, nothing is written
+        let unasked = ToolContext::new(dir.clone());
+        assert!(tool.execute(input.clone(), &unasked).await.is_err());
+        assert!(tool.execute(input.clone(), &answering(false)).await.is_err());
+        assert_eq!(read(), "a\n");
+        assert!(asked.lock().unwrap()[0].contains("+b"));
+
+        tool.execute(input, &answering(true)).await.unwrap();
+        assert_eq!(read(), "b\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
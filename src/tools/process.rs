@@ -0,0 +1,118 @@
+//! Running the programs behind tools
+//!
+//! A tool running a slow program, such as a test suite behind a custom
+//! tool, would otherwise show nothing until the program exits. Within
+//! [`echoing`], [`output`] prints every line the program writes to stdout
+//! or stderr to the terminal as it arrives, dimmed and prefixed with the
+//! tool's name, while still collecting all of it for the tool's result.
+
+use std::io;
+use std::process::{Output, Stdio};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+use crate::status::Printer;
+
+tokio::task_local! {
+    /// Where the tool call being run echoes the output of its programs
+    static ECHO: Option<Echo>;
+}
+
+/// Where the output of a tool's programs is echoed
+#[derive(Clone)]
+pub struct Echo {
+    printer: Printer,
+    prefix: String,
+}
+
+impl Echo {
+    /// Echoes the output of the programs of the tool `name` with `printer`
+    pub fn new(printer: Printer, name: &str) -> Self {
+        Self { printer, prefix: format!("{name} │ ") }
+    }
+
+    fn line(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches(['\n', '\r']);
+        self.printer
+            .print_line(&format!("\x1b[2m{}{line}\x1b[0m", self.prefix));
+    }
+}
+
+/// Runs `future`, a tool call, echoing the output of the programs it runs
+/// with [`output`] if `echo` is given
+pub async fn echoing<F: Future>(echo: Option<Echo>, future: F) -> F::Output {
+    ECHO.scope(echo, future).await
+}
+
+/// Runs `command` to completion and collects its output, like
+/// [`Command::output`], echoing each line as it arrives when called
+/// within [`echoing`]
+pub async fn output(command: &mut Command) -> io::Result<Output> {
+    let Some(echo) = ECHO.try_with(Clone::clone).ok().flatten() else {
+        return command.output().await;
+    };
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (Some(stdout), Some(stderr)) =
+        (child.stdout.take(), child.stderr.take())
+    else {
+        return Err(io::Error::other("the output of the program isn't piped"));
+    };
+
+    let (stdout, stderr, status) = tokio::try_join!(
+        collect(stdout, &echo),
+        collect(stderr, &echo),
+        child.wait()
+    )?;
+    Ok(Output { status, stdout, stderr })
+}
+
+/// Reads `stream` to its end, echoing each line
+async fn collect(
+    stream: impl AsyncRead + Unpin,
+    echo: &Echo,
+) -> io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(stream);
+    let mut collected = Vec::new();
+    loop {
+        let start = collected.len();
+        if reader.read_until(b'\n', &mut collected).await? == 0 {
+            return Ok(collected);
+        }
+        echo.line(&collected[start..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusLine;
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_output_is_collected_while_echoed() {
+        let command = || {
+            let mut command = Command::new("sh");
+            command.args(["-c", "echo one; echo two >&2; printf three"]);
+            command
+        };
+        let echo = Echo::new(StatusLine::new(false).printer(), "test");
+
+        let echoed =
+            echoing(Some(echo), async { output(&mut command()).await })
+                .await
+                .unwrap();
+        let plain = output(&mut command()).await.unwrap();
+
+        assert!(echoed.status.success());
+        assert_eq!(echoed.stdout, b"one\nthree");
+        assert_eq!(echoed.stderr, b"two\n");
+        assert_eq!(echoed, plain);
+    }
+}
@@ -0,0 +1,308 @@
+//! Which tools runs may use
+//!
+//! The [`ToolRegistry`] starts from the built-in tools, leaves out those
+//! named in `disabled` under `[tools]` in the config as well as the opt-in
+//! ones [`tools::OPT_IN`] lists unless `enabled` names them, and adds the
+//! custom shell-command tools declared there:
+//!
+//! ```toml
+//! [tools]
+//! disabled = ["git_log"]
+//! enabled = ["apply_patch"]
+//!
+//! [tools.custom.todo]
+//! description = "List the TODO comments in the project"
+//! command = "grep -rn TODO src"
+//! capability = "read"
+//!
+//! [tools.format]
+//! git_diff = "```diff\n{{output}}\n```"
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::retrieval::{self, Retrieve};
+use crate::tools::{self, CustomTool, CustomToolConfig, Tool, format};
+
+/// Longest tool name the API accepts
+const MAX_NAME_LEN: usize = 64;
+
+/// The `[tools]` section of the config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolsConfig {
+    /// Names of built-in tools runs may not use
+    #[serde(default)]
+    pub disabled: Vec<String>,
+    /// Names of opt-in built-in tools runs may use
+    #[serde(default)]
+    pub enabled: Vec<String>,
+    /// Shell-command tools, by name
+    #[serde(default)]
+    pub custom: BTreeMap<String, CustomToolConfig>,
+    /// Templates applied to the output of tools, by tool name; see
+    /// [`tools::format`]
+    #[serde(default)]
+    pub format: BTreeMap<String, String>,
+    /// Directory the files tools are given must be in, instead of the one
+    /// aido was started in; see [`tools::sandbox`]
+    #[serde(default)]
+    pub sandbox_root: Option<PathBuf>,
+}
+
+impl ToolsConfig {
+    /// Checks that only existing built-in tools are disabled, only opt-in
+    /// ones enabled, and that the custom tools have usable names and
+    /// commands
+    pub fn check(&self) -> Result<(), String> {
+        let builtin = tools::builtin(&Config::default())
+            .iter()
+            .map(|tool| tool.definition().name().to_owned())
+            .chain([retrieval::TOOL_NAME.to_owned()])
+            .collect::<Vec<_>>();
+
+        if let Some(name) =
+            self.disabled.iter().find(|name| !builtin.contains(name))
+        {
+            return Err(format!(
+                "cannot disable '{name}': there is no built-in tool by that \
+                 name; the built-in tools are {builtin:?}"
+            ));
+        }
+        if let Some(name) = self
+            .enabled
+            .iter()
+            .find(|name| !tools::OPT_IN.contains(&name.as_str()))
+        {
+            return Err(format!(
+                "cannot enable '{name}': only the opt-in tools {:?} need \
+                 enabling",
+                tools::OPT_IN
+            ));
+        }
+
+        for (name, custom) in &self.custom {
+            let valid =
+                |c: char| c.is_ascii_alphanumeric() || "_-".contains(c);
+            if name.is_empty()
+                || name.len() > MAX_NAME_LEN
+                || !name.chars().all(valid)
+            {
+                return Err(format!(
+                    "invalid tool name '{name}': use up to {MAX_NAME_LEN} \
+                     letters, digits, '_' and '-'"
+                ));
+            }
+            if builtin.contains(name) && !self.disabled.contains(name) {
+                return Err(format!(
+                    "custom tool '{name}' has the name of a built-in tool; \
+                     disable the built-in one to replace it"
+                ));
+            }
+            custom
+                .check()
+                .map_err(|e| format!("custom tool '{name}': {e}"))?;
+        }
+
+        for (name, template) in &self.format {
+            format::check(template)
+                .map_err(|e| format!("format of tool '{name}': {e}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The tools available to runs, in the order they are offered to the model
+#[derive(Debug, Default)]
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    /// The built-in tools `config` doesn't disable, and the opt-in ones
+    /// it enables, followed by its custom tools
+    pub fn from_config(config: &Config) -> Self {
+        let mut registry = Self::default();
+
+        for tool in tools::builtin(config) {
+            let name = tool.definition().name();
+            let named = |names: &[String]| names.iter().any(|n| n == name);
+            let offered = if tools::OPT_IN.contains(&name) {
+                named(&config.tools.enabled)
+            } else {
+                !named(&config.tools.disabled)
+            };
+            if offered {
+                registry.register(tool);
+            }
+        }
+        for (name, custom) in &config.tools.custom {
+            registry.register(Box::new(CustomTool::new(
+                name,
+                custom,
+                config.exec.clone(),
+            )));
+        }
+
+        registry
+    }
+
+    /// Adds the `retrieve` tool, searching the index at `index`, if the
+    /// config enables it and `aido index` has made one there
+    #[must_use]
+    pub fn with_index(mut self, config: &Config, index: &Path) -> Self {
+        let enabled =
+            config.tools.enabled.iter().any(|n| n == retrieval::TOOL_NAME);
+        if enabled && index.exists() {
+            self.register(Box::new(Retrieve::new(config, index)));
+        }
+        self
+    }
+
+    /// Adds `tool`, replacing the tool of the same name if there is one
+    pub fn register(&mut self, tool: Box<dyn Tool>) {
+        let name = tool.definition().name();
+        match self.tools.iter().position(|t| t.definition().name() == name) {
+            Some(index) => self.tools[index] = tool,
+            None => self.tools.push(tool),
+        }
+    }
+
+    /// The names of the tools, in order
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.iter().map(|tool| tool.definition().name()).collect()
+    }
+
+    pub fn tools(&self) -> &[Box<dyn Tool>] {
+        &self.tools
+    }
+
+    pub fn into_tools(self) -> Vec<Box<dyn Tool>> {
+        self.tools
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom(command: &str) -> CustomToolConfig {
+        CustomToolConfig {
+            description: "custom".to_owned(),
+            command: command.to_owned(),
+            args: BTreeMap::new(),
+            capability: tools::Capability::Read,
+        }
+    }
+
+    #[test]
+    fn test_registry_merges_builtin_and_custom_tools() {
+        let mut config = Config::default();
+        config.tools.disabled = vec!["git_log".to_owned(), "ls".to_owned()];
+        config.tools.custom.insert("ls".to_owned(), custom("ls -a"));
+        config.tools.custom.insert("todo".to_owned(), custom("grep TODO"));
+        config.tools.check().unwrap();
+
+        let registry = ToolRegistry::from_config(&config);
+
+        assert_eq!(
+            registry.names(),
+            ["search", "git_status", "git_diff", "ls", "todo"]
+        );
+        assert_eq!(registry.tools()[3].definition().description(), "custom");
+
+        config.tools.enabled = vec!["apply_patch".to_owned(), "ps".to_owned()];
+        let registry = ToolRegistry::from_config(&config);
+        assert!(registry.names().contains(&"apply_patch"));
+        assert!(registry.names().contains(&"ps"));
+        assert!(!registry.names().contains(&"df"));
+    }
+
+    #[test]
+    fn test_registry_offers_retrieve_with_an_index() {
+        let mut config = Config::default();
+        let index = std::env::temp_dir()
+            .join(format!("aido-registry-test-{}.json", std::process::id()));
+
+        let registry = ToolRegistry::from_config(&config);
+        assert!(
+            !registry
+                .with_index(&config, &index)
+                .names()
+                .contains(&"retrieve")
+        );
+
+        std::fs::write(&index, "{}").unwrap();
+        let registry = ToolRegistry::from_config(&config);
+        assert!(
+            !registry
+                .with_index(&config, &index)
+                .names()
+                .contains(&"retrieve")
+        );
+
+        config.tools.enabled = vec!["retrieve".to_owned()];
+        config.tools.check().unwrap();
+        let registry = ToolRegistry::from_config(&config);
+        assert!(
+            registry.with_index(&config, &index).names().contains(&"retrieve")
+        );
+
+        std::fs::remove_file(index).unwrap();
+    }
+
+    #[test]
+    fn test_check_rejects_bad_tools_config() {
+        let mut config = ToolsConfig {
+            disabled: vec!["rm".to_owned()],
+            ..ToolsConfig::default()
+        };
+        assert!(config.check().unwrap_err().contains("cannot disable 'rm'"));
+
+        config.disabled.clear();
+        config.custom.insert("ls".to_owned(), custom("ls -a"));
+        assert!(config.check().unwrap_err().contains("built-in tool"));
+
+        config.custom.clear();
+        config.custom.insert("run tests".to_owned(), custom("make test"));
+        assert!(config.check().unwrap_err().contains("invalid tool name"));
+
+        config.custom.clear();
+        config.custom.insert("cat".to_owned(), custom("cat {{path}}"));
+        assert!(config.check().unwrap_err().contains("custom tool 'cat'"));
+
+        config.disabled.clear();
+        config.enabled = vec!["search".to_owned()];
+        assert!(config.check().unwrap_err().contains("cannot enable"));
+
+        config.enabled.clear();
+        config.custom.clear();
+        config.format.insert("ls".to_owned(), "{{stdout}}".to_owned());
+        assert!(config.check().unwrap_err().contains("format of tool 'ls'"));
+    }
+
+    #[test]
+    fn test_tools_config_deserialization() {
+        let config: ToolsConfig = serde_json::from_value(serde_json::json!({
+            "disabled": ["search"],
+            "custom": {
+                "cat": {
+                    "description": "Print a file",
+                    "command": "cat {{path}}",
+                    "args": { "path": { "required": true } },
+                },
+            },
+        }))
+        .unwrap();
+
+        let cat = &config.custom["cat"];
+        assert_eq!(cat.capability, tools::Capability::Exec);
+        assert_eq!(cat.args["path"].kind, tools::ArgType::String);
+        assert!(cat.args["path"].required);
+        config.check().unwrap();
+    }
+}
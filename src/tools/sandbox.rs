@@ -0,0 +1,229 @@
+//! Keeping tools to the files under one directory
+//!
+//! The arguments of tools that name files or directories, such as the
+//! `path` of `search`, must be inside the sandbox root: the directory aido
+//! works in, unless `sandbox_root` under `[tools]` in the config names
+//! another. A recipe header's `sandbox_root` can only narrow that root to a
+//! directory inside it. Paths are resolved against that working
+//! directory with `..` and symbolic links followed, so neither
+//! leads out of the root. Calls with a path outside it are denied, and the
+//! model is told why.
+//!
+//! ```toml
+//! [tools]
+//! sandbox_root = "~/src"
+//! ```
+//!
+//! Paths starting with `-` are denied, since a program would take them
+//! for options, which can name files of their own. Custom tools mark their
+//! path arguments with `path = true`. The files a diff given to
+//! `apply_patch` names are checked too.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use serde_json::Value;
+
+use super::{ToolDefinition, ToolInput, patch};
+use crate::config::Config;
+
+/// The directory tools working in `cwd` are confined to under `config`
+pub fn root(config: &Config, cwd: &Path) -> io::Result<PathBuf> {
+    let root = config
+        .tools
+        .sandbox_root
+        .as_deref()
+        .map_or_else(|| cwd.to_owned(), |root| cwd.join(expand_home(root)));
+
+    root.canonicalize()
+}
+
+/// Checks that `narrower`, relative to `cwd`, is a directory inside the
+/// sandbox root of `config`, so that confining tools to it instead never
+/// lets them reach more, saying why not otherwise
+pub fn check_narrower_root(
+    config: &Config,
+    cwd: &Path,
+    narrower: &Path,
+) -> Result<(), String> {
+    let root = root(config, cwd).map_err(|e| {
+        format!("the directory tools are confined to is unusable: {e}")
+    })?;
+    let narrower = cwd
+        .join(expand_home(narrower))
+        .canonicalize()
+        .map_err(|e| format!("it is unusable: {e}"))?;
+
+    if !narrower.starts_with(&root) {
+        return Err(format!(
+            "it is outside {}, the directory tools are confined to, which a \
+             recipe can only narrow",
+            root.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the paths a call of the tool `definition` with `input`,
+/// made in `cwd`, names are inside the sandbox root of `config`, saying
+/// which isn't otherwise
+pub fn check(
+    definition: &ToolDefinition,
+    input: &ToolInput,
+    config: &Config,
+    cwd: &Path,
+) -> Result<(), String> {
+    if !definition.args().iter().any(|arg| arg.is_path() || arg.is_patch()) {
+        return Ok(());
+    }
+
+    let root = root(config, cwd).map_err(|e| {
+        format!("the directory tools are confined to is unusable: {e}")
+    })?;
+
+    check_paths(definition, input, &root, cwd)
+}
+
+/// Checks that the path arguments in `input`, relative to `cwd`, are
+/// inside `root`
+fn check_paths(
+    definition: &ToolDefinition,
+    input: &ToolInput,
+    root: &Path,
+    cwd: &Path,
+) -> Result<(), String> {
+    for arg in definition.args() {
+        let Some(value) = input.get(arg.name()).and_then(Value::as_str) else {
+            continue;
+        };
+        let values = if arg.is_patch() {
+            patch::paths(value)?
+        } else if arg.is_path() {
+            vec![PathBuf::from(value.trim())]
+        } else {
+            continue;
+        };
+
+        for value in values {
+            check_path(&value, root, cwd)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that `path`, relative to `cwd`, is inside `root`
+fn check_path(path: &Path, root: &Path, cwd: &Path) -> Result<(), String> {
+    let value = path.display().to_string();
+    if value.is_empty() {
+        return Ok(());
+    }
+    if value.starts_with('-') {
+        return Err(format!(
+            "`{value}` would be taken for an option rather than a path; \
+             write it as `./{value}`"
+        ));
+    }
+
+    if !resolve(&cwd.join(expand_home(path))).starts_with(root) {
+        return Err(format!(
+            "`{value}` is outside {}, the directory tools are confined to",
+            root.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// `path` with the symbolic links and `..` of the part of it that exists
+/// followed, and the `..` of the rest taken away
+fn resolve(path: &Path) -> PathBuf {
+    for existing in path.ancestors() {
+        let Ok(mut resolved) = existing.canonicalize() else {
+            continue;
+        };
+        let rest =
+            path.strip_prefix(existing).unwrap_or_else(|_| Path::new(""));
+        for component in rest.components() {
+            match component {
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(name) => resolved.push(name),
+                _ => {}
+            }
+        }
+        return resolved;
+    }
+
+    path.to_path_buf()
+}
+
+/// `path` with a leading `~` replaced by the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), directories::BaseDirs::new()) {
+        (Ok(rest), Some(base)) => base.home_dir().join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{Arg, ToolDefinitionBuilder};
+
+    #[test]
+    fn test_check_paths() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-sandbox-test-{}", std::process::id()));
+        let root = dir.join("project");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let root = root.canonicalize().unwrap();
+        let definition = ToolDefinitionBuilder::new("search")
+            .arg(Arg::new("pattern"))
+            .arg(Arg::new("path").path())
+            .build();
+        let check = |path: &str| {
+            let input = ToolInput::from([
+                ("pattern".to_owned(), Value::from("../..")),
+                ("path".to_owned(), Value::from(path)),
+            ]);
+            check_paths(&definition, &input, &root, &root)
+        };
+
+        assert!(check("src").is_ok());
+        assert!(check("src/../new/file.rs").is_ok());
+        assert!(check("-al").unwrap_err().contains("option"));
+        assert!(check("--output=/etc/passwd").is_err());
+        assert!(check("./-al").is_ok());
+        assert!(check("..").unwrap_err().contains("`..` is outside"));
+        assert!(check("src/../../project-other").is_err());
+        assert!(check("/etc").is_err());
+
+        #[cfg(unix)]
+        {
+            std::fs::create_dir_all(dir.join("outside")).unwrap();
+            std::os::unix::fs::symlink(dir.join("outside"), root.join("out"))
+                .unwrap();
+            std::os::unix::fs::symlink(&dir, root.join("up")).unwrap();
+            assert!(check("out").is_err());
+            assert!(check("out/new.txt").is_err());
+            assert!(check("up/project/src").is_ok());
+            assert!(check("out/../project").is_ok());
+        }
+
+        let definition = ToolDefinitionBuilder::new("apply_patch")
+            .arg(Arg::new("patch").patch())
+            .build();
+        let check = |diff: &str| {
+            let input =
+                ToolInput::from([("patch".to_owned(), Value::from(diff))]);
+            check_paths(&definition, &input, &root, &root)
+        };
+        assert!(check("--- a/src/main.rs\n+++ b/src/main.rs\n").is_ok());
+        assert!(check("--- /dev/null\n+++ b/../escape.rs\n").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::context;
+use crate::error::AidoResult;
+use crate::tools::{
+    Arg, ArgType, Tool, ToolContext, ToolDefinition, ToolDefinitionBuilder,
+    ToolInput,
+};
+
+/// Number of matching lines returned when the model doesn't ask for a limit
+const DEFAULT_MAX_RESULTS: usize = 100;
+
+/// Longest line included in the results; longer lines are cut off
+const MAX_LINE_LENGTH: usize = 300;
+
+pub struct Search {
+    definition: ToolDefinition,
+}
+
+impl Search {
+    pub fn new() -> Self {
+        let definition = ToolDefinitionBuilder::new("search")
+            .description(
+                "Search files under the current directory for lines matching \
+                 a regular expression. Files ignored by .gitignore or \
+                 .aidoignore are skipped. Results are formatted as path:line: content",
+            )
+            .arg(
+                Arg::new("pattern")
+                    .description("The regular expression to search for")
+                    .kind(ArgType::String)
+                    .required(),
+            )
+            .arg(
+                Arg::new("path")
+                    .description(
+                        "Directory or file to search, relative to the \
+                         current directory. Defaults to the current directory",
+                    )
+                    .kind(ArgType::String)
+                    .path(),
+            )
+            .arg(
+                Arg::new("max_results")
+                    .description("Maximum number of matching lines to return")
+                    .kind(ArgType::Integer),
+            )
+            .build();
+        Self { definition }
+    }
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Tool for Search {
+    async fn execute(
+        &self,
+        input: ToolInput,
+        context: &ToolContext,
+    ) -> AidoResult<String> {
+        let pattern = input
+            .get("pattern")
+            .and_then(Value::as_str)
+            .ok_or("Missing required argument: pattern")?;
+        let path = input.get("path").and_then(Value::as_str).unwrap_or(".");
+        let max_results = input
+            .get("max_results")
+            .and_then(Value::as_u64)
+            .and_then(|n| usize::try_from(n).ok())
+            .unwrap_or(DEFAULT_MAX_RESULTS);
+
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("Invalid search pattern: {e}"))?;
+        let cwd = &context.working_dir;
+        let root = cwd.join(path);
+
+        Ok(search(&regex, cwd, &root, max_results))
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+/// Searches every non-ignored file under `root` for lines matching `regex`
+///
+/// A `root` inside `cwd` is reached by walking down from `cwd`, so that
+/// naming an ignored directory doesn't get around the ignore files above
+/// it.
+fn search(
+    regex: &Regex,
+    cwd: &Path,
+    root: &Path,
+    max_results: usize,
+) -> String {
+    let mut output = String::new();
+    let mut matches = 0;
+
+    let walk = if root.starts_with(cwd) {
+        let root = root.to_path_buf();
+        context::walker(cwd)
+            .filter_entry(move |entry| {
+                entry.path().starts_with(&root)
+                    || root.starts_with(entry.path())
+            })
+            .build()
+    } else {
+        context::walker(root).build()
+    };
+
+    'files: for entry in walk.flatten() {
+        if !entry.file_type().is_some_and(|ft| ft.is_file()) {
+            continue;
+        }
+
+        // Binary and non-UTF-8 files are skipped
+        let Ok(content) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+
+        let display_path =
+            entry.path().strip_prefix(cwd).unwrap_or_else(|_| entry.path());
+
+        for (line_number, line) in content.lines().enumerate() {
+            if !regex.is_match(line) {
+                continue;
+            }
+
+            if matches == max_results {
+                let _ = writeln!(
+                    output,
+                    "[results truncated after {max_results} matches]"
+                );
+                break 'files;
+            }
+
+            let line = truncate_line(line.trim_end());
+            let _ = writeln!(
+                output,
+                "{}:{}: {line}",
+                display_path.display(),
+                line_number + 1
+            );
+            matches += 1;
+        }
+    }
+
+    if matches == 0 {
+        return "No matches found.".to_string();
+    }
+
+    output
+}
+
+fn truncate_line(line: &str) -> &str {
+    match line.char_indices().nth(MAX_LINE_LENGTH) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_definition() {
+        let search = Search::new();
+        let json = search.definition().json_value();
+
+        assert_eq!(search.definition().name(), "search");
+        assert_eq!(json["required"], serde_json::json!(["pattern"]));
+        assert_eq!(json["properties"]["max_results"]["type"], "integer");
+    }
+
+    #[test]
+    fn test_search_finds_matches_in_source() {
+        let regex = Regex::new(r"struct\s+Search").unwrap();
+        let cwd = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let root = cwd.join("src/tools");
+
+        let output = search(&regex, cwd, &root, 10);
+
+        assert!(output.contains("search.rs:"));
+        assert!(output.contains("pub struct Search {"));
+    }
+
+    #[test]
+    fn test_search_respects_max_results() {
+        let regex = Regex::new(r"fn\s").unwrap();
+        let cwd = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let root = cwd.join("src/tools");
+
+        let output = search(&regex, cwd, &root, 2);
+
+        assert_eq!(output.lines().count(), 3);
+        assert!(output.ends_with("[results truncated after 2 matches]\n"));
+    }
+
+    #[test]
+    fn test_search_no_matches() {
+        let regex = Regex::new("a^").unwrap();
+        let cwd = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let root = cwd.join("src/tools");
+
+        assert_eq!(search(&regex, cwd, &root, 10), "No matches found.");
+    }
+
+    #[test]
+    fn test_search_skips_aidoignored_files() {
+        let cwd = std::env::temp_dir()
+            .join(format!("aido-search-ignore-test-{}", std::process::id()));
+        std::fs::create_dir_all(cwd.join("secrets")).unwrap();
+        std::fs::write(cwd.join(context::IGNORE_FILE_NAME), "secrets/\n")
+            .unwrap();
+        std::fs::write(cwd.join("secrets/token.txt"), "TOKEN=1\n").unwrap();
+        std::fs::write(cwd.join("notes.txt"), "TOKEN is set\n").unwrap();
+        let regex = Regex::new(r"TOKEN\b").unwrap();
+
+        assert_eq!(
+            search(&regex, &cwd, &cwd, 10),
+            "notes.txt:1: TOKEN is set\n"
+        );
+        assert_eq!(
+            search(&regex, &cwd, &cwd.join("secrets"), 10),
+            "No matches found."
+        );
+
+        std::fs::remove_dir_all(cwd).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_execute_requires_pattern() {
+        let error = Search::new()
+            .execute(ToolInput::new(), &ToolContext::current().unwrap())
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "Missing required argument: pattern");
+    }
+
+    #[test]
+    fn test_truncate_line() {
+        let long_line = "x".repeat(MAX_LINE_LENGTH + 50);
+
+        assert_eq!(truncate_line(&long_line).len(), MAX_LINE_LENGTH);
+        assert_eq!(truncate_line("short"), "short");
+    }
+}
@@ -0,0 +1,538 @@
+//! Read-only system tools: `ps`, `df` and `uname`
+//!
+//! They read the process table, the mounted disks and the details of the
+//! OS through sysinfo instead of running the commands they are named
+//! after, so they answer the same way on Linux, macOS and Windows. They
+//! look at the machine aido runs on, whatever the exec backend. Command
+//! lines often carry credentials, such as `--password=...`, so `ps` masks
+//! the values of options named like secrets along with the token formats
+//! the [`Redactor`] knows.
+
+use std::cmp::Reverse;
+use std::fmt::Write as _;
+use std::sync::LazyLock;
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::Value;
+use sysinfo::{
+    Disks, MINIMUM_CPU_UPDATE_INTERVAL, Process, ProcessRefreshKind,
+    ProcessesToUpdate, System, UpdateKind,
+};
+
+use crate::error::AidoResult;
+use crate::redact::{REDACTED, Redactor};
+use crate::tools::{
+    Arg, ArgType, Tool, ToolContext, ToolDefinition, ToolDefinitionBuilder,
+    ToolInput,
+};
+
+/// Processes `ps` lists when the model doesn't ask for a number
+const DEFAULT_PROCESS_COUNT: u64 = 15;
+
+/// Most processes `ps` lists at once
+const MAX_PROCESS_COUNT: u64 = 100;
+
+/// Longest command line `ps` shows, in characters
+const MAX_COMMAND_LENGTH: usize = 120;
+
+/// Options and variables in command lines whose values are secrets, as
+/// `--password=hunter2`, `--token hunter2` or `DB_PASSWORD=hunter2`
+static SECRET_OPTION: LazyLock<Regex> = LazyLock::new(|| {
+    const NAME: &str = concat!(
+        r"[\w.-]*",
+        r"(?:passw(?:or)?d|passphrase|secret|token|api[_-]?key|credential)",
+        r"[\w.-]*",
+    );
+    Regex::new(&format!(r"(?i)(\b{NAME}=|(?:^|\s)--?{NAME}\s+)\S+"))
+        .expect("The secret option pattern should be valid")
+});
+
+/// Masks the secret token formats left in command lines
+static REDACTOR: LazyLock<Redactor> = LazyLock::new(Redactor::new);
+
+/// `command` with the values of its secret options masked
+fn redact_command(command: &str) -> String {
+    let command =
+        SECRET_OPTION.replace_all(command, format!("${{1}}{REDACTED}"));
+    REDACTOR.redact(&command).into_owned()
+}
+
+/// `bytes` in the largest binary unit that keeps it at or above 1
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+
+    format!("{size:.1} {unit}")
+}
+
+/// Lists the processes using the most memory or CPU
+pub struct Ps {
+    definition: ToolDefinition,
+}
+
+impl Ps {
+    pub fn new() -> Self {
+        let definition = ToolDefinitionBuilder::new("ps")
+            .description(
+                "List running processes using the most memory or CPU, as: \
+                 pid cpu% memory name command",
+            )
+            .arg(
+                Arg::new("sort")
+                    .description("What to sort by. Defaults to memory")
+                    .kind(ArgType::String)
+                    .with_enum(["memory", "cpu"]),
+            )
+            .arg(
+                Arg::new("count")
+                    .description(format!(
+                        "Number of processes to list, at most \
+                         {MAX_PROCESS_COUNT}. Defaults to \
+                         {DEFAULT_PROCESS_COUNT}"
+                    ))
+                    .kind(ArgType::Integer),
+            )
+            .arg(
+                Arg::new("name")
+                    .description(
+                        "Only list processes whose name contains this, \
+                         ignoring case",
+                    )
+                    .kind(ArgType::String),
+            )
+            .build();
+        Self { definition }
+    }
+}
+
+impl Default for Ps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A process as `ps` lists it
+#[derive(Debug, Clone, PartialEq)]
+struct ProcessRow {
+    pid: u32,
+    cpu: f32,
+    memory: u64,
+    name: String,
+    command: String,
+}
+
+impl ProcessRow {
+    fn new(process: &Process) -> Self {
+        // One line per process, even for scripts given on the command line
+        let command = process
+            .cmd()
+            .iter()
+            .flat_map(|arg| {
+                arg.to_string_lossy()
+                    .split_whitespace()
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let command = redact_command(&command);
+        let command = match command.char_indices().nth(MAX_COMMAND_LENGTH) {
+            Some((end, _)) => format!("{}...", &command[..end]),
+            None => command,
+        };
+
+        Self {
+            pid: process.pid().as_u32(),
+            cpu: process.cpu_usage(),
+            memory: process.memory(),
+            name: process.name().to_string_lossy().into_owned(),
+            command,
+        }
+    }
+}
+
+/// The table `ps` answers with: the `count` of `rows` using the most CPU,
+/// if `by_cpu`, or else memory, whose name contains `name`
+fn process_table(
+    mut rows: Vec<ProcessRow>,
+    by_cpu: bool,
+    count: usize,
+    name: Option<&str>,
+) -> String {
+    if let Some(name) = name {
+        let name = name.to_lowercase();
+        rows.retain(|row| row.name.to_lowercase().contains(&name));
+    }
+    if by_cpu {
+        rows.sort_by(|a, b| b.cpu.total_cmp(&a.cpu));
+    } else {
+        rows.sort_by_key(|row| Reverse(row.memory));
+    }
+
+    if rows.is_empty() {
+        return "No processes found.".to_owned();
+    }
+
+    let mut table = format!(
+        "{} of {} processes, by {} use:\n",
+        count.min(rows.len()),
+        rows.len(),
+        if by_cpu { "CPU" } else { "memory" },
+    );
+    for row in rows.iter().take(count) {
+        let _ = writeln!(
+            table,
+            "{:>7} {:>5.1}% {:>10} {} {}",
+            row.pid,
+            row.cpu,
+            human_size(row.memory),
+            row.name,
+            row.command,
+        );
+    }
+
+    table
+}
+
+/// The processes running now; with CPU usage measured over a short
+/// interval if `with_cpu`, which blocks for that long
+fn processes(with_cpu: bool) -> Vec<ProcessRow> {
+    let kind = ProcessRefreshKind::nothing()
+        .with_memory()
+        .with_cmd(UpdateKind::OnlyIfNotSet);
+    let kind = if with_cpu { kind.with_cpu() } else { kind };
+
+    let mut system = System::new();
+    system.refresh_processes_specifics(ProcessesToUpdate::All, true, kind);
+    if with_cpu {
+        // CPU usage is the difference between two refreshes
+        std::thread::sleep(MINIMUM_CPU_UPDATE_INTERVAL);
+        system.refresh_processes_specifics(ProcessesToUpdate::All, true, kind);
+    }
+
+    system
+        .processes()
+        .values()
+        .filter(|process| process.thread_kind().is_none())
+        .map(ProcessRow::new)
+        .collect()
+}
+
+#[async_trait]
+impl Tool for Ps {
+    async fn execute(
+        &self,
+        input: ToolInput,
+        _context: &ToolContext,
+    ) -> AidoResult<String> {
+        let by_cpu = input.get("sort").and_then(Value::as_str) == Some("cpu");
+        let count = input
+            .get("count")
+            .and_then(Value::as_u64)
+            .unwrap_or(DEFAULT_PROCESS_COUNT)
+            .clamp(1, MAX_PROCESS_COUNT);
+        let count = usize::try_from(count).unwrap_or(usize::MAX);
+        let name = input
+            .get("name")
+            .and_then(Value::as_str)
+            .filter(|name| !name.is_empty());
+
+        let rows = tokio::task::spawn_blocking(move || processes(by_cpu))
+            .await
+            .map_err(|e| format!("Failed to list processes: {e}"))?;
+
+        Ok(process_table(rows, by_cpu, count, name))
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+/// Lists the mounted disks and how full they are
+pub struct Df {
+    definition: ToolDefinition,
+}
+
+impl Df {
+    pub fn new() -> Self {
+        let definition = ToolDefinitionBuilder::new("df")
+            .description(
+                "List mounted disks and how full they are, as: mount point, \
+                 file system, size, used, available, use%",
+            )
+            .build();
+        Self { definition }
+    }
+}
+
+impl Default for Df {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The table `df` answers with, from the disks mounted now
+fn disk_table() -> String {
+    let disks = Disks::new_with_refreshed_list();
+    let mut disks = disks
+        .list()
+        .iter()
+        .filter(|disk| disk.total_space() > 0)
+        .collect::<Vec<_>>();
+    disks.sort_by_key(|disk| disk.mount_point());
+
+    if disks.is_empty() {
+        return "No disks found.".to_owned();
+    }
+
+    let mut table = String::new();
+    for disk in disks {
+        let total = disk.total_space();
+        let used = total.saturating_sub(disk.available_space());
+        let _ = writeln!(
+            table,
+            "{} {} {} {} {} {}%",
+            disk.mount_point().display(),
+            disk.file_system().to_string_lossy(),
+            human_size(total),
+            human_size(used),
+            human_size(disk.available_space()),
+            used * 100 / total,
+        );
+    }
+
+    table
+}
+
+#[async_trait]
+impl Tool for Df {
+    async fn execute(
+        &self,
+        _input: ToolInput,
+        _context: &ToolContext,
+    ) -> AidoResult<String> {
+        Ok(tokio::task::spawn_blocking(disk_table)
+            .await
+            .map_err(|e| format!("Failed to list disks: {e}"))?)
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+/// Describes the OS, the hardware and how busy the machine is
+pub struct Uname {
+    definition: ToolDefinition,
+}
+
+impl Uname {
+    pub fn new() -> Self {
+        let definition = ToolDefinitionBuilder::new("uname")
+            .description(
+                "Describe the operating system, kernel, host name, CPU \
+                 architecture, uptime, load and memory of this machine",
+            )
+            .build();
+        Self { definition }
+    }
+}
+
+impl Default for Uname {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `seconds` as days, hours and minutes
+fn human_duration(seconds: u64) -> String {
+    let days = seconds / 86_400;
+    let hours = seconds % 86_400 / 3600;
+    let minutes = seconds % 3600 / 60;
+
+    match days {
+        0 => format!("{hours}:{minutes:02}"),
+        1 => format!("1 day, {hours}:{minutes:02}"),
+        _ => format!("{days} days, {hours}:{minutes:02}"),
+    }
+}
+
+/// What `uname` answers with, read from the machine now
+fn machine_info() -> String {
+    let unknown = || "unknown".to_owned();
+    let mut system = System::new();
+    system.refresh_memory();
+    system.refresh_cpu_list(sysinfo::CpuRefreshKind::nothing());
+
+    let mut info = String::new();
+    let _ = writeln!(
+        info,
+        "OS: {}",
+        System::long_os_version().unwrap_or_else(unknown)
+    );
+    let _ = writeln!(
+        info,
+        "Kernel: {}",
+        System::kernel_version().unwrap_or_else(unknown)
+    );
+    let _ = writeln!(
+        info,
+        "Host: {}",
+        System::host_name().unwrap_or_else(unknown)
+    );
+    let _ = writeln!(info, "Architecture: {}", System::cpu_arch());
+    let _ = writeln!(
+        info,
+        "CPUs: {}{}",
+        system.cpus().len(),
+        System::physical_core_count()
+            .map(|cores| format!(" ({cores} physical)"))
+            .unwrap_or_default()
+    );
+    let _ = writeln!(info, "Uptime: {}", human_duration(System::uptime()));
+    let load = System::load_average();
+    let _ = writeln!(
+        info,
+        "Load average: {:.2} {:.2} {:.2}",
+        load.one, load.five, load.fifteen
+    );
+    let _ = writeln!(
+        info,
+        "Memory: {} used of {}, {} available",
+        human_size(system.used_memory()),
+        human_size(system.total_memory()),
+        human_size(system.available_memory())
+    );
+    let _ = writeln!(
+        info,
+        "Swap: {} used of {}",
+        human_size(system.used_swap()),
+        human_size(system.total_swap())
+    );
+
+    info
+}
+
+#[async_trait]
+impl Tool for Uname {
+    async fn execute(
+        &self,
+        _input: ToolInput,
+        _context: &ToolContext,
+    ) -> AidoResult<String> {
+        Ok(tokio::task::spawn_blocking(machine_info)
+            .await
+            .map_err(|e| format!("Failed to describe the machine: {e}"))?)
+    }
+
+    fn definition(&self) -> &ToolDefinition {
+        &self.definition
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(pid: u32, cpu: f32, memory: u64, name: &str) -> ProcessRow {
+        ProcessRow {
+            pid,
+            cpu,
+            memory,
+            name: name.to_owned(),
+            command: format!("/usr/bin/{name}"),
+        }
+    }
+
+    #[test]
+    fn test_process_table() {
+        let rows = vec![
+            row(1, 0.5, 1024, "init"),
+            row(200, 90.0, 512 * 1024 * 1024, "Firefox"),
+            row(300, 3.0, 2 * 1024 * 1024 * 1024, "java"),
+        ];
+
+        let table = process_table(rows.clone(), false, 2, None);
+        assert_eq!(
+            table,
+            "2 of 3 processes, by memory use:\n    \
+             300   3.0%    2.0 GiB java /usr/bin/java\n    \
+             200  90.0%  512.0 MiB Firefox /usr/bin/Firefox\n"
+        );
+
+        let table = process_table(rows.clone(), true, 10, None);
+        assert!(table.lines().nth(1).unwrap().contains("Firefox"));
+
+        let table = process_table(rows.clone(), false, 10, Some("fire"));
+        assert!(table.starts_with("1 of 1 processes"));
+        assert_eq!(
+            process_table(rows, false, 10, Some("python")),
+            "No processes found."
+        );
+    }
+
+    #[test]
+    fn test_redact_command() {
+        assert_eq!(
+            redact_command(
+                "mysql --user=root --password=hunter2 -h db --token abc123"
+            ),
+            "mysql --user=root --password=[REDACTED] -h db --token [REDACTED]"
+        );
+        assert_eq!(
+            redact_command("env DB_PASSWORD=hunter2 api_key=x1 ./serve"),
+            "env DB_PASSWORD=[REDACTED] api_key=[REDACTED] ./serve"
+        );
+        assert_eq!(
+            redact_command("curl -H Bearer abcdefghijklmnopqrstuvwxyz"),
+            "curl -H [REDACTED]"
+        );
+        assert_eq!(
+            redact_command("vim --noplugin notes/tokenizer.rs"),
+            "vim --noplugin notes/tokenizer.rs"
+        );
+    }
+
+    #[test]
+    fn test_human_units() {
+        assert_eq!(human_size(1000), "1000 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024 * 1024), "5.0 TiB");
+        assert_eq!(human_duration(59), "0:00");
+        assert_eq!(human_duration(90_061), "1 day, 1:01");
+        assert_eq!(
+            human_duration(3 * 86_400 + 3600 * 4 + 300),
+            "3 days, 4:05"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tools_describe_this_machine() {
+        let info = Uname::new()
+            .execute(ToolInput::new(), &ToolContext::current().unwrap())
+            .await
+            .unwrap();
+        assert!(info.contains("Memory: "));
+
+        let ps = Ps::new()
+            .execute(ToolInput::new(), &ToolContext::current().unwrap())
+            .await
+            .unwrap();
+        assert!(ps.contains("processes, by memory use:"));
+    }
+}
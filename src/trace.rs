@@ -0,0 +1,220 @@
+//! A timeline of what happened during an agent run
+//!
+//! The trace records every model reply and tool invocation along with when
+//! it started and how long it took, after what was done to the request
+//! before it was sent. Unlike logging it is meant for the user:
+//! with `--trace` it is printed as a numbered outline once the run is over.
+
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Longest tool argument string shown in the outline
+const MAX_ARGUMENTS_LENGTH: usize = 60;
+
+/// What happened during a single step of the run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEventKind {
+    /// The model finished a reply
+    ModelReply {
+        model: String,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        tool_calls: usize,
+    },
+    /// A tool was invoked
+    ToolCall { name: String, arguments: String, failed: bool },
+    /// Examples of the recipe were left out to fit the token budget
+    ExamplesPruned { dropped: usize, total: usize },
+}
+
+/// A single step of the run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// When the step started, relative to the start of the run
+    pub started_at: Duration,
+    /// How long the step took
+    pub duration: Duration,
+    pub kind: TraceEventKind,
+}
+
+/// Ordered record of the steps taken during a run
+#[derive(Debug, Clone)]
+pub struct Trace {
+    start: Instant,
+    /// The wall clock time at `start`, for exporting the trace
+    started_at: SystemTime,
+    events: Vec<TraceEvent>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::start()
+    }
+}
+
+impl Trace {
+    /// Starts a new, empty trace
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            started_at: SystemTime::now(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Records a step that began at `started` and just finished
+    pub fn record(&mut self, started: Instant, kind: TraceEventKind) {
+        self.events.push(TraceEvent {
+            started_at: started.saturating_duration_since(self.start),
+            duration: started.elapsed(),
+            kind,
+        });
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    /// When the run started, by the wall clock
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// Time from the start of the run until now
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Time from the start of the run to the end of its last step
+    pub fn duration(&self) -> Duration {
+        self.events
+            .iter()
+            .map(|event| event.started_at + event.duration)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for TraceEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ModelReply { completion_tokens, tool_calls, .. } => {
+                write!(f, "model reply: {completion_tokens} tokens")?;
+                match tool_calls {
+                    0 => Ok(()),
+                    1 => write!(f, ", 1 tool call"),
+                    n => write!(f, ", {n} tool calls"),
+                }
+            }
+            Self::ToolCall { name, arguments, failed } => {
+                let arguments =
+                    match arguments.char_indices().nth(MAX_ARGUMENTS_LENGTH) {
+                        Some((end, _)) => format!("{}…", &arguments[..end]),
+                        None => arguments.clone(),
+                    };
+                write!(f, "tool {name}({arguments})")?;
+                if *failed {
+                    write!(f, " failed")?;
+                }
+                Ok(())
+            }
+            Self::ExamplesPruned { dropped, total } => write!(
+                f,
+                "left out {dropped} of {total} recipe examples to fit the \
+                 context limit"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Trace:")?;
+
+        for (i, event) in self.events.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>4}. [+{:.3}s] {} ({}ms)",
+                i + 1,
+                event.started_at.as_secs_f64(),
+                event.kind,
+                event.duration.as_millis()
+            )?;
+        }
+
+        writeln!(f, "Total: {:.3}s", self.elapsed().as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_records_events_in_order() {
+        let mut trace = Trace::start();
+
+        trace.record(
+            Instant::now(),
+            TraceEventKind::ModelReply {
+                model: "gpt-4o".to_string(),
+                prompt_tokens: 100,
+                completion_tokens: 12,
+                tool_calls: 1,
+            },
+        );
+        trace.record(
+            Instant::now(),
+            TraceEventKind::ToolCall {
+                name: "ls".to_string(),
+                arguments: "{}".to_string(),
+                failed: false,
+            },
+        );
+
+        assert_eq!(trace.events().len(), 2);
+        assert!(trace.events()[0].started_at <= trace.events()[1].started_at);
+        assert!(trace.duration() >= trace.events()[1].started_at);
+        assert_eq!(Trace::start().duration(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_event_kind_display() {
+        let reply = TraceEventKind::ModelReply {
+            model: "gpt-4o".to_string(),
+            prompt_tokens: 1000,
+            completion_tokens: 412,
+            tool_calls: 2,
+        };
+        assert_eq!(reply.to_string(), "model reply: 412 tokens, 2 tool calls");
+
+        let tool = TraceEventKind::ToolCall {
+            name: "search".to_string(),
+            arguments: format!(r#"{{"pattern": "{}"}}"#, "x".repeat(100)),
+            failed: true,
+        };
+        let display = tool.to_string();
+        assert!(display.starts_with(r#"tool search({"pattern": "xxx"#));
+        assert!(display.ends_with("…) failed"));
+    }
+
+    #[test]
+    fn test_trace_display_is_numbered() {
+        let mut trace = Trace::start();
+        trace.record(
+            Instant::now(),
+            TraceEventKind::ModelReply {
+                model: "gpt-4o".to_string(),
+                prompt_tokens: 10,
+                completion_tokens: 1,
+                tool_calls: 0,
+            },
+        );
+
+        let display = trace.to_string();
+
+        assert!(display.starts_with("Trace:\n   1. [+"));
+        assert!(display.contains("model reply: 1 tokens"));
+        assert!(display.contains("Total: "));
+    }
+}
@@ -0,0 +1,471 @@
+//! Cost estimation and the persistent usage ledger
+//!
+//! Every run appends one line of JSON to the ledger file next to the config
+//! file, recording the tokens it consumed and what they are estimated to have
+//! cost according to the configured price table.
+//! `aido usage report --weekly` breaks the last weeks down per model and
+//! recipe, with a sparkline of the cost of each week.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::hash::BuildHasher;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::llm::Usage;
+
+/// Name of the ledger file inside the config directory
+const LEDGER_FILE_NAME: &str = "usage.jsonl";
+
+/// Number of weeks covered by the weekly report
+pub const REPORT_WEEKS: usize = 8;
+
+const WEEK_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Bars of a sparkline, from lowest to highest
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Price of a model in dollars per one million tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    /// Price per 1M prompt tokens
+    pub prompt: f64,
+    /// Price per 1M completion tokens
+    pub completion: f64,
+}
+
+impl ModelPrice {
+    /// Estimates the cost in dollars of the given usage
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let prompt = f64::from(usage.prompt_tokens()) * self.prompt;
+        let completion =
+            f64::from(usage.completion_tokens()) * self.completion;
+
+        (prompt + completion) / 1_000_000.0
+    }
+}
+
+/// Estimates the cost of `usage` for `model`, if the model has a price
+pub fn estimate_cost<S: BuildHasher>(
+    prices: &HashMap<String, ModelPrice, S>,
+    model: &str,
+    usage: &Usage,
+) -> Option<f64> {
+    prices.get(model).map(|price| price.cost(usage))
+}
+
+/// A single run recorded in the ledger
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// Seconds since the Unix epoch when the run finished
+    pub timestamp: u64,
+    /// Model used for the run
+    pub model: String,
+    /// Recipe that was run, if any
+    #[serde(default)]
+    pub recipe: Option<String>,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// Estimated cost in dollars, if the model has a configured price
+    #[serde(default)]
+    pub cost: Option<f64>,
+    /// How long the run took in milliseconds; older entries lack it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+impl LedgerEntry {
+    /// Creates an entry for a run that just finished
+    pub fn new(
+        model: impl Into<String>,
+        recipe: Option<String>,
+        usage: &Usage,
+        cost: Option<f64>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Self {
+            timestamp,
+            model: model.into(),
+            recipe,
+            prompt_tokens: usage.prompt_tokens(),
+            completion_tokens: usage.completion_tokens(),
+            cost,
+            duration_ms: None,
+        }
+    }
+
+    /// Records how long the run took
+    #[must_use]
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration_ms =
+            Some(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX));
+        self
+    }
+}
+
+/// Append-only log of the usage of every run
+#[derive(Debug, Clone)]
+pub struct Ledger {
+    path: PathBuf,
+}
+
+impl Ledger {
+    /// Opens the ledger stored next to the given config file
+    pub fn for_config_file(config_file_path: &str) -> Self {
+        let path = Path::new(config_file_path)
+            .parent()
+            .expect("Config file path should have a parent directory")
+            .join(LEDGER_FILE_NAME);
+
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends an entry to the ledger
+    pub fn record(&self, entry: &LedgerEntry) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file =
+            OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+
+        Ok(())
+    }
+
+    /// Reads every entry in the ledger, skipping lines that cannot be parsed
+    pub fn entries(&self) -> std::io::Result<Vec<LedgerEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let file = std::fs::File::open(&self.path)?;
+        let mut entries = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            if let Ok(entry) = serde_json::from_str(&line?) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Aggregated usage of a single model across the ledger
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelTotals {
+    pub runs: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Summed cost of the runs that had a known price
+    pub cost: f64,
+}
+
+/// Sums up ledger entries per model
+pub fn totals_by_model(
+    entries: &[LedgerEntry],
+) -> BTreeMap<String, ModelTotals> {
+    let mut totals: BTreeMap<String, ModelTotals> = BTreeMap::new();
+
+    for entry in entries {
+        let model_totals = totals.entry(entry.model.clone()).or_default();
+        model_totals.runs += 1;
+        model_totals.prompt_tokens += u64::from(entry.prompt_tokens);
+        model_totals.completion_tokens += u64::from(entry.completion_tokens);
+        model_totals.cost += entry.cost.unwrap_or_default();
+    }
+
+    totals
+}
+
+/// Prints a per-model summary of everything in the ledger
+pub fn print_report(ledger: &Ledger) -> std::io::Result<()> {
+    let entries = ledger.entries()?;
+
+    if entries.is_empty() {
+        println!("No usage recorded yet.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<40} {:>6} {:>12} {:>12} {:>10}",
+        "MODEL", "RUNS", "PROMPT", "COMPLETION", "COST"
+    );
+
+    let totals = totals_by_model(&entries);
+    for (model, t) in &totals {
+        println!(
+            "{model:<40} {:>6} {:>12} {:>12} {:>10}",
+            t.runs,
+            t.prompt_tokens,
+            t.completion_tokens,
+            format!("${:.4}", t.cost)
+        );
+    }
+
+    let total_cost: f64 = totals.values().map(|t| t.cost).sum();
+    println!("\nTotal estimated cost: ${total_cost:.4}");
+
+    Ok(())
+}
+
+/// Aggregated usage of a model or recipe over the weeks of the weekly
+/// report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WeeklyTotals {
+    pub runs: usize,
+    pub cost: f64,
+    /// Cost of each week, oldest first
+    pub weekly_cost: [f64; REPORT_WEEKS],
+    /// Summed duration of the runs that recorded one
+    duration_ms: u64,
+    timed_runs: u64,
+}
+
+impl WeeklyTotals {
+    fn add(&mut self, week: usize, entry: &LedgerEntry) {
+        let cost = entry.cost.unwrap_or_default();
+        self.runs += 1;
+        self.cost += cost;
+        self.weekly_cost[week] += cost;
+        if let Some(duration_ms) = entry.duration_ms {
+            self.duration_ms += duration_ms;
+            self.timed_runs += 1;
+        }
+    }
+
+    /// Average duration of the runs, if any of them recorded one
+    pub fn average_duration(&self) -> Option<Duration> {
+        (self.timed_runs > 0)
+            .then(|| Duration::from_millis(self.duration_ms / self.timed_runs))
+    }
+}
+
+/// The last [`REPORT_WEEKS`] weeks of the ledger
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WeeklyReport {
+    pub by_model: BTreeMap<String, WeeklyTotals>,
+    /// Runs without a recipe are listed under `(none)`
+    pub by_recipe: BTreeMap<String, WeeklyTotals>,
+    pub total: WeeklyTotals,
+}
+
+/// Which of the weeks before `now` the `timestamp` falls in, oldest first,
+/// or `None` if it is older than the report or in the future
+fn week_index(timestamp: u64, now: u64) -> Option<usize> {
+    let weeks_ago = usize::try_from(now.checked_sub(timestamp)? / WEEK_SECS)
+        .ok()
+        .filter(|&weeks_ago| weeks_ago < REPORT_WEEKS)?;
+
+    Some(REPORT_WEEKS - 1 - weeks_ago)
+}
+
+/// Sums up the entries of the last [`REPORT_WEEKS`] weeks before `now`, in
+/// seconds since the Unix epoch, per model and per recipe
+pub fn weekly_report(entries: &[LedgerEntry], now: u64) -> WeeklyReport {
+    let mut report = WeeklyReport::default();
+
+    for entry in entries {
+        let Some(week) = week_index(entry.timestamp, now) else {
+            continue;
+        };
+        let recipe = entry.recipe.as_deref().unwrap_or("(none)");
+
+        report
+            .by_model
+            .entry(entry.model.clone())
+            .or_default()
+            .add(week, entry);
+        report
+            .by_recipe
+            .entry(recipe.to_owned())
+            .or_default()
+            .add(week, entry);
+        report.total.add(week, entry);
+    }
+
+    report
+}
+
+/// Draws `values` as a line of bars scaled to the largest of them
+pub fn sparkline(values: &[f64]) -> String {
+    let max = values.iter().copied().fold(0.0, f64::max);
+
+    values
+        .iter()
+        .map(|&value| {
+            if max <= 0.0 {
+                return SPARKS[0];
+            }
+            #[allow(
+                clippy::cast_possible_truncation,
+                clippy::cast_sign_loss,
+                clippy::cast_precision_loss
+            )] // The level is between 0 and the number of bars
+            let level =
+                (value / max * (SPARKS.len() - 1) as f64).round() as usize;
+            SPARKS[level.min(SPARKS.len() - 1)]
+        })
+        .collect()
+}
+
+fn print_weekly_section(
+    heading: &str,
+    totals: &BTreeMap<String, WeeklyTotals>,
+) {
+    println!(
+        "{heading:<32} {:>6} {:>10} {:>9}  TREND",
+        "RUNS", "COST", "AVG TIME"
+    );
+
+    for (name, t) in totals {
+        let average = t.average_duration().map_or_else(
+            || "-".to_owned(),
+            |d| format!("{:.1}s", d.as_secs_f64()),
+        );
+        println!(
+            "{name:<32} {:>6} {:>10} {average:>9}  {}",
+            t.runs,
+            format!("${:.4}", t.cost),
+            sparkline(&t.weekly_cost)
+        );
+    }
+}
+
+/// Prints the usage of the last [`REPORT_WEEKS`] weeks per model and
+/// recipe, with the cost of each week as a sparkline, oldest first
+pub fn print_weekly_report(ledger: &Ledger) -> std::io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let report = weekly_report(&ledger.entries()?, now);
+
+    if report.total.runs == 0 {
+        println!("No usage recorded in the last {REPORT_WEEKS} weeks.");
+        return Ok(());
+    }
+
+    println!("Last {REPORT_WEEKS} weeks, oldest first\n");
+    print_weekly_section("MODEL", &report.by_model);
+    println!();
+    print_weekly_section("RECIPE", &report.by_recipe);
+
+    println!(
+        "\nTotal estimated cost: ${:.4}  {}",
+        report.total.cost,
+        sparkline(&report.total.weekly_cost)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_price_cost() {
+        let price = ModelPrice { prompt: 2.0, completion: 8.0 };
+        let usage = Usage::new(500_000, 250_000, 750_000);
+
+        assert!((price.cost(&usage) - 3.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_estimate_cost_unknown_model() {
+        let prices = HashMap::new();
+        let usage = Usage::new(10, 10, 20);
+
+        assert_eq!(estimate_cost(&prices, "unknown", &usage), None);
+    }
+
+    #[test]
+    fn test_totals_by_model() {
+        let usage = Usage::new(100, 50, 150);
+        let entries = vec![
+            LedgerEntry::new("a", None, &usage, Some(0.5)),
+            LedgerEntry::new("a", Some("do".to_string()), &usage, Some(0.25)),
+            LedgerEntry::new("b", None, &usage, None),
+        ];
+
+        let totals = totals_by_model(&entries);
+
+        assert_eq!(totals["a"].runs, 2);
+        assert_eq!(totals["a"].prompt_tokens, 200);
+        assert_eq!(totals["a"].completion_tokens, 100);
+        assert!((totals["a"].cost - 0.75).abs() < f64::EPSILON);
+        assert_eq!(totals["b"].runs, 1);
+        assert!(totals["b"].cost.abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_weekly_report() {
+        let now = 100 * WEEK_SECS;
+        let usage = Usage::new(1, 1, 2);
+        let entry = |weeks_ago: u64, model: &str, recipe: Option<&str>| {
+            let mut entry = LedgerEntry::new(
+                model,
+                recipe.map(str::to_owned),
+                &usage,
+                Some(1.0),
+            )
+            .with_duration(Duration::from_secs(weeks_ago));
+            entry.timestamp = now - weeks_ago * WEEK_SECS - 1;
+            entry
+        };
+        let entries = [
+            entry(0, "a", Some("commit")),
+            entry(0, "a", None),
+            entry(2, "b", Some("commit")),
+            // Too old for the report
+            entry(8, "a", None),
+        ];
+
+        let report = weekly_report(&entries, now);
+
+        assert_eq!(report.total.runs, 3);
+        assert_eq!(report.by_model["a"].runs, 2);
+        assert_eq!(sparkline(&report.by_model["a"].weekly_cost), "▁▁▁▁▁▁▁█");
+        assert_eq!(sparkline(&report.by_model["b"].weekly_cost), "▁▁▁▁▁█▁▁");
+        assert_eq!(report.by_recipe["commit"].runs, 2);
+        assert_eq!(report.by_recipe["(none)"].runs, 1);
+        assert_eq!(
+            report.by_recipe["commit"].average_duration(),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0.0, 1.0, 2.0, 4.0]), "▁▃▅█");
+        assert_eq!(sparkline(&[0.0, 0.0]), "▁▁");
+        assert_eq!(sparkline(&[]), "");
+    }
+
+    #[test]
+    fn test_ledger_entry_round_trip() {
+        let entry = LedgerEntry::new(
+            "model",
+            Some("commit".to_string()),
+            &Usage::new(1, 2, 3),
+            Some(0.1),
+        )
+        .with_duration(Duration::from_millis(1500));
+
+        let json = serde_json::to_string(&entry).unwrap();
+        let parsed: LedgerEntry = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed, entry);
+    }
+}
@@ -0,0 +1,205 @@
+//! Scoring answers with a judge model, and revising them for a reviewer
+//!
+//! When `[verify]` is configured, every answer is shown to a judge prompt
+//! that scores it from 1 to 10. Answers scoring below the threshold are
+//! produced again by the next model in `fallback_models`, and the best
+//! scoring answer is the one presented. The next model answers the same
+//! conversation, picking up after the tools the first one already ran.
+//!
+//! A recipe can instead name a reviewing recipe with `verify: <recipe>`.
+//! That recipe is given the question and the answer, and either accepts
+//! the answer or says what is wrong with it, in which case the critique is
+//! sent back to have the answer revised, up to `max_revisions` times.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::error::AidoResult;
+use crate::llm::{LlmClient, LlmRequest, Message, Usage};
+use crate::session;
+
+/// Lowest passing score when none is configured
+const DEFAULT_THRESHOLD: u8 = 7;
+
+/// Most revisions of an answer for a reviewing recipe when a recipe
+/// doesn't set `max_revisions`
+pub const DEFAULT_MAX_REVISIONS: usize = 2;
+
+/// What a reviewing recipe starts its reply with to accept an answer
+const ACCEPT: &str = "ACCEPT";
+
+const JUDGE_PROMPT: &str = "You review answers given by an AI assistant. \
+    Score how well the final answer fulfills the request in the \
+    conversation, from 1 (wrong or useless) to 10 (correct and complete). \
+    Reply with the score alone on the first line, followed by one sentence \
+    explaining it.";
+
+/// Matches the score on the first line of the judge's reply, e.g. `8`,
+/// `8/10` or `Score: 8`
+static SCORE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(10|[1-9])\b(?:\s*/\s*10\b)?").unwrap());
+
+/// Settings for scoring answers before they are presented
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyConfig {
+    /// Lowest score, from 1 to 10, an answer needs to be accepted
+    #[serde(default = "default_threshold")]
+    pub threshold: u8,
+    /// Model doing the scoring; defaults to the configured model
+    #[serde(default)]
+    pub judge_model: Option<String>,
+}
+
+fn default_threshold() -> u8 {
+    DEFAULT_THRESHOLD
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        Self { threshold: DEFAULT_THRESHOLD, judge_model: None }
+    }
+}
+
+/// A judge's opinion of an answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    /// From 1 to 10
+    pub score: u8,
+    pub reason: String,
+}
+
+/// Parses the judge's reply, or `None` when it doesn't start with a score
+fn parse_verdict(reply: &str) -> Option<Verdict> {
+    let reply = reply.trim();
+    let (first_line, rest) = reply.split_once('\n').unwrap_or((reply, ""));
+    let found = SCORE_REGEX.captures(first_line)?;
+    let score = found[1].parse().ok()?;
+
+    // The reason may share the first line with the score
+    let reason = match rest.trim() {
+        "" => first_line[found.get(0)?.end()..]
+            .trim_start_matches(['.', ':', '-', ' '])
+            .trim(),
+        rest => rest,
+    };
+
+    Some(Verdict { score, reason: reason.to_owned() })
+}
+
+/// Has `judge` score `answer` as a reply to `conversation`
+///
+/// Returns `None` for the verdict when the judge's reply has no score.
+pub async fn judge(
+    judge: &LlmClient,
+    conversation: &[Message],
+    answer: &str,
+) -> AidoResult<(Option<Verdict>, Usage)> {
+    let request = LlmRequest::new(
+        vec![
+            Message::System(JUDGE_PROMPT.to_owned()),
+            Message::User(format!(
+                "{}\n\nFinal answer:\n{answer}",
+                session::transcript(conversation)
+            )),
+        ],
+        Vec::new(),
+    );
+    let response = judge.get_chat_completion(&request).await?;
+
+    Ok((parse_verdict(response.text()), response.usage().clone()))
+}
+
+/// A reviewing recipe's opinion of an answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Review {
+    Accepted,
+    /// What is wrong with the answer
+    Rejected(String),
+}
+
+/// The user message asking a reviewing recipe to review `answer`, the
+/// last reply in the `conversation` transcript
+pub fn review_request(conversation: &str, answer: &str) -> String {
+    format!(
+        "Review the answer to the conversation below. If it is correct and \
+         complete, reply with {ACCEPT} alone. Otherwise, say what is wrong \
+         with it and how to fix it.\n\nConversation:\n\n{conversation}\n\n\
+         Answer:\n\n{answer}"
+    )
+}
+
+/// Parses the reply of a reviewing recipe: one starting with `ACCEPT`
+/// accepts the answer, and any other is the critique
+pub fn parse_review(reply: &str) -> Review {
+    let reply = reply.trim();
+    let accepted = reply
+        .split(|c: char| !c.is_alphanumeric())
+        .find(|word| !word.is_empty())
+        .is_some_and(|word| word.eq_ignore_ascii_case(ACCEPT));
+    if accepted {
+        Review::Accepted
+    } else {
+        Review::Rejected(reply.to_owned())
+    }
+}
+
+/// The user message asking for the answer to be revised for `critique`
+pub fn revision_request(critique: &str) -> String {
+    format!(
+        "A reviewer found problems with your answer:\n\n{critique}\n\n\
+         Reply with a revised answer that fixes them."
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_verdict() {
+        let verdict = parse_verdict("8\nCorrect, but misses an edge case.");
+        assert_eq!(
+            verdict,
+            Some(Verdict {
+                score: 8,
+                reason: "Correct, but misses an edge case.".to_owned()
+            })
+        );
+
+        let verdict = parse_verdict("Score: 10/10 - spot on").unwrap();
+        assert_eq!((verdict.score, verdict.reason.as_str()), (10, "spot on"));
+
+        assert_eq!(parse_verdict("3/10").unwrap().score, 3);
+        assert_eq!(parse_verdict("I can't tell.\n7"), None);
+        assert_eq!(parse_verdict("Score: 42"), None);
+    }
+
+    #[test]
+    fn test_parse_review() {
+        assert_eq!(parse_review("ACCEPT"), Review::Accepted);
+        assert_eq!(parse_review("**Accept.** Looks right."), Review::Accepted);
+        assert_eq!(
+            parse_review("The regex misses the area code.\n"),
+            Review::Rejected("The regex misses the area code.".to_owned())
+        );
+        // Only the first word decides
+        assert!(matches!(
+            parse_review("I can't accept this: it's wrong."),
+            Review::Rejected(_)
+        ));
+        assert!(matches!(
+            parse_review("Acceptable, but"),
+            Review::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_verify_config_defaults() {
+        let config: VerifyConfig = serde_json::from_str("{}").unwrap();
+
+        assert_eq!(config, VerifyConfig::default());
+        assert_eq!(config.threshold, DEFAULT_THRESHOLD);
+    }
+}
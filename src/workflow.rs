@@ -0,0 +1,452 @@
+//! Named pipelines of recipes
+//!
+//! A workflow is a YAML file in the `workflows` directory next to the
+//! config file, naming the recipes to run in order:
+//!
+//! ```yaml
+//! description: Review the staged changes, then write a commit message
+//! steps:
+//!   - recipe: review
+//!     tools: [git_diff]
+//!   - recipe: commit-message
+//!     input: "Staged changes, as reviewed:\n\n{{ review }}"
+//!     vars:
+//!       style: "{{ style | default('conventional') }}"
+//!     tools: []
+//! ```
+//!
+//! `aido workflow run <name> [input]` runs each step's recipe in turn. The
+//! first step gets the input as its user message, and every later one the
+//! answer of the step before it, unless the step's `input` says otherwise.
+//! A step's `input` and `vars` are templates that see the workflow's
+//! `input`, the answer of the step before as `previous`, the answer of
+//! every earlier step under its name, and the `--var` values; the recipes
+//! see the same answers as variables. A step's name defaults to its
+//! recipe's, with anything but letters, digits and `_` made a `_`.
+//!
+//! A step with `tools` is offered only the tools listed, so a step that
+//! only summarizes can't run commands; without `tools` it is offered all
+//! of those its recipe's `allowed_tools` allows.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use minijinja::Value;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::error::AidoResult;
+use crate::paths;
+use crate::recipe::{RecipeError, RecipeStore, template};
+use crate::run::{self, RunOptions, RunOutcome};
+use crate::tools::Tool;
+
+/// Extensions of workflow files, the first being the one preferred
+const EXTENSIONS: [&str; 2] = ["yaml", "yml"];
+
+/// Names templates see besides the answers of the steps
+const RESERVED_NAMES: [&str; 2] = ["input", "previous"];
+
+#[derive(Error, Debug)]
+pub enum WorkflowError {
+    #[error("Workflow not found: {name}")]
+    NotFound { name: String },
+
+    #[error("Invalid workflow name: {name}")]
+    InvalidName { name: String },
+
+    #[error("Invalid workflow {name}: {reason}")]
+    Invalid { name: String, reason: String },
+
+    #[error("Step `{step}` names `{tool}`, which isn't a tool")]
+    UnknownTool { step: String, tool: String },
+
+    #[error("Could not render step `{step}`: {source}")]
+    Template { step: String, source: RecipeError },
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// An ordered list of recipe steps
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Workflow {
+    /// What the workflow is for, shown by `aido workflow list`
+    #[serde(default)]
+    pub description: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+/// One recipe run of a workflow
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// Name of the recipe to run
+    pub recipe: String,
+    /// Name later steps know the answer by
+    #[serde(default)]
+    name: Option<String>,
+    /// Template of the user message
+    #[serde(default)]
+    pub input: Option<String>,
+    /// Templates of the values of the recipe's variables
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    /// Names of the only tools the step is offered, if limited
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+}
+
+impl Step {
+    /// Name later steps know the answer by
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.recipe
+                .chars()
+                .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                .collect()
+        })
+    }
+}
+
+impl Workflow {
+    /// Parses the workflow named `name` from its YAML `content`
+    pub fn parse(name: &str, content: &str) -> Result<Self, WorkflowError> {
+        let invalid = |reason: String| WorkflowError::Invalid {
+            name: name.to_owned(),
+            reason,
+        };
+
+        let workflow = serde_yaml::from_str::<Self>(content)
+            .map_err(|e| invalid(e.to_string()))?;
+        if workflow.steps.is_empty() {
+            return Err(invalid("it has no steps".to_owned()));
+        }
+
+        let mut names = HashSet::new();
+        for step in &workflow.steps {
+            let step_name = step.name();
+            if !is_identifier(&step_name) {
+                return Err(invalid(format!(
+                    "`{step_name}` can't be used as a variable name"
+                )));
+            }
+            if RESERVED_NAMES.contains(&step_name.as_str()) {
+                return Err(invalid(format!("`{step_name}` is reserved")));
+            }
+            if !names.insert(step_name.clone()) {
+                return Err(invalid(format!(
+                    "two steps are named `{step_name}`"
+                )));
+            }
+        }
+
+        Ok(workflow)
+    }
+
+    /// Fails on steps limited to tools that aren't in `tools`
+    pub fn check_tools(
+        &self,
+        tools: &[Box<dyn Tool>],
+    ) -> Result<(), WorkflowError> {
+        for step in &self.steps {
+            for tool in step.tools.iter().flatten() {
+                if !tools.iter().any(|t| t.definition().name() == tool) {
+                    return Err(WorkflowError::UnknownTool {
+                        step: step.name(),
+                        tool: tool.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// A workflow in the workflows directory
+#[derive(Debug, Clone)]
+pub struct WorkflowInfo {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// The directory workflows are kept in
+#[derive(Debug, Clone)]
+pub struct WorkflowStore {
+    dir: PathBuf,
+}
+
+impl WorkflowStore {
+    /// Opens the workflows directory next to the given config file
+    pub fn for_config_file(config_file_path: &str) -> io::Result<Self> {
+        Ok(Self::new(paths::config_dir(config_file_path)?.join("workflows")))
+    }
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Loads the workflow named `name`
+    pub fn get(&self, name: &str) -> Result<Workflow, WorkflowError> {
+        // The name is joined to the directory, so it can't lead out of it
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(WorkflowError::InvalidName { name: name.to_owned() });
+        }
+
+        for extension in EXTENSIONS {
+            let path = self.dir.join(format!("{name}.{extension}"));
+            match std::fs::read_to_string(&path) {
+                Ok(content) => return Workflow::parse(name, &content),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(WorkflowError::NotFound { name: name.to_owned() })
+    }
+
+    /// Lists the workflows, by name; one that can't be loaded is listed
+    /// without a description
+    pub fn list(&self) -> Result<Vec<WorkflowInfo>, WorkflowError> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut workflows = entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let extension = path.extension()?.to_str()?;
+                EXTENSIONS.contains(&extension).then_some(())?;
+                let name = path.file_stem()?.to_str()?.to_owned();
+                let description =
+                    self.get(&name).ok().and_then(|w| w.description);
+                Some(WorkflowInfo { name, description })
+            })
+            .collect::<Vec<_>>();
+        workflows.sort_by(|a, b| a.name.cmp(&b.name));
+        workflows.dedup_by(|a, b| a.name == b.name);
+
+        Ok(workflows)
+    }
+}
+
+/// Runs the steps of `workflow` in order, giving the first `input`
+///
+/// `on_step` is called with the recipe and outcome of each step as it
+/// finishes. The returned outcome is that of the last step, except that its
+/// usage and cost cover the whole workflow. A cancelled step ends the
+/// workflow early, as does a dry run, which only prints the first request.
+pub async fn run_workflow(
+    config: &Config,
+    recipes: &RecipeStore,
+    workflow: &Workflow,
+    input: Option<String>,
+    tools: &[Box<dyn Tool>],
+    options: &RunOptions,
+    mut on_step: impl FnMut(&str, &RunOutcome),
+) -> AidoResult<RunOutcome> {
+    workflow.check_tools(tools)?;
+    // A missing recipe is better found before the first step is paid for
+    for step in &workflow.steps {
+        recipes.get(&step.recipe)?;
+    }
+
+    let mut values = options
+        .vars
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<HashMap<_, _>>();
+    if let Some(input) = &input {
+        values.insert("input".to_owned(), input.clone());
+    }
+
+    let mut total = RunOutcome::default();
+    let mut previous = input;
+    for (i, step) in workflow.steps.iter().enumerate() {
+        let name = step.name();
+        eprintln!("[{}/{}] {name}", i + 1, workflow.steps.len());
+
+        let render = |body: &str| {
+            let context = values
+                .iter()
+                .map(|(name, value)| {
+                    (name.clone(), Value::from(value.as_str()))
+                })
+                .collect();
+            template::render(body, context).map_err(|source| {
+                WorkflowError::Template { step: name.clone(), source }
+            })
+        };
+        let user_message = match &step.input {
+            Some(input) => Some(render(input)?),
+            None => previous.take(),
+        };
+        let mut step_options = options.clone();
+        for (var, value) in &step.vars {
+            step_options.vars.insert(var.clone(), render(value)?);
+        }
+        for (name, value) in &values {
+            step_options
+                .vars
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+        step_options.allowed_tools.clone_from(&step.tools);
+
+        let outcome = Box::pin(run::run_recipe(
+            config.clone(),
+            recipes,
+            &step.recipe,
+            user_message,
+            tools,
+            &step_options,
+        ))
+        .await?;
+        on_step(&step.recipe, &outcome);
+
+        let mut usage = std::mem::take(&mut total.usage);
+        usage += &outcome.usage;
+        let cost = run::add_costs(total.cost, outcome.cost);
+        total = RunOutcome { usage, cost, ..outcome };
+
+        if total.cancelled || options.dry_run {
+            break;
+        }
+        values.insert(name, total.text.clone());
+        values.insert("previous".to_owned(), total.text.clone());
+        previous = Some(total.text.clone());
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let workflow = Workflow::parse(
+            "ship",
+            "description: Ship it\nsteps:\n  - recipe: review\n    tools: \
+             [git_diff]\n  - recipe: commit-message\n    input: '{{ review \
+             }}'\n    vars: { style: short }\n",
+        )
+        .unwrap();
+
+        assert_eq!(workflow.description.as_deref(), Some("Ship it"));
+        let names = workflow.steps.iter().map(Step::name).collect::<Vec<_>>();
+        assert_eq!(names, ["review", "commit_message"]);
+        assert_eq!(
+            workflow.steps[0].tools.as_deref(),
+            Some(&["git_diff".to_owned()][..])
+        );
+        assert_eq!(workflow.steps[1].tools, None);
+        assert_eq!(workflow.steps[1].vars["style"], "short");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let invalid = |content: &str| {
+            let error = Workflow::parse("w", content).unwrap_err();
+            assert!(matches!(error, WorkflowError::Invalid { .. }), "{error}");
+            error.to_string()
+        };
+
+        assert!(invalid("steps: []").contains("no steps"));
+        assert!(
+            invalid("steps:\n  - recipe: a\n  - recipe: a").contains("two")
+        );
+        assert!(
+            invalid("steps:\n  - recipe: a\n    name: input")
+                .contains("reserved")
+        );
+        assert!(
+            invalid("steps:\n  - recipe: a\n    name: 1st").contains("1st")
+        );
+        assert!(
+            invalid("steps:\n  - recipe: a\n    tool: [ls]").contains("tool")
+        );
+    }
+
+    #[test]
+    fn test_check_tools() {
+        let tools: Vec<Box<dyn Tool>> =
+            vec![Box::new(crate::tools::Search::new())];
+        let workflow = |tools: &str| {
+            Workflow::parse(
+                "w",
+                &format!("steps:\n  - recipe: a\n    tools: {tools}"),
+            )
+            .unwrap()
+        };
+
+        workflow("[search]").check_tools(&tools).unwrap();
+        workflow("[]").check_tools(&tools).unwrap();
+        assert!(matches!(
+            workflow("[serch]").check_tools(&tools),
+            Err(WorkflowError::UnknownTool { tool, .. }) if tool == "serch"
+        ));
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir()
+            .join(format!("aido-workflows-{}", std::process::id()));
+        let store = WorkflowStore::new(&dir);
+        assert!(store.list().unwrap().is_empty());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("ship.yaml"),
+            "description: Ship it\nsteps: [{ recipe: review }]",
+        )
+        .unwrap();
+        std::fs::write(dir.join("broken.yml"), "steps: 3").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let listed = store.list().unwrap();
+        let names = listed.iter().map(|w| w.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["broken", "ship"]);
+        assert_eq!(listed[1].description.as_deref(), Some("Ship it"));
+
+        assert_eq!(store.get("ship").unwrap().steps[0].recipe, "review");
+        assert!(matches!(
+            store.get("broken"),
+            Err(WorkflowError::Invalid { .. })
+        ));
+        assert!(matches!(
+            store.get("missing"),
+            Err(WorkflowError::NotFound { .. })
+        ));
+        assert!(matches!(
+            store.get("../workflows/ship"),
+            Err(WorkflowError::InvalidName { .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}